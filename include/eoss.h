@@ -0,0 +1,143 @@
+#ifndef EOSS_H
+#define EOSS_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define EOSS_DIR 1
+
+#define EOSS_FILE 2
+
+/**
+ * An open file of a filesystem, used with the filesystem it is of.
+ */
+typedef struct EossFile EossFile;
+
+/**
+ * An open filesystem.
+ */
+typedef struct EossVfs EossVfs;
+
+/**
+ * Attributes of an entry, times as seconds and nanoseconds since the epoch.
+ */
+typedef struct EossStat {
+  /**
+   * `EOSS_DIR` or `EOSS_FILE`.
+   */
+  uint32_t kind;
+  uint32_t perm;
+  uint32_t uid;
+  uint32_t gid;
+  uint64_t size;
+  int64_t atime_sec;
+  uint32_t atime_nsec;
+  int64_t mtime_sec;
+  uint32_t mtime_nsec;
+  int64_t ctime_sec;
+  uint32_t ctime_nsec;
+} EossStat;
+
+/**
+ * Called by `eoss_readdir` with the name and attributes of each entry, and
+ * its `context`. Returning non-zero stops the listing.
+ */
+typedef int (*EossDirCallback)(const char *name, const struct EossStat *stat, void *context);
+
+/**
+ * Open the filesystem stored at `uri`, as given on the command line, an
+ * encrypted one unlocked with the secret from `key` as given to `--key`,
+ * null otherwise. Returns null on failure, described by `eoss_last_error`.
+ *
+ * # Safety
+ * `uri` is a nul-terminated string, and `key` one or null.
+ */
+struct EossVfs *eoss_open(const char *uri, const char *key);
+
+/**
+ * Persist everything and close `vfs`, freeing it even on failure, null
+ * closing nothing. Returns 0, or a negated errno.
+ *
+ * # Safety
+ * `vfs` is from `eoss_open` or null, its files all closed, and not used
+ * after.
+ */
+int eoss_close(struct EossVfs *vfs);
+
+/**
+ * Attributes of the entry at `path` into `stat`. Returns 0, or a negated
+ * errno.
+ *
+ * # Safety
+ * `vfs` is from `eoss_open`, `path` a nul-terminated string and `stat`
+ * writable.
+ */
+int eoss_stat(struct EossVfs *vfs, const char *path, struct EossStat *stat);
+
+/**
+ * Call `callback` with each entry of the directory at `path`, in no
+ * particular order. Returns 0, the non-zero value `callback` stopped with,
+ * or a negated errno.
+ *
+ * # Safety
+ * `vfs` is from `eoss_open` and `path` a nul-terminated string.
+ */
+int eoss_readdir(struct EossVfs *vfs, const char *path, EossDirCallback callback, void *context);
+
+/**
+ * Open the file at `path`, or with `create` create it with permission
+ * `perm`, failing if it exists. Returns null on failure, described by
+ * `eoss_last_error`.
+ *
+ * # Safety
+ * `vfs` is from `eoss_open` and `path` a nul-terminated string.
+ */
+struct EossFile *eoss_file_open(struct EossVfs *vfs, const char *path, bool create, uint32_t perm);
+
+/**
+ * Close `file`, persisting what was written to it, and free it even on
+ * failure. Returns 0, or a negated errno.
+ *
+ * # Safety
+ * `file` is from `eoss_file_open` on `vfs`, and not used after.
+ */
+int eoss_file_close(struct EossVfs *vfs, struct EossFile *file);
+
+/**
+ * Read at most `len` bytes at `offset` of `file` into `buf`. Returns the
+ * bytes read, fewer at its end, or a negated errno.
+ *
+ * # Safety
+ * `file` is from `eoss_file_open` on `vfs`, and `buf` holds `len` bytes.
+ */
+ptrdiff_t eoss_read(struct EossVfs *vfs,
+                    const struct EossFile *file,
+                    uint64_t offset,
+                    uint8_t *buf,
+                    size_t len);
+
+/**
+ * Write the `len` bytes of `buf` at `offset` of `file`. Returns the bytes
+ * written, or a negated errno.
+ *
+ * # Safety
+ * `file` is from `eoss_file_open` on `vfs`, and `buf` holds `len` bytes.
+ */
+ptrdiff_t eoss_write(struct EossVfs *vfs,
+                     const struct EossFile *file,
+                     uint64_t offset,
+                     const uint8_t *buf,
+                     size_t len);
+
+/**
+ * Message of the last failure on this thread, null if none. Valid until
+ * the next call failing on this thread.
+ */
+const char *eoss_last_error(void);
+
+#endif /* EOSS_H */
@@ -0,0 +1,250 @@
+use std::convert::TryInto;
+use std::time::SystemTime;
+
+use crate::control::barrier_point;
+use crate::id::{Id, ID_LENGTH};
+
+/// Type of the ioctl commands of a mount, as in `_IO('E', nr)`.
+const IOC_TYPE: u32 = b'E' as u32;
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+/// Bytes of the name of a snapshot taken by `EOSS_IOC_SNAPSHOT`, padded
+/// with NULs.
+pub const NAME_BYTES: usize = 256;
+/// Chunk ids answered at most by one `EOSS_IOC_CHUNK_MAP`.
+pub const CHUNK_MAP_IDS: usize = 64;
+/// Bytes of `struct { u64 count; u64 first; u8 ids[CHUNK_MAP_IDS][32]; }`.
+const CHUNK_MAP_BYTES: usize = 16 + CHUNK_MAP_IDS * ID_LENGTH;
+/// Bytes of `struct { u64 file_bytes; u64 dirty_bytes; u64 oldest_ms; }`.
+const BACKLOG_BYTES: usize = 24;
+
+/// Take a snapshot of the filesystem named by a `char[NAME_BYTES]`, on
+/// behalf of root only.
+pub const EOSS_IOC_SNAPSHOT: u32 = ioc(IOC_WRITE, 1, NAME_BYTES);
+/// Pin the entry opened in the chunk cache given an `int` other than 0,
+/// unpin it given 0.
+pub const EOSS_IOC_PIN: u32 = ioc(IOC_WRITE, 2, 4);
+/// Persist what was written to the file opened, and write its chunks back.
+pub const EOSS_IOC_FLUSH: u32 = ioc(0, 3, 0);
+/// Ids of the chunks of the file opened from chunk `first` of the struct
+/// given, answered in the struct along with the `count` of chunks.
+pub const EOSS_IOC_CHUNK_MAP: u32 = ioc(IOC_READ | IOC_WRITE, 4, CHUNK_MAP_BYTES);
+/// Drop the chunks of the file opened from the chunk cache once persisted,
+/// on behalf of root only.
+pub const EOSS_IOC_EVICT: u32 = ioc(0, 5, 0);
+/// Bytes of the chunks of the file opened and of all chunks not written back
+/// to the provider yet, and milliseconds the oldest of all has been waiting.
+pub const EOSS_IOC_BACKLOG: u32 = ioc(IOC_READ, 6, BACKLOG_BYTES);
+/// Block until the chunks dirty since the time given as a `u64` of
+/// milliseconds since the epoch or before are stored in the provider, all
+/// given 0, as a `syncfs` up to then.
+pub const EOSS_IOC_BARRIER: u32 = ioc(IOC_WRITE, 7, 8);
+
+const fn ioc(dir: u32, nr: u32, size: usize) -> u32 {
+    dir << 30 | (size as u32) << 16 | IOC_TYPE << 8 | nr
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IoctlError {
+    #[error("unknown ioctl {0:#x}")]
+    UnknownCommand(u32),
+    #[error("malformed argument of ioctl {0:#x}")]
+    Malformed(u32),
+}
+
+/// An admin command to a mount through an ioctl on a file opened in it,
+/// by tooling holding the file descriptor rather than a control socket.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Ioctl {
+    Snapshot(String),
+    Pin(bool),
+    Flush,
+    /// Chunk ids from the chunk index given.
+    ChunkMap(u64),
+    Evict,
+    Backlog,
+    /// Write back chunks dirty since the time given or before.
+    Barrier(SystemTime),
+}
+
+impl Ioctl {
+    /// Command `cmd` with its argument `in_data`, as copied in by the kernel.
+    pub fn parse(cmd: u32, in_data: &[u8]) -> Result<Self, IoctlError> {
+        let malformed = || IoctlError::Malformed(cmd);
+        match cmd {
+            EOSS_IOC_SNAPSHOT => {
+                let name = in_data.split(|&b| b == 0).next().unwrap_or_default();
+                let name = std::str::from_utf8(name).map_err(|_| malformed())?;
+                Ok(Ioctl::Snapshot(name.to_owned()))
+            }
+            EOSS_IOC_PIN => {
+                let pinned = in_data.get(..4).ok_or_else(malformed)?;
+                Ok(Ioctl::Pin(pinned != [0; 4]))
+            }
+            EOSS_IOC_FLUSH => Ok(Ioctl::Flush),
+            EOSS_IOC_CHUNK_MAP => {
+                let first = in_data.get(8..16).ok_or_else(malformed)?;
+                Ok(Ioctl::ChunkMap(u64::from_ne_bytes(
+                    first.try_into().unwrap(),
+                )))
+            }
+            EOSS_IOC_EVICT => Ok(Ioctl::Evict),
+            EOSS_IOC_BACKLOG => Ok(Ioctl::Backlog),
+            EOSS_IOC_BARRIER => {
+                let point = in_data.get(..8).ok_or_else(malformed)?;
+                Ok(Ioctl::Barrier(barrier_point(u64::from_ne_bytes(
+                    point.try_into().unwrap(),
+                ))))
+            }
+            cmd => Err(IoctlError::UnknownCommand(cmd)),
+        }
+    }
+}
+
+/// Answer of `EOSS_IOC_BACKLOG`.
+pub fn backlog(file_bytes: usize, dirty_bytes: usize, oldest_ms: u64) -> Vec<u8> {
+    let mut backlog = Vec::with_capacity(BACKLOG_BYTES);
+    backlog.extend_from_slice(&(file_bytes as u64).to_ne_bytes());
+    backlog.extend_from_slice(&(dirty_bytes as u64).to_ne_bytes());
+    backlog.extend_from_slice(&oldest_ms.to_ne_bytes());
+    backlog
+}
+
+/// Answer of `EOSS_IOC_CHUNK_MAP` from chunk `first` of a file of chunks
+/// `ids`, those beyond the ones answered left zero.
+pub fn chunk_map(ids: &[Id], first: u64) -> Vec<u8> {
+    let mut map = Vec::with_capacity(CHUNK_MAP_BYTES);
+    map.extend_from_slice(&(ids.len() as u64).to_ne_bytes());
+    map.extend_from_slice(&first.to_ne_bytes());
+    let from = (first as usize).min(ids.len());
+    for id in ids[from..].iter().take(CHUNK_MAP_IDS) {
+        map.extend_from_slice(&**id);
+    }
+    map.resize(CHUNK_MAP_BYTES, 0);
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use fuser::FUSE_ROOT_ID;
+
+    use super::{
+        chunk_map, Ioctl, IoctlError, CHUNK_MAP_IDS, EOSS_IOC_BARRIER, EOSS_IOC_CHUNK_MAP,
+        EOSS_IOC_FLUSH, EOSS_IOC_PIN, EOSS_IOC_SNAPSHOT, NAME_BYTES,
+    };
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fuse::EossFs;
+    use crate::id::{Id, ID_LENGTH};
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+
+    #[test]
+    fn test_ioctl() {
+        assert_eq!(EOSS_IOC_FLUSH, 0x4503);
+        assert_eq!(EOSS_IOC_PIN, 0x4004_4502);
+        let mut name = b"daily".to_vec();
+        name.resize(NAME_BYTES, 0);
+        assert_eq!(
+            Ioctl::parse(EOSS_IOC_SNAPSHOT, &name).unwrap(),
+            Ioctl::Snapshot("daily".to_owned())
+        );
+        assert_eq!(
+            Ioctl::parse(EOSS_IOC_PIN, &1i32.to_ne_bytes()).unwrap(),
+            Ioctl::Pin(true)
+        );
+        assert!(matches!(
+            Ioctl::parse(EOSS_IOC_PIN, &[]),
+            Err(IoctlError::Malformed(_))
+        ));
+        assert!(matches!(
+            Ioctl::parse(0x5401, &[]),
+            Err(IoctlError::UnknownCommand(_))
+        ));
+
+        let ids: Vec<Id> = (0..100).map(|n| Id::new([n; ID_LENGTH])).collect();
+        let map = chunk_map(&ids, 64);
+        assert_eq!(&map[..8], &100u64.to_ne_bytes());
+        assert_eq!(&map[16..16 + ID_LENGTH], &[64; ID_LENGTH]);
+        // 36 left
+        assert_eq!(map[16 + 36 * ID_LENGTH..], vec![0; 28 * ID_LENGTH][..]);
+        assert_eq!(map.len(), 16 + CHUNK_MAP_IDS * ID_LENGTH);
+
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        let (attr, _) = fs
+            .create_file(FUSE_ROOT_ID, "file", 0o644, 1000, 1000)
+            .unwrap();
+        fs.write_direct(attr.ino, 0, &vec![7; CHUNK_SIZE + 10])
+            .unwrap();
+        let mut request = vec![0; map.len()];
+        request[8..16].copy_from_slice(&1u64.to_ne_bytes());
+        let ioctl = Ioctl::parse(EOSS_IOC_CHUNK_MAP, &request).unwrap();
+        let map = fs.admin(1000, attr.ino, ioctl).unwrap();
+        assert_eq!(
+            &map[..16],
+            &[2u64.to_ne_bytes(), 1u64.to_ne_bytes()].concat()[..]
+        );
+        assert_ne!(&map[16..16 + ID_LENGTH], &[0; ID_LENGTH]);
+        assert_eq!(fs.admin(1000, attr.ino, Ioctl::Evict), Err(libc::EPERM));
+        let cached = fs.runtime_stats().cache_bytes;
+        fs.admin(0, attr.ino, Ioctl::Evict).unwrap();
+        // none of the file left cached, only metadata
+        let evicted = cached - fs.runtime_stats().cache_bytes;
+        assert_eq!(evicted, 2 * CHUNK_SIZE);
+        fs.admin(1000, attr.ino, Ioctl::Flush).unwrap();
+        let snapshot = Ioctl::Snapshot("daily".to_owned());
+        fs.admin(0, attr.ino, snapshot.clone()).unwrap();
+        assert_eq!(fs.admin(0, attr.ino, snapshot), Err(libc::EEXIST));
+        assert_eq!(fs.snapshots().len(), 1);
+        fs.close().unwrap();
+
+        // backlog of a mount writing back
+        let options = MountOptions {
+            write_back: true,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), options, &id).unwrap();
+        let (attr, _) = fs
+            .create_file(FUSE_ROOT_ID, "dirty", 0o644, 1000, 1000)
+            .unwrap();
+        fs.write_direct(attr.ino, 0, &vec![7; CHUNK_SIZE + 10])
+            .unwrap();
+        let backlog = fs.admin(1000, attr.ino, Ioctl::Backlog).unwrap();
+        let file_bytes = u64::from_ne_bytes(backlog[..8].try_into().unwrap());
+        let dirty_bytes = u64::from_ne_bytes(backlog[8..16].try_into().unwrap());
+        assert_eq!(file_bytes, 2 * CHUNK_SIZE as u64);
+        assert!(dirty_bytes >= file_bytes);
+        let barrier = Ioctl::parse(EOSS_IOC_BARRIER, &0u64.to_ne_bytes()).unwrap();
+        fs.admin(1000, attr.ino, barrier).unwrap();
+        assert_eq!(fs.runtime_stats().dirty_bytes, 0);
+        let map = fs.admin(1000, attr.ino, Ioctl::ChunkMap(1)).unwrap();
+        let id = Id::new(map[16..16 + ID_LENGTH].try_into().unwrap());
+        assert!(provider.contains_chunk(&id).unwrap());
+
+        // writes buffered before the point are written back
+        fs.buffer_write(1, attr.ino, 0, b"buffered", CHUNK_SIZE)
+            .unwrap();
+        let backlog = fs.admin(1000, attr.ino, Ioctl::Backlog).unwrap();
+        assert_eq!(u64::from_ne_bytes(backlog[..8].try_into().unwrap()), 8);
+        thread::sleep(Duration::from_millis(10));
+        let point = SystemTime::now() - Duration::from_millis(5);
+        assert_eq!(fs.barrier(point).unwrap(), 1);
+        assert_eq!(fs.runtime_stats().dirty_bytes, 0);
+        let map = fs.admin(1000, attr.ino, Ioctl::ChunkMap(0)).unwrap();
+        let id = Id::new(map[16..16 + ID_LENGTH].try_into().unwrap());
+        let chunk = provider.get_chunk_by_id(&id).unwrap();
+        let mut data = [0; 8];
+        chunk.read_at(0, &mut data);
+        assert_eq!(&data, b"buffered");
+        fs.close().unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use std::env;
+#[cfg(windows)]
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// Variable naming the socket of the ssh-agent.
+const AUTH_SOCK: &str = "SSH_AUTH_SOCK";
+/// Pipe of the ssh-agent of Windows OpenSSH, unless `AUTH_SOCK` names another.
+#[cfg(windows)]
+const AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+const REQUEST_IDENTITIES: u8 = 11;
+const IDENTITIES_ANSWER: u8 = 12;
+const SIGN_REQUEST: u8 = 13;
+const SIGN_RESPONSE: u8 = 14;
+/// Only ed25519 signatures are deterministic, the same on each unlock.
+const KEY_TYPE: &[u8] = b"ssh-ed25519";
+/// Challenge signed by the agent, the signature is the secret.
+const CHALLENGE: &[u8] = b"eoss-fuse 2021-05 ssh-agent secret";
+
+#[derive(thiserror::Error, Debug)]
+pub enum AgentError {
+    #[cfg(unix)]
+    #[error("no ssh-agent running, {0} is not set")]
+    NotRunning(&'static str),
+    #[error("no ed25519 key with comment {0} in the ssh-agent")]
+    NoSuchKey(String),
+    #[error("unexpected ssh-agent reply {0}")]
+    UnexpectedReply(u8),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// Connection to the ssh-agent, a named pipe on Windows.
+#[cfg(unix)]
+type Stream = UnixStream;
+#[cfg(windows)]
+type Stream = File;
+
+/// A connection to the ssh-agent, speaking the protocol of
+/// draft-miller-ssh-agent.
+pub struct Agent {
+    stream: Stream,
+}
+
+impl Agent {
+    /// Connect to the agent at `$SSH_AUTH_SOCK`.
+    #[cfg(unix)]
+    pub fn connect() -> Result<Self, AgentError> {
+        let path = env::var_os(AUTH_SOCK).ok_or(AgentError::NotRunning(AUTH_SOCK))?;
+        Ok(Self::new(UnixStream::connect(path)?))
+    }
+
+    /// Connect to the agent at `$SSH_AUTH_SOCK`, or that of Windows OpenSSH.
+    #[cfg(windows)]
+    pub fn connect() -> Result<Self, AgentError> {
+        let path = env::var_os(AUTH_SOCK).unwrap_or_else(|| AGENT_PIPE.into());
+        let stream = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn new(stream: Stream) -> Self {
+        Self { stream }
+    }
+
+    /// Public key blobs and comments of the keys held by the agent.
+    pub fn identities(&mut self) -> Result<Vec<(Vec<u8>, String)>, AgentError> {
+        let reply = self.request(&[REQUEST_IDENTITIES])?;
+        let mut reader = &reply[..];
+        match take_u8(&mut reader)? {
+            IDENTITIES_ANSWER => {}
+            tag => return Err(AgentError::UnexpectedReply(tag)),
+        }
+        let count = take_u32(&mut reader)?;
+        let mut identities = Vec::new();
+        for _ in 0..count {
+            let blob = take_string(&mut reader)?.to_vec();
+            let comment = String::from_utf8_lossy(take_string(&mut reader)?).into_owned();
+            identities.push((blob, comment));
+        }
+        Ok(identities)
+    }
+
+    /// Secret derived from the ed25519 key with `comment`, its signature
+    /// of a fixed challenge, which never leaves this host otherwise.
+    pub fn secret(&mut self, comment: &str) -> Result<Vec<u8>, AgentError> {
+        let blob = self
+            .identities()?
+            .into_iter()
+            .find(|(blob, name)| name == comment && key_type(blob) == Some(KEY_TYPE))
+            .map(|(blob, _)| blob)
+            .ok_or_else(|| AgentError::NoSuchKey(comment.to_owned()))?;
+        let mut request = vec![SIGN_REQUEST];
+        put_string(&mut request, &blob);
+        put_string(&mut request, CHALLENGE);
+        request.extend_from_slice(&0u32.to_be_bytes());
+        let reply = self.request(&request)?;
+        let mut reader = &reply[..];
+        match take_u8(&mut reader)? {
+            SIGN_RESPONSE => Ok(take_string(&mut reader)?.to_vec()),
+            tag => Err(AgentError::UnexpectedReply(tag)),
+        }
+    }
+
+    /// Send a message and read the reply, both framed by their length.
+    fn request(&mut self, message: &[u8]) -> Result<Vec<u8>, AgentError> {
+        let mut framed = Vec::with_capacity(4 + message.len());
+        put_string(&mut framed, message);
+        self.stream.write_all(&framed)?;
+        let mut length = [0; 4];
+        self.stream.read_exact(&mut length)?;
+        let mut reply = vec![0; u32::from_be_bytes(length) as usize];
+        self.stream.read_exact(&mut reply)?;
+        Ok(reply)
+    }
+}
+
+fn key_type(blob: &[u8]) -> Option<&[u8]> {
+    let mut reader = blob;
+    take_string(&mut reader).ok()
+}
+
+fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn take<'a>(reader: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if reader.len() < n {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (taken, rest) = reader.split_at(n);
+    *reader = rest;
+    Ok(taken)
+}
+
+fn take_u8(reader: &mut &[u8]) -> io::Result<u8> {
+    Ok(take(reader, 1)?[0])
+}
+
+fn take_u32(reader: &mut &[u8]) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(take(reader, 4)?);
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn take_string<'a>(reader: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let length = take_u32(reader)? as usize;
+    take(reader, length)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{put_string, take_string, take_u8, Agent, AgentError};
+    use super::{IDENTITIES_ANSWER, KEY_TYPE, SIGN_REQUEST, SIGN_RESPONSE};
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    /// Answer requests of one connection as an agent holding one key.
+    fn serve(mut stream: UnixStream) {
+        let mut blob = Vec::new();
+        put_string(&mut blob, KEY_TYPE);
+        put_string(&mut blob, &[1; 32]);
+        loop {
+            let mut length = [0; 4];
+            if stream.read_exact(&mut length).is_err() {
+                return;
+            }
+            let mut request = vec![0; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut request).unwrap();
+            let mut reader = &request[..];
+            let mut reply = Vec::new();
+            if take_u8(&mut reader).unwrap() == SIGN_REQUEST {
+                assert_eq!(take_string(&mut reader).unwrap(), &blob[..]);
+                let data = take_string(&mut reader).unwrap();
+                reply.push(SIGN_RESPONSE);
+                put_string(&mut reply, &[data[0]; 64]);
+            } else {
+                reply.push(IDENTITIES_ANSWER);
+                reply.extend_from_slice(&1u32.to_be_bytes());
+                put_string(&mut reply, &blob);
+                put_string(&mut reply, b"backup@host");
+            }
+            let mut framed = Vec::new();
+            put_string(&mut framed, &reply);
+            stream.write_all(&framed).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_secret_from_agent() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let agent = thread::spawn(move || serve(server));
+        let mut client = Agent::new(client);
+        let secret = client.secret("backup@host").unwrap();
+        assert_eq!(secret, vec![b'e'; 64]);
+        assert!(matches!(
+            client.secret("other@host"),
+            Err(AgentError::NoSuchKey(_))
+        ));
+        drop(client);
+        agent.join().unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::chunk::BLOCK_PER_CHUNK;
+use crate::id::Id;
+
+/// Blocks at the end of a shared chunk taken by the `FatBitMap`.
+pub const FAT_BLOCKS: usize = 2;
+/// Blocks of a shared chunk available to tiny files.
+pub const SHARED_BLOCKS: usize = BLOCK_PER_CHUNK - FAT_BLOCKS;
+
+/// Occupancy of the blocks of a shared chunk, one bit per block.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FatBitMap([u64; BLOCK_PER_CHUNK / 64]);
+
+impl FatBitMap {
+    fn is_used(&self, block: usize) -> bool {
+        self.0[block / 64] & (1 << (block % 64)) != 0
+    }
+
+    fn set(&mut self, start: usize, blocks: usize, used: bool) {
+        for block in start..start + blocks {
+            if used {
+                self.0[block / 64] |= 1 << (block % 64);
+            } else {
+                self.0[block / 64] &= !(1 << (block % 64));
+            }
+        }
+    }
+
+    /// Find the first run of `blocks` free blocks.
+    fn find_free(&self, blocks: usize) -> Option<usize> {
+        let mut run = 0;
+        for block in 0..SHARED_BLOCKS {
+            if self.is_used(block) {
+                run = 0;
+            } else {
+                run += 1;
+                if run == blocks {
+                    return Some(block + 1 - blocks);
+                }
+            }
+        }
+        None
+    }
+
+    /// Number of used blocks.
+    pub fn used(&self) -> usize {
+        self.0.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Serialize to be stored in the last blocks of the chunk.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    /// The map stored by `to_bytes` in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut fat = Self::default();
+        for (word, le) in fat.0.iter_mut().zip(bytes.chunks_exact(8)) {
+            *word = u64::from_le_bytes(le.try_into().unwrap());
+        }
+        fat
+    }
+
+    /// Blocks used here but not in `other`.
+    pub fn difference(&self, other: &FatBitMap) -> Self {
+        let mut fat = self.clone();
+        for (word, other) in fat.0.iter_mut().zip(other.0.iter()) {
+            *word &= !other;
+        }
+        fat
+    }
+
+    /// Runs of used blocks, by first block and length.
+    pub fn runs(&self) -> Vec<(u16, u16)> {
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        for block in (0..SHARED_BLOCKS).filter(|block| self.is_used(*block)) {
+            match runs.last_mut() {
+                Some((start, blocks)) if (*start + *blocks) as usize == block => *blocks += 1,
+                _ => runs.push((block as u16, 1)),
+            }
+        }
+        runs
+    }
+}
+
+/// TinyFileAllocator packs tiny files into shared chunks at block aligned
+/// offsets, and tracks the free space of each shared chunk for reuse.
+#[derive(Default)]
+pub struct TinyFileAllocator {
+    chunks: HashMap<Id, FatBitMap>,
+}
+
+impl TinyFileAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate a slot of `blocks` blocks in the fullest shared chunk having enough
+    /// space, or in a new shared chunk if none has.
+    /// Returns the shared chunk id and the first block of the slot,
+    /// or `None` if `blocks` cannot fit in a shared chunk.
+    pub fn allocate(&mut self, blocks: usize) -> Option<(Id, u16)> {
+        if blocks == 0 || blocks > SHARED_BLOCKS {
+            return None;
+        }
+        let found = self
+            .chunks
+            .iter()
+            .filter_map(|(id, fat)| fat.find_free(blocks).map(|start| (id, fat.used(), start)))
+            .max_by_key(|(_, used, _)| *used)
+            .map(|(id, _, start)| (id.clone(), start));
+        let (id, start) = found.unwrap_or_else(|| (Id::new_random(), 0));
+        self.mark(&id, start as u16, blocks as u16);
+        Some((id, start as u16))
+    }
+
+    /// Mark a slot as used, e.g. when rebuilding from metadata.
+    pub fn mark(&mut self, chunk_id: &Id, offset: u16, blocks: u16) {
+        self.chunks.entry(chunk_id.clone()).or_default().set(
+            offset as usize,
+            blocks as usize,
+            true,
+        );
+    }
+
+    /// Release a slot for reuse.
+    pub fn free(&mut self, chunk_id: &Id, offset: u16, blocks: u16) {
+        if let Some(fat) = self.chunks.get_mut(chunk_id) {
+            fat.set(offset as usize, blocks as usize, false);
+        }
+    }
+
+    /// Shared chunks with less than `threshold` of their blocks used.
+    pub fn sparse_chunks(&self, threshold: f64) -> Vec<Id> {
+        self.chunks
+            .iter()
+            .filter(|(_, fat)| (fat.used() as f64) < threshold * SHARED_BLOCKS as f64)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Stop allocating from a shared chunk, e.g. before it is deleted.
+    pub fn retire(&mut self, chunk_id: &Id) {
+        self.chunks.remove(chunk_id);
+    }
+
+    /// Shared chunks tracked.
+    pub fn chunk_ids(&self) -> impl Iterator<Item = &Id> {
+        self.chunks.keys()
+    }
+
+    /// The occupancy of a shared chunk.
+    pub fn fat(&self, chunk_id: &Id) -> Option<&FatBitMap> {
+        self.chunks.get(chunk_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TinyFileAllocator, SHARED_BLOCKS};
+
+    #[test]
+    fn test_allocate_and_reuse() {
+        let mut allocator = TinyFileAllocator::new();
+        let (a, offset_a) = allocator.allocate(10).unwrap();
+        let (b, offset_b) = allocator.allocate(SHARED_BLOCKS - 10).unwrap();
+        assert_eq!(a, b);
+        assert_eq!((offset_a, offset_b), (0, 10));
+        // full, goes to a new chunk
+        let (c, offset_c) = allocator.allocate(1).unwrap();
+        assert_ne!(a, c);
+        assert_eq!(offset_c, 0);
+
+        allocator.free(&a, offset_a, 10);
+        assert_eq!(allocator.fat(&a).unwrap().used(), SHARED_BLOCKS - 10);
+        let (d, offset_d) = allocator.allocate(4).unwrap();
+        assert_eq!((&d, offset_d), (&a, 0));
+        assert!(allocator.allocate(SHARED_BLOCKS + 1).is_none());
+    }
+}
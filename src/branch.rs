@@ -0,0 +1,77 @@
+use crate::fs::{DirMeta, Entry, EntryMut, Node};
+use crate::meta::MetaError;
+use crate::snapshot;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CloneError {
+    #[error("source not found")]
+    NotFound,
+    #[error("source is not a directory")]
+    NotDir,
+    #[error("destination already exists")]
+    Exists,
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+}
+
+/// Copy the directory at `from` to `to` in the tree at `root`, which has to
+/// be fully loaded. Only metadata is copied, files of the copy share data
+/// with the source until either one is modified.
+pub fn clone_dir<S: AsRef<str>>(
+    root: &mut DirMeta,
+    from: &[S],
+    to: &[S],
+) -> Result<(), CloneError> {
+    let mut copy = match root.resolve(from) {
+        Some(Entry::Dir(dir)) => dir.clone(),
+        Some(_) => return Err(CloneError::NotDir),
+        None => return Err(CloneError::NotFound),
+    };
+    let (parent, name) = match to.split_last() {
+        Some((name, parent)) => (parent, name.as_ref()),
+        None => return Err(CloneError::Exists),
+    };
+    let parent = match root.resolve_mut(parent) {
+        Some(EntryMut::Dir(dir)) => dir,
+        Some(_) => return Err(CloneError::NotDir),
+        None => return Err(CloneError::NotFound),
+    };
+    if parent.lookup(name).is_some() {
+        return Err(CloneError::Exists);
+    }
+    snapshot::renew_ids(&mut copy);
+    copy.name = name.to_owned();
+    parent.insert(Node::Dir(copy));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clone_dir, CloneError};
+    use crate::fs::{Attrs, DirMeta, Entry, Node, TinyFileMeta};
+
+    #[test]
+    fn test_clone_dir() {
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut dir = DirMeta::new("a".to_owned(), Attrs::new(0o755, 0, 0));
+        let file = TinyFileMeta::new("file".to_owned(), Attrs::new(0o644, 0, 0));
+        dir.insert(Node::TinyFile(file));
+        root.insert(Node::Dir(dir));
+
+        clone_dir(&mut root, &["a"], &["b"]).unwrap();
+        assert!(matches!(
+            clone_dir(&mut root, &["a"], &["b"]),
+            Err(CloneError::Exists)
+        ));
+        let (a, b) = match (root.resolve(&["a"]), root.resolve(&["b"])) {
+            (Some(Entry::Dir(a)), Some(Entry::Dir(b))) => (a, b),
+            _ => panic!("b not cloned"),
+        };
+        assert_ne!(a.id, b.id);
+        assert_eq!(b.name, "b");
+        match (a.lookup("file"), b.lookup("file")) {
+            (Some(Entry::TinyFile(x)), Some(Entry::TinyFile(y))) => assert_eq!(x.id, y.id),
+            _ => panic!("file not cloned"),
+        }
+    }
+}
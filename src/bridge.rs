@@ -0,0 +1,107 @@
+use std::future::Future;
+#[cfg(unix)]
+use std::ops::Range;
+#[cfg(unix)]
+use std::sync::Arc;
+
+#[cfg(unix)]
+use fuser::ReplyData;
+use libc::{c_int, EIO};
+use tokio::runtime::Handle;
+use tokio::task;
+
+/// A reply to a FUSE request, answered once the result is known. Only
+/// reads of file data are answered so, other requests hold the filesystem
+/// exclusively and are answered on the session thread.
+pub trait Respond: Send + 'static {
+    type Value: Send + 'static;
+
+    fn respond(self, result: Result<Self::Value, c_int>);
+}
+
+#[cfg(unix)]
+impl Respond for ReplyData {
+    type Value = (Arc<Vec<u8>>, Range<usize>);
+
+    fn respond(self, result: Result<Self::Value, c_int>) {
+        match result {
+            Ok((data, range)) => self.data(&data[range]),
+            Err(errno) => self.error(errno),
+        }
+    }
+}
+
+/// Bridge answers FUSE requests from futures on a tokio runtime, the reply
+/// moved into the task, so the callback returns at once rather than block
+/// on I/O.
+#[derive(Clone)]
+pub struct Bridge {
+    handle: Handle,
+}
+
+impl Bridge {
+    pub fn new(handle: Handle) -> Self {
+        Self { handle }
+    }
+
+    /// Answer `reply` with the output of `future`, spawned on the runtime.
+    pub fn reply<R, F>(&self, reply: R, future: F)
+    where
+        R: Respond,
+        F: Future<Output = Result<R::Value, c_int>> + Send + 'static,
+    {
+        self.handle
+            .spawn(async move { reply.respond(future.await) });
+    }
+
+    /// Run `f` to completion on the runtime from outside of it.
+    pub fn wait<F: Future>(&self, f: F) -> F::Output {
+        self.handle.block_on(f)
+    }
+}
+
+/// Run the synchronous `f` on the blocking pool, for the filesystem and
+/// providers to be called from a future. `EIO` if it panics.
+pub async fn blocking<T, F>(f: F) -> Result<T, c_int>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, c_int> + Send + 'static,
+{
+    task::spawn_blocking(f).await.unwrap_or(Err(EIO))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Sender};
+
+    use libc::{c_int, EIO, ENOENT};
+
+    use super::{blocking, Bridge, Respond};
+    use crate::options::RuntimeOptions;
+    use crate::runtime::Background;
+
+    impl Respond for Sender<Result<u32, c_int>> {
+        type Value = u32;
+
+        fn respond(self, result: Result<u32, c_int>) {
+            self.send(result).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reply() {
+        let background = Background::new(&RuntimeOptions::Dedicated {
+            worker_threads: 1,
+            blocking_threads: 1,
+        })
+        .unwrap();
+        let bridge = Bridge::new(background.handle().clone());
+        let (tx, rx) = mpsc::channel();
+        bridge.reply(tx.clone(), blocking(|| Ok(7)));
+        assert_eq!(rx.recv().unwrap(), Ok(7));
+        bridge.reply(tx.clone(), async { Err(ENOENT) });
+        assert_eq!(rx.recv().unwrap(), Err(ENOENT));
+        bridge.reply(tx, blocking(|| panic!("failed")));
+        assert_eq!(rx.recv().unwrap(), Err(EIO));
+    }
+}
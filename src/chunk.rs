@@ -1,10 +1,12 @@
+use crate::governor::{Reservation, GOVERNOR};
 use crate::id::Id;
 use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
 use std::array;
 use std::cmp::min;
 use std::convert::{TryFrom, TryInto};
+use std::future::Future;
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Range};
 use std::pin::Pin;
 use std::task::{Context, Poll, Waker};
 use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
@@ -63,11 +65,22 @@ impl TryFrom<&[u8]> for Block {
     }
 }
 
+/// Blocks of `data`, a whole number of blocks long.
+pub fn split(data: &[u8]) -> Result<Vec<Block>, ChunkError> {
+    if data.len() % BLOCK_SIZE != 0 {
+        return Err(ChunkError::InvalidLength(data.len()));
+    }
+    let blocks: Result<Vec<Block>, BlockError> =
+        data.chunks_exact(BLOCK_SIZE).map(Block::try_from).collect();
+    Ok(blocks?)
+}
+
 /// Chunk is the minimum storage unit with size of 4MiB.
 pub struct Chunk {
     id: Id,
     data: Box<[RwLock<Block>; BLOCK_PER_CHUNK]>,
     subscriber: Mutex<Vec<Waker>>,
+    _memory: Reservation,
 }
 
 /// A Writer for `Chunk`
@@ -86,6 +99,7 @@ pub struct ChunkReader<'a> {
 impl Chunk {
     /// Build a chunk with initialized blocks with zero.
     pub fn new(id: Id) -> Self {
+        let memory = GOVERNOR.reserve(CHUNK_SIZE);
         let data: Box<[RwLock<Block>]> = (0..BLOCK_PER_CHUNK)
             .map(|_| RwLock::new(Block::default()))
             .collect();
@@ -94,6 +108,7 @@ impl Chunk {
             id,
             data,
             subscriber: Default::default(),
+            _memory: memory,
         }
     }
 
@@ -104,6 +119,8 @@ impl Chunk {
             id,
             data: data.try_into().unwrap(),
             subscriber: Default::default(),
+            // already allocated
+            _memory: GOVERNOR.track(CHUNK_SIZE),
         }
     }
 
@@ -111,15 +128,13 @@ impl Chunk {
         if blocks.len() != CHUNK_SIZE {
             return Err(ChunkError::InvalidLength(blocks.len()));
         }
-        let blocks: Result<Vec<Block>, BlockError> = blocks
-            .chunks_exact(CHUNK_SIZE)
-            .map(Block::try_from)
-            .collect();
-        let blocks: Box<[RwLock<Block>]> = blocks?.into_iter().map(RwLock::new).collect();
+        let memory = GOVERNOR.reserve(CHUNK_SIZE);
+        let blocks: Box<[RwLock<Block>]> = split(&blocks)?.into_iter().map(RwLock::new).collect();
         Ok(Self {
             id,
             data: blocks.try_into().unwrap(),
             subscriber: Default::default(),
+            _memory: memory,
         })
     }
 
@@ -135,9 +150,80 @@ impl Chunk {
         ChunkReader::new(self)
     }
 
+    /// Read from `offset` into `buf`, will block on blocks being written.
+    /// Returns the number of bytes read.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while read < buf.len() && offset + read < CHUNK_SIZE {
+            let block_idx = (offset + read) / BLOCK_SIZE;
+            let block_offset = (offset + read) % BLOCK_SIZE;
+            let read_in = min(BLOCK_SIZE - block_offset, buf.len() - read);
+            let block = self.data[block_idx].read();
+            buf[read..read + read_in].copy_from_slice(&block[block_offset..block_offset + read_in]);
+            read += read_in;
+        }
+        read
+    }
+
+    /// Write `buf` at `offset`, locking one block at a time.
+    /// Returns the number of bytes written.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let mut written = 0;
+        while written < buf.len() && offset + written < CHUNK_SIZE {
+            let block_idx = (offset + written) / BLOCK_SIZE;
+            let block_offset = (offset + written) % BLOCK_SIZE;
+            let write_in = min(BLOCK_SIZE - block_offset, buf.len() - written);
+            let mut block = self.data[block_idx].write();
+            block[block_offset..block_offset + write_in]
+                .copy_from_slice(&buf[written..written + write_in]);
+            written += write_in;
+        }
+        self.notify();
+        written
+    }
+
+    /// Blocks `blocks` of the chunk, taken out without copy.
+    pub fn into_blocks(self, blocks: Range<usize>) -> Vec<Block> {
+        let all: Box<[Block; BLOCK_PER_CHUNK]> = self.into();
+        let mut all = Vec::from(all as Box<[Block]>);
+        all.drain(blocks).collect()
+    }
+
+    /// Wait until no writer holds a block of the chunk, so it can be read
+    /// whole without blocking.
+    pub fn released(&self) -> Released<'_> {
+        Released { chunk: self }
+    }
+
     fn subscribe(&self, waker: Waker) {
         self.subscriber.lock().push(waker)
     }
+
+    /// Wake readers waiting on blocks being written.
+    fn notify(&self) {
+        let mut subscriber = self.subscriber.lock();
+        subscriber.iter().for_each(|s| s.wake_by_ref());
+        subscriber.truncate(0);
+    }
+}
+
+/// Future of `Chunk::released`.
+pub struct Released<'a> {
+    chunk: &'a Chunk,
+}
+
+impl Future for Released<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let chunk = self.chunk;
+        // subscribed before checking, so a block released meanwhile wakes it
+        chunk.subscribe(cx.waker().clone());
+        match chunk.data.iter().all(|b| b.try_read_recursive().is_some()) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
 }
 
 /// Build blocks from a existing chunk without copy its content.
@@ -187,9 +273,7 @@ impl<'a> ChunkWriter<'a> {
 
         // buf full
         if buf.len() == write_in {
-            let mut subscriber = self.chunk.subscriber.lock();
-            subscriber.iter().for_each(|s| s.wake_by_ref());
-            subscriber.truncate(0);
+            self.chunk.notify();
             Ok(acc + write_in)
         } else {
             self.write(&buf[write_in..], acc + write_in)
@@ -197,6 +281,14 @@ impl<'a> ChunkWriter<'a> {
     }
 }
 
+impl Drop for ChunkWriter<'_> {
+    /// Release the blocks left, waking readers waiting on them.
+    fn drop(&mut self) {
+        self.guards.iter_mut().for_each(|guard| *guard = None);
+        self.chunk.notify();
+    }
+}
+
 impl<'a> ChunkReader<'a> {
     pub fn new(chunk: &'a Chunk) -> Self {
         Self { chunk, ptr: 0 }
@@ -309,6 +401,7 @@ impl<'a> AsyncWrite for ChunkWriter<'a> {
             .filter(|block| block.is_some())
             .for_each(|block| *block = None);
         writer.ptr = CHUNK_SIZE;
+        writer.chunk.notify();
         Poll::Ready(Ok(()))
     }
 }
@@ -320,12 +413,20 @@ impl<'a> AsyncRead for ChunkReader<'a> {
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
         let reader = self.get_mut();
+        // EOF
+        if reader.ptr == CHUNK_SIZE {
+            return Poll::Ready(Ok(()));
+        }
         // currently we cannot incrementally initialize `ReadBuf`
-        match reader.try_read(buf.initialize_unfilled(), 0) {
-            Ok(0) => {
-                reader.chunk.subscribe(cx.waker().clone());
-                Poll::Pending
-            }
+        let mut read = reader.try_read(buf.initialize_unfilled(), 0);
+        if let Ok(0) = read {
+            // subscribed before trying again, so a block released meanwhile
+            // wakes it
+            reader.chunk.subscribe(cx.waker().clone());
+            read = reader.try_read(buf.initialize_unfilled(), 0);
+        }
+        match read {
+            Ok(0) => Poll::Pending,
             Ok(n) => {
                 buf.set_filled(buf.filled().len() + n);
                 Poll::Ready(Ok(()))
@@ -348,7 +449,7 @@ impl<'a> AsyncSeek for ChunkReader<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Chunk, BLOCK_PER_CHUNK, BLOCK_SIZE};
+    use super::{Chunk, BLOCK_PER_CHUNK, BLOCK_SIZE, CHUNK_SIZE};
     use crate::id::Id;
     use std::io::Write;
 
@@ -366,4 +467,18 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_read_write_at() {
+        let chunk = Chunk::new(Id::new_random());
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| i as u8).collect();
+        assert_eq!(chunk.write_at(BLOCK_SIZE / 2, &data), data.len());
+        let mut buf = vec![0; data.len()];
+        assert_eq!(chunk.read_at(BLOCK_SIZE / 2, &mut buf), data.len());
+        assert_eq!(buf, data);
+        // stop at the end of chunk
+        assert_eq!(chunk.write_at(CHUNK_SIZE - 10, &data), 10);
+        assert_eq!(chunk.read_at(CHUNK_SIZE - 10, &mut buf), 10);
+        assert_eq!(&buf[..10], &data[..10]);
+    }
 }
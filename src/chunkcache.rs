@@ -0,0 +1,448 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::chunk::{Chunk, CHUNK_SIZE};
+use crate::governor::{self, Reservation, GOVERNOR};
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+/// A chunk held by the cache.
+struct Cached {
+    /// Shared with readers, replaced as a whole when modified
+    data: Arc<Vec<u8>>,
+    /// Modified since read from or written to the provider
+    dirty: bool,
+    tick: u64,
+    /// Order of the last modification
+    seq: u64,
+    /// When it became dirty
+    dirtied: Option<Instant>,
+    _memory: Reservation,
+}
+
+/// ChunkCache holds recently used chunks in memory within a byte budget.
+/// Least recently used clean chunks are evicted first, dirty chunks are
+/// written back to the provider before they are evicted.
+/// Dirty chunks are always written back in the order they were last
+/// modified, so the provider never holds a chunk without all chunks
+/// modified before, e.g. a journal record without the data it refers to.
+/// Pinned chunks are never evicted, even beyond the budget, so they are
+/// held to half of it.
+pub struct ChunkCache {
+    budget: usize,
+    tick: u64,
+    seq: u64,
+    chunks: HashMap<Id, Cached>,
+    /// Cached chunks ordered by last use
+    order: BTreeMap<u64, Id>,
+    pinned: HashSet<Id>,
+}
+
+impl ChunkCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            tick: 0,
+            seq: 0,
+            chunks: HashMap::new(),
+            order: BTreeMap::new(),
+            pinned: HashSet::new(),
+        }
+    }
+
+    /// Data of chunk `id` if cached, marked used.
+    pub fn get(&mut self, id: &Id) -> Option<Arc<Vec<u8>>> {
+        self.touch(id);
+        self.chunks.get(id).map(|cached| cached.data.clone())
+    }
+
+    /// Whether chunk `id` is cached, without marking it used.
+    pub fn contains(&self, id: &Id) -> bool {
+        self.chunks.contains_key(id)
+    }
+
+    /// Whether chunk `id` is cached with modifications not written back.
+    pub fn is_dirty(&self, id: &Id) -> bool {
+        self.chunks.get(id).map_or(false, |cached| cached.dirty)
+    }
+
+    /// Cache `data` of chunk `id`, `dirty` if it is not in the provider yet,
+    /// then evict chunks beyond the budget into `provider`.
+    pub fn insert(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        id: Id,
+        data: impl Into<Arc<Vec<u8>>>,
+        dirty: bool,
+    ) -> Result<(), ChunkProviderError> {
+        self.tick += 1;
+        self.seq += 1;
+        let dirtied = match self.chunks.get(&id) {
+            // still dirty since then
+            Some(old) if old.dirty && dirty => old.dirtied,
+            _ if dirty => Some(Instant::now()),
+            _ => None,
+        };
+        let cached = Cached {
+            data: data.into(),
+            dirty,
+            tick: self.tick,
+            seq: self.seq,
+            dirtied,
+            _memory: GOVERNOR.track(CHUNK_SIZE),
+        };
+        if let Some(old) = self.chunks.insert(id.clone(), cached) {
+            self.order.remove(&old.tick);
+        }
+        self.order.insert(self.tick, id);
+        self.evict(provider)
+    }
+
+    /// Drop chunk `id` without writing it back.
+    pub fn remove(&mut self, id: &Id) {
+        if let Some(cached) = self.chunks.remove(id) {
+            self.order.remove(&cached.tick);
+        }
+    }
+
+    /// Drop the clean chunks not pinned.
+    pub fn drop_clean(&mut self) {
+        let clean: Vec<Id> = self
+            .chunks
+            .iter()
+            .filter(|(id, cached)| !cached.dirty && !self.pinned.contains(*id))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in clean.iter() {
+            self.remove(id);
+        }
+    }
+
+    /// Keep chunk `id` once cached, or stop keeping it if not `pinned`.
+    pub fn pin(&mut self, id: Id, pinned: bool) {
+        if pinned {
+            self.pinned.insert(id);
+        } else {
+            self.pinned.remove(&id);
+        }
+    }
+
+    /// Whether chunks `ids` can be pinned along with those already, within
+    /// half of the budget.
+    pub fn pinnable(&self, ids: &[Id]) -> bool {
+        let added = ids.iter().filter(|id| !self.pinned.contains(*id)).count();
+        (self.pinned.len() + added) * CHUNK_SIZE <= self.budget / 2
+    }
+
+    /// Ids of at most `n` chunks cached, most recently used first.
+    pub fn recent(&self, n: usize) -> Vec<Id> {
+        self.order.values().rev().take(n).cloned().collect()
+    }
+
+    /// Ids of chunks not written back yet, in the order last modified.
+    pub fn dirty(&self) -> Vec<Id> {
+        let mut dirty: Vec<_> = self
+            .chunks
+            .iter()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(id, cached)| (cached.seq, id.clone()))
+            .collect();
+        dirty.sort();
+        dirty.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Write back all dirty chunks to `provider`.
+    /// Returns the number of chunks written.
+    pub fn flush(&mut self, provider: &dyn ChunkProvider) -> Result<usize, ChunkProviderError> {
+        self.write_back_through(provider, u64::MAX)
+    }
+
+    /// Write back dirty chunks while more than `max_bytes` are dirty, and
+    /// those dirty for longer than `max_age` along with all modified before.
+    /// Returns the number of chunks written.
+    pub fn write_back_due(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        max_bytes: usize,
+        max_age: Duration,
+    ) -> Result<usize, ChunkProviderError> {
+        let dirty: Vec<&Cached> = self.chunks.values().filter(|c| c.dirty).collect();
+        let mut seqs: Vec<u64> = dirty.iter().map(|cached| cached.seq).collect();
+        seqs.sort_unstable();
+        // the oldest beyond the threshold
+        let excess = (seqs.len() * CHUNK_SIZE).saturating_sub(max_bytes);
+        let mut through = match (excess + CHUNK_SIZE - 1) / CHUNK_SIZE {
+            0 => 0,
+            n => seqs[n - 1],
+        };
+        for cached in dirty.iter() {
+            if cached.dirtied.map_or(false, |at| at.elapsed() >= max_age) {
+                through = through.max(cached.seq);
+            }
+        }
+        self.write_back_through(provider, through)
+    }
+
+    /// Write back dirty chunks that became dirty at `point` or before, along
+    /// with all modified before. Returns the number of chunks written.
+    pub fn write_back_before(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        point: Instant,
+    ) -> Result<usize, ChunkProviderError> {
+        let through = self
+            .chunks
+            .values()
+            .filter(|cached| cached.dirtied.map_or(false, |at| at <= point))
+            .map(|cached| cached.seq)
+            .max()
+            .unwrap_or(0);
+        self.write_back_through(provider, through)
+    }
+
+    /// Evict chunks of at least `bytes` if possible, as when over budget.
+    pub fn shrink(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        bytes: usize,
+    ) -> Result<(), ChunkProviderError> {
+        self.evict_to(provider, self.size().saturating_sub(bytes))
+    }
+
+    /// Change the budget, evicting chunks beyond it into `provider`.
+    pub fn set_budget(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        budget: usize,
+    ) -> Result<(), ChunkProviderError> {
+        self.budget = budget;
+        self.evict(provider)
+    }
+
+    /// Bytes of chunks cached.
+    pub fn size(&self) -> usize {
+        self.chunks.len() * CHUNK_SIZE
+    }
+
+    /// Bytes of dirty chunks cached.
+    pub fn dirty_size(&self) -> usize {
+        self.chunks.values().filter(|cached| cached.dirty).count() * CHUNK_SIZE
+    }
+
+    /// Bytes of dirty chunks cached among `ids`.
+    pub fn dirty_size_of(&self, ids: &[Id]) -> usize {
+        let ids: HashSet<&Id> = ids.iter().collect();
+        ids.into_iter().filter(|id| self.is_dirty(id)).count() * CHUNK_SIZE
+    }
+
+    /// When the chunk dirty for the longest became dirty.
+    pub fn oldest_dirty(&self) -> Option<Instant> {
+        self.chunks
+            .values()
+            .filter_map(|cached| cached.dirtied)
+            .min()
+    }
+
+    fn touch(&mut self, id: &Id) {
+        if let Some(cached) = self.chunks.get_mut(id) {
+            self.tick += 1;
+            self.order.remove(&cached.tick);
+            cached.tick = self.tick;
+            self.order.insert(self.tick, id.clone());
+        }
+    }
+
+    /// Write dirty chunks last modified up to `seq` to `provider` in order,
+    /// they are clean then. Returns the number of chunks written.
+    fn write_back_through(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        seq: u64,
+    ) -> Result<usize, ChunkProviderError> {
+        // frees memory, never waits for it holding the cache
+        governor::without_stall(|| {
+            let mut written = 0;
+            for id in self.dirty() {
+                let cached = self.chunks.get_mut(&id).unwrap();
+                if cached.seq > seq {
+                    break;
+                }
+                let chunk = Chunk::new_with_data(id, cached.data.to_vec())?;
+                provider.save_chunk(&chunk)?;
+                cached.dirty = false;
+                cached.dirtied = None;
+                written += 1;
+            }
+            Ok(written)
+        })
+    }
+
+    /// Evict the least recently used chunks beyond the budget.
+    fn evict(&mut self, provider: &dyn ChunkProvider) -> Result<(), ChunkProviderError> {
+        self.evict_to(provider, self.budget)
+    }
+
+    /// Evict the least recently used chunks beyond `budget`, clean ones
+    /// first but the one just used, pinned ones never.
+    fn evict_to(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        budget: usize,
+    ) -> Result<(), ChunkProviderError> {
+        while self.size() > budget {
+            let (chunks, pinned) = (&self.chunks, &self.pinned);
+            let newest = self.tick;
+            let mut unpinned = self.order.iter().filter(|(_, id)| !pinned.contains(*id));
+            let oldest_clean = unpinned
+                .clone()
+                .find(|(tick, id)| **tick != newest && !chunks[*id].dirty);
+            let id = match oldest_clean.or_else(|| unpinned.next()).map(|(_, id)| id) {
+                Some(id) => id.clone(),
+                None => break,
+            };
+            let cached = &self.chunks[&id];
+            if cached.dirty {
+                let seq = cached.seq;
+                self.write_back_through(provider, seq)?;
+            }
+            self.remove(&id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChunkCache;
+    use crate::chunk::CHUNK_SIZE;
+    use crate::id::Id;
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_evict() {
+        let provider = MemoryProvider::new();
+        let mut cache = ChunkCache::new(2 * CHUNK_SIZE);
+        let (a, b, c) = (Id::new_random(), Id::new_random(), Id::new_random());
+        cache
+            .insert(&provider, a.clone(), vec![1; CHUNK_SIZE], true)
+            .unwrap();
+        cache
+            .insert(&provider, b.clone(), vec![2; CHUNK_SIZE], false)
+            .unwrap();
+        assert!(cache.get(&a).is_some());
+
+        // b is clean, evicted before the older dirty a
+        cache
+            .insert(&provider, c.clone(), vec![3; CHUNK_SIZE], true)
+            .unwrap();
+        assert!(cache.get(&b).is_none());
+        assert!(!provider.contains_chunk(&a).unwrap());
+
+        // a is written back before evicted
+        cache
+            .insert(&provider, b.clone(), vec![2; CHUNK_SIZE], false)
+            .unwrap();
+        assert!(cache.get(&a).is_none());
+        assert!(provider.contains_chunk(&a).unwrap());
+        assert_eq!(cache.dirty(), vec![c.clone()]);
+        assert_eq!(cache.flush(&provider).unwrap(), 1);
+        assert!(provider.contains_chunk(&c).unwrap());
+    }
+
+    #[test]
+    fn test_pinned() {
+        let provider = MemoryProvider::new();
+        let mut cache = ChunkCache::new(CHUNK_SIZE);
+        let (a, b) = (Id::new_random(), Id::new_random());
+        cache.pin(a.clone(), true);
+        cache
+            .insert(&provider, a.clone(), vec![1; CHUNK_SIZE], false)
+            .unwrap();
+        cache
+            .insert(&provider, b.clone(), vec![2; CHUNK_SIZE], false)
+            .unwrap();
+        // kept, the one just inserted is evicted instead
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&b).is_none());
+        assert!(!cache.pinnable(std::slice::from_ref(&b)));
+        assert!(ChunkCache::new(4 * CHUNK_SIZE).pinnable(&[a.clone(), b.clone()]));
+
+        cache.pin(a.clone(), false);
+        cache
+            .insert(&provider, b.clone(), vec![2; CHUNK_SIZE], false)
+            .unwrap();
+        assert!(cache.get(&a).is_none());
+    }
+
+    #[test]
+    fn test_write_back_in_order() {
+        let provider = MemoryProvider::new();
+        let mut cache = ChunkCache::new(4 * CHUNK_SIZE);
+        let ids: Vec<Id> = (0..3).map(|_| Id::new_random()).collect();
+        for id in ids.iter() {
+            cache
+                .insert(&provider, id.clone(), vec![0; CHUNK_SIZE], true)
+                .unwrap();
+        }
+        // modified again, last in order
+        cache
+            .insert(&provider, ids[0].clone(), vec![1; CHUNK_SIZE], true)
+            .unwrap();
+        assert_eq!(
+            cache.dirty(),
+            vec![ids[1].clone(), ids[2].clone(), ids[0].clone()]
+        );
+
+        let age = Duration::from_secs(60);
+        assert_eq!(
+            cache
+                .write_back_due(&provider, 2 * CHUNK_SIZE, age)
+                .unwrap(),
+            1
+        );
+        assert!(provider.contains_chunk(&ids[1]).unwrap());
+        // ids[0] is dirty the longest, everything before goes along
+        assert_eq!(
+            cache
+                .write_back_due(&provider, 4 * CHUNK_SIZE, Duration::from_secs(0))
+                .unwrap(),
+            2
+        );
+        assert_eq!(cache.dirty_size(), 0);
+    }
+
+    #[test]
+    fn test_write_back_before() {
+        let provider = MemoryProvider::new();
+        let mut cache = ChunkCache::new(4 * CHUNK_SIZE);
+        let (a, b) = (Id::new_random(), Id::new_random());
+        cache
+            .insert(&provider, a.clone(), vec![1; CHUNK_SIZE], true)
+            .unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let point = Instant::now();
+        cache
+            .insert(&provider, b.clone(), vec![2; CHUNK_SIZE], true)
+            .unwrap();
+        assert_eq!(cache.dirty_size_of(&[b.clone(), b.clone()]), CHUNK_SIZE);
+        assert!(cache.oldest_dirty().unwrap() <= point);
+
+        assert_eq!(cache.write_back_before(&provider, point).unwrap(), 1);
+        assert!(provider.contains_chunk(&a).unwrap());
+        assert!(!provider.contains_chunk(&b).unwrap());
+        // dirty again since after the point
+        cache
+            .insert(&provider, a.clone(), vec![3; CHUNK_SIZE], true)
+            .unwrap();
+        assert_eq!(cache.write_back_before(&provider, point).unwrap(), 0);
+        assert_eq!(
+            cache.write_back_before(&provider, Instant::now()).unwrap(),
+            2
+        );
+        assert_eq!(cache.oldest_dirty(), None);
+    }
+}
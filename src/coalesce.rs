@@ -0,0 +1,94 @@
+use std::time::Instant;
+
+use crate::governor::{Reservation, GOVERNOR};
+
+/// WriteBuffer merges small sequential writes through a file handle, so
+/// they are written to chunks at once instead of one lock per write.
+pub struct WriteBuffer {
+    /// Inode written through the handle
+    pub ino: u64,
+    offset: u64,
+    data: Vec<u8>,
+    capacity: usize,
+    /// When the first write buffered was
+    dirtied: Option<Instant>,
+    _memory: Reservation,
+}
+
+impl WriteBuffer {
+    /// Buffer at most `capacity` bytes written to inode `ino`.
+    pub fn new(ino: u64, capacity: usize) -> Self {
+        Self {
+            ino,
+            offset: 0,
+            _memory: GOVERNOR.reserve(capacity),
+            data: Vec::with_capacity(capacity),
+            capacity,
+            dirtied: None,
+        }
+    }
+
+    /// Append `data` written at `offset` if it follows the writes buffered
+    /// and fits. Returns whether appended.
+    pub fn append(&mut self, offset: u64, data: &[u8]) -> bool {
+        if self.data.is_empty() {
+            self.offset = offset;
+        } else if offset != self.offset + self.data.len() as u64 {
+            return false;
+        }
+        if self.data.len() + data.len() > self.capacity {
+            return false;
+        }
+        self.data.extend_from_slice(data);
+        self.dirtied.get_or_insert_with(Instant::now);
+        true
+    }
+
+    /// Whether no more writes are buffered until taken.
+    pub fn is_full(&self) -> bool {
+        self.data.len() == self.capacity
+    }
+
+    /// Bytes of the writes buffered.
+    pub fn buffered(&self) -> usize {
+        self.data.len()
+    }
+
+    /// When the first of the writes buffered was, counted as dirty since.
+    pub fn dirtied(&self) -> Option<Instant> {
+        self.dirtied
+    }
+
+    /// Take the writes buffered, as the offset and data to write.
+    pub fn take(&mut self) -> Option<(u64, Vec<u8>)> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let data = std::mem::replace(&mut self.data, Vec::with_capacity(self.capacity));
+        self.dirtied = None;
+        Some((self.offset, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WriteBuffer;
+
+    #[test]
+    fn test_merge() {
+        let mut buffer = WriteBuffer::new(2, 4);
+        assert!(buffer.append(10, b"a"));
+        assert!(buffer.append(11, b"bc"));
+        // not following, or beyond the capacity
+        assert!(!buffer.append(20, b"d"));
+        assert!(!buffer.append(13, b"de"));
+        assert!(buffer.append(13, b"d"));
+        assert!(buffer.is_full());
+        assert_eq!(buffer.buffered(), 4);
+        assert!(buffer.dirtied().is_some());
+        assert_eq!(buffer.take(), Some((10, b"abcd".to_vec())));
+        assert_eq!(buffer.take(), None);
+        assert_eq!(buffer.dirtied(), None);
+        assert!(buffer.append(20, b"e"));
+    }
+}
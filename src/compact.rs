@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+
+use crate::allocator::TinyFileAllocator;
+use crate::fs::{DirMeta, TinyFileError};
+use crate::id::Id;
+use crate::meta::MetaError;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+#[derive(thiserror::Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum CompactError {
+    #[error(transparent)]
+    TinyFileError(#[from] TinyFileError),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+    #[error(transparent)]
+    ProviderError(#[from] ChunkProviderError),
+}
+
+/// What a compaction has done.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompactStats {
+    /// Shared chunks deleted
+    pub chunks: usize,
+    /// Tiny files relocated
+    pub files: usize,
+}
+
+/// Rewrite shared chunks with less than `threshold` of their blocks used:
+/// relocate tiny files in them into denser chunks, then delete them.
+#[cfg(test)]
+pub fn compact(
+    root: &mut DirMeta,
+    provider: &dyn ChunkProvider,
+    allocator: &mut TinyFileAllocator,
+    threshold: f64,
+) -> Result<CompactStats, CompactError> {
+    let (sparse, stats) = relocate(root, provider, allocator, threshold)?;
+    for id in sparse.iter() {
+        provider.delete_chunk(id)?;
+    }
+    Ok(stats)
+}
+
+/// Relocate tiny files out of shared chunks with less than `threshold` of
+/// their blocks used. Returns the emptied chunks, to be deleted once the
+/// relocated files are persisted.
+pub fn relocate(
+    root: &mut DirMeta,
+    provider: &dyn ChunkProvider,
+    allocator: &mut TinyFileAllocator,
+    threshold: f64,
+) -> Result<(Vec<Id>, CompactStats), TinyFileError> {
+    let mut sparse: HashSet<Id> = allocator.sparse_chunks(threshold).into_iter().collect();
+    // a single sparse chunk cannot get any denser, unless it is empty
+    if sparse.len() == 1 {
+        sparse.retain(|id| allocator.fat(id).map_or(true, |fat| fat.used() == 0));
+    }
+    let mut stats = CompactStats::default();
+    if sparse.is_empty() {
+        return Ok((Vec::new(), stats));
+    }
+    // relocated files must not land in sparse chunks again
+    for id in sparse.iter() {
+        allocator.retire(id);
+    }
+    root.visit_tiny_files_mut(&mut |file| {
+        if file.chunk_blocks > 0 && sparse.contains(&file.chunk_id) {
+            file.relocate(provider, allocator, file.chunk_blocks)?;
+            stats.files += 1;
+        }
+        Ok::<_, TinyFileError>(())
+    })?;
+    stats.chunks = sparse.len();
+    Ok((sparse.into_iter().collect(), stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compact;
+    use crate::allocator::{TinyFileAllocator, SHARED_BLOCKS};
+    use crate::chunk::BLOCK_SIZE;
+    use crate::fs::{Attrs, DirMeta, Entry, Node, TinyFileMeta};
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_compact() {
+        let provider = MemoryProvider::new();
+        let mut allocator = TinyFileAllocator::new();
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        // fill up the first chunk so that the last file goes to another one
+        let sizes = [100, (SHARED_BLOCKS - 1) * BLOCK_SIZE, 100];
+        for (i, size) in sizes.iter().enumerate() {
+            let mut file = TinyFileMeta::new(i.to_string(), Attrs::new(0o644, 0, 0));
+            file.write(&provider, &mut allocator, 0, &vec![i as u8; *size])
+                .unwrap();
+            root.insert(Node::TinyFile(file));
+        }
+        let chunk_of = |root: &DirMeta, name: &str| match root.lookup(name) {
+            Some(Entry::TinyFile(file)) => file.chunk_id.clone(),
+            _ => panic!("{} not found", name),
+        };
+        assert_ne!(chunk_of(&root, "0"), chunk_of(&root, "2"));
+        if let Some(Node::TinyFile(mut file)) = root.remove("1") {
+            file.free(&mut allocator);
+        }
+
+        let stats = compact(&mut root, &provider, &mut allocator, 0.5).unwrap();
+        assert_eq!(stats.chunks, 2);
+        assert_eq!(stats.files, 2);
+        assert_eq!(chunk_of(&root, "0"), chunk_of(&root, "2"));
+        for (file, i) in root.tiny_files().zip([0u8, 2].iter()) {
+            let mut buf = [0; 100];
+            file.read(&provider, 0, &mut buf).unwrap();
+            assert_eq!(buf, [*i; 100]);
+        }
+        // nothing to do with a single sparse chunk
+        let stats = compact(&mut root, &provider, &mut allocator, 0.5).unwrap();
+        assert_eq!(stats.chunks, 0);
+    }
+}
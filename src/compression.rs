@@ -0,0 +1,230 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::chunk::CHUNK_SIZE;
+use crate::fs::{Attrs, DirMeta, COMPRESSION_FL};
+
+/// Extended attribute setting the compression of an entry, which files
+/// beneath a directory inherit unless set on their own.
+pub const COMPRESSION_XATTR: &str = "user.eoss.compression";
+/// Level of zstd unless given.
+const ZSTD_LEVEL: u8 = 3;
+const ZSTD_MAX_LEVEL: u8 = 22;
+/// Offset of the compression within the flags of an entry.
+const COMPRESSION_SHIFT: u32 = COMPRESSION_FL.trailing_zeros();
+/// Bit of the compression within the flags marking zstd, below which the
+/// level is kept.
+const ZSTD_BIT: u32 = 0x40;
+/// First byte of a chunk stored compressed, telling how.
+const LZ4_TAG: u8 = 1;
+const ZSTD_TAG: u8 = 2;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CompressionError {
+    #[error("unknown compression {0}")]
+    UnknownCompression(String),
+    #[error("invalid compression rule {0}, expected <extension>=<compression>")]
+    InvalidRule(String),
+}
+
+/// How chunks of a file are compressed by the provider storing them,
+/// `None` for data compressed already such as media.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    /// At a level from 1 to 22.
+    Zstd(u8),
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Lz4 => f.write_str("lz4"),
+            Compression::Zstd(level) => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = CompressionError;
+
+    /// Parse `none`, `lz4`, `zstd` or `zstd:<level>`.
+    fn from_str(s: &str) -> Result<Self, CompressionError> {
+        let unknown = || CompressionError::UnknownCompression(s.to_owned());
+        match s.split_once(':') {
+            None if s == "none" => Ok(Compression::None),
+            None if s == "lz4" => Ok(Compression::Lz4),
+            None if s == "zstd" => Ok(Compression::Zstd(ZSTD_LEVEL)),
+            Some(("zstd", level)) => match level.parse() {
+                Ok(level) if (1..=ZSTD_MAX_LEVEL).contains(&level) => Ok(Compression::Zstd(level)),
+                _ => Err(unknown()),
+            },
+            _ => Err(unknown()),
+        }
+    }
+}
+
+/// Compression of files whose name ends with `.<extension>`, unless set
+/// on them or a directory above.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompressionRule {
+    pub extension: String,
+    pub compression: Compression,
+}
+
+impl FromStr for CompressionRule {
+    type Err = CompressionError;
+
+    /// Parse `<extension>=<compression>`, such as `mp4=none`.
+    fn from_str(s: &str) -> Result<Self, CompressionError> {
+        match s.split_once('=') {
+            Some((extension, compression)) if !extension.is_empty() => Ok(Self {
+                extension: extension.trim_start_matches('.').to_ascii_lowercase(),
+                compression: compression.parse()?,
+            }),
+            _ => Err(CompressionError::InvalidRule(s.to_owned())),
+        }
+    }
+}
+
+impl Attrs {
+    /// Compression set on the entry itself.
+    pub fn compression(&self) -> Option<Compression> {
+        match (self.flags & COMPRESSION_FL) >> COMPRESSION_SHIFT {
+            0 => None,
+            1 => Some(Compression::None),
+            2 => Some(Compression::Lz4),
+            bits => Some(Compression::Zstd((bits & !ZSTD_BIT) as u8)),
+        }
+    }
+
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        let bits = match compression {
+            None => 0,
+            Some(Compression::None) => 1,
+            Some(Compression::Lz4) => 2,
+            Some(Compression::Zstd(level)) => ZSTD_BIT | level.min(ZSTD_MAX_LEVEL) as u32,
+        };
+        self.flags = self.flags & !COMPRESSION_FL | bits << COMPRESSION_SHIFT;
+    }
+}
+
+/// `data` of a chunk compressed as `compression` and tagged with it, or
+/// `None` if to be stored as it is, not shrinking below a chunk.
+pub fn compress(compression: Compression, data: &[u8]) -> Option<Vec<u8>> {
+    let (tag, compressed) = match compression {
+        Compression::None => return None,
+        Compression::Lz4 => (LZ4_TAG, lz4_flex::compress_prepend_size(data)),
+        Compression::Zstd(level) => (ZSTD_TAG, zstd::bulk::compress(data, level as i32).ok()?),
+    };
+    let mut stored = vec![tag];
+    stored.extend(compressed);
+    Some(stored).filter(|stored| stored.len() < CHUNK_SIZE)
+}
+
+/// Data of a chunk stored by `compress`.
+pub fn decompress(stored: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let data = match stored.split_first() {
+        Some((&LZ4_TAG, data)) => {
+            lz4_flex::decompress_size_prepended(data).map_err(|e| invalid(e.to_string()))?
+        }
+        Some((&ZSTD_TAG, data)) => zstd::bulk::decompress(data, CHUNK_SIZE)?,
+        _ => return Err(invalid("unknown compression".to_owned())),
+    };
+    match data.len() {
+        CHUNK_SIZE => Ok(data),
+        _ => Err(invalid("not a chunk".to_owned())),
+    }
+}
+
+/// Compression of the file at `path` of the tree at `root`, set on the
+/// file or else inherited from the nearest directory above with one, or
+/// else by the first of `rules` matching its extension.
+pub fn resolve<S: AsRef<str>>(
+    root: &DirMeta,
+    path: &[S],
+    rules: &[CompressionRule],
+) -> Option<Compression> {
+    let set = (0..=path.len())
+        .rev()
+        .find_map(|n| root.resolve(&path[..n])?.attrs().compression());
+    set.or_else(|| {
+        let name = path.last()?.as_ref();
+        let (_, extension) = name.rsplit_once('.')?;
+        rules
+            .iter()
+            .find(|rule| rule.extension.eq_ignore_ascii_case(extension))
+            .map(|rule| rule.compression)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Compression, CompressionError, CompressionRule};
+    use crate::fs::{Attrs, DirMeta, FileMeta, Node, PINNED_FL};
+    use crate::id::Id;
+    use crate::options::MountOptions;
+
+    #[test]
+    fn test_compression() {
+        assert_eq!("zstd".parse::<Compression>().unwrap(), Compression::Zstd(3));
+        assert_eq!(
+            "zstd:19".parse::<Compression>().unwrap(),
+            Compression::Zstd(19)
+        );
+        for invalid in &["zstd:0", "zstd:23", "gzip"] {
+            assert!(matches!(
+                invalid.parse::<Compression>(),
+                Err(CompressionError::UnknownCompression(_))
+            ));
+        }
+        let mut attrs = Attrs::new(0o644, 0, 0);
+        attrs.flags = PINNED_FL;
+        for compression in &[Compression::None, Compression::Lz4, Compression::Zstd(22)] {
+            attrs.set_compression(Some(*compression));
+            assert_eq!(attrs.compression(), Some(*compression));
+        }
+        attrs.set_compression(None);
+        assert_eq!((attrs.flags, attrs.compression()), (PINNED_FL, None));
+
+        assert!("=none".parse::<CompressionRule>().is_err());
+        let mut options = MountOptions::default();
+        options
+            .apply_config("compress = MP4=none\ncompress = .log=zstd:19 # text\n")
+            .unwrap();
+        let rules = options.compression_rules.clone();
+        // reloaded without any
+        options.apply_config("memory_limit = none\n").unwrap();
+        assert!(options.compression_rules.is_empty());
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut dir = DirMeta::new("raw".to_owned(), Attrs::new(0o755, 0, 0));
+        dir.attrs.set_compression(Some(Compression::Lz4));
+        for name in &["a.log", "b.mp4", "c.txt"] {
+            let file = FileMeta {
+                name: name.to_string(),
+                id: Id::new_random(),
+                attrs: Attrs::new(0o644, 0, 0),
+                hashes: Vec::new(),
+                content: false,
+            };
+            root.insert(Node::File(file.clone()));
+            dir.insert(Node::File(file));
+        }
+        root.insert(Node::Dir(dir));
+        assert_eq!(
+            resolve(&root, &["a.log"], &rules),
+            Some(Compression::Zstd(19))
+        );
+        assert_eq!(resolve(&root, &["b.mp4"], &rules), Some(Compression::None));
+        assert_eq!(resolve(&root, &["c.txt"], &rules), None);
+        // set on a directory above over rules
+        assert_eq!(
+            resolve(&root, &["raw", "b.mp4"], &rules),
+            Some(Compression::Lz4)
+        );
+    }
+}
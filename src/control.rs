@@ -0,0 +1,350 @@
+#[cfg(unix)]
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+use std::iter::Peekable;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::str::Chars;
+use std::sync::mpsc;
+#[cfg(unix)]
+use std::sync::Arc;
+#[cfg(unix)]
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+use crate::publish::escape;
+
+/// Interval the commands queued are run by a mounted filesystem in.
+pub const COMMAND_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ControlError {
+    #[error("malformed request, expected a JSON array of strings")]
+    Malformed,
+    #[error("unknown command {0}")]
+    UnknownCommand(String),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// A command to a mounted filesystem, sent to its control socket as a
+/// JSON array of words on a line, e.g. `["snapshot", "create", "daily"]`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    /// Usage of the filesystem.
+    Stats,
+    /// Write back everything buffered or cached, and persist the tree.
+    Flush,
+    /// Flush, then drop clean chunks and directories from memory.
+    DropCaches,
+    /// Pin the entry at a path in the chunk cache.
+    Pin(String),
+    /// Create a snapshot of the name.
+    Snapshot(String),
+    /// Delete unreferenced chunks.
+    Gc,
+    /// The heatmap of files accessed, as JSON.
+    Heatmap,
+    /// Write the chunks accessed most as a warm-up manifest to a file at
+    /// the path.
+    HeatmapWarmup(String),
+    /// Write back the chunks dirty since the milliseconds since the epoch
+    /// given or before, all if not given.
+    Barrier(Option<u64>),
+}
+
+impl Command {
+    pub fn parse(words: &[String]) -> Result<Self, ControlError> {
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        match words.as_slice() {
+            ["stats"] => Ok(Command::Stats),
+            ["flush"] => Ok(Command::Flush),
+            ["drop-caches"] => Ok(Command::DropCaches),
+            ["pin", path] => Ok(Command::Pin((*path).to_owned())),
+            ["snapshot", "create", name] => Ok(Command::Snapshot((*name).to_owned())),
+            ["gc"] => Ok(Command::Gc),
+            ["heatmap", "export"] => Ok(Command::Heatmap),
+            ["heatmap", "warmup", path] => Ok(Command::HeatmapWarmup((*path).to_owned())),
+            ["barrier"] => Ok(Command::Barrier(None)),
+            ["barrier", point] => match point.parse() {
+                Ok(point) => Ok(Command::Barrier(Some(point))),
+                Err(_) => Err(ControlError::UnknownCommand(words.join(" "))),
+            },
+            _ => Err(ControlError::UnknownCommand(words.join(" "))),
+        }
+    }
+}
+
+/// Value of a reply to a command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    Number(u64),
+    /// JSON on a line, answered as is
+    Json(String),
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Number(n)
+    }
+}
+
+/// Outcome of a command, named values on success, answered as a JSON
+/// object with `ok` set, or `ok` false and the `error`.
+pub type Reply = Result<Vec<(&'static str, Value)>, String>;
+
+/// A command queued, answered on its sender.
+pub type Queued = (Command, mpsc::Sender<Reply>);
+
+/// Mailbox queues commands from the control socket until the filesystem
+/// runs them.
+pub struct Mailbox {
+    /// Commands queued, none once closed
+    queue: Mutex<Option<Vec<Queued>>>,
+}
+
+impl Mailbox {
+    pub fn new() -> Self {
+        Self {
+            queue: Mutex::new(Some(Vec::new())),
+        }
+    }
+
+    /// Commands queued, to be answered on their sender.
+    pub fn take(&self) -> Vec<Queued> {
+        self.queue
+            .lock()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Refuse commands from now on, those queued answered as unmounted.
+    pub fn close(&self) {
+        self.queue.lock().take();
+    }
+
+    /// Queue `command` for the filesystem to run, and wait for its reply.
+    fn send(&self, command: Command) -> Reply {
+        let (sender, receiver) = mpsc::channel();
+        match self.queue.lock().as_mut() {
+            Some(queue) => queue.push((command, sender)),
+            // answered as unmounted once the sender is dropped
+            None => drop(sender),
+        }
+        receiver
+            .recv()
+            .unwrap_or_else(|_| Err("filesystem unmounted".to_owned()))
+    }
+}
+
+impl Default for Mailbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Time of milliseconds `ms` since the epoch, now if 0.
+pub fn barrier_point(ms: u64) -> SystemTime {
+    match ms {
+        0 => SystemTime::now(),
+        ms => UNIX_EPOCH + Duration::from_millis(ms),
+    }
+}
+
+/// Answer requests on a socket at `path`, accessible by its owner only,
+/// with commands run by the filesystem from `mailbox`, each connection on
+/// a thread of its own.
+#[cfg(unix)]
+pub fn serve(path: &Path, mailbox: Arc<Mailbox>) -> io::Result<()> {
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mailbox = mailbox.clone();
+            thread::spawn(move || answer(stream, &mailbox));
+        }
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+fn answer(stream: UnixStream, mailbox: &Mailbox) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let reply = match parse_words(&line).and_then(|words| Command::parse(&words)) {
+        Ok(command) => mailbox.send(command),
+        Err(e) => Err(e.to_string()),
+    };
+    (&stream).write_all(format!("{}\n", encode_reply(&reply)).as_bytes())
+}
+
+/// Send the command of `words` to the socket at `path`. Returns the reply,
+/// a line of JSON.
+#[cfg(unix)]
+pub fn request(path: &Path, words: &[String]) -> Result<String, ControlError> {
+    let stream = UnixStream::connect(path)?;
+    let words: Vec<String> = words.iter().map(|word| quote(word)).collect();
+    (&stream).write_all(format!("[{}]\n", words.join(",")).as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Ok(line.trim_end().to_owned())
+}
+
+fn encode_reply(reply: &Reply) -> String {
+    match reply {
+        Ok(values) => {
+            let mut json = String::from("{\"ok\":true");
+            for (name, value) in values {
+                match value {
+                    Value::Number(n) => json.push_str(&format!(",{}:{}", quote(name), n)),
+                    Value::Json(value) => json.push_str(&format!(",{}:{}", quote(name), value)),
+                }
+            }
+            json + "}"
+        }
+        Err(e) => format!("{{\"ok\":false,\"error\":{}}}", quote(e)),
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+/// Parse a JSON array of strings.
+fn parse_words(line: &str) -> Result<Vec<String>, ControlError> {
+    let inner = line
+        .trim()
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .ok_or(ControlError::Malformed)?;
+    let mut words = Vec::new();
+    let mut chars = inner.trim().chars().peekable();
+    while chars.peek().is_some() {
+        if chars.next() != Some('"') {
+            return Err(ControlError::Malformed);
+        }
+        let mut word = String::new();
+        loop {
+            match chars.next().ok_or(ControlError::Malformed)? {
+                '"' => break,
+                '\\' => match chars.next().ok_or(ControlError::Malformed)? {
+                    c @ ('"' | '\\' | '/') => word.push(c),
+                    'b' => word.push('\u{8}'),
+                    'f' => word.push('\u{c}'),
+                    'n' => word.push('\n'),
+                    'r' => word.push('\r'),
+                    't' => word.push('\t'),
+                    'u' => word.push(parse_escaped(&mut chars)?),
+                    _ => return Err(ControlError::Malformed),
+                },
+                c => word.push(c),
+            }
+        }
+        words.push(word);
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.next() {
+            Some(',') => {
+                while chars.peek().map_or(false, |c| c.is_whitespace()) {
+                    chars.next();
+                }
+            }
+            None => break,
+            Some(_) => return Err(ControlError::Malformed),
+        }
+    }
+    Ok(words)
+}
+
+/// Parse the code point of a `\\u` escape past the `u`, a surrogate pair
+/// taking two.
+fn parse_escaped(chars: &mut Peekable<Chars>) -> Result<char, ControlError> {
+    fn unit(chars: &mut Peekable<Chars>) -> Result<u32, ControlError> {
+        let hex: String = chars.by_ref().take(4).collect();
+        match hex.len() {
+            4 => u32::from_str_radix(&hex, 16).map_err(|_| ControlError::Malformed),
+            _ => Err(ControlError::Malformed),
+        }
+    }
+    let high = unit(chars)?;
+    if !(0xd800..0xdc00).contains(&high) {
+        return char::from_u32(high).ok_or(ControlError::Malformed);
+    }
+    if chars.next() != Some('\\') || chars.next() != Some('u') {
+        return Err(ControlError::Malformed);
+    }
+    let low = unit(chars)?;
+    if !(0xdc00..0xe000).contains(&low) {
+        return Err(ControlError::Malformed);
+    }
+    let code = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+    char::from_u32(code).ok_or(ControlError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_reply, parse_words, quote, Command, Mailbox, Value};
+
+    #[test]
+    fn test_protocol() {
+        let words = vec![
+            "snapshot".to_owned(),
+            "create".to_owned(),
+            "a \"b\"".to_owned(),
+        ];
+        let line = format!(
+            "[{}]",
+            words
+                .iter()
+                .map(|w| quote(w))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let parsed = parse_words(&line).unwrap();
+        assert_eq!(parsed, words);
+        assert_eq!(
+            Command::parse(&parsed).unwrap(),
+            Command::Snapshot("a \"b\"".to_owned())
+        );
+        assert!(parse_words("[\"gc\"").is_err());
+        assert_eq!(
+            parse_words(r#"["a\r\/b", "\ud83d\ude00"]"#).unwrap(),
+            vec!["a\r/b".to_owned(), "\u{1f600}".to_owned()]
+        );
+        assert!(parse_words(r#"["\ud83d"]"#).is_err());
+        assert!(parse_words(r#"["\x"]"#).is_err());
+        assert!(Command::parse(&["fly".to_owned()]).is_err());
+        assert_eq!(
+            Command::parse(&["barrier".to_owned(), "1000".to_owned()]).unwrap(),
+            Command::Barrier(Some(1000))
+        );
+        assert_eq!(
+            encode_reply(&Ok(vec![("deleted", 2.into())])),
+            "{\"ok\":true,\"deleted\":2}"
+        );
+        let heatmap = Value::Json("{\"files\":[]}".to_owned());
+        assert_eq!(
+            encode_reply(&Ok(vec![("heatmap", heatmap)])),
+            "{\"ok\":true,\"heatmap\":{\"files\":[]}}"
+        );
+        assert_eq!(
+            encode_reply(&Err("no".to_owned())),
+            "{\"ok\":false,\"error\":\"no\"}"
+        );
+        // refused once the filesystem is dropped
+        let mailbox = Mailbox::new();
+        mailbox.close();
+        assert!(mailbox.send(Command::Gc).is_err());
+    }
+}
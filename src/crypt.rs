@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use parking_lot::RwLock;
+use rand::{thread_rng, RngCore};
+
+use crate::chunk::{BLOCK_PER_CHUNK, BLOCK_SIZE, CHUNK_SIZE};
+use crate::id::Id;
+use crate::meta::{Decode, Encode, MetaError, Reader};
+
+pub const KEY_LENGTH: usize = 32;
+/// Random nonce stored ahead of each sealed block.
+pub const NONCE_LENGTH: usize = 24;
+/// Authentication tag stored after each sealed block.
+pub const TAG_LENGTH: usize = 16;
+/// Length of a block once sealed.
+pub const SEALED_BLOCK_SIZE: usize = NONCE_LENGTH + BLOCK_SIZE + TAG_LENGTH;
+/// Epoch of the key ahead of the sealed blocks of a chunk.
+pub const EPOCH_LENGTH: usize = 4;
+/// Epoch and mode ahead of the sealed blocks of a chunk.
+pub const HEADER_LENGTH: usize = EPOCH_LENGTH + 1;
+/// Content key of a convergent chunk wrapped after the header.
+pub const WRAPPED_LENGTH: usize = NONCE_LENGTH + KEY_LENGTH + TAG_LENGTH;
+
+/// Context of the key derivation checked at mount.
+const CHECK_CONTEXT: &str = "eoss-fuse 2021-05 master key check";
+/// Context deriving the secret salt of content keys from an epoch key.
+const CONVERGENCE_CONTEXT: &str = "eoss-fuse 2021-05 convergence salt";
+/// Context deriving the key wrapping content keys from an epoch key.
+const WRAPPING_CONTEXT: &str = "eoss-fuse 2021-05 content key wrapping";
+/// Context deriving the key wrapping retired keys from the master key.
+const RETIRED_CONTEXT: &str = "eoss-fuse 2021-05 retired key wrapping";
+
+#[derive(thiserror::Error, Debug)]
+pub enum CryptError {
+    #[error("invalid sealed length {0}")]
+    InvalidLength(usize),
+    #[error("block {1} of chunk {0} fails authentication")]
+    Forged(String, usize),
+    #[error("chunk {0} sealed under unknown key epoch {1}")]
+    UnknownEpoch(String, u32),
+    #[error("chunk {0} sealed in unknown mode {1}")]
+    UnknownMode(String, u8),
+    #[error("key of epoch {0} fails authentication")]
+    ForgedEpoch(u32),
+}
+
+/// How chunks are sealed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Under a key derived from the chunk id with random nonces, nothing
+    /// is learned from comparing chunks.
+    Random,
+    /// Under a key derived from the content with a secret salt, so equal
+    /// chunks are sealed alike and can be deduplicated, revealing which
+    /// chunks are equal but not their content. Sealed chunks are not bound
+    /// to their id, chunks of files being stored at the hash of their
+    /// content.
+    Convergent,
+}
+
+impl Mode {
+    fn tag(self) -> u8 {
+        match self {
+            Mode::Random => 0,
+            Mode::Convergent => 1,
+        }
+    }
+
+    /// Length of a chunk once sealed.
+    pub fn sealed_length(self) -> usize {
+        let wrapped = match self {
+            Mode::Random => 0,
+            Mode::Convergent => WRAPPED_LENGTH,
+        };
+        HEADER_LENGTH + wrapped + SEALED_BLOCK_SIZE * BLOCK_PER_CHUNK
+    }
+}
+
+/// MasterKey is the root of the key hierarchy of an encrypted filesystem,
+/// every chunk is sealed with its own key derived from it.
+#[derive(Clone)]
+pub struct MasterKey([u8; KEY_LENGTH]);
+
+impl MasterKey {
+    pub fn new(key: [u8; KEY_LENGTH]) -> Self {
+        Self(key)
+    }
+
+    pub fn new_random() -> Self {
+        let mut key = [0; KEY_LENGTH];
+        thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+        &self.0
+    }
+
+    /// Key sealing chunk `id`, so no two chunks share a key.
+    pub fn chunk_key(&self, id: &Id) -> [u8; KEY_LENGTH] {
+        *blake3::keyed_hash(&self.0, &**id).as_bytes()
+    }
+
+    /// Key sealing a convergent chunk holding `data`.
+    fn content_key(&self, data: &[u8]) -> [u8; KEY_LENGTH] {
+        let salt = blake3::derive_key(CONVERGENCE_CONTEXT, &self.0);
+        *blake3::keyed_hash(&salt, data).as_bytes()
+    }
+
+    fn wrapping_key(&self) -> [u8; KEY_LENGTH] {
+        blake3::derive_key(WRAPPING_CONTEXT, &self.0)
+    }
+
+    /// Value kept in the superblock to tell a wrong key at mount, reveals
+    /// nothing of the key itself.
+    pub fn check_value(&self) -> [u8; KEY_LENGTH] {
+        blake3::derive_key(CHECK_CONTEXT, &self.0)
+    }
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+/// Zero key material before it is freed.
+pub fn wipe(secret: &mut [u8]) {
+    for byte in secret.iter_mut() {
+        // not optimized away as a dead store
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+}
+
+/// The key of an older epoch, retired by a rotation of the master key and
+/// kept wrapped under the new one until no chunk is sealed under it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Epoch {
+    pub number: u32,
+    nonce: [u8; NONCE_LENGTH],
+    wrapped: Vec<u8>,
+}
+
+impl Epoch {
+    /// Wrap `key` of epoch `number` under `master`.
+    fn wrap(number: u32, key: &MasterKey, master: &MasterKey) -> Self {
+        let mut nonce = [0; NONCE_LENGTH];
+        thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: key.as_bytes(),
+            aad: &number.to_le_bytes(),
+        };
+        let wrapped = cipher(&blake3::derive_key(RETIRED_CONTEXT, master.as_bytes()))
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .expect("key within the length limit");
+        Self {
+            number,
+            nonce,
+            wrapped,
+        }
+    }
+
+    fn unwrap(&self, master: &MasterKey) -> Result<MasterKey, CryptError> {
+        let payload = Payload {
+            msg: &self.wrapped,
+            aad: &self.number.to_le_bytes(),
+        };
+        let mut key = cipher(&blake3::derive_key(RETIRED_CONTEXT, master.as_bytes()))
+            .decrypt(XNonce::from_slice(&self.nonce), payload)
+            .map_err(|_| CryptError::ForgedEpoch(self.number))?;
+        let bytes = key.as_slice().try_into();
+        wipe(&mut key);
+        bytes
+            .map(MasterKey::new)
+            .map_err(|_| CryptError::ForgedEpoch(self.number))
+    }
+}
+
+impl Encode for Epoch {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.number.encode(buf);
+        buf.extend_from_slice(&self.nonce);
+        self.wrapped.encode(buf);
+    }
+}
+
+impl Decode for Epoch {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let number = Decode::decode(reader)?;
+        let mut nonce = [0; NONCE_LENGTH];
+        nonce.copy_from_slice(reader.take(NONCE_LENGTH)?);
+        Ok(Self {
+            number,
+            nonce,
+            wrapped: Decode::decode(reader)?,
+        })
+    }
+}
+
+struct Epochs {
+    current: u32,
+    keys: HashMap<u32, MasterKey>,
+}
+
+/// KeyRing holds the master key of a filesystem and the keys it retired,
+/// chunks are sealed under the master key and opened under the key of the
+/// epoch they were sealed in.
+pub struct KeyRing {
+    mode: Mode,
+    epochs: RwLock<Epochs>,
+}
+
+impl KeyRing {
+    /// Seal chunks under `master`, as epoch 0.
+    pub fn new(master: MasterKey) -> Self {
+        Self {
+            mode: Mode::Random,
+            epochs: RwLock::new(Epochs {
+                current: 0,
+                keys: HashMap::from([(0, master)]),
+            }),
+        }
+    }
+
+    /// Seal chunks under `master` of epoch `current`, and open them under
+    /// the `retired` keys of older epochs as well.
+    pub fn open(master: MasterKey, current: u32, retired: &[Epoch]) -> Result<Self, CryptError> {
+        let mut keys = HashMap::new();
+        for epoch in retired {
+            keys.insert(epoch.number, epoch.unwrap(&master)?);
+        }
+        keys.insert(current, master);
+        Ok(Self {
+            mode: Mode::Random,
+            epochs: RwLock::new(Epochs { current, keys }),
+        })
+    }
+
+    /// Seal chunks in `mode`, chunks sealed in any mode are opened.
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The epoch chunks are sealed under.
+    pub fn current(&self) -> u32 {
+        self.epochs.read().current
+    }
+
+    /// Every key of the ring wrapped under `master`, the keys a rotation
+    /// to `master` retires.
+    pub fn retire(&self, master: &MasterKey) -> Vec<Epoch> {
+        let epochs = self.epochs.read();
+        let mut retired: Vec<Epoch> = epochs
+            .keys
+            .iter()
+            .map(|(number, key)| Epoch::wrap(*number, key, master))
+            .collect();
+        retired.sort_by_key(|epoch| epoch.number);
+        retired
+    }
+
+    /// Seal chunks under `master` from now on, as the next epoch, which is
+    /// returned.
+    pub fn rotate(&self, master: MasterKey) -> u32 {
+        let mut epochs = self.epochs.write();
+        epochs.current += 1;
+        let current = epochs.current;
+        epochs.keys.insert(current, master);
+        current
+    }
+
+    /// Forget the keys of older epochs, once no chunk is sealed under them.
+    pub fn drop_retired(&self) {
+        let mut epochs = self.epochs.write();
+        let current = epochs.current;
+        epochs.keys.retain(|number, _| *number == current);
+    }
+
+    fn key(&self, epoch: u32) -> Option<MasterKey> {
+        self.epochs.read().keys.get(&epoch).cloned()
+    }
+}
+
+/// Epoch the key `sealed` is sealed under.
+pub fn epoch(sealed: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        sealed.get(..EPOCH_LENGTH)?.try_into().ok()?,
+    ))
+}
+
+/// Associated data binding a sealed block to its position and chunk if
+/// any, so blocks cannot be swapped, and to the length of data shorter
+/// than a chunk, so blocks cannot be dropped.
+fn associated_data(id: Option<&Id>, block: usize, length: usize) -> Vec<u8> {
+    let mut aad = id.map(|id| id.to_vec()).unwrap_or_default();
+    aad.extend_from_slice(&(block as u32).to_le_bytes());
+    if length != CHUNK_SIZE {
+        aad.extend_from_slice(&(length as u32).to_le_bytes());
+    }
+    aad
+}
+
+/// Nonce derived from what it seals, never reused for distinct messages.
+fn synthetic_nonce(key: &[u8; KEY_LENGTH], message: &[u8]) -> [u8; NONCE_LENGTH] {
+    let mut nonce = [0; NONCE_LENGTH];
+    nonce.copy_from_slice(&blake3::keyed_hash(key, message).as_bytes()[..NONCE_LENGTH]);
+    nonce
+}
+
+fn cipher(key: &[u8; KEY_LENGTH]) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+/// Seal `data` of chunk `id` block by block, under the current epoch.
+/// Data shorter than a chunk, e.g. compressed, is sealed as long.
+pub fn seal(keys: &KeyRing, id: &Id, data: &[u8]) -> Vec<u8> {
+    assert!(data.len() <= CHUNK_SIZE);
+    let epoch = keys.current();
+    let key = keys.key(epoch).expect("current epoch has a key");
+    let mut sealed = Vec::with_capacity(keys.mode.sealed_length());
+    sealed.extend_from_slice(&epoch.to_le_bytes());
+    sealed.push(keys.mode.tag());
+    let (block_key, bound) = match keys.mode {
+        Mode::Random => (key.chunk_key(id), Some(id)),
+        Mode::Convergent => {
+            let content = key.content_key(data);
+            let wrapping = key.wrapping_key();
+            let nonce = synthetic_nonce(&wrapping, &content);
+            let wrapped = cipher(&wrapping)
+                .encrypt(XNonce::from_slice(&nonce), &content[..])
+                .expect("key within the length limit");
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&wrapped);
+            (content, None)
+        }
+    };
+    let cipher = cipher(&block_key);
+    for (n, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        let nonce = match keys.mode {
+            Mode::Random => {
+                let mut nonce = [0; NONCE_LENGTH];
+                thread_rng().fill_bytes(&mut nonce);
+                nonce
+            }
+            // the content key seals nothing but this content
+            Mode::Convergent => synthetic_nonce(&block_key, &(n as u32).to_le_bytes()),
+        };
+        let aad = associated_data(bound, n, data.len());
+        let payload = Payload {
+            msg: block,
+            aad: &aad,
+        };
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .expect("block within the length limit");
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+    }
+    sealed
+}
+
+/// Open `sealed` data of chunk `id`, failing if any block is tampered
+/// with or sealed with another key.
+pub fn open(keys: &KeyRing, id: &Id, sealed: &[u8]) -> Result<Vec<u8>, CryptError> {
+    let mode = match sealed.get(EPOCH_LENGTH) {
+        Some(0) => Mode::Random,
+        Some(1) => Mode::Convergent,
+        Some(tag) => return Err(CryptError::UnknownMode(id.hex().to_owned(), *tag)),
+        None => return Err(CryptError::InvalidLength(sealed.len())),
+    };
+    let header = mode.sealed_length() - SEALED_BLOCK_SIZE * BLOCK_PER_CHUNK;
+    if sealed.len() < header || sealed.len() > mode.sealed_length() {
+        return Err(CryptError::InvalidLength(sealed.len()));
+    }
+    let stored = sealed.len() - header;
+    let blocks = (stored + SEALED_BLOCK_SIZE - 1) / SEALED_BLOCK_SIZE;
+    // the last block holds a byte at least
+    let length = match stored.checked_sub(blocks * (NONCE_LENGTH + TAG_LENGTH)) {
+        Some(length) if blocks == 0 || length > (blocks - 1) * BLOCK_SIZE => length,
+        _ => return Err(CryptError::InvalidLength(sealed.len())),
+    };
+    let epoch = epoch(sealed).unwrap();
+    let key = match keys.key(epoch) {
+        Some(key) => key,
+        None => return Err(CryptError::UnknownEpoch(id.hex().to_owned(), epoch)),
+    };
+    let (block_key, bound, blocks) = match mode {
+        Mode::Random => (key.chunk_key(id), Some(id), &sealed[HEADER_LENGTH..]),
+        Mode::Convergent => {
+            let (wrapped, blocks) = sealed[HEADER_LENGTH..].split_at(WRAPPED_LENGTH);
+            let (nonce, wrapped) = wrapped.split_at(NONCE_LENGTH);
+            let content = cipher(&key.wrapping_key())
+                .decrypt(XNonce::from_slice(nonce), wrapped)
+                .map_err(|_| CryptError::Forged(id.hex().to_owned(), 0))?;
+            (content[..].try_into().unwrap(), None, blocks)
+        }
+    };
+    let cipher = cipher(&block_key);
+    let mut data = Vec::with_capacity(CHUNK_SIZE);
+    for (n, block) in blocks.chunks(SEALED_BLOCK_SIZE).enumerate() {
+        let (nonce, ciphertext) = block.split_at(NONCE_LENGTH);
+        let aad = associated_data(bound, n, length);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| CryptError::Forged(id.hex().to_owned(), n))?;
+        data.extend_from_slice(&plaintext);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{epoch, open, seal, CryptError, KeyRing, MasterKey, Mode};
+    use super::{HEADER_LENGTH, NONCE_LENGTH, SEALED_BLOCK_SIZE, TAG_LENGTH};
+    use crate::chunk::{BLOCK_SIZE, CHUNK_SIZE};
+    use crate::id::Id;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = MasterKey::new_random();
+        let keys = KeyRing::new(key);
+        let id = Id::new_random();
+        let data: Vec<u8> = (0..CHUNK_SIZE).map(|n| n as u8).collect();
+        let mut sealed = seal(&keys, &id, &data);
+        assert_eq!(open(&keys, &id, &sealed).unwrap(), data);
+        // another key, another chunk
+        assert!(open(&KeyRing::new(MasterKey::new_random()), &id, &sealed).is_err());
+        assert!(open(&keys, &Id::new_random(), &sealed).is_err());
+        // sealed under a new key once rotated, older ones still open
+        let master = MasterKey::new_random();
+        let retired = keys.retire(&master);
+        let rotated = KeyRing::open(master.clone(), 1, &retired).unwrap();
+        let resealed = seal(&rotated, &id, &data);
+        assert_eq!(epoch(&resealed), Some(1));
+        assert_eq!(open(&rotated, &id, &sealed).unwrap(), data);
+        // the former master key opens nothing sealed since
+        assert!(open(&keys, &id, &resealed).is_err());
+        assert!(matches!(
+            KeyRing::open(MasterKey::new_random(), 1, &retired),
+            Err(CryptError::ForgedEpoch(0))
+        ));
+        rotated.drop_retired();
+        assert!(open(&rotated, &id, &sealed).is_err());
+        assert_eq!(open(&rotated, &id, &resealed).unwrap(), data);
+        // blocks swapped
+        let blocks = &mut sealed[HEADER_LENGTH..];
+        let (first, rest) = blocks.split_at_mut(SEALED_BLOCK_SIZE);
+        first.swap_with_slice(&mut rest[..SEALED_BLOCK_SIZE]);
+        assert!(matches!(
+            open(&keys, &id, &sealed),
+            Err(CryptError::Forged(_, 0))
+        ));
+
+        // equal chunks sealed alike whatever their id
+        let convergent = KeyRing::new(MasterKey::new_random()).with_mode(Mode::Convergent);
+        let other = Id::new_random();
+        let sealed = seal(&convergent, &id, &data);
+        assert_eq!(sealed, seal(&convergent, &other, &data));
+        assert_ne!(sealed, seal(&convergent, &id, &vec![0; CHUNK_SIZE]));
+        assert_eq!(open(&convergent, &other, &sealed).unwrap(), data);
+
+        // shorter data, whose blocks cannot be dropped
+        let short = &data[..BLOCK_SIZE + 10];
+        let sealed = seal(&keys, &id, short);
+        assert_eq!(open(&keys, &id, &sealed).unwrap(), short);
+        let dropped = &sealed[..sealed.len() - (10 + NONCE_LENGTH + TAG_LENGTH)];
+        assert!(matches!(
+            open(&keys, &id, dropped),
+            Err(CryptError::Forged(_, 0))
+        ));
+    }
+}
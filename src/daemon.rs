@@ -0,0 +1,203 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc::{c_int, SIGHUP, SIGINT, SIGTERM};
+
+/// Write end of the pipe signals are forwarded to.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// What a signal received asks of the daemon.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Signal {
+    /// SIGTERM or SIGINT, flush and unmount.
+    Stop,
+    /// SIGHUP, reload the config.
+    Reload,
+}
+
+/// Ready tells the parent of a daemon that it is up, letting it exit.
+pub struct Ready {
+    pipe: File,
+}
+
+impl Ready {
+    /// Report success to the parent, which exits with 0, and detach from
+    /// the terminal. Output redirected elsewhere, such as to a log file,
+    /// is kept for the errors logged.
+    pub fn notify(mut self) -> io::Result<()> {
+        self.pipe.write_all(&[0])?;
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        for fd in 0..3 {
+            if unsafe { libc::isatty(fd) } != 1 {
+                continue;
+            }
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fork into the background, in a new session, keeping the working
+/// directory paths given are relative to. The parent waits until the
+/// child calls `Ready::notify` and exits with 0, or with 1 if the child
+/// exits first, its errors still printed to the terminal.
+/// Must be called before any thread is spawned.
+pub fn daemonize() -> io::Result<Ready> {
+    let (mut read, write) = pipe()?;
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(read);
+            if unsafe { libc::setsid() } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Ready { pipe: write })
+        }
+        _ => {
+            drop(write);
+            let mut byte = [0];
+            match read.read(&mut byte) {
+                Ok(1) => process::exit(0),
+                _ => process::exit(1),
+            }
+        }
+    }
+}
+
+/// Pidfile holds the pid of the daemon while it runs, removed on drop.
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Write the pid of this process to `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::write(&path, format!("{}\n", process::id()))?;
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Signals handled rather than killing the process.
+const SIGNALS: [c_int; 3] = [SIGTERM, SIGINT, SIGHUP];
+
+/// Signals receives SIGTERM, SIGINT and SIGHUP while installed, rather than
+/// letting them kill the process.
+pub struct Signals {
+    pipe: File,
+    /// Write end the handlers forward to, open until reset on drop
+    forwarded: File,
+    installed: bool,
+}
+
+impl Signals {
+    /// Handle the signals from now on, until dropped.
+    pub fn install() -> io::Result<Self> {
+        let mut signals = Self::new()?;
+        SIGNAL_PIPE.store(signals.forwarded.as_raw_fd(), Ordering::SeqCst);
+        signals.installed = true;
+        for signal in SIGNALS.iter() {
+            let handler = forward as extern "C" fn(c_int) as libc::sighandler_t;
+            if unsafe { libc::signal(*signal, handler) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(signals)
+    }
+
+    /// Signals forwarded to the pipe, without handling any.
+    fn new() -> io::Result<Self> {
+        let (pipe, forwarded) = pipe()?;
+        Ok(Self {
+            pipe,
+            forwarded,
+            installed: false,
+        })
+    }
+
+    /// Wait for the next signal.
+    pub fn wait(&mut self) -> io::Result<Signal> {
+        let mut byte = [0];
+        loop {
+            match self.pipe.read(&mut byte) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) if byte[0] as c_int == SIGHUP => return Ok(Signal::Reload),
+                Ok(_) => return Ok(Signal::Stop),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        if self.installed {
+            for signal in SIGNALS.iter() {
+                unsafe { libc::signal(*signal, libc::SIG_DFL) };
+            }
+            SIGNAL_PIPE.store(-1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Handler of the signals, only async-signal-safe calls here.
+extern "C" fn forward(signal: c_int) {
+    let byte = signal as u8;
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::SeqCst),
+            &byte as *const u8 as _,
+            1,
+        )
+    };
+}
+
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pidfile, Signal, Signals};
+    use libc::{SIGHUP, SIGTERM};
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_signals_and_pidfile() {
+        let path = std::env::temp_dir().join(format!("eoss-fuse-{}.pid", std::process::id()));
+        let pidfile = Pidfile::create(&path).unwrap();
+        let pid: u32 = fs::read_to_string(&path).unwrap().trim().parse().unwrap();
+        assert_eq!(pid, std::process::id());
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // forwarded as by the handlers, left to the test harness
+        let mut signals = Signals::new().unwrap();
+        (&signals.forwarded).write_all(&[SIGHUP as u8]).unwrap();
+        assert_eq!(signals.wait().unwrap(), Signal::Reload);
+        (&signals.forwarded).write_all(&[SIGTERM as u8]).unwrap();
+        assert_eq!(signals.wait().unwrap(), Signal::Stop);
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::fs::{DirMeta, FileMeta};
+use crate::id::{ChunkId, Id, Meta};
+use crate::merkle::UNHASHED;
+use crate::meta::{self, Decode, Encode, MetaError, Reader};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+/// Context deriving the id of the index from the superblock id.
+const INDEX_CONTEXT: &str = "eoss-fuse 2021-05 dedup index";
+/// Buckets the counts are split in by the first byte of their hash, each
+/// stored on its own so a change rewrites its bucket alone.
+const BUCKETS: usize = 256;
+
+/// DedupIndex counts the references to each chunk stored at its hash by a
+/// content-addressed filesystem, so a chunk is stored once however many
+/// files hold it, and deleted with the last of them.
+/// A file referenced by snapshots or clones under the same id counts once.
+pub struct DedupIndex {
+    superblock_id: Id,
+    buckets: Vec<HashMap<[u8; 32], u64>>,
+    /// Buckets changed since stored
+    dirty: HashSet<usize>,
+}
+
+struct Count([u8; 32], u64);
+
+impl Encode for Count {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+        self.1.encode(buf);
+    }
+}
+
+impl Decode for Count {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self(Decode::decode(reader)?, Decode::decode(reader)?))
+    }
+}
+
+/// Ids of the buckets of the index of the filesystem with superblock
+/// `superblock_id`.
+pub fn bucket_ids(superblock_id: &Id) -> impl Iterator<Item = ChunkId<Meta>> {
+    let id = Id::new(blake3::derive_key(INDEX_CONTEXT, &**superblock_id));
+    (0..BUCKETS).map(move |n| ChunkId::derive_labeled(&id, &[n as u8]))
+}
+
+impl DedupIndex {
+    /// Open the index of the filesystem with superblock `superblock_id`,
+    /// empty if never stored.
+    pub fn open(provider: &dyn ChunkProvider, superblock_id: &Id) -> Result<Self, MetaError> {
+        let mut buckets = Vec::with_capacity(BUCKETS);
+        for id in bucket_ids(superblock_id) {
+            buckets.push(match meta::load::<Vec<Count>>(provider, &id) {
+                Ok(counts) => counts.into_iter().map(|Count(hash, n)| (hash, n)).collect(),
+                Err(MetaError::BadMagic) => HashMap::new(),
+                Err(e) => return Err(e),
+            });
+        }
+        Ok(Self {
+            superblock_id: superblock_id.clone(),
+            buckets,
+            dirty: HashSet::new(),
+        })
+    }
+
+    /// References to the chunk of `hash`.
+    #[cfg(test)]
+    pub fn count(&self, hash: &[u8; 32]) -> u64 {
+        self.buckets[bucket(hash)].get(hash).copied().unwrap_or(0)
+    }
+
+    /// Add a reference to the chunk of `hash`. Returns whether it is the
+    /// first, the chunk to be stored.
+    pub fn add(&mut self, hash: &[u8; 32]) -> bool {
+        self.dirty.insert(bucket(hash));
+        let count = self.buckets[bucket(hash)].entry(*hash).or_insert(0);
+        *count += 1;
+        *count == 1
+    }
+
+    /// Drop a reference to the chunk of `hash`. Returns whether it was the
+    /// last, the chunk to be deleted.
+    pub fn remove(&mut self, hash: &[u8; 32]) -> bool {
+        let counts = &mut self.buckets[bucket(hash)];
+        match counts.get_mut(hash) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                counts.remove(hash);
+            }
+            None => return false,
+        }
+        self.dirty.insert(bucket(hash));
+        !self.buckets[bucket(hash)].contains_key(hash)
+    }
+
+    /// Drop a reference to each chunk of `hashes`, deleting those no
+    /// longer referenced.
+    pub fn release(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        hashes: &[[u8; 32]],
+    ) -> Result<(), ChunkProviderError> {
+        for hash in hashes.iter().filter(|hash| self.remove(hash)) {
+            provider.delete_chunk(&Id::new(*hash))?;
+        }
+        Ok(())
+    }
+
+    /// Count the references again from `trees`, fully loaded, in case
+    /// counts were lost by an unclean shutdown.
+    pub fn rebuild<'a>(&mut self, trees: impl IntoIterator<Item = &'a DirMeta>) {
+        let mut seen = HashSet::new();
+        self.buckets.iter_mut().for_each(HashMap::clear);
+        for tree in trees {
+            let _ = tree.visit_files(&mut |file| {
+                if seen.insert(file.id.clone()) {
+                    for hash in held(file) {
+                        *self.buckets[bucket(&hash)].entry(hash).or_insert(0) += 1;
+                    }
+                }
+                Ok::<_, ()>(())
+            });
+        }
+        self.dirty = (0..BUCKETS).collect();
+    }
+
+    /// Persist the buckets changed since stored.
+    pub fn store(&mut self, provider: &dyn ChunkProvider) -> Result<(), MetaError> {
+        for (n, id) in bucket_ids(&self.superblock_id).enumerate() {
+            if self.dirty.contains(&n) {
+                meta::store(provider, &id, &self.encoded(n))?;
+                // stored, left dirty if a later bucket fails
+                self.dirty.remove(&n);
+            }
+        }
+        Ok(())
+    }
+
+    fn encoded(&self, bucket: usize) -> Vec<Count> {
+        self.buckets[bucket]
+            .iter()
+            .map(|(hash, n)| Count(*hash, *n))
+            .collect()
+    }
+}
+
+/// Bucket holding the count of `hash`.
+fn bucket(hash: &[u8; 32]) -> usize {
+    hash[0] as usize % BUCKETS
+}
+
+/// Hashes of the chunks of `file` stored at their hash, by position,
+/// `UNHASHED` for the others.
+pub fn shared_leaves(file: &FileMeta) -> Vec<[u8; 32]> {
+    (0..file.hashes.len())
+        .map(|n| {
+            if file.shared(n) {
+                file.hashes[n]
+            } else {
+                UNHASHED
+            }
+        })
+        .collect()
+}
+
+/// Hashes of the chunks of `file` stored at their hash.
+pub fn held(file: &FileMeta) -> Vec<[u8; 32]> {
+    shared_leaves(file)
+        .into_iter()
+        .filter(|hash| *hash != UNHASHED)
+        .collect()
+}
+
+/// Hashes of `before`, as returned by `shared_leaves`, no longer held at
+/// the same position by `file`.
+pub fn dropped(before: &[[u8; 32]], file: Option<&FileMeta>) -> Vec<[u8; 32]> {
+    let after = file.map(shared_leaves).unwrap_or_default();
+    before
+        .iter()
+        .enumerate()
+        .filter(|(n, hash)| **hash != UNHASHED && after.get(*n) != Some(*hash))
+        .map(|(_, hash)| *hash)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{held, DedupIndex};
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fs::{Attrs, FileMeta};
+    use crate::id::Id;
+    use crate::merkle;
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+
+    fn file(provider: &dyn ChunkProvider, index: &mut DedupIndex, byte: u8) -> FileMeta {
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: true,
+        };
+        file.write(provider, 0, &vec![byte; CHUNK_SIZE]).unwrap();
+        merkle::update(&mut file, provider, Some(index)).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_stored_once() {
+        let provider = MemoryProvider::new();
+        let superblock_id = Id::new(SUPERBLOCK_ID);
+        let mut index = DedupIndex::open(&provider, &superblock_id).unwrap();
+        let a = file(&provider, &mut index, 1);
+        let b = file(&provider, &mut index, 1);
+        assert_eq!(a.chunk_id(0), b.chunk_id(0));
+        let hash = a.hashes[0];
+        assert_eq!(index.count(&hash), 2);
+        // the bucket changed alone is stored
+        assert_eq!(index.dirty.len(), 1);
+        index.store(&provider).unwrap();
+        assert!(index.dirty.is_empty());
+
+        // reopened
+        let mut index = DedupIndex::open(&provider, &superblock_id).unwrap();
+        assert_eq!(index.count(&hash), 2);
+        index.release(&provider, &held(&a)).unwrap();
+        assert!(provider.contains_chunk(b.chunk_id(0).id()).unwrap());
+        index.release(&provider, &held(&b)).unwrap();
+        assert!(!provider.contains_chunk(b.chunk_id(0).id()).unwrap());
+
+        index.rebuild(vec![]);
+        assert_eq!(index.count(&hash), 0);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::fs::{Attrs, DirMeta, Node};
+use crate::id::{ChunkId, Id, Meta, ID_LENGTH};
+use crate::meta::{self, Decode, Encode, MetaError, Reader};
+use crate::provider::ChunkProvider;
+use crate::quota::{Quota, Usage};
+
+/// Entries a bucket holds on average before the directory doubles its buckets.
+pub const BUCKET_ENTRIES: usize = 1024;
+
+/// The header of a stored directory, stored at `header_id`.
+struct Header {
+    name: String,
+    attrs: Attrs,
+    usage: Usage,
+    quota: Quota,
+    versions: u32,
+    buckets: u32,
+}
+
+impl Encode for Header {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.attrs.encode(buf);
+        self.usage.encode(buf);
+        self.quota.encode(buf);
+        self.versions.encode(buf);
+        self.buckets.encode(buf);
+    }
+}
+
+impl Decode for Header {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            name: Decode::decode(reader)?,
+            attrs: Decode::decode(reader)?,
+            usage: Decode::decode(reader)?,
+            quota: Decode::decode(reader)?,
+            versions: Decode::decode(reader)?,
+            buckets: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// An entry stored in a bucket, a sub-directory is stored on its own and
+/// referred by id, along with its attributes to be listed without loading it.
+enum Stored<'a> {
+    Dir(&'a DirMeta),
+    Node(&'a Node),
+}
+
+impl<'a> Encode for Stored<'a> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Stored::Dir(dir) => {
+                0u8.encode(buf);
+                dir.name.encode(buf);
+                dir.id.encode(buf);
+                dir.attrs.encode(buf);
+            }
+            Stored::Node(node) => {
+                1u8.encode(buf);
+                node.encode(buf);
+            }
+        }
+    }
+}
+
+/// A sub-directory is loaded without its entries.
+struct Loaded(Node);
+
+impl Decode for Loaded {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        match u8::decode(reader)? {
+            0 => Ok(Loaded(Node::Dir(DirMeta::new_unloaded(
+                Decode::decode(reader)?,
+                Decode::decode(reader)?,
+                Decode::decode(reader)?,
+            )))),
+            1 => Ok(Loaded(Decode::decode(reader)?)),
+            tag => Err(MetaError::InvalidTag(tag)),
+        }
+    }
+}
+
+fn header_id(dir_id: &[u8; ID_LENGTH]) -> ChunkId<Meta> {
+    ChunkId::derive_n(&Id::new(*dir_id), 0)
+}
+
+fn bucket_id(dir_id: &[u8; ID_LENGTH], bucket: usize) -> ChunkId<Meta> {
+    ChunkId::derive_n(&Id::new(*dir_id), bucket + 1)
+}
+
+/// Bucket of the entry `name` among `buckets`, a power of two.
+fn bucket_of(name: &str, buckets: usize) -> usize {
+    let hash = blake3::hash(name.as_bytes());
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_le_bytes(prefix) as usize & (buckets - 1)
+}
+
+/// Encode the header and buckets of `dir`, without its sub-directories.
+fn encode_dir(dir: &DirMeta) -> Vec<Vec<u8>> {
+    let buckets = ((dir.entries.len() + BUCKET_ENTRIES - 1) / BUCKET_ENTRIES)
+        .max(1)
+        .next_power_of_two();
+    let mut stored: Vec<Vec<Stored>> = (0..buckets).map(|_| Vec::new()).collect();
+    for (name, node) in dir.entries.iter() {
+        let entry = match node {
+            Node::Dir(sub) => Stored::Dir(sub),
+            node => Stored::Node(node),
+        };
+        stored[bucket_of(name, buckets)].push(entry);
+    }
+    let header = Header {
+        name: dir.name.clone(),
+        attrs: dir.attrs.clone(),
+        usage: dir.usage,
+        quota: dir.quota,
+        versions: dir.versions,
+        buckets: buckets as u32,
+    };
+    let mut encoded = vec![meta::encode(&header)];
+    encoded.extend(stored.iter().map(meta::encode));
+    encoded
+}
+
+/// Store the directory tree at `dir`, each directory in MetaChunks of its own.
+/// Entries of a directory are spread into buckets by the hash of their name,
+/// only the header and buckets changed since last stored are written.
+/// Directories not loaded are left as stored.
+/// Returns the number of header and buckets written.
+pub fn store_dir(provider: &dyn ChunkProvider, dir: &mut DirMeta) -> Result<usize, MetaError> {
+    if !dir.loaded {
+        return Ok(0);
+    }
+    let mut written = 0;
+    for sub in dir.dirs_mut() {
+        written += store_dir(provider, sub)?;
+    }
+    let encoded = encode_dir(dir);
+    let mut stored = Vec::with_capacity(encoded.len());
+    for (n, data) in encoded.iter().enumerate() {
+        let hash = *blake3::hash(data).as_bytes();
+        if dir.stored.get(n) != Some(&hash) {
+            let id = match n {
+                0 => header_id(&dir.id),
+                n => bucket_id(&dir.id, n - 1),
+            };
+            meta::store_bytes(provider, &id, data)?;
+            written += 1;
+        }
+        stored.push(hash);
+    }
+    dir.stored = stored;
+    Ok(written)
+}
+
+/// Load the whole directory tree stored at `id` by `store_dir`.
+pub fn load_dir(provider: &dyn ChunkProvider, id: &[u8; ID_LENGTH]) -> Result<DirMeta, MetaError> {
+    let data = meta::load_bytes(provider, &header_id(id))?;
+    let header: Header = meta::decode(&data)?;
+    let mut dir = DirMeta::new_unloaded(header.name, Id::new(*id), header.attrs);
+    load_all(provider, &mut dir)?;
+    Ok(dir)
+}
+
+/// Load entries of `dir` and all directories beneath.
+pub fn load_all(provider: &dyn ChunkProvider, dir: &mut DirMeta) -> Result<(), MetaError> {
+    load_entries(provider, dir)?;
+    for sub in dir.dirs_mut() {
+        load_all(provider, sub)?;
+    }
+    Ok(())
+}
+
+/// Load entries of `dir` if not loaded yet, sub-directories are left unloaded.
+pub fn load_entries(provider: &dyn ChunkProvider, dir: &mut DirMeta) -> Result<(), MetaError> {
+    if dir.loaded {
+        return Ok(());
+    }
+    let data = meta::load_bytes(provider, &header_id(&dir.id))?;
+    let header: Header = meta::decode(&data)?;
+    dir.usage = header.usage;
+    dir.quota = header.quota;
+    dir.versions = header.versions;
+    let mut stored = vec![*blake3::hash(&data).as_bytes()];
+    for bucket in 0..header.buckets as usize {
+        let data = meta::load_bytes(provider, &bucket_id(&dir.id, bucket))?;
+        for Loaded(node) in meta::decode::<Vec<Loaded>>(&data)? {
+            dir.insert(node);
+        }
+        stored.push(*blake3::hash(&data).as_bytes());
+    }
+    dir.stored = stored;
+    dir.loaded = true;
+    Ok(())
+}
+
+/// Ids of all MetaChunks holding the directory tree at `dir`, fully loaded.
+pub fn chunk_ids(dir: &DirMeta) -> Vec<Id> {
+    let mut ids = dir_chunk_ids(dir);
+    for sub in dir.dirs() {
+        ids.extend(chunk_ids(sub));
+    }
+    ids
+}
+
+/// Ids of the MetaChunks holding `dir` itself, not the directories beneath.
+pub fn dir_chunk_ids(dir: &DirMeta) -> Vec<Id> {
+    let mut ids = Vec::new();
+    for (n, data) in encode_dir(dir).iter().enumerate() {
+        let id = match n {
+            0 => header_id(&dir.id),
+            n => bucket_id(&dir.id, n - 1),
+        };
+        ids.extend(meta::chunk_ids(&id, data.len()));
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_dir, store_dir, BUCKET_ENTRIES};
+    use crate::fs::{Attrs, DirMeta, Node, TinyFileMeta};
+    use crate::meta;
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_localized_update() {
+        let provider = MemoryProvider::new();
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut sub = DirMeta::new("sub".to_owned(), Attrs::new(0o755, 0, 0));
+        for i in 1..BUCKET_ENTRIES * 4 {
+            let file = TinyFileMeta::new(i.to_string(), Attrs::new(0o644, 0, 0));
+            sub.insert(Node::TinyFile(file));
+        }
+        root.insert(Node::Dir(sub));
+        // 2 headers, 1 bucket of root and 4 buckets of sub
+        assert_eq!(store_dir(&provider, &mut root).unwrap(), 7);
+        assert_eq!(store_dir(&provider, &mut root).unwrap(), 0);
+
+        let mut loaded = load_dir(&provider, &root.id).unwrap();
+        assert_eq!(meta::encode(&loaded), meta::encode(&root));
+        match loaded.lookup_mut("sub") {
+            Some(crate::fs::EntryMut::Dir(sub)) => {
+                let file = TinyFileMeta::new("new".to_owned(), Attrs::new(0o644, 0, 0));
+                sub.insert(Node::TinyFile(file));
+            }
+            _ => panic!("sub not loaded"),
+        }
+        assert_eq!(store_dir(&provider, &mut loaded).unwrap(), 1);
+    }
+}
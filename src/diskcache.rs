@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::chunk::CHUNK_SIZE;
+use crate::id::{Id, ID_LENGTH};
+use crate::meta::{self, Decode, Encode, MetaError, Reader};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::Uring;
+
+/// Name of the file holding the index in the cache directory.
+const INDEX_FILE: &str = "index";
+
+#[derive(thiserror::Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum DiskCacheError {
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+    #[error(transparent)]
+    ProviderError(#[from] ChunkProviderError),
+}
+
+/// A chunk held in the cache directory.
+struct Cached {
+    tick: u64,
+    /// Generation in the provider when cached, if tracked
+    generation: Option<u64>,
+    /// Checked against the provider since the cache was opened
+    verified: bool,
+}
+
+/// A chunk in the persisted index, least recently used first.
+struct IndexEntry {
+    id: [u8; ID_LENGTH],
+    generation: Option<u64>,
+}
+
+impl Encode for IndexEntry {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.id.encode(buf);
+        self.generation.is_some().encode(buf);
+        self.generation.unwrap_or_default().encode(buf);
+    }
+}
+
+impl Decode for IndexEntry {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let id = Decode::decode(reader)?;
+        let tracked = bool::decode(reader)?;
+        let generation = u64::decode(reader)?;
+        Ok(Self {
+            id,
+            generation: if tracked { Some(generation) } else { None },
+        })
+    }
+}
+
+/// DiskCache keeps clean chunks of a remote provider in a local directory
+/// within a byte budget, least recently used ones are evicted first.
+/// The index survives restarts, chunks cached before are checked against
+/// the generation in the provider when first read again.
+/// Only chunks already in the provider are cached, so a lost cache loses
+/// nothing but the time to fetch them again.
+pub struct DiskCache {
+    dir: PathBuf,
+    budget: usize,
+    tick: u64,
+    chunks: HashMap<Id, Cached>,
+    /// Cached chunks ordered by last use
+    order: BTreeMap<u64, Id>,
+    /// Chunk files are read and written through io_uring if supported
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<Uring>,
+}
+
+impl DiskCache {
+    /// Open the cache in `dir` holding at most `budget` bytes, creating it
+    /// if needed. Files not in the index, e.g. written before a crash, are
+    /// removed.
+    pub fn open<P: AsRef<Path>>(dir: P, budget: usize) -> Result<Self, DiskCacheError> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+        let mut cache = Self {
+            dir,
+            budget,
+            tick: 0,
+            chunks: HashMap::new(),
+            order: BTreeMap::new(),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring: Uring::new().ok(),
+        };
+        let index = match fs::read(cache.dir.join(INDEX_FILE)) {
+            Ok(data) => meta::decode::<Vec<IndexEntry>>(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in index {
+            cache.add(Id::new(entry.id), entry.generation, false);
+        }
+        let indexed: HashSet<String> = cache.chunks.keys().map(|id| id.hex().to_owned()).collect();
+        for entry in fs::read_dir(&cache.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            match name.to_str() {
+                Some(INDEX_FILE) => {}
+                Some(name) if indexed.contains(name) => {}
+                _ => fs::remove_file(entry.path())?,
+            }
+        }
+        cache.evict()?;
+        Ok(cache)
+    }
+
+    /// Data of chunk `id` if cached and not modified in `provider` since,
+    /// marked used.
+    pub fn get(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        id: &Id,
+    ) -> Result<Option<Vec<u8>>, DiskCacheError> {
+        let cached = match self.chunks.get(id) {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+        if !cached.verified {
+            // modified by others while the cache was closed
+            if cached.generation.is_some() && provider.generation(id)? != cached.generation {
+                self.remove(id)?;
+                return Ok(None);
+            }
+            self.chunks.get_mut(id).unwrap().verified = true;
+        }
+        let data = match self.read_file(&self.path(id)) {
+            Ok(data) if data.len() == CHUNK_SIZE => data,
+            Ok(_) => {
+                self.remove(id)?;
+                return Ok(None);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.remove(id)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.touch(id);
+        Ok(Some(data))
+    }
+
+    /// Cache `data` of chunk `id`, stored in the provider at `generation`,
+    /// then evict chunks beyond the budget.
+    pub fn insert(
+        &mut self,
+        id: Id,
+        data: &[u8],
+        generation: Option<u64>,
+    ) -> Result<(), DiskCacheError> {
+        // renamed into place, never read half written
+        let temp = self.dir.join(format!("{}.tmp", id.hex()));
+        self.write_file(&temp, data)?;
+        fs::rename(&temp, self.path(&id))?;
+        self.add(id, generation, true);
+        self.evict()
+    }
+
+    /// Drop chunk `id`, e.g. modified since cached.
+    pub fn remove(&mut self, id: &Id) -> Result<(), DiskCacheError> {
+        if let Some(cached) = self.chunks.remove(id) {
+            self.order.remove(&cached.tick);
+            match fs::remove_file(self.path(id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist the index, so cached chunks are found after a restart.
+    pub fn save_index(&self) -> Result<(), DiskCacheError> {
+        let index: Vec<IndexEntry> = self
+            .order
+            .values()
+            .map(|id| IndexEntry {
+                id: **id,
+                generation: self.chunks[id].generation,
+            })
+            .collect();
+        let temp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        fs::write(&temp, meta::encode(&index))?;
+        fs::rename(&temp, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    /// Bytes of chunks cached.
+    pub fn size(&self) -> usize {
+        self.chunks.len() * CHUNK_SIZE
+    }
+
+    fn path(&self, id: &Id) -> PathBuf {
+        self.dir.join(id.hex())
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            if let Some(uring) = &self.uring {
+                return uring.read(&fs::File::open(path)?);
+            }
+        }
+        fs::read(path)
+    }
+
+    fn write_file(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            if let Some(uring) = &self.uring {
+                return uring.write(&fs::File::create(path)?, data);
+            }
+        }
+        fs::write(path, data)
+    }
+
+    fn add(&mut self, id: Id, generation: Option<u64>, verified: bool) {
+        self.tick += 1;
+        let cached = Cached {
+            tick: self.tick,
+            generation,
+            verified,
+        };
+        if let Some(old) = self.chunks.insert(id.clone(), cached) {
+            self.order.remove(&old.tick);
+        }
+        self.order.insert(self.tick, id);
+    }
+
+    fn touch(&mut self, id: &Id) {
+        if let Some(cached) = self.chunks.get_mut(id) {
+            self.tick += 1;
+            self.order.remove(&cached.tick);
+            cached.tick = self.tick;
+            self.order.insert(self.tick, id.clone());
+        }
+    }
+
+    fn evict(&mut self) -> Result<(), DiskCacheError> {
+        while self.size() > self.budget {
+            let id = match self.order.values().next() {
+                Some(id) => id.clone(),
+                None => break,
+            };
+            self.remove(&id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiskCache;
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::id::Id;
+    use crate::provider::ChunkProvider;
+    use crate::providers::local::LocalProvider;
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_persisted_index() {
+        let base = env::temp_dir().join(format!("eoss-disk-cache-{}", Id::new_random().hex()));
+        let provider = LocalProvider::new(base.join("chunks")).unwrap();
+        let dir = base.join("cache");
+        let ids: Vec<Id> = (0..3).map(|_| Id::new_random()).collect();
+        let mut cache = DiskCache::open(&dir, 2 * CHUNK_SIZE).unwrap();
+        for id in ids.iter() {
+            let chunk = Chunk::new(id.clone());
+            provider.save_chunk(&chunk).unwrap();
+            let generation = provider.generation(id).unwrap();
+            cache
+                .insert(id.clone(), &vec![1; CHUNK_SIZE], generation)
+                .unwrap();
+        }
+        // the oldest is evicted
+        assert!(cache.get(&provider, &ids[0]).unwrap().is_none());
+        cache.save_index().unwrap();
+        drop(cache);
+
+        // left by a crash before the index is saved
+        fs::write(dir.join(ids[0].hex()), vec![0; CHUNK_SIZE]).unwrap();
+        let mut cache = DiskCache::open(&dir, 2 * CHUNK_SIZE).unwrap();
+        assert!(!dir.join(ids[0].hex()).exists());
+        assert_eq!(
+            cache.get(&provider, &ids[1]).unwrap(),
+            Some(vec![1; CHUNK_SIZE])
+        );
+        // modified by others meanwhile
+        thread::sleep(Duration::from_millis(10));
+        provider.save_chunk(&Chunk::new(ids[2].clone())).unwrap();
+        assert!(cache.get(&provider, &ids[2]).unwrap().is_none());
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,542 @@
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyIoctl, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
+    TimeOrNow,
+};
+use libc::c_int;
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::Semaphore;
+
+use crate::bridge::{blocking, Bridge};
+use crate::fuse::{EossFs, ReadData};
+
+/// Locks inodes are striped over.
+const STRIPES: usize = 256;
+
+/// Locks of inodes, held shared by reads and exclusively by changes of
+/// data, so a read fetching from the provider without the filesystem lock
+/// sees a write to the same inode wholly or not at all.
+pub struct InodeLocks {
+    stripes: Vec<RwLock<()>>,
+}
+
+impl InodeLocks {
+    pub fn new() -> Self {
+        Self {
+            stripes: (0..STRIPES).map(|_| RwLock::new(())).collect(),
+        }
+    }
+
+    pub fn read(&self, ino: u64) -> RwLockReadGuard<'_, ()> {
+        self.stripes[ino as usize % STRIPES].read()
+    }
+
+    pub fn write(&self, ino: u64) -> RwLockWriteGuard<'_, ()> {
+        self.stripes[ino as usize % STRIPES].write()
+    }
+}
+
+impl Default for InodeLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serves a filesystem with reads of file data answered from tasks on its
+/// runtime, fetching from the provider in parallel without holding the
+/// filesystem, and the other requests on the session thread meanwhile.
+pub struct Dispatcher {
+    fs: Arc<RwLock<EossFs>>,
+    locks: Arc<InodeLocks>,
+    bridge: Bridge,
+    /// Reads in flight, at most `fs.parallel_reads()` or one
+    reads: Arc<Semaphore>,
+    permits: u32,
+}
+
+impl Dispatcher {
+    pub fn new(fs: EossFs) -> Self {
+        let permits = fs.parallel_reads().max(1) as u32;
+        Self {
+            bridge: fs.bridge(),
+            fs: Arc::new(RwLock::new(fs)),
+            locks: Arc::new(InodeLocks::new()),
+            reads: Arc::new(Semaphore::new(permits as usize)),
+            permits,
+        }
+    }
+
+    /// The filesystem served, shared with the tasks of its requests.
+    pub(crate) fn fs(&self) -> &Arc<RwLock<EossFs>> {
+        &self.fs
+    }
+}
+
+impl Filesystem for Dispatcher {
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.fs.write().init(req, config)
+    }
+
+    fn destroy(&mut self) {
+        // reads still in flight hold the filesystem
+        let _reads = self.bridge.wait(self.reads.acquire_many(self.permits));
+        self.fs.write().destroy()
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.fs.write().lookup(req, parent, name, reply)
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.fs.write().forget(req, ino, nlookup)
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        self.fs.write().getattr(req, ino, reply)
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        self.fs.write().statfs(req, ino, reply)
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.fs.write().open(req, ino, flags, reply)
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _inode = self.locks.write(ino);
+        let mut fs = self.fs.write();
+        fs.release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let _inode = self.locks.write(ino);
+        self.fs.write().setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+            flags, reply,
+        )
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let (fs, locks, uid) = (self.fs.clone(), self.locks.clone(), req.uid());
+        let (offset, size) = (offset as u64, size as usize);
+        let reads = self.reads.clone();
+        self.bridge.reply(reply, async move {
+            let _read = reads.acquire_owned().await;
+            blocking(move || read(&fs, &locks, ino, offset, size, (uid, fh))).await
+        })
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let _inode = self.locks.write(ino);
+        let mut fs = self.fs.write();
+        fs.write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        )
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let mut fs = self.fs.write();
+        fs.create(req, parent, name, mode, umask, flags, reply)
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.fs.write().mkdir(req, parent, name, mode, umask, reply)
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        self.fs.write().readdir(req, ino, fh, offset, reply)
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs.write().unlink(req, parent, name, reply)
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs.write().rmdir(req, parent, name, reply)
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let mut fs = self.fs.write();
+        fs.rename(req, parent, name, newparent, newname, flags, reply)
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let mut fs = self.fs.write();
+        fs.ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let _inode = self.locks.write(ino);
+        let mut fs = self.fs.write();
+        fs.fallocate(req, ino, fh, offset, length, mode, reply)
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.fs.write().getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.fs.write().listxattr(req, ino, size, reply)
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let mut fs = self.fs.write();
+        fs.setxattr(req, ino, name, value, flags, position, reply)
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs.write().removexattr(req, ino, name, reply)
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let _inode = self.locks.write(ino);
+        self.fs.write().flush(req, ino, fh, lock_owner, reply)
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.fs.write().fsync(req, ino, fh, datasync, reply)
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let mut fs = self.fs.write();
+        fs.getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let mut fs = self.fs.write();
+        fs.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
+    }
+}
+
+/// Read `size` bytes of inode `ino` at `offset` for `reader`, the uid and
+/// handle reading, holding the filesystem only before and after fetching.
+fn read(
+    fs: &RwLock<EossFs>,
+    locks: &InodeLocks,
+    ino: u64,
+    offset: u64,
+    size: usize,
+    reader: (u32, u64),
+) -> ReadData {
+    let _inode = locks.read(ino);
+    let pending = {
+        let shared = fs.read();
+        if let Some(data) = shared.read_stats(reader.1, offset, size) {
+            return Ok((Arc::new(data.to_vec()), 0..data.len()));
+        }
+        shared.start_read(ino, offset, size, Some(reader))
+    };
+    match pending {
+        Some(Ok(pending)) => {
+            let fetched = pending.fetch();
+            fs.read().finish_read(&pending, fetched)
+        }
+        Some(Err(e)) => Err(e),
+        // flushes buffered writes or loads the inode first
+        None => fs.write().read_data(ino, offset, size, Some(reader)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use fuser::FUSE_ROOT_ID;
+    use parking_lot::{Mutex, RwLock};
+
+    use super::{read, InodeLocks};
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+
+    fn shared<T: Send + Sync>() {}
+
+    /// Holds fetches of chunks on `gate` while `gated`, telling `waiting`.
+    struct Gate {
+        inner: MemoryProvider,
+        gated: AtomicBool,
+        gate: Mutex<()>,
+        waiting: Mutex<mpsc::Sender<()>>,
+    }
+
+    impl ChunkProvider for Gate {
+        fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+            if self.gated.load(Ordering::SeqCst) {
+                self.waiting.lock().send(()).unwrap();
+                drop(self.gate.lock());
+            }
+            self.inner.get_chunk_by_id(id)
+        }
+
+        fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+            self.inner.save_chunk(chunk)
+        }
+
+        fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+            self.inner.contains_chunk(id)
+        }
+
+        fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+            self.inner.get_object(id)
+        }
+
+        fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+            self.inner.save_object(id, data)
+        }
+
+        fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+            self.inner.delete_chunk(id)
+        }
+    }
+
+    /// Names of the methods of the `impl` block starting with `header` in
+    /// `source`.
+    fn methods(source: &str, header: &str) -> BTreeSet<String> {
+        let start = source.find(header).unwrap();
+        let block = &source[start..];
+        let block = &block[..block.find("\n}\n").unwrap()];
+        block
+            .lines()
+            .filter_map(|line| line.strip_prefix("    fn "))
+            .map(|line| line.split('(').next().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_forwarded() {
+        // every request the filesystem answers is served by a dispatcher
+        assert_eq!(
+            methods(
+                include_str!("fuse/filesystem.rs"),
+                "impl Filesystem for EossFs {"
+            ),
+            methods(
+                include_str!("dispatch.rs"),
+                "impl Filesystem for Dispatcher {"
+            )
+        );
+    }
+
+    #[test]
+    fn test_inode_locks() {
+        // the filesystem is read from worker threads
+        shared::<EossFs>();
+
+        let locks = Arc::new(InodeLocks::new());
+        let (first, second) = (locks.read(1), locks.read(1));
+        let (tx, rx) = mpsc::channel();
+        let writer = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                let _inode = locks.write(1);
+                tx.send(()).unwrap();
+            })
+        };
+        // other inodes are not held up by readers of inode 1
+        drop(locks.write(2));
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        drop((first, second));
+        rx.recv().unwrap();
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_unlocked() {
+        let (tx, rx) = mpsc::channel();
+        let provider = Arc::new(Gate {
+            inner: MemoryProvider::new(),
+            gated: AtomicBool::new(false),
+            gate: Mutex::new(()),
+            waiting: Mutex::new(tx),
+        });
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, &vec![7; CHUNK_SIZE]).unwrap();
+        fs.close().unwrap();
+        drop(fs);
+
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        let (attr, _) = fs.lookup_entry(FUSE_ROOT_ID, "file").unwrap();
+        let (fs, locks) = (Arc::new(RwLock::new(fs)), Arc::new(InodeLocks::new()));
+        let gate = provider.gate.lock();
+        provider.gated.store(true, Ordering::SeqCst);
+        let reader = {
+            let (fs, locks) = (fs.clone(), locks.clone());
+            thread::spawn(move || read(&fs, &locks, attr.ino, 0, 4096, (0, 0)))
+        };
+        rx.recv().unwrap();
+        provider.gated.store(false, Ordering::SeqCst);
+        // metadata changes go on while the read waits on the provider
+        let mut exclusive = fs.try_write_for(Duration::from_secs(5)).unwrap();
+        exclusive
+            .create_file(FUSE_ROOT_ID, "other", 0o644, 0, 0)
+            .unwrap();
+        drop(exclusive);
+        drop(gate);
+        let (data, range) = reader.join().unwrap().unwrap();
+        assert_eq!(data[range], [7; 4096]);
+        fs.write().close().unwrap();
+    }
+}
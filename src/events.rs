@@ -0,0 +1,135 @@
+use std::ffi::OsStr;
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+#[cfg(unix)]
+use fuser::Notifier;
+use parking_lot::Mutex;
+
+use crate::fs::Entry;
+use crate::id::ID_LENGTH;
+#[cfg(windows)]
+use crate::win::Notifier;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    Create,
+    Modify,
+    Delete,
+}
+
+/// A change of the entry `name` of directory `parent`, whose inode is
+/// `ino`, or 0 if none was assigned to it yet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub parent: u64,
+    pub name: String,
+    pub ino: u64,
+    /// Path from the root, joined by '/'
+    pub path: String,
+    /// Size of the entry after the change, or before it was deleted
+    pub size: u64,
+    /// Id of the entry, `None` if not found in the tree
+    pub id: Option<[u8; ID_LENGTH]>,
+}
+
+impl Event {
+    /// Event of a change of the entry at `path`, `entry` in the tree.
+    pub fn new(
+        kind: EventKind,
+        parent: u64,
+        path: &[String],
+        entry: Option<Entry>,
+        ino: u64,
+    ) -> Self {
+        Self {
+            kind,
+            parent,
+            name: path.last().cloned().unwrap_or_default(),
+            ino,
+            path: path.join("/"),
+            size: entry.as_ref().map_or(0, |entry| entry.attrs().size),
+            id: entry.map(|entry| *entry.id()),
+        }
+    }
+
+    /// Tell the kernel of a change it did not make itself, dropping what it
+    /// caches of the entry and reaching its inotify watchers.
+    pub fn notify(&self, notifier: &Notifier) -> io::Result<()> {
+        let name = OsStr::new(&self.name);
+        match self.kind {
+            EventKind::Create => notifier.inval_entry(self.parent, name),
+            EventKind::Modify => notifier.inval_inode(self.ino, 0, 0),
+            EventKind::Delete => notifier.delete(self.parent, self.ino, name),
+        }
+    }
+}
+
+/// Events fans changes of the tree out to subscribers, each queueing them
+/// up to its own capacity. Events beyond are dropped for that subscriber
+/// rather than hold up the filesystem.
+#[derive(Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<SyncSender<Event>>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive the events emitted from now on, until the receiver is dropped.
+    pub fn subscribe(&self, capacity: usize) -> Receiver<Event> {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.lock().is_empty()
+    }
+
+    pub fn emit(&self, event: Event) {
+        self.subscribers.lock().retain(|tx| {
+            !matches!(
+                tx.try_send(event.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    pub fn emit_all(&self, events: impl IntoIterator<Item = Event>) {
+        for event in events {
+            self.emit(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventKind, Events};
+
+    #[test]
+    fn test_subscribe() {
+        let events = Events::new();
+        let event = |name: &str| Event::new(EventKind::Create, 1, &[name.to_owned()], None, 2);
+        assert!(!events.has_subscribers());
+        let (all, lagging) = (events.subscribe(8), events.subscribe(1));
+        events.emit(event("a"));
+        events.emit(event("b"));
+        assert_eq!(
+            all.try_iter().collect::<Vec<_>>(),
+            vec![event("a"), event("b")]
+        );
+        // missed once its queue is full
+        assert_eq!(lagging.try_iter().collect::<Vec<_>>(), vec![event("a")]);
+
+        drop(lagging);
+        events.emit(event("c"));
+        assert_eq!(all.recv().unwrap(), event("c"));
+        drop(all);
+        events.emit(event("d"));
+        assert!(!events.has_subscribers());
+    }
+}
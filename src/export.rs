@@ -0,0 +1,345 @@
+#[cfg(unix)]
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chunk::CHUNK_SIZE;
+use crate::fs::{Attrs, Entry};
+use crate::meta::MetaError;
+use crate::provider::ChunkProvider;
+
+/// Size of the blocks of a tar archive.
+const TAR_BLOCK: usize = 512;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExportError {
+    #[error("path not found")]
+    NotFound,
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// What an export has done.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ExportStats {
+    pub dirs: usize,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Sink receives the entries exported, each directory before its entries.
+pub trait Sink {
+    /// Create the directory at `path`, relative to the export.
+    fn dir(&mut self, path: &str, attrs: &Attrs) -> io::Result<()>;
+    /// Write the file at `path` of `attrs.size` bytes from `data`.
+    fn file(&mut self, path: &str, attrs: &Attrs, data: &mut dyn Read) -> io::Result<()>;
+    /// Complete the export.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Export `entry` and everything beneath, a fully loaded tree if a
+/// directory, with its data read from `provider`. The root of the
+/// filesystem, with no name, is exported as its entries.
+pub fn export(
+    provider: &dyn ChunkProvider,
+    entry: Entry,
+    sink: &mut dyn Sink,
+) -> Result<ExportStats, ExportError> {
+    let mut stats = ExportStats::default();
+    walk(provider, entry, "", sink, &mut stats)?;
+    sink.finish()?;
+    Ok(stats)
+}
+
+fn walk(
+    provider: &dyn ChunkProvider,
+    entry: Entry,
+    parent: &str,
+    sink: &mut dyn Sink,
+    stats: &mut ExportStats,
+) -> Result<(), ExportError> {
+    let path = match (parent, entry.name()) {
+        ("", name) => name.to_owned(),
+        (parent, name) => format!("{}/{}", parent, name),
+    };
+    match entry {
+        Entry::Dir(dir) => {
+            if !path.is_empty() {
+                sink.dir(&path, &dir.attrs)?;
+                stats.dirs += 1;
+            }
+            for node in dir.entries.values() {
+                walk(provider, node.as_entry(), &path, sink, stats)?;
+            }
+        }
+        file => {
+            let reader = EntryReader {
+                provider,
+                entry: file,
+                offset: 0,
+            };
+            // whole chunks read at once, each verified as a whole
+            let mut reader = BufReader::with_capacity(CHUNK_SIZE, reader);
+            sink.file(&path, file.attrs(), &mut reader)?;
+            stats.files += 1;
+            stats.bytes += file.attrs().size;
+        }
+    }
+    Ok(())
+}
+
+/// EntryReader reads the data of a file from its chunks.
+struct EntryReader<'a> {
+    provider: &'a dyn ChunkProvider,
+    entry: Entry<'a>,
+    offset: u64,
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.entry {
+            Entry::File(file) => file.read(self.provider, self.offset, buf),
+            Entry::TinyFile(file) => file.read(self.provider, self.offset, buf),
+            Entry::Dir(_) => return Err(io::ErrorKind::InvalidInput.into()),
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// DirSink writes entries beneath a local directory, with their permissions
+/// and modification times.
+pub struct DirSink {
+    root: PathBuf,
+}
+
+impl DirSink {
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+}
+
+impl Sink for DirSink {
+    fn dir(&mut self, path: &str, attrs: &Attrs) -> io::Result<()> {
+        let path = self.root.join(path);
+        fs::create_dir_all(&path)?;
+        set_perm(&path, attrs.perm as u32 | 0o700)
+    }
+
+    fn file(&mut self, path: &str, attrs: &Attrs, data: &mut dyn Read) -> io::Result<()> {
+        let path = self.root.join(path);
+        io::copy(data, &mut File::create(&path)?)?;
+        // before it may be made read-only
+        set_mtime(&path, attrs.mtime)?;
+        set_perm(&path, attrs.perm as u32)
+    }
+}
+
+#[cfg(unix)]
+fn set_perm(path: &Path, perm: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(perm))
+}
+
+/// Set `perm` on `path` as far as Windows can, read-only without write
+/// permission for the owner.
+#[cfg(windows)]
+fn set_perm(path: &Path, perm: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(perm & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(unix)]
+fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    let since = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: since.as_secs() as libc::time_t,
+            tv_nsec: since.subsec_nanos() as _,
+        },
+    ];
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    File::options().write(true).open(path)?.set_modified(mtime)
+}
+
+/// TarSink writes entries as a POSIX tar archive, with long paths, large
+/// sizes and large ids in pax headers.
+pub struct TarSink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TarSink<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    fn header(&mut self, path: &str, attrs: &Attrs, kind: u8, size: u64) -> io::Result<()> {
+        let mut pax = String::new();
+        if path.len() > 100 {
+            pax.push_str(&pax_record("path", path));
+        }
+        // 11 octal digits
+        if size >= 1 << 33 {
+            pax.push_str(&pax_record("size", &size.to_string()));
+        }
+        // 7 octal digits
+        for (key, id) in [("uid", attrs.uid), ("gid", attrs.gid)].iter() {
+            if *id >= 1 << 21 {
+                pax.push_str(&pax_record(key, &id.to_string()));
+            }
+        }
+        if !pax.is_empty() {
+            let block = header_block("pax", attrs, b'x', pax.len() as u64);
+            self.out.write_all(&block)?;
+            self.write_padded(pax.as_bytes())?;
+        }
+        // the tail of a long path, set by the pax header
+        let start = (path.len().saturating_sub(100)..path.len())
+            .find(|n| path.is_char_boundary(*n))
+            .unwrap_or(0);
+        let name = &path[start..];
+        self.out.write_all(&header_block(name, attrs, kind, size))
+    }
+
+    fn write_padded(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.pad(data.len() as u64)
+    }
+
+    fn pad(&mut self, len: u64) -> io::Result<()> {
+        let rest = (TAR_BLOCK - (len % TAR_BLOCK as u64) as usize) % TAR_BLOCK;
+        self.out.write_all(&[0; TAR_BLOCK][..rest])
+    }
+}
+
+impl<W: Write> Sink for TarSink<W> {
+    fn dir(&mut self, path: &str, attrs: &Attrs) -> io::Result<()> {
+        self.header(&format!("{}/", path), attrs, b'5', 0)
+    }
+
+    fn file(&mut self, path: &str, attrs: &Attrs, data: &mut dyn Read) -> io::Result<()> {
+        self.header(path, attrs, b'0', attrs.size)?;
+        let copied = io::copy(&mut data.take(attrs.size), &mut self.out)?;
+        if copied != attrs.size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.pad(copied)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.write_all(&[0; 2 * TAR_BLOCK])?;
+        self.out.flush()
+    }
+}
+
+/// A ustar header block.
+fn header_block(name: &str, attrs: &Attrs, kind: u8, size: u64) -> [u8; TAR_BLOCK] {
+    let mut block = [0; TAR_BLOCK];
+    let mtime = attrs
+        .mtime
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut block[100..108], attrs.perm as u64);
+    // large ids set by the pax header
+    octal(&mut block[108..116], (attrs.uid as u64).min((1 << 21) - 1));
+    octal(&mut block[116..124], (attrs.gid as u64).min((1 << 21) - 1));
+    octal(&mut block[124..136], size.min((1 << 33) - 1));
+    octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..265].copy_from_slice(b"ustar\x0000");
+    // summed with the checksum field as spaces
+    block[148..156].copy_from_slice(b"        ");
+    let sum: u64 = block.iter().map(|b| *b as u64).sum();
+    octal(&mut block[148..155], sum);
+    block
+}
+
+/// Write `value` in octal, zero padded and terminated, to `field`.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(&digits.as_bytes()[digits.len() - field.len()..]);
+}
+
+/// A pax record, prefixed by its own length.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {}={}\n", key, value);
+    let mut len = rest.len() + 1;
+    while (len.to_string().len() + rest.len()) != len {
+        len += 1;
+    }
+    format!("{}{}", len, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, pax_record, TarSink, TAR_BLOCK};
+    use crate::allocator::TinyFileAllocator;
+    use crate::fs::{Attrs, DirMeta, Entry, Node, TinyFileMeta};
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_export_tar() {
+        let provider = MemoryProvider::new();
+        let mut allocator = TinyFileAllocator::new();
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut dir = DirMeta::new("dir".to_owned(), Attrs::new(0o755, 0, 0));
+        let mut file = TinyFileMeta::new("file".to_owned(), Attrs::new(0o644, 0, 0));
+        file.write(&provider, &mut allocator, 0, b"hello").unwrap();
+        dir.insert(Node::TinyFile(file));
+        root.insert(Node::Dir(dir));
+
+        let mut tar = TarSink::new(Vec::new());
+        let stats = export(&provider, Entry::Dir(&root), &mut tar).unwrap();
+        assert_eq!((stats.dirs, stats.files, stats.bytes), (1, 1, 5));
+        let archive = tar.out;
+        // a header for each entry, a block of data and the end
+        assert_eq!(archive.len(), 5 * TAR_BLOCK);
+        assert_eq!(&archive[..4], b"dir/");
+        assert_eq!(archive[156], b'5');
+        assert_eq!(&archive[TAR_BLOCK..TAR_BLOCK + 8], b"dir/file");
+        assert_eq!(&archive[2 * TAR_BLOCK..2 * TAR_BLOCK + 5], b"hello");
+        assert!(archive[3 * TAR_BLOCK..].iter().all(|b| *b == 0));
+
+        let record = pax_record("path", "a");
+        assert_eq!(record, "9 path=a\n");
+
+        // a large uid in a pax header, the field left at its largest
+        let mut tar = TarSink::new(Vec::new());
+        let attrs = Attrs::new(0o644, 3_000_000, 0);
+        tar.header("file", &attrs, b'0', 0).unwrap();
+        let record = pax_record("uid", "3000000");
+        assert_eq!(
+            &tar.out[TAR_BLOCK..TAR_BLOCK + record.len()],
+            record.as_bytes()
+        );
+        assert_eq!(
+            &tar.out[2 * TAR_BLOCK + 108..2 * TAR_BLOCK + 116],
+            b"7777777\0"
+        );
+    }
+}
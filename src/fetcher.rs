@@ -0,0 +1,346 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::chunk::CHUNK_SIZE;
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+/// Times a fetch failing for a reason likely to pass is tried again.
+const RETRIES: u32 = 3;
+/// Wait before the first retry, doubled for each next one.
+const BACKOFF: Duration = Duration::from_millis(100);
+
+thread_local! {
+    /// Set while serving a metadata operation
+    static INTERACTIVE: Cell<bool> = Cell::new(false);
+}
+
+/// Fetch chunks missed by `f` as interactive, ahead of reads.
+pub fn interactive<T>(f: impl FnOnce() -> T) -> T {
+    let outer = INTERACTIVE.with(|interactive| interactive.replace(true));
+    let result = f();
+    INTERACTIVE.with(|interactive| interactive.set(outer));
+    result
+}
+
+/// Who waits for a chunk fetched.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Priority {
+    /// A metadata operation, e.g. a lookup or a directory listed.
+    Interactive,
+    /// A read by the kernel.
+    Foreground,
+    /// A prefetch ahead of reads on behalf of an owner, e.g. a file handle,
+    /// served in turn with other owners.
+    Background(u64),
+}
+
+impl Priority {
+    /// The priority of a fetch waited for by the current thread.
+    pub fn current() -> Self {
+        if INTERACTIVE.with(Cell::get) {
+            Priority::Interactive
+        } else {
+            Priority::Foreground
+        }
+    }
+
+    /// Lower is served first.
+    fn rank(self) -> u8 {
+        match self {
+            Priority::Interactive => 0,
+            Priority::Foreground => 1,
+            Priority::Background(_) => 2,
+        }
+    }
+}
+
+/// A fetch in progress, shared by all requests of the same chunk.
+struct Flight {
+    /// Rank of the most urgent request waiting for it
+    rank: AtomicU8,
+    result: Mutex<Option<Result<Arc<Vec<u8>>, String>>>,
+    done: Condvar,
+}
+
+/// A fetch waiting for a slot.
+struct Waiter {
+    ticket: u64,
+    owner: u64,
+    flight: Arc<Flight>,
+}
+
+struct State {
+    running: usize,
+    next_ticket: u64,
+    waiting: Vec<Waiter>,
+    /// Ticket last granted to each owner waiting, owners served longest
+    /// ago go first
+    served: HashMap<u64, u64>,
+    flights: HashMap<Id, Arc<Flight>>,
+}
+
+impl State {
+    /// Ticket of the waiter to start next, if a slot is free for it.
+    /// A slot is kept for interactive and foreground fetches if more than
+    /// one, so they never queue behind prefetches.
+    fn next(&self, limit: usize) -> Option<u64> {
+        if self.running >= limit {
+            return None;
+        }
+        let waiter = self.waiting.iter().min_by_key(|waiter| {
+            let rank = waiter.flight.rank.load(Ordering::SeqCst);
+            let served = self.served.get(&waiter.owner).copied().unwrap_or(0);
+            (rank, served, waiter.ticket)
+        })?;
+        let background = waiter.flight.rank.load(Ordering::SeqCst) == 2;
+        if background && limit > 1 && self.running >= limit - 1 {
+            return None;
+        }
+        Some(waiter.ticket)
+    }
+}
+
+/// Fetcher fetches chunks from a provider on behalf of concurrent callers.
+/// Requests of a chunk already being fetched wait for that fetch instead
+/// of fetching it again, distinct chunks are fetched in parallel up to a
+/// limit. Waiting fetches start interactive first, then foreground, then
+/// background ones in turn between their owners.
+pub struct Fetcher {
+    inner: Arc<dyn ChunkProvider>,
+    limit: usize,
+    state: Mutex<State>,
+    slot: Condvar,
+}
+
+impl Fetcher {
+    /// Fetch from `inner` at most `limit` chunks at a time.
+    pub fn new(inner: Arc<dyn ChunkProvider>, limit: usize) -> Self {
+        Self {
+            inner,
+            limit: limit.max(1),
+            state: Mutex::new(State {
+                running: 0,
+                next_ticket: 1,
+                waiting: Vec::new(),
+                served: HashMap::new(),
+                flights: HashMap::new(),
+            }),
+            slot: Condvar::new(),
+        }
+    }
+
+    /// Data of chunk `id`.
+    pub fn fetch(&self, id: &Id, priority: Priority) -> Result<Vec<u8>, ChunkProviderError> {
+        let mut state = self.state.lock();
+        if let Some(flight) = state.flights.get(id).cloned() {
+            // a prefetch waiting for a slot is read now
+            flight.rank.fetch_min(priority.rank(), Ordering::SeqCst);
+            self.slot.notify_all();
+            drop(state);
+            return Self::wait(&flight);
+        }
+        let flight = Arc::new(Flight {
+            rank: AtomicU8::new(priority.rank()),
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        });
+        state.flights.insert(id.clone(), flight.clone());
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        let owner = match priority {
+            Priority::Background(owner) => owner,
+            _ => 0,
+        };
+        state.waiting.push(Waiter {
+            ticket,
+            owner,
+            flight: flight.clone(),
+        });
+        while state.next(self.limit) != Some(ticket) {
+            self.slot.wait(&mut state);
+        }
+        state.waiting.retain(|waiter| waiter.ticket != ticket);
+        if state.waiting.iter().any(|waiter| waiter.owner == owner) {
+            state.served.insert(owner, ticket);
+        } else {
+            state.served.remove(&owner);
+        }
+        state.running += 1;
+        // the next may start as well
+        self.slot.notify_all();
+        drop(state);
+
+        let result = self.get(id);
+
+        let mut state = self.state.lock();
+        state.running -= 1;
+        state.flights.remove(id);
+        self.slot.notify_all();
+        drop(state);
+        *flight.result.lock() = Some(match &result {
+            Ok(data) => Ok(Arc::new(data.clone())),
+            Err(e) => Err(e.to_string()),
+        });
+        flight.done.notify_all();
+        result
+    }
+
+    /// Data of chunk `id` from the inner provider, tried again while it
+    /// fails for a reason likely to pass.
+    fn get(&self, id: &Id) -> Result<Vec<u8>, ChunkProviderError> {
+        let mut tries = 0;
+        loop {
+            match self.inner.get_chunk_by_id(id) {
+                Ok(chunk) => {
+                    let mut data = vec![0; CHUNK_SIZE];
+                    chunk.read_at(0, &mut data);
+                    return Ok(data);
+                }
+                Err(e) if e.is_retryable() && tries < RETRIES => {
+                    tracing::debug!("retrying fetch of chunk {}: {}", id.hex(), e);
+                    thread::sleep(BACKOFF * 2u32.pow(tries));
+                    tries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Wait for the result of a fetch started by another caller.
+    fn wait(flight: &Flight) -> Result<Vec<u8>, ChunkProviderError> {
+        let mut result = flight.result.lock();
+        while result.is_none() {
+            flight.done.wait(&mut result);
+        }
+        match result.as_ref().unwrap() {
+            Ok(data) => Ok(data.to_vec()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.clone()).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fetcher, Priority};
+    use crate::chunk::Chunk;
+    use crate::id::Id;
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::memory::MemoryProvider;
+    use parking_lot::Mutex;
+    use std::io;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Logs and slows down fetches, failing the first `failures`.
+    struct Slow {
+        inner: MemoryProvider,
+        fetched: Mutex<Vec<Id>>,
+        failures: Mutex<usize>,
+    }
+
+    impl Slow {
+        fn new() -> Self {
+            Self {
+                inner: MemoryProvider::new(),
+                fetched: Mutex::new(Vec::new()),
+                failures: Mutex::new(0),
+            }
+        }
+    }
+
+    impl ChunkProvider for Slow {
+        fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+            self.fetched.lock().push(id.clone());
+            thread::sleep(Duration::from_millis(50));
+            let mut failures = self.failures.lock();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(io::Error::from(io::ErrorKind::ConnectionReset).into());
+            }
+            self.inner.get_chunk_by_id(id)
+        }
+
+        fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+            self.inner.save_chunk(chunk)
+        }
+
+        fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+            self.inner.contains_chunk(id)
+        }
+
+        fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+            self.inner.delete_chunk(id)
+        }
+    }
+
+    #[test]
+    fn test_single_flight() {
+        let slow = Arc::new(Slow::new());
+        let fetcher = Arc::new(Fetcher::new(slow.clone(), 2));
+        let id = Id::new_random();
+        let threads: Vec<_> = (0..4)
+            .map(|n| {
+                let (fetcher, id) = (fetcher.clone(), id.clone());
+                let priority = match n {
+                    0 => Priority::Foreground,
+                    _ => Priority::Background(n),
+                };
+                thread::spawn(move || fetcher.fetch(&id, priority).unwrap())
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(slow.fetched.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_retried() {
+        let slow = Arc::new(Slow::new());
+        let fetcher = Fetcher::new(slow.clone(), 2);
+        *slow.failures.lock() = 2;
+        fetcher
+            .fetch(&Id::new_random(), Priority::Foreground)
+            .unwrap();
+        assert_eq!(slow.fetched.lock().len(), 3);
+    }
+
+    #[test]
+    fn test_owners_in_turn() {
+        let slow = Arc::new(Slow::new());
+        // one slot kept for reads, prefetches run one at a time
+        let fetcher = Arc::new(Fetcher::new(slow.clone(), 2));
+        let ids: Vec<Id> = (0..4).map(|_| Id::new_random()).collect();
+        let threads: Vec<_> = [1, 1, 1, 2]
+            .iter()
+            .zip(ids.iter())
+            .map(|(owner, id)| {
+                let (fetcher, id, owner) = (fetcher.clone(), id.clone(), *owner);
+                let thread =
+                    thread::spawn(move || fetcher.fetch(&id, Priority::Background(owner)).unwrap());
+                thread::sleep(Duration::from_millis(10));
+                thread
+            })
+            .collect();
+        // a read is not queued behind them
+        let read = Id::new_random();
+        fetcher.fetch(&read, Priority::Foreground).unwrap();
+        assert_eq!(slow.fetched.lock()[1], read);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let fetched = slow.fetched.lock();
+        let position = |id| fetched.iter().position(|fetched| fetched == id).unwrap();
+        // the other owner is served before the third prefetch of the first
+        assert!(position(&ids[3]) < position(&ids[2]));
+    }
+}
@@ -0,0 +1,371 @@
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fmt::Display;
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::slice;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::{EACCES, EINVAL, EIO};
+
+use crate::crypt::MasterKey;
+use crate::id::Id;
+use crate::keys::{self, KeySource};
+use crate::options::MountOptions;
+use crate::provider::ChunkProvider;
+use crate::superblock::{Superblock, SUPERBLOCK_ID};
+use crate::uri::ProviderUri;
+use crate::vfs::{FileHandle, FileKind, Metadata, Vfs};
+
+pub const EOSS_DIR: u32 = 1;
+pub const EOSS_FILE: u32 = 2;
+
+/// An open filesystem.
+pub struct EossVfs {
+    vfs: Vfs,
+}
+
+/// An open file of a filesystem, used with the filesystem it is of.
+pub struct EossFile {
+    file: FileHandle,
+}
+
+/// Attributes of an entry, times as seconds and nanoseconds since the epoch.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct EossStat {
+    /// `EOSS_DIR` or `EOSS_FILE`.
+    pub kind: u32,
+    pub perm: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub atime_sec: i64,
+    pub atime_nsec: u32,
+    pub mtime_sec: i64,
+    pub mtime_nsec: u32,
+    pub ctime_sec: i64,
+    pub ctime_nsec: u32,
+}
+
+/// Called by `eoss_readdir` with the name and attributes of each entry, and
+/// its `context`. Returning non-zero stops the listing.
+pub type EossDirCallback =
+    extern "C" fn(name: *const c_char, stat: *const EossStat, context: *mut c_void) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// Open the filesystem stored at `uri`, as given on the command line, an
+/// encrypted one unlocked with the secret from `key` as given to `--key`,
+/// null otherwise. Returns null on failure, described by `eoss_last_error`.
+///
+/// # Safety
+/// `uri` is a nul-terminated string, and `key` one or null.
+#[no_mangle]
+pub unsafe extern "C" fn eoss_open(uri: *const c_char, key: *const c_char) -> *mut EossVfs {
+    let opened = str_arg(uri).and_then(|uri| {
+        let provider = uri.parse::<ProviderUri>().and_then(|uri| uri.open());
+        let provider = provider.map_err(|e| fail(EIO, e))?;
+        let mut options = MountOptions::default();
+        if !key.is_null() {
+            options.key = Some(unlock(provider.as_ref(), str_arg(key)?)?);
+        }
+        Vfs::open(provider, options).map_err(|e| fail(EIO, e))
+    });
+    match opened {
+        Ok(vfs) => Box::into_raw(Box::new(EossVfs { vfs })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Persist everything and close `vfs`, freeing it even on failure, null
+/// closing nothing. Returns 0, or a negated errno.
+///
+/// # Safety
+/// `vfs` is from `eoss_open` or null, its files all closed, and not used
+/// after.
+#[no_mangle]
+pub unsafe extern "C" fn eoss_close(vfs: *mut EossVfs) -> c_int {
+    if vfs.is_null() {
+        return 0;
+    }
+    let mut vfs = Box::from_raw(vfs);
+    result(vfs.vfs.close().map_err(|e| fail(EIO, e)))
+}
+
+/// Attributes of the entry at `path` into `stat`. Returns 0, or a negated
+/// errno.
+///
+/// # Safety
+/// `vfs` is from `eoss_open`, `path` a nul-terminated string and `stat`
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn eoss_stat(
+    vfs: *mut EossVfs,
+    path: *const c_char,
+    stat: *mut EossStat,
+) -> c_int {
+    let metadata = str_arg(path).and_then(|path| (*vfs).vfs.metadata(path).map_err(io_fail));
+    result(metadata.map(|metadata| *stat = eoss_stat_of(&metadata)))
+}
+
+/// Call `callback` with each entry of the directory at `path`, in no
+/// particular order. Returns 0, the non-zero value `callback` stopped with,
+/// or a negated errno.
+///
+/// # Safety
+/// `vfs` is from `eoss_open` and `path` a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn eoss_readdir(
+    vfs: *mut EossVfs,
+    path: *const c_char,
+    callback: EossDirCallback,
+    context: *mut c_void,
+) -> c_int {
+    let entries = match str_arg(path).and_then(|path| (*vfs).vfs.read_dir(path).map_err(io_fail)) {
+        Ok(entries) => entries,
+        Err(errno) => return -errno,
+    };
+    for entry in entries {
+        // names hold no nul, they are paths on the mount
+        let name = CString::new(entry.name).unwrap_or_default();
+        let stat = eoss_stat_of(&entry.metadata);
+        match callback(name.as_ptr(), &stat, context) {
+            0 => {}
+            stopped => return stopped,
+        }
+    }
+    0
+}
+
+/// Open the file at `path`, or with `create` create it with permission
+/// `perm`, failing if it exists. Returns null on failure, described by
+/// `eoss_last_error`.
+///
+/// # Safety
+/// `vfs` is from `eoss_open` and `path` a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn eoss_file_open(
+    vfs: *mut EossVfs,
+    path: *const c_char,
+    create: bool,
+    perm: u32,
+) -> *mut EossFile {
+    let vfs = &mut (*vfs).vfs;
+    let opened = str_arg(path).and_then(|path| {
+        let opened = match create {
+            true => vfs.create(path, perm as u16),
+            false => vfs.open_file(path),
+        };
+        opened.map_err(io_fail)
+    });
+    match opened {
+        Ok(file) => Box::into_raw(Box::new(EossFile { file })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Close `file`, persisting what was written to it, and free it even on
+/// failure. Returns 0, or a negated errno.
+///
+/// # Safety
+/// `file` is from `eoss_file_open` on `vfs`, and not used after.
+#[no_mangle]
+pub unsafe extern "C" fn eoss_file_close(vfs: *mut EossVfs, file: *mut EossFile) -> c_int {
+    let file = Box::from_raw(file);
+    result((*vfs).vfs.close_file(file.file).map_err(io_fail))
+}
+
+/// Read at most `len` bytes at `offset` of `file` into `buf`. Returns the
+/// bytes read, fewer at its end, or a negated errno.
+///
+/// # Safety
+/// `file` is from `eoss_file_open` on `vfs`, and `buf` holds `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn eoss_read(
+    vfs: *mut EossVfs,
+    file: *const EossFile,
+    offset: u64,
+    buf: *mut u8,
+    len: usize,
+) -> isize {
+    let buf = slice::from_raw_parts_mut(buf, len);
+    match (*vfs).vfs.read(&(*file).file, offset, buf) {
+        Ok(read) => read as isize,
+        Err(e) => -io_fail(e) as isize,
+    }
+}
+
+/// Write the `len` bytes of `buf` at `offset` of `file`. Returns the bytes
+/// written, or a negated errno.
+///
+/// # Safety
+/// `file` is from `eoss_file_open` on `vfs`, and `buf` holds `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn eoss_write(
+    vfs: *mut EossVfs,
+    file: *const EossFile,
+    offset: u64,
+    buf: *const u8,
+    len: usize,
+) -> isize {
+    let buf = slice::from_raw_parts(buf, len);
+    match (*vfs).vfs.write(&(*file).file, offset, buf) {
+        Ok(written) => written as isize,
+        Err(e) => -io_fail(e) as isize,
+    }
+}
+
+/// Message of the last failure on this thread, null if none. Valid until
+/// the next call failing on this thread.
+#[no_mangle]
+pub extern "C" fn eoss_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Record `e` as the last error, returning `errno`.
+/// Master key of the filesystem stored in `provider`, unlocked with the
+/// secret from `source`.
+fn unlock(provider: &dyn ChunkProvider, source: &str) -> Result<MasterKey, c_int> {
+    let superblock = Superblock::load(provider, &Id::new(SUPERBLOCK_ID));
+    let superblock = superblock.map_err(|e| fail(EIO, e))?;
+    let secret = source.parse::<KeySource>().and_then(|source| source.load());
+    let secret = secret.map_err(|e| fail(EINVAL, e))?;
+    keys::unlock(&superblock.key_slots, &secret).map_err(|e| fail(EACCES, e))
+}
+
+fn fail(errno: c_int, e: impl Display) -> c_int {
+    let message = CString::new(e.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    errno
+}
+
+fn io_fail(e: io::Error) -> c_int {
+    fail(e.raw_os_error().unwrap_or(EIO), e)
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(fail(EINVAL, "null string"));
+    }
+    CStr::from_ptr(s).to_str().map_err(|e| fail(EINVAL, e))
+}
+
+fn result(result: Result<(), c_int>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}
+
+fn eoss_stat_of(metadata: &Metadata) -> EossStat {
+    let time = |time: SystemTime| {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        (since.as_secs() as i64, since.subsec_nanos())
+    };
+    let ((atime_sec, atime_nsec), (mtime_sec, mtime_nsec), (ctime_sec, ctime_nsec)) = (
+        time(metadata.atime),
+        time(metadata.mtime),
+        time(metadata.ctime),
+    );
+    EossStat {
+        kind: match metadata.kind {
+            FileKind::Dir => EOSS_DIR,
+            FileKind::File => EOSS_FILE,
+        },
+        perm: metadata.perm as u32,
+        uid: metadata.uid,
+        gid: metadata.gid,
+        size: metadata.size,
+        atime_sec,
+        atime_nsec,
+        mtime_sec,
+        mtime_nsec,
+        ctime_sec,
+        ctime_nsec,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::{CStr, CString};
+    use std::fs;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::ptr;
+
+    use super::{
+        eoss_close, eoss_file_close, eoss_file_open, eoss_last_error, eoss_open, eoss_read,
+        eoss_readdir, eoss_stat, eoss_write, EossStat, EOSS_FILE,
+    };
+    use crate::crypt::MasterKey;
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::keys::{KdfParams, KeySlot, Secret};
+    use crate::options::FormatOptions;
+    use crate::providers::local::LocalProvider;
+
+    extern "C" fn collect(name: *const c_char, stat: *const EossStat, names: *mut c_void) -> c_int {
+        let names = unsafe { &mut *(names as *mut Vec<(String, u64)>) };
+        let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap().to_owned();
+        names.push((name, unsafe { (*stat).size }));
+        0
+    }
+
+    #[test]
+    fn test_ffi() {
+        let dir = env::temp_dir().join(format!("eoss-ffi-{}", Id::new_random().hex()));
+        let provider = LocalProvider::new(&dir).unwrap();
+        let key = MasterKey::new_random();
+        let cheap = KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let key_file = env::temp_dir().join(format!("eoss-ffi-key-{}", Id::new_random().hex()));
+        fs::write(&key_file, [7; 64]).unwrap();
+        let options = FormatOptions {
+            key_slots: vec![KeySlot::new(&key, &Secret::KeyFile(vec![7; 64]), cheap).unwrap()],
+            key: Some(key),
+            ..FormatOptions::default()
+        };
+        EossFs::format(&provider, &options).unwrap();
+        let uri = CString::new(dir.to_str().unwrap()).unwrap();
+        let source = CString::new(format!("file:{}", key_file.display())).unwrap();
+        let path = CString::new("file").unwrap();
+        unsafe {
+            assert!(eoss_open(uri.as_ptr(), ptr::null()).is_null());
+            let vfs = eoss_open(uri.as_ptr(), source.as_ptr());
+            assert!(!vfs.is_null());
+            let file = eoss_file_open(vfs, path.as_ptr(), true, 0o644);
+            assert_eq!(eoss_write(vfs, file, 0, b"hello".as_ptr(), 5), 5);
+            let mut buf = [0; 8];
+            assert_eq!(eoss_read(vfs, file, 1, buf.as_mut_ptr(), buf.len()), 4);
+            assert_eq!(&buf[..4], b"ello");
+            assert_eq!(eoss_file_close(vfs, file), 0);
+
+            let mut stat = EossStat::default();
+            assert_eq!(eoss_stat(vfs, path.as_ptr(), &mut stat), 0);
+            assert_eq!((stat.kind, stat.size, stat.perm), (EOSS_FILE, 5, 0o644));
+            let mut names: Vec<(String, u64)> = Vec::new();
+            let root = CString::new("/").unwrap();
+            let context = &mut names as *mut _ as *mut c_void;
+            assert_eq!(eoss_readdir(vfs, root.as_ptr(), collect, context), 0);
+            assert_eq!(names, [("file".to_owned(), 5)]);
+
+            let missing = CString::new("missing").unwrap();
+            assert!(eoss_file_open(vfs, missing.as_ptr(), false, 0).is_null());
+            assert!(!eoss_last_error().is_null());
+            assert_eq!(eoss_close(vfs), 0);
+            assert_eq!(eoss_close(ptr::null_mut()), 0);
+        }
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_file(key_file).unwrap();
+    }
+}
@@ -1,58 +1,1032 @@
-use crate::id::ID_LENGTH;
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::SystemTime;
 
-pub struct RawChunk;
-pub struct MetaChunk;
-pub struct TinyFileChunk;
+use crate::allocator::{TinyFileAllocator, SHARED_BLOCKS};
+use crate::chunk::{Chunk, BLOCK_SIZE, CHUNK_SIZE};
+use crate::id::{ChunkId, Data, Id, ID_LENGTH};
+use crate::merkle::{self, UNHASHED};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::quota::{Quota, Usage};
 
-enum ChunkType {
-    /// Chunk holds raw data (file part)
-    Raw,
-    /// Chunk holds a directory metadata
-    DirMeta,
-    /// Chunk holds tiny files
-    TinyFiles,
-    /// If a provider does not known the type, leave it as Unknown
-    Unknown,
-}
-
-struct TinyFileChunkMeta<'a> {
-    data: &'a [u8; 20],
-}
+/// Files up to this size are stored as tiny files in shared chunks.
+pub const TINY_FILE_MAX: u64 = (SHARED_BLOCKS * BLOCK_SIZE) as u64;
+/// Flag of immutable entries, as set by `chattr +i`.
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+/// Flag of append-only entries, as set by `chattr +a`.
+pub const FS_APPEND_FL: u32 = 0x20;
+/// Flag of entries pinned in the chunk cache, not a chattr flag so hidden
+/// from `lsattr`.
+pub const PINNED_FL: u32 = 0x8000_0000;
+/// Bits of the storage class of an entry, see `StorageClass`, clear if
+/// none is set on it.
+pub const STORAGE_CLASS_FL: u32 = 0x6000_0000;
+/// Bits of the compression of an entry, see `Compression`, clear if none
+/// is set on it.
+pub const COMPRESSION_FL: u32 = 0x1fc0_0000;
+/// Flags set by `chattr`.
+pub const CHATTR_FLAGS: u32 = FS_IMMUTABLE_FL | FS_APPEND_FL;
 
 /// FileMeta stores the metadata of a file with size greater or
 /// equal than 4MiB.
 /// A file with size greater or equal than 4MiB SHOULD be store
 /// in multiple *contiguous* exclusive chunks.
-struct FileMeta {
-    id: [u8; ID_LENGTH],
+/// The id of the nth chunk is derived from the file id by `ChunkId::derive_n`,
+/// or is the hash of its contents once hashed if content addressed.
+/// The last chunk is used partially according to `Attrs::size`.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileMeta {
+    pub name: String,
+    pub id: Id,
     /// POSIX attributes contains the size and blocks of this file.
-    attrs: Attrs,
+    pub attrs: Attrs,
+    /// Hash of each chunk as last written, the leaves of the Merkle tree of
+    /// this file, `merkle::UNHASHED` if written since
+    pub hashes: Vec<[u8; 32]>,
+    /// Whether chunks are stored at the hash of their contents once hashed,
+    /// so equal chunks are stored once
+    pub content: bool,
 }
 /// TinyFileMeta stores the metadata of a file with size less than
 /// 4MiB.
 /// A file with size less than 4MiB MAY be stored with in
 /// a shared chunk.
-struct TinyFileMeta {
-    id: [u8; ID_LENGTH],
-    chunk_id: [u8; ID_LENGTH],
-    chunk_offset: u16,
+/// The file takes `chunk_blocks` blocks of the shared chunk from block
+/// `chunk_offset`, an empty file may take none.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TinyFileMeta {
+    pub name: String,
+    pub id: Id,
+    pub chunk_id: Id,
+    pub chunk_offset: u16,
+    pub chunk_blocks: u16,
     /// POSIX attributes contains the size and blocks of this file.
-    attrs: Attrs,
+    pub attrs: Attrs,
 }
 
 /// DirMeta stores the metadata of a directory.
 /// A directory may contains 0 or more sub-directories.
 /// A directory may contains 0 or more files.
-struct DirMeta {
-    dirs: Vec<DirMeta>,
-    files: Vec<FileMeta>,
-    tiny_files: Vec<TinyFileMeta>,
-    attrs: Attrs,
+/// Entries are indexed by name, the name of an entry is unique in a directory.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DirMeta {
+    pub name: String,
+    /// Id of the MetaChunks holding this directory
+    pub id: Id,
+    pub entries: BTreeMap<String, Node>,
+    pub attrs: Attrs,
+    /// Checksums of the header and buckets of this directory last stored,
+    /// unchanged ones are not written again
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub stored: Vec<[u8; 32]>,
+    /// Whether `entries` are loaded from the provider
+    pub loaded: bool,
+    /// Space used beneath this directory
+    pub usage: Usage,
+    pub quota: Quota,
+    /// Number of previous versions kept of files in this directory when
+    /// overwritten, none if 0
+    pub versions: u32,
 }
 
 /// Attrs contains all needed POSIX attributes
-struct Attrs {
-    size: u64,
-    blocks: u64,
-    // ... more POSIX attributes
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Attrs {
+    pub size: u64,
+    pub blocks: u64,
+    /// Permission bits
+    pub perm: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    /// chattr-style flags, see `FS_IMMUTABLE_FL` and `FS_APPEND_FL`,
+    /// along with `PINNED_FL`, `STORAGE_CLASS_FL` and `COMPRESSION_FL`
+    pub flags: u32,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TinyFileError {
+    #[error("file of {0} bytes cannot fit in a shared chunk")]
+    TooLarge(u64),
+    #[error(transparent)]
+    ProviderError(#[from] ChunkProviderError),
+}
+
+/// A reference to an entry in a directory.
+#[derive(Clone, Copy)]
+pub enum Entry<'a> {
+    Dir(&'a DirMeta),
+    File(&'a FileMeta),
+    TinyFile(&'a TinyFileMeta),
+}
+
+/// A mutable reference to an entry in a directory.
+pub enum EntryMut<'a> {
+    Dir(&'a mut DirMeta),
+    File(&'a mut FileMeta),
+    TinyFile(&'a mut TinyFileMeta),
+}
+
+/// An entry detached from its directory.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Node {
+    Dir(DirMeta),
+    File(FileMeta),
+    TinyFile(TinyFileMeta),
+}
+
+impl Attrs {
+    /// Build attributes of an empty entry created now.
+    pub fn new(perm: u16, uid: u32, gid: u32) -> Self {
+        let now = SystemTime::now();
+        Self {
+            size: 0,
+            blocks: 0,
+            perm,
+            uid,
+            gid,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            flags: 0,
+        }
+    }
+
+    /// Whether the entry may not be modified, removed or moved, even by root.
+    pub fn immutable(&self) -> bool {
+        self.flags & FS_IMMUTABLE_FL != 0
+    }
+
+    /// Whether the file may only be appended to, and the entry not removed
+    /// or moved. Entries may only be added to such a directory.
+    pub fn append_only(&self) -> bool {
+        self.flags & FS_APPEND_FL != 0
+    }
+
+    /// Whether chunks of the entry are kept in the chunk cache.
+    pub fn pinned(&self) -> bool {
+        self.flags & PINNED_FL != 0
+    }
+
+    /// Set the size of file, and the number of 512B blocks along with it.
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size;
+        self.blocks = (size + 511) / 512;
+    }
+}
+
+impl FileMeta {
+    /// Id of the nth chunk of this file.
+    pub fn chunk_id(&self, n: usize) -> ChunkId<Data> {
+        if self.shared(n) {
+            return ChunkId::at_hash(self.hashes[n]);
+        }
+        self.staging_id(n)
+    }
+
+    /// Id the nth chunk is written at, until hashed if content addressed.
+    pub fn staging_id(&self, n: usize) -> ChunkId<Data> {
+        ChunkId::derive_n(&self.id, n)
+    }
+
+    /// Whether the nth chunk is stored at the hash of its contents, maybe
+    /// shared with other files, so never modified in place nor deleted
+    /// along with this file.
+    pub fn shared(&self, n: usize) -> bool {
+        self.content && !self.hole(n) && self.hashes.get(n).map_or(false, |hash| *hash != UNHASHED)
+    }
+
+    /// Whether the nth chunk was hashed as zeros, a hole which is not
+    /// stored, reading as zeros once its chunk is deleted.
+    pub fn hole(&self, n: usize) -> bool {
+        self.hashes.get(n) == Some(&*merkle::ZERO_CHUNK)
+    }
+
+    /// The nth chunk to modify, copied out of the shared one if needed.
+    fn writable_chunk(
+        &self,
+        provider: &dyn ChunkProvider,
+        n: usize,
+    ) -> Result<Chunk, ChunkProviderError> {
+        let chunk = provider.get_chunk_by_id(self.chunk_id(n).id())?;
+        if !self.shared(n) {
+            return Ok(chunk);
+        }
+        let mut data = vec![0; CHUNK_SIZE];
+        chunk.read_at(0, &mut data);
+        Ok(Chunk::new_with_data(self.staging_id(n).into_id(), data)?)
+    }
+
+    /// Ids stored for this file, its chunks and the outboards of those
+    /// hashed, but holes.
+    pub fn stored_ids(&self) -> impl Iterator<Item = Id> + '_ {
+        let outboards = (0..self.hashes.len().min(self.chunk_count()))
+            .filter(move |n| self.hashes[*n] != UNHASHED && !self.hole(*n))
+            .map(move |n| merkle::outboard_id(&self.chunk_id(n).into_id()));
+        (0..self.chunk_count())
+            .filter(move |n| !self.hole(*n))
+            .map(move |n| self.chunk_id(n).into_id())
+            .chain(outboards)
+    }
+
+    /// Number of chunks used by this file, the last one may be partial.
+    pub fn chunk_count(&self) -> usize {
+        ((self.attrs.size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64) as usize
+    }
+
+    /// Indexes of the chunks holding `len` bytes from `offset`.
+    pub fn chunk_span(offset: u64, len: usize) -> Range<usize> {
+        if len == 0 {
+            return 0..0;
+        }
+        let first = offset / CHUNK_SIZE as u64;
+        let last = (offset + len as u64 - 1) / CHUNK_SIZE as u64;
+        first as usize..last as usize + 1
+    }
+
+    /// Read from `offset` into `buf`, returns the number of bytes read,
+    /// which is 0 at the end of file.
+    /// Chunks are verified against their hashes as a whole, the cost of
+    /// which is small next to fetching them.
+    pub fn read(
+        &self,
+        provider: &dyn ChunkProvider,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, ChunkProviderError> {
+        if offset >= self.attrs.size {
+            return Ok(0);
+        }
+        let end = min(self.attrs.size, offset + buf.len() as u64);
+        let mut pos = offset;
+        let mut whole = vec![0; CHUNK_SIZE];
+        while pos < end {
+            let n = (pos / CHUNK_SIZE as u64) as usize;
+            let chunk_offset = (pos % CHUNK_SIZE as u64) as usize;
+            let len = min(CHUNK_SIZE - chunk_offset, (end - pos) as usize);
+            let start = (pos - offset) as usize;
+
+            let chunk = provider.get_chunk_by_id(self.chunk_id(n).id())?;
+            chunk.read_at(0, &mut whole);
+            merkle::verify(self, n, &whole)?;
+            buf[start..start + len].copy_from_slice(&whole[chunk_offset..chunk_offset + len]);
+            pos += len as u64;
+        }
+        Ok((end - offset) as usize)
+    }
+
+    /// Write `data` at `offset`, extending the file if needed.
+    /// Returns the number of bytes written.
+    pub fn write(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, ChunkProviderError> {
+        let end = offset + data.len() as u64;
+        let mut pos = offset;
+        while pos < end {
+            let n = (pos / CHUNK_SIZE as u64) as usize;
+            let chunk_offset = (pos % CHUNK_SIZE as u64) as usize;
+            let len = min(CHUNK_SIZE - chunk_offset, (end - pos) as usize);
+            let start = (pos - offset) as usize;
+
+            let chunk = self.writable_chunk(provider, n)?;
+            chunk.write_at(chunk_offset, &data[start..start + len]);
+            provider.save_chunk(&chunk)?;
+            self.unhash(n);
+            pos += len as u64;
+        }
+        if end > self.attrs.size {
+            self.attrs.set_size(end);
+        }
+        self.attrs.mtime = SystemTime::now();
+        Ok(data.len())
+    }
+
+    /// Set the size of this file, chunks beyond released at once if
+    /// `discard`, see `clear_chunk`.
+    /// Bytes beyond the end of file are always kept zero in chunks,
+    /// so extending the file needs no write.
+    pub fn truncate(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        size: u64,
+        discard: bool,
+    ) -> Result<(), ChunkProviderError> {
+        if size < self.attrs.size {
+            let count = self.chunk_count();
+            let tail = (size % CHUNK_SIZE as u64) as usize;
+            if tail != 0 {
+                let chunk = self.writable_chunk(provider, size as usize / CHUNK_SIZE)?;
+                chunk.write_at(tail, &vec![0; CHUNK_SIZE - tail]);
+                provider.save_chunk(&chunk)?;
+                self.unhash(size as usize / CHUNK_SIZE);
+            }
+            let kept = ((size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64) as usize;
+            for n in kept..count {
+                self.clear_chunk(provider, n, discard)?;
+            }
+            self.hashes.truncate(kept);
+        }
+        self.attrs.set_size(size);
+        self.attrs.mtime = SystemTime::now();
+        Ok(())
+    }
+
+    /// Zero `len` bytes from `offset` within the file, keeping its size.
+    /// Chunks wholly inside are released at once if `discard`.
+    pub fn punch_hole(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        offset: u64,
+        len: u64,
+        discard: bool,
+    ) -> Result<(), ChunkProviderError> {
+        let end = min(offset.saturating_add(len), self.attrs.size);
+        let mut pos = offset;
+        while pos < end {
+            let n = (pos / CHUNK_SIZE as u64) as usize;
+            let chunk_offset = (pos % CHUNK_SIZE as u64) as usize;
+            let len = min(CHUNK_SIZE - chunk_offset, (end - pos) as usize);
+            if len == CHUNK_SIZE {
+                self.clear_chunk(provider, n, discard)?;
+            } else {
+                let chunk = self.writable_chunk(provider, n)?;
+                chunk.write_at(chunk_offset, &vec![0; len]);
+                provider.save_chunk(&chunk)?;
+            }
+            self.unhash(n);
+            pos += len as u64;
+        }
+        self.attrs.mtime = SystemTime::now();
+        Ok(())
+    }
+
+    /// Make chunk `n` read as zeros: deleted from the provider if
+    /// `discard`, otherwise written as zeros, left for the garbage
+    /// collection to delete once hashed as a hole or beyond the end of file.
+    fn clear_chunk(
+        &self,
+        provider: &dyn ChunkProvider,
+        n: usize,
+        discard: bool,
+    ) -> Result<(), ChunkProviderError> {
+        match discard {
+            true => provider.delete_chunk(self.staging_id(n).id()),
+            false => provider.save_chunk(&Chunk::new(self.staging_id(n).into_id())),
+        }
+    }
+
+    /// Record `len` bytes written at `offset` streamed into their chunk rather
+    /// than by `write`.
+    pub fn streamed(&mut self, offset: u64, len: usize) {
+        self.unhash((offset / CHUNK_SIZE as u64) as usize);
+        let end = offset + len as u64;
+        if end > self.attrs.size {
+            self.attrs.set_size(end);
+        }
+        self.attrs.mtime = SystemTime::now();
+    }
+
+    /// Mark chunk `n` written since last hashed, chunks skipped by a sparse
+    /// write are hashed along with it.
+    fn unhash(&mut self, n: usize) {
+        if self.hashes.len() <= n {
+            self.hashes.resize(n + 1, UNHASHED);
+        }
+        self.hashes[n] = UNHASHED;
+    }
+
+    /// Zero bytes beyond the end of file left by writes never recorded in
+    /// metadata, e.g. by a crash. Returns whether anything is cleared.
+    pub fn clear_tail(&self, provider: &dyn ChunkProvider) -> Result<bool, ChunkProviderError> {
+        let mut cleared = false;
+        let tail = (self.attrs.size % CHUNK_SIZE as u64) as usize;
+        // a shared chunk is as hashed, zero beyond the end
+        if tail != 0 && !self.shared(self.chunk_count() - 1) {
+            let chunk = provider.get_chunk_by_id(self.chunk_id(self.chunk_count() - 1).id())?;
+            let mut buf = vec![0; CHUNK_SIZE - tail];
+            chunk.read_at(tail, &mut buf);
+            if buf.iter().any(|b| *b != 0) {
+                chunk.write_at(tail, &vec![0; CHUNK_SIZE - tail]);
+                provider.save_chunk(&chunk)?;
+                cleared = true;
+            }
+        }
+        // chunks beyond the end are written in order, stop at the first empty one
+        let mut buf = vec![0; CHUNK_SIZE];
+        for n in self.chunk_count().. {
+            let chunk = provider.get_chunk_by_id(self.chunk_id(n).id())?;
+            chunk.read_at(0, &mut buf);
+            if buf.iter().all(|b| *b == 0) {
+                break;
+            }
+            provider.save_chunk(&Chunk::new(self.chunk_id(n).into_id()))?;
+            cleared = true;
+        }
+        Ok(cleared)
+    }
+}
+
+impl TinyFileMeta {
+    /// Build an empty tiny file, which takes no slot yet.
+    pub fn new(name: String, attrs: Attrs) -> Self {
+        Self {
+            name,
+            id: Id::new_random(),
+            chunk_id: Id::new([0; ID_LENGTH]),
+            chunk_offset: 0,
+            chunk_blocks: 0,
+            attrs,
+        }
+    }
+
+    /// Bytes this file can hold without moving to another slot.
+    pub fn capacity(&self) -> u64 {
+        self.chunk_blocks as u64 * BLOCK_SIZE as u64
+    }
+
+    fn slot_offset(&self) -> usize {
+        self.chunk_offset as usize * BLOCK_SIZE
+    }
+
+    /// Read from `offset` into `buf`, returns the number of bytes read,
+    /// which is 0 at the end of file.
+    pub fn read(
+        &self,
+        provider: &dyn ChunkProvider,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, ChunkProviderError> {
+        if offset >= self.attrs.size {
+            return Ok(0);
+        }
+        let len = min(self.attrs.size - offset, buf.len() as u64) as usize;
+        let chunk = provider.get_chunk_by_id(&self.chunk_id)?;
+        chunk.read_at(self.slot_offset() + offset as usize, &mut buf[..len]);
+        Ok(len)
+    }
+
+    /// Write `data` at `offset`, moving the file to a larger slot if needed.
+    /// Returns the number of bytes written.
+    pub fn write(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        allocator: &mut TinyFileAllocator,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, TinyFileError> {
+        // a file taking no slot yet would write to the zero chunk id
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if offset > self.attrs.size {
+            self.truncate(provider, allocator, offset)?;
+        }
+        let end = offset + data.len() as u64;
+        if end > self.capacity() {
+            self.grow(provider, allocator, end)?;
+        }
+        let chunk = provider.get_chunk_by_id(&self.chunk_id)?;
+        chunk.write_at(self.slot_offset() + offset as usize, data);
+        if let Some(fat) = allocator.fat(chunk.id()) {
+            chunk.write_at(SHARED_BLOCKS * BLOCK_SIZE, &fat.to_bytes());
+        }
+        provider.save_chunk(&chunk)?;
+        if end > self.attrs.size {
+            self.attrs.set_size(end);
+        }
+        self.attrs.mtime = SystemTime::now();
+        Ok(data.len())
+    }
+
+    /// Set the size of this file, bytes in the slot beyond the end of file are
+    /// undefined, so extending the file fills them with zero.
+    pub fn truncate(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        allocator: &mut TinyFileAllocator,
+        size: u64,
+    ) -> Result<(), TinyFileError> {
+        if size > self.capacity() {
+            self.grow(provider, allocator, size)?;
+        }
+        if size > self.attrs.size {
+            let chunk = provider.get_chunk_by_id(&self.chunk_id)?;
+            let zeros = vec![0; (size - self.attrs.size) as usize];
+            chunk.write_at(self.slot_offset() + self.attrs.size as usize, &zeros);
+            provider.save_chunk(&chunk)?;
+        }
+        self.attrs.set_size(size);
+        self.attrs.mtime = SystemTime::now();
+        Ok(())
+    }
+
+    /// Release the slot taken by this file.
+    pub fn free(&mut self, allocator: &mut TinyFileAllocator) {
+        if self.chunk_blocks > 0 {
+            allocator.free(&self.chunk_id, self.chunk_offset, self.chunk_blocks);
+            self.chunk_blocks = 0;
+        }
+    }
+
+    /// Move to a slot holding at least `size` bytes, at least doubling the slot
+    /// so that appending does not move the file every time.
+    fn grow(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        allocator: &mut TinyFileAllocator,
+        size: u64,
+    ) -> Result<(), TinyFileError> {
+        let needed = (size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64;
+        if needed > SHARED_BLOCKS as u64 {
+            return Err(TinyFileError::TooLarge(size));
+        }
+        let blocks = min(
+            max(needed, self.chunk_blocks as u64 * 2),
+            SHARED_BLOCKS as u64,
+        );
+        self.relocate(provider, allocator, blocks as u16)
+    }
+
+    /// Move to a new slot of `blocks` blocks allocated from `allocator`,
+    /// and release the current one.
+    pub fn relocate(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        allocator: &mut TinyFileAllocator,
+        blocks: u16,
+    ) -> Result<(), TinyFileError> {
+        let (chunk_id, chunk_offset) = allocator
+            .allocate(blocks as usize)
+            .ok_or_else(|| TinyFileError::TooLarge(blocks as u64 * BLOCK_SIZE as u64))?;
+
+        if self.chunk_blocks > 0 {
+            let mut data = vec![0; self.attrs.size as usize];
+            self.read(provider, 0, &mut data)?;
+            let chunk = provider.get_chunk_by_id(&chunk_id)?;
+            chunk.write_at(chunk_offset as usize * BLOCK_SIZE, &data);
+            provider.save_chunk(&chunk)?;
+            self.free(allocator);
+        }
+        self.chunk_id = chunk_id.clone();
+        self.chunk_offset = chunk_offset;
+        self.chunk_blocks = blocks;
+        Ok(())
+    }
+}
+
+impl DirMeta {
+    /// Build an empty directory.
+    pub fn new(name: String, attrs: Attrs) -> Self {
+        Self {
+            name,
+            id: Id::new_random(),
+            entries: BTreeMap::new(),
+            attrs,
+            stored: Vec::new(),
+            loaded: true,
+            usage: Usage::default(),
+            quota: Quota::default(),
+            versions: 0,
+        }
+    }
+
+    /// A directory stored at `id` whose entries are not loaded yet.
+    pub fn new_unloaded(name: String, id: Id, attrs: Attrs) -> Self {
+        Self {
+            id,
+            loaded: false,
+            ..Self::new(name, attrs)
+        }
+    }
+
+    /// Drop the entries of this directory to free memory, they have to be
+    /// stored before.
+    pub fn unload(&mut self) {
+        self.entries.clear();
+        self.loaded = false;
+    }
+
+    /// Sub-directories of this directory.
+    pub fn dirs(&self) -> impl Iterator<Item = &DirMeta> {
+        self.entries.values().filter_map(|node| match node {
+            Node::Dir(dir) => Some(dir),
+            _ => None,
+        })
+    }
+
+    pub fn dirs_mut(&mut self) -> impl Iterator<Item = &mut DirMeta> {
+        self.entries.values_mut().filter_map(|node| match node {
+            Node::Dir(dir) => Some(dir),
+            _ => None,
+        })
+    }
+
+    /// Files stored in exclusive chunks of this directory.
+    pub fn files(&self) -> impl Iterator<Item = &FileMeta> {
+        self.entries.values().filter_map(|node| match node {
+            Node::File(file) => Some(file),
+            _ => None,
+        })
+    }
+
+    pub fn files_mut(&mut self) -> impl Iterator<Item = &mut FileMeta> {
+        self.entries.values_mut().filter_map(|node| match node {
+            Node::File(file) => Some(file),
+            _ => None,
+        })
+    }
+
+    /// Tiny files of this directory.
+    pub fn tiny_files(&self) -> impl Iterator<Item = &TinyFileMeta> {
+        self.entries.values().filter_map(|node| match node {
+            Node::TinyFile(file) => Some(file),
+            _ => None,
+        })
+    }
+
+    pub fn tiny_files_mut(&mut self) -> impl Iterator<Item = &mut TinyFileMeta> {
+        self.entries.values_mut().filter_map(|node| match node {
+            Node::TinyFile(file) => Some(file),
+            _ => None,
+        })
+    }
+
+    /// Find the entry named `name` in this directory.
+    pub fn lookup(&self, name: &str) -> Option<Entry> {
+        self.entries.get(name).map(Node::as_entry)
+    }
+
+    /// Find the entry named `name` in this directory for modification.
+    pub fn lookup_mut(&mut self, name: &str) -> Option<EntryMut> {
+        self.entries.get_mut(name).map(Node::as_entry_mut)
+    }
+
+    /// Remove the entry named `name` from this directory.
+    pub fn remove(&mut self, name: &str) -> Option<Node> {
+        self.entries.remove(name)
+    }
+
+    /// Insert `node` into this directory, replacing the entry of the same name.
+    pub fn insert(&mut self, node: Node) -> Option<Node> {
+        self.entries.insert(node.name().to_owned(), node)
+    }
+
+    /// Find the entry at `path` relative to this directory.
+    pub fn resolve<S: AsRef<str>>(&self, path: &[S]) -> Option<Entry> {
+        match path.split_first() {
+            None => Some(Entry::Dir(self)),
+            Some((name, rest)) => match self.lookup(name.as_ref())? {
+                Entry::Dir(dir) => dir.resolve(rest),
+                entry if rest.is_empty() => Some(entry),
+                _ => None,
+            },
+        }
+    }
+
+    /// Move the tiny file `name` to its own chunks once it outgrows shared
+    /// chunks, content addressed if `content`.
+    pub fn promote(
+        &mut self,
+        name: &str,
+        provider: &dyn ChunkProvider,
+        allocator: &mut TinyFileAllocator,
+        content: bool,
+    ) -> Result<&mut FileMeta, TinyFileError> {
+        let tiny = match self.entries.get(name) {
+            Some(Node::TinyFile(tiny)) => tiny,
+            _ => panic!("tiny file to promote"),
+        };
+        let mut data = vec![0; tiny.attrs.size as usize];
+        tiny.read(provider, 0, &mut data)?;
+        let mut file = FileMeta {
+            name: tiny.name.clone(),
+            id: tiny.id.clone(),
+            attrs: tiny.attrs.clone(),
+            hashes: Vec::new(),
+            content,
+        };
+        file.write(provider, 0, &data)?;
+        file.attrs = tiny.attrs.clone();
+
+        if let Some(Node::TinyFile(mut tiny)) = self.insert(Node::File(file)) {
+            tiny.free(allocator);
+        }
+        match self.entries.get_mut(name) {
+            Some(Node::File(file)) => Ok(file),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Move the file `name` back into a shared chunk if it is small enough.
+    /// Returns whether the file is moved.
+    pub fn demote(
+        &mut self,
+        name: &str,
+        provider: &dyn ChunkProvider,
+        allocator: &mut TinyFileAllocator,
+    ) -> Result<bool, TinyFileError> {
+        let file = match self.entries.get(name) {
+            Some(Node::File(file)) => file,
+            _ => panic!("file to demote"),
+        };
+        if file.attrs.size > TINY_FILE_MAX {
+            return Ok(false);
+        }
+        let mut data = vec![0; file.attrs.size as usize];
+        file.read(provider, 0, &mut data)?;
+        // take a new id, chunks of the old one are left to be collected
+        let mut tiny = TinyFileMeta::new(file.name.clone(), file.attrs.clone());
+        tiny.attrs.set_size(0);
+        tiny.write(provider, allocator, 0, &data)?;
+        tiny.attrs = file.attrs.clone();
+
+        self.insert(Node::TinyFile(tiny));
+        Ok(true)
+    }
+
+    /// Visit all tiny files in this directory and its sub-directories.
+    pub fn visit_tiny_files_mut<E>(
+        &mut self,
+        f: &mut impl FnMut(&mut TinyFileMeta) -> Result<(), E>,
+    ) -> Result<(), E> {
+        for node in self.entries.values_mut() {
+            match node {
+                Node::TinyFile(file) => f(file)?,
+                Node::Dir(dir) => dir.visit_tiny_files_mut(f)?,
+                Node::File(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Visit all files in this directory and its sub-directories.
+    pub fn visit_files<E>(&self, f: &mut impl FnMut(&FileMeta) -> Result<(), E>) -> Result<(), E> {
+        for node in self.entries.values() {
+            match node {
+                Node::File(file) => f(file)?,
+                Node::Dir(dir) => dir.visit_files(f)?,
+                Node::TinyFile(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the directory holding the entry at `path` for modification,
+    /// along with the name of the entry.
+    pub fn parent_mut<'a, 'b, S: AsRef<str>>(
+        &'a mut self,
+        path: &'b [S],
+    ) -> Option<(&'a mut DirMeta, &'b str)> {
+        let (name, parent) = path.split_last()?;
+        match self.resolve_mut(parent)? {
+            EntryMut::Dir(dir) => Some((dir, name.as_ref())),
+            _ => None,
+        }
+    }
+
+    /// Find the entry at `path` relative to this directory for modification.
+    pub fn resolve_mut<S: AsRef<str>>(&mut self, path: &[S]) -> Option<EntryMut> {
+        match path.split_first() {
+            None => Some(EntryMut::Dir(self)),
+            Some((name, rest)) => match self.lookup_mut(name.as_ref())? {
+                EntryMut::Dir(dir) => dir.resolve_mut(rest),
+                entry if rest.is_empty() => Some(entry),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl<'a> Entry<'a> {
+    pub fn name(&self) -> &'a str {
+        match self {
+            Entry::Dir(dir) => &dir.name,
+            Entry::File(file) => &file.name,
+            Entry::TinyFile(file) => &file.name,
+        }
+    }
+
+    pub fn attrs(&self) -> &'a Attrs {
+        match self {
+            Entry::Dir(dir) => &dir.attrs,
+            Entry::File(file) => &file.attrs,
+            Entry::TinyFile(file) => &file.attrs,
+        }
+    }
+
+    pub fn id(&self) -> &'a [u8; ID_LENGTH] {
+        match self {
+            Entry::Dir(dir) => &dir.id,
+            Entry::File(file) => &file.id,
+            Entry::TinyFile(file) => &file.id,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Entry::Dir(_))
+    }
+}
+
+impl Node {
+    pub fn as_entry(&self) -> Entry {
+        match self {
+            Node::Dir(dir) => Entry::Dir(dir),
+            Node::File(file) => Entry::File(file),
+            Node::TinyFile(file) => Entry::TinyFile(file),
+        }
+    }
+
+    pub fn as_entry_mut(&mut self) -> EntryMut {
+        match self {
+            Node::Dir(dir) => EntryMut::Dir(dir),
+            Node::File(file) => EntryMut::File(file),
+            Node::TinyFile(file) => EntryMut::TinyFile(file),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Node::Dir(dir) => &dir.name,
+            Node::File(file) => &file.name,
+            Node::TinyFile(file) => &file.name,
+        }
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        match self {
+            Node::Dir(dir) => dir.name = name,
+            Node::File(file) => file.name = name,
+            Node::TinyFile(file) => file.name = name,
+        }
+    }
+}
+
+impl<'a> EntryMut<'a> {
+    pub fn attrs_mut(&mut self) -> &mut Attrs {
+        match self {
+            EntryMut::Dir(dir) => &mut dir.attrs,
+            EntryMut::File(file) => &mut file.attrs,
+            EntryMut::TinyFile(file) => &mut file.attrs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Attrs, DirMeta, FileMeta, Node, TinyFileMeta};
+    use crate::allocator::TinyFileAllocator;
+    use crate::chunk::{BLOCK_SIZE, CHUNK_SIZE};
+    use crate::id::Id;
+    use crate::merkle;
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_file_across_chunks() {
+        let provider = MemoryProvider::new();
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        let data: Vec<u8> = (0..CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let offset = CHUNK_SIZE as u64 - 50;
+        assert_eq!(file.write(&provider, offset, &data).unwrap(), data.len());
+        assert_eq!(file.attrs.size, offset + data.len() as u64);
+        assert_eq!(file.chunk_count(), 3);
+
+        let mut buf = vec![0; data.len() + 10];
+        assert_eq!(file.read(&provider, offset, &mut buf).unwrap(), data.len());
+        assert_eq!(&buf[..data.len()], &data[..]);
+        assert_eq!(file.read(&provider, file.attrs.size, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_punch_hole_discards() {
+        let provider = MemoryProvider::new();
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        file.write(&provider, 0, &vec![7; 3 * CHUNK_SIZE]).unwrap();
+        file.punch_hole(&provider, 10, 2 * CHUNK_SIZE as u64, true)
+            .unwrap();
+        assert_eq!(file.attrs.size, 3 * CHUNK_SIZE as u64);
+        assert!(!provider.contains_chunk(file.chunk_id(1).id()).unwrap());
+        merkle::update(&mut file, &provider, None).unwrap();
+        assert_eq!(file.hashes[1], *merkle::ZERO_CHUNK);
+        // no longer referenced, not stored again
+        let hole = file.chunk_id(1).into_id();
+        assert!(!file.stored_ids().any(|id| id == hole));
+        assert!(!provider
+            .contains_chunk(&merkle::outboard_id(&hole))
+            .unwrap());
+
+        let mut buf = vec![1; 3 * CHUNK_SIZE];
+        file.read(&provider, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..10], &[7; 10]);
+        assert!(buf[10..2 * CHUNK_SIZE + 10].iter().all(|b| *b == 0));
+        assert_eq!(buf[2 * CHUNK_SIZE + 10], 7);
+
+        file.truncate(&provider, 10, true).unwrap();
+        assert!(!provider.contains_chunk(file.chunk_id(2).id()).unwrap());
+    }
+
+    #[test]
+    fn test_content_addressed() {
+        let provider = MemoryProvider::new();
+        let data = vec![7; CHUNK_SIZE];
+        let mut files: Vec<FileMeta> = (0..2)
+            .map(|_| {
+                let mut file = FileMeta {
+                    name: "file".to_owned(),
+                    id: Id::new_random(),
+                    attrs: Attrs::new(0o644, 0, 0),
+                    hashes: Vec::new(),
+                    content: true,
+                };
+                file.write(&provider, 0, &data).unwrap();
+                merkle::update(&mut file, &provider, None).unwrap();
+                file
+            })
+            .collect();
+        // stored once
+        assert_eq!(files[0].chunk_id(0), files[1].chunk_id(0));
+        assert!(files[0].shared(0));
+
+        // copied out before modified
+        files[0].write(&provider, 0, b"changed").unwrap();
+        assert_eq!(files[0].chunk_id(0), files[0].staging_id(0));
+        let mut buf = vec![0; CHUNK_SIZE];
+        files[1].read(&provider, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        files[0].read(&provider, 0, &mut buf[..7]).unwrap();
+        assert_eq!(&buf[..7], b"changed");
+    }
+
+    #[test]
+    fn test_tiny_file_grow() {
+        let provider = MemoryProvider::new();
+        let mut allocator = TinyFileAllocator::new();
+        let mut file = TinyFileMeta::new("tiny".to_owned(), Attrs::new(0o644, 0, 0));
+        assert_eq!(file.write(&provider, &mut allocator, 10, &[]).unwrap(), 0);
+        assert!(!provider.contains_chunk(&file.chunk_id).unwrap());
+        assert_eq!(file.attrs.size, 0);
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        file.write(&provider, &mut allocator, 0, &data[..100])
+            .unwrap();
+        assert_eq!(file.chunk_blocks, 1);
+        file.write(&provider, &mut allocator, 100, &data[100..])
+            .unwrap();
+        assert_eq!(file.chunk_blocks, 3);
+        assert_eq!(file.attrs.size, data.len() as u64);
+
+        let mut buf = vec![0; data.len()];
+        assert_eq!(file.read(&provider, 0, &mut buf).unwrap(), data.len());
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_promote_and_demote() {
+        let provider = MemoryProvider::new();
+        let mut allocator = TinyFileAllocator::new();
+        let mut dir = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut tiny = TinyFileMeta::new("file".to_owned(), Attrs::new(0o644, 0, 0));
+        tiny.write(&provider, &mut allocator, 0, b"hello").unwrap();
+        dir.insert(Node::TinyFile(tiny));
+
+        let file = dir
+            .promote("file", &provider, &mut allocator, false)
+            .unwrap();
+        file.write(&provider, CHUNK_SIZE as u64, b"world").unwrap();
+        assert_eq!(dir.tiny_files().count(), 0);
+        let mut buf = [0; 5];
+        let file = dir.files_mut().next().unwrap();
+        file.read(&provider, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        file.truncate(&provider, 5, false).unwrap();
+        assert!(dir.demote("file", &provider, &mut allocator).unwrap());
+        assert_eq!(dir.files().count(), 0);
+        let mut buf = [0; 10];
+        let tiny = dir.tiny_files().next().unwrap();
+        assert_eq!(tiny.read(&provider, 0, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
 }
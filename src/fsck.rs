@@ -0,0 +1,445 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::allocator::TinyFileAllocator;
+use crate::chunk::Chunk;
+use crate::dirindex;
+use crate::fs::{Attrs, DirMeta, TinyFileError};
+use crate::gc;
+use crate::id::Id;
+use crate::journal::Journal;
+use crate::meta::MetaError;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::recovery;
+use crate::snapshot::Snapshots;
+use crate::superblock::{Superblock, SuperblockError};
+use crate::tenant;
+
+#[derive(thiserror::Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum FsckError {
+    #[error(transparent)]
+    SuperblockError(#[from] SuperblockError),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+    #[error(transparent)]
+    TinyFileError(#[from] TinyFileError),
+    #[error(transparent)]
+    ProviderError(#[from] ChunkProviderError),
+}
+
+/// An inconsistency found by `fsck`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    /// The filesystem was not cleanly unmounted, the journal needs replay
+    UncleanShutdown,
+    /// A chunk referenced by the file at `path` does not exist
+    MissingChunk { path: String, id: Id },
+    /// Block count not matching the size
+    BadBlockCount { path: String },
+    /// The slot of the tiny file at `path` overlaps the slot of `other`
+    CrossLinkedSlot { path: String, other: String },
+    /// A chunk referenced by nothing
+    OrphanChunk(Id),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UncleanShutdown => write!(f, "not cleanly unmounted"),
+            Problem::MissingChunk { path, id } => {
+                write!(f, "{}: missing chunk {}", path, id.hex())
+            }
+            Problem::BadBlockCount { path } => write!(f, "{}: bad block count", path),
+            Problem::CrossLinkedSlot { path, other } => {
+                write!(f, "{}: slot cross-linked with {}", path, other)
+            }
+            Problem::OrphanChunk(id) => write!(f, "orphan chunk {}", id.hex()),
+        }
+    }
+}
+
+/// Result of a `fsck` run.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub problems: Vec<Problem>,
+    /// Whether `problems` are repaired
+    pub repaired: bool,
+    pub dirs: usize,
+    pub files: usize,
+    /// Chunks referenced by the filesystem
+    pub chunks: usize,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check the filesystem whose superblock is stored at `superblock_id`
+/// offline: walk the directory tree, verify referenced chunks, block counts
+/// and tiny-file slots, and look for orphaned chunks if the provider can
+/// list them, unless it hosts tenants whose chunks cannot be told from
+/// orphans. Checksums are verified while loading the metadata.
+/// With `repair`, found problems are fixed and the tree is written back.
+pub fn fsck(
+    provider: &dyn ChunkProvider,
+    superblock_id: &Id,
+    repair: bool,
+) -> Result<FsckReport, FsckError> {
+    let mut superblock = Superblock::load(provider, superblock_id)?;
+    let mut root = dirindex::load_dir(provider, &superblock.root_id)?;
+    let (mut journal, records) = Journal::open(provider, superblock.journal_id())?;
+    let snapshots = Snapshots::open(provider, superblock.snapshots_id())?;
+    let mut report = FsckReport {
+        repaired: repair,
+        ..FsckReport::default()
+    };
+    if superblock.dirty {
+        report.problems.push(Problem::UncleanShutdown);
+        if repair {
+            recovery::recover(&mut root, provider, records)?;
+        }
+    }
+
+    let mut checker = Checker {
+        provider,
+        repair,
+        report: &mut report,
+        referenced: HashSet::new(),
+        slots: HashMap::new(),
+        cross_linked: HashSet::new(),
+    };
+    checker.check_dir(&mut root, "")?;
+    let Checker {
+        mut referenced,
+        slots,
+        cross_linked,
+        ..
+    } = checker;
+
+    if repair && !cross_linked.is_empty() {
+        let mut allocator = TinyFileAllocator::new();
+        snapshots.mark(&mut allocator);
+        relocate_cross_linked(&mut root, provider, &mut allocator, &slots, &cross_linked)?;
+    }
+
+    referenced.extend(gc::referenced(
+        provider,
+        provider,
+        superblock_id,
+        &superblock,
+        &root,
+        &journal,
+        &snapshots,
+    )?);
+    report.chunks = referenced.len();
+
+    let shared = tenant::shares(provider, superblock_id)?;
+    if let Some(stored) = provider.list_chunks()?.filter(|_| !shared) {
+        for id in stored {
+            if !referenced.contains(&id) {
+                if repair {
+                    provider.delete_chunk(&id)?;
+                }
+                report.problems.push(Problem::OrphanChunk(id));
+            }
+        }
+    }
+
+    if repair && !report.problems.is_empty() {
+        dirindex::store_dir(provider, &mut root)?;
+        journal.checkpoint(provider)?;
+        superblock.dirty = false;
+        superblock.store(provider, superblock_id)?;
+        provider.flush()?;
+    }
+    Ok(report)
+}
+
+struct Checker<'a> {
+    provider: &'a dyn ChunkProvider,
+    repair: bool,
+    report: &'a mut FsckReport,
+    referenced: HashSet<Id>,
+    /// Owner of each used block of shared chunks, by path and file id.
+    /// Clones of a file share its slot.
+    slots: HashMap<(Id, u16), (String, Id)>,
+    /// Paths of tiny files to move to their own slots
+    cross_linked: HashSet<String>,
+}
+
+impl<'a> Checker<'a> {
+    fn check_dir(&mut self, dir: &mut DirMeta, path: &str) -> Result<(), ChunkProviderError> {
+        self.report.dirs += 1;
+        self.check_blocks(&mut dir.attrs, path);
+
+        for file in dir.files_mut() {
+            let file_path = format!("{}/{}", path, file.name);
+            self.report.files += 1;
+            self.check_blocks(&mut file.attrs, &file_path);
+            for n in 0..file.chunk_count() {
+                let id = file.chunk_id(n).into_id();
+                // discarded, read as zeros
+                if file.hole(n) {
+                    continue;
+                }
+                if !self.provider.contains_chunk(&id)? {
+                    if self.repair {
+                        // a missing chunk reads as zeros
+                        self.provider.save_chunk(&Chunk::new(id.clone()))?;
+                    }
+                    self.report.problems.push(Problem::MissingChunk {
+                        path: file_path.clone(),
+                        id: id.clone(),
+                    });
+                }
+                self.referenced.insert(id);
+            }
+        }
+
+        for file in dir.tiny_files_mut() {
+            let file_path = format!("{}/{}", path, file.name);
+            self.report.files += 1;
+            self.check_blocks(&mut file.attrs, &file_path);
+            if file.chunk_blocks == 0 {
+                continue;
+            }
+            let id = file.chunk_id.clone();
+            if self.referenced.insert(id.clone()) && !self.provider.contains_chunk(&id)? {
+                self.report.problems.push(Problem::MissingChunk {
+                    path: file_path.clone(),
+                    id: id.clone(),
+                });
+            }
+            for block in file.chunk_offset..file.chunk_offset + file.chunk_blocks {
+                match self.slots.get(&(id.clone(), block)) {
+                    Some((_, other_id)) if *other_id == file.id => {}
+                    Some((other, _)) => {
+                        self.report.problems.push(Problem::CrossLinkedSlot {
+                            path: file_path.clone(),
+                            other: other.clone(),
+                        });
+                        self.cross_linked.insert(file_path.clone());
+                        break;
+                    }
+                    None => {
+                        self.slots
+                            .insert((id.clone(), block), (file_path.clone(), file.id.clone()));
+                    }
+                }
+            }
+        }
+
+        for sub in dir.dirs_mut() {
+            let sub_path = format!("{}/{}", path, sub.name);
+            self.check_dir(sub, &sub_path)?;
+        }
+        Ok(())
+    }
+
+    fn check_blocks(&mut self, attrs: &mut Attrs, path: &str) {
+        let mut expected = attrs.clone();
+        expected.set_size(attrs.size);
+        if expected.blocks != attrs.blocks {
+            self.report.problems.push(Problem::BadBlockCount {
+                path: path.to_owned(),
+            });
+            if self.repair {
+                attrs.blocks = expected.blocks;
+            }
+        }
+    }
+}
+
+/// Give each cross-linked tiny file a slot of its own.
+fn relocate_cross_linked(
+    root: &mut DirMeta,
+    provider: &dyn ChunkProvider,
+    allocator: &mut TinyFileAllocator,
+    slots: &HashMap<(Id, u16), (String, Id)>,
+    cross_linked: &HashSet<String>,
+) -> Result<(), TinyFileError> {
+    for (id, block) in slots.keys() {
+        allocator.mark(id, *block, 1);
+    }
+    let mut relocate = |dir: &mut DirMeta, path: &str| -> Result<(), TinyFileError> {
+        for file in dir.tiny_files_mut() {
+            if cross_linked.contains(&format!("{}/{}", path, file.name)) {
+                let (id, offset, blocks) =
+                    (file.chunk_id.clone(), file.chunk_offset, file.chunk_blocks);
+                file.relocate(provider, allocator, blocks)?;
+                // the old slot still belongs to the other file
+                allocator.mark(&id, offset, blocks);
+            }
+        }
+        Ok(())
+    };
+    visit_dirs(root, "", &mut relocate)
+}
+
+fn visit_dirs<E>(
+    dir: &mut DirMeta,
+    path: &str,
+    f: &mut impl FnMut(&mut DirMeta, &str) -> Result<(), E>,
+) -> Result<(), E> {
+    f(dir, path)?;
+    for sub in dir.dirs_mut() {
+        let sub_path = format!("{}/{}", path, sub.name);
+        visit_dirs(sub, &sub_path, f)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fsck, Problem};
+    use crate::allocator::TinyFileAllocator;
+    use crate::chunk::Chunk;
+    use crate::crypt::{KeyRing, MasterKey};
+    use crate::dirindex;
+    use crate::fs::{Attrs, DirMeta, Node, TinyFileMeta};
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::inode::FUSE_ROOT_ID;
+    use crate::keys::{self, KdfParams, KeySlot, Secret};
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::ChunkProvider;
+    use crate::providers::encrypted::EncryptedProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::sign::SigningKey;
+    use crate::superblock::{Superblock, SUPERBLOCK_ID};
+    use crate::tenant;
+    use std::sync::Arc;
+
+    /// Write a file, hashed, to the filesystem at `provider` opened with
+    /// `options`, and run `f` before closing it.
+    fn write_file(
+        provider: Arc<dyn ChunkProvider>,
+        options: MountOptions,
+        f: impl FnOnce(&mut EossFs),
+    ) {
+        let id = Id::new(SUPERBLOCK_ID);
+        let mut fs = EossFs::open(provider, options, &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, &[7; 10]).unwrap();
+        fs.sync_inode(attr.ino).unwrap();
+        f(&mut fs);
+        fs.close().unwrap();
+    }
+
+    /// Check the filesystem at `provider` is left alone by a repair through
+    /// `checked`, and its file readable.
+    fn assert_repair_keeps(
+        checked: &dyn ChunkProvider,
+        provider: Arc<dyn ChunkProvider>,
+        options: MountOptions,
+    ) {
+        let id = Id::new(SUPERBLOCK_ID);
+        let stored = provider.list_chunks().unwrap().unwrap().len();
+        let report = fsck(checked, &id, true).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(provider.list_chunks().unwrap().unwrap().len(), stored);
+        let mut fs = EossFs::open(provider, options, &id).unwrap();
+        let (attr, _) = fs.lookup_entry(FUSE_ROOT_ID, "file").unwrap();
+        let (data, range) = fs.read_shared(attr.ino, 0, 10, None).unwrap().unwrap();
+        assert_eq!(data[range], [7; 10]);
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn test_repair_keeps_features() {
+        // signed and content addressed, with a dedup index and outboards
+        let provider: Arc<dyn ChunkProvider> = Arc::new(MemoryProvider::new());
+        let signing = SigningKey::new_random();
+        let format = FormatOptions {
+            content_addressed: true,
+            signing_key: Some(signing.clone()),
+            ..FormatOptions::default()
+        };
+        EossFs::format(provider.as_ref(), &format).unwrap();
+        let options = MountOptions {
+            signing_key: Some(signing),
+            ..MountOptions::default()
+        };
+        write_file(provider.clone(), options.clone(), |_| {});
+        assert_repair_keeps(provider.as_ref(), provider.clone(), options);
+
+        // encrypted, rekeyed in part
+        let memory: Arc<dyn ChunkProvider> = Arc::new(MemoryProvider::new());
+        let key = MasterKey::new_random();
+        let secret = Secret::KeyFile(vec![7; 64]);
+        let format = FormatOptions {
+            key: Some(key.clone()),
+            key_slots: vec![KeySlot::new(&key, &secret, KdfParams::default()).unwrap()],
+            ..FormatOptions::default()
+        };
+        EossFs::format(memory.as_ref(), &format).unwrap();
+        let options = MountOptions {
+            key: Some(key),
+            rekey_batch: 1,
+            lease_ttl: None,
+            ..MountOptions::default()
+        };
+        write_file(memory.clone(), options.clone(), |fs| {
+            assert_eq!(fs.rotate_key(&secret).unwrap(), 1);
+            assert!(!fs.rekey().unwrap());
+        });
+        let id = Id::new(SUPERBLOCK_ID);
+        let superblock = Superblock::load(memory.as_ref(), &id).unwrap();
+        let key = keys::unlock(&superblock.key_slots, &secret).unwrap();
+        let keys = KeyRing::open(key.clone(), 1, &superblock.key_epochs).unwrap();
+        let encrypted = EncryptedProvider::new(memory.clone(), Arc::new(keys), id);
+        let options = MountOptions {
+            key: Some(key),
+            ..options
+        };
+        assert_repair_keeps(&encrypted, memory, options);
+    }
+
+    #[test]
+    fn test_fsck_and_repair() {
+        let provider = MemoryProvider::new();
+        let id = Id::new(SUPERBLOCK_ID);
+        let superblock = EossFs::format(&provider, &FormatOptions::default()).unwrap();
+        assert!(fsck(&provider, &id, false).unwrap().is_clean());
+
+        let mut allocator = TinyFileAllocator::new();
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut a = TinyFileMeta::new("a".to_owned(), Attrs::new(0o644, 0, 0));
+        a.write(&provider, &mut allocator, 0, b"hello").unwrap();
+        let mut b = a.clone();
+        b.name = "b".to_owned();
+        b.id = Id::new_random();
+        root.insert(Node::TinyFile(a));
+        root.insert(Node::TinyFile(b));
+        root.id = Id::new(superblock.root_id);
+        dirindex::store_dir(&provider, &mut root).unwrap();
+        provider.save_chunk(&Chunk::new(Id::new_random())).unwrap();
+
+        let report = fsck(&provider, &id, false).unwrap();
+        assert_eq!(report.problems.len(), 2);
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| matches!(p, Problem::CrossLinkedSlot { .. })));
+
+        fsck(&provider, &id, true).unwrap();
+        assert!(fsck(&provider, &id, false).unwrap().is_clean());
+        let root = dirindex::load_dir(&provider, &superblock.root_id).unwrap();
+        let mut buf = [0; 5];
+        for file in root.tiny_files() {
+            file.read(&provider, 0, &mut buf).unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+
+        // chunks of tenants are not orphans
+        tenant::create(&provider, "a", &FormatOptions::default()).unwrap();
+        let stored = provider.list_chunks().unwrap().unwrap().len();
+        assert!(fsck(&provider, &id, true).unwrap().is_clean());
+        assert_eq!(provider.list_chunks().unwrap().unwrap().len(), stored);
+        fsck(&provider, &tenant::superblock_id("a"), true).unwrap();
+        assert_eq!(provider.list_chunks().unwrap().unwrap().len(), stored);
+    }
+}
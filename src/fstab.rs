@@ -0,0 +1,120 @@
+/// Name the binary is run as by mount(8) for filesystems of type `eoss`.
+pub const HELPER_NAME: &str = "mount.eoss";
+
+/// Options of `mount -o` meant for mount(8) or other tools, or for the
+/// kernel and without effect on a FUSE mount, not passed on.
+const GENERIC: &[&str] = &[
+    "defaults",
+    "rw",
+    "auto",
+    "noauto",
+    "user",
+    "users",
+    "nouser",
+    "owner",
+    "group",
+    "nofail",
+    "_netdev",
+    "exec",
+    "noexec",
+    "suid",
+    "nosuid",
+    "dev",
+    "nodev",
+    "async",
+    "sync",
+    "dirsync",
+    "atime",
+    "noatime",
+    "relatime",
+    "norelatime",
+    "strictatime",
+    "nostrictatime",
+    "lazytime",
+    "nolazytime",
+    "diratime",
+    "nodiratime",
+    "silent",
+    "loud",
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum FstabError {
+    #[error("usage: mount.eoss <provider> <mountpoint> [-fnsv] [-t <type>] [-o <options>]")]
+    Usage,
+}
+
+/// Arguments of `eoss-fuse mount` in the background for those of the
+/// helper, `<provider> <mountpoint> [-fnsv] [-t <type>] [-o <options>]`,
+/// as given by mount(8) for an fstab entry or a systemd mount unit.
+/// Options are those of `mount` with `_` for `-`, e.g.
+/// `key=credential:eoss,chunk_cache_bytes=67108864,write_back`, along with
+/// `ro` and the standard ones ignored.
+/// Returns `None` with `-f`, to fake the mount.
+pub fn mount_args(args: &[String]) -> Result<Option<Vec<String>>, FstabError> {
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => options.extend(args.next().ok_or(FstabError::Usage)?.split(',')),
+            "-f" => return Ok(None),
+            // the type, `eoss` or a subtype
+            "-t" => {
+                args.next().ok_or(FstabError::Usage)?;
+            }
+            // sloppy, no mtab, verbose
+            "-s" | "-n" | "-v" => {}
+            _ if arg.starts_with('-') => return Err(FstabError::Usage),
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let (provider, mountpoint) = match positional.as_slice() {
+        [provider, mountpoint] => (provider.clone(), mountpoint.clone()),
+        _ => return Err(FstabError::Usage),
+    };
+    let mut mount = vec![
+        "eoss-fuse".to_owned(),
+        "mount".to_owned(),
+        "--daemon".to_owned(),
+    ];
+    for option in options {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        if GENERIC.contains(&name) || name.starts_with("x-") || name == "comment" {
+            continue;
+        }
+        if name == "ro" {
+            mount.push("--read-only".to_owned());
+            continue;
+        }
+        mount.push(format!("--{}", name.replace('_', "-")));
+        mount.extend(value.map(str::to_owned));
+    }
+    mount.push(provider);
+    mount.push(mountpoint);
+    Ok(Some(mount))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mount_args;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split(' ').map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn test_fstab_options() {
+        let options = "rw,nofail,x-systemd.automount,noatime,key=credential:eoss,write_back,ro";
+        let helper = format!("local:///chunks /mnt -t eoss -o {}", options);
+        let mount = mount_args(&args(&helper)).unwrap().unwrap();
+        let expected = "eoss-fuse mount --daemon --key credential:eoss --write-back --read-only";
+        assert_eq!(mount, args(&format!("{} local:///chunks /mnt", expected)));
+        let fake = mount_args(&args("local:///chunks /mnt -f")).unwrap();
+        assert!(fake.is_none());
+        assert!(mount_args(&args("/mnt -o rw")).is_err());
+    }
+}
@@ -0,0 +1,4195 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
+use std::vec;
+
+#[cfg(unix)]
+use fuser::consts::FOPEN_DIRECT_IO;
+#[cfg(unix)]
+use fuser::{
+    BackgroundSession, FileAttr, FileType, MountOption, ReplyEmpty, Session, FUSE_ROOT_ID,
+};
+use libc::{
+    c_int, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENOENT, ENOSPC, ENOTDIR,
+    ENOTEMPTY, EOPNOTSUPP, EPERM, EROFS,
+};
+#[cfg(unix)]
+use libc::{EDQUOT, ESTALE};
+#[cfg(all(unix, not(target_os = "macos")))]
+use libc::{
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, O_DIRECT, RENAME_EXCHANGE,
+    RENAME_NOREPLACE,
+};
+use parking_lot::{Mutex, RwLock};
+
+#[cfg(target_os = "linux")]
+use crate::admin::{self, Ioctl};
+use crate::allocator::TinyFileAllocator;
+use crate::branch::{self, CloneError};
+use crate::bridge::Bridge;
+use crate::chunk::{BLOCK_SIZE, CHUNK_SIZE};
+use crate::coalesce::WriteBuffer;
+use crate::compact::{self, CompactError, CompactStats};
+use crate::compression::{self, Compression, CompressionRule};
+use crate::control::{self, Command, Mailbox, Reply, Value};
+#[cfg(unix)]
+use crate::control::COMMAND_INTERVAL;
+use crate::crypt::{KeyRing, MasterKey, Mode};
+use crate::dedup::{self, DedupIndex};
+use crate::dirindex;
+use crate::diskcache::DiskCache;
+#[cfg(unix)]
+use crate::dispatch::Dispatcher;
+use crate::events::{Event, EventKind, Events};
+use crate::export::{self, ExportError, ExportStats, Sink};
+use crate::fetcher;
+use crate::fs::{
+    Attrs, DirMeta, Entry, EntryMut, FileMeta, Node, TinyFileError, TinyFileMeta, CHATTR_FLAGS,
+    PINNED_FL,
+};
+use crate::gc::{self, GcStats, LeakReport};
+use crate::governor::GOVERNOR;
+use crate::health::Health;
+use crate::heatmap::{self, FileHeat, Heatmap};
+use crate::id::{ChunkId, Id, Journal as JournalChunk, Meta as IdMeta};
+use crate::import::{ImportError, ImportStats, Importer};
+use crate::inode::InodeTable;
+use crate::inspect::{self, ChunkReport, InspectError, InspectTarget, Reference, References};
+use crate::invalidate::{ChunkWatcher, Target};
+use crate::journal::{Batch, Journal, Record};
+use crate::keys::{KeyError, Secret};
+use crate::layout;
+use crate::lazy::{self, LazyChunks};
+use crate::lease::{self, Lease};
+#[cfg(unix)]
+use crate::lock::{Lock, LockTable};
+#[cfg(all(feature = "macos", target_os = "macos"))]
+use crate::macos::{
+    self, opened_direct, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, RENAME_EXCHANGE,
+    RENAME_NOREPLACE,
+};
+use crate::merkle::{self, UNHASHED};
+use crate::meta::{self, MetaError};
+use crate::metacache::MetaCache;
+use crate::nfs;
+use crate::options::{CacheMode, Discard, Fairness, FormatOptions, MountOptions};
+use crate::pin;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::providers::cached::CachedProvider;
+use crate::providers::compressed::CompressedProvider;
+use crate::providers::encrypted::EncryptedProvider;
+use crate::providers::guarded::GuardedProvider;
+use crate::providers::signed::SignedProvider;
+use crate::providers::spool::SpoolProvider;
+use crate::providers::traced::TracedProvider;
+use crate::publish::{Publisher, QUEUE_EVENTS};
+use crate::quota::{self, Quota, QuotaError, Usage};
+use crate::readahead::ReadaheadTable;
+use crate::recovery;
+use crate::refresh::{self, Refresh};
+use crate::rekey::Rekey;
+use crate::runtime::Background;
+use crate::scrub::{self, ScrubStats};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshots};
+use crate::stats::{RuntimeStats, STATS_DIR, STATS_DIR_INO, STATS_FILE, STATS_INO};
+use crate::stream::ChunkStream;
+use crate::superblock::{
+    Superblock, SuperblockError, FEATURE_COMPRESSED, FEATURE_CONTENT_ADDRESSED, FEATURE_CONVERGENT,
+    SUPERBLOCK_ID,
+};
+use crate::tenant;
+use crate::tier::{self, StorageClass};
+use crate::trace::Op;
+use crate::trash::{self, TrashError, TRASH_DIR};
+use crate::versions::{self, VersionError, VERSIONS_DIR};
+use crate::warmup::{self, WarmUp};
+#[cfg(windows)]
+use crate::win::{
+    FileAttr, FileType, EDQUOT, ESTALE, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE,
+    FUSE_ROOT_ID, RENAME_EXCHANGE, RENAME_NOREPLACE,
+};
+
+#[cfg(unix)]
+mod filesystem;
+
+/// Mode of `fallocate` punching a hole, which has to keep the size.
+const PUNCH_HOLE: i32 = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+/// Largest value of an extended attribute the kernel takes.
+const XATTR_SIZE_MAX: usize = 64 << 10;
+/// Error of the heatmap commands unless accesses are counted.
+const HEATMAP_OFF: &str = "accesses are not counted, mount with --heatmap";
+
+/// Data read of a file, and the range of it asked for.
+pub(crate) type ReadData = Result<(Arc<Vec<u8>>, Range<usize>), c_int>;
+
+/// The FUSE frontend of EOSS-fs.
+pub struct EossFs {
+    provider: Arc<dyn ChunkProvider>,
+    /// The provider beneath every layer, holding the table of tenants
+    backend: Arc<dyn ChunkProvider>,
+    options: MountOptions,
+    watcher: Arc<ChunkWatcher>,
+    superblock_id: Id,
+    superblock: Superblock,
+    root: DirMeta,
+    /// Mutations of `root` not persisted yet
+    journal: Journal,
+    /// Directories of `root` loaded in memory
+    cache: MetaCache,
+    /// Point-in-time copies of `root`
+    snapshots: Snapshots,
+    inodes: InodeTable,
+    /// Whether the whole tree was walked for the inodes of file handles,
+    /// each handle given since being remembered by `inodes`
+    handles_indexed: bool,
+    #[cfg(unix)]
+    locks: LockTable,
+    allocator: TinyFileAllocator,
+    /// Blocked `setlk` requests waiting for conflicting locks to be released
+    #[cfg(unix)]
+    lock_waiters: Vec<(u64, Lock, ReplyEmpty)>,
+    /// When unreferenced chunks were last collected
+    last_gc: Instant,
+    /// When chunks were last scrubbed, and what was found
+    last_scrub: Instant,
+    scrub_stats: Option<ScrubStats>,
+    /// Checksum of the content of the inode last asked for, by its Merkle
+    /// root and size when hashed, as the size of an attribute is asked for
+    /// before its value
+    checksum: Option<(u64, String, u64, String)>,
+    /// The chunk cache in front of the provider, if enabled
+    cached: Option<Arc<CachedProvider>>,
+    /// Changes queued while the provider is unreachable, if enabled
+    spool: Option<Arc<SpoolProvider>>,
+    /// Sealing chunks of an encrypted filesystem
+    encrypted: Option<Arc<EncryptedProvider<Arc<dyn ChunkProvider>>>>,
+    /// Resealing chunks sealed under retired keys, shared with the
+    /// background job
+    rekey: Arc<Mutex<Rekey>>,
+    /// References to chunks stored at their hash, if content addressed
+    dedup: Option<DedupIndex>,
+    /// Open file handles, detecting sequential reads, locked as reads run
+    /// in parallel
+    handles: Mutex<ReadaheadTable>,
+    /// Runs prefetching and periodic work
+    background: Background,
+    /// Small writes not written yet, by file handle
+    buffers: HashMap<u64, WriteBuffer>,
+    /// File handles which kept a version of their file since opened
+    versioned: HashSet<u64>,
+    /// Inodes written to since flushed, their `Modify` event held back
+    modified: HashSet<u64>,
+    /// Chunks being appended from their start past the end of file, saved
+    /// as written, by inode along with the index of the chunk
+    streams: HashMap<u64, (usize, ChunkStream)>,
+    /// Chunks read block by block, if reading lazily
+    lazies: Arc<LazyChunks>,
+    /// Accesses of files, if counted
+    heatmap: Option<Heatmap>,
+    /// Commands from the control socket, if served
+    control: Option<Arc<Mailbox>>,
+    /// Calls to the provider failed
+    provider_errors: Arc<AtomicU64>,
+    /// The stats file as of its opening, by file handle
+    stats_files: HashMap<u64, Vec<u8>>,
+    /// The publication mounted read-only, replaced by newer ones
+    refresh: Option<Arc<Refresh>>,
+    /// Changes of the tree, for watchers beyond the kernel
+    events: Arc<Events>,
+    /// Lease on mounting read-write, renewed in background once mounted
+    lease: Option<Arc<Lease>>,
+    /// Compression of files by extension, replaced once reloaded
+    compression_rules: Arc<RwLock<Vec<CompressionRule>>>,
+}
+
+/// Reloader applies the options reloadable to a filesystem mounted.
+pub struct Reloader {
+    cached: Option<Arc<CachedProvider>>,
+    compression_rules: Arc<RwLock<Vec<CompressionRule>>>,
+}
+
+impl Reloader {
+    /// Apply the memory limit, the chunk cache budget and the compression
+    /// rules of `options`. The chunk cache is not enabled if it was not at
+    /// mount.
+    pub fn reload(&self, options: &MountOptions) -> Result<(), ChunkProviderError> {
+        GOVERNOR.set_limit(options.memory_limit.unwrap_or(usize::MAX));
+        *self.compression_rules.write() = options.compression_rules.clone();
+        match &self.cached {
+            Some(cached) => cached.set_budget(options.chunk_cache_bytes),
+            None => Ok(()),
+        }
+    }
+}
+
+impl EossFs {
+    /// Write a new filesystem with an empty root directory to `provider`,
+    /// refuses to overwrite an existing one unless `options.force` is set.
+    pub fn format(
+        provider: &dyn ChunkProvider,
+        options: &FormatOptions,
+    ) -> Result<Superblock, SuperblockError> {
+        Self::format_at(provider, options, &Id::new(SUPERBLOCK_ID))
+    }
+
+    /// Format like `format`, the superblock stored at `superblock_id`.
+    pub fn format_at(
+        provider: &dyn ChunkProvider,
+        options: &FormatOptions,
+        superblock_id: &Id,
+    ) -> Result<Superblock, SuperblockError> {
+        match Superblock::load(provider, superblock_id) {
+            Err(SuperblockError::NotFormatted) => {}
+            _ if !options.force => return Err(SuperblockError::AlreadyFormatted),
+            _ => {}
+        }
+        if options.content_addressed && options.key.is_some() {
+            return Err(SuperblockError::ContentAddressedEncrypted);
+        }
+        let signed;
+        let provider = match &options.signing_key {
+            Some(signing) => {
+                signed = SignedProvider::with_signing_key(provider, signing.clone());
+                &signed as &dyn ChunkProvider
+            }
+            None => provider,
+        };
+        let encrypted;
+        let provider = match &options.key {
+            Some(key) => {
+                let mode = if options.convergent {
+                    Mode::Convergent
+                } else {
+                    Mode::Random
+                };
+                let keys = Arc::new(KeyRing::new(key.clone()).with_mode(mode));
+                encrypted = EncryptedProvider::new(provider, keys, superblock_id.clone());
+                &encrypted as &dyn ChunkProvider
+            }
+            None => provider,
+        };
+        let mut root = DirMeta::new(
+            String::new(),
+            Attrs::new(options.perm, options.uid, options.gid),
+        );
+        root.quota = options.quota;
+        dirindex::store_dir(provider, &mut root)?;
+        let root_id = root.id.clone();
+        let journal_id = Id::new_random();
+        Journal::new(journal_id.clone()).checkpoint(provider)?;
+        let snapshots_id = ChunkId::<IdMeta>::new_random();
+        Snapshots::create(provider, &snapshots_id)?;
+        // written last, so an interrupted format leaves no filesystem behind
+        let mut superblock = Superblock::new(&root_id, &journal_id, snapshots_id.id());
+        if let Some(key) = &options.key {
+            superblock.set_key(key);
+            superblock.key_slots = options.key_slots.clone();
+            if options.convergent {
+                superblock.features |= FEATURE_CONVERGENT;
+            }
+        }
+        if let Some(signing) = &options.signing_key {
+            superblock.set_signer(signing.verifying_key());
+        }
+        if options.content_addressed {
+            superblock.features |= FEATURE_CONTENT_ADDRESSED;
+        }
+        if options.compressed {
+            superblock.features |= FEATURE_COMPRESSED;
+        }
+        superblock.store(provider, superblock_id)?;
+        provider.flush().map_err(MetaError::from)?;
+        Ok(superblock)
+    }
+
+    /// Open the filesystem whose superblock is stored at `superblock_id`.
+    /// After an unclean shutdown the journal is replayed before the
+    /// filesystem is returned.
+    /// With `options.snapshot`, the snapshot is opened read-only instead,
+    /// leaving the live tree untouched. A signed filesystem is opened
+    /// read-only as well with only its verifying key.
+    pub fn open(
+        provider: Arc<dyn ChunkProvider>,
+        options: MountOptions,
+        superblock_id: &Id,
+    ) -> Result<Self, SuperblockError> {
+        let options = match options.verify {
+            // nothing is served unless fetched and checked again
+            true => MountOptions {
+                chunk_cache_bytes: 0,
+                disk_cache: None,
+                readahead_chunks: 0,
+                cache_mode: CacheMode::DirectIo,
+                ..options
+            },
+            false => options,
+        };
+        GOVERNOR.set_limit(options.memory_limit.unwrap_or(usize::MAX));
+        // checked on the superblock in the clear, before anything is opened
+        let clear = Superblock::load(provider.as_ref(), superblock_id)?;
+        if options.verify && clear.signer.is_some() && options.verifying_key.is_none() {
+            // checked against the key of the reader, not of the signer
+            return Err(SuperblockError::VerifyingKeyRequired);
+        }
+        clear.check_key(options.key.as_ref())?;
+        let signer = options.signer();
+        clear.check_signer(signer.as_ref())?;
+        let backend = provider.clone();
+        let provider: Arc<dyn ChunkProvider> = match options.detect_conflicts {
+            true => Arc::new(GuardedProvider::new(provider)),
+            false => provider,
+        };
+        // calls reaching the provider, beneath every layer
+        let errors = Arc::new(AtomicU64::new(0));
+        let provider: Arc<dyn ChunkProvider> =
+            Arc::new(TracedProvider::new(provider).with_errors(errors.clone()));
+        let provider = match (&options.signing_key, signer) {
+            (Some(signing), _) => {
+                Arc::new(SignedProvider::with_signing_key(provider, signing.clone()))
+            }
+            (None, Some(verifying)) => Arc::new(SignedProvider::new(provider, verifying)),
+            (None, None) => provider,
+        };
+        let encrypted = match &options.key {
+            Some(key) => {
+                let keys = KeyRing::open(key.clone(), clear.key_epoch, &clear.key_epochs)
+                    .map_err(|e| MetaError::from(ChunkProviderError::from(e)))?;
+                let keys = Arc::new(keys.with_mode(clear.seal_mode()));
+                Some(Arc::new(EncryptedProvider::new(
+                    provider.clone(),
+                    keys,
+                    superblock_id.clone(),
+                )))
+            }
+            None => None,
+        };
+        let provider = match &encrypted {
+            Some(encrypted) => encrypted.clone() as Arc<dyn ChunkProvider>,
+            None => provider,
+        };
+        let provider: Arc<dyn ChunkProvider> = match clear.compressed() {
+            true => Arc::new(CompressedProvider::new(provider)),
+            false => provider,
+        };
+        let spool = match &options.spool {
+            Some(dir) => Some(Arc::new(SpoolProvider::open(
+                provider.clone(),
+                dir,
+                options.spool_bytes,
+            )?)),
+            None => None,
+        };
+        let provider = match &spool {
+            Some(spool) => spool.clone() as Arc<dyn ChunkProvider>,
+            None => provider,
+        };
+        let cached = match options.chunk_cache_bytes {
+            0 => None,
+            budget => {
+                let concurrency = options.fetch_concurrency;
+                let mut cached = CachedProvider::new(provider.clone(), budget, concurrency);
+                if let Some(dir) = &options.disk_cache {
+                    let disk = DiskCache::open(dir, options.disk_cache_bytes)?;
+                    cached = cached.with_disk_cache(disk);
+                }
+                let cached = Arc::new(cached);
+                let reclaimer: Weak<CachedProvider> = Arc::downgrade(&cached);
+                GOVERNOR.register(reclaimer);
+                Some(cached)
+            }
+        };
+        let provider = match &cached {
+            Some(cached) => cached.clone() as Arc<dyn ChunkProvider>,
+            None => provider,
+        };
+        let mut superblock = Superblock::load(provider.as_ref(), superblock_id)?;
+        if options.signing_key.is_none() {
+            superblock.check_fresh(options.min_sequence, options.max_signature_age)?;
+        }
+        if options.read_only || options.dry_run || options.verify {
+            let snapshot = options.snapshot.clone();
+            return Self::open_read_only(
+                provider,
+                options,
+                superblock_id,
+                superblock,
+                snapshot.as_deref(),
+            )
+            .map(|fs| fs.with_layers(errors, cached));
+        }
+        if let Some(name) = options.snapshot.clone() {
+            return Self::open_read_only(provider, options, superblock_id, superblock, Some(&name))
+                .map(|fs| fs.with_layers(errors, cached));
+        }
+        if options.signing_key.is_none() && signer.is_some() {
+            // the journal cannot be replayed without signing it
+            if superblock.dirty {
+                return Err(SuperblockError::Unclean);
+            }
+            return Self::open_read_only(provider, options, superblock_id, superblock, None)
+                .map(|fs| fs.with_layers(errors, cached));
+        }
+        // released once dropped if opening fails
+        let lease = match options.lease_ttl {
+            Some(ttl) => {
+                let holder = lease::holder();
+                Some(Lease::acquire(backend.clone(), superblock_id, holder, ttl)?)
+            }
+            None => None,
+        };
+        let mut root = dirindex::load_dir(provider.as_ref(), &superblock.root_id)?;
+        let (journal, records) = Journal::open(provider.as_ref(), superblock.journal_id())?;
+        // records left by a clean unmount are already in the tree
+        if superblock.dirty {
+            recovery::recover(&mut root, provider.as_ref(), records).map_err(MetaError::from)?;
+        }
+        let snapshots = Snapshots::open(provider.as_ref(), superblock.snapshots_id())?;
+        let dedup = match superblock.content_addressed() {
+            true => Some(DedupIndex::open(provider.as_ref(), superblock_id)?),
+            false => None,
+        };
+        let unclean = superblock.dirty;
+        superblock.dirty = true;
+        superblock.store(provider.as_ref(), superblock_id)?;
+
+        let mut fs = Self::new_with_root(
+            provider,
+            options,
+            superblock_id.clone(),
+            superblock,
+            root,
+            journal,
+            snapshots,
+        )?;
+        if fs.options.write_back && cached.is_some() {
+            fs.journal.set_write_back(true);
+        }
+        fs.journal.set_batch(fs.options.journal_batch);
+        fs.cached = cached;
+        fs.backend = backend;
+        fs.provider_errors = errors;
+        fs.spool = spool;
+        fs.encrypted = encrypted;
+        fs.dedup = dedup;
+        fs.lease = lease.map(Arc::new);
+        // the tree is still fully loaded
+        if fs
+            .pin_chunks(&pin::pinned_chunks(&fs.root, false), true)
+            .is_err()
+        {
+            tracing::warn!("pinned files take more than half of the chunk cache, not pinned");
+        }
+        if unclean {
+            // references since last synced are lost
+            fs.rebuild_dedup()?;
+        }
+        fs.sync()?;
+        // loaded again on demand
+        fs.cache.clear(&mut fs.root);
+        Ok(fs)
+    }
+
+    /// Open the tree of `snapshot` read-only, or the live tree if `None`.
+    fn open_read_only(
+        provider: Arc<dyn ChunkProvider>,
+        options: MountOptions,
+        superblock_id: &Id,
+        superblock: Superblock,
+        snapshot: Option<&str>,
+    ) -> Result<Self, SuperblockError> {
+        let snapshots = Snapshots::open(provider.as_ref(), superblock.snapshots_id())?;
+        let root_id = match snapshot {
+            Some(name) => match snapshots.list().iter().find(|s| s.name == name) {
+                Some(snapshot) => snapshot.root_id,
+                None => return Err(SnapshotError::NotFound(name.to_owned()).into()),
+            },
+            None => superblock.root_id,
+        };
+        let root = dirindex::load_dir(provider.as_ref(), &root_id)?;
+        let refresh = match options.refresh_interval.filter(|_| snapshot.is_none()) {
+            Some(_) => {
+                let (_, mounted) = refresh::publication(provider.as_ref(), superblock_id)?;
+                let max_age = options.max_signature_age;
+                Some(Arc::new(Refresh::new(
+                    superblock_id.clone(),
+                    mounted,
+                    max_age,
+                )))
+            }
+            None => None,
+        };
+        // never written
+        let journal = Journal::new(superblock.journal_id());
+        let mut fs = Self::new_with_root(
+            provider,
+            options,
+            superblock_id.clone(),
+            superblock,
+            root,
+            journal,
+            snapshots,
+        )?;
+        fs.refresh = refresh;
+        fs.cache.clear(&mut fs.root);
+        Ok(fs)
+    }
+
+    /// Swap the tree mounted read-only for the one its writer published
+    /// since, if polling loaded one, queueing what the kernel cached of the
+    /// old one to be dropped in background. Returns whether it was swapped.
+    fn refresh(&mut self) -> Result<bool, SuperblockError> {
+        let (refresh, loaded) = match &self.refresh {
+            Some(refresh) => match refresh.take() {
+                Some(loaded) => (refresh.clone(), loaded),
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        self.superblock = loaded.superblock;
+        let mounted = mem::replace(&mut self.root, loaded.root);
+        self.snapshots = loaded.snapshots;
+        let events = self.changes(&mounted);
+        self.cache.clear(&mut self.root);
+        self.events.emit_all(events.clone());
+        let mut targets = Vec::new();
+        for (ino, path) in self.inodes.iter() {
+            targets.push(Target::Inode(ino));
+            if let Some((name, parent)) = path.split_last() {
+                if let Some(parent) = self.inodes.get(parent) {
+                    targets.push(Target::Entry(parent, name.into()));
+                }
+            }
+        }
+        refresh.invalidate(events, targets);
+        Ok(true)
+    }
+
+    /// Changes of the entries the kernel looked up from the tree `mounted`
+    /// to the one mounted now: those gone or modified, and those created in
+    /// directories loaded.
+    fn changes(&mut self, mounted: &DirMeta) -> Vec<Event> {
+        let provider = self.provider.clone();
+        let known: Vec<(u64, Vec<String>)> = self
+            .inodes
+            .iter()
+            .map(|(ino, path)| (ino, path.to_vec()))
+            .collect();
+        let mut events = Vec::new();
+        for (ino, path) in known {
+            let before = match mounted.resolve(&path) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // left out unless known to be gone
+            if let Err(e) = self.cache.load(&mut self.root, provider.as_ref(), &path) {
+                tracing::warn!("failed to load {}: {}", path.join("/"), e);
+                continue;
+            }
+            let parent = match path.split_last() {
+                Some((_, parent)) => self.inodes.get(parent).unwrap_or(0),
+                None => 0,
+            };
+            match (before, self.root.resolve(&path)) {
+                (before, None) => events.push(Event::new(
+                    EventKind::Delete,
+                    parent,
+                    &path,
+                    Some(before),
+                    ino,
+                )),
+                (Entry::Dir(before), Some(Entry::Dir(after))) if before.loaded => {
+                    for (name, node) in after.entries.iter() {
+                        if !before.entries.contains_key(name) {
+                            let mut child = path.clone();
+                            child.push(name.clone());
+                            let entry = Some(node.as_entry());
+                            events.push(Event::new(EventKind::Create, ino, &child, entry, 0));
+                        }
+                    }
+                }
+                (Entry::Dir(_), Some(_)) => {}
+                (before, Some(after)) => {
+                    let changed = before.attrs().size != after.attrs().size
+                        || before.attrs().mtime != after.attrs().mtime;
+                    if changed {
+                        events.push(Event::new(
+                            EventKind::Modify,
+                            parent,
+                            &path,
+                            Some(after),
+                            ino,
+                        ));
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    /// Emit a change of inode `ino` to subscribers of the events.
+    fn emit(&self, kind: EventKind, ino: u64) {
+        if !self.events.has_subscribers() {
+            return;
+        }
+        if let Some(path) = self.inodes.path(ino) {
+            if let Some(parent) = path
+                .split_last()
+                .and_then(|(_, parent)| self.inodes.get(parent))
+            {
+                let event = Event::new(kind, parent, path, self.root.resolve(path), ino);
+                self.events.emit(event);
+            }
+        }
+    }
+
+    /// Event of a change of the entry `name` of `parent`.
+    fn entry_event(&self, kind: EventKind, parent: u64, name: &str) -> Option<Event> {
+        if !self.events.has_subscribers() {
+            return None;
+        }
+        let mut path = self.inodes.path(parent)?.to_vec();
+        path.push(name.to_owned());
+        let ino = self.inodes.get(&path).unwrap_or(0);
+        Some(Event::new(
+            kind,
+            parent,
+            &path,
+            self.root.resolve(&path),
+            ino,
+        ))
+    }
+
+    /// Changes of the tree made from now on, along with those of the
+    /// writer published to a mount refreshed, through any of its frontends.
+    pub fn events(&self) -> Arc<Events> {
+        self.events.clone()
+    }
+
+    /// Whether writes are kept in the chunk cache until written back.
+    fn writes_back(&self) -> bool {
+        self.options.write_back && self.cached.is_some() && !self.read_only()
+    }
+
+    /// Persist writes kept in the chunk cache and journal records batched,
+    /// data before the metadata referring to it.
+    fn write_back(&self) -> Result<(), c_int> {
+        self.journal
+            .commit(self.provider.as_ref())
+            .map_err(|e| e.errno())?;
+        if self.writes_back() {
+            self.provider.flush().map_err(|e| e.errno())?;
+        }
+        Ok(())
+    }
+
+    /// Whether a snapshot, or a signed filesystem without its signing key,
+    /// is mounted, which cannot be modified, or a dry run or verification.
+    pub(crate) fn read_only(&self) -> bool {
+        let published = self.options.signing_key.is_none() && self.options.verifying_key.is_some();
+        let checking = self.options.dry_run || self.options.verify;
+        self.options.read_only || self.options.snapshot.is_some() || published || checking
+    }
+
+    /// Persist writes buffered and the directory tree, the filesystem
+    /// staying mounted.
+    pub fn sync_all(&mut self) -> Result<(), SuperblockError> {
+        if self.read_only() {
+            return Ok(());
+        }
+        self.flush_all_writes()
+            .map_err(io::Error::from_raw_os_error)?;
+        self.sync()?;
+        self.provider.flush().map_err(MetaError::from)?;
+        Ok(())
+    }
+
+    /// Persist the directory tree and mark the filesystem cleanly unmounted.
+    pub fn close(&mut self) -> Result<(), SuperblockError> {
+        self.record_warmup();
+        if self.read_only() {
+            return Ok(());
+        }
+        // nothing to report the failure of a write buffered to
+        let _ = self.flush_all_writes();
+        self.sync()?;
+        self.drop_retired_keys();
+        self.superblock.dirty = false;
+        self.superblock
+            .store(self.provider.as_ref(), &self.superblock_id)?;
+        self.provider.flush().map_err(MetaError::from)?;
+        // released once dropped
+        self.lease = None;
+        Ok(())
+    }
+
+    fn new_with_root(
+        provider: Arc<dyn ChunkProvider>,
+        options: MountOptions,
+        superblock_id: Id,
+        superblock: Superblock,
+        mut root: DirMeta,
+        journal: Journal,
+        mut snapshots: Snapshots,
+    ) -> Result<Self, SuperblockError> {
+        // rebuild the occupancy of shared chunks
+        let mut allocator = TinyFileAllocator::new();
+        let _ = root.visit_tiny_files_mut(&mut |file| -> Result<(), ()> {
+            if file.chunk_blocks > 0 {
+                allocator.mark(&file.chunk_id, file.chunk_offset, file.chunk_blocks);
+            }
+            Ok(())
+        });
+        snapshots.mark(&mut allocator);
+        snapshots.track_clones(&root);
+        let compression_rules = Arc::new(RwLock::new(options.compression_rules.clone()));
+        let heatmap = match options.heatmap {
+            true => Some(Heatmap::new()),
+            false => None,
+        };
+        let rekey = Arc::new(Mutex::new(Rekey::new(&superblock_id)));
+        Ok(Self {
+            background: Background::new(&options.runtime)?,
+            cache: MetaCache::new(options.meta_cache_dirs),
+            handles: Mutex::new(ReadaheadTable::new(options.readahead_chunks)),
+            backend: provider.clone(),
+            provider,
+            options,
+            watcher: Arc::new(ChunkWatcher::new()),
+            superblock_id,
+            superblock,
+            root,
+            journal,
+            snapshots,
+            inodes: InodeTable::new(),
+            handles_indexed: false,
+            #[cfg(unix)]
+            locks: LockTable::new(),
+            allocator,
+            #[cfg(unix)]
+            lock_waiters: Vec::new(),
+            last_gc: Instant::now(),
+            last_scrub: Instant::now(),
+            scrub_stats: None,
+            checksum: None,
+            cached: None,
+            spool: None,
+            encrypted: None,
+            rekey,
+            dedup: None,
+            buffers: HashMap::new(),
+            versioned: HashSet::new(),
+            modified: HashSet::new(),
+            streams: HashMap::new(),
+            lazies: Arc::default(),
+            heatmap,
+            control: None,
+            provider_errors: Arc::new(AtomicU64::new(0)),
+            stats_files: HashMap::new(),
+            refresh: None,
+            events: Arc::new(Events::new()),
+            lease: None,
+            compression_rules,
+        })
+    }
+
+    /// Count the calls to the provider failed in `errors`, keeping the
+    /// chunk cache `cached` of a filesystem opened read-only.
+    fn with_layers(mut self, errors: Arc<AtomicU64>, cached: Option<Arc<CachedProvider>>) -> Self {
+        self.provider_errors = errors;
+        self.cached = cached;
+        self
+    }
+
+    /// State of the filesystem mounted, shown in the stats file.
+    pub fn runtime_stats(&self) -> RuntimeStats {
+        RuntimeStats {
+            cache_bytes: self
+                .cached
+                .as_ref()
+                .map_or(0, |cached| cached.cached_bytes()),
+            dirty_bytes: self
+                .cached
+                .as_ref()
+                .map_or(0, |cached| cached.dirty_bytes()),
+            oldest_dirty_ms: self
+                .cached
+                .as_ref()
+                .and_then(|cached| cached.dirty_age())
+                .into_iter()
+                // writes buffered are dirty since buffered
+                .chain(
+                    self.buffers
+                        .values()
+                        .filter_map(|buffer| buffer.dirtied().map(|at| at.elapsed())),
+                )
+                .max()
+                .map_or(0, |age| age.as_millis() as u64),
+            provider_errors: self.provider_errors.load(Ordering::Relaxed),
+            open_handles: self.handles.lock().len(),
+            // the root is never forgotten
+            inodes: self.inodes.len() - 1,
+            journal_records: self.journal.len(),
+        }
+    }
+
+    /// Ids of the chunks of the file of inode `ino` in order.
+    pub(crate) fn chunk_ids(&self, ino: u64) -> Result<Vec<Id>, c_int> {
+        self.entry(ino).and_then(layout::chunk_ids).ok_or(EISDIR)
+    }
+
+    /// Bytes of the chunks of inode `ino` not written back to the provider,
+    /// and of the writes to it still buffered.
+    fn dirty_bytes_of(&self, ino: u64) -> Result<usize, c_int> {
+        let ids = self.chunk_ids(ino)?;
+        let buffered: usize = self
+            .buffers
+            .values()
+            .filter(|buffer| buffer.ino == ino)
+            .map(WriteBuffer::buffered)
+            .sum();
+        Ok(buffered
+            + self
+                .cached
+                .as_ref()
+                .map_or(0, |cached| cached.dirty_bytes_of(&ids)))
+    }
+
+    /// Block until every chunk that became dirty at `point` or before is
+    /// durably stored in the provider along with the journal, as a `syncfs`
+    /// of what was written until then. Writes still buffered count as dirty
+    /// since buffered, so are moved to the chunk cache and written back
+    /// along with all dirty since. Returns the number of chunks written back.
+    pub fn barrier(&mut self, point: SystemTime) -> Result<usize, c_int> {
+        let age = SystemTime::now().duration_since(point).unwrap_or_default();
+        // `None` if before any chunk became dirty
+        let mut point = Instant::now().checked_sub(age);
+        let buffered = point.map_or(false, |point| {
+            self.buffers
+                .values()
+                .any(|buffer| buffer.dirtied().map_or(false, |at| at <= point))
+        });
+        self.flush_all_writes()?;
+        if buffered {
+            // dirty in the cache from now on
+            point = Some(Instant::now());
+        }
+        self.journal
+            .commit(self.provider.as_ref())
+            .map_err(|e| e.errno())?;
+        let written = match (&self.cached, point) {
+            (Some(cached), Some(point)) if self.writes_back() => {
+                cached.write_back_before(point).map_err(|e| e.errno())?
+            }
+            _ => 0,
+        };
+        self.provider.flush().map_err(|e| e.errno())?;
+        Ok(written)
+    }
+
+    /// Persist the directory tree to the provider, and discard the journal.
+    pub fn sync(&mut self) -> Result<(), MetaError> {
+        dirindex::store_dir(self.provider.as_ref(), &mut self.root)?;
+        if let Some(dedup) = &mut self.dedup {
+            dedup.store(self.provider.as_ref())?;
+        }
+        self.provider.flush()?;
+        self.journal.checkpoint(self.provider.as_ref())
+    }
+
+    /// Load all metadata and reach the provider as a mount would, and report
+    /// what is found.
+    pub fn health(&mut self) -> Result<Health, MetaError> {
+        let provider = self.provider.clone();
+        dirindex::load_all(provider.as_ref(), &mut self.root)?;
+        let usage = quota::recount(&mut self.root);
+        self.cache.release(&mut self.root);
+        let (_, records) = Journal::open(provider.as_ref(), self.superblock.journal_id())?;
+        Ok(Health {
+            uuid: self.superblock.uuid,
+            encrypted: self.superblock.key_check.is_some(),
+            signed: self.superblock.signer.is_some(),
+            content_addressed: self.superblock.content_addressed(),
+            unclean: self.superblock.dirty,
+            pending_records: match self.superblock.dirty {
+                true => records.len(),
+                false => 0,
+            },
+            snapshots: self.snapshots.list().len(),
+            usage,
+            chunks: provider.list_chunks()?.map(|ids| ids.len()),
+        })
+    }
+
+    /// Handle applying options reloaded once mounted.
+    pub fn reloader(&self) -> Reloader {
+        Reloader {
+            cached: self.cached.clone(),
+            compression_rules: self.compression_rules.clone(),
+        }
+    }
+
+    /// Mailbox of the commands of a control socket, run in background
+    /// once mounted.
+    pub fn control(&mut self) -> Arc<Mailbox> {
+        self.control.get_or_insert_with(Default::default).clone()
+    }
+
+    /// Prefetch the chunks of the warm-up manifest into the chunk cache in
+    /// background, as many as fit in it, those no longer stored skipped.
+    pub fn warm_up(&self) -> WarmUp {
+        let (cached, path) = match (&self.cached, &self.options.warmup) {
+            (Some(cached), Some(path)) => (cached, path),
+            _ => return WarmUp::none(),
+        };
+        let mut ids = match warmup::load(path) {
+            Ok(ids) => ids,
+            Err(e) => {
+                tracing::warn!("failed to load warm-up manifest {}: {}", path.display(), e);
+                return WarmUp::none();
+            }
+        };
+        ids.truncate(self.options.chunk_cache_bytes / CHUNK_SIZE);
+        let workers = self.options.fetch_concurrency.max(1);
+        let warm_up = WarmUp::new(ids.len(), workers);
+        let ids = Arc::new(Mutex::new(ids.into_iter()));
+        for _ in 0..workers {
+            let (cached, ids, done) = (Arc::downgrade(cached), ids.clone(), warm_up.done());
+            self.background.spawn_blocking(move || {
+                warm_up_chunks(&cached, &ids);
+                done();
+            });
+        }
+        warm_up
+    }
+
+    /// Record the chunks used most recently as the warm-up manifest, for
+    /// the next mount to prefetch, unless kept as given.
+    fn record_warmup(&self) {
+        let (cached, path) = match (&self.cached, &self.options.warmup) {
+            (Some(cached), Some(path)) if self.options.warmup_chunks > 0 => (cached, path),
+            _ => return,
+        };
+        let ids = cached.recent(self.options.warmup_chunks);
+        if let Err(e) = warmup::store(path, &ids) {
+            tracing::warn!(
+                "failed to record warm-up manifest {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Start the tasks run in background while mounted, by any frontend.
+    pub(crate) fn start_background(&self) {
+        self.warm_up();
+        let watcher = self.watcher.clone();
+        if let Some(window) = self.options.journal_batch.filter(|_| !self.read_only()) {
+            let batch = Arc::downgrade(self.journal.batch());
+            let (provider, flush) = (self.provider.clone(), self.journal.flushes());
+            self.background.every(window, move || {
+                commit_journal(&batch, provider.as_ref(), window, flush)
+            });
+        }
+        if let Some(encrypted) = self.encrypted.as_ref().filter(|_| !self.read_only()) {
+            let encrypted = Arc::downgrade(encrypted);
+            let (rekey, batch) = (self.rekey.clone(), self.options.rekey_batch);
+            self.background.every(self.options.rekey_interval, move || {
+                reseal_chunks(&encrypted, &rekey, batch)
+            });
+        }
+        if let Some(spool) = &self.spool {
+            let spool = Arc::downgrade(spool);
+            self.background
+                .every(self.options.resync_interval, move || resync_spool(&spool));
+        }
+        if let (Some(interval), Some(refresh)) = (self.options.refresh_interval, &self.refresh) {
+            let (refresh, provider) = (Arc::downgrade(refresh), self.provider.clone());
+            let (watcher, cached) = (Arc::downgrade(&watcher), self.cached.clone());
+            self.background.every(interval, move || {
+                poll_publication(&refresh, &watcher, cached.as_deref(), provider.as_ref())
+            });
+        }
+        if let Some(sink) = self.options.publish.clone() {
+            Publisher::new(sink).spawn(self.events.subscribe(QUEUE_EVENTS));
+        }
+        if let Some(lease) = &self.lease {
+            let (interval, lease) = (lease.ttl() / 3, Arc::downgrade(lease));
+            self.background.every(interval, move || renew_lease(&lease));
+        }
+        if let Some(interval) = self.options.invalidate_interval {
+            let (watcher, provider) = (Arc::downgrade(&watcher), self.provider.clone());
+            self.background
+                .every(interval, move || poll_changes(&watcher, provider.as_ref()));
+        }
+        if let Some(cached) = self.cached.as_ref().filter(|_| self.writes_back()) {
+            let cached = Arc::downgrade(cached);
+            let (max_bytes, max_age) = (self.options.dirty_bytes, self.options.dirty_age);
+            let interval = (max_age / 4).clamp(Duration::from_millis(100), Duration::from_secs(1));
+            self.background
+                .every(interval, move || flush_dirty(&cached, max_bytes, max_age));
+        }
+    }
+
+    /// Mount the filesystem at `mountpoint` in background.
+    #[cfg(unix)]
+    pub fn mount<P: AsRef<Path>>(self, mountpoint: P) -> io::Result<BackgroundSession> {
+        self.start_background();
+        let watcher = self.watcher.clone();
+        let mut options = Vec::new();
+        if self.read_only() {
+            options.push(MountOption::RO);
+        }
+        #[cfg(all(feature = "macos", target_os = "macos"))]
+        options.extend(macos::mount_options(&self.options.mac));
+
+        // commands are run holding the filesystem shared by a dispatcher
+        if self.options.parallel_reads == 0 && self.control.is_none() {
+            let session = Session::new(self, mountpoint.as_ref(), &options)?;
+            watcher.set_notifier(session.notifier());
+            return session.spawn();
+        }
+        let dispatcher = Dispatcher::new(self);
+        let fs = dispatcher.fs().read();
+        if let Some(mailbox) = fs.control.clone() {
+            let shared = Arc::downgrade(dispatcher.fs());
+            fs.background
+                .every(COMMAND_INTERVAL, move || run_commands(&shared, &mailbox));
+        }
+        drop(fs);
+        let session = Session::new(dispatcher, mountpoint.as_ref(), &options)?;
+        watcher.set_notifier(session.notifier());
+        session.spawn()
+    }
+
+    pub(crate) fn parallel_reads(&self) -> usize {
+        self.options.parallel_reads
+    }
+
+    pub(crate) fn bridge(&self) -> Bridge {
+        Bridge::new(self.background.handle().clone())
+    }
+
+    /// Rotate the master key to a new random one, chunks are sealed under
+    /// it from now on and the older ones resealed by `rekey` or in the
+    /// background, after which the former key opens none of them. Key
+    /// slots unlocked by `secret` wrap the new key, the others are removed.
+    /// Returns the new epoch.
+    pub fn rotate_key(&mut self, secret: &Secret) -> Result<u32, SuperblockError> {
+        let encrypted = match &self.encrypted {
+            Some(encrypted) => encrypted.clone(),
+            None => return Err(SuperblockError::NotEncrypted),
+        };
+        let key = MasterKey::new_random();
+        let mut slots = Vec::new();
+        for slot in self.superblock.key_slots.iter() {
+            slots.extend(slot.rewrap(&key, secret)?);
+        }
+        if slots.is_empty() {
+            return Err(KeyError::NoMatchingSlot.into());
+        }
+        self.superblock.set_key(&key);
+        self.superblock.key_slots = slots;
+        self.superblock.key_epoch = encrypted.keys().current() + 1;
+        self.superblock.key_epochs = encrypted.keys().retire(&key);
+        // stored first, so no chunk is sealed under a key not persisted
+        self.superblock
+            .store(self.provider.as_ref(), &self.superblock_id)?;
+        Ok(encrypted.keys().rotate(key))
+    }
+
+    /// Reseal the next chunks sealed under a retired key, dropping the
+    /// retired keys once none are left. Returns true once none are left.
+    pub fn rekey(&mut self) -> Result<bool, SuperblockError> {
+        let done = match &self.encrypted {
+            Some(encrypted) => self
+                .rekey
+                .lock()
+                .step(encrypted.as_ref(), self.options.rekey_batch)?,
+            None => return Ok(true),
+        };
+        if self.drop_retired_keys() {
+            self.superblock
+                .store(self.provider.as_ref(), &self.superblock_id)?;
+        }
+        Ok(done)
+    }
+
+    /// Drop the retired keys from the superblock once every chunk is
+    /// resealed. Returns whether any were dropped.
+    fn drop_retired_keys(&mut self) -> bool {
+        let keys = match &self.encrypted {
+            Some(encrypted) => encrypted.keys(),
+            None => return false,
+        };
+        if self.superblock.key_epochs.is_empty() || !self.rekey.lock().done(keys.current()) {
+            return false;
+        }
+        self.superblock.key_epochs.clear();
+        keys.drop_retired();
+        true
+    }
+
+    /// Compact sparse shared chunks of tiny files.
+    /// The tree is persisted before the old chunks are deleted.
+    pub fn compact(&mut self) -> Result<CompactStats, CompactError> {
+        dirindex::load_all(self.provider.as_ref(), &mut self.root)?;
+        let (mut sparse, mut stats) = compact::relocate(
+            &mut self.root,
+            self.provider.as_ref(),
+            &mut self.allocator,
+            self.options.compact_threshold,
+        )?;
+        self.sync()?;
+        self.cache.clear(&mut self.root);
+        // still holding tiny files of snapshots
+        sparse.retain(|id| !self.snapshots.references_chunk(id));
+        stats.chunks = sparse.len();
+        for id in sparse.iter() {
+            self.provider.delete_chunk(id)?;
+        }
+        Ok(stats)
+    }
+
+    /// Take a snapshot of the current tree named `name`.
+    pub fn snapshot(&mut self, name: &str) -> Result<Snapshot, SnapshotError> {
+        dirindex::load_all(self.provider.as_ref(), &mut self.root)?;
+        self.sync()?;
+        let snapshot = self
+            .snapshots
+            .take(self.provider.as_ref(), name, &self.root);
+        self.cache.clear(&mut self.root);
+        snapshot
+    }
+
+    /// Clone the directory at `from` to `to`, files of the clone share data
+    /// with the source until modified.
+    pub fn clone_dir(&mut self, from: &[String], to: &[String]) -> Result<(), CloneError> {
+        dirindex::load_all(self.provider.as_ref(), &mut self.root)?;
+        let result = branch::clone_dir(&mut self.root, from, to);
+        if result.is_ok() {
+            self.snapshots.track_clones(&self.root);
+            quota::recount(&mut self.root);
+        }
+        self.sync()?;
+        self.cache.clear(&mut self.root);
+        result
+    }
+
+    /// Import the directory tree at `source` as the new directory `to`, its
+    /// files stored at once rather than written one by one.
+    pub fn import(&mut self, source: &Path, to: &[String]) -> Result<ImportStats, ImportError> {
+        let provider = self.provider.clone();
+        dirindex::load_all(provider.as_ref(), &mut self.root)?;
+        let (name, parent) = to.split_last().ok_or(ImportError::Exists)?;
+        match self.root.resolve(parent) {
+            Some(Entry::Dir(dir)) if dir.lookup(name).is_some() => return Err(ImportError::Exists),
+            Some(Entry::Dir(_)) => {}
+            _ => return Err(ImportError::NotFound),
+        }
+        let content = self.superblock.content_addressed();
+        let mut importer = Importer::new(
+            provider.as_ref(),
+            &mut self.allocator,
+            self.dedup.as_mut(),
+            content,
+        );
+        let dir = importer.import_dir(source, name.clone())?;
+        let stats = importer.finish()?;
+        if let Some(EntryMut::Dir(parent)) = self.root.resolve_mut(parent) {
+            parent.insert(Node::Dir(dir));
+        }
+        quota::recount(&mut self.root);
+        self.sync()?;
+        self.cache.clear(&mut self.root);
+        Ok(stats)
+    }
+
+    /// Export the entry at `path` and everything beneath to `sink`.
+    pub fn export(
+        &mut self,
+        path: &[String],
+        sink: &mut dyn Sink,
+    ) -> Result<ExportStats, ExportError> {
+        let provider = self.provider.clone();
+        dirindex::load_all(provider.as_ref(), &mut self.root)?;
+        let result = match self.root.resolve(path) {
+            Some(entry) => export::export(provider.as_ref(), entry, sink),
+            None => Err(ExportError::NotFound),
+        };
+        self.cache.release(&mut self.root);
+        result
+    }
+
+    /// Report on the chunk of `target`, or the chunks of the entry at its
+    /// path, along with what references them, dumping `blocks` of each.
+    pub fn inspect(
+        &mut self,
+        target: &InspectTarget,
+        blocks: Option<Range<usize>>,
+    ) -> Result<Vec<ChunkReport>, InspectError> {
+        let provider = self.provider.clone();
+        dirindex::load_all(provider.as_ref(), &mut self.root)?;
+        let mut references = References::new();
+        let superblock = meta::encode(&self.superblock).len();
+        for id in meta::chunk_ids(&ChunkId::root(self.superblock_id.clone()), superblock) {
+            references.add(id, Reference::Meta("superblock"));
+        }
+        for n in 0..self.journal.chunk_count() {
+            let id = ChunkId::<JournalChunk>::derive_n(self.journal.id(), n).into_id();
+            references.add(id, Reference::Meta("journal"));
+        }
+        references.add_tree(&self.root, "");
+        for snapshot in self.snapshots.list() {
+            let tree = dirindex::load_dir(provider.as_ref(), &snapshot.root_id)?;
+            references.add_tree(&tree, &format!("@{}", snapshot.name));
+        }
+        let ids = match target {
+            InspectTarget::Id(id) => vec![id.clone()],
+            InspectTarget::Prefix(prefix) => match provider.resolve_prefix(prefix)?.pop() {
+                Some(id) => vec![id],
+                None => return Err(InspectError::NoMatch(prefix.clone())),
+            },
+            InspectTarget::Path(path) => match self.root.resolve(path) {
+                Some(Entry::Dir(dir)) => dirindex::dir_chunk_ids(dir),
+                Some(Entry::File(file)) => (0..file.chunk_count())
+                    .map(|n| file.chunk_id(n).into_id())
+                    .collect(),
+                Some(Entry::TinyFile(file)) if file.chunk_blocks > 0 => {
+                    vec![file.chunk_id.clone()]
+                }
+                Some(Entry::TinyFile(_)) => Vec::new(),
+                None => return Err(InspectError::NotFound),
+            },
+        };
+        self.cache.release(&mut self.root);
+        let mut reports = Vec::new();
+        for id in ids.iter() {
+            reports.push(inspect::report(
+                provider.as_ref(),
+                id,
+                &references,
+                blocks.clone(),
+            )?);
+        }
+        Ok(reports)
+    }
+
+    /// Delete chunks referenced by neither the tree nor snapshots. Sharing
+    /// the provider, only chunks this filesystem referenced as of its last
+    /// collection are.
+    pub fn gc(&mut self) -> Result<GcStats, MetaError> {
+        self.last_gc = Instant::now();
+        if self.read_only() {
+            return Ok(GcStats::default());
+        }
+        let provider = self.provider.clone();
+        let referenced = self.referenced()?;
+        let deleted = match self.shares_provider()? {
+            true => gc::sweep_owned(provider.as_ref(), &self.superblock_id, &referenced)?,
+            false => gc::sweep(provider.as_ref(), &referenced)?,
+        };
+        for id in deleted.iter() {
+            self.allocator.retire(id);
+        }
+        Ok(GcStats {
+            referenced: referenced.len(),
+            deleted: deleted.len(),
+        })
+    }
+
+    /// Report chunks and slots of shared chunks referenced by nothing, and
+    /// reclaim them if `delete`.
+    pub fn leaks(&mut self, delete: bool) -> Result<LeakReport, MetaError> {
+        if self.read_only() || self.shares_provider()? {
+            return Ok(LeakReport::default());
+        }
+        let provider = self.provider.clone();
+        let referenced = self.referenced()?;
+        let report = gc::leaks(provider.as_ref(), &referenced, &self.allocator)?;
+        if delete {
+            gc::reclaim(provider.as_ref(), &report, &self.allocator)?;
+            for id in report.orphans.iter().chain(report.metadata.iter()) {
+                self.allocator.retire(id);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Whether the provider hosts tenants, whose chunks cannot be told from
+    /// garbage, or this is one.
+    fn shares_provider(&self) -> Result<bool, MetaError> {
+        tenant::shares(self.backend.as_ref(), &self.superblock_id)
+    }
+
+    /// Chunks referenced by the filesystem, what a migration of its live
+    /// set copies, their signatures along if signed.
+    pub fn live_chunks(&mut self) -> Result<HashSet<Id>, MetaError> {
+        self.referenced()
+    }
+
+    /// Chunks referenced by the tree, the snapshots or the metadata, with
+    /// the tree persisted and unloaded.
+    fn referenced(&mut self) -> Result<HashSet<Id>, MetaError> {
+        let provider = self.provider.clone();
+        dirindex::load_all(provider.as_ref(), &mut self.root)?;
+        self.rebuild_dedup()?;
+        // nothing only referenced by the journal may be deleted
+        self.sync()?;
+        let referenced = gc::referenced(
+            provider.as_ref(),
+            self.backend.as_ref(),
+            &self.superblock_id,
+            &self.superblock,
+            &self.root,
+            &self.journal,
+            &self.snapshots,
+        );
+        self.cache.release(&mut self.root);
+        referenced
+    }
+
+    /// Verify chunks of the tree and the snapshots against the hashes of
+    /// their files, as stored beneath the chunk cache, and repair those
+    /// corrupt from copies cached unless read-only.
+    pub fn scrub(&mut self) -> Result<ScrubStats, MetaError> {
+        self.last_scrub = Instant::now();
+        let provider = self.provider.clone();
+        dirindex::load_all(provider.as_ref(), &mut self.root)?;
+        if !self.read_only() {
+            // written back, so what is stored is verified
+            self.sync()?;
+        }
+        let mut trees = Vec::new();
+        for snapshot in self.snapshots.list() {
+            trees.push(dirindex::load_dir(provider.as_ref(), &snapshot.root_id)?);
+        }
+        let origin = match &self.cached {
+            Some(cached) => cached.inner().clone(),
+            None => provider,
+        };
+        let stats = scrub::scrub(
+            origin.as_ref(),
+            self.cached.as_deref(),
+            std::iter::once(&self.root).chain(trees.iter()),
+            !self.read_only(),
+        );
+        self.cache.release(&mut self.root);
+        let stats = stats?;
+        self.scrub_stats = Some(stats.clone());
+        Ok(stats)
+    }
+
+    /// Names of entries in the trash.
+    pub fn trash(&mut self) -> Result<Vec<String>, MetaError> {
+        let path = [TRASH_DIR.to_owned()];
+        self.cache
+            .load(&mut self.root, self.provider.as_ref(), &path)?;
+        Ok(match self.root.resolve(&path) {
+            Some(Entry::Dir(trash)) => trash.entries.keys().cloned().collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    /// Move the entry `name` out of the trash back to where it was deleted.
+    /// Returns the path it is restored to.
+    pub fn restore(&mut self, name: &str) -> Result<Vec<String>, TrashError> {
+        dirindex::load_all(self.provider.as_ref(), &mut self.root)?;
+        let result = trash::restore(&mut self.root, name);
+        // directories may have been created
+        quota::recount(&mut self.root);
+        self.sync()?;
+        self.cache.clear(&mut self.root);
+        result
+    }
+
+    /// Delete entries in the trash deleted longer than `age` ago, along with
+    /// their data. Returns the number of entries deleted.
+    pub fn purge_trash(&mut self, age: Duration) -> Result<usize, MetaError> {
+        let trash_path = [TRASH_DIR.to_owned()];
+        let provider = self.provider.clone();
+        self.cache
+            .load(&mut self.root, provider.as_ref(), &trash_path)?;
+        let before = SystemTime::now()
+            .checked_sub(age)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let expired = trash::expired(&self.root, before);
+        for name in expired.iter() {
+            let node = match self.root.resolve_mut(&trash_path) {
+                Some(EntryMut::Dir(trash)) => trash.remove(name),
+                _ => None,
+            };
+            if let Some(node) = node {
+                let path = vec![TRASH_DIR.to_owned(), name.clone()];
+                let usage = quota::usage_of(node.as_entry());
+                quota::account(&mut self.root, &path, usage, Usage::default());
+                self.journal
+                    .append(provider.as_ref(), Record::Remove { path })?;
+                self.release_node(&node)?;
+            }
+        }
+        Ok(expired.len())
+    }
+
+    /// Set the quota of the directory at `path`.
+    pub fn set_quota(&mut self, path: &[String], quota: Quota) -> Result<(), QuotaError> {
+        self.cache
+            .load(&mut self.root, self.provider.as_ref(), path)?;
+        match self.root.resolve_mut(path) {
+            Some(EntryMut::Dir(dir)) => dir.quota = quota,
+            _ => return Err(QuotaError::NotDir(path.join("/"))),
+        }
+        self.sync()?;
+        Ok(())
+    }
+
+    /// Usage and quota of the directory at `path`.
+    pub fn quota(&mut self, path: &[String]) -> Result<(Usage, Quota), QuotaError> {
+        self.cache
+            .load(&mut self.root, self.provider.as_ref(), path)?;
+        match self.root.resolve(path) {
+            Some(Entry::Dir(dir)) => Ok((dir.usage, dir.quota)),
+            _ => Err(QuotaError::NotDir(path.join("/"))),
+        }
+    }
+
+    /// Set the number of previous versions kept of files in the directory
+    /// at `path` when overwritten.
+    pub fn set_versions(&mut self, path: &[String], count: u32) -> Result<(), VersionError> {
+        self.cache
+            .load(&mut self.root, self.provider.as_ref(), path)?;
+        match self.root.resolve_mut(path) {
+            Some(EntryMut::Dir(dir)) => dir.versions = count,
+            _ => return Err(VersionError::NotDir(path.join("/"))),
+        }
+        self.sync()?;
+        Ok(())
+    }
+
+    /// Names of the versions kept of the file at `path`, oldest first.
+    pub fn versions(&mut self, path: &[String]) -> Result<Vec<String>, MetaError> {
+        let versions_path = [VERSIONS_DIR.to_owned()];
+        self.cache
+            .load(&mut self.root, self.provider.as_ref(), &versions_path)?;
+        Ok(versions::list(&self.root, path))
+    }
+
+    /// Replace the file at `path` with its version `name`, the current
+    /// content is kept as a version as the policy of its directory asks.
+    pub fn restore_version(&mut self, path: &[String], name: &str) -> Result<(), VersionError> {
+        let not_found = || VersionError::NotFound(name.to_owned());
+        match trash::parse_name(name) {
+            Some((_, of)) if of == path => {}
+            _ => return Err(not_found()),
+        }
+        let provider = self.provider.clone();
+        dirindex::load_all(provider.as_ref(), &mut self.root)?;
+        let mut node = match self.root.resolve(&[VERSIONS_DIR, name]) {
+            Some(Entry::File(file)) => Node::File(file.clone()),
+            Some(Entry::TinyFile(file)) => Node::TinyFile(file.clone()),
+            _ => return Err(not_found()),
+        };
+        if let Some(Entry::Dir(_)) = self.root.resolve(path) {
+            return Err(not_found());
+        }
+        self.keep_version(path)?;
+        let (dir, last) = match self.root.parent_mut(path) {
+            Some(found) => found,
+            None => return Err(VersionError::NotDir(path[..path.len() - 1].join("/"))),
+        };
+        node.set_name(last.to_owned());
+        self.snapshots.track_clone(&node);
+        let new = quota::usage_of(node.as_entry());
+        let replaced = dir.insert(node);
+        let old = match &replaced {
+            Some(node) => quota::usage_of(node.as_entry()),
+            None => Usage::default(),
+        };
+        quota::account(&mut self.root, path, old, new);
+        if let Some(node) = replaced {
+            self.release_node(&node).map_err(MetaError::from)?;
+        }
+        self.sync()?;
+        self.cache.clear(&mut self.root);
+        Ok(())
+    }
+
+    /// Time a FUSE request within `span`, logged if slow.
+    fn op(&self, span: tracing::Span) -> Op {
+        Op::new(span, self.options.slow_op)
+    }
+
+    pub(crate) fn run_command(&mut self, command: Command) -> Reply {
+        let errno = |errno| io::Error::from_raw_os_error(errno).to_string();
+        match command {
+            Command::Stats => {
+                let (usage, _) = self.quota(&[]).map_err(|e| e.to_string())?;
+                let runtime = self.runtime_stats();
+                Ok(vec![
+                    ("bytes", usage.bytes.into()),
+                    ("inodes", usage.inodes.into()),
+                    ("chunks", usage.chunks.into()),
+                    ("snapshots", (self.snapshots().len() as u64).into()),
+                    ("dirty_bytes", (runtime.dirty_bytes as u64).into()),
+                    ("oldest_dirty_ms", runtime.oldest_dirty_ms.into()),
+                ])
+            }
+            Command::Flush | Command::DropCaches => {
+                self.flush_all_writes().map_err(errno)?;
+                self.sync().map_err(|e| e.to_string())?;
+                if command == Command::DropCaches {
+                    self.cache.clear(&mut self.root);
+                    if let Some(cached) = &self.cached {
+                        cached.drop_clean();
+                    }
+                }
+                Ok(Vec::new())
+            }
+            Command::Pin(path) => {
+                let path: Vec<String> = path
+                    .split('/')
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+                self.cache
+                    .load(&mut self.root, self.provider.as_ref(), &path)
+                    .map_err(|e| e.to_string())?;
+                if self.root.resolve(&path).is_none() {
+                    return Err(errno(ENOENT));
+                }
+                let ino = self.assign(path);
+                self.set_pinned(0, ino, true).map_err(errno)?;
+                Ok(Vec::new())
+            }
+            Command::Snapshot(name) => {
+                self.flush_all_writes().map_err(errno)?;
+                self.snapshot(&name).map_err(|e| e.to_string())?;
+                Ok(Vec::new())
+            }
+            Command::Gc => {
+                let stats = self.gc().map_err(|e| e.to_string())?;
+                Ok(vec![
+                    ("referenced", (stats.referenced as u64).into()),
+                    ("deleted", (stats.deleted as u64).into()),
+                ])
+            }
+            Command::Heatmap => {
+                let files = self.heatmap().ok_or_else(|| HEATMAP_OFF.to_owned())?;
+                Ok(vec![
+                    ("files", (files.len() as u64).into()),
+                    ("heatmap", Value::Json(heatmap::to_json(&files))),
+                ])
+            }
+            Command::HeatmapWarmup(path) => {
+                // as many as warm-up prefetches
+                let ids = self
+                    .hottest_chunks(self.options.chunk_cache_bytes / CHUNK_SIZE)
+                    .ok_or_else(|| HEATMAP_OFF.to_owned())?;
+                warmup::store(Path::new(&path), &ids).map_err(|e| e.to_string())?;
+                Ok(vec![("chunks", (ids.len() as u64).into())])
+            }
+            Command::Barrier(point) => {
+                let point = control::barrier_point(point.unwrap_or(0));
+                let written = self.barrier(point).map_err(errno)?;
+                Ok(vec![("written", (written as u64).into())])
+            }
+        }
+    }
+
+    /// Count an access of `len` bytes at `offset` of inode `ino`, if counted.
+    fn count_access(&self, ino: u64, offset: u64, len: usize, write: bool) {
+        if let (Some(heatmap), Some(entry)) = (&self.heatmap, self.entry(ino)) {
+            heatmap.record(&Id::new(*entry.id()), offset, len, write);
+        }
+    }
+
+    /// Files accessed since mounted by entry id, with their paths if
+    /// loaded, most accessed first, `None` unless accesses are counted.
+    fn heatmap(&self) -> Option<Vec<(Id, Option<String>, FileHeat)>> {
+        let files = self.heatmap.as_ref()?.files();
+        let ids = files.iter().map(|(id, _)| id.clone()).collect();
+        let found = heatmap::find(&self.root, &ids);
+        let files = files
+            .into_iter()
+            .map(|(id, heat)| {
+                let path = found.get(&id).map(|(path, _)| path.clone());
+                (id, path, heat)
+            })
+            .collect();
+        Some(files)
+    }
+
+    /// Ids of at most `n` chunks of files accessed since mounted, most
+    /// accessed first, `None` unless accesses are counted.
+    fn hottest_chunks(&self, n: usize) -> Option<Vec<Id>> {
+        let files = self.heatmap.as_ref()?.files();
+        let ids = files.iter().map(|(id, _)| id.clone()).collect();
+        let found = heatmap::find(&self.root, &ids);
+        let mut chunks = Vec::new();
+        for (id, heat) in files {
+            // left out once removed or unloaded
+            match found.get(&id).map(|(_, entry)| *entry) {
+                Some(Entry::File(file)) => chunks.extend(
+                    heat.chunks
+                        .iter()
+                        .filter(|(index, _)| **index < file.chunk_count())
+                        .map(|(index, accesses)| (*accesses, file.chunk_id(*index).into_id())),
+                ),
+                Some(Entry::TinyFile(file)) if file.chunk_blocks > 0 => {
+                    chunks.push((heat.accesses(), file.chunk_id.clone()))
+                }
+                _ => {}
+            }
+        }
+        chunks.sort_by_key(|(accesses, _)| std::cmp::Reverse(*accesses));
+        // shared chunks of tiny files once
+        let mut seen = HashSet::new();
+        let ids = chunks
+            .into_iter()
+            .map(|(_, id)| id)
+            .filter(|id| seen.insert(id.clone()))
+            .take(n)
+            .collect();
+        Some(ids)
+    }
+
+    /// Run the admin command `ioctl` on inode `ino` on behalf of user `uid`.
+    /// Returns the data answered.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn admin(&mut self, uid: u32, ino: u64, ioctl: Ioctl) -> Result<Vec<u8>, c_int> {
+        let root_only = matches!(ioctl, Ioctl::Snapshot(_) | Ioctl::Evict);
+        if root_only && uid != 0 {
+            return Err(EPERM);
+        }
+        match ioctl {
+            Ioctl::Snapshot(name) => {
+                if self.read_only() {
+                    return Err(EROFS);
+                }
+                self.flush_all_writes()?;
+                self.snapshot(&name).map_err(|e| match e {
+                    SnapshotError::InvalidName => EINVAL,
+                    SnapshotError::Exists(_) => EEXIST,
+                    _ => EIO,
+                })?;
+            }
+            Ioctl::Pin(pinned) => self.set_pinned(uid, ino, pinned)?,
+            Ioctl::Flush => self.sync_inode(ino)?,
+            Ioctl::ChunkMap(first) => {
+                let ids = self.chunk_ids(ino)?;
+                return Ok(admin::chunk_map(&ids, first));
+            }
+            Ioctl::Evict => {
+                // dirty chunks are kept until written back
+                self.sync_inode(ino)?;
+                let ids = self.chunk_ids(ino)?;
+                for id in ids {
+                    self.provider.invalidate(&id);
+                }
+            }
+            Ioctl::Backlog => {
+                let file_bytes = self.dirty_bytes_of(ino)?;
+                let stats = self.runtime_stats();
+                return Ok(admin::backlog(
+                    file_bytes,
+                    stats.dirty_bytes,
+                    stats.oldest_dirty_ms,
+                ));
+            }
+            Ioctl::Barrier(point) => {
+                self.barrier(point)?;
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    pub fn snapshots(&self) -> &[Snapshot] {
+        self.snapshots.list()
+    }
+
+    /// Delete the snapshot `name`, along with data no longer referenced.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), SnapshotError> {
+        dirindex::load_all(self.provider.as_ref(), &mut self.root)?;
+        self.sync()?;
+        let result = self
+            .snapshots
+            .delete(
+                self.provider.as_ref(),
+                name,
+                &self.root,
+                &mut self.allocator,
+            )
+            .and_then(|released| Ok(self.unreference(&released)?));
+        self.cache.clear(&mut self.root);
+        result
+    }
+
+    /// Count the references of the dedup index again from the tree, fully
+    /// loaded, and the snapshots.
+    fn rebuild_dedup(&mut self) -> Result<(), MetaError> {
+        let dedup = match &mut self.dedup {
+            Some(dedup) => dedup,
+            None => return Ok(()),
+        };
+        let mut trees = Vec::new();
+        for snapshot in self.snapshots.list() {
+            trees.push(dirindex::load_dir(
+                self.provider.as_ref(),
+                &snapshot.root_id,
+            )?);
+        }
+        dedup.rebuild(std::iter::once(&self.root).chain(trees.iter()));
+        Ok(())
+    }
+
+    /// Free data of the removed entry `node`, see `Snapshots::release`.
+    fn release_node(&mut self, node: &Node) -> Result<(), ChunkProviderError> {
+        let released = self
+            .snapshots
+            .release(node, self.provider.as_ref(), &mut self.allocator)?;
+        self.unreference(&released)
+    }
+
+    /// Drop references of the dedup index to chunks of `hashes`, deleting
+    /// those referenced no longer.
+    fn unreference(&mut self, hashes: &[[u8; 32]]) -> Result<(), ChunkProviderError> {
+        match &mut self.dedup {
+            Some(dedup) => dedup.release(self.provider.as_ref(), hashes),
+            None => Ok(()),
+        }
+    }
+
+    /// Hashes of chunks of the file of inode `ino` stored at their hash,
+    /// by position, to find those dropped by a change.
+    fn shared_leaves(&self, ino: u64) -> Vec<[u8; 32]> {
+        match self.entry(ino) {
+            Some(Entry::File(file)) if self.dedup.is_some() => dedup::shared_leaves(file),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Drop references to chunks of `before`, see `shared_leaves`, no longer
+    /// held by the file of inode `ino`.
+    fn drop_leaves(&mut self, ino: u64, before: &[[u8; 32]]) -> Result<(), c_int> {
+        let file = match self.entry(ino) {
+            Some(Entry::File(file)) => Some(file),
+            _ => None,
+        };
+        let dropped = dedup::dropped(before, file);
+        self.unreference(&dropped).map_err(|e| e.errno())
+    }
+
+    /// Log the current state of the entry at `path` to the journal, and
+    /// persist the tree if the journal is long enough.
+    fn log_entry(&mut self, path: &[String]) -> Result<(), c_int> {
+        let node = self.root.resolve(path).map(|entry| match entry {
+            Entry::Dir(dir) => {
+                // entries are logged on their own
+                let mut shallow = DirMeta::new(dir.name.clone(), dir.attrs.clone());
+                shallow.id = dir.id.clone();
+                Node::Dir(shallow)
+            }
+            Entry::File(file) => Node::File(file.clone()),
+            Entry::TinyFile(file) => Node::TinyFile(file.clone()),
+        });
+        let path = path.to_vec();
+        self.log(match node {
+            Some(node) => Record::Put { path, node },
+            None => Record::Remove { path },
+        })
+    }
+
+    /// Append `record` to the journal, and persist the tree if the journal
+    /// is long enough.
+    fn log(&mut self, record: Record) -> Result<(), c_int> {
+        self.journal
+            .append(self.provider.as_ref(), record)
+            .map_err(|e| e.errno())?;
+        if self.journal.len() >= self.options.journal_records {
+            if let Some(ttl) = self.options.trash_ttl {
+                self.purge_trash(ttl).map_err(|e| e.errno())?;
+            }
+            self.sync().map_err(|e| e.errno())?;
+        }
+        if let Some(interval) = self.options.gc_interval {
+            if self.last_gc.elapsed() >= interval {
+                self.gc().map_err(|e| e.errno())?;
+            }
+        }
+        if let Some(interval) = self.options.scrub_interval {
+            if self.last_scrub.elapsed() >= interval {
+                self.scrub().map_err(|e| e.errno())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the directories along the path of inode `ino` into memory.
+    fn load(&mut self, ino: u64) -> Result<(), c_int> {
+        let path = self.inodes.path(ino).ok_or(ENOENT)?;
+        self.cache
+            .load(&mut self.root, self.provider.as_ref(), path)
+            .map_err(|e| {
+                if self.options.verify {
+                    tracing::error!("failed to verify metadata along {:?}: {}", path, e);
+                }
+                e.errno()
+            })
+    }
+
+    /// Inode number and generation of the child `name` of `parent`, counted
+    /// as a kernel lookup.
+    fn lookup_child(&mut self, parent: u64, name: &str) -> Option<(u64, u64)> {
+        let mut path = self.inodes.path(parent)?.to_vec();
+        path.push(name.to_owned());
+        Some(self.lookup_path(path))
+    }
+
+    /// Inode number and generation of the entry at `path` derived from its
+    /// id when re-exported over NFS.
+    fn handle(&self, path: &[String]) -> Option<(u64, u64)> {
+        match self.options.nfs_export {
+            true => self.root.resolve(path).map(|entry| nfs::handle(entry.id())),
+            false => None,
+        }
+    }
+
+    /// Inode number of `path`, not counted as a kernel lookup.
+    fn assign(&mut self, path: Vec<String>) -> u64 {
+        let handle = self.handle(&path);
+        self.inodes.insert(path, handle.map(|(ino, _)| ino))
+    }
+
+    /// Inode number and generation of `path`, counted as a kernel lookup.
+    fn lookup_path(&mut self, path: Vec<String>) -> (u64, u64) {
+        let handle = self.handle(&path);
+        let ino = self.inodes.lookup(path, handle.map(|(ino, _)| ino));
+        // a path given another inode before keeps it, with no generation
+        let generation = handle.filter(|(stable, _)| *stable == ino);
+        (ino, generation.map_or(0, |(_, generation)| generation))
+    }
+
+    /// Look up "." or ".." of `ino`, which the kernel may have forgotten
+    /// since it gave its handle to an NFS client when re-exported.
+    fn lookup_handle(&mut self, ino: u64, name: &str) -> Result<(u64, u64), c_int> {
+        let mut path = match self.inodes.path(ino) {
+            Some(path) => path.to_vec(),
+            None => self.handle_path(ino)?,
+        };
+        if name == ".." {
+            path.pop();
+        }
+        self.cache
+            .load(&mut self.root, self.provider.as_ref(), &path)
+            .map_err(|e| e.errno())?;
+        if self.root.resolve(&path).is_none() {
+            return Err(ESTALE);
+        }
+        Ok(self.lookup_path(path))
+    }
+
+    /// Path of the entry whose inode from its file handle is `ino`, released
+    /// by the kernel. The tree is walked once for handles given by a former
+    /// mount, those given since are remembered by `inodes`.
+    fn handle_path(&mut self, ino: u64) -> Result<Vec<String>, c_int> {
+        if let Some(path) = self.inodes.handle_path(ino).map(<[String]>::to_vec) {
+            self.cache
+                .load(&mut self.root, self.provider.as_ref(), &path)
+                .map_err(|e| e.errno())?;
+            if self.handle(&path).map(|(stable, _)| stable) == Some(ino) {
+                return Ok(path);
+            }
+        }
+        if self.handles_indexed {
+            return Err(ESTALE);
+        }
+        dirindex::load_all(self.provider.as_ref(), &mut self.root).map_err(|e| e.errno())?;
+        for (stable, path) in nfs::index(&self.root) {
+            self.inodes.remember(stable, path);
+        }
+        self.cache.release(&mut self.root);
+        self.handles_indexed = true;
+        self.handle_path(ino)
+    }
+
+    /// Move the entry of inode `ino` out of data shared with snapshots,
+    /// before it is modified.
+    fn unshare(&mut self, ino: u64) -> Result<(), c_int> {
+        let path = self.inodes.path(ino).ok_or(ENOENT)?;
+        let before = match self.root.resolve(path) {
+            Some(Entry::File(file)) => Some(file.id.clone()),
+            _ => None,
+        };
+        let entry = self.root.resolve_mut(path).ok_or(ENOENT)?;
+        self.snapshots
+            .unshare(entry, self.provider.as_ref(), &mut self.allocator)
+            .map_err(|e| tiny_errno(&e))?;
+        // chunks stored at their hash are shared with the copy
+        if let (Some(dedup), Some(Entry::File(file))) = (&mut self.dedup, self.root.resolve(path)) {
+            if Some(&file.id) != before.as_ref() {
+                for hash in dedup::held(file) {
+                    dedup.add(&hash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the quotas of the directories containing the file of inode `ino`
+    /// for it to grow to `size`. Returns its current usage.
+    fn reserve(&mut self, ino: u64, size: u64) -> Result<Usage, c_int> {
+        let path = self.inodes.path(ino).ok_or(ENOENT)?;
+        let old = quota::usage_of(self.root.resolve(path).ok_or(ENOENT)?);
+        if !quota::check(&mut self.root, path, size.saturating_sub(old.bytes), 0) {
+            return Err(EDQUOT);
+        }
+        Ok(old)
+    }
+
+    /// Roll the change of usage of the file of inode `ino` from `old` up to
+    /// the directories containing it.
+    fn account_change(&mut self, ino: u64, old: Usage) {
+        if let Some(path) = self.inodes.path(ino) {
+            if let Some(entry) = self.root.resolve(path) {
+                let new = quota::usage_of(entry);
+                quota::account(&mut self.root, path, old, new);
+            }
+        }
+    }
+
+    /// Keep the current content of the file at `path` as a version if its
+    /// directory asks for it, before it is overwritten, and drop the oldest
+    /// versions beyond. Returns the paths changed, to be logged.
+    fn keep_version(&mut self, path: &[String]) -> Result<Vec<Vec<String>>, VersionError> {
+        let count = match path.split_last() {
+            Some((_, parent)) if path[0] != VERSIONS_DIR && path[0] != TRASH_DIR => {
+                match self.root.resolve(parent) {
+                    Some(Entry::Dir(dir)) => dir.versions as usize,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+        let node = match self.root.resolve(path) {
+            Some(Entry::File(file)) if count > 0 => Node::File(file.clone()),
+            Some(Entry::TinyFile(file)) if count > 0 => Node::TinyFile(file.clone()),
+            _ => return Ok(Vec::new()),
+        };
+        let provider = self.provider.clone();
+        let versions_path = vec![VERSIONS_DIR.to_owned()];
+        self.cache
+            .load(&mut self.root, provider.as_ref(), &versions_path)?;
+        let mut changed = Vec::new();
+        if self.root.lookup(VERSIONS_DIR).is_none() {
+            let usage = Usage {
+                inodes: 1,
+                ..Usage::default()
+            };
+            quota::account(&mut self.root, &versions_path, Usage::default(), usage);
+            changed.push(versions_path);
+        }
+        let kept = match versions::keep(&mut self.root, path, &node, SystemTime::now())? {
+            Some(kept) => kept,
+            None => return Ok(changed),
+        };
+        let usage = quota::usage_of(node.as_entry());
+        quota::account(&mut self.root, &kept, Usage::default(), usage);
+        self.snapshots.track_clone(&node);
+        changed.push(kept);
+        for (name, node) in versions::prune(&mut self.root, path, count) {
+            let pruned = vec![VERSIONS_DIR.to_owned(), name];
+            let usage = quota::usage_of(node.as_entry());
+            quota::account(&mut self.root, &pruned, usage, Usage::default());
+            self.release_node(&node).map_err(MetaError::from)?;
+            changed.push(pruned);
+        }
+        Ok(changed)
+    }
+
+    /// Keep a version of the file of inode `ino` before it is overwritten,
+    /// see `keep_version`.
+    fn version(&mut self, ino: u64) -> Result<(), c_int> {
+        let path = self.inodes.path(ino).ok_or(ENOENT)?.to_vec();
+        for changed in self.keep_version(&path).map_err(|e| e.errno())? {
+            self.log_entry(&changed)?;
+        }
+        // loading the versions may have evicted its directory
+        self.load(ino)
+    }
+
+    /// Remove the entry `name` from the directory of inode `parent`, a file
+    /// unless `rmdir`, or an empty directory. Files go to the trash if enabled.
+    fn remove_entry(&mut self, parent: u64, name: &str, rmdir: bool) -> Result<(), c_int> {
+        let mut path = self.inodes.path(parent).ok_or(ENOENT)?.to_vec();
+        path.push(name.to_owned());
+        let provider = self.provider.clone();
+        self.cache
+            .load(&mut self.root, provider.as_ref(), &path)
+            .map_err(|e| e.errno())?;
+        if self.protected(&path) && self.root.resolve(&path).is_some() {
+            return Err(EPERM);
+        }
+        let (dir, name) = self.root.parent_mut(&path).ok_or(ENOENT)?;
+        match dir.lookup(name) {
+            Some(Entry::Dir(_)) if !rmdir => return Err(EISDIR),
+            Some(Entry::Dir(sub)) if !sub.entries.is_empty() => return Err(ENOTEMPTY),
+            Some(Entry::Dir(_)) => {}
+            Some(_) if rmdir => return Err(ENOTDIR),
+            Some(_) => {}
+            None => return Err(ENOENT),
+        }
+        let mut node = dir.remove(name).unwrap();
+        let usage = quota::usage_of(node.as_entry());
+        quota::account(&mut self.root, &path, usage, Usage::default());
+        if rmdir {
+            self.cache.invalidate(&mut self.root, &path);
+        }
+        self.log_entry(&path)?;
+
+        if self.options.trash_ttl.is_some() && !rmdir && path[0] != TRASH_DIR {
+            let trash_path = [TRASH_DIR.to_owned()];
+            self.cache
+                .load(&mut self.root, provider.as_ref(), &trash_path)
+                .map_err(|e| e.errno())?;
+            if self.root.lookup(TRASH_DIR).is_none() {
+                let usage = Usage {
+                    inodes: 1,
+                    ..Usage::default()
+                };
+                quota::account(&mut self.root, &trash_path, Usage::default(), usage);
+            }
+            let (trashed, replaced) = trash::put(&mut self.root, &path, node, SystemTime::now())
+                .map_err(|e| e.errno())?;
+            let replaced_usage = match &replaced {
+                Some(node) => quota::usage_of(node.as_entry()),
+                None => Usage::default(),
+            };
+            quota::account(&mut self.root, &trashed, replaced_usage, usage);
+            self.log_entry(&trash_path)?;
+            self.log_entry(&trashed)?;
+            node = match replaced {
+                Some(node) => node,
+                None => return Ok(()),
+            };
+        }
+        self.release_node(&node).map_err(|e| e.errno())
+    }
+
+    /// Move the entry `name` of the directory of inode `parent` to `newname`
+    /// of the directory of inode `newparent`, atomically replacing an entry
+    /// there, or swapping with it with `RENAME_EXCHANGE`. Either is a single
+    /// journal record, so no crash leaves the destination missing.
+    fn rename_entry(
+        &mut self,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        let mut from = self.inodes.path(parent).ok_or(ENOENT)?.to_vec();
+        from.push(name.to_owned());
+        #[cfg(all(feature = "macos", target_os = "macos"))]
+        if macos::refused(&self.options.mac, newname) {
+            return Err(EPERM);
+        }
+        let mut to = self.inodes.path(newparent).ok_or(ENOENT)?.to_vec();
+        to.push(newname.to_owned());
+        let exchange = flags & RENAME_EXCHANGE != 0;
+        // into itself
+        if to.starts_with(&from) && from != to || exchange && from.starts_with(&to) {
+            return Err(EINVAL);
+        }
+        self.load_both(&to, &from)?;
+        let (moved_dir, target) = match (self.root.resolve(&from), self.root.resolve(&to)) {
+            (None, _) => return Err(ENOENT),
+            (Some(_), _) if from == to => return Ok(()),
+            (Some(moved), target) => (moved.is_dir(), target),
+        };
+        if self.protected(&from) || self.protected(&to) {
+            return Err(EPERM);
+        }
+        match target {
+            None if exchange => return Err(ENOENT),
+            Some(_) if flags & RENAME_NOREPLACE != 0 => return Err(EEXIST),
+            Some(_) if exchange => {}
+            Some(Entry::Dir(_)) if !moved_dir => return Err(EISDIR),
+            Some(Entry::Dir(dir)) if !dir.entries.is_empty() => return Err(ENOTEMPTY),
+            Some(_) if moved_dir => return Err(ENOTDIR),
+            _ => {}
+        }
+        let replaces_file = !exchange && matches!(target, Some(ref entry) if !entry.is_dir());
+        if replaces_file {
+            // editors save files by renaming over them
+            for changed in self.keep_version(&to).map_err(|e| e.errno())? {
+                self.log_entry(&changed)?;
+            }
+            self.load_both(&to, &from)?;
+        }
+        self.unload_moved(&from)?;
+        self.unload_moved(&to)?;
+        let usage_of = |root: &DirMeta, path: &[String]| match root.resolve(path) {
+            Some(entry) => quota::usage_of(entry),
+            None => Usage::default(),
+        };
+        let (moved, replaced) = (usage_of(&self.root, &from), usage_of(&self.root, &to));
+
+        if exchange {
+            let id = *self.root.resolve(&from).ok_or(ENOENT)?.id();
+            quota::account(&mut self.root, &from, moved, replaced);
+            quota::account(&mut self.root, &to, replaced, moved);
+            let record = Record::Exchange {
+                a: to.clone(),
+                b: from.clone(),
+                id,
+            };
+            record.clone().apply(&mut self.root);
+            self.inodes.exchange(&from, &to);
+            return self.log(record);
+        }
+
+        quota::account(&mut self.root, &from, moved, Usage::default());
+        quota::account(&mut self.root, &to, replaced, Usage::default());
+        if !quota::check(&mut self.root, &to, moved.bytes, moved.inodes) {
+            quota::account(&mut self.root, &from, Usage::default(), moved);
+            quota::account(&mut self.root, &to, Usage::default(), replaced);
+            return Err(EDQUOT);
+        }
+        quota::account(&mut self.root, &to, Usage::default(), moved);
+        let old = match self.root.parent_mut(&to) {
+            Some((dir, name)) => dir.remove(name),
+            None => return Err(ENOENT),
+        };
+        let record = Record::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        };
+        record.clone().apply(&mut self.root);
+        self.inodes.rename(&from, &to);
+        self.log(record)?;
+        match old {
+            // released once the rename is logged
+            Some(node) => self.release_node(&node).map_err(|e| e.errno()),
+            None => Ok(()),
+        }
+    }
+
+    /// Load the directories along both `a` and `b`.
+    fn load_both(&mut self, a: &[String], b: &[String]) -> Result<(), c_int> {
+        let provider = self.provider.clone();
+        for path in [a, b].iter() {
+            self.cache
+                .load(&mut self.root, provider.as_ref(), path)
+                .map_err(|e| e.errno())?;
+        }
+        Ok(())
+    }
+
+    /// Write back and unload the directory at `path` before it moves, as
+    /// loaded directories are cached by path.
+    fn unload_moved(&mut self, path: &[String]) -> Result<(), c_int> {
+        if let Some(EntryMut::Dir(dir)) = self.root.resolve_mut(path) {
+            dirindex::store_dir(self.provider.as_ref(), dir).map_err(|e| e.errno())?;
+            self.cache.invalidate(&mut self.root, path);
+        }
+        Ok(())
+    }
+
+    /// Whether the entry at `path` may not be created, removed or replaced,
+    /// as flagged itself or its directory.
+    fn protected(&self, path: &[String]) -> bool {
+        let parent = match path.split_last() {
+            Some((_, parent)) => parent,
+            None => return true,
+        };
+        // shadowed by the stats directory
+        if path.first().map(String::as_str) == Some(STATS_DIR) {
+            return true;
+        }
+        let entry = self.root.resolve(path);
+        let flagged = entry.map_or(false, |entry| {
+            entry.attrs().immutable() || entry.attrs().append_only()
+        });
+        flagged
+            || match self.root.resolve(parent) {
+                Some(dir) => {
+                    dir.attrs().immutable() || entry.is_some() && dir.attrs().append_only()
+                }
+                None => false,
+            }
+    }
+
+    /// Check the flags of the file of inode `ino` allow writing at `offset`.
+    fn check_write(&self, ino: u64, offset: u64) -> Result<(), c_int> {
+        let attrs = self.entry(ino).ok_or(ENOENT)?.attrs();
+        if attrs.immutable() || attrs.append_only() && offset != attrs.size {
+            return Err(EPERM);
+        }
+        Ok(())
+    }
+
+    /// Set the chattr-style flags of the entry of inode `ino` to those in
+    /// `data`. Only root may, as `CAP_LINUX_IMMUTABLE` is not known here.
+    fn set_flags(&mut self, uid: u32, ino: u64, data: &[u8]) -> Result<(), c_int> {
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        if uid != 0 {
+            return Err(EPERM);
+        }
+        let flags = match data.len() {
+            8 => u64::from_ne_bytes(data.try_into().unwrap()) as u32,
+            4 => u32::from_ne_bytes(data.try_into().unwrap()),
+            _ => return Err(EINVAL),
+        };
+        let path = self.inodes.path(ino).ok_or(ENOENT)?.to_vec();
+        let mut entry = self.root.resolve_mut(&path).ok_or(ENOENT)?;
+        let attrs = entry.attrs_mut();
+        // other flags are not supported
+        attrs.flags = flags & CHATTR_FLAGS | attrs.flags & !CHATTR_FLAGS;
+        attrs.ctime = SystemTime::now();
+        self.log_entry(&path)
+    }
+
+    /// Pin the entry of inode `ino` in the chunk cache, or unpin it.
+    /// Only the owner of a file or root may, root alone a directory, whose
+    /// files may be of others. Pinned chunks are held to half of the cache.
+    fn set_pinned(&mut self, uid: u32, ino: u64, pinned: bool) -> Result<(), c_int> {
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        let path = self.inodes.path(ino).ok_or(ENOENT)?.to_vec();
+        match self.root.resolve(&path).ok_or(ENOENT)? {
+            _ if uid == 0 => {}
+            Entry::Dir(_) => return Err(EPERM),
+            entry if entry.attrs().uid != uid => return Err(EPERM),
+            _ => {}
+        }
+
+        // chunks beneath, and those pinned on their own
+        let (chunks, still) = match self.root.resolve(&path) {
+            Some(Entry::Dir(dir)) => {
+                // loaded aside, leaving the loaded directories as they are
+                let mut dir = dir.clone();
+                dirindex::load_all(self.provider.as_ref(), &mut dir).map_err(|e| e.errno())?;
+                (
+                    pin::chunk_ids(Entry::Dir(&dir)),
+                    pin::pinned_chunks(&dir, false),
+                )
+            }
+            Some(entry) => (pin::chunk_ids(entry), Vec::new()),
+            None => return Err(ENOENT),
+        };
+        let above = (0..path.len()).any(|n| {
+            let entry = self.root.resolve(&path[..n]);
+            entry.map_or(false, |entry| entry.attrs().pinned())
+        });
+        if pinned || above {
+            self.pin_chunks(&chunks, true)?;
+        } else {
+            self.pin_chunks(&chunks, false)?;
+            self.pin_chunks(&still, true)?;
+        }
+
+        let mut entry = self.root.resolve_mut(&path).ok_or(ENOENT)?;
+        let attrs = entry.attrs_mut();
+        if pinned {
+            attrs.flags |= PINNED_FL;
+        } else {
+            attrs.flags &= !PINNED_FL;
+        }
+        attrs.ctime = SystemTime::now();
+        self.log_entry(&path)
+    }
+
+    /// Set the storage class of inode `ino` on behalf of user `uid`, or
+    /// clear it to inherit the class of the directory above. Chunks of a
+    /// file are hinted to the provider along with the class, to be stored
+    /// in its tier from their next save on.
+    pub(crate) fn set_storage_class(
+        &mut self,
+        uid: u32,
+        ino: u64,
+        class: Option<StorageClass>,
+    ) -> Result<(), c_int> {
+        self.update_attrs(uid, ino, |attrs| attrs.set_storage_class(class))?;
+        self.hint_stored(ino);
+        Ok(())
+    }
+
+    /// Set the compression of inode `ino` on behalf of user `uid`, or clear
+    /// it to inherit the compression of the directory above, or else of the
+    /// rule matching its extension. Chunks of a file are hinted to the
+    /// provider along with the compression, to be compressed from their next
+    /// save on.
+    pub(crate) fn set_compression(
+        &mut self,
+        uid: u32,
+        ino: u64,
+        compression: Option<Compression>,
+    ) -> Result<(), c_int> {
+        self.update_attrs(uid, ino, |attrs| attrs.set_compression(compression))?;
+        self.hint_stored(ino);
+        Ok(())
+    }
+
+    /// Hint the provider with the storage class and compression of the
+    /// chunks of the file of inode `ino`, as written chunks are.
+    fn hint_stored(&self, ino: u64) {
+        let path = match self.inodes.path(ino) {
+            Some(path) => path,
+            None => return,
+        };
+        let file = match self.root.resolve(path) {
+            Some(Entry::File(file)) => file,
+            _ => return,
+        };
+        let class = tier::resolve(&self.root, path);
+        let compression = compression::resolve(&self.root, path, &self.compression_rules.read());
+        for n in 0..file.chunk_count() {
+            let id = file.chunk_id(n).into_id();
+            if let Some(class) = class {
+                self.provider.hint_class(&id, class);
+            }
+            if let Some(compression) = compression {
+                self.provider.hint_compression(&id, compression);
+            }
+        }
+    }
+
+    /// Apply `update` to the attributes of inode `ino` on behalf of its
+    /// owner or root `uid`.
+    fn update_attrs(
+        &mut self,
+        uid: u32,
+        ino: u64,
+        update: impl FnOnce(&mut Attrs),
+    ) -> Result<(), c_int> {
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        let path = self.inodes.path(ino).ok_or(ENOENT)?.to_vec();
+        let mut entry = self.root.resolve_mut(&path).ok_or(ENOENT)?;
+        let attrs = entry.attrs_mut();
+        if uid != 0 && uid != attrs.uid {
+            return Err(EPERM);
+        }
+        update(attrs);
+        attrs.ctime = SystemTime::now();
+        self.log_entry(&path)
+    }
+
+    /// Keep chunks `ids` in the chunk cache and prefetch them, or stop
+    /// keeping them if not `pinned`. Fails with `ENOSPC` if they would take
+    /// more than half of the cache.
+    fn pin_chunks(&self, ids: &[Id], pinned: bool) -> Result<(), c_int> {
+        if let Some(cached) = &self.cached {
+            if !cached.pin(ids, pinned) {
+                return Err(ENOSPC);
+            }
+        }
+        if pinned {
+            for id in ids {
+                // pins share a turn
+                self.prefetch(id.clone(), 0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the entry of inode `ino`.
+    fn entry(&self, ino: u64) -> Option<Entry> {
+        self.root.resolve(self.inodes.path(ino)?)
+    }
+
+    /// Watch chunks backing `len` bytes from `offset` of `file`,
+    /// if changes of other clients are polled.
+    fn watch_chunks(&self, ino: u64, file: &FileMeta, offset: u64, len: usize) {
+        if self.options.invalidate_interval.is_none() {
+            return;
+        }
+        for n in FileMeta::chunk_span(offset, len) {
+            let id = file.chunk_id(n).into_id();
+            if let Ok(generation) = self.provider.generation(&id) {
+                self.watcher
+                    .watch(id.clone(), generation, Target::Inode(ino));
+                // we may have just modified it
+                self.watcher.update(&id, generation);
+            }
+        }
+    }
+
+    /// Write `data` at `offset` of the file of inode `ino`, through the
+    /// handle `fh` if any.
+    fn write_data(
+        &mut self,
+        fh: Option<u64>,
+        ino: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, c_int> {
+        let end = offset + data.len() as u64;
+        self.load(ino)?;
+        self.upgrade_lazy(ino, offset, data.len())?;
+        self.check_write(ino, offset)?;
+        self.count_access(ino, offset, data.len(), true);
+        // a version is kept when existing data is overwritten, once per
+        // handle, or per write without one
+        let overwrites = match self.entry(ino) {
+            Some(entry) => !data.is_empty() && offset < entry.attrs().size,
+            None => false,
+        };
+        if overwrites && fh.map_or(true, |fh| !self.versioned.contains(&fh)) {
+            self.version(ino)?;
+            self.versioned.extend(fh);
+        }
+        self.unshare(ino)?;
+        let leaves = self.shared_leaves(ino);
+        let old = self.reserve(ino, end)?;
+        let path = match self.inodes.path(ino) {
+            Some(path) if path.is_empty() => return Err(EISDIR),
+            Some(path) => path,
+            None => return Err(ENOENT),
+        };
+        let class = tier::resolve(&self.root, path);
+        let compression = compression::resolve(&self.root, path, &self.compression_rules.read());
+        let (dir, name) = self.root.parent_mut(path).ok_or(ENOENT)?;
+        let shared = &self.provider;
+        let provider = shared.as_ref();
+        let allocator = &mut self.allocator;
+        let streams = &mut self.streams;
+        let content = self.superblock.content_addressed();
+        // before the chunks are saved
+        let hint = |file: &FileMeta| {
+            for n in FileMeta::chunk_span(offset, data.len()) {
+                let id = file.chunk_id(n).into_id();
+                if let Some(class) = class {
+                    provider.hint_class(&id, class);
+                }
+                if let Some(compression) = compression {
+                    provider.hint_compression(&id, compression);
+                }
+            }
+        };
+        let result = match dir.lookup_mut(name) {
+            Some(EntryMut::File(file)) => {
+                hint(file);
+                match stream_write(streams, shared, ino, file, offset, data) {
+                    Some(result) => result.map_err(From::from),
+                    None => file.write(provider, offset, data).map_err(From::from),
+                }
+            }
+            Some(EntryMut::TinyFile(file)) => match file.write(provider, allocator, offset, data) {
+                // outgrows shared chunks
+                Err(TinyFileError::TooLarge(_)) => dir
+                    .promote(name, provider, allocator, content)
+                    .and_then(|file| {
+                        hint(file);
+                        Ok(file.write(provider, offset, data)?)
+                    }),
+                result => result,
+            },
+            Some(EntryMut::Dir(_)) => return Err(EISDIR),
+            None => return Err(ENOENT),
+        };
+        let path = path.to_vec();
+        self.account_change(ino, old);
+        self.drop_leaves(ino, &leaves)?;
+        let n = result.map_err(|e| tiny_errno(&e))?;
+        self.log_entry(&path)?;
+        if let Some(Entry::File(file)) = self.entry(ino) {
+            self.watch_chunks(ino, file, offset, n);
+        }
+        // one event for all the writes until flushed
+        if self.events.has_subscribers() {
+            self.modified.insert(ino);
+        }
+        Ok(n)
+    }
+
+    /// Hash of the content of the file of inode `ino`, see `CHECKSUM_XATTR`,
+    /// hashed again only once the file changed.
+    fn checksum(&mut self, ino: u64) -> Result<Option<String>, c_int> {
+        let entry = self.entry(ino).ok_or(ENOENT)?;
+        let key = match entry {
+            Entry::File(file) => Some((merkle::root_hex(file), file.attrs.size)),
+            _ => None,
+        };
+        if let (Some((root, size)), Some((cached, cached_root, cached_size, checksum))) =
+            (&key, &self.checksum)
+        {
+            if (*cached, cached_root, cached_size) == (ino, root, size) {
+                return Ok(Some(checksum.clone()));
+            }
+        }
+        let checksum = layout::checksum(entry, self.provider.as_ref()).map_err(|e| e.errno())?;
+        if let (Some((root, size)), Some(checksum)) = (key, &checksum) {
+            self.checksum = Some((ino, root, size, checksum.clone()));
+        }
+        Ok(checksum)
+    }
+
+    /// Hash chunks of the file of inode `ino` written since last hashed, so
+    /// they are verified when read again.
+    fn hash_chunks(&mut self, ino: u64) -> Result<(), c_int> {
+        if self.read_only() {
+            return Ok(());
+        }
+        self.flush_writes(ino)?;
+        let path = match self.inodes.path(ino) {
+            Some(path) if !path.is_empty() => path.to_vec(),
+            _ => return Ok(()),
+        };
+        let provider = self.provider.as_ref();
+        let (result, changed, staged) = match self.root.parent_mut(&path) {
+            Some((dir, name)) => match dir.lookup_mut(name) {
+                Some(EntryMut::File(file)) => {
+                    let before = file.hashes.clone();
+                    let result = merkle::update(file, provider, self.dedup.as_mut());
+                    // leaves hashed before a failure are logged all the same
+                    let changed = file.hashes != before;
+                    (result, changed, merkle::staged(&before, file))
+                }
+                _ => (Ok(false), false, Vec::new()),
+            },
+            None => (Ok(false), false, Vec::new()),
+        };
+        if changed {
+            self.log_entry(&path)?;
+        }
+        if !staged.is_empty() {
+            // the leaves saved before their staging chunks are deleted
+            self.journal
+                .commit(self.provider.as_ref())
+                .map_err(|e| e.errno())?;
+            for id in staged {
+                // left to the garbage collection if failing
+                let _ = self.provider.delete_chunk(&id);
+            }
+        }
+        result.map(|_| ()).map_err(|e| e.errno())
+    }
+
+    /// Buffer a small write through `fh`, merged with the writes before it
+    /// if contiguous, written once the buffer of `capacity` bytes is full.
+    /// Failures of buffered writes are reported when flushed.
+    pub(crate) fn buffer_write(
+        &mut self,
+        fh: u64,
+        ino: u64,
+        offset: u64,
+        data: &[u8],
+        capacity: usize,
+    ) -> Result<(), c_int> {
+        self.load(ino)?;
+        let attrs = self.entry(ino).ok_or(ENOENT)?.attrs();
+        if attrs.immutable() || attrs.append_only() {
+            // checked against the size written so far
+            self.flush_writes(ino)?;
+            return self.write_data(Some(fh), ino, offset, data).map(|_| ());
+        }
+        // writes through other handles are not reordered
+        let others: Vec<u64> = self
+            .buffers
+            .iter()
+            .filter(|(other, buffer)| **other != fh && buffer.ino == ino)
+            .map(|(other, _)| *other)
+            .collect();
+        for other in others {
+            self.flush_buffer(other)?;
+        }
+        let buffer = self
+            .buffers
+            .entry(fh)
+            .or_insert_with(|| WriteBuffer::new(ino, capacity));
+        if !buffer.append(offset, data) {
+            self.flush_buffer(fh)?;
+            self.buffers.get_mut(&fh).unwrap().append(offset, data);
+        }
+        if self.buffers[&fh].is_full() {
+            self.flush_buffer(fh)?;
+        }
+        Ok(())
+    }
+
+    /// Write the writes buffered through `fh`.
+    fn flush_buffer(&mut self, fh: u64) -> Result<(), c_int> {
+        let (ino, pending) = match self.buffers.get_mut(&fh) {
+            Some(buffer) => (buffer.ino, buffer.take()),
+            None => return Ok(()),
+        };
+        match pending {
+            Some((offset, data)) => self.write_data(Some(fh), ino, offset, &data).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the writes buffered to the file of inode `ino`, and wait for the
+    /// chunk streamed to it, before it is read, or its attributes or entry
+    /// are accessed.
+    fn flush_writes(&mut self, ino: u64) -> Result<(), c_int> {
+        self.flush_buffers(ino)?;
+        match self.streams.remove(&ino) {
+            Some((_, mut stream)) => stream.finish().map_err(|e| e.errno()),
+            None => Ok(()),
+        }
+    }
+
+    /// Emit the `Modify` event held back for the writes to inode `ino`
+    /// since flushed.
+    fn emit_modified(&mut self, ino: u64) {
+        if self.modified.remove(&ino) {
+            self.emit(EventKind::Modify, ino);
+        }
+    }
+
+    /// Write the writes buffered to the file of inode `ino`.
+    fn flush_buffers(&mut self, ino: u64) -> Result<(), c_int> {
+        let handles: Vec<u64> = self
+            .buffers
+            .iter()
+            .filter(|(_, buffer)| buffer.ino == ino)
+            .map(|(fh, _)| *fh)
+            .collect();
+        for fh in handles {
+            self.flush_buffer(fh)?;
+        }
+        Ok(())
+    }
+
+    /// Write all writes buffered, before entries are moved or removed.
+    fn flush_all_writes(&mut self) -> Result<(), c_int> {
+        let handles: Vec<u64> = self.buffers.keys().copied().collect();
+        for fh in handles {
+            self.flush_buffer(fh)?;
+        }
+        for (_, (_, mut stream)) in self.streams.drain() {
+            stream.finish().map_err(|e| e.errno())?;
+        }
+        for ino in std::mem::take(&mut self.modified) {
+            self.emit(EventKind::Modify, ino);
+        }
+        Ok(())
+    }
+
+    /// Hand the chunks of `ino` about to be written at `offset` that were
+    /// read lazily to the chunk cache, fetching the blocks left, so they are
+    /// not fetched again whole.
+    fn upgrade_lazy(&self, ino: u64, offset: u64, len: usize) -> Result<(), c_int> {
+        let file = match self.entry(ino) {
+            Some(Entry::File(file)) => file,
+            _ => return Ok(()),
+        };
+        for n in FileMeta::chunk_span(offset, len) {
+            let id = file.chunk_id(n).into_id();
+            let chunk = match file.hashes.get(n) {
+                Some(hash) => self.lazies.take(&id, *hash),
+                None => None,
+            };
+            if let (Some(chunk), Some(cached)) = (chunk, &self.cached) {
+                let data = chunk.data(self.provider.as_ref()).map_err(|e| e.errno())?;
+                cached.seed(&id, data).map_err(|e| e.errno())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prefetch chunks of `file` ahead of sequential reads through `fh` by
+    /// user `uid`.
+    fn read_ahead(&self, uid: u32, fh: u64, file: &FileMeta, offset: u64, len: usize) {
+        if self.cached.is_none() {
+            return;
+        }
+        let owner = match self.options.fetch_fairness {
+            Fairness::None => 0,
+            Fairness::Handle => fh,
+            Fairness::Uid => uid as u64,
+        };
+        let chunks = self.handles.lock().read(fh, offset, len);
+        for n in chunks.start..chunks.end.min(file.chunk_count()) {
+            if let (Some(cached), Some(hash)) = (&self.cached, file.hashes.get(n)) {
+                cached.expect(file.chunk_id(n).id(), *hash);
+            }
+            self.prefetch(file.chunk_id(n).into_id(), owner);
+        }
+    }
+
+    /// Fetch chunk `id` into the chunk cache in background on behalf of
+    /// `owner`.
+    fn prefetch(&self, id: Id, owner: u64) {
+        if let Some(cached) = &self.cached {
+            let cached = Arc::downgrade(cached);
+            self.background
+                .spawn_blocking(move || prefetch_chunk(cached, id, owner));
+        }
+    }
+
+    /// Truncate the file of inode `ino` to `size`, versioned first if
+    /// shrunk and moved out of data shared with snapshots, promoted or
+    /// demoted between a tiny file and chunks as its size calls for.
+    fn truncate(&mut self, ino: u64, size: u64) -> Result<(), c_int> {
+        if size < self.entry(ino).map_or(0, |entry| entry.attrs().size) {
+            self.version(ino)?;
+        }
+        self.unshare(ino)?;
+        let leaves = self.shared_leaves(ino);
+        let old = self.reserve(ino, size)?;
+        let path = self.inodes.path(ino).ok_or(ENOENT)?;
+        if path.is_empty() {
+            return Err(EISDIR);
+        }
+        let (dir, name) = self.root.parent_mut(path).ok_or(ENOENT)?;
+        let provider = self.provider.as_ref();
+        let allocator = &mut self.allocator;
+        let content = self.superblock.content_addressed();
+        let discard = self.options.discard == Discard::Eager;
+        let result = match dir.lookup_mut(name) {
+            Some(EntryMut::TinyFile(file)) => match file.truncate(provider, allocator, size) {
+                Err(TinyFileError::TooLarge(_)) => dir
+                    .promote(name, provider, allocator, content)
+                    .and_then(|file| Ok(file.truncate(provider, size, discard)?)),
+                result => result,
+            },
+            Some(EntryMut::File(file)) => match file.truncate(provider, size, discard) {
+                Ok(()) if self.options.demote_tiny_files => {
+                    dir.demote(name, provider, allocator).map(|_| ())
+                }
+                result => result.map_err(From::from),
+            },
+            Some(EntryMut::Dir(_)) => return Err(EISDIR),
+            None => return Err(ENOENT),
+        };
+        self.account_change(ino, old);
+        self.drop_leaves(ino, &leaves)?;
+        result.map_err(|e| tiny_errno(&e))
+    }
+
+    /// Preallocate `len` bytes from `offset` of the file of inode `ino`,
+    /// which only extends it as chunks are not allocated ahead, or punch a
+    /// hole there.
+    fn allocate(&mut self, ino: u64, offset: u64, len: u64, mode: i32) -> Result<(), c_int> {
+        let path = self.inodes.path(ino).ok_or(ENOENT)?.to_vec();
+        match mode {
+            0 => {
+                let end = offset.checked_add(len).ok_or(EFBIG)?;
+                if end > self.entry(ino).ok_or(ENOENT)?.attrs().size {
+                    self.truncate(ino, end)?;
+                }
+            }
+            FALLOC_FL_KEEP_SIZE => return Ok(()),
+            PUNCH_HOLE => self.punch_hole(ino, offset, len)?,
+            _ => return Err(EOPNOTSUPP),
+        }
+        self.log_entry(&path)
+    }
+
+    /// Zero `len` bytes from `offset` of the file of inode `ino`, releasing
+    /// chunks wholly inside as configured by `MountOptions::discard`.
+    fn punch_hole(&mut self, ino: u64, offset: u64, len: u64) -> Result<(), c_int> {
+        self.check_write(ino, offset)?;
+        self.version(ino)?;
+        self.unshare(ino)?;
+        let leaves = self.shared_leaves(ino);
+        let path = self.inodes.path(ino).ok_or(ENOENT)?;
+        let provider = self.provider.as_ref();
+        let discard = self.options.discard == Discard::Eager;
+        let result = match self.root.resolve_mut(path) {
+            Some(EntryMut::File(file)) => file
+                .punch_hole(provider, offset, len, discard)
+                .map_err(|e| e.errno()),
+            Some(EntryMut::TinyFile(file)) => {
+                let end = min(offset.saturating_add(len), file.attrs.size);
+                match offset < end {
+                    true => file
+                        .write(
+                            provider,
+                            &mut self.allocator,
+                            offset,
+                            &vec![0; (end - offset) as usize],
+                        )
+                        .map(|_| ())
+                        .map_err(|e| tiny_errno(&e)),
+                    false => Ok(()),
+                }
+            }
+            Some(EntryMut::Dir(_)) => Err(EISDIR),
+            None => Err(ENOENT),
+        };
+        self.drop_leaves(ino, &leaves)?;
+        result?;
+        // discarded chunks verify as zeros from now on
+        self.hash_chunks(ino)
+    }
+
+    /// Close the handle `fh` of inode `ino`, releasing the `flock` locks of
+    /// `lock_owner` on it.
+    #[cfg(unix)]
+    fn close_handle(&mut self, ino: u64, fh: u64, lock_owner: Option<u64>) -> Result<(), c_int> {
+        if let Some(owner) = lock_owner {
+            self.locks.release(ino, owner);
+            self.wake_lock_waiters();
+        }
+        self.handles.lock().release(fh);
+        self.stats_files.remove(&fh);
+        let result = self.flush_buffer(fh);
+        self.buffers.remove(&fh);
+        self.versioned.remove(&fh);
+        result
+    }
+
+    /// Retry blocked `setlk` requests after some locks are released.
+    #[cfg(unix)]
+    fn wake_lock_waiters(&mut self) {
+        for (ino, lock, reply) in std::mem::take(&mut self.lock_waiters) {
+            match self.locks.set(ino, lock) {
+                Ok(()) => reply.ok(),
+                Err(_) => self.lock_waiters.push((ino, lock, reply)),
+            }
+        }
+    }
+
+    /// Flags replied to an `open` request with `flags`.
+    /// A file opened with `O_DIRECT` bypasses the page cache even if the mount does not.
+    #[cfg(unix)]
+    fn open_flags(&self, flags: i32) -> u32 {
+        if self.options.cache_mode == CacheMode::DirectIo || opened_direct(flags) {
+            FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
+}
+
+/// Attributes to change on an entry, those `None` are kept.
+#[derive(Clone, Debug, Default)]
+pub struct SetAttrs {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+}
+
+/// Entry a read is of, as of its start.
+enum ReadSource {
+    File(FileMeta),
+    TinyFile(TinyFileMeta),
+}
+
+/// A read started by `EossFs::start_read`, holding what it fetches from
+/// the provider with, so it is fetched without holding the filesystem.
+pub(crate) struct PendingRead {
+    ino: u64,
+    offset: u64,
+    size: usize,
+    reader: Option<(u32, u64)>,
+    source: ReadSource,
+    provider: Arc<dyn ChunkProvider>,
+    cached: Option<Arc<CachedProvider>>,
+    /// Chunks read block by block, if reading lazily
+    lazies: Option<Arc<LazyChunks>>,
+}
+
+impl PendingRead {
+    /// Fetch the bytes read as a range of a buffer. Reads within a chunk
+    /// are replied from the chunk cache as is, instead of copied from
+    /// chunks into a buffer of their own. Chunks read through the chunk
+    /// cache are verified once as fetched, not at every read.
+    pub(crate) fn fetch(&self) -> Result<(Arc<Vec<u8>>, Range<usize>), ChunkProviderError> {
+        let (offset, size) = (self.offset, self.size);
+        let file = match &self.source {
+            ReadSource::File(file) => file,
+            ReadSource::TinyFile(file) => {
+                let mut buf = vec![0; size];
+                let n = file.read(self.provider.as_ref(), offset, &mut buf)?;
+                return Ok((Arc::new(buf), 0..n));
+            }
+        };
+        let end = file.attrs.size.min(offset + size as u64).max(offset);
+        let len = (end - offset) as usize;
+        let span = FileMeta::chunk_span(offset, len);
+        if let Some(cached) = self.cached.as_ref().filter(|_| span.len() == 1) {
+            let id = file.chunk_id(span.start).into_id();
+            let start = (offset % CHUNK_SIZE as u64) as usize;
+            // read lazily unless cached already
+            let data = match self.lazies {
+                Some(_) => cached.resident(&id),
+                None => {
+                    if let Some(hash) = file.hashes.get(span.start) {
+                        cached.expect(&id, *hash);
+                    }
+                    Some(cached.chunk_data(&id)?)
+                }
+            };
+            if let Some(data) = data {
+                return Ok((data, start..start + len));
+            }
+        }
+        if let Some(cached) = self.cached.as_ref().filter(|_| self.lazies.is_none()) {
+            let mut buf = vec![0; len];
+            let mut pos = offset;
+            while pos < end {
+                let n = (pos / CHUNK_SIZE as u64) as usize;
+                let chunk_offset = (pos % CHUNK_SIZE as u64) as usize;
+                let take = min(CHUNK_SIZE - chunk_offset, (end - pos) as usize);
+                let start = (pos - offset) as usize;
+                let id = file.chunk_id(n).into_id();
+                if let Some(hash) = file.hashes.get(n) {
+                    cached.expect(&id, *hash);
+                }
+                let data = cached.chunk_data(&id)?;
+                buf[start..start + take].copy_from_slice(&data[chunk_offset..chunk_offset + take]);
+                pos += take as u64;
+            }
+            return Ok((Arc::new(buf), 0..len));
+        }
+        let mut buf = vec![0; size];
+        let n = match &self.lazies {
+            Some(lazies) => lazy::read_file(
+                file,
+                self.provider.as_ref(),
+                self.cached.as_deref(),
+                lazies,
+                offset,
+                &mut buf,
+            )?,
+            None => file.read(self.provider.as_ref(), offset, &mut buf)?,
+        };
+        Ok((Arc::new(buf), 0..n))
+    }
+}
+
+/// Operations on inodes of `inodes`, which `Vfs` resolves paths to and
+/// the FUSE and 9P frontends only adapt to their protocols.
+impl EossFs {
+    /// Attributes of inode `ino`.
+    pub(crate) fn attr(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        self.flush_writes(ino)
+            .and_then(|_| fetcher::interactive(|| self.load(ino)))?;
+        self.entry(ino)
+            .map(|entry| file_attr(ino, entry))
+            .ok_or(ENOENT)
+    }
+
+    /// Attributes of inode `ino` loaded again, as writing the journal may
+    /// have unloaded directories since.
+    fn loaded_attr(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        self.load(ino)?;
+        self.entry(ino)
+            .map(|entry| file_attr(ino, entry))
+            .ok_or(EIO)
+    }
+
+    /// Attributes and generation of the child `name` of `parent`, counted
+    /// as a kernel lookup.
+    pub(crate) fn lookup_entry(
+        &mut self,
+        parent: u64,
+        name: &str,
+    ) -> Result<(FileAttr, u64), c_int> {
+        if name == "." || name == ".." {
+            let (ino, generation) = self.lookup_handle(parent, name)?;
+            return Ok((self.loaded_attr(ino)?, generation));
+        }
+        // metadata is fetched ahead of data
+        fetcher::interactive(|| self.load(parent))?;
+        let found = self
+            .entry(parent)
+            .and_then(|dir| match dir {
+                Entry::Dir(dir) => dir.lookup(name),
+                _ => None,
+            })
+            .is_some();
+        if !found {
+            return Err(ENOENT);
+        }
+        let (ino, generation) = self.lookup_child(parent, name).unwrap();
+        self.flush_writes(ino)?;
+        Ok((self.loaded_attr(ino)?, generation))
+    }
+
+    /// Drop `nlookup` lookups of inode `ino`.
+    pub(crate) fn forget_inode(&mut self, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
+        if self.inodes.path(ino).is_none() {
+            self.watcher.unwatch(&Target::Inode(ino));
+        }
+    }
+
+    /// Entries of directory `ino` with their attributes, each assigned an
+    /// inode not counted as looked up.
+    pub(crate) fn list_dir(&mut self, ino: u64) -> Result<Vec<(String, FileAttr)>, c_int> {
+        fetcher::interactive(|| self.load(ino))?;
+        let path = self.inodes.path(ino).ok_or(ENOENT)?.to_vec();
+        let names: Vec<String> = match self.entry(ino) {
+            Some(Entry::Dir(dir)) => dir
+                .dirs()
+                .map(|sub| sub.name.clone())
+                .chain(dir.files().map(|file| file.name.clone()))
+                .chain(dir.tiny_files().map(|file| file.name.clone()))
+                .collect(),
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        };
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            let mut child = path.clone();
+            child.push(name.clone());
+            let child = self.assign(child);
+            let entry = self.entry(child).ok_or(EIO)?;
+            entries.push((name, file_attr(child, entry)));
+        }
+        Ok(entries)
+    }
+
+    /// Entries of directory `ino` from position `offset` on, `.` and `..`
+    /// first, each with its inode, the offset of the one next and its type.
+    pub(crate) fn read_dir(
+        &mut self,
+        ino: u64,
+        offset: i64,
+    ) -> Result<Vec<(u64, i64, FileType, String)>, c_int> {
+        let listed = match ino {
+            STATS_DIR_INO => vec![(STATS_INO, FileType::RegularFile, STATS_FILE.to_owned())],
+            ino => self
+                .list_dir(ino)?
+                .into_iter()
+                .map(|(name, attr)| (attr.ino, attr.kind, name))
+                .collect(),
+        };
+        let parent = match self.inodes.path(ino).and_then(<[String]>::split_last) {
+            Some((_, parent)) => self.inodes.get(parent).unwrap_or(FUSE_ROOT_ID),
+            None => FUSE_ROOT_ID,
+        };
+        let dots = vec![
+            (ino, FileType::Directory, ".".to_owned()),
+            (parent, FileType::Directory, "..".to_owned()),
+        ];
+        Ok(dots
+            .into_iter()
+            .chain(listed)
+            .enumerate()
+            .skip(offset.max(0) as usize)
+            .map(|(n, (ino, kind, name))| (ino, n as i64 + 1, kind, name))
+            .collect())
+    }
+
+    /// Read up to `size` bytes of inode `ino` at `offset`, reading ahead
+    /// for `reader`, the uid and handle reading, if any.
+    pub(crate) fn read_data(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        size: usize,
+        reader: Option<(u32, u64)>,
+    ) -> ReadData {
+        self.flush_writes(ino).and_then(|_| self.load(ino))?;
+        self.read_shared(ino, offset, size, reader)
+            .unwrap_or(Err(ENOENT))
+    }
+
+    /// Read like `read_data` without modifying the filesystem, so reads can
+    /// run in parallel. `None` if the inode has writes buffered or streamed,
+    /// or is not loaded, to be read by `read_data`.
+    pub(crate) fn read_shared(
+        &self,
+        ino: u64,
+        offset: u64,
+        size: usize,
+        reader: Option<(u32, u64)>,
+    ) -> Option<ReadData> {
+        let read = match self.start_read(ino, offset, size, reader)? {
+            Ok(pending) => {
+                let fetched = pending.fetch();
+                self.finish_read(&pending, fetched)
+            }
+            Err(e) => Err(e),
+        };
+        Some(read)
+    }
+
+    /// Plan a read like `read_shared`, to be fetched from the provider
+    /// without holding the filesystem and finished by `finish_read`.
+    pub(crate) fn start_read(
+        &self,
+        ino: u64,
+        offset: u64,
+        size: usize,
+        reader: Option<(u32, u64)>,
+    ) -> Option<Result<PendingRead, c_int>> {
+        if self.buffers.values().any(|buffer| buffer.ino == ino) || self.streams.contains_key(&ino)
+        {
+            return None;
+        }
+        let source = match self.entry(ino)? {
+            Entry::File(file) => ReadSource::File(file.clone()),
+            Entry::TinyFile(file) => ReadSource::TinyFile(file.clone()),
+            Entry::Dir(_) => return Some(Err(EISDIR)),
+        };
+        Some(Ok(PendingRead {
+            ino,
+            offset,
+            size,
+            reader,
+            source,
+            provider: self.provider.clone(),
+            cached: self.cached.clone(),
+            lazies: self.options.lazy_chunks.then(|| self.lazies.clone()),
+        }))
+    }
+
+    /// Account the read `pending` having fetched `fetched`.
+    pub(crate) fn finish_read(
+        &self,
+        pending: &PendingRead,
+        fetched: Result<(Arc<Vec<u8>>, Range<usize>), ChunkProviderError>,
+    ) -> ReadData {
+        let (ino, offset) = (pending.ino, pending.offset);
+        let (data, range) = fetched.map_err(|e| self.read_errno(ino, &e))?;
+        if let ReadSource::File(file) = &pending.source {
+            if self.options.verify {
+                self.check_hashed(ino, file, offset, range.len())?;
+            }
+            self.watch_chunks(ino, file, offset, range.len());
+            if let Some((uid, fh)) = pending.reader {
+                self.read_ahead(uid, fh, file, offset, range.len());
+            }
+        }
+        self.count_access(ino, offset, range.len(), false);
+        Ok((data, range))
+    }
+
+    /// Errno of a read of inode `ino` failed with `err`, logging chunks
+    /// failing their checks along with the path they were read for.
+    fn read_errno(&self, ino: u64, err: &ChunkProviderError) -> c_int {
+        let path = self
+            .inodes
+            .path(ino)
+            .map_or(String::new(), |path| path.join("/"));
+        match err {
+            ChunkProviderError::IntegrityError(id) => {
+                tracing::error!(
+                    "/{} (inode {}): chunk {} does not match its hash",
+                    path,
+                    ino,
+                    id
+                )
+            }
+            ChunkProviderError::SignError(e) => {
+                tracing::error!("/{} (inode {}): failed to verify: {}", path, ino, e)
+            }
+            _ if self.options.verify => {
+                tracing::error!("/{} (inode {}): failed to read: {}", path, ino, err)
+            }
+            _ => {}
+        }
+        err.errno()
+    }
+
+    /// Fail reads of chunks of `file` at `offset` that have no hash to be
+    /// checked against, written since last synced by the writer.
+    fn check_hashed(
+        &self,
+        ino: u64,
+        file: &FileMeta,
+        offset: u64,
+        len: usize,
+    ) -> Result<(), c_int> {
+        for n in FileMeta::chunk_span(offset, len) {
+            if file.hashes.get(n).map_or(true, |hash| *hash == UNHASHED) {
+                tracing::error!(
+                    "inode {}: chunk {} cannot be verified, not hashed yet",
+                    ino,
+                    n
+                );
+                return Err(EIO);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes at `offset` of the stats file opened as `fh`, `None` if `fh`
+    /// is not of the stats file.
+    pub(crate) fn read_stats(&self, fh: u64, offset: u64, size: usize) -> Option<&[u8]> {
+        let json = self.stats_files.get(&fh)?;
+        let start = (offset as usize).min(json.len());
+        let end = (start + size).min(json.len());
+        Some(&json[start..end])
+    }
+
+    /// Write `data` to inode `ino` at `offset`, past any buffered write.
+    pub(crate) fn write_direct(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, c_int> {
+        self.write_through(None, ino, offset, data)
+    }
+
+    /// Write `data` to inode `ino` at `offset` through the handle `fh` if
+    /// any, past any buffered write.
+    fn write_through(
+        &mut self,
+        fh: Option<u64>,
+        ino: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, c_int> {
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        // a chunk streamed is carried on by the write, or waited for
+        self.flush_buffers(ino)
+            .and_then(|_| self.write_data(fh, ino, offset, data))
+    }
+
+    /// Write `data` at `offset` of inode `ino` through the handle `fh`,
+    /// small writes buffered by handle.
+    pub(crate) fn write_handle(
+        &mut self,
+        fh: u64,
+        ino: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, c_int> {
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        match self.options.write_buffer_bytes {
+            capacity if data.len() < BLOCK_SIZE && capacity > 0 => self
+                .buffer_write(fh, ino, offset, data, capacity)
+                .map(|_| data.len()),
+            _ => self.write_through(Some(fh), ino, offset, data),
+        }
+    }
+
+    /// Persist what was written to inode `ino`.
+    pub(crate) fn sync_inode(&mut self, ino: u64) -> Result<(), c_int> {
+        self.flush_writes(ino)?;
+        self.emit_modified(ino);
+        self.hash_chunks(ino).and_then(|_| self.write_back())
+    }
+
+    /// Create the empty file `name` in `parent`, counted as a kernel lookup.
+    /// Returns its attributes and generation.
+    pub(crate) fn create_file(
+        &mut self,
+        parent: u64,
+        name: &str,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+    ) -> Result<(FileAttr, u64), c_int> {
+        // every file starts tiny
+        let attrs = Attrs::new(perm, uid, gid);
+        self.create_entry(
+            parent,
+            Node::TinyFile(TinyFileMeta::new(name.to_owned(), attrs)),
+        )
+    }
+
+    /// Create the empty directory `name` in `parent`, counted as a kernel
+    /// lookup. Returns its attributes and generation.
+    pub(crate) fn create_dir(
+        &mut self,
+        parent: u64,
+        name: &str,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+    ) -> Result<(FileAttr, u64), c_int> {
+        let attrs = Attrs::new(perm, uid, gid);
+        self.create_entry(parent, Node::Dir(DirMeta::new(name.to_owned(), attrs)))
+    }
+
+    /// Insert `node`, new, in `parent`.
+    fn create_entry(&mut self, parent: u64, node: Node) -> Result<(FileAttr, u64), c_int> {
+        let name = node.name().to_owned();
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        self.load(parent)?;
+        let path = self.inodes.path(parent).ok_or(ENOENT)?;
+        let dir = match self.root.resolve_mut(path) {
+            Some(EntryMut::Dir(dir)) => dir,
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        };
+        if dir.lookup(&name).is_some() {
+            return Err(EEXIST);
+        }
+        #[cfg(all(feature = "macos", target_os = "macos"))]
+        if macos::refused(&self.options.mac, &name) {
+            return Err(EPERM);
+        }
+        let mut child = path.to_vec();
+        child.push(name.clone());
+        if self.protected(&child) {
+            return Err(EPERM);
+        }
+        if !quota::check(&mut self.root, &child, 0, 1) {
+            return Err(EDQUOT);
+        }
+        let dir = match self.root.resolve_mut(&child[..child.len() - 1]) {
+            Some(EntryMut::Dir(dir)) => dir,
+            _ => return Err(ENOENT),
+        };
+        dir.insert(node);
+        if let Some(entry) = self.root.resolve(&child) {
+            let usage = quota::usage_of(entry);
+            quota::account(&mut self.root, &child, Usage::default(), usage);
+        }
+
+        let (ino, generation) = self.lookup_child(parent, &name).unwrap();
+        self.log_entry(&child)?;
+        self.emit(EventKind::Create, ino);
+        Ok((self.loaded_attr(ino)?, generation))
+    }
+
+    /// Change attributes of inode `ino` as `changes` says.
+    pub(crate) fn set_attrs(&mut self, ino: u64, changes: SetAttrs) -> Result<FileAttr, c_int> {
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        self.flush_writes(ino).and_then(|_| self.load(ino))?;
+        if let Some(attrs) = self.entry(ino).map(|entry| entry.attrs()) {
+            let SetAttrs {
+                mode,
+                uid,
+                gid,
+                size,
+                ..
+            } = changes;
+            let modified = mode.is_some() || uid.is_some() || gid.is_some() || size.is_some();
+            if attrs.immutable() || attrs.append_only() && modified {
+                return Err(EPERM);
+            }
+        }
+        if let Some(size) = changes.size {
+            self.truncate(ino, size)?;
+        }
+        let path = self.inodes.path(ino).ok_or(ENOENT)?;
+        let mut entry = self.root.resolve_mut(path).ok_or(ENOENT)?;
+        let attrs = entry.attrs_mut();
+        if let Some(mode) = changes.mode {
+            attrs.perm = (mode & 0o7777) as u16;
+        }
+        if let Some(uid) = changes.uid {
+            attrs.uid = uid;
+        }
+        if let Some(gid) = changes.gid {
+            attrs.gid = gid;
+        }
+        if let Some(atime) = changes.atime {
+            attrs.atime = atime;
+        }
+        if let Some(mtime) = changes.mtime {
+            attrs.mtime = mtime;
+        }
+        attrs.ctime = SystemTime::now();
+        let path = self.inodes.path(ino).unwrap().to_vec();
+        self.log_entry(&path)?;
+        self.emit(EventKind::Modify, ino);
+        self.loaded_attr(ino)
+    }
+
+    /// Remove the file, or the empty directory if `rmdir`, `name` of `parent`.
+    pub(crate) fn remove(&mut self, parent: u64, name: &str, rmdir: bool) -> Result<(), c_int> {
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        let event = self.entry_event(EventKind::Delete, parent, name);
+        self.flush_all_writes()
+            .and_then(|_| self.remove_entry(parent, name, rmdir))?;
+        self.events.emit_all(event);
+        Ok(())
+    }
+
+    /// Move `name` of `parent` to `newname` of `newparent`, with the
+    /// `RENAME_*` flags.
+    pub(crate) fn move_entry(
+        &mut self,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        if self.read_only() {
+            return Err(EROFS);
+        }
+        let deleted = self.entry_event(EventKind::Delete, parent, name);
+        self.flush_all_writes()
+            .and_then(|_| self.rename_entry(parent, name, newparent, newname, flags))?;
+        let created = self.entry_event(EventKind::Create, newparent, newname);
+        self.events.emit_all(deleted.into_iter().chain(created));
+        Ok(())
+    }
+}
+
+/// Whether a file opened with `flags` bypasses the page cache.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn opened_direct(flags: i32) -> bool {
+    flags & O_DIRECT != 0
+}
+
+/// Convert the attributes of `entry` to what the kernel needs.
+fn file_attr(ino: u64, entry: Entry) -> FileAttr {
+    let attrs = entry.attrs();
+    let (kind, nlink) = if entry.is_dir() {
+        (FileType::Directory, 2)
+    } else {
+        (FileType::RegularFile, 1)
+    };
+    FileAttr {
+        ino,
+        size: attrs.size,
+        blocks: attrs.blocks,
+        atime: attrs.atime,
+        mtime: attrs.mtime,
+        ctime: attrs.ctime,
+        crtime: attrs.ctime,
+        kind,
+        perm: attrs.perm,
+        nlink,
+        uid: attrs.uid,
+        gid: attrs.gid,
+        rdev: 0,
+        blksize: BLOCK_SIZE as u32,
+        flags: 0,
+    }
+}
+
+/// Write `data` at `offset` of `file` of inode `ino` through `streams`, if
+/// appended in order from the start of a chunk past the end of file, so the
+/// chunk is saved as written instead of read, modified and saved whole. A
+/// stream not carried on is waited for first.
+/// Returns `None` if to be written otherwise.
+fn stream_write(
+    streams: &mut HashMap<u64, (usize, ChunkStream)>,
+    provider: &Arc<dyn ChunkProvider>,
+    ino: u64,
+    file: &mut FileMeta,
+    offset: u64,
+    data: &[u8],
+) -> Option<Result<usize, ChunkProviderError>> {
+    let n = (offset / CHUNK_SIZE as u64) as usize;
+    let chunk_offset = (offset % CHUNK_SIZE as u64) as usize;
+    let appended = offset >= file.attrs.size;
+    let carried = match streams.get(&ino) {
+        Some((m, stream)) => {
+            *m == n && stream.written() == chunk_offset && offset == file.attrs.size
+        }
+        None => false,
+    };
+    if !carried {
+        if let Some((_, mut stream)) = streams.remove(&ino) {
+            if let Err(e) = stream.finish() {
+                return Some(Err(e));
+            }
+        }
+        if !appended || chunk_offset != 0 || data.is_empty() || file.shared(n) {
+            return None;
+        }
+    }
+    let mut written = 0;
+    while written < data.len() {
+        let pos = offset + written as u64;
+        let n = (pos / CHUNK_SIZE as u64) as usize;
+        let (_, stream) = streams.entry(ino).or_insert_with(|| {
+            let id = file.staging_id(n).into_id();
+            (n, ChunkStream::start(provider.clone(), id))
+        });
+        let len = match stream.write(&data[written..]) {
+            Ok(len) => len,
+            Err(e) => {
+                streams.remove(&ino);
+                return Some(Err(e));
+            }
+        };
+        let full = stream.is_full();
+        file.streamed(pos, len);
+        written += len;
+        if full {
+            let (_, mut stream) = streams.remove(&ino).unwrap();
+            if let Err(e) = stream.finish() {
+                return Some(Err(e));
+            }
+        }
+    }
+    Some(Ok(written))
+}
+
+fn tiny_errno(err: &TinyFileError) -> c_int {
+    match err {
+        TinyFileError::TooLarge(_) => EFBIG,
+        TinyFileError::ProviderError(e) => e.errno(),
+    }
+}
+
+/// Write back dirty chunks of the cache beyond `max_bytes` or dirty for
+/// longer than `max_age`. Returns false once the filesystem is dropped.
+fn flush_dirty(cached: &Weak<CachedProvider>, max_bytes: usize, max_age: Duration) -> bool {
+    match cached.upgrade() {
+        // a failed write back will be retried in next round
+        Some(cached) => {
+            let _ = cached.write_back_due(max_bytes, max_age);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Save journal records left batched once `window` elapsed. Returns false
+/// once the filesystem is dropped.
+fn commit_journal(
+    batch: &Weak<Mutex<Batch>>,
+    provider: &dyn ChunkProvider,
+    window: Duration,
+    flush: bool,
+) -> bool {
+    match batch.upgrade() {
+        // a failed commit will be retried in next round
+        Some(batch) => {
+            let mut batch = batch.lock();
+            if batch.expired(window) {
+                let _ = batch.commit(provider, flush);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Fetch chunks taken from `ids` of the warm-up manifest into the cache
+/// until none are left, or the filesystem is dropped meanwhile.
+fn warm_up_chunks(cached: &Weak<CachedProvider>, ids: &Mutex<vec::IntoIter<Id>>) {
+    loop {
+        let id = match ids.lock().next() {
+            Some(id) => id,
+            None => return,
+        };
+        match cached.upgrade() {
+            // warm-up takes a turn of its own among readahead
+            // deleted since recorded, read as zeros
+            Some(cached) if !cached.inner().contains_chunk(&id).unwrap_or(false) => {}
+            Some(cached) => {
+                let _ = cached.prefetch(&id, u64::MAX);
+            }
+            None => return,
+        }
+    }
+}
+
+/// Fetch chunk `id` sent by readahead or pinning into the cache, unless
+/// the filesystem is dropped meanwhile.
+fn prefetch_chunk(cached: Weak<CachedProvider>, id: Id, owner: u64) {
+    // a chunk failed to prefetch is fetched again when read
+    if let Some(cached) = cached.upgrade() {
+        let _ = cached.prefetch(&id, owner);
+    }
+}
+
+/// Reseal chunks sealed under a retired key. Returns false once all are
+/// resealed or the filesystem is dropped.
+fn reseal_chunks(
+    encrypted: &Weak<EncryptedProvider<Arc<dyn ChunkProvider>>>,
+    rekey: &Mutex<Rekey>,
+    batch: usize,
+) -> bool {
+    match encrypted.upgrade() {
+        // a failed round will be retried in next round
+        Some(encrypted) => !matches!(rekey.lock().step(encrypted.as_ref(), batch), Ok(true)),
+        None => false,
+    }
+}
+
+/// Replay changes spooled while the provider was unreachable. Returns
+/// false once the filesystem is dropped.
+fn resync_spool(spool: &Weak<SpoolProvider>) -> bool {
+    match spool.upgrade() {
+        // still unreachable, retried in next round
+        Some(spool) => {
+            let _ = spool.resync();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Poll the provider for modified chunks. Returns false once the
+/// filesystem is dropped.
+fn poll_changes(watcher: &Weak<ChunkWatcher>, provider: &dyn ChunkProvider) -> bool {
+    match watcher.upgrade() {
+        // a failed poll will be retried in next round
+        Some(watcher) => {
+            let _ = watcher.poll(provider);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run the commands queued by the control socket. Returns false once the
+/// filesystem is dropped, refusing commands from then on.
+#[cfg(unix)]
+fn run_commands(fs: &Weak<RwLock<EossFs>>, mailbox: &Mailbox) -> bool {
+    let fs = match fs.upgrade() {
+        Some(fs) => fs,
+        None => {
+            mailbox.close();
+            return false;
+        }
+    };
+    for (command, reply) in mailbox.take() {
+        let _ = reply.send(fs.write().run_command(command));
+    }
+    true
+}
+
+/// Renew the lease on mounting read-write. Returns false once released.
+fn renew_lease(lease: &Weak<Lease>) -> bool {
+    match lease.upgrade() {
+        Some(lease) => {
+            // a failed renewal will be retried in next round, unless taken over
+            if let Err(e) = lease.renew() {
+                tracing::error!("failed to renew lease: {}", e);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Drop what the kernel cached of the trees swapped out, then poll the
+/// provider for a tree newly published and load it, having the kernel come
+/// back to the root for the filesystem to swap it in. Returns false once
+/// the filesystem is dropped.
+fn poll_publication(
+    refresh: &Weak<Refresh>,
+    watcher: &Weak<ChunkWatcher>,
+    cached: Option<&CachedProvider>,
+    provider: &dyn ChunkProvider,
+) -> bool {
+    let (refresh, watcher) = match (refresh.upgrade(), watcher.upgrade()) {
+        (Some(refresh), Some(watcher)) => (refresh, watcher),
+        _ => return false,
+    };
+    let (events, targets) = refresh.take_invalidated();
+    // before the entries deleted are dropped, for watchers to see them go
+    watcher.notify(&events);
+    watcher.invalidate(&targets);
+    // a failed poll will be retried in next round
+    let polled = match refresh.poll(provider) {
+        Ok(polled) => polled,
+        Err(e @ (SuperblockError::RolledBack(..) | SuperblockError::Stale(_))) => {
+            tracing::warn!("refusing the tree published: {}", e);
+            None
+        }
+        Err(_) => None,
+    };
+    if let Some((superblock, publication)) = polled {
+        if let Some(cached) = cached {
+            // directories and files are rewritten in place
+            cached.drop_clean();
+        }
+        match refresh.load(provider, superblock, publication) {
+            Ok(()) => watcher.invalidate(&[Target::Inode(FUSE_ROOT_ID)]),
+            Err(e) => tracing::warn!("failed to load the tree published: {}", e),
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EossFs;
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::crypt::MasterKey;
+    use crate::events::EventKind;
+    use crate::fs::{Attrs, DirMeta, Entry, Node};
+    use crate::id::Id;
+    use crate::invalidate::Target;
+    #[cfg(unix)]
+    use crate::lock::Lock;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::sign::SigningKey;
+    use crate::superblock::{Superblock, SuperblockError, SUPERBLOCK_ID};
+    use crate::inode::FUSE_ROOT_ID;
+    use libc::EIO;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format() {
+        let provider = Arc::new(MemoryProvider::new());
+        let mut options = FormatOptions::default();
+        let first = EossFs::format(provider.as_ref(), &options).unwrap();
+        assert!(matches!(
+            EossFs::format(provider.as_ref(), &options),
+            Err(SuperblockError::AlreadyFormatted)
+        ));
+        options.force = true;
+        let second = EossFs::format(provider.as_ref(), &options).unwrap();
+        assert_ne!(first.uuid, second.uuid);
+
+        let fs = EossFs::open(provider, MountOptions::default(), &Id::new(SUPERBLOCK_ID)).unwrap();
+        assert_eq!(*fs.root.id, second.root_id);
+    }
+
+    #[test]
+    fn test_open_snapshot() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        let snapshot = fs.snapshot("s").unwrap();
+        fs.close().unwrap();
+
+        let options = MountOptions {
+            snapshot: Some("s".to_owned()),
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), options, &id).unwrap();
+        assert_eq!(*fs.root.id, snapshot.root_id);
+        fs.close().unwrap();
+        assert!(!Superblock::load(provider.as_ref(), &id).unwrap().dirty);
+    }
+
+    #[test]
+    fn test_nfs_handles() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            nfs_export: true,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), options.clone(), &id).unwrap();
+        let (dir, _) = fs.create_dir(FUSE_ROOT_ID, "sub", 0o755, 0, 0).unwrap();
+        let (file, generation) = fs.create_file(dir.ino, "file", 0o644, 0, 0).unwrap();
+        fs.forget_inode(file.ino, u64::MAX);
+        // listed with the inode derived from its handle
+        let listed = fs.list_dir(dir.ino).unwrap();
+        assert_eq!(listed[0].1.ino, file.ino);
+        let read = fs.read_dir(dir.ino, 0).unwrap();
+        let names: Vec<&str> = read.iter().map(|(_, _, _, name)| name.as_str()).collect();
+        assert_eq!(names, [".", "..", "file"]);
+        assert_eq!((read[1].0, read[2].0), (FUSE_ROOT_ID, file.ino));
+        // resumed from the offset of the last entry read
+        assert_eq!(fs.read_dir(dir.ino, read[1].1).unwrap(), read[2..]);
+        assert!(fs.read_dir(dir.ino, read[2].1).unwrap().is_empty());
+        assert_eq!(fs.read_dir(file.ino, 0), Err(libc::ENOTDIR));
+        fs.forget_inode(file.ino, u64::MAX);
+        fs.forget_inode(dir.ino, u64::MAX);
+        assert_eq!(fs.lookup_entry(file.ino, ".").unwrap().0.ino, file.ino);
+        assert!(!fs.handles_indexed);
+        fs.close().unwrap();
+        drop(fs);
+
+        // handles given by a former mount are found by walking the tree once
+        let mut fs = EossFs::open(provider, options, &id).unwrap();
+        let (found, found_generation) = fs.lookup_entry(file.ino, ".").unwrap();
+        assert_eq!((found.ino, found_generation), (file.ino, generation));
+        assert_eq!(fs.lookup_entry(file.ino, "..").unwrap().0.ino, dir.ino);
+        assert!(fs.handles_indexed);
+        assert!(matches!(
+            fs.lookup_entry(file.ino ^ 1, "."),
+            Err(super::ESTALE)
+        ));
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn test_dirty_flag() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        assert!(Superblock::load(provider.as_ref(), &id).unwrap().dirty);
+        fs.close().unwrap();
+        assert!(!Superblock::load(provider.as_ref(), &id).unwrap().dirty);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let stored = provider.list_chunks().unwrap().unwrap().len();
+        let options = MountOptions {
+            dry_run: true,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), options, &id).unwrap();
+        let health = fs.health().unwrap();
+        assert!(!health.unclean);
+        assert_eq!(health.chunks, Some(stored));
+        fs.close().unwrap();
+        assert!(!Superblock::load(provider.as_ref(), &id).unwrap().dirty);
+        assert_eq!(provider.list_chunks().unwrap().unwrap().len(), stored);
+    }
+
+    #[test]
+    fn test_verify() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        let signing = SigningKey::new_random();
+        let format = FormatOptions {
+            signing_key: Some(signing.clone()),
+            ..FormatOptions::default()
+        };
+        EossFs::format(provider.as_ref(), &format).unwrap();
+        let options = MountOptions {
+            signing_key: Some(signing.clone()),
+            ..MountOptions::default()
+        };
+        let mut writer = EossFs::open(provider.clone(), options.clone(), &id).unwrap();
+        let (attr, _) = writer
+            .create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0)
+            .unwrap();
+        let data = vec![7; CHUNK_SIZE + 10];
+        writer.write_direct(attr.ino, 0, &data).unwrap();
+        // hashed once synced
+        writer.sync_inode(attr.ino).unwrap();
+        let (unhashed, _) = writer
+            .create_file(FUSE_ROOT_ID, "unhashed", 0o644, 0, 0)
+            .unwrap();
+        writer.write_direct(unhashed.ino, 0, &data).unwrap();
+        writer.close().unwrap();
+
+        // checked against the key of the reader only
+        let options = MountOptions {
+            verify: true,
+            ..options
+        };
+        assert!(matches!(
+            EossFs::open(provider.clone(), options.clone(), &id),
+            Err(SuperblockError::VerifyingKeyRequired)
+        ));
+        let options = MountOptions {
+            signing_key: None,
+            verifying_key: Some(signing.verifying_key()),
+            ..options
+        };
+        let mut fs = EossFs::open(provider.clone(), options, &id).unwrap();
+        assert!(fs.read_only());
+        let (ino, _) = fs.lookup_child(FUSE_ROOT_ID, "unhashed").unwrap();
+        assert_eq!(fs.read_data(ino, 0, 10, None).unwrap_err(), EIO);
+        let (ino, _) = fs.lookup_child(FUSE_ROOT_ID, "file").unwrap();
+        let (read, range) = fs.read_data(ino, 0, 10, None).unwrap();
+        assert_eq!(read[range], [7; 10]);
+        let chunk_id = match fs.root.lookup("file") {
+            Some(Entry::File(file)) => file.chunk_id(0).into_id(),
+            _ => panic!("not a file"),
+        };
+        // changed behind the filesystem's back after read once
+        let chunk = Chunk::new(chunk_id);
+        chunk.write_at(0, &[8; 10]);
+        provider.save_chunk(&chunk).unwrap();
+        assert_eq!(fs.read_data(ino, 0, 10, None).unwrap_err(), EIO);
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn test_verified_once() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE).map(|i| (i / 4096) as u8).collect();
+        fs.write_direct(attr.ino, 0, &data).unwrap();
+        fs.sync_inode(attr.ino).unwrap();
+        fs.close().unwrap();
+        drop(fs);
+
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        let (ino, _) = fs.lookup_child(FUSE_ROOT_ID, "file").unwrap();
+        let chunk_id = match fs.root.lookup("file") {
+            Some(Entry::File(file)) => file.chunk_id(1).into_id(),
+            _ => panic!("not a file"),
+        };
+        // across chunks, verified once fetched into the cache
+        let offset = CHUNK_SIZE as u64 - 10;
+        let (read, range) = fs.read_data(ino, offset, 20, None).unwrap();
+        assert_eq!(read[range], data[CHUNK_SIZE - 10..CHUNK_SIZE + 10]);
+        let chunk = Chunk::new(chunk_id);
+        chunk.write_at(0, &[8; 10]);
+        provider.save_chunk(&chunk).unwrap();
+        let (read, range) = fs.read_data(ino, offset, 20, None).unwrap();
+        assert_eq!(read[range], data[CHUNK_SIZE - 10..CHUNK_SIZE + 10]);
+        fs.close().unwrap();
+        drop(fs);
+
+        // fetched again once remounted
+        let mut fs = EossFs::open(provider, MountOptions::default(), &id).unwrap();
+        let (ino, _) = fs.lookup_child(FUSE_ROOT_ID, "file").unwrap();
+        assert_eq!(fs.read_data(ino, offset, 20, None).unwrap_err(), EIO);
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn test_pin_limited() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            chunk_cache_bytes: 4 * CHUNK_SIZE,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider, options, &id).unwrap();
+        let (big, _) = fs.create_file(FUSE_ROOT_ID, "big", 0o644, 1000, 0).unwrap();
+        fs.write_direct(big.ino, 0, &vec![1; 3 * CHUNK_SIZE])
+            .unwrap();
+        let (small, _) = fs
+            .create_file(FUSE_ROOT_ID, "small", 0o644, 1000, 0)
+            .unwrap();
+        fs.write_direct(small.ino, 0, &vec![1; CHUNK_SIZE]).unwrap();
+
+        assert_eq!(fs.set_pinned(1001, small.ino, true), Err(libc::EPERM));
+        assert_eq!(fs.set_pinned(1000, FUSE_ROOT_ID, true), Err(libc::EPERM));
+        // beyond half of the cache
+        assert_eq!(fs.set_pinned(1000, big.ino, true), Err(libc::ENOSPC));
+        assert!(!fs.entry(big.ino).unwrap().attrs().pinned());
+        fs.set_pinned(1000, small.ino, true).unwrap();
+        assert!(fs.entry(small.ino).unwrap().attrs().pinned());
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn test_versions_per_handle() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider, MountOptions::default(), &id).unwrap();
+        fs.set_versions(&[], 10).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, &[1; 100]).unwrap();
+        let path = ["file".to_owned()];
+
+        // kept once while the handle is open
+        let fh = fs.handles.lock().open();
+        fs.write_handle(fh, attr.ino, 0, &[2; 100]).unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+        fs.write_handle(fh, attr.ino, 0, &[3; 100]).unwrap();
+        fs.flush_buffer(fh).unwrap();
+        assert_eq!(fs.versions(&path).unwrap().len(), 1);
+        fs.versioned.remove(&fh);
+        // and per write without one
+        std::thread::sleep(Duration::from_millis(1100));
+        fs.write_direct(attr.ino, 0, &[4; 100]).unwrap();
+        assert_eq!(fs.versions(&path).unwrap().len(), 2);
+        fs.close().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_flock_released() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider, MountOptions::default(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        let lock = |owner| Lock {
+            owner,
+            pid: 1,
+            start: 0,
+            end: u64::MAX,
+            typ: libc::F_WRLCK,
+        };
+        fs.locks.set(attr.ino, lock(1)).unwrap();
+        let fh = fs.handles.lock().open();
+        // closing without an owner keeps the lock
+        fs.close_handle(attr.ino, fh, None).unwrap();
+        assert!(fs.locks.conflict(attr.ino, &lock(2)).is_some());
+        let fh = fs.handles.lock().open();
+        fs.close_handle(attr.ino, fh, Some(1)).unwrap();
+        assert!(fs.locks.conflict(attr.ino, &lock(2)).is_none());
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn test_modify_coalesced() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider, MountOptions::default(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        let events = fs.events().subscribe(8);
+        for n in 0..3 {
+            fs.write_direct(attr.ino, n * 10, &[1; 10]).unwrap();
+        }
+        assert!(events.try_recv().is_err());
+        // one for all the writes once flushed
+        fs.sync_inode(attr.ino).unwrap();
+        let modified = events.try_recv().unwrap();
+        assert_eq!((modified.kind, modified.size), (EventKind::Modify, 30));
+        assert!(events.try_recv().is_err());
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn test_streamed_writes() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 100)
+            .map(|i| (i / 4096) as u8)
+            .collect();
+        fs.write_direct(attr.ino, 0, &data[..CHUNK_SIZE]).unwrap();
+        assert!(fs.streams.is_empty());
+        // appended from the start of a chunk, saved as written
+        let mut offset = CHUNK_SIZE;
+        for piece in data[CHUNK_SIZE..CHUNK_SIZE + 3 * 131072].chunks(131072) {
+            fs.write_direct(attr.ino, offset as u64, piece).unwrap();
+            offset += piece.len();
+        }
+        assert_eq!(fs.streams[&attr.ino].1.written(), 3 * 131072);
+        assert!(fs.read_shared(attr.ino, 0, 10, None).is_none());
+        fs.write_direct(attr.ino, offset as u64, &data[offset..])
+            .unwrap();
+        assert_eq!(fs.streams[&attr.ino].0, 2);
+        let (read, range) = fs
+            .read_data(attr.ino, CHUNK_SIZE as u64 - 5, 4096, None)
+            .unwrap();
+        assert_eq!(read[range], data[CHUNK_SIZE - 5..CHUNK_SIZE + 4091]);
+        assert!(fs.streams.is_empty());
+
+        // overwriting is not streamed
+        fs.write_direct(attr.ino, CHUNK_SIZE as u64, &[9; 10])
+            .unwrap();
+        assert!(fs.streams.is_empty());
+        fs.sync_inode(attr.ino).unwrap();
+        fs.close().unwrap();
+
+        let options = MountOptions {
+            verify: true,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider, options, &id).unwrap();
+        let (ino, _) = fs.lookup_child(FUSE_ROOT_ID, "file").unwrap();
+        let (read, range) = fs.read_data(ino, 2 * CHUNK_SIZE as u64, 200, None).unwrap();
+        assert_eq!(read[range], data[2 * CHUNK_SIZE..]);
+        let (read, range) = fs.read_data(ino, CHUNK_SIZE as u64, 12, None).unwrap();
+        assert_eq!(read[range.clone()][..10], [9; 10]);
+        assert_eq!(read[range][10..], data[CHUNK_SIZE + 10..CHUNK_SIZE + 12]);
+        fs.close().unwrap();
+    }
+
+    #[test]
+    fn test_refresh() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            dry_run: true,
+            refresh_interval: Some(Duration::from_secs(1)),
+            ..MountOptions::default()
+        };
+        let mut reader = EossFs::open(provider.clone(), options, &id).unwrap();
+        let mut writer = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        writer
+            .create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0)
+            .unwrap();
+        writer.close().unwrap();
+        assert!(!reader.refresh().unwrap());
+        assert!(reader.root.lookup("file").is_none());
+
+        let events = reader.events().subscribe(8);
+        let poll = reader.refresh.clone().unwrap();
+        let (superblock, publication) = poll.poll(provider.as_ref()).unwrap().unwrap();
+        // the tree in use until the one loaded is swapped in
+        poll.load(provider.as_ref(), superblock, publication)
+            .unwrap();
+        assert!(reader.root.lookup("file").is_none());
+        assert!(reader.refresh().unwrap());
+        assert!(reader.root.lookup("file").is_some());
+        let (notified, targets) = poll.take_invalidated();
+        assert!(targets.contains(&Target::Inode(FUSE_ROOT_ID)));
+        let created = events.try_recv().unwrap();
+        assert_eq!(
+            (created.kind, created.name.as_str()),
+            (EventKind::Create, "file")
+        );
+        assert_eq!(notified, vec![created]);
+        assert!(poll.poll(provider.as_ref()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_names_sealed() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        let key = MasterKey::new_random();
+        let options = FormatOptions {
+            key: Some(key.clone()),
+            ..FormatOptions::default()
+        };
+        EossFs::format(provider.as_ref(), &options).unwrap();
+        let options = MountOptions {
+            key: Some(key),
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), options, &id).unwrap();
+        let dir = DirMeta::new("plainly-named".to_owned(), Attrs::new(0o755, 0, 0));
+        fs.root.insert(Node::Dir(dir));
+        fs.sync().unwrap();
+        fs.snapshot("plainly-named-snapshot").unwrap();
+        fs.close().unwrap();
+
+        // directory entries, snapshots and the journal are sealed with the
+        // chunks holding them
+        for id in provider.list_chunks().unwrap().unwrap() {
+            let stored = provider.get_object(&id).unwrap().unwrap();
+            assert!(!stored.windows(7).any(|window| window == b"plainly"));
+        }
+    }
+
+    #[test]
+    fn test_convergent_dedup() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        let key = MasterKey::new_random();
+        let options = FormatOptions {
+            key: Some(key.clone()),
+            convergent: true,
+            ..FormatOptions::default()
+        };
+        EossFs::format(provider.as_ref(), &options).unwrap();
+        let options = MountOptions {
+            key: Some(key),
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), options, &id).unwrap();
+        let mut ids = Vec::new();
+        for name in ["a", "b"].iter() {
+            let (attr, _) = fs.create_file(FUSE_ROOT_ID, name, 0o644, 0, 0).unwrap();
+            fs.write_direct(attr.ino, 0, &vec![7; CHUNK_SIZE]).unwrap();
+            fs.hash_chunks(attr.ino).unwrap();
+            match fs.entry(attr.ino) {
+                Some(Entry::File(file)) => {
+                    ids.push(file.chunk_id(0));
+                    // its staging chunk deleted once moved
+                    let staging = file.staging_id(0).into_id();
+                    assert!(!provider.contains_chunk(&staging).unwrap());
+                }
+                _ => panic!("not promoted"),
+            }
+        }
+        // stored once at the hash of the content
+        assert_eq!(ids[0], ids[1]);
+        fs.close().unwrap();
+    }
+}
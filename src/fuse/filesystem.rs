@@ -0,0 +1,683 @@
+use std::ffi::OsStr;
+use std::time::{Duration, SystemTime};
+use std::vec;
+
+use fuser::consts::{
+    FOPEN_DIRECT_IO, FUSE_EXPORT_SUPPORT, FUSE_FLOCK_LOCKS, FUSE_POSIX_LOCKS, FUSE_WRITEBACK_CACHE,
+};
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyIoctl, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
+    TimeOrNow, FUSE_ROOT_ID,
+};
+#[cfg(not(target_os = "macos"))]
+use libc::ENODATA as ENOATTR;
+use libc::{
+    c_int, E2BIG, EACCES, EAGAIN, EINVAL, ENOENT, ENOTSUP, ENOTTY, ERANGE, EROFS, F_UNLCK,
+    O_ACCMODE, O_RDONLY,
+};
+use tracing::info_span;
+
+#[cfg(target_os = "linux")]
+use crate::admin::{Ioctl, IoctlError};
+use crate::chunk::BLOCK_SIZE;
+use crate::compression::COMPRESSION_XATTR;
+use crate::fs::{Entry, CHATTR_FLAGS};
+use crate::layout::{self, CHECKSUM_XATTR, GENERATION_XATTR};
+use crate::lock::Lock;
+#[cfg(all(feature = "macos", target_os = "macos"))]
+use crate::macos::ENOATTR;
+use crate::merkle::{self, MERKLE_XATTR};
+use crate::options::CacheMode;
+use crate::pin::PIN_XATTR;
+use crate::quota::USAGE_XATTRS;
+use crate::scrub::SCRUB_XATTR;
+use crate::stats::{self, STATS_DIR, STATS_DIR_INO, STATS_FILE, STATS_INO};
+use crate::tier::STORAGE_CLASS_XATTR;
+
+use super::{EossFs, SetAttrs, XATTR_SIZE_MAX};
+
+/// ioctl commands of `lsattr` and `chattr`, taking a long or an int.
+const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+const FS_IOC32_GETFLAGS: u32 = 0x8004_6601;
+const FS_IOC32_SETFLAGS: u32 = 0x4004_6602;
+/// Bytes and inodes reported free without a quota at the root, as
+/// providers have no set capacity. Finder refuses to copy onto a volume
+/// reporting none free.
+const UNLIMITED_FREE: u64 = 1 << 50;
+/// Longest name reported by `statfs`.
+const NAME_MAX: u32 = 255;
+
+impl Filesystem for EossFs {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        if self.options.cache_mode == CacheMode::Writeback
+            && config.add_capabilities(FUSE_WRITEBACK_CACHE).is_err()
+        {
+            self.options.cache_mode = CacheMode::PageCache;
+        }
+        // without these the kernel only enforces locks locally on this host
+        let _ = config.add_capabilities(FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS);
+        // lets the kernel look up "." and ".." of inodes it has forgotten
+        if self.options.nfs_export && config.add_capabilities(FUSE_EXPORT_SUPPORT).is_err() {
+            tracing::warn!("kernel does not support NFS export");
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        let _ = self.close();
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _op = self.op(info_span!("lookup", parent, name = ?name));
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return reply.error(ENOENT),
+        };
+        if let Err(e) = self.refresh() {
+            tracing::warn!("refreshing the tree: {}", e);
+        }
+        match (parent, name) {
+            (FUSE_ROOT_ID, STATS_DIR) => {
+                return reply.entry(&self.options.entry_ttl, &stats::attr(STATS_DIR_INO, 0), 0)
+            }
+            (STATS_DIR_INO, STATS_FILE) => {
+                // its size is not known until read
+                return reply.entry(&Duration::ZERO, &stats::attr(STATS_INO, 0), 0);
+            }
+            (STATS_DIR_INO, _) => return reply.error(ENOENT),
+            _ => {}
+        }
+        match self.lookup_entry(parent, name) {
+            Ok((attr, generation)) => reply.entry(&self.options.entry_ttl, &attr, generation),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        let _op = self.op(info_span!("forget", ino, nlookup));
+        self.forget_inode(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        let _op = self.op(info_span!("getattr", ino));
+        // the kernel comes back to the root once a tree is loaded
+        if ino == FUSE_ROOT_ID {
+            if let Err(e) = self.refresh() {
+                tracing::warn!("refreshing the tree: {}", e);
+            }
+        }
+        if ino == STATS_DIR_INO || ino == STATS_INO {
+            return reply.attr(&Duration::ZERO, &stats::attr(ino, 0));
+        }
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&self.options.attr_ttl, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let _op = self.op(info_span!("statfs"));
+        let (usage, quota) = (self.root.usage, self.root.quota);
+        let limit = |limit: u64, used: u64| match limit {
+            0 => used.saturating_add(UNLIMITED_FREE),
+            limit => limit.max(used),
+        };
+        let block = BLOCK_SIZE as u64;
+        let blocks = limit(quota.bytes, usage.bytes) / block;
+        let bfree = blocks.saturating_sub((usage.bytes + block - 1) / block);
+        let files = limit(quota.inodes, usage.inodes);
+        let ffree = files.saturating_sub(usage.inodes);
+        let bsize = BLOCK_SIZE as u32;
+        reply.statfs(blocks, bfree, bfree, files, ffree, bsize, NAME_MAX, bsize);
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let _op = self.op(info_span!("open", flags));
+        if ino == STATS_INO {
+            if flags & O_ACCMODE != O_RDONLY {
+                return reply.error(EACCES);
+            }
+            let fh = self.handles.lock().open();
+            let json = self.runtime_stats().to_json();
+            self.stats_files.insert(fh, json.into_bytes());
+            // read past its size, unknown when looked up
+            return reply.opened(fh, FOPEN_DIRECT_IO);
+        }
+        reply.opened(self.handles.lock().open(), self.open_flags(flags))
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _op = self.op(info_span!("release", fh));
+        match self.close_handle(ino, fh, lock_owner) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let _op = self.op(info_span!("setattr", ino, size = ?size));
+        let now = SystemTime::now();
+        let changes = SetAttrs {
+            mode,
+            uid,
+            gid,
+            size,
+            atime: atime.map(|atime| system_time(atime, now)),
+            mtime: mtime.map(|mtime| system_time(mtime, now)),
+        };
+        match self.set_attrs(ino, changes) {
+            Ok(attr) => reply.attr(&self.options.attr_ttl, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let _op = self.op(info_span!("read", ino, fh, offset, size));
+        if let Some(data) = self.read_stats(fh, offset as u64, size as usize) {
+            return reply.data(data);
+        }
+        let reader = Some((req.uid(), fh));
+        match self.read_data(ino, offset as u64, size as usize, reader) {
+            Ok((data, range)) => reply.data(&data[range]),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let _op = self.op(info_span!("write", ino, fh, offset, size = data.len()));
+        match self.write_handle(fh, ino, offset as u64, data) {
+            Ok(n) => reply.written(n as u32),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let _op = self.op(info_span!("create", parent, name = ?name));
+        if self.read_only() {
+            return reply.error(EROFS);
+        }
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return reply.error(EINVAL),
+        };
+        let perm = (mode & !umask & 0o7777) as u16;
+        let (attr, generation) = match self.create_file(parent, name, perm, req.uid(), req.gid()) {
+            Ok(created) => created,
+            Err(errno) => return reply.error(errno),
+        };
+        let fh = self.handles.lock().open();
+        reply.created(
+            &self.options.entry_ttl,
+            &attr,
+            generation,
+            fh,
+            self.open_flags(flags),
+        )
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let _op = self.op(info_span!("mkdir", parent, name = ?name));
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return reply.error(EINVAL),
+        };
+        let perm = (mode & !umask & 0o7777) as u16;
+        match self.create_dir(parent, name, perm, req.uid(), req.gid()) {
+            Ok((attr, generation)) => reply.entry(&self.options.entry_ttl, &attr, generation),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let _op = self.op(info_span!("readdir", ino, offset));
+        let entries = match self.read_dir(ino, offset) {
+            Ok(entries) => entries,
+            Err(errno) => return reply.error(errno),
+        };
+        for (ino, next, kind, name) in entries {
+            // the rest is read from `next` on once the reply is full
+            if reply.add(ino, next, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _op = self.op(info_span!("unlink", parent, name = ?name));
+        let result = match name.to_str() {
+            Some(name) => self.remove(parent, name, false),
+            None => Err(ENOENT),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _op = self.op(info_span!("rmdir", parent, name = ?name));
+        let result = match name.to_str() {
+            Some(name) => self.remove(parent, name, true),
+            None => Err(ENOENT),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _op =
+            self.op(info_span!("rename", parent, name = ?name, newparent, newname = ?newname));
+        let (name, newname) = match (name.to_str(), newname.to_str()) {
+            (Some(name), Some(newname)) => (name, newname),
+            _ => return reply.error(EINVAL),
+        };
+        match self.move_entry(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let _op = self.op(info_span!("ioctl", ino, cmd));
+        if let Err(errno) = self.flush_writes(ino).and_then(|_| self.load(ino)) {
+            return reply.error(errno);
+        }
+        match cmd {
+            FS_IOC_GETFLAGS | FS_IOC32_GETFLAGS => match self.entry(ino) {
+                Some(entry) if out_size >= 8 => {
+                    let flags = entry.attrs().flags & CHATTR_FLAGS;
+                    reply.ioctl(0, &(flags as u64).to_ne_bytes())
+                }
+                Some(entry) => reply.ioctl(0, &(entry.attrs().flags & CHATTR_FLAGS).to_ne_bytes()),
+                None => reply.error(ENOENT),
+            },
+            FS_IOC_SETFLAGS | FS_IOC32_SETFLAGS => match self.set_flags(req.uid(), ino, in_data) {
+                Ok(()) => reply.ioctl(0, &[]),
+                Err(errno) => reply.error(errno),
+            },
+            #[cfg(target_os = "linux")]
+            cmd => match Ioctl::parse(cmd, in_data) {
+                Ok(ioctl) => match self.admin(req.uid(), ino, ioctl) {
+                    Ok(out) => reply.ioctl(0, &out),
+                    Err(errno) => reply.error(errno),
+                },
+                Err(IoctlError::UnknownCommand(_)) => reply.error(ENOTTY),
+                Err(IoctlError::Malformed(_)) => reply.error(EINVAL),
+            },
+            #[cfg(not(target_os = "linux"))]
+            _ => reply.error(ENOTTY),
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let _op = self.op(info_span!("fallocate", ino, offset, length, mode));
+        if self.read_only() {
+            return reply.error(EROFS);
+        }
+        if offset < 0 || length <= 0 {
+            return reply.error(EINVAL);
+        }
+        let result = self
+            .flush_writes(ino)
+            .and_then(|_| self.load(ino))
+            .and_then(|_| self.allocate(ino, offset as u64, length as u64, mode));
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let _op = self.op(info_span!("getxattr", ino, name = ?name));
+        if let Err(errno) = self.load(ino) {
+            return reply.error(errno);
+        }
+        let page = name.to_str().and_then(layout::chunks_page);
+        if page.is_some() || matches!(name.to_str(), Some(MERKLE_XATTR) | Some(CHECKSUM_XATTR)) {
+            // covering all data written so far, at the chunks it is hashed to
+            if let Err(errno) = self.flush_writes(ino).and_then(|_| self.hash_chunks(ino)) {
+                return reply.error(errno);
+            }
+        }
+        let value = match (self.entry(ino), name.to_str()) {
+            (Some(entry), Some(PIN_XATTR)) if entry.attrs().pinned() => Some(1.to_string()),
+            (Some(entry), Some(STORAGE_CLASS_XATTR)) => {
+                entry.attrs().storage_class().map(|class| class.to_string())
+            }
+            (Some(entry), Some(COMPRESSION_XATTR)) => entry
+                .attrs()
+                .compression()
+                .map(|compression| compression.to_string()),
+            (Some(Entry::File(file)), Some(MERKLE_XATTR)) => Some(merkle::root_hex(file)),
+            // the chunks used beneath a directory, see `USAGE_XATTRS`
+            (Some(entry), _) if page.is_some() && !entry.is_dir() => {
+                layout::chunks(entry, page.unwrap())
+            }
+            (Some(_), Some(CHECKSUM_XATTR)) => match self.checksum(ino) {
+                Ok(checksum) => checksum,
+                Err(errno) => return reply.error(errno),
+            },
+            (Some(entry), Some(GENERATION_XATTR)) => Some(layout::generation(entry).to_string()),
+            (Some(_), Some(SCRUB_XATTR)) if ino == FUSE_ROOT_ID => {
+                self.scrub_stats.as_ref().map(ToString::to_string)
+            }
+            (Some(Entry::Dir(dir)), Some(name)) => dir.usage.xattr(name).map(|n| n.to_string()),
+            (Some(_), _) => None,
+            (None, _) => return reply.error(ENOENT),
+        };
+        match value {
+            Some(value) => reply_xattr(value.as_bytes(), size, reply),
+            None => reply.error(ENOATTR),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let _op = self.op(info_span!("listxattr", ino));
+        let mut names: Vec<&str> = match self.entry(ino) {
+            Some(Entry::Dir(_)) => USAGE_XATTRS.to_vec(),
+            Some(Entry::File(_)) => vec![MERKLE_XATTR],
+            Some(_) => Vec::new(),
+            None => return reply.error(ENOENT),
+        };
+        if self
+            .entry(ino)
+            .map_or(false, |entry| entry.attrs().pinned())
+        {
+            names.push(PIN_XATTR);
+        }
+        if let Some(Some(_)) = self.entry(ino).map(|entry| entry.attrs().storage_class()) {
+            names.push(STORAGE_CLASS_XATTR);
+        }
+        if let Some(Some(_)) = self.entry(ino).map(|entry| entry.attrs().compression()) {
+            names.push(COMPRESSION_XATTR);
+        }
+        if ino == FUSE_ROOT_ID && self.scrub_stats.is_some() {
+            names.push(SCRUB_XATTR);
+        }
+        let names: Vec<u8> = names
+            .iter()
+            .flat_map(|name| name.bytes().chain(Some(0)))
+            .collect();
+        reply_xattr(&names, size, reply)
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _op = self.op(info_span!("setxattr", ino, name = ?name));
+        if let Err(errno) = self.load(ino) {
+            return reply.error(errno);
+        }
+        let result = match name.to_str() {
+            Some(PIN_XATTR) => self.set_pinned(req.uid(), ino, true),
+            Some(STORAGE_CLASS_XATTR) => match std::str::from_utf8(value).map(str::parse) {
+                Ok(Ok(class)) => self.set_storage_class(req.uid(), ino, Some(class)),
+                _ => Err(EINVAL),
+            },
+            Some(COMPRESSION_XATTR) => match std::str::from_utf8(value).map(str::parse) {
+                Ok(Ok(compression)) => self.set_compression(req.uid(), ino, Some(compression)),
+                _ => Err(EINVAL),
+            },
+            _ => Err(ENOTSUP),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _op = self.op(info_span!("removexattr", ino, name = ?name));
+        if let Err(errno) = self.load(ino) {
+            return reply.error(errno);
+        }
+        let attrs = match self.entry(ino) {
+            Some(entry) => entry.attrs().clone(),
+            None => return reply.error(ENOENT),
+        };
+        let result = match name.to_str() {
+            Some(PIN_XATTR) if attrs.pinned() => self.set_pinned(req.uid(), ino, false),
+            Some(STORAGE_CLASS_XATTR) if attrs.storage_class().is_some() => {
+                self.set_storage_class(req.uid(), ino, None)
+            }
+            Some(COMPRESSION_XATTR) if attrs.compression().is_some() => {
+                self.set_compression(req.uid(), ino, None)
+            }
+            _ => Err(ENOATTR),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        let _op = self.op(info_span!("flush", ino, lock_owner));
+        // closing any descriptor releases all POSIX locks of the owner on this file
+        self.locks.release(ino, lock_owner);
+        self.wake_lock_waiters();
+        match self.sync_inode(ino) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _op = self.op(info_span!("fsync", ino));
+        match self.sync_inode(ino) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let _op = self.op(info_span!("getlk", ino, start, end));
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            start,
+            end,
+            typ,
+        };
+        match self.locks.conflict(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.typ, held.pid),
+            None => reply.locked(start, end, F_UNLCK, pid),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _op = self.op(info_span!("setlk", ino, start, end, sleep));
+        // only excludes the processes using this mount, see `LockTable`
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            start,
+            end,
+            typ,
+        };
+        match self.locks.set(ino, lock) {
+            Ok(()) => {
+                reply.ok();
+                // unlocking or downgrading may unblock others
+                self.wake_lock_waiters();
+            }
+            Err(_) if sleep => self.lock_waiters.push((ino, lock, reply)),
+            Err(_) => reply.error(EAGAIN),
+        }
+    }
+}
+
+/// Reply `value` of an extended attribute, or its size if `size` is 0.
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if value.len() > XATTR_SIZE_MAX {
+        reply.error(E2BIG)
+    } else if size == 0 {
+        reply.size(value.len() as u32)
+    } else if (size as usize) < value.len() {
+        reply.error(ERANGE)
+    } else {
+        reply.data(value)
+    }
+}
+
+fn system_time(time: TimeOrNow, now: SystemTime) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => now,
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+
+use crate::allocator::{FatBitMap, TinyFileAllocator, SHARED_BLOCKS};
+use crate::chunk::{BLOCK_SIZE, CHUNK_SIZE};
+use crate::dedup;
+use crate::dirindex;
+use crate::fs::DirMeta;
+use crate::id::{ChunkId, Id, Journal as JournalChunk, Meta};
+use crate::journal::Journal;
+use crate::lease;
+use crate::meta::{self, MetaError, HEADER_LENGTH};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::rekey;
+use crate::sign;
+use crate::snapshot::Snapshots;
+use crate::superblock::{Superblock, SUPERBLOCK_ID};
+use crate::tenant;
+
+/// Metadata a feature keeps beside the tree, in chunks of its own at an
+/// id derived from the superblock id.
+type Root = fn(&Id) -> ChunkId<Meta>;
+
+/// Roots of every feature stored through the filesystem's provider, kept
+/// by garbage collection and fsck alike. A feature storing metadata of its
+/// own registers it here.
+const ROOTS: &[Root] = &[rekey::progress_id, owned_id];
+
+/// Roots stored in the clear beneath any layer sealing or signing chunks.
+const CLEAR_ROOTS: &[Root] = &[lease::lease_id];
+
+/// What a garbage collection has done.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    /// Chunks referenced by the filesystem
+    pub referenced: usize,
+    /// Unreferenced chunks deleted
+    pub deleted: usize,
+}
+
+/// Space stored but referenced by nothing, found by `leaks`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LeakReport {
+    /// Unreferenced chunks of data, or metadata past its first chunk
+    pub orphans: Vec<Id>,
+    /// Unreferenced first chunks of metadata
+    pub metadata: Vec<Id>,
+    /// Slots of shared chunks marked used, whose tiny file is gone
+    pub slots: Vec<LeakedSlot>,
+}
+
+/// Blocks of a shared chunk marked used by nothing.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LeakedSlot {
+    pub chunk: Id,
+    pub offset: u16,
+    pub blocks: u16,
+}
+
+/// Chunks referenced by the filesystem whose superblock is stored at
+/// `superblock_id`: metadata, data of files in the tree at `root`, which has
+/// to be fully loaded, everything referenced by snapshots, the `ROOTS` of
+/// every feature, the buckets of the dedup index, the superblocks of
+/// tenants and signatures if signed.
+/// `backend` is the provider beneath any layer sealing or signing chunks.
+pub fn referenced(
+    provider: &dyn ChunkProvider,
+    backend: &dyn ChunkProvider,
+    superblock_id: &Id,
+    superblock: &Superblock,
+    root: &DirMeta,
+    journal: &Journal,
+    snapshots: &Snapshots,
+) -> Result<HashSet<Id>, MetaError> {
+    let mut ids: HashSet<Id> = meta::chunk_ids(
+        &ChunkId::root(superblock_id.clone()),
+        meta::encode(superblock).len(),
+    )
+    .collect();
+    ids.extend(
+        (0..journal.chunk_count())
+            .map(|n| ChunkId::<JournalChunk>::derive_n(journal.id(), n).into_id()),
+    );
+    ids.extend(dirindex::chunk_ids(root));
+    root.visit_files(&mut |file| {
+        ids.extend(file.stored_ids());
+        Ok::<_, MetaError>(())
+    })?;
+    add_tiny_chunks(root, &mut ids);
+    ids.extend(snapshots.chunk_ids(provider)?);
+    for root in ROOTS {
+        ids.extend(meta_ids(provider, &root(superblock_id)));
+    }
+    for bucket in dedup::bucket_ids(superblock_id) {
+        ids.extend(meta_ids(provider, &bucket));
+    }
+    for root in CLEAR_ROOTS {
+        ids.extend(meta_ids(backend, &root(superblock_id)));
+    }
+    // the filesystem hosting tenants, if any
+    if *superblock_id == Id::new(SUPERBLOCK_ID) {
+        ids.extend(meta_ids(backend, &tenant::table_id()));
+        for tenant in tenant::list(backend)? {
+            let id = ChunkId::root(tenant::superblock_id(&tenant.name));
+            ids.extend(meta_ids(backend, &id));
+        }
+    }
+    if superblock.signer.is_some() {
+        let signatures: Vec<Id> = ids.iter().map(sign::signature_id).collect();
+        ids.extend(signatures);
+    }
+    Ok(ids)
+}
+
+/// Ids of the chunks holding the metadata stored at `id`, its first chunk
+/// alone if not stored or not readable through `provider`.
+fn meta_ids(provider: &dyn ChunkProvider, id: &ChunkId<Meta>) -> Vec<Id> {
+    let mut header = [0; HEADER_LENGTH];
+    if let Ok(chunk) = provider.get_chunk_by_id(&meta::chunk_id(id, 0)) {
+        chunk.read_at(0, &mut header);
+    }
+    let length = meta::encoded_length(&header).unwrap_or(1);
+    meta::chunk_ids(id, length).collect()
+}
+
+fn add_tiny_chunks(dir: &DirMeta, ids: &mut HashSet<Id>) {
+    for file in dir.tiny_files().filter(|file| file.chunk_blocks > 0) {
+        ids.insert(file.chunk_id.clone());
+    }
+    for sub in dir.dirs() {
+        add_tiny_chunks(sub, ids);
+    }
+}
+
+/// Delete chunks stored by `provider` not in `referenced`, returns the
+/// deleted ones. Nothing is deleted if the provider cannot list its chunks.
+pub fn sweep(
+    provider: &dyn ChunkProvider,
+    referenced: &HashSet<Id>,
+) -> Result<Vec<Id>, ChunkProviderError> {
+    let mut deleted = Vec::new();
+    for id in provider.list_chunks()?.unwrap_or_default() {
+        if !referenced.contains(&id) {
+            provider.delete_chunk(&id)?;
+            deleted.push(id);
+        }
+    }
+    Ok(deleted)
+}
+
+/// Id of the chunks referenced by the filesystem of `superblock_id` as of
+/// its last collection, kept while it shares its provider.
+fn owned_id(superblock_id: &Id) -> ChunkId<Meta> {
+    ChunkId::derive_labeled(superblock_id, b"gc/owned")
+}
+
+/// Delete chunks the filesystem of `superblock_id`, sharing its provider
+/// with others, referenced as of its last collection but no longer in
+/// `referenced`, and keep `referenced` for the next. Chunks of other
+/// filesystems are never among them, chunks written and dropped between
+/// two collections are left over. Returns the deleted ones.
+pub fn sweep_owned(
+    provider: &dyn ChunkProvider,
+    superblock_id: &Id,
+    referenced: &HashSet<Id>,
+) -> Result<Vec<Id>, MetaError> {
+    let owned: Vec<Id> = match meta::load(provider, &owned_id(superblock_id)) {
+        Err(MetaError::BadMagic) => Vec::new(),
+        owned => owned?,
+    };
+    let mut deleted = Vec::new();
+    for id in owned {
+        if !referenced.contains(&id) {
+            provider.delete_chunk(&id)?;
+            deleted.push(id);
+        }
+    }
+    let owned: Vec<Id> = referenced.iter().cloned().collect();
+    meta::store(provider, &owned_id(superblock_id), &owned)?;
+    Ok(deleted)
+}
+
+/// Find chunks stored by `provider` not in `referenced`, and blocks of
+/// shared chunks marked used in their stored map but not in `allocator`,
+/// which tracks the tiny files of the tree and snapshots.
+/// No chunk is found orphan if the provider cannot list its chunks.
+pub fn leaks(
+    provider: &dyn ChunkProvider,
+    referenced: &HashSet<Id>,
+    allocator: &TinyFileAllocator,
+) -> Result<LeakReport, ChunkProviderError> {
+    let mut report = LeakReport::default();
+    let mut header = [0; meta::META_MAGIC.len()];
+    for id in provider.list_chunks()?.unwrap_or_default() {
+        if referenced.contains(&id) {
+            continue;
+        }
+        provider.get_chunk_by_id(&id)?.read_at(0, &mut header);
+        match &header == meta::META_MAGIC {
+            true => report.metadata.push(id),
+            false => report.orphans.push(id),
+        }
+    }
+    let mut data = vec![0; CHUNK_SIZE];
+    for id in allocator.chunk_ids() {
+        provider.get_chunk_by_id(id)?.read_at(0, &mut data);
+        let stored = FatBitMap::from_bytes(&data[SHARED_BLOCKS * BLOCK_SIZE..]);
+        let owned = allocator.fat(id).cloned().unwrap_or_default();
+        for (offset, blocks) in stored.difference(&owned).runs() {
+            report.slots.push(LeakedSlot {
+                chunk: id.clone(),
+                offset,
+                blocks,
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Delete the chunks found by `leaks`, and zero the leaked slots, storing
+/// the maps of their chunks from `allocator`.
+pub fn reclaim(
+    provider: &dyn ChunkProvider,
+    report: &LeakReport,
+    allocator: &TinyFileAllocator,
+) -> Result<(), ChunkProviderError> {
+    for id in report.orphans.iter().chain(report.metadata.iter()) {
+        provider.delete_chunk(id)?;
+    }
+    for slot in report.slots.iter() {
+        let chunk = provider.get_chunk_by_id(&slot.chunk)?;
+        let start = slot.offset as usize * BLOCK_SIZE;
+        chunk.write_at(start, &vec![0; slot.blocks as usize * BLOCK_SIZE]);
+        let owned = allocator.fat(&slot.chunk).cloned().unwrap_or_default();
+        chunk.write_at(SHARED_BLOCKS * BLOCK_SIZE, &owned.to_bytes());
+        provider.save_chunk(&chunk)?;
+    }
+    provider.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leaks, reclaim, sweep_owned, LeakReport, LeakedSlot};
+    use crate::allocator::{TinyFileAllocator, SHARED_BLOCKS};
+    use crate::chunk::{Chunk, BLOCK_SIZE};
+    use crate::fsck::fsck;
+    use crate::fuse::EossFs;
+    use crate::id::{ChunkId, Id, Meta};
+    use crate::meta;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_gc() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        fs.snapshot("s").unwrap();
+        let orphan = Id::new_random();
+        provider.save_chunk(&Chunk::new(orphan.clone())).unwrap();
+
+        let stats = fs.gc().unwrap();
+        assert_eq!(stats.deleted, 1);
+        assert!(!provider.contains_chunk(&orphan).unwrap());
+        fs.close().unwrap();
+        assert!(fsck(provider.as_ref(), &id, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_sweep_owned() {
+        let provider = MemoryProvider::new();
+        let superblock_id = Id::new_random();
+        let (mine, other) = (Id::new_random(), Id::new_random());
+        for id in [&mine, &other].iter() {
+            provider.save_chunk(&Chunk::new((*id).clone())).unwrap();
+        }
+        let referenced: HashSet<Id> = [mine.clone()].iter().cloned().collect();
+        assert!(sweep_owned(&provider, &superblock_id, &referenced)
+            .unwrap()
+            .is_empty());
+        // dropped since, the chunk of another filesystem left alone
+        let deleted = sweep_owned(&provider, &superblock_id, &HashSet::new()).unwrap();
+        assert_eq!(deleted, vec![mine.clone()]);
+        assert!(!provider.contains_chunk(&mine).unwrap());
+        assert!(provider.contains_chunk(&other).unwrap());
+    }
+
+    #[test]
+    fn test_leaks() {
+        let provider = MemoryProvider::new();
+        let (orphan, dir) = (Id::new_random(), ChunkId::<Meta>::new_random());
+        provider.save_chunk(&Chunk::new(orphan.clone())).unwrap();
+        meta::store(&provider, &dir, &String::from("gone")).unwrap();
+        // blocks 0..3 stored as used, block 0 still owned
+        let shared = Id::new_random();
+        let mut allocator = TinyFileAllocator::new();
+        allocator.mark(&shared, 0, 3);
+        let chunk = Chunk::new(shared.clone());
+        chunk.write_at(BLOCK_SIZE, &[1; 2 * BLOCK_SIZE]);
+        chunk.write_at(
+            SHARED_BLOCKS * BLOCK_SIZE,
+            &allocator.fat(&shared).unwrap().to_bytes(),
+        );
+        provider.save_chunk(&chunk).unwrap();
+        allocator.free(&shared, 1, 2);
+
+        let referenced: HashSet<Id> = [shared.clone()].iter().cloned().collect();
+        let report = leaks(&provider, &referenced, &allocator).unwrap();
+        assert_eq!(report.orphans, vec![orphan]);
+        assert_eq!(report.metadata, vec![meta::chunk_id(&dir, 0)]);
+        let slot = LeakedSlot {
+            chunk: shared,
+            offset: 1,
+            blocks: 2,
+        };
+        assert_eq!(report.slots, vec![slot]);
+
+        reclaim(&provider, &report, &allocator).unwrap();
+        assert_eq!(
+            leaks(&provider, &referenced, &allocator).unwrap(),
+            LeakReport::default()
+        );
+    }
+}
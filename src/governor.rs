@@ -0,0 +1,174 @@
+use std::cell::Cell;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::{Condvar, Mutex};
+
+/// Longest a reservation waits for memory to be released, beyond which it
+/// is granted anyway rather than waiting on memory held by its own thread.
+const MAX_STALL: Duration = Duration::from_secs(1);
+
+/// The governor of the process, unlimited until a limit is set.
+pub static GOVERNOR: Lazy<MemoryGovernor> = Lazy::new(|| MemoryGovernor::new(usize::MAX));
+
+thread_local! {
+    /// Set while freeing memory, which must not wait for memory itself
+    static UNSTALLED: Cell<bool> = Cell::new(false);
+}
+
+/// Frees memory when the governor runs out of it.
+pub trait Reclaim: Send + Sync {
+    /// Free about `bytes`, e.g. by evicting cached chunks, giving up on
+    /// locks held elsewhere rather than waiting for them.
+    fn reclaim(&self, bytes: usize);
+}
+
+struct State {
+    limit: usize,
+    used: usize,
+}
+
+/// MemoryGovernor accounts the bytes held by chunks, caches and write
+/// buffers against a ceiling. Beyond it, new chunks stall until memory is
+/// reclaimed from caches or released.
+pub struct MemoryGovernor {
+    state: Mutex<State>,
+    released: Condvar,
+    reclaimers: Mutex<Vec<Weak<dyn Reclaim>>>,
+}
+
+impl MemoryGovernor {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            state: Mutex::new(State { limit, used: 0 }),
+            released: Condvar::new(),
+            reclaimers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.state.lock().limit = limit;
+        self.released.notify_all();
+    }
+
+    /// Ask `reclaimer` to free memory while it is alive.
+    pub fn register(&self, reclaimer: Weak<dyn Reclaim>) {
+        self.reclaimers.lock().push(reclaimer);
+    }
+
+    /// Account `bytes` about to be allocated. Beyond the limit, memory is
+    /// reclaimed and the caller stalls until it is released.
+    pub fn reserve(&'static self, bytes: usize) -> Reservation {
+        if !UNSTALLED.with(Cell::get) {
+            self.wait_for(bytes);
+        }
+        self.track(bytes)
+    }
+
+    /// Account `bytes` already allocated, without stalling.
+    pub fn track(&'static self, bytes: usize) -> Reservation {
+        self.state.lock().used += bytes;
+        Reservation {
+            governor: self,
+            bytes,
+        }
+    }
+
+    /// Bytes accounted.
+    #[cfg(test)]
+    pub fn used(&self) -> usize {
+        self.state.lock().used
+    }
+
+    fn wait_for(&self, bytes: usize) {
+        let excess = {
+            let state = self.state.lock();
+            (state.used + bytes).saturating_sub(state.limit)
+        };
+        if excess == 0 {
+            return;
+        }
+        self.reclaim(excess);
+        let deadline = Instant::now() + MAX_STALL;
+        let mut state = self.state.lock();
+        while state.used + bytes > state.limit {
+            if self.released.wait_until(&mut state, deadline).timed_out() {
+                break;
+            }
+        }
+    }
+
+    fn reclaim(&self, bytes: usize) {
+        let reclaimers: Vec<Arc<dyn Reclaim>> = {
+            let mut reclaimers = self.reclaimers.lock();
+            reclaimers.retain(|reclaimer| reclaimer.strong_count() > 0);
+            reclaimers.iter().filter_map(Weak::upgrade).collect()
+        };
+        without_stall(|| {
+            for reclaimer in reclaimers {
+                reclaimer.reclaim(bytes);
+            }
+        })
+    }
+}
+
+/// Run `f` without stalling on reservations, for work freeing memory, or
+/// holding locks needed to reclaim it.
+pub fn without_stall<T>(f: impl FnOnce() -> T) -> T {
+    let outer = UNSTALLED.with(|unstalled| unstalled.replace(true));
+    let result = f();
+    UNSTALLED.with(|unstalled| unstalled.set(outer));
+    result
+}
+
+/// Bytes accounted to a governor until dropped.
+pub struct Reservation {
+    governor: &'static MemoryGovernor,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.governor.state.lock().used -= self.bytes;
+        self.governor.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryGovernor, Reclaim, Reservation};
+    use parking_lot::Mutex;
+    use std::sync::{Arc, Weak};
+
+    /// Holds reservations, dropping them when asked to reclaim.
+    struct Cache(Mutex<Vec<Reservation>>);
+
+    impl Reclaim for Cache {
+        fn reclaim(&self, bytes: usize) {
+            let mut held = self.0.lock();
+            let mut freed = 0;
+            while freed < bytes && !held.is_empty() {
+                freed += held.remove(0).bytes;
+            }
+        }
+    }
+
+    #[test]
+    fn test_reclaim() {
+        let governor: &'static MemoryGovernor = Box::leak(Box::new(MemoryGovernor::new(10)));
+        let cache = Arc::new(Cache(Mutex::new(Vec::new())));
+        let weak: Weak<Cache> = Arc::downgrade(&cache);
+        governor.register(weak);
+        for _ in 0..2 {
+            let reservation = governor.reserve(4);
+            cache.0.lock().push(reservation);
+        }
+        // beyond the limit, the oldest is reclaimed instead of stalling
+        let reservation = governor.reserve(4);
+        assert_eq!(governor.used(), 8);
+        assert_eq!(cache.0.lock().len(), 1);
+        drop(reservation);
+        assert_eq!(governor.used(), 4);
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt;
+
+use crate::quota::Usage;
+
+/// What a dry run of a mount has found, everything loaded but nothing
+/// written.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Health {
+    pub uuid: [u8; 16],
+    pub encrypted: bool,
+    pub signed: bool,
+    pub content_addressed: bool,
+    /// Whether the filesystem was not cleanly unmounted
+    pub unclean: bool,
+    /// Records of the journal to be replayed at mount
+    pub pending_records: usize,
+    pub snapshots: usize,
+    /// Usage of the tree, every directory loaded and verified
+    pub usage: Usage,
+    /// Chunks stored, `None` if the provider cannot list them
+    pub chunks: Option<usize>,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "uuid\t{}", hex::encode(self.uuid))?;
+        writeln!(f, "encrypted\t{}", self.encrypted)?;
+        writeln!(f, "signed\t{}", self.signed)?;
+        writeln!(f, "content addressed\t{}", self.content_addressed)?;
+        writeln!(f, "unclean\t{}", self.unclean)?;
+        writeln!(f, "pending records\t{}", self.pending_records)?;
+        writeln!(f, "snapshots\t{}", self.snapshots)?;
+        writeln!(f, "bytes\t{}", self.usage.bytes)?;
+        writeln!(f, "inodes\t{}", self.usage.inodes)?;
+        match self.chunks {
+            Some(chunks) => writeln!(f, "chunks stored\t{}", chunks),
+            None => writeln!(f, "chunks stored\tunknown"),
+        }
+    }
+}
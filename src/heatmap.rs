@@ -0,0 +1,243 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use parking_lot::Mutex;
+
+use crate::fs::{DirMeta, Entry, FileMeta};
+use crate::id::Id;
+use crate::publish::escape;
+
+/// Most files counted, the colder half dropped once more are accessed.
+pub const MAX_FILES: usize = 1 << 16;
+/// Most chunks of a file counted, the colder half dropped once more are.
+pub const MAX_CHUNKS: usize = 1 << 12;
+
+/// Accesses of a file counted since mounted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileHeat {
+    pub reads: u64,
+    pub writes: u64,
+    /// Reads and writes by chunk index
+    pub chunks: BTreeMap<usize, u64>,
+}
+
+impl FileHeat {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+/// Heatmap counts reads and writes of files and of their chunks, so
+/// tiering policies and warm-up manifests can be built from real usage.
+/// Files are counted by entry id, which unlike inodes and paths stays the
+/// same while mounted, resolved to their paths once exported.
+#[derive(Default)]
+pub struct Heatmap {
+    files: Mutex<HashMap<Id, FileHeat>>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count an access of `len` bytes at `offset` of the file `id`.
+    pub fn record(&self, id: &Id, offset: u64, len: usize, write: bool) {
+        let mut files = self.files.lock();
+        if files.len() >= MAX_FILES && !files.contains_key(id) {
+            let mut hottest: Vec<u64> = files.values().map(FileHeat::accesses).collect();
+            hottest.sort_unstable_by_key(|accesses| Reverse(*accesses));
+            let least = hottest[MAX_FILES / 2 - 1];
+            files.retain(|_, heat| heat.accesses() > least);
+        }
+        let heat = files.entry(id.clone()).or_default();
+        match write {
+            true => heat.writes += 1,
+            false => heat.reads += 1,
+        }
+        for n in FileMeta::chunk_span(offset, len) {
+            if heat.chunks.len() >= MAX_CHUNKS && !heat.chunks.contains_key(&n) {
+                let mut hottest: Vec<u64> = heat.chunks.values().copied().collect();
+                hottest.sort_unstable_by_key(|accesses| Reverse(*accesses));
+                let least = hottest[MAX_CHUNKS / 2 - 1];
+                heat.chunks.retain(|_, accesses| *accesses > least);
+            }
+            *heat.chunks.entry(n).or_default() += 1;
+        }
+    }
+
+    /// Files counted by entry id, most accessed first.
+    pub fn files(&self) -> Vec<(Id, FileHeat)> {
+        let mut files: Vec<_> = self
+            .files
+            .lock()
+            .iter()
+            .map(|(id, heat)| (id.clone(), heat.clone()))
+            .collect();
+        files.sort_by(|(a, a_heat), (b, b_heat)| {
+            let order = b_heat.accesses().cmp(&a_heat.accesses());
+            order.then_with(|| a.hex().cmp(b.hex()))
+        });
+        files
+    }
+}
+
+/// Paths and entries beneath `dir` loaded of the files `ids`, those not
+/// loaded left out.
+pub fn find<'a>(dir: &'a DirMeta, ids: &HashSet<Id>) -> HashMap<Id, (String, Entry<'a>)> {
+    let mut found = HashMap::new();
+    find_in(dir, "", ids, &mut found);
+    found
+}
+
+fn find_in<'a>(
+    dir: &'a DirMeta,
+    prefix: &str,
+    ids: &HashSet<Id>,
+    found: &mut HashMap<Id, (String, Entry<'a>)>,
+) {
+    let files = dir
+        .files()
+        .map(|file| (&file.name, &file.id, Entry::File(file)))
+        .chain(
+            dir.tiny_files()
+                .map(|file| (&file.name, &file.id, Entry::TinyFile(file))),
+        );
+    for (name, id, entry) in files {
+        if ids.contains(id) {
+            found.insert(id.clone(), (format!("{}{}", prefix, name), entry));
+        }
+    }
+    for sub in dir.dirs() {
+        find_in(sub, &format!("{}{}/", prefix, sub.name), ids, found);
+    }
+}
+
+/// Heatmap of `files` by entry id as a JSON object on a line, with their
+/// paths if known and chunks as pairs of their index and accesses.
+pub fn to_json(files: &[(Id, Option<String>, FileHeat)]) -> String {
+    let files: Vec<String> = files
+        .iter()
+        .map(|(id, path, heat)| {
+            let chunks: Vec<String> = heat
+                .chunks
+                .iter()
+                .map(|(n, accesses)| format!("[{},{}]", n, accesses))
+                .collect();
+            let path = match path {
+                Some(path) => format!("\"{}\"", escape(path)),
+                None => "null".to_owned(),
+            };
+            format!(
+                concat!(
+                    "{{\"id\":\"{}\",\"path\":{},",
+                    "\"reads\":{},\"writes\":{},\"chunks\":[{}]}}"
+                ),
+                id.hex(),
+                path,
+                heat.reads,
+                heat.writes,
+                chunks.join(",")
+            )
+        })
+        .collect();
+    format!("{{\"files\":[{}]}}", files.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::inode::FUSE_ROOT_ID;
+
+    use super::{to_json, FileHeat, Heatmap, MAX_FILES};
+    use crate::chunk::CHUNK_SIZE;
+    use crate::control::{Command, Value};
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+    use crate::warmup;
+
+    #[test]
+    fn test_heatmap() {
+        let heatmap = Heatmap::new();
+        let (a, b) = (Id::new_random(), Id::new_random());
+        heatmap.record(&a, CHUNK_SIZE as u64 - 1, 2, false);
+        heatmap.record(&b, 0, 1, true);
+        heatmap.record(&b, 0, 1, false);
+        heatmap.record(&b, 0, 0, false);
+        let files = heatmap.files();
+        assert_eq!(files[0].0, b);
+        assert_eq!((files[0].1.reads, files[0].1.writes), (2, 1));
+        assert_eq!(
+            files[1].1.chunks.keys().copied().collect::<Vec<_>>(),
+            [0, 1]
+        );
+        // the colder half dropped once full
+        for _ in 0..MAX_FILES {
+            heatmap.record(&Id::new_random(), 0, 1, false);
+        }
+        let files = heatmap.files();
+        assert!(files.len() <= MAX_FILES);
+        assert_eq!(files[0].0, b);
+
+        let heat = FileHeat {
+            reads: 1,
+            chunks: vec![(0, 1)].into_iter().collect(),
+            ..FileHeat::default()
+        };
+        let id = Id::new([0; 32]);
+        assert_eq!(
+            to_json(&[(id.clone(), Some("a \"b\"".to_owned()), heat.clone())]),
+            format!(
+                "{{\"files\":[{{\"id\":\"{}\",\"path\":\"a \\\"b\\\"\",\
+                 \"reads\":1,\"writes\":0,\"chunks\":[[0,1]]}}]}}",
+                id.hex()
+            )
+        );
+        assert!(to_json(&[(id, None, heat)]).contains("\"path\":null"));
+
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            heatmap: true,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider, options, &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, &vec![7; CHUNK_SIZE + 10])
+            .unwrap();
+        for _ in 0..3 {
+            fs.read_shared(attr.ino, CHUNK_SIZE as u64, 10, None)
+                .unwrap()
+                .unwrap();
+        }
+        // counted along with the file once renamed
+        fs.move_entry(FUSE_ROOT_ID, "file", FUSE_ROOT_ID, "renamed", 0)
+            .unwrap();
+        let (attr, _) = fs.lookup_entry(FUSE_ROOT_ID, "renamed").unwrap();
+        fs.read_shared(attr.ino, 0, 10, None).unwrap().unwrap();
+        let reply = fs.run_command(Command::Heatmap).unwrap();
+        assert_eq!(reply[0], ("files", Value::Number(1)));
+        let exported = match &reply[1] {
+            ("heatmap", Value::Json(json)) => json.clone(),
+            _ => panic!("no heatmap"),
+        };
+        assert!(exported.contains("\"path\":\"renamed\",\"reads\":4,\"writes\":1"));
+        assert!(exported.contains("[[0,2],[1,4]]"));
+
+        let manifest = env::temp_dir().join(format!("eoss-heatmap-{}", Id::new_random().hex()));
+        let hottest = Command::HeatmapWarmup(manifest.to_string_lossy().into_owned());
+        assert_eq!(fs.run_command(hottest).unwrap(), vec![("chunks", 2.into())]);
+        let ids = warmup::load(&manifest).unwrap();
+        // the chunk read most first
+        assert_eq!(ids[0], fs.chunk_ids(attr.ino).unwrap()[1]);
+        fs::remove_file(&manifest).unwrap();
+        fs.close().unwrap();
+    }
+}
@@ -1,15 +1,30 @@
 use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::fmt;
 use std::fmt::Formatter;
 use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
 use std::ops::Deref;
 use std::sync::Arc;
 
 use once_cell::sync::OnceCell;
-use rand::{RngCore, thread_rng};
+
+use crate::rng;
 
 pub const ID_LENGTH: usize = 32;
 
+/// Prefix of the labels hashed by `Id::derive_labeled`, longer than the
+/// indexes hashed by `Id::derive_n` so the two never collide.
+const LABEL_PREFIX: &[u8] = b"eoss-fuse label\0";
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum IdError {
+    #[error("{0} bytes long, expected {}", ID_LENGTH)]
+    InvalidLength(usize),
+    #[error("invalid hex character {0:?}")]
+    InvalidHex(char),
+}
+
 #[derive(Clone, Debug, Eq)]
 pub struct Id {
     inner: Arc<[u8; ID_LENGTH]>,
@@ -27,10 +42,20 @@ impl Id {
 
     pub fn new_random() -> Self {
         let mut id = [0u8; ID_LENGTH];
-        thread_rng().fill_bytes(&mut id);
+        rng::fill_bytes(&mut id);
         Self::new(id)
     }
 
+    /// Parse an id printed by `hex`.
+    pub fn from_hex(s: &str) -> Result<Self, IdError> {
+        let mut id = [0u8; ID_LENGTH];
+        hex::decode_to_slice(s, &mut id).map_err(|e| match e {
+            hex::FromHexError::InvalidHexCharacter { c, .. } => IdError::InvalidHex(c),
+            _ => IdError::InvalidLength(s.len() / 2),
+        })?;
+        Ok(Self::new(id))
+    }
+
     pub fn hex(&self) -> &str {
         self.hex
             .get_or_init(|| hex::encode(&*self.inner).into_boxed_str())
@@ -40,6 +65,16 @@ impl Id {
         let hash = blake3::keyed_hash(self.inner.deref(), n.to_le_bytes().as_ref());
         *hash.as_bytes()
     }
+
+    /// Derive the id of a child named by `label`, distinct from the
+    /// children derived by `derive_n` and for any other label.
+    pub fn derive_labeled(&self, label: &[u8]) -> [u8; ID_LENGTH] {
+        let hash = blake3::Hasher::new_keyed(self.inner.deref())
+            .update(LABEL_PREFIX)
+            .update(label)
+            .finalize();
+        *hash.as_bytes()
+    }
 }
 
 impl Deref for Id {
@@ -92,14 +127,275 @@ impl From<[u8; ID_LENGTH]> for Id {
     }
 }
 
+/// Kind of the chunks an id names, so an id of one kind cannot be passed
+/// where another is expected.
+pub trait IdKind {
+    /// Mixed into the ids derived, so kinds never collide.
+    const TAG: &'static [u8];
+}
+
+/// Chunks of file data.
+pub enum Data {}
+/// MetaChunks, holding encoded metadata.
+pub enum Meta {}
+/// Chunks of the journal.
+pub enum Journal {}
+
+impl IdKind for Data {
+    const TAG: &'static [u8] = b"data";
+}
+
+impl IdKind for Meta {
+    const TAG: &'static [u8] = b"meta";
+}
+
+impl IdKind for Journal {
+    const TAG: &'static [u8] = b"journal";
+}
+
+/// ChunkId is an id naming chunks of kind `K`, only derived as one or
+/// taken as one where the kind is known, never converted from another.
+pub struct ChunkId<K> {
+    id: Id,
+    kind: PhantomData<K>,
+}
+
+impl<K: IdKind> ChunkId<K> {
+    fn new(id: Id) -> Self {
+        Self {
+            id,
+            kind: PhantomData,
+        }
+    }
+
+    /// A new random id, of a root of its own.
+    pub fn new_random() -> Self {
+        Self::new(Id::new_random())
+    }
+
+    /// The nth child of `parent`, distinct across kinds.
+    pub fn derive_n(parent: &Id, n: usize) -> Self {
+        Self::new(Id::new(parent.derive_labeled(&tagged(K::TAG, 1, &n.to_le_bytes()))))
+    }
+
+    /// The child of `parent` named by `label`, distinct across kinds.
+    pub fn derive_labeled(parent: &Id, label: &[u8]) -> Self {
+        Self::new(Id::new(parent.derive_labeled(&tagged(K::TAG, 0, label))))
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    pub fn into_id(self) -> Id {
+        self.id
+    }
+}
+
+impl ChunkId<Data> {
+    /// The chunk stored at `hash` of its contents.
+    pub fn at_hash(hash: [u8; ID_LENGTH]) -> Self {
+        Self::new(Id::new(hash))
+    }
+}
+
+impl ChunkId<Meta> {
+    /// MetaChunks rooted at `id`, named out of the tree, as a superblock,
+    /// or recorded in one.
+    pub fn root(id: Id) -> Self {
+        Self::new(id)
+    }
+}
+
+/// `tag` of a kind, then `separator` telling indexes from labels, then
+/// `label`.
+fn tagged(tag: &[u8], separator: u8, label: &[u8]) -> Vec<u8> {
+    let mut tagged = tag.to_vec();
+    tagged.push(separator);
+    tagged.extend_from_slice(label);
+    tagged
+}
+
+impl<K> Clone for ChunkId<K> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            kind: PhantomData,
+        }
+    }
+}
+
+impl<K> PartialEq for ChunkId<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<K> Eq for ChunkId<K> {}
+
+impl<K> Hash for ChunkId<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl<K> fmt::Debug for ChunkId<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ChunkId({})", self.id.hex())
+    }
+}
+
+impl<K> fmt::Display for ChunkId<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.id.fmt(f)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Id {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde_id::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Id {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde_id::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// Ids as hex in human-readable formats and bytes in binary ones, for raw
+/// ids with `#[serde(with = "crate::id::serde_id")]`.
+#[cfg(feature = "serde")]
+pub mod serde_id {
+    use std::convert::TryFrom;
+    use std::fmt;
+
+    use serde::de::{self, Deserializer, SeqAccess, Visitor};
+    use serde::Serializer;
+
+    use super::{Id, ID_LENGTH};
+
+    pub fn serialize<S: Serializer>(
+        id: &[u8; ID_LENGTH],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(id))
+        } else {
+            serializer.serialize_bytes(id)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[u8; ID_LENGTH], D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdVisitor)
+        } else {
+            deserializer.deserialize_bytes(IdVisitor)
+        }
+    }
+
+    struct IdVisitor;
+
+    impl<'de> Visitor<'de> for IdVisitor {
+        type Value = [u8; ID_LENGTH];
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "an id of {} bytes, or in hex", ID_LENGTH)
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            Id::from_hex(s).map(|id| *id).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Id::try_from(bytes).map(|id| *id).map_err(E::custom)
+        }
+
+        // binary formats without bytes of their own
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut id = [0; ID_LENGTH];
+            for (n, byte) in id.iter_mut().enumerate() {
+                *byte = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(n, &self))?;
+            }
+            Ok(id)
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Id {
+    type Error = IdError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let id = <[u8; ID_LENGTH]>::try_from(bytes)
+            .map_err(|_| IdError::InvalidLength(bytes.len()))?;
+        Ok(Self::new(id))
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::id::Id;
+    use crate::id::{ChunkId, Data, Id, IdError, Journal, Meta, ID_LENGTH};
+    use std::convert::TryFrom;
 
     #[test]
     fn test_random() {
         let id = Id::new_random();
         println!("{}", id);
     }
+
+    #[test]
+    fn test_from_hex() {
+        let id = Id::new_random();
+        assert_eq!(Id::from_hex(id.hex()).unwrap(), id);
+        assert_eq!(Id::try_from(id.as_ref()).unwrap(), id);
+        assert_eq!(Id::from_hex("abcd"), Err(IdError::InvalidLength(2)));
+        let bad = "g".repeat(2 * ID_LENGTH);
+        assert_eq!(Id::from_hex(&bad), Err(IdError::InvalidHex('g')));
+        assert_eq!(Id::try_from(&[0u8; 3][..]), Err(IdError::InvalidLength(3)));
+    }
+
+    #[test]
+    fn test_derive_labeled() {
+        let id = Id::new_random();
+        let versions = id.derive_labeled(b"versions");
+        assert_eq!(versions, id.derive_labeled(b"versions"));
+        assert_ne!(versions, id.derive_labeled(b"journal"));
+        assert_ne!(versions, Id::new_random().derive_labeled(b"versions"));
+        assert_ne!(id.derive_labeled(&0usize.to_le_bytes()), id.derive_n(0));
+    }
+
+    #[test]
+    fn test_chunk_id_kinds() {
+        let parent = Id::new_random();
+        let data = ChunkId::<Data>::derive_labeled(&parent, b"0");
+        let meta = ChunkId::<Meta>::derive_labeled(&parent, b"0");
+        assert_ne!(data.id(), meta.id());
+        assert_eq!(data, ChunkId::<Data>::derive_labeled(&parent, b"0"));
+        // indexes are distinct across kinds, and from labels
+        let journal = ChunkId::<Journal>::derive_n(&parent, 1);
+        assert_ne!(journal.id(), ChunkId::<Data>::derive_n(&parent, 1).id());
+        assert_ne!(journal.id(), &Id::new(parent.derive_n(1)));
+        let label = 1usize.to_le_bytes();
+        assert_ne!(journal.id(), ChunkId::<Journal>::derive_labeled(&parent, &label).id());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        use serde::de::value::{BytesDeserializer, Error, StrDeserializer};
+        use serde::Deserialize;
+
+        let id = Id::new_random();
+        let hex = StrDeserializer::<Error>::new(id.hex());
+        assert_eq!(Id::deserialize(hex).unwrap(), id);
+        let bytes = BytesDeserializer::<Error>::new(id.as_ref());
+        assert_eq!(Id::deserialize(bytes).unwrap(), id);
+        assert!(Id::deserialize(StrDeserializer::<Error>::new("abcd")).is_err());
+    }
 }
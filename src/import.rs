@@ -0,0 +1,346 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use crate::allocator::{TinyFileAllocator, SHARED_BLOCKS};
+use crate::chunk::{Chunk, ChunkError, BLOCK_PER_CHUNK, BLOCK_SIZE, CHUNK_SIZE};
+use crate::dedup::DedupIndex;
+use crate::fs::{Attrs, DirMeta, FileMeta, Node, TinyFileError, TinyFileMeta, TINY_FILE_MAX};
+use crate::id::Id;
+use crate::merkle;
+use crate::meta::MetaError;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::stream;
+
+/// Shared chunks being packed kept in memory at most, stored when more are
+/// needed.
+const PACKING_CHUNKS: usize = 16;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ImportError {
+    #[error("destination not found")]
+    NotFound,
+    #[error("destination already exists")]
+    Exists,
+    #[error("{0} is not a UTF-8 name")]
+    BadName(String),
+    #[error(transparent)]
+    ChunkError(#[from] ChunkError),
+    #[error(transparent)]
+    TinyFileError(#[from] TinyFileError),
+    #[error(transparent)]
+    ChunkProviderError(#[from] ChunkProviderError),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// What an import has done.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ImportStats {
+    pub dirs: usize,
+    /// Files packed into shared chunks
+    pub tiny_files: usize,
+    /// Files stored in their own chunks
+    pub files: usize,
+    pub bytes: u64,
+    /// Symlinks, devices and other entries not supported
+    pub skipped: usize,
+}
+
+/// Importer builds the metadata of a directory tree outside the filesystem
+/// with its data stored directly, rather than through writes of each file.
+pub struct Importer<'a> {
+    provider: &'a dyn ChunkProvider,
+    allocator: &'a mut TinyFileAllocator,
+    dedup: Option<&'a mut DedupIndex>,
+    /// Whether files are content addressed
+    content: bool,
+    /// Shared chunks being packed
+    packing: Vec<Chunk>,
+    stats: ImportStats,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(
+        provider: &'a dyn ChunkProvider,
+        allocator: &'a mut TinyFileAllocator,
+        dedup: Option<&'a mut DedupIndex>,
+        content: bool,
+    ) -> Self {
+        Self {
+            provider,
+            allocator,
+            dedup,
+            content,
+            packing: Vec::new(),
+            stats: ImportStats::default(),
+        }
+    }
+
+    /// Build the directory `name` of the tree at `source`.
+    pub fn import_dir(&mut self, source: &Path, name: String) -> Result<DirMeta, ImportError> {
+        let mut dir = DirMeta::new(name, attrs(&fs::metadata(source)?));
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|name| ImportError::BadName(name.to_string_lossy().into_owned()))?;
+            let metadata = fs::symlink_metadata(entry.path())?;
+            let node = if metadata.is_dir() {
+                Node::Dir(self.import_dir(&entry.path(), name)?)
+            } else if metadata.is_file() {
+                self.import_file(File::open(entry.path())?, name, &metadata)?
+            } else {
+                self.stats.skipped += 1;
+                continue;
+            };
+            dir.insert(node);
+        }
+        self.stats.dirs += 1;
+        Ok(dir)
+    }
+
+    /// Store the rest of the shared chunks packed. Returns what was imported.
+    pub fn finish(mut self) -> Result<ImportStats, ImportError> {
+        while !self.packing.is_empty() {
+            self.store_packed()?;
+        }
+        Ok(self.stats)
+    }
+
+    /// Build the file `name` from `file`, packed into a shared chunk if small
+    /// enough.
+    fn import_file(
+        &mut self,
+        mut file: File,
+        name: String,
+        metadata: &Metadata,
+    ) -> Result<Node, ImportError> {
+        let mut head = Vec::new();
+        (&mut file).take(TINY_FILE_MAX + 1).read_to_end(&mut head)?;
+        if head.len() as u64 > TINY_FILE_MAX {
+            let reader = io::Cursor::new(head).chain(file);
+            return Ok(Node::File(self.import_chunks(reader, name, metadata)?));
+        }
+        let mut tiny = TinyFileMeta::new(name, attrs(metadata));
+        let blocks = (head.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        if blocks > 0 {
+            let (chunk_id, offset) = self
+                .allocator
+                .allocate(blocks)
+                .ok_or(TinyFileError::TooLarge(head.len() as u64))?;
+            self.packed(&chunk_id)?
+                .write_at(offset as usize * BLOCK_SIZE, &head);
+            tiny.chunk_id = chunk_id.clone();
+            tiny.chunk_offset = offset;
+            tiny.chunk_blocks = blocks as u16;
+        }
+        tiny.attrs.set_size(head.len() as u64);
+        self.stats.tiny_files += 1;
+        self.stats.bytes += head.len() as u64;
+        Ok(Node::TinyFile(tiny))
+    }
+
+    /// Build a file stored in its own chunks read from `reader`, hashed as
+    /// they are stored.
+    fn import_chunks(
+        &mut self,
+        mut reader: impl Read,
+        name: String,
+        metadata: &Metadata,
+    ) -> Result<FileMeta, ImportError> {
+        let mut file = FileMeta {
+            name,
+            id: Id::new_random(),
+            attrs: attrs(metadata),
+            hashes: Vec::new(),
+            content: self.content,
+        };
+        let mut size = 0;
+        loop {
+            let (len, hash) = match self.content {
+                // its id known ahead, stored as read
+                false => {
+                    let id = file.staging_id(file.hashes.len()).into_id();
+                    stream_chunk(self.provider, &mut reader, id)?
+                }
+                true => {
+                    let mut data = vec![0; CHUNK_SIZE];
+                    let len = fill(&mut reader, &mut data)?;
+                    if len == 0 {
+                        break;
+                    }
+                    let hash = merkle::chunk_hash(&data);
+                    let first = match self.dedup.as_mut() {
+                        Some(dedup) => dedup.add(&hash),
+                        None => !self.provider.contains_chunk(&Id::new(hash))?,
+                    };
+                    if first {
+                        let chunk = Chunk::new_with_data(Id::new(hash), data)?;
+                        self.provider.save_chunk(&chunk)?;
+                    }
+                    (len, hash)
+                }
+            };
+            if len == 0 {
+                break;
+            }
+            file.hashes.push(hash);
+            size += len as u64;
+            if len < CHUNK_SIZE {
+                break;
+            }
+        }
+        file.attrs.set_size(size);
+        self.stats.files += 1;
+        self.stats.bytes += size;
+        Ok(file)
+    }
+
+    /// The shared chunk `id` being packed, loaded as stored if not yet.
+    fn packed(&mut self, id: &Id) -> Result<&Chunk, ImportError> {
+        let n = match self.packing.iter().position(|chunk| chunk.id() == id) {
+            Some(n) => n,
+            None => {
+                if self.packing.len() == PACKING_CHUNKS {
+                    self.store_packed()?;
+                }
+                self.packing.push(self.provider.get_chunk_by_id(id)?);
+                self.packing.len() - 1
+            }
+        };
+        Ok(&self.packing[n])
+    }
+
+    /// Store the oldest shared chunk being packed, with its occupancy.
+    fn store_packed(&mut self) -> Result<(), ImportError> {
+        let chunk = self.packing.remove(0);
+        if let Some(fat) = self.allocator.fat(chunk.id()) {
+            chunk.write_at(SHARED_BLOCKS * BLOCK_SIZE, &fat.to_bytes());
+        }
+        self.provider.save_chunk(&chunk)?;
+        Ok(())
+    }
+}
+
+/// Attributes of an entry imported, as of the source.
+fn attrs(metadata: &Metadata) -> Attrs {
+    let (perm, uid, gid) = owner(metadata);
+    let mut attrs = Attrs::new(perm, uid, gid);
+    if let Ok(time) = metadata.modified() {
+        attrs.mtime = time;
+    }
+    if let Ok(time) = metadata.accessed() {
+        attrs.atime = time;
+    }
+    attrs
+}
+
+/// Permissions and owner of an entry imported.
+#[cfg(unix)]
+fn owner(metadata: &Metadata) -> (u16, u32, u32) {
+    let perm = (metadata.permissions().mode() & 0o7777) as u16;
+    (perm, metadata.uid(), metadata.gid())
+}
+
+/// Permissions of an entry imported from Windows, which has none but the
+/// read-only attribute, owned by root.
+#[cfg(windows)]
+fn owner(metadata: &Metadata) -> (u16, u32, u32) {
+    let perm = match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    };
+    (perm, 0, 0)
+}
+
+/// Store chunk `id` of the data read from `reader` block by block as read,
+/// hashed along like `merkle::chunk_hash`. Returns the number of bytes read
+/// and the hash, nothing stored if none read.
+fn stream_chunk(
+    provider: &dyn ChunkProvider,
+    reader: &mut impl Read,
+    id: Id,
+) -> Result<(usize, [u8; 32]), ImportError> {
+    let chunk = Chunk::new(id);
+    let mut hasher = blake3::Hasher::new();
+    let len = stream::save_filled(provider, &chunk, |block| {
+        let n = fill(reader, block)?;
+        if n > 0 {
+            // hashed as stored, zero beyond
+            block[n..].fill(0);
+            hasher.update(block);
+        }
+        Ok::<_, ImportError>(n)
+    })?;
+    for _ in (len + BLOCK_SIZE - 1) / BLOCK_SIZE..BLOCK_PER_CHUNK {
+        hasher.update(&[0; BLOCK_SIZE]);
+    }
+    Ok((len, *hasher.finalize().as_bytes()))
+}
+
+/// Read from `reader` until `buf` is full or the end. Returns the number of
+/// bytes read.
+fn fill(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Importer;
+    use crate::allocator::TinyFileAllocator;
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fs::Entry;
+    use crate::providers::memory::MemoryProvider;
+    use std::fs;
+
+    #[test]
+    fn test_import_tree() {
+        let source = std::env::temp_dir().join(format!("eoss-fuse-{}.import", std::process::id()));
+        fs::create_dir_all(source.join("sub")).unwrap();
+        let large: Vec<u8> = (0..CHUNK_SIZE + 10).map(|n| n as u8).collect();
+        fs::write(source.join("sub/large"), &large).unwrap();
+        fs::write(source.join("small"), b"small").unwrap();
+        fs::write(source.join("empty"), b"").unwrap();
+
+        let provider = MemoryProvider::new();
+        let mut allocator = TinyFileAllocator::new();
+        let mut importer = Importer::new(&provider, &mut allocator, None, false);
+        let dir = importer.import_dir(&source, "imported".to_owned()).unwrap();
+        let stats = importer.finish().unwrap();
+        fs::remove_dir_all(&source).unwrap();
+        assert_eq!((stats.dirs, stats.tiny_files, stats.files), (2, 2, 1));
+
+        let mut buf = vec![0; large.len() + 1];
+        match dir.resolve(&["small"]) {
+            Some(Entry::TinyFile(file)) => {
+                assert_eq!(file.read(&provider, 0, &mut buf).unwrap(), 5);
+                assert_eq!(&buf[..5], b"small");
+            }
+            _ => panic!("small file imported tiny"),
+        }
+        match dir.resolve(&["sub", "large"]) {
+            Some(Entry::File(file)) => {
+                assert_eq!(file.read(&provider, 0, &mut buf).unwrap(), large.len());
+                assert_eq!(&buf[..large.len()], &large[..]);
+            }
+            _ => panic!("large file imported in chunks"),
+        }
+        assert!(matches!(dir.resolve(&["empty"]), Some(Entry::TinyFile(_))));
+    }
+}
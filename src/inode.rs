@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+#[cfg(unix)]
+pub(crate) use fuser::FUSE_ROOT_ID;
+
+#[cfg(windows)]
+pub(crate) use crate::win::FUSE_ROOT_ID;
+
+/// InodeTable assigns inode numbers to paths looked up by the kernel.
+pub struct InodeTable {
+    paths: HashMap<u64, Vec<String>>,
+    inodes: HashMap<Vec<String>, u64>,
+    /// Lookup count of each inode held by the kernel
+    lookups: HashMap<u64, u64>,
+    /// Last paths of inodes derived from file handles, kept once released
+    /// for the kernel to look them up again by handle.
+    handles: HashMap<u64, Vec<String>>,
+    next: u64,
+}
+
+impl InodeTable {
+    /// Build a table holding only the root directory.
+    pub fn new() -> Self {
+        let mut table = Self {
+            paths: HashMap::new(),
+            inodes: HashMap::new(),
+            lookups: HashMap::new(),
+            handles: HashMap::new(),
+            next: FUSE_ROOT_ID,
+        };
+        table.get_or_insert(Vec::new());
+        table
+    }
+
+    /// Number of inodes assigned, the root included.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Inodes assigned and their paths.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[String])> {
+        self.paths.iter().map(|(ino, path)| (*ino, path.as_slice()))
+    }
+
+    /// Inode number assigned to `path`, if any.
+    pub fn get(&self, path: &[String]) -> Option<u64> {
+        self.inodes.get(path).copied()
+    }
+
+    /// Path of inode `ino` relative to the root.
+    pub fn path(&self, ino: u64) -> Option<&[String]> {
+        self.paths.get(&ino).map(Vec::as_slice)
+    }
+
+    /// Inode number of `path`, allocate one if not assigned yet.
+    pub fn get_or_insert(&mut self, path: Vec<String>) -> u64 {
+        if let Some(ino) = self.inodes.get(&path) {
+            return *ino;
+        }
+        let ino = self.next;
+        self.next += 1;
+        self.inodes.insert(path.clone(), ino);
+        self.paths.insert(ino, path);
+        ino
+    }
+
+    /// Inode number of `path`, one not assigned yet gets `hint` if no other
+    /// path has it.
+    pub fn insert(&mut self, path: Vec<String>, hint: Option<u64>) -> u64 {
+        match hint {
+            Some(hint) if !self.inodes.contains_key(&path) && !self.paths.contains_key(&hint) => {
+                self.inodes.insert(path.clone(), hint);
+                self.paths.insert(hint, path.clone());
+                self.handles.insert(hint, path);
+                hint
+            }
+            _ => self.get_or_insert(path),
+        }
+    }
+
+    /// Inode number of `path` as `insert`, counted as a kernel lookup.
+    pub fn lookup(&mut self, path: Vec<String>, hint: Option<u64>) -> u64 {
+        let ino = self.insert(path, hint);
+        *self.lookups.entry(ino).or_default() += 1;
+        ino
+    }
+
+    /// Last path of inode `ino` derived from a file handle, even released.
+    pub fn handle_path(&self, ino: u64) -> Option<&[String]> {
+        self.handles.get(&ino).map(Vec::as_slice)
+    }
+
+    /// Remember `path` as the one of inode `ino` derived from a file handle.
+    pub fn remember(&mut self, ino: u64, path: Vec<String>) {
+        self.handles.insert(ino, path);
+    }
+
+    /// Move the paths of inodes at and beneath `from` to `to`, inodes
+    /// previously there lose their paths.
+    pub fn rename(&mut self, from: &[String], to: &[String]) {
+        self.take(to);
+        let moved = self.take(from);
+        self.put(to, moved);
+        let moved = self.moved_handles(from, to);
+        self.handles.extend(moved);
+    }
+
+    /// Swap the paths of inodes at and beneath `a` and `b`.
+    pub fn exchange(&mut self, a: &[String], b: &[String]) {
+        let (moved_a, moved_b) = (self.take(a), self.take(b));
+        self.put(b, moved_a);
+        self.put(a, moved_b);
+        let (moved_a, moved_b) = (self.moved_handles(a, b), self.moved_handles(b, a));
+        self.handles.extend(moved_a.into_iter().chain(moved_b));
+    }
+
+    /// Inodes derived from file handles at and beneath `from`, with their
+    /// paths moved beneath `to`.
+    fn moved_handles(&self, from: &[String], to: &[String]) -> Vec<(u64, Vec<String>)> {
+        self.handles
+            .iter()
+            .filter(|(_, path)| path.starts_with(from))
+            .map(|(ino, path)| {
+                let mut new = to.to_vec();
+                new.extend_from_slice(&path[from.len()..]);
+                (*ino, new)
+            })
+            .collect()
+    }
+
+    /// Remove the paths of inodes at and beneath `path`, returning the
+    /// inodes with their paths relative to `path`.
+    fn take(&mut self, path: &[String]) -> Vec<(u64, Vec<String>)> {
+        let inos: Vec<u64> = self
+            .inodes
+            .iter()
+            .filter(|(beneath, _)| beneath.starts_with(path))
+            .map(|(_, ino)| *ino)
+            .collect();
+        inos.into_iter()
+            .filter_map(|ino| {
+                let old = self.paths.remove(&ino)?;
+                self.inodes.remove(&old);
+                Some((ino, old[path.len()..].to_vec()))
+            })
+            .collect()
+    }
+
+    /// Give inodes taken by `take` paths beneath `path`.
+    fn put(&mut self, path: &[String], taken: Vec<(u64, Vec<String>)>) {
+        for (ino, rest) in taken {
+            let mut new = path.to_vec();
+            new.extend(rest);
+            self.inodes.insert(new.clone(), ino);
+            self.paths.insert(ino, new);
+        }
+    }
+
+    /// Drop `nlookup` kernel lookups of inode `ino`, and release it when
+    /// none is left. The root is never released.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        if ino == FUSE_ROOT_ID {
+            return;
+        }
+        let lookups = self.lookups.entry(ino).or_default();
+        *lookups = lookups.saturating_sub(nlookup);
+        if *lookups == 0 {
+            self.lookups.remove(&ino);
+            if let Some(path) = self.paths.remove(&ino) {
+                self.inodes.remove(&path);
+            }
+        }
+    }
+}
+
+impl Default for InodeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::allocator::{FatBitMap, SHARED_BLOCKS};
+use crate::chunk::{BLOCK_PER_CHUNK, BLOCK_SIZE, CHUNK_SIZE};
+use crate::dirindex;
+use crate::fs::DirMeta;
+use crate::id::Id;
+use crate::merkle::{self, UNHASHED};
+use crate::meta::{Decode, MetaError, Reader, HEADER_LENGTH, META_MAGIC};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum InspectError {
+    #[error("path not found")]
+    NotFound,
+    #[error("no chunk matches {0}")]
+    NoMatch(String),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+    #[error(transparent)]
+    ChunkProviderError(#[from] ChunkProviderError),
+}
+
+/// What to inspect, a chunk by the hex of its id or a prefix of it, or
+/// the chunks of the entry at a path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InspectTarget {
+    Id(Id),
+    Prefix(String),
+    Path(Vec<String>),
+}
+
+/// Shortest prefix of an id taken as such rather than a path.
+const MIN_PREFIX: usize = 4;
+
+impl FromStr for InspectTarget {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = Id::from_hex(s) {
+            return Ok(InspectTarget::Id(id));
+        }
+        // a path starting with a slash never is
+        if s.len() >= MIN_PREFIX && s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(InspectTarget::Prefix(s.to_owned()));
+        }
+        let path = s
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+        Ok(InspectTarget::Path(path))
+    }
+}
+
+/// Something referencing a chunk.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Reference {
+    /// Metadata other than directories, e.g. the superblock.
+    Meta(&'static str),
+    /// The MetaChunks of the directory at a path.
+    Dir(String),
+    /// The nth chunk of the file at a path, with its leaf.
+    Data {
+        path: String,
+        n: usize,
+        leaf: [u8; 32],
+    },
+    /// A slot in a shared chunk of the tiny file at a path.
+    Slot {
+        path: String,
+        offset: u16,
+        blocks: u16,
+    },
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Meta(what) => f.write_str(what),
+            Reference::Dir(path) => write!(f, "directory {}", path),
+            Reference::Data { path, n, .. } => write!(f, "chunk {} of {}", n, path),
+            Reference::Slot {
+                path,
+                offset,
+                blocks,
+            } => write!(f, "blocks {}..{} of {}", offset, offset + blocks, path),
+        }
+    }
+}
+
+/// References indexes what references each chunk.
+#[derive(Default)]
+pub struct References {
+    map: HashMap<Id, Vec<Reference>>,
+}
+
+impl References {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, id: Id, reference: Reference) {
+        self.map.entry(id).or_default().push(reference);
+    }
+
+    /// Index the tree at `dir`, fully loaded, its paths beneath `prefix`.
+    pub fn add_tree(&mut self, dir: &DirMeta, prefix: &str) {
+        let path = match prefix {
+            "" => "/".to_owned(),
+            prefix => prefix.to_owned(),
+        };
+        for id in dirindex::dir_chunk_ids(dir) {
+            self.add(id, Reference::Dir(path.clone()));
+        }
+        for file in dir.files() {
+            let path = format!("{}/{}", prefix, file.name);
+            for n in 0..file.chunk_count() {
+                let leaf = file.hashes.get(n).copied().unwrap_or(UNHASHED);
+                let path = path.clone();
+                self.add(
+                    file.chunk_id(n).into_id(),
+                    Reference::Data { path, n, leaf },
+                );
+            }
+        }
+        for file in dir.tiny_files().filter(|file| file.chunk_blocks > 0) {
+            let reference = Reference::Slot {
+                path: format!("{}/{}", prefix, file.name),
+                offset: file.chunk_offset,
+                blocks: file.chunk_blocks,
+            };
+            self.add(file.chunk_id.clone(), reference);
+        }
+        for sub in dir.dirs() {
+            self.add_tree(sub, &format!("{}/{}", prefix, sub.name));
+        }
+    }
+
+    pub fn get(&self, id: &Id) -> &[Reference] {
+        self.map.get(id).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Whether data matches the checksum it is stored with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Checksum {
+    Valid,
+    Invalid,
+    /// Nothing to check against, e.g. written since last hashed.
+    Unknown,
+}
+
+/// What a chunk holds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChunkKind {
+    /// The first chunk of encoded metadata, with the fields of its header.
+    Metadata {
+        version: u16,
+        length: u64,
+        checksum: Checksum,
+    },
+    /// A shared chunk of tiny files, with the blocks marked used.
+    Shared { used: usize },
+    /// Data of a file, checked against its leaf.
+    Data { checksum: Checksum },
+    /// Anything else, e.g. metadata past its first chunk.
+    Unknown,
+}
+
+/// What is found of a chunk.
+pub struct ChunkReport {
+    pub id: Id,
+    pub stored: bool,
+    pub kind: ChunkKind,
+    pub references: Vec<Reference>,
+    /// Blocks dumped, from the first
+    pub dump: Option<(usize, Vec<u8>)>,
+}
+
+/// Inspect the chunk `id` stored by `provider`, dumping `blocks` if given.
+pub fn report(
+    provider: &dyn ChunkProvider,
+    id: &Id,
+    references: &References,
+    blocks: Option<Range<usize>>,
+) -> Result<ChunkReport, ChunkProviderError> {
+    let stored = provider.contains_chunk(id)?;
+    let mut data = vec![0; CHUNK_SIZE];
+    // fetching a chunk not stored may create it
+    if stored {
+        provider.get_chunk_by_id(id)?.read_at(0, &mut data);
+    }
+    let references = references.get(id).to_vec();
+    let kind = if data.starts_with(META_MAGIC) {
+        metadata(&data)
+    } else if references
+        .iter()
+        .any(|r| matches!(r, Reference::Slot { .. }))
+    {
+        let fat = FatBitMap::from_bytes(&data[SHARED_BLOCKS * BLOCK_SIZE..]);
+        ChunkKind::Shared { used: fat.used() }
+    } else {
+        match references.iter().find_map(|r| match r {
+            Reference::Data { leaf, .. } => Some(leaf),
+            _ => None,
+        }) {
+            Some(leaf) if *leaf == UNHASHED => ChunkKind::Data {
+                checksum: Checksum::Unknown,
+            },
+            Some(leaf) => ChunkKind::Data {
+                checksum: match merkle::chunk_hash(&data) == *leaf {
+                    true => Checksum::Valid,
+                    false => Checksum::Invalid,
+                },
+            },
+            None => ChunkKind::Unknown,
+        }
+    };
+    let dump = blocks.map(|blocks| {
+        let start = blocks.start.min(BLOCK_PER_CHUNK);
+        let blocks = start..blocks.end.min(BLOCK_PER_CHUNK).max(start);
+        let bytes = data[blocks.start * BLOCK_SIZE..blocks.end * BLOCK_SIZE].to_vec();
+        (blocks.start, bytes)
+    });
+    Ok(ChunkReport {
+        id: id.clone(),
+        stored,
+        kind,
+        references,
+        dump,
+    })
+}
+
+/// Fields of the metadata header at the beginning of `data`, its checksum
+/// only checked if the metadata fits in the chunk.
+fn metadata(data: &[u8]) -> ChunkKind {
+    // the header is within the chunk
+    let mut reader = Reader::new(&data[META_MAGIC.len()..HEADER_LENGTH]);
+    let version = u16::decode(&mut reader).unwrap();
+    let length = u64::decode(&mut reader).unwrap();
+    let stored = reader.take(32).unwrap();
+    let checksum = match (length as usize).checked_add(HEADER_LENGTH) {
+        Some(end) if end <= data.len() => {
+            match blake3::hash(&data[HEADER_LENGTH..end]).as_bytes() {
+                hash if &hash[..] == stored => Checksum::Valid,
+                _ => Checksum::Invalid,
+            }
+        }
+        _ => Checksum::Unknown,
+    };
+    ChunkKind::Metadata {
+        version,
+        length,
+        checksum,
+    }
+}
+
+impl fmt::Display for ChunkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "chunk {}", self.id.hex())?;
+        writeln!(f, "stored\t{}", self.stored)?;
+        match &self.kind {
+            ChunkKind::Metadata {
+                version,
+                length,
+                checksum,
+            } => {
+                writeln!(f, "type\tmetadata")?;
+                writeln!(f, "version\t{}", version)?;
+                writeln!(f, "length\t{}", length)?;
+                writeln!(f, "checksum\t{:?}", checksum)?;
+            }
+            ChunkKind::Shared { used } => {
+                writeln!(f, "type\tshared")?;
+                writeln!(f, "used\t{}/{} blocks", used, SHARED_BLOCKS)?;
+            }
+            ChunkKind::Data { checksum } => {
+                writeln!(f, "type\tdata")?;
+                writeln!(f, "checksum\t{:?}", checksum)?;
+            }
+            ChunkKind::Unknown => writeln!(f, "type\tunknown")?,
+        }
+        if self.references.is_empty() {
+            writeln!(f, "referenced by nothing")?;
+        }
+        for reference in self.references.iter() {
+            writeln!(f, "referenced by {}", reference)?;
+        }
+        if let Some((first, data)) = &self.dump {
+            f.write_str(&hexdump(data, first * BLOCK_SIZE))?;
+        }
+        Ok(())
+    }
+}
+
+/// Lines of 16 bytes of `data` in hex and printable ASCII, from offset
+/// `base`, runs of repeated lines shown as `*`.
+pub fn hexdump(data: &[u8], base: usize) -> String {
+    let mut dump = String::new();
+    let mut previous: Option<&[u8]> = None;
+    let mut repeated = false;
+    for (n, line) in data.chunks(16).enumerate() {
+        if previous == Some(line) {
+            if !repeated {
+                dump.push_str("*\n");
+                repeated = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        repeated = false;
+        dump.push_str(&format!("{:08x} ", base + n * 16));
+        for byte in line {
+            dump.push_str(&format!(" {:02x}", byte));
+        }
+        dump.push_str(&"   ".repeat(16 - line.len()));
+        let ascii: String = line
+            .iter()
+            .map(|b| match b {
+                0x20..=0x7e => *b as char,
+                _ => '.',
+            })
+            .collect();
+        dump.push_str(&format!("  |{}|\n", ascii));
+    }
+    if repeated {
+        dump.push_str(&format!("{:08x}\n", base + data.len()));
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::{hexdump, report, Checksum, ChunkKind, InspectTarget, Reference, References};
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::fs::{Attrs, DirMeta, FileMeta, Node};
+    use crate::id::Id;
+    use crate::merkle;
+    use crate::provider::ChunkProvider;
+    use crate::providers::local::LocalProvider;
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_inspect() {
+        let provider = MemoryProvider::new();
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        file.write(&provider, 0, b"data").unwrap();
+        merkle::update(&mut file, &provider, None).unwrap();
+        let id = file.chunk_id(0).into_id();
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut dir = DirMeta::new("dir".to_owned(), Attrs::new(0o755, 0, 0));
+        dir.insert(Node::File(file));
+        root.insert(Node::Dir(dir));
+        let mut references = References::new();
+        references.add_tree(&root, "");
+
+        let found = report(&provider, &id, &references, Some(0..1)).unwrap();
+        let valid = ChunkKind::Data {
+            checksum: Checksum::Valid,
+        };
+        assert_eq!(found.kind, valid);
+        let data =
+            |r: &Reference| matches!(r, Reference::Data { path, n: 0, .. } if path == "/dir/file");
+        assert!(found.references.len() == 1 && data(&found.references[0]));
+        assert!(found
+            .to_string()
+            .contains("referenced by chunk 0 of /dir/file"));
+
+        provider
+            .save_chunk(&Chunk::new_with_data(id.clone(), vec![1; CHUNK_SIZE]).unwrap())
+            .unwrap();
+        let found = report(&provider, &id, &references, None).unwrap();
+        let invalid = ChunkKind::Data {
+            checksum: Checksum::Invalid,
+        };
+        assert_eq!(found.kind, invalid);
+
+        // a chunk missing is left so
+        let base = env::temp_dir().join(format!("eoss-inspect-{}", Id::new_random().hex()));
+        let local = LocalProvider::new(&base).unwrap();
+        let missing = report(&local, &id, &references, None).unwrap();
+        assert!(!missing.stored && !local.contains_chunk(&id).unwrap());
+        fs::remove_dir_all(&base).unwrap();
+
+        assert_eq!(
+            hexdump(b"AB", 16),
+            "00000010  41 42                                            |AB|\n"
+        );
+        assert_eq!(
+            "/dir/file".parse::<InspectTarget>().unwrap(),
+            InspectTarget::Path(vec!["dir".to_owned(), "file".to_owned()])
+        );
+        assert_eq!(
+            id.hex().parse::<InspectTarget>().unwrap(),
+            InspectTarget::Id(id.clone())
+        );
+        assert_eq!(
+            id.hex()[..8].parse::<InspectTarget>().unwrap(),
+            InspectTarget::Prefix(id.hex()[..8].to_owned())
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+
+#[cfg(unix)]
+use fuser::Notifier;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+
+use crate::events::Event;
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+#[cfg(windows)]
+use crate::win::Notifier;
+
+/// Kernel cache entry backed by a chunk.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Target {
+    /// Cached data and attributes of an inode.
+    Inode(u64),
+    /// Cached lookup of `name` in the parent inode.
+    Entry(u64, OsString),
+}
+
+struct Watch {
+    generation: Option<u64>,
+    targets: HashSet<Target>,
+}
+
+/// ChunkWatcher tracks the chunks backing kernel caches, and invalidates
+/// those caches when a chunk is modified by someone else.
+pub struct ChunkWatcher {
+    notifier: OnceCell<Notifier>,
+    watches: Mutex<HashMap<Id, Watch>>,
+}
+
+impl ChunkWatcher {
+    pub fn new() -> Self {
+        Self {
+            notifier: OnceCell::new(),
+            watches: Default::default(),
+        }
+    }
+
+    /// Set the notifier of the mounted session, no invalidation is sent before that.
+    pub fn set_notifier(&self, notifier: Notifier) {
+        let _ = self.notifier.set(notifier);
+    }
+
+    /// Watch chunk `id` last seen at `generation` for `target`.
+    pub fn watch(&self, id: Id, generation: Option<u64>, target: Target) {
+        self.watches
+            .lock()
+            .entry(id)
+            .or_insert_with(|| Watch {
+                generation,
+                targets: HashSet::new(),
+            })
+            .targets
+            .insert(target);
+    }
+
+    /// Stop watching for `target`, e.g. when the kernel forgets an inode.
+    pub fn unwatch(&self, target: &Target) {
+        self.watches.lock().retain(|_, watch| {
+            watch.targets.remove(target);
+            !watch.targets.is_empty()
+        })
+    }
+
+    /// Record the generation of a chunk we modified ourselves.
+    pub fn update(&self, id: &Id, generation: Option<u64>) {
+        if let Some(watch) = self.watches.lock().get_mut(id) {
+            watch.generation = generation;
+        }
+    }
+
+    /// Notify that chunk `id` has changed to `generation`,
+    /// for providers which are able to push modifications.
+    pub fn changed(&self, id: &Id, generation: Option<u64>) {
+        let targets = match self.watches.lock().get_mut(id) {
+            Some(watch) if watch.generation != generation || generation.is_none() => {
+                watch.generation = generation;
+                watch.targets.iter().cloned().collect()
+            }
+            _ => Vec::new(),
+        };
+        self.invalidate(&targets);
+    }
+
+    /// Compare generations of all watched chunks with the provider,
+    /// returns the number of chunks changed.
+    pub fn poll(&self, provider: &dyn ChunkProvider) -> Result<usize, ChunkProviderError> {
+        let watched: Vec<(Id, Option<u64>)> = self
+            .watches
+            .lock()
+            .iter()
+            .map(|(id, watch)| (id.clone(), watch.generation))
+            .collect();
+        let mut changed = 0;
+        for (id, generation) in watched {
+            let current = provider.generation(&id)?;
+            if current != generation {
+                provider.invalidate(&id);
+                self.changed(&id, current);
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Tell the kernel of `events` it did not cause itself.
+    pub fn notify(&self, events: &[Event]) {
+        if let Some(notifier) = self.notifier.get() {
+            for event in events {
+                // the kernel may have never looked up the entry
+                let _ = event.notify(notifier);
+            }
+        }
+    }
+
+    /// Drop what the kernel caches for `targets`.
+    pub fn invalidate(&self, targets: &[Target]) {
+        let notifier = match self.notifier.get() {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        for target in targets {
+            // the kernel may have already dropped the entry, nothing to do then
+            let _ = match target {
+                Target::Inode(ino) => notifier.inval_inode(*ino, 0, 0),
+                Target::Entry(parent, name) => notifier.inval_entry(*parent, name),
+            };
+        }
+    }
+}
+
+impl Default for ChunkWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,504 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::chunk::{Chunk, CHUNK_SIZE};
+use crate::fs::{DirMeta, EntryMut, Node};
+use crate::id::{ChunkId, Id, Journal as JournalChunk, ID_LENGTH};
+use crate::meta::{self, Decode, Encode, MetaError, Reader, HEADER_LENGTH};
+use crate::provider::ChunkProvider;
+
+/// A metadata mutation logged before it reaches the MetaChunks.
+#[derive(Clone)]
+pub enum Record {
+    /// Create or replace the entry at `path`.
+    /// A directory only carries its attributes, its entries are kept.
+    Put { path: Vec<String>, node: Node },
+    /// Remove the entry at `path`.
+    Remove { path: Vec<String> },
+    /// Move the entry at `from` to `to`, replacing the entry there.
+    Rename { from: Vec<String>, to: Vec<String> },
+    /// Swap the entries at `a` and `b`, the entry with `id` ends at `a`.
+    Exchange {
+        a: Vec<String>,
+        b: Vec<String>,
+        id: [u8; ID_LENGTH],
+    },
+    /// Starts the journal after the tree is persisted.
+    Checkpoint,
+}
+
+impl Record {
+    /// Apply the mutation to the tree at `root`.
+    /// Records are safe to apply again to a tree already containing them.
+    pub fn apply(self, root: &mut DirMeta) {
+        match self {
+            Record::Put { path, node } => {
+                if path.is_empty() {
+                    if let Node::Dir(dir) = node {
+                        root.attrs = dir.attrs;
+                    }
+                    return;
+                }
+                let (dir, name) = match root.parent_mut(&path) {
+                    Some(found) => found,
+                    None => return,
+                };
+                if let Node::Dir(new) = &node {
+                    if let Some(EntryMut::Dir(old)) = dir.lookup_mut(name) {
+                        old.attrs = new.attrs.clone();
+                        return;
+                    }
+                }
+                dir.insert(node);
+            }
+            Record::Remove { path } => {
+                if let Some((dir, name)) = root.parent_mut(&path) {
+                    dir.remove(name);
+                }
+            }
+            Record::Rename { from, to } => {
+                let mut node = match root.parent_mut(&from) {
+                    Some((dir, name)) => match dir.remove(name) {
+                        Some(node) => node,
+                        None => return,
+                    },
+                    None => return,
+                };
+                if let Some((dir, name)) = root.parent_mut(&to) {
+                    node.set_name(name.to_owned());
+                    dir.insert(node);
+                }
+            }
+            Record::Exchange { a, b, id } => {
+                // already swapped
+                if root.resolve(&b).map(|entry| *entry.id()) != Some(id) {
+                    return;
+                }
+                let mut take = |path: &[String]| {
+                    let (dir, name) = root.parent_mut(path)?;
+                    dir.remove(name)
+                };
+                let (mut first, mut second) = match (take(&a), take(&b)) {
+                    (Some(first), Some(second)) => (first, second),
+                    _ => return,
+                };
+                first.set_name(b[b.len() - 1].clone());
+                second.set_name(a[a.len() - 1].clone());
+                for (path, node) in vec![(a, second), (b, first)] {
+                    if let Some((dir, _)) = root.parent_mut(&path) {
+                        dir.insert(node);
+                    }
+                }
+            }
+            Record::Checkpoint => {}
+        }
+    }
+}
+
+impl Encode for Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Record::Put { path, node } => {
+                0u8.encode(buf);
+                path.encode(buf);
+                node.encode(buf);
+            }
+            Record::Remove { path } => {
+                1u8.encode(buf);
+                path.encode(buf);
+            }
+            Record::Rename { from, to } => {
+                2u8.encode(buf);
+                from.encode(buf);
+                to.encode(buf);
+            }
+            Record::Checkpoint => 3u8.encode(buf),
+            Record::Exchange { a, b, id } => {
+                4u8.encode(buf);
+                a.encode(buf);
+                b.encode(buf);
+                id.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for Record {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        match u8::decode(reader)? {
+            0 => Ok(Record::Put {
+                path: Decode::decode(reader)?,
+                node: Decode::decode(reader)?,
+            }),
+            1 => Ok(Record::Remove {
+                path: Decode::decode(reader)?,
+            }),
+            2 => Ok(Record::Rename {
+                from: Decode::decode(reader)?,
+                to: Decode::decode(reader)?,
+            }),
+            3 => Ok(Record::Checkpoint),
+            4 => Ok(Record::Exchange {
+                a: Decode::decode(reader)?,
+                b: Decode::decode(reader)?,
+                id: Decode::decode(reader)?,
+            }),
+            tag => Err(MetaError::InvalidTag(tag)),
+        }
+    }
+}
+
+/// A record as stored in the journal.
+struct Frame {
+    /// Bumped on every checkpoint, older frames left in the chunks are stale
+    epoch: u64,
+    /// Position of the record in its epoch
+    seq: u64,
+    record: Record,
+}
+
+impl Encode for Frame {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.epoch.encode(buf);
+        self.seq.encode(buf);
+        self.record.encode(buf);
+    }
+}
+
+impl Decode for Frame {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            epoch: Decode::decode(reader)?,
+            seq: Decode::decode(reader)?,
+            record: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// Records appended to the journal not saved yet, shared with the thread
+/// saving them once the batch window elapsed.
+pub struct Batch {
+    /// Chunk of the next record
+    chunk: Option<Chunk>,
+    /// When the oldest record not saved was appended
+    since: Option<Instant>,
+}
+
+impl Batch {
+    /// Save the records appended in a single chunk write, and flush them
+    /// if `flush`.
+    pub fn commit(&mut self, provider: &dyn ChunkProvider, flush: bool) -> Result<(), MetaError> {
+        if let (Some(chunk), Some(_)) = (&self.chunk, self.since) {
+            provider.save_chunk(chunk)?;
+            if flush {
+                provider.flush()?;
+            }
+        }
+        self.since = None;
+        Ok(())
+    }
+
+    /// Whether records appended at least `window` ago are not saved yet.
+    pub fn expired(&self, window: Duration) -> bool {
+        self.since.map_or(false, |since| since.elapsed() >= window)
+    }
+}
+
+/// Journal is a write-ahead log of metadata mutations, stored in the chunks
+/// derived from its id by `ChunkId::derive_n`.
+/// Records are appended until the tree is persisted, then a checkpoint
+/// starts a new epoch from the first chunk.
+/// A record never spans chunks, one not fitting in the current chunk starts
+/// the next chunk.
+pub struct Journal {
+    id: Id,
+    epoch: u64,
+    seq: u64,
+    /// Chunk of the next record
+    chunk: usize,
+    /// Offset of the next record in the chunk
+    offset: usize,
+    /// Records are left in the cache of the provider to be written back
+    /// after the data before them, instead of flushed on append
+    write_back: bool,
+    /// Records are saved together once this long after the first of them
+    /// instead of on append, so mutations in a burst cost one chunk write
+    window: Option<Duration>,
+    batch: Arc<Mutex<Batch>>,
+}
+
+impl Journal {
+    /// An empty journal, not written yet.
+    pub fn new(id: Id) -> Self {
+        Self {
+            id,
+            epoch: 0,
+            seq: 0,
+            chunk: 0,
+            offset: 0,
+            write_back: false,
+            window: None,
+            batch: Arc::new(Mutex::new(Batch {
+                chunk: None,
+                since: None,
+            })),
+        }
+    }
+
+    /// Leave appended records to be written back by the provider, they are
+    /// durable only once the provider is flushed.
+    pub fn set_write_back(&mut self, write_back: bool) {
+        self.write_back = write_back;
+    }
+
+    /// Save records appended within `window` together, they are durable
+    /// only once committed.
+    pub fn set_batch(&mut self, window: Option<Duration>) {
+        self.window = window;
+    }
+
+    /// Records not saved yet, to be committed in background.
+    pub fn batch(&self) -> &Arc<Mutex<Batch>> {
+        &self.batch
+    }
+
+    /// Whether records appended are flushed when saved.
+    pub fn flushes(&self) -> bool {
+        !self.write_back
+    }
+
+    /// Read the records of the current epoch, and position the journal
+    /// after them. A torn record ends the journal.
+    pub fn open(provider: &dyn ChunkProvider, id: Id) -> Result<(Self, Vec<Record>), MetaError> {
+        let mut journal = Self::new(id);
+        let mut records = Vec::new();
+        let mut chunk =
+            provider.get_chunk_by_id(ChunkId::<JournalChunk>::derive_n(&journal.id, 0).id())?;
+        while let Some((frame, length)) = journal.next_frame(provider, &mut chunk)? {
+            if journal.seq == 0 {
+                journal.epoch = frame.epoch;
+            }
+            journal.seq += 1;
+            journal.offset += length;
+            records.push(frame.record);
+        }
+        Ok((journal, records))
+    }
+
+    /// Read the frame at the position of the journal, or at the start of the
+    /// next chunk if the record did not fit.
+    fn next_frame(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        chunk: &mut Chunk,
+    ) -> Result<Option<(Frame, usize)>, MetaError> {
+        if let Some(found) = self.read_frame(chunk, self.offset) {
+            return Ok(Some(found));
+        }
+        let next = provider
+            .get_chunk_by_id(ChunkId::<JournalChunk>::derive_n(&self.id, self.chunk + 1).id())?;
+        match self.read_frame(&next, 0) {
+            Some(found) => {
+                *chunk = next;
+                self.chunk += 1;
+                self.offset = 0;
+                Ok(Some(found))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read a valid frame of the current epoch at `offset` of `chunk`.
+    fn read_frame(&self, chunk: &Chunk, offset: usize) -> Option<(Frame, usize)> {
+        if offset + HEADER_LENGTH > CHUNK_SIZE {
+            return None;
+        }
+        let mut header = [0; HEADER_LENGTH];
+        chunk.read_at(offset, &mut header);
+        let length = meta::encoded_length(&header).ok()?;
+        if offset + length > CHUNK_SIZE {
+            return None;
+        }
+        let mut data = vec![0; length];
+        chunk.read_at(offset, &mut data);
+        let frame: Frame = meta::decode(&data).ok()?;
+        let current = frame.seq == self.seq && (self.seq == 0 || frame.epoch == self.epoch);
+        if current {
+            Some((frame, length))
+        } else {
+            None
+        }
+    }
+
+    /// Durably append `record` to the journal, unless written back or
+    /// batched.
+    pub fn append(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        record: Record,
+    ) -> Result<(), MetaError> {
+        let data = meta::encode(&Frame {
+            epoch: self.epoch,
+            seq: self.seq,
+            record,
+        });
+        assert!(data.len() <= CHUNK_SIZE, "journal record too large");
+        let mut batch = self.batch.lock();
+        if self.offset + data.len() > CHUNK_SIZE {
+            batch.commit(provider, self.flushes())?;
+            batch.chunk = None;
+            self.chunk += 1;
+            self.offset = 0;
+        }
+        if batch.chunk.is_none() {
+            batch.chunk =
+                Some(provider.get_chunk_by_id(
+                    ChunkId::<JournalChunk>::derive_n(&self.id, self.chunk).id(),
+                )?);
+        }
+        batch.chunk.as_ref().unwrap().write_at(self.offset, &data);
+        batch.since.get_or_insert_with(Instant::now);
+        self.seq += 1;
+        self.offset += data.len();
+        match self.window {
+            Some(window) if !batch.expired(window) => Ok(()),
+            _ => batch.commit(provider, self.flushes()),
+        }
+    }
+
+    /// Save the records appended and not saved yet.
+    pub fn commit(&self, provider: &dyn ChunkProvider) -> Result<(), MetaError> {
+        self.batch.lock().commit(provider, self.flushes())
+    }
+
+    /// Discard all records, called after the tree containing them is persisted.
+    pub fn checkpoint(&mut self, provider: &dyn ChunkProvider) -> Result<(), MetaError> {
+        self.epoch += 1;
+        self.seq = 0;
+        self.chunk = 0;
+        self.offset = 0;
+        {
+            // already in the tree
+            let mut batch = self.batch.lock();
+            batch.chunk = None;
+            batch.since = None;
+        }
+        self.append(provider, Record::Checkpoint)?;
+        self.commit(provider)
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    /// Epoch of the records, incremented by each checkpoint.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Number of chunks holding the records since the last checkpoint.
+    pub fn chunk_count(&self) -> usize {
+        self.chunk + 1
+    }
+
+    /// Number of records since the last checkpoint.
+    pub fn len(&self) -> u64 {
+        self.seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Journal, Record};
+    use crate::fs::{Attrs, DirMeta, Node, TinyFileMeta};
+    use crate::id::Id;
+    use crate::providers::memory::MemoryProvider;
+    use std::time::Duration;
+
+    fn put(path: &[&str]) -> Record {
+        let name = path.last().unwrap().to_string();
+        Record::Put {
+            path: path.iter().map(|s| s.to_string()).collect(),
+            node: Node::TinyFile(TinyFileMeta::new(name, Attrs::new(0o644, 0, 0))),
+        }
+    }
+
+    #[test]
+    fn test_append_and_replay() {
+        let provider = MemoryProvider::new();
+        let id = Id::new_random();
+        let mut journal = Journal::new(id.clone());
+        journal.checkpoint(&provider).unwrap();
+        journal.append(&provider, put(&["a"])).unwrap();
+        journal.append(&provider, put(&["b"])).unwrap();
+        journal
+            .append(
+                &provider,
+                Record::Rename {
+                    from: vec!["a".to_owned()],
+                    to: vec!["c".to_owned()],
+                },
+            )
+            .unwrap();
+        journal
+            .append(
+                &provider,
+                Record::Remove {
+                    path: vec!["b".to_owned()],
+                },
+            )
+            .unwrap();
+
+        let (mut reopened, records) = Journal::open(&provider, id.clone()).unwrap();
+        assert_eq!(records.len(), 5);
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        for _ in 0..2 {
+            records.iter().cloned().for_each(|r| r.apply(&mut root));
+            assert!(root.lookup("a").is_none());
+            assert!(root.lookup("b").is_none());
+            assert!(root.lookup("c").is_some());
+        }
+
+        // stale records of the previous epoch are not replayed
+        reopened.checkpoint(&provider).unwrap();
+        reopened.append(&provider, put(&["d"])).unwrap();
+        let (_, records) = Journal::open(&provider, id).unwrap();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn test_batch() {
+        let provider = MemoryProvider::new();
+        let id = Id::new_random();
+        let mut journal = Journal::new(id.clone());
+        journal.set_batch(Some(Duration::from_secs(60)));
+        journal.checkpoint(&provider).unwrap();
+        journal.append(&provider, put(&["a"])).unwrap();
+        journal.append(&provider, put(&["b"])).unwrap();
+        // only the checkpoint is saved
+        assert_eq!(Journal::open(&provider, id.clone()).unwrap().1.len(), 1);
+        journal.commit(&provider).unwrap();
+        assert_eq!(Journal::open(&provider, id).unwrap().1.len(), 3);
+    }
+
+    #[test]
+    fn test_exchange() {
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        put(&["a"]).apply(&mut root);
+        put(&["b"]).apply(&mut root);
+        let id_of = |root: &DirMeta, name| *root.lookup(name).unwrap().id();
+        let (a, b) = (id_of(&root, "a"), id_of(&root, "b"));
+        let record = Record::Exchange {
+            a: vec!["a".to_owned()],
+            b: vec!["b".to_owned()],
+            id: b,
+        };
+        // applied again as replayed
+        record.clone().apply(&mut root);
+        record.apply(&mut root);
+        assert_eq!((id_of(&root, "a"), id_of(&root, "b")), (b, a));
+        assert_eq!(root.lookup("a").unwrap().name(), "a");
+    }
+}
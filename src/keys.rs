@@ -0,0 +1,404 @@
+use std::env;
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::io::{BufRead, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::{thread_rng, RngCore};
+
+use crate::agent::{Agent, AgentError};
+use crate::crypt::{self, MasterKey, KEY_LENGTH, NONCE_LENGTH};
+use crate::meta::{Decode, Encode, MetaError, Reader};
+
+const SALT_LENGTH: usize = 16;
+/// Context deriving the wrapping key from a key file.
+const KEY_FILE_CONTEXT: &str = "eoss-fuse 2021-05 key file";
+/// Variable naming the directory systemd passes credentials in.
+const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+/// Service secrets are stored under in the OS keyring.
+const KEYRING_SERVICE: &str = "eoss-fuse";
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("no key slot is unlocked by the key given")]
+    NoMatchingSlot,
+    #[error(
+        "invalid key source {0}, expected passphrase, env:<var>, file:<path>, \
+         credential:<name>, keyring:<name> or ssh-agent:<comment>"
+    )]
+    InvalidSource(String),
+    #[error("environment variable {0} is not set")]
+    MissingVariable(String),
+    #[error("no systemd credentials passed")]
+    NoCredentials,
+    #[error("key derivation failed: {0}")]
+    KdfError(String),
+    #[error("no secret {0} in the keyring")]
+    NotInKeyring(String),
+    #[error(transparent)]
+    AgentError(#[from] AgentError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// Where the secret unlocking a filesystem comes from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum KeySource {
+    /// A passphrase typed at the terminal.
+    Prompt,
+    /// A passphrase in an environment variable.
+    Env(String),
+    /// A key file, whose whole content is the secret.
+    File(PathBuf),
+    /// A key file passed as a systemd credential.
+    Credential(String),
+    /// A passphrase in the OS keyring, the Secret Service or the Keychain.
+    Keyring(String),
+    /// A secret derived from an ed25519 key held by the ssh-agent, by its
+    /// comment.
+    Agent(String),
+}
+
+impl FromStr for KeySource {
+    type Err = KeyError;
+
+    /// Parse `passphrase`, `env:<var>`, `file:<path>`, `credential:<name>`,
+    /// `keyring:<name>` or `ssh-agent:<comment>`.
+    fn from_str(s: &str) -> Result<Self, KeyError> {
+        if s == "passphrase" {
+            return Ok(KeySource::Prompt);
+        }
+        match s.split_once(':') {
+            Some(("env", var)) => Ok(KeySource::Env(var.to_owned())),
+            Some(("file", path)) => Ok(KeySource::File(path.into())),
+            Some(("credential", name)) => Ok(KeySource::Credential(name.to_owned())),
+            Some(("keyring", name)) => Ok(KeySource::Keyring(name.to_owned())),
+            Some(("ssh-agent", comment)) => Ok(KeySource::Agent(comment.to_owned())),
+            _ => Err(KeyError::InvalidSource(s.to_owned())),
+        }
+    }
+}
+
+impl KeySource {
+    /// Read the secret.
+    pub fn load(&self) -> Result<Secret, KeyError> {
+        match self {
+            KeySource::Prompt => Ok(Secret::Passphrase(prompt("passphrase: ")?)),
+            KeySource::Env(var) => match env::var(var) {
+                Ok(passphrase) => Ok(Secret::Passphrase(passphrase.into_bytes())),
+                Err(_) => Err(KeyError::MissingVariable(var.clone())),
+            },
+            KeySource::File(path) => Ok(Secret::KeyFile(fs::read(path)?)),
+            KeySource::Credential(name) => {
+                let dir = env::var_os(CREDENTIALS_DIRECTORY).ok_or(KeyError::NoCredentials)?;
+                Ok(Secret::KeyFile(fs::read(PathBuf::from(dir).join(name))?))
+            }
+            KeySource::Keyring(name) => Ok(Secret::Passphrase(keyring(name)?)),
+            KeySource::Agent(comment) => Ok(Secret::KeyFile(Agent::connect()?.secret(comment)?)),
+        }
+    }
+}
+
+/// Look up secret `name` in the OS keyring through its command line tool,
+/// stored e.g. by `secret-tool store --label=eoss-fuse service eoss-fuse
+/// account <name>`.
+fn keyring(name: &str) -> Result<Vec<u8>, KeyError> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args(&[
+            "find-generic-password",
+            "-w",
+            "-s",
+            KEYRING_SERVICE,
+            "-a",
+            name,
+        ]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(&["lookup", "service", KEYRING_SERVICE, "account", name]);
+        command
+    };
+    let mut output = command.output()?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(KeyError::NotInKeyring(name.to_owned()));
+    }
+    if output.stdout.last() == Some(&b'\n') {
+        output.stdout.pop();
+    }
+    Ok(output.stdout)
+}
+
+/// Read a line from the terminal without echoing it.
+#[cfg(unix)]
+fn prompt(message: &str) -> io::Result<Vec<u8>> {
+    let mut tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")?;
+    tty.write_all(message.as_bytes())?;
+    let fd = tty.as_raw_fd();
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let saved = termios;
+    termios.c_lflag &= !libc::ECHO;
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    let mut line = Vec::new();
+    let result = io::BufReader::new(&tty).read_until(b'\n', &mut line);
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    tty.write_all(b"\n")?;
+    result?;
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+/// Refuse to prompt, the console of Windows is not switched from echoing.
+#[cfg(windows)]
+fn prompt(_message: &str) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cannot prompt for a passphrase on Windows, use env:<var> or file:<path>",
+    ))
+}
+
+/// A secret unlocking key slots, wiped on drop.
+pub enum Secret {
+    Passphrase(Vec<u8>),
+    KeyFile(Vec<u8>),
+}
+
+impl Secret {
+    fn kind(&self) -> SlotKind {
+        match self {
+            Secret::Passphrase(_) => SlotKind::Passphrase,
+            Secret::KeyFile(_) => SlotKind::KeyFile,
+        }
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        match self {
+            Secret::Passphrase(secret) | Secret::KeyFile(secret) => crypt::wipe(secret),
+        }
+    }
+}
+
+/// How a key slot derives the key wrapping the master key.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SlotKind {
+    /// Derived by argon2id, slow to guess.
+    Passphrase,
+    /// Derived by blake3 from a key file of enough entropy.
+    KeyFile,
+}
+
+/// Cost of argon2id deriving the key of a passphrase slot.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 << 10,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// KeySlot holds the master key wrapped by a key derived from one secret,
+/// so a filesystem can be unlocked in several ways, each revoked alone.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeySlot {
+    pub kind: SlotKind,
+    params: KdfParams,
+    salt: [u8; SALT_LENGTH],
+    nonce: [u8; NONCE_LENGTH],
+    wrapped: Vec<u8>,
+}
+
+impl KeySlot {
+    /// Wrap `key` with `secret`, derived with `params` if a passphrase.
+    pub fn new(key: &MasterKey, secret: &Secret, params: KdfParams) -> Result<Self, KeyError> {
+        let mut slot = Self {
+            kind: secret.kind(),
+            params,
+            salt: [0; SALT_LENGTH],
+            nonce: [0; NONCE_LENGTH],
+            wrapped: Vec::new(),
+        };
+        thread_rng().fill_bytes(&mut slot.salt);
+        thread_rng().fill_bytes(&mut slot.nonce);
+        let cipher = slot.cipher(secret)?;
+        let payload = Payload {
+            msg: key.as_bytes(),
+            aad: &slot.salt,
+        };
+        slot.wrapped = cipher
+            .encrypt(XNonce::from_slice(&slot.nonce), payload)
+            .expect("key within the length limit");
+        Ok(slot)
+    }
+
+    /// The master key, if `secret` unlocks this slot.
+    pub fn unwrap_key(&self, secret: &Secret) -> Result<Option<MasterKey>, KeyError> {
+        if secret.kind() != self.kind {
+            return Ok(None);
+        }
+        let payload = Payload {
+            msg: &self.wrapped,
+            aad: &self.salt,
+        };
+        let mut key = match self
+            .cipher(secret)?
+            .decrypt(XNonce::from_slice(&self.nonce), payload)
+        {
+            Ok(key) if key.len() == KEY_LENGTH => key,
+            _ => return Ok(None),
+        };
+        let mut bytes = [0; KEY_LENGTH];
+        bytes.copy_from_slice(&key);
+        crypt::wipe(&mut key);
+        Ok(Some(MasterKey::new(bytes)))
+    }
+
+    /// This slot wrapping `key` instead, if `secret` unlocks it.
+    pub fn rewrap(&self, key: &MasterKey, secret: &Secret) -> Result<Option<Self>, KeyError> {
+        match self.unwrap_key(secret)? {
+            Some(_) => Ok(Some(Self::new(key, secret, self.params)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn cipher(&self, secret: &Secret) -> Result<XChaCha20Poly1305, KeyError> {
+        let mut wrapping = [0; KEY_LENGTH];
+        match secret {
+            Secret::Passphrase(passphrase) => {
+                let params = Params::new(
+                    self.params.memory_kib,
+                    self.params.iterations,
+                    self.params.parallelism,
+                    Some(KEY_LENGTH),
+                )
+                .map_err(|e| KeyError::KdfError(e.to_string()))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase, &self.salt, &mut wrapping)
+                    .map_err(|e| KeyError::KdfError(e.to_string()))?;
+            }
+            Secret::KeyFile(data) => {
+                let mut material = self.salt.to_vec();
+                material.extend_from_slice(data);
+                wrapping = blake3::derive_key(KEY_FILE_CONTEXT, &material);
+                crypt::wipe(&mut material);
+            }
+        }
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&wrapping));
+        crypt::wipe(&mut wrapping);
+        Ok(cipher)
+    }
+}
+
+/// The master key unlocked by `secret` from any of `slots`.
+pub fn unlock(slots: &[KeySlot], secret: &Secret) -> Result<MasterKey, KeyError> {
+    for slot in slots {
+        if let Some(key) = slot.unwrap_key(secret)? {
+            return Ok(key);
+        }
+    }
+    Err(KeyError::NoMatchingSlot)
+}
+
+impl Encode for KeySlot {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let kind: u8 = match self.kind {
+            SlotKind::Passphrase => 0,
+            SlotKind::KeyFile => 1,
+        };
+        kind.encode(buf);
+        self.params.memory_kib.encode(buf);
+        self.params.iterations.encode(buf);
+        self.params.parallelism.encode(buf);
+        buf.extend_from_slice(&self.salt);
+        buf.extend_from_slice(&self.nonce);
+        self.wrapped.encode(buf);
+    }
+}
+
+impl Decode for KeySlot {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let kind = match u8::decode(reader)? {
+            0 => SlotKind::Passphrase,
+            1 => SlotKind::KeyFile,
+            tag => return Err(MetaError::InvalidTag(tag)),
+        };
+        let params = KdfParams {
+            memory_kib: Decode::decode(reader)?,
+            iterations: Decode::decode(reader)?,
+            parallelism: Decode::decode(reader)?,
+        };
+        let mut salt = [0; SALT_LENGTH];
+        salt.copy_from_slice(reader.take(SALT_LENGTH)?);
+        let mut nonce = [0; NONCE_LENGTH];
+        nonce.copy_from_slice(reader.take(NONCE_LENGTH)?);
+        Ok(Self {
+            kind,
+            params,
+            salt,
+            nonce,
+            wrapped: Decode::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unlock, KdfParams, KeyError, KeySlot, KeySource, Secret};
+    use crate::crypt::MasterKey;
+    use crate::meta;
+
+    #[test]
+    fn test_key_slots() {
+        let key = MasterKey::new_random();
+        let cheap = KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let passphrase = || Secret::Passphrase(b"correct horse".to_vec());
+        let key_file = || Secret::KeyFile(vec![7; 64]);
+        let slots = vec![
+            KeySlot::new(&key, &passphrase(), cheap).unwrap(),
+            KeySlot::new(&key, &key_file(), cheap).unwrap(),
+        ];
+        let slots: Vec<KeySlot> = meta::decode(&meta::encode(&slots)).unwrap();
+        for secret in [passphrase(), key_file()].iter() {
+            let unlocked = unlock(&slots, secret).unwrap();
+            assert_eq!(unlocked.as_bytes(), key.as_bytes());
+        }
+        assert!(matches!(
+            unlock(&slots, &Secret::Passphrase(b"battery staple".to_vec())),
+            Err(KeyError::NoMatchingSlot)
+        ));
+        assert_eq!(
+            "env:EOSS_PASSPHRASE".parse::<KeySource>().unwrap(),
+            KeySource::Env("EOSS_PASSPHRASE".to_owned())
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use crate::chunk::CHUNK_SIZE;
+use crate::fs::Entry;
+use crate::id::Id;
+use crate::nfs;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+/// Extended attribute listing the ids of the chunks of a file in order, in
+/// hex one per line, or the id of the shared chunk holding a tiny file.
+/// Lists the first `CHUNKS_PER_XATTR` of them, the next ones are listed by
+/// `user.eoss.chunks.1` and so on, empty past the last chunk.
+/// None of these attributes is listed, so tools copying extended attributes
+/// along with files leave them out.
+pub const CHUNKS_XATTR: &str = "user.eoss.chunks";
+/// Most chunk ids listed by an extended attribute, kept below the largest
+/// value the kernel takes.
+pub const CHUNKS_PER_XATTR: usize = 1000;
+/// Extended attribute holding the BLAKE3 hash of the content of a file,
+/// in hex.
+pub const CHECKSUM_XATTR: &str = "user.eoss.checksum";
+/// Extended attribute holding the generation of an entry, derived from its
+/// id like the generation of its NFS file handle. It changes once the entry
+/// is replaced, as by a copy out of a snapshot, not once modified.
+pub const GENERATION_XATTR: &str = "user.eoss.generation";
+
+/// Ids of the chunks of the file at `entry` in order, or of the shared
+/// chunk holding a tiny file, `None` if a directory.
+pub fn chunk_ids(entry: Entry) -> Option<Vec<Id>> {
+    match entry {
+        Entry::File(file) => Some(
+            (0..file.chunk_count())
+                .map(|n| file.chunk_id(n).into_id())
+                .collect(),
+        ),
+        Entry::TinyFile(file) if file.chunk_blocks > 0 => Some(vec![file.chunk_id.clone()]),
+        Entry::TinyFile(_) => Some(Vec::new()),
+        Entry::Dir(_) => None,
+    }
+}
+
+/// Page of chunk ids listed by the extended attribute `name`, see
+/// `CHUNKS_XATTR`.
+pub fn chunks_page(name: &str) -> Option<usize> {
+    match name.strip_prefix(CHUNKS_XATTR)? {
+        "" => Some(0),
+        page => page
+            .strip_prefix('.')?
+            .parse()
+            .ok()
+            .filter(|&page| page > 0),
+    }
+}
+
+/// Ids listed by page `page` of `CHUNKS_XATTR` of `entry`, `None` if a
+/// directory, whose attribute of the name counts the chunks beneath it.
+pub fn chunks(entry: Entry, page: usize) -> Option<String> {
+    let ids = chunk_ids(entry)?;
+    let listed = ids.iter().skip(page.saturating_mul(CHUNKS_PER_XATTR));
+    Some(
+        listed
+            .take(CHUNKS_PER_XATTR)
+            .map(|id| format!("{}\n", id))
+            .collect(),
+    )
+}
+
+/// Hash of the content of the file at `entry` read from `provider`, `None`
+/// if a directory.
+pub fn checksum(
+    entry: Entry,
+    provider: &dyn ChunkProvider,
+) -> Result<Option<String>, ChunkProviderError> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = match entry {
+            Entry::File(file) => file.read(provider, offset, &mut buf)?,
+            Entry::TinyFile(file) => file.read(provider, offset, &mut buf)?,
+            Entry::Dir(_) => return Ok(None),
+        };
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        offset += n as u64;
+    }
+    Ok(Some(hex::encode(hasher.finalize().as_bytes())))
+}
+
+/// Generation of `entry`, see `GENERATION_XATTR`.
+pub fn generation(entry: Entry) -> u64 {
+    nfs::handle(entry.id()).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, chunks, chunks_page, generation, CHUNKS_XATTR};
+    use crate::allocator::TinyFileAllocator;
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fs::{Attrs, DirMeta, Entry, FileMeta, TinyFileMeta};
+    use crate::id::Id;
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_layout() {
+        let provider = MemoryProvider::new();
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        let data = vec![7; CHUNK_SIZE + 10];
+        file.write(&provider, 0, &data).unwrap();
+        let listed = chunks(Entry::File(&file), 0).unwrap();
+        let expected = format!("{}\n{}\n", file.chunk_id(0), file.chunk_id(1));
+        assert_eq!(listed, expected);
+        assert_eq!(chunks(Entry::File(&file), 1).unwrap(), "");
+        assert_eq!(chunks_page(CHUNKS_XATTR), Some(0));
+        assert_eq!(chunks_page("user.eoss.chunks.2"), Some(2));
+        assert_eq!(chunks_page("user.eoss.chunks.0"), None);
+        assert_eq!(chunks_page("user.eoss.chunksx"), None);
+        let hash = hex::encode(blake3::hash(&data).as_bytes());
+        assert_eq!(checksum(Entry::File(&file), &provider).unwrap(), Some(hash));
+
+        let mut tiny = TinyFileMeta::new("tiny".to_owned(), Attrs::new(0o644, 0, 0));
+        assert_eq!(chunks(Entry::TinyFile(&tiny), 0).unwrap(), "");
+        tiny.write(&provider, &mut TinyFileAllocator::new(), 0, b"tiny")
+            .unwrap();
+        assert_eq!(
+            chunks(Entry::TinyFile(&tiny), 0).unwrap(),
+            format!("{}\n", tiny.chunk_id)
+        );
+        let hash = hex::encode(blake3::hash(b"tiny").as_bytes());
+        assert_eq!(
+            checksum(Entry::TinyFile(&tiny), &provider).unwrap(),
+            Some(hash)
+        );
+
+        let dir = DirMeta::new("dir".to_owned(), Attrs::new(0o755, 0, 0));
+        assert_eq!(chunks(Entry::Dir(&dir), 0), None);
+        assert_eq!(
+            generation(Entry::File(&file)),
+            generation(Entry::File(&file.clone()))
+        );
+        assert_ne!(generation(Entry::File(&file)), generation(Entry::Dir(&dir)));
+    }
+}
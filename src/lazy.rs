@@ -0,0 +1,357 @@
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::chunk::{Block, ChunkError, BLOCK_PER_CHUNK, BLOCK_SIZE, CHUNK_SIZE};
+use crate::fs::FileMeta;
+use crate::id::Id;
+use crate::merkle::{self, UNHASHED};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::providers::cached::CachedProvider;
+
+/// Chunks opened lazily kept at most, most recently read first.
+pub const LAZY_CHUNKS: usize = 16;
+
+/// LazyChunk opens a chunk without reading the whole of it, fetching the
+/// blocks read from the provider on demand, a run of blocks missing in one
+/// range read. Blocks are checked against the outboard of the chunk, if
+/// stored and matching its hash, otherwise the chunk is read whole and
+/// checked against its hash.
+pub struct LazyChunk {
+    id: Id,
+    hash: [u8; 32],
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Blocks fetched and checked so far
+    blocks: Vec<Option<Block>>,
+    /// Chaining values of the blocks, `None` if not stored
+    outboard: Option<Vec<[u8; 32]>>,
+}
+
+impl LazyChunk {
+    /// Open chunk `id` last hashed as `hash`, fetching its outboard. The
+    /// chunk is read whole if the outboard cannot be fetched.
+    pub fn open(provider: &dyn ChunkProvider, id: Id, hash: [u8; 32]) -> Self {
+        let outboard = provider
+            .get_object(&merkle::outboard_id(&id))
+            .ok()
+            .flatten()
+            .as_deref()
+            .and_then(merkle::decode_outboard)
+            .filter(|outboard| merkle::outboard_hash(outboard) == hash);
+        Self {
+            id,
+            hash,
+            state: Mutex::new(State {
+                blocks: vec![None; BLOCK_PER_CHUNK],
+                outboard,
+            }),
+        }
+    }
+
+    /// Number of blocks fetched so far.
+    #[cfg(test)]
+    pub fn fetched(&self) -> usize {
+        self.state
+            .lock()
+            .blocks
+            .iter()
+            .filter(|b| b.is_some())
+            .count()
+    }
+
+    /// Read from `offset` into `buf`, fetching the blocks not fetched yet.
+    /// Returns the number of bytes read.
+    pub fn read_at(
+        &self,
+        provider: &dyn ChunkProvider,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, ChunkProviderError> {
+        if offset >= CHUNK_SIZE {
+            return Ok(0);
+        }
+        let end = min(CHUNK_SIZE, offset + buf.len());
+        let mut state = self.state.lock();
+        self.fetch(
+            provider,
+            &mut state,
+            offset / BLOCK_SIZE..(end + BLOCK_SIZE - 1) / BLOCK_SIZE,
+        )?;
+        let mut read = 0;
+        while offset + read < end {
+            let block = state.blocks[(offset + read) / BLOCK_SIZE].as_ref().unwrap();
+            let block_offset = (offset + read) % BLOCK_SIZE;
+            let read_in = min(BLOCK_SIZE - block_offset, end - offset - read);
+            buf[read..read + read_in].copy_from_slice(&block[block_offset..block_offset + read_in]);
+            read += read_in;
+        }
+        Ok(read)
+    }
+
+    /// Data of the whole chunk, fetching the blocks left.
+    pub fn data(&self, provider: &dyn ChunkProvider) -> Result<Vec<u8>, ChunkProviderError> {
+        let mut data = vec![0; CHUNK_SIZE];
+        self.read_at(provider, 0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Fetch blocks `span` not fetched yet, all of them without an
+    /// outboard.
+    fn fetch(
+        &self,
+        provider: &dyn ChunkProvider,
+        state: &mut State,
+        span: Range<usize>,
+    ) -> Result<(), ChunkProviderError> {
+        let span = match state.outboard {
+            Some(_) => span,
+            None => 0..BLOCK_PER_CHUNK,
+        };
+        let mut n = span.start;
+        while n < span.end {
+            if state.blocks[n].is_some() {
+                n += 1;
+                continue;
+            }
+            let start = n;
+            while n < span.end && state.blocks[n].is_none() {
+                n += 1;
+            }
+            let fetched = provider.get_blocks(&self.id, start..n)?;
+            if fetched.len() != n - start {
+                return Err(ChunkError::InvalidLength(fetched.len() * BLOCK_SIZE).into());
+            }
+            self.check(state, start, &fetched)?;
+            for (slot, block) in state.blocks[start..n].iter_mut().zip(fetched) {
+                *slot = Some(block);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check `blocks` fetched from the `start`th against the outboard, or
+    /// the whole chunk against its hash.
+    fn check(
+        &self,
+        state: &State,
+        start: usize,
+        blocks: &[Block],
+    ) -> Result<(), ChunkProviderError> {
+        let intact = match &state.outboard {
+            Some(outboard) => blocks
+                .iter()
+                .enumerate()
+                .all(|(i, block)| merkle::block_cv(start + i, &block[..]) == outboard[start + i]),
+            None => {
+                let mut data = Vec::with_capacity(CHUNK_SIZE);
+                for block in blocks {
+                    data.extend_from_slice(&block[..]);
+                }
+                merkle::chunk_hash(&data) == self.hash
+            }
+        };
+        match intact {
+            true => Ok(()),
+            false => Err(ChunkProviderError::IntegrityError(self.id.clone())),
+        }
+    }
+}
+
+/// LazyChunks keeps the chunks opened lazily across reads, so blocks are
+/// fetched once.
+#[derive(Default)]
+pub struct LazyChunks {
+    chunks: Mutex<VecDeque<Arc<LazyChunk>>>,
+}
+
+impl LazyChunks {
+    /// Chunk `id` hashed as `hash`, opened unless kept already.
+    pub fn open(&self, provider: &dyn ChunkProvider, id: &Id, hash: [u8; 32]) -> Arc<LazyChunk> {
+        if let Some(chunk) = self.find(id, hash) {
+            return chunk;
+        }
+        let chunk = Arc::new(LazyChunk::open(provider, id.clone(), hash));
+        let mut chunks = self.chunks.lock();
+        chunks.push_front(chunk.clone());
+        chunks.truncate(LAZY_CHUNKS);
+        chunk
+    }
+
+    /// Chunk `id` hashed as `hash` if kept, no longer kept.
+    pub fn take(&self, id: &Id, hash: [u8; 32]) -> Option<Arc<LazyChunk>> {
+        let mut chunks = self.chunks.lock();
+        let pos = chunks.iter().position(|c| c.id == *id && c.hash == hash)?;
+        chunks.remove(pos)
+    }
+
+    fn find(&self, id: &Id, hash: [u8; 32]) -> Option<Arc<LazyChunk>> {
+        let chunk = self.take(id, hash)?;
+        self.chunks.lock().push_front(chunk.clone());
+        Some(chunk)
+    }
+}
+
+/// Read `file` from `offset` into `buf` like `FileMeta::read`, opening its
+/// chunks lazily through `lazies` so only the blocks read are fetched.
+/// Chunks held by the chunk cache `cached` are read from memory, those
+/// written since last hashed are read whole.
+/// Returns the number of bytes read.
+pub fn read_file(
+    file: &FileMeta,
+    provider: &dyn ChunkProvider,
+    cached: Option<&CachedProvider>,
+    lazies: &LazyChunks,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<usize, ChunkProviderError> {
+    if offset >= file.attrs.size {
+        return Ok(0);
+    }
+    let end = min(file.attrs.size, offset + buf.len() as u64);
+    let mut pos = offset;
+    while pos < end {
+        let n = (pos / CHUNK_SIZE as u64) as usize;
+        let chunk_offset = (pos % CHUNK_SIZE as u64) as usize;
+        let len = min(CHUNK_SIZE - chunk_offset, (end - pos) as usize);
+        let start = (pos - offset) as usize;
+        let out = &mut buf[start..start + len];
+
+        let id = file.chunk_id(n).into_id();
+        let hash = file.hashes.get(n).copied().unwrap_or(UNHASHED);
+        match cached.and_then(|cached| cached.resident(&id)) {
+            Some(data) => out.copy_from_slice(&data[chunk_offset..chunk_offset + len]),
+            None if hash == UNHASHED => {
+                file.read(provider, pos, out)?;
+            }
+            None => {
+                lazies
+                    .open(provider, &id, hash)
+                    .read_at(provider, chunk_offset, out)?;
+            }
+        }
+        pos += len as u64;
+    }
+    Ok((end - offset) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{read_file, LazyChunks};
+    use crate::chunk::{Block, Chunk, BLOCK_PER_CHUNK, BLOCK_SIZE, CHUNK_SIZE};
+    use crate::fs::{Attrs, FileMeta};
+    use crate::id::Id;
+    use crate::merkle;
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::memory::MemoryProvider;
+
+    /// Counts the blocks fetched.
+    #[derive(Default)]
+    struct Ranges {
+        inner: MemoryProvider,
+        blocks: AtomicUsize,
+    }
+
+    impl ChunkProvider for Ranges {
+        fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+            self.blocks.fetch_add(BLOCK_PER_CHUNK, Ordering::Relaxed);
+            self.inner.get_chunk_by_id(id)
+        }
+
+        fn get_blocks(
+            &self,
+            id: &Id,
+            blocks: Range<usize>,
+        ) -> Result<Vec<Block>, ChunkProviderError> {
+            self.blocks.fetch_add(blocks.len(), Ordering::Relaxed);
+            self.inner.get_blocks(id, blocks)
+        }
+
+        fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+            self.inner.save_chunk(chunk)
+        }
+
+        fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+            self.inner.get_object(id)
+        }
+
+        fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+            self.inner.save_object(id, data)
+        }
+
+        fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+            self.inner.contains_chunk(id)
+        }
+
+        fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+            self.inner.delete_chunk(id)
+        }
+    }
+
+    #[test]
+    fn test_lazy_chunk() {
+        let provider = Ranges::default();
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+        file.write(&provider.inner, 0, &data).unwrap();
+        merkle::update(&mut file, &provider.inner, None).unwrap();
+        let count = || provider.blocks.swap(0, Ordering::Relaxed);
+
+        let lazies = LazyChunks::default();
+        let mut buf = vec![0; BLOCK_SIZE];
+        // across two blocks
+        let offset = 3 * BLOCK_SIZE as u64 - 10;
+        read_file(&file, &provider, None, &lazies, offset, &mut buf).unwrap();
+        assert_eq!((buf[9], buf[10]), (2, 3));
+        assert_eq!(count(), 2);
+        // kept across reads
+        read_file(&file, &provider, None, &lazies, offset + 10, &mut buf).unwrap();
+        assert_eq!(count(), 0);
+        let chunk = lazies
+            .take(&file.chunk_id(0).into_id(), file.hashes[0])
+            .unwrap();
+        assert_eq!(chunk.fetched(), 2);
+        assert_eq!(chunk.data(&provider).unwrap(), data[..CHUNK_SIZE]);
+        assert_eq!(count(), BLOCK_PER_CHUNK - 2);
+
+        // a block changed behind the filesystem's back
+        let id = file.chunk_id(1).into_id();
+        let tampered = provider.inner.get_chunk_by_id(&id).unwrap();
+        tampered.write_at(7 * BLOCK_SIZE, &[0xff]);
+        provider.inner.save_chunk(&tampered).unwrap();
+        let offset = CHUNK_SIZE as u64 + 7 * BLOCK_SIZE as u64;
+        assert!(matches!(
+            read_file(&file, &provider, None, &lazies, offset, &mut buf),
+            Err(ChunkProviderError::IntegrityError(_))
+        ));
+        assert_eq!(count(), 1);
+
+        // read whole without its outboard
+        provider
+            .inner
+            .delete_chunk(&merkle::outboard_id(&id))
+            .unwrap();
+        let lazies = LazyChunks::default();
+        assert!(matches!(
+            read_file(&file, &provider, None, &lazies, offset + 1, &mut buf[..1]),
+            Err(ChunkProviderError::IntegrityError(_))
+        ));
+        assert_eq!(count(), BLOCK_PER_CHUNK);
+    }
+}
@@ -0,0 +1,228 @@
+#[cfg(windows)]
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::chunk::CHUNK_SIZE;
+use crate::id::{ChunkId, Id, Meta};
+use crate::meta::{self, Decode, Encode, MetaError, Reader};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum LeaseError {
+    #[error("mounted read-write by {0}, whose lease expires in {1:?}")]
+    Held(String, Duration),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+}
+
+impl From<ChunkProviderError> for LeaseError {
+    fn from(err: ChunkProviderError) -> Self {
+        Self::MetaError(err.into())
+    }
+}
+
+/// The mount holding the lease, and until when unless renewed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Holder {
+    name: String,
+    expires: SystemTime,
+}
+
+impl Encode for Holder {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.expires.encode(buf);
+    }
+}
+
+impl Decode for Holder {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            name: Decode::decode(reader)?,
+            expires: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// Name of this process as a holder, `<host>:<pid>`.
+pub fn holder() -> String {
+    format!("{}:{}", hostname(), process::id())
+}
+
+/// Name of this host.
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    match unsafe { libc::gethostname(name.as_mut_ptr() as *mut _, name.len()) } {
+        0 => {
+            let length = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..length]).into_owned()
+        }
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// Name of this host.
+#[cfg(windows)]
+fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_owned())
+}
+
+/// Id of the chunk holding the lease on the filesystem whose superblock is
+/// stored at `superblock_id`.
+pub fn lease_id(superblock_id: &Id) -> ChunkId<Meta> {
+    ChunkId::derive_labeled(superblock_id, b"lease")
+}
+
+/// Lease on mounting a filesystem read-write, a chunk in the clear next to
+/// its superblock naming the holder, which has to renew it within `ttl`
+/// for other hosts to keep off.
+pub struct Lease {
+    provider: Arc<dyn ChunkProvider>,
+    id: ChunkId<Meta>,
+    holder: String,
+    ttl: Duration,
+}
+
+impl Lease {
+    /// Acquire the lease on the filesystem whose superblock is stored at
+    /// `superblock_id` as `holder`, unless held by another one.
+    /// `provider` has to be beneath any layer sealing or signing chunks.
+    pub fn acquire(
+        provider: Arc<dyn ChunkProvider>,
+        superblock_id: &Id,
+        holder: String,
+        ttl: Duration,
+    ) -> Result<Self, LeaseError> {
+        let lease = Self {
+            provider,
+            id: lease_id(superblock_id),
+            holder,
+            ttl,
+        };
+        lease.renew()?;
+        Ok(lease)
+    }
+
+    /// Extend the lease by `ttl` from now, failing if another holder took
+    /// it over once expired.
+    pub fn renew(&self) -> Result<(), LeaseError> {
+        let id = meta::chunk_id(&self.id, 0);
+        let generation = self.provider.generation(&id)?;
+        let now = SystemTime::now();
+        if let Some(holder) = self.load()? {
+            let left = holder.expires.duration_since(now).unwrap_or_default();
+            if holder.name != self.holder && left > Duration::ZERO {
+                return Err(LeaseError::Held(holder.name, left));
+            }
+        }
+        let mut data = meta::encode(&Holder {
+            name: self.holder.clone(),
+            expires: now + self.ttl,
+        });
+        data.resize(CHUNK_SIZE, 0);
+        // fails if another holder got in since loaded
+        self.provider.save_object_if(&id, &data, generation)?;
+        Ok(())
+    }
+
+    /// Give up the lease if still held.
+    pub fn release(&self) -> Result<(), LeaseError> {
+        if let Some(holder) = self.load()? {
+            if holder.name == self.holder {
+                self.provider.delete_chunk(&meta::chunk_id(&self.id, 0))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn load(&self) -> Result<Option<Holder>, MetaError> {
+        match meta::load(self.provider.as_ref(), &self.id) {
+            Ok(holder) => Ok(Some(holder)),
+            Err(MetaError::BadMagic) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // left to expire if it cannot be released
+        let _ = self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::inode::FUSE_ROOT_ID;
+
+    use super::{Lease, LeaseError};
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::ChunkProvider;
+    use crate::providers::local::LocalProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::{SuperblockError, SUPERBLOCK_ID};
+
+    #[test]
+    fn test_lease() {
+        let base = env::temp_dir().join(format!("eoss-lease-{}", Id::new_random().hex()));
+        let local: Arc<dyn ChunkProvider> = Arc::new(LocalProvider::new(&base).unwrap());
+        let memory: Arc<dyn ChunkProvider> = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        let ttl = Duration::from_secs(60);
+        for provider in vec![memory, local.clone()] {
+            let first = Lease::acquire(provider.clone(), &id, "a:1".to_owned(), ttl).unwrap();
+            first.renew().unwrap();
+            assert!(matches!(
+                Lease::acquire(provider.clone(), &id, "b:1".to_owned(), ttl),
+                Err(LeaseError::Held(holder, _)) if holder == "a:1"
+            ));
+            drop(first);
+
+            // an expired lease is taken over
+            let expired = Lease::acquire(provider.clone(), &id, "b:1".to_owned(), Duration::ZERO);
+            let expired = expired.unwrap();
+            let third = Lease::acquire(provider.clone(), &id, "c:1".to_owned(), ttl).unwrap();
+            assert!(matches!(expired.renew(), Err(LeaseError::Held(_, _))));
+            // not released by a former holder
+            drop(expired);
+            third.renew().unwrap();
+        }
+
+        // mounted read-write on local storage, held against other hosts
+        EossFs::format(local.as_ref(), &FormatOptions::default()).unwrap();
+        let mut fs = EossFs::open(local.clone(), MountOptions::default(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, b"data").unwrap();
+        assert!(matches!(
+            Lease::acquire(local.clone(), &id, "other:1".to_owned(), ttl),
+            Err(LeaseError::Held(holder, _)) if holder == super::holder()
+        ));
+        fs.close().unwrap();
+        drop(fs);
+        let mut fs = EossFs::open(local.clone(), MountOptions::default(), &id).unwrap();
+        fs.lookup_entry(FUSE_ROOT_ID, "file").unwrap();
+        fs.close().unwrap();
+        drop(fs);
+        let other = Lease::acquire(local.clone(), &id, "other:1".to_owned(), ttl).unwrap();
+        assert!(matches!(
+            EossFs::open(local, MountOptions::default(), &id),
+            Err(SuperblockError::LeaseError(LeaseError::Held(_, _)))
+        ));
+        drop(other);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+// only vfs, options, provider and winfsp are the embedding API, the hidden
+// modules are public for the binary alone
+
+// what only FUSE requests reach, extended attributes, ioctls and the stats
+// files, is unused by the WinFsp frontend
+#![cfg_attr(windows, allow(dead_code))]
+
+#[cfg(all(target_os = "macos", not(feature = "macos")))]
+compile_error!("building on macOS requires the macos feature");
+
+#[cfg(target_os = "linux")]
+mod admin;
+mod agent;
+mod allocator;
+mod branch;
+mod bridge;
+mod chunk;
+mod chunkcache;
+mod coalesce;
+mod compact;
+mod compression;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod crypt;
+#[cfg(unix)]
+#[doc(hidden)]
+pub mod daemon;
+mod dedup;
+mod dirindex;
+#[cfg(unix)]
+mod dispatch;
+mod events;
+#[doc(hidden)]
+pub mod export;
+mod diskcache;
+#[cfg(feature = "cdylib")]
+mod ffi;
+mod fetcher;
+mod fs;
+#[cfg(unix)]
+#[doc(hidden)]
+pub mod fstab;
+#[doc(hidden)]
+pub mod fsck;
+#[doc(hidden)]
+pub mod fuse;
+mod gc;
+mod governor;
+mod health;
+mod heatmap;
+mod id;
+mod import;
+mod inode;
+mod inspect;
+mod invalidate;
+mod journal;
+#[doc(hidden)]
+pub mod keys;
+mod layout;
+mod lazy;
+mod lease;
+#[cfg(unix)]
+mod lock;
+#[cfg(all(feature = "macos", target_os = "macos"))]
+mod macos;
+mod merkle;
+mod meta;
+mod metacache;
+#[doc(hidden)]
+pub mod migrate;
+mod nfs;
+#[cfg(unix)]
+#[doc(hidden)]
+pub mod ninep;
+pub mod options;
+mod pin;
+pub mod provider;
+#[doc(hidden)]
+pub mod providers;
+mod publish;
+#[doc(hidden)]
+pub mod quota;
+mod readahead;
+mod recovery;
+mod refresh;
+mod rekey;
+#[doc(hidden)]
+pub mod rng;
+mod runtime;
+#[doc(hidden)]
+pub mod s3;
+mod scrub;
+#[cfg(unix)]
+#[doc(hidden)]
+pub mod sftp;
+#[doc(hidden)]
+pub mod sign;
+mod snapshot;
+mod stats;
+mod stream;
+#[doc(hidden)]
+pub mod superblock;
+#[doc(hidden)]
+pub mod tenant;
+mod tier;
+mod trace;
+mod trash;
+mod uri;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod versions;
+pub mod vfs;
+mod warmup;
+#[cfg(windows)]
+mod win;
+#[cfg(any(test, all(windows, feature = "winfsp")))]
+mod winattr;
+#[cfg(all(windows, feature = "winfsp"))]
+pub mod winfsp;
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use libc::{F_UNLCK, F_WRLCK};
+
+/// A POSIX byte-range lock, `flock` is a lock over the whole file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Lock {
+    pub owner: u64,
+    pub pid: u32,
+    /// First byte of the range
+    pub start: u64,
+    /// Last byte of the range, inclusive
+    pub end: u64,
+    /// One of `F_RDLCK`, `F_WRLCK` and `F_UNLCK`
+    pub typ: i32,
+}
+
+impl Lock {
+    fn overlaps(&self, other: &Lock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other)
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// LockTable holds locks of all inodes in memory.
+///
+/// Locks are per mount: they are not stored with the provider, so other
+/// mounts of the same filesystem neither see nor honour them.
+#[derive(Default)]
+pub struct LockTable {
+    locks: HashMap<u64, Vec<Lock>>,
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Find a lock held on inode `ino` conflicting with `lock`.
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        if lock.typ == F_UNLCK {
+            return None;
+        }
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|held| held.conflicts(lock))
+            .copied()
+    }
+
+    /// Acquire, convert or release (with `F_UNLCK`) `lock` on inode `ino`.
+    /// Returns the conflicting lock if it cannot be acquired.
+    pub fn set(&mut self, ino: u64, lock: Lock) -> Result<(), Lock> {
+        if let Some(conflict) = self.conflict(ino, &lock) {
+            return Err(conflict);
+        }
+        let held = self.locks.entry(ino).or_default();
+        let mut kept = Vec::with_capacity(held.len() + 2);
+        for old in held.drain(..) {
+            if old.owner != lock.owner || !old.overlaps(&lock) {
+                kept.push(old);
+                continue;
+            }
+            // keep the parts of the old lock outside of the new range
+            if old.start < lock.start {
+                kept.push(Lock {
+                    end: lock.start - 1,
+                    ..old
+                });
+            }
+            if old.end > lock.end {
+                kept.push(Lock {
+                    start: lock.end + 1,
+                    ..old
+                });
+            }
+        }
+        if lock.typ != F_UNLCK {
+            kept.push(lock);
+        }
+        if kept.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *held = kept;
+        }
+        Ok(())
+    }
+
+    /// Release all locks of `owner` on inode `ino`, e.g. when the file is closed.
+    pub fn release(&mut self, ino: u64, owner: u64) {
+        if let Some(held) = self.locks.get_mut(&ino) {
+            held.retain(|lock| lock.owner != owner);
+            if held.is_empty() {
+                self.locks.remove(&ino);
+            }
+        }
+    }
+
+    /// Ranges held by `owner` on inode `ino`.
+    #[cfg(test)]
+    fn held(&self, ino: u64, owner: u64) -> Vec<(u64, u64, i32)> {
+        let mut held: Vec<_> = self
+            .locks
+            .get(&ino)
+            .map(|locks| {
+                locks
+                    .iter()
+                    .filter(|l| l.owner == owner)
+                    .map(|l| (l.start, l.end, l.typ))
+                    .collect()
+            })
+            .unwrap_or_default();
+        held.sort_unstable();
+        held
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lock, LockTable};
+    use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> Lock {
+        Lock {
+            owner,
+            pid: owner as u32,
+            start,
+            end,
+            typ,
+        }
+    }
+
+    #[test]
+    fn test_shared_and_exclusive() {
+        let mut table = LockTable::new();
+        table.set(1, lock(1, 0, 99, F_RDLCK)).unwrap();
+        table.set(1, lock(2, 50, 149, F_RDLCK)).unwrap();
+        let conflict = table.set(1, lock(3, 90, 200, F_WRLCK)).unwrap_err();
+        assert_ne!(conflict.owner, 3);
+        // other inodes are not affected
+        table.set(2, lock(3, 90, 200, F_WRLCK)).unwrap();
+        assert!(table.conflict(1, &lock(3, 150, 200, F_WRLCK)).is_none());
+    }
+
+    #[test]
+    fn test_split_on_unlock() {
+        let mut table = LockTable::new();
+        table.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+        table.set(1, lock(1, 40, 59, F_UNLCK)).unwrap();
+        assert_eq!(table.held(1, 1), vec![(0, 39, F_WRLCK), (60, 99, F_WRLCK)]);
+        table.set(1, lock(2, 40, 59, F_WRLCK)).unwrap();
+        table.release(1, 1);
+        assert!(table.held(1, 1).is_empty());
+        assert!(table.set(1, lock(3, 0, 39, F_WRLCK)).is_ok());
+    }
+}
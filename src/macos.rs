@@ -0,0 +1,102 @@
+use fuser::MountOption;
+pub use libc::ENOATTR;
+
+use crate::options::{MacBackend, MacOptions};
+
+/// Flags of fallocate as Linux numbers them, which macFUSE forwards as is.
+pub const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+/// Flags of renamex_np, RENAME_SWAP and RENAME_EXCL.
+pub const RENAME_EXCHANGE: u32 = 0x02;
+pub const RENAME_NOREPLACE: u32 = 0x04;
+
+/// Prefix of the AppleDouble files Finder keeps extended attributes and
+/// resource forks in.
+const APPLE_DOUBLE_PREFIX: &str = "._";
+
+/// Whether a file opened with `flags` bypasses the page cache, never as
+/// macOS has no O_DIRECT but sets F_NOCACHE with fcntl after opening.
+pub fn opened_direct(_flags: i32) -> bool {
+    false
+}
+
+/// Whether `name` is of an AppleDouble file refused unless
+/// `MacOptions::apple_double`.
+pub fn refused(options: &MacOptions, name: &str) -> bool {
+    !options.apple_double && name.starts_with(APPLE_DOUBLE_PREFIX)
+}
+
+/// Options of mounting with the backend of `options`.
+pub fn mount_options(options: &MacOptions) -> Vec<MountOption> {
+    let mut mount = vec![
+        MountOption::FSName("eoss".to_owned()),
+        MountOption::CUSTOM(format!("volname={}", options.volume_name)),
+    ];
+    match options.backend {
+        // the kernel refuses AppleDouble files and com.apple xattrs itself
+        MacBackend::MacFuse if !options.apple_double => mount.extend(vec![
+            MountOption::CUSTOM("noappledouble".to_owned()),
+            MountOption::CUSTOM("noapplexattr".to_owned()),
+        ]),
+        // fuse-t passes them through, they are refused by `refused`
+        MacBackend::MacFuse | MacBackend::FuseT => {}
+    }
+    mount
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use fuser::MountOption;
+
+    use super::{mount_options, refused};
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::options::{FormatOptions, MacBackend, MacOptions, MountOptions};
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+
+    #[test]
+    fn test_mount_options() {
+        let noappledouble = MountOption::CUSTOM("noappledouble".to_owned());
+        let options = MacOptions::default();
+        assert!(refused(&options, "._file"));
+        assert!(!refused(&options, "file"));
+        assert!(mount_options(&options).contains(&noappledouble));
+        let options = MacOptions {
+            backend: MacBackend::FuseT,
+            apple_double: true,
+            ..MacOptions::default()
+        };
+        assert!(!refused(&options, "._file"));
+        assert!(!mount_options(&options).contains(&noappledouble));
+    }
+
+    /// Mount with fuse-t, the backend installable on CI runners.
+    #[test]
+    #[ignore]
+    fn test_mount() {
+        let provider = Arc::new(MemoryProvider::new());
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            mac: MacOptions {
+                backend: MacBackend::FuseT,
+                ..MacOptions::default()
+            },
+            ..MountOptions::default()
+        };
+        let fs = EossFs::open(provider, options, &Id::new(SUPERBLOCK_ID)).unwrap();
+        let mountpoint = std::env::temp_dir().join(format!("eoss-{}", std::process::id()));
+        fs::create_dir_all(&mountpoint).unwrap();
+        let session = fs.mount(&mountpoint).unwrap();
+
+        fs::write(mountpoint.join("file"), b"hello").unwrap();
+        assert_eq!(fs::read(mountpoint.join("file")).unwrap(), b"hello");
+        assert!(fs::write(mountpoint.join("._file"), b"").is_err());
+        assert!(fs::metadata(mountpoint.join("._file")).is_err());
+        drop(session);
+        fs::remove_dir(&mountpoint).unwrap();
+    }
+}
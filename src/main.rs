@@ -1,5 +1,1150 @@
-mod provider;
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+#[cfg(unix)]
+use std::net::TcpListener;
+use std::ops::Range;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process;
+#[cfg(unix)]
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+#[cfg(unix)]
+use parking_lot::Mutex;
+use tracing_subscriber::EnvFilter;
+
+use eoss_fuse::crypt::MasterKey;
+#[cfg(unix)]
+use eoss_fuse::daemon::{self, Pidfile, Signal, Signals};
+use eoss_fuse::export::{DirSink, TarSink};
+use eoss_fuse::fuse::EossFs;
+#[cfg(unix)]
+use eoss_fuse::fuse::Reloader;
+use eoss_fuse::keys::{self, KdfParams, KeySlot, KeySource};
+use eoss_fuse::migrate::{self, MigrateOptions};
+#[cfg(all(feature = "macos", target_os = "macos"))]
+use eoss_fuse::options::MacBackend;
+use eoss_fuse::options::{Discard, FormatOptions, MountOptions};
+use eoss_fuse::provider::{ChunkProvider, Id, ProviderUri};
+use eoss_fuse::providers::signed::SignedProvider;
+use eoss_fuse::quota::Quota;
+#[cfg(feature = "seeded")]
+use eoss_fuse::rng;
+use eoss_fuse::sign::{SignError, SigningKey};
+use eoss_fuse::superblock::{Superblock, SUPERBLOCK_ID};
+#[cfg(any(unix, feature = "winfsp"))]
+use eoss_fuse::vfs::Vfs;
+#[cfg(all(windows, feature = "winfsp"))]
+use eoss_fuse::winfsp::{self, WinFs};
+#[cfg(unix)]
+use eoss_fuse::{control, fstab, ninep, s3, sftp};
+use eoss_fuse::{fsck, tenant};
+
+const AFTER_HELP: &str = "<provider> is a directory, local://<dir> or another provider URI,
+tiered://<provider>;<class>=<provider>... storing chunks by their storage class.
+<source> is passphrase, env:<var>, file:<path>, credential:<name>,
+keyring:<name> or ssh-agent:<comment>,
+commands on an encrypted filesystem not mounted take it from $EOSS_KEY,
+on a signed one the signing key file from $EOSS_SIGNING_KEY.
+$EOSS_LOG filters what is logged to stderr, info by default.
+$EOSS_SEED seeds the ids generated, for reproducible tests, if built with
+the seeded feature.";
+
+/// Variable holding the key source of commands without `--key`.
+const KEY_VAR: &str = "EOSS_KEY";
+/// Variable holding the signing key file of commands without `--signing-key`.
+const SIGNING_KEY_VAR: &str = "EOSS_SIGNING_KEY";
+/// Variable holding the log filter, e.g. `debug` or `eoss_fuse=trace`.
+const LOG_VAR: &str = "EOSS_LOG";
+/// Variable holding the seed of ids generated, a number.
+#[cfg(feature = "seeded")]
+const SEED_VAR: &str = "EOSS_SEED";
+
+type Result<T = ()> = std::result::Result<T, Box<dyn Error>>;
 
 fn main() {
-    println!("Hello, world!");
+    let filter = EnvFilter::try_from_env(LOG_VAR).unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+    let args: Vec<String> = env::args().collect();
+    // run by mount(8) through a link named mount.eoss
+    #[cfg(unix)]
+    let args = if Path::new(&args[0]).file_name() == Some(fstab::HELPER_NAME.as_ref()) {
+        match fstab::mount_args(&args[1..]) {
+            Ok(Some(args)) => args,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        args
+    };
+    if let Err(e) = run(&app().get_matches_from(args)) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    let provider = || {
+        Arg::with_name("provider")
+            .required(true)
+            .help("Where the chunks are stored")
+    };
+    let arg = |name| Arg::with_name(name).required(true);
+    let option = |name| Arg::with_name(name).long(name).takes_value(true);
+    let command = |name| SubCommand::with_name(name).arg(provider());
+    // opening an existing filesystem, the main one or a tenant's
+    let filesystem = |name| command(name).arg(option("tenant").value_name("name"));
+    let group = |name| SubCommand::with_name(name).setting(AppSettings::SubcommandRequiredElseHelp);
+    App::new("eoss-fuse")
+        .version(clap::crate_version!())
+        .about("A FUSE filesystem storing its data in chunks")
+        .after_help(AFTER_HELP)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            command("format")
+                .about("Create an empty filesystem")
+                .arg(Arg::with_name("force").long("force"))
+                .arg(option("key").value_name("source"))
+                .arg(
+                    Arg::with_name("convergent")
+                        .long("convergent")
+                        .requires("key"),
+                )
+                .arg(option("signing-key").value_name("key-file"))
+                .arg(Arg::with_name("content-addressed").long("content-addressed"))
+                .arg(Arg::with_name("compressed").long("compressed")),
+        )
+        .subcommand(
+            filesystem("fsck")
+                .about("Check the filesystem")
+                .arg(Arg::with_name("repair").long("repair")),
+        )
+        .subcommand(
+            filesystem("mount")
+                .about("Mount the filesystem until unmounted")
+                .arg(arg("mountpoint"))
+                .arg(option("snapshot").value_name("name"))
+                .arg(option("disk-cache").value_name("cache-dir"))
+                .arg(option("warmup").value_name("manifest"))
+                .arg(option("warmup-chunks").value_name("chunks"))
+                .arg(option("spool").value_name("spool-dir"))
+                .arg(option("key").value_name("source"))
+                .arg(option("signing-key").value_name("key-file"))
+                .arg(option("verifying-key").value_name("hex"))
+                .arg(option("min-sequence").value_name("sequence"))
+                .arg(option("max-signature-age").value_name("seconds"))
+                .arg(option("chunk-cache-bytes").value_name("bytes"))
+                .arg(option("parallel-reads").value_name("reads"))
+                .arg(Arg::with_name("read-only").long("read-only"))
+                .arg(Arg::with_name("write-back").long("write-back"))
+                .arg(Arg::with_name("detect-conflicts").long("detect-conflicts"))
+                .arg(option("gc-interval").value_name("seconds"))
+                .arg(option("scrub-interval").value_name("seconds"))
+                .arg(option("refresh-interval").value_name("seconds"))
+                .arg(option("lease-ttl").value_name("seconds"))
+                .arg(option("publish").value_name("uri"))
+                .arg(
+                    option("compress")
+                        .value_name("ext=compression")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Compress files by extension, such as log=zstd:19 or mp4=none"),
+                )
+                .arg(option("trash-ttl").value_name("seconds"))
+                .arg(option("discard").possible_values(&["eager", "deferred"]))
+                .arg(Arg::with_name("daemon").long("daemon"))
+                .arg(option("pidfile").value_name("file"))
+                .arg(option("config").value_name("file"))
+                .arg(option("control").value_name("socket"))
+                .arg(option("slow-op-ms").value_name("millis"))
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Load everything and report, without mounting nor writing"),
+                )
+                .arg(
+                    Arg::with_name("verify")
+                        .long("verify")
+                        .help("Mount read-only, checking every chunk and signature on access"),
+                )
+                .arg(
+                    Arg::with_name("lazy-chunks").long("lazy-chunks").help(
+                        "Fetch only the blocks of chunks read, checked against their outboard",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("heatmap")
+                        .long("heatmap")
+                        .help("Count accesses of files and chunks, exported by the control socket"),
+                )
+                .arg(
+                    Arg::with_name("nfs-export")
+                        .long("nfs-export")
+                        .help("Keep inode numbers stable across mounts, to re-export over NFS"),
+                )
+                .arg(
+                    option("volume-name")
+                        .value_name("name")
+                        .help("Name of the volume in Finder, on macOS"),
+                )
+                .arg(
+                    Arg::with_name("fuse-t")
+                        .long("fuse-t")
+                        .help("Mount with fuse-t instead of macFUSE, on macOS"),
+                )
+                .arg(
+                    Arg::with_name("apple-double")
+                        .long("apple-double")
+                        .help("Allow AppleDouble ._ files, on macOS"),
+                ),
+        )
+        .subcommand(
+            filesystem("serve-9p")
+                .about("Serve the filesystem over 9P2000.L on a socket until stopped")
+                .arg(arg("socket"))
+                .arg(option("key").value_name("source"))
+                .arg(option("signing-key").value_name("key-file"))
+                .arg(option("config").value_name("file")),
+        )
+        .subcommand(
+            filesystem("serve-s3")
+                .about("Serve the files read-only as objects of an S3 bucket until stopped")
+                .arg(arg("address").help("Address to listen on, such as 127.0.0.1:9000"))
+                .arg(
+                    option("bucket")
+                        .value_name("name")
+                        .default_value("eoss")
+                        .help("Name of the bucket"),
+                )
+                .arg(option("key").value_name("source"))
+                .arg(option("signing-key").value_name("key-file"))
+                .arg(option("config").value_name("file")),
+        )
+        .subcommand(
+            filesystem("serve-sftp")
+                .about("Serve the filesystem over SFTP on a socket until stopped, to sftp-server")
+                .arg(arg("socket"))
+                .arg(option("key").value_name("source"))
+                .arg(option("signing-key").value_name("key-file"))
+                .arg(option("config").value_name("file")),
+        )
+        .subcommand(
+            SubCommand::with_name("sftp-server")
+                .about("Relay sshd's sftp subsystem on stdin and stdout to serve-sftp's socket")
+                .arg(arg("socket")),
+        )
+        .subcommand(
+            SubCommand::with_name("umount")
+                .about("Unmount a mounted filesystem")
+                .arg(arg("mountpoint")),
+        )
+        .subcommand(
+            SubCommand::with_name("control")
+                .about("Send a command to the control socket of a mount")
+                .arg(arg("socket"))
+                .arg(
+                    arg("command").multiple(true).help(
+                        "stats, flush, drop-caches, pin <path>, snapshot create <name> or gc",
+                    ),
+                ),
+        )
+        .subcommand(filesystem("stats").about("Print the usage of the filesystem"))
+        .subcommand(filesystem("gc").about("Delete unreferenced chunks"))
+        .subcommand(filesystem("scrub").about("Verify chunks against their hashes"))
+        .subcommand(
+            filesystem("leaks")
+                .about("Report space referenced by nothing")
+                .arg(Arg::with_name("delete").long("delete")),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Copy the chunks of a provider to another")
+                .arg(arg("from"))
+                .arg(arg("to"))
+                .arg(
+                    Arg::with_name("live")
+                        .long("live")
+                        .help("Only the chunks referenced by the filesystem"),
+                )
+                .arg(option("threads").value_name("count"))
+                .arg(option("checkpoint").value_name("file"))
+                .arg(option("tenant").value_name("name"))
+                .arg(Arg::with_name("no-verify").long("no-verify")),
+        )
+        .subcommand(
+            filesystem("import")
+                .about("Copy a directory tree into the filesystem, not mounted")
+                .arg(arg("source"))
+                .arg(arg("path")),
+        )
+        .subcommand(
+            filesystem("export")
+                .about("Copy files out of the filesystem, not mounted")
+                .arg(arg("path"))
+                .arg(arg("dest").help("A directory, or a tar file with --tar, - for stdout"))
+                .arg(Arg::with_name("tar").long("tar")),
+        )
+        .subcommand(
+            filesystem("inspect")
+                .about("Print what a chunk holds and what references it")
+                .arg(
+                    arg("target")
+                        .help("The hex id of a chunk or a prefix of it, or a path for its chunks"),
+                )
+                .arg(
+                    option("dump")
+                        .value_name("blocks")
+                        .help("Hexdump a range of blocks of 4 KiB, e.g. 0..2"),
+                ),
+        )
+        .subcommand(
+            filesystem("clone")
+                .about("Clone a directory")
+                .arg(arg("from"))
+                .arg(arg("to")),
+        )
+        .subcommand(
+            group("sign").subcommand(
+                SubCommand::with_name("keygen")
+                    .about("Write a new signing key, printing its verifying key")
+                    .arg(arg("key-file")),
+            ),
+        )
+        .subcommand(
+            group("key")
+                .about("Manage the keys of an encrypted filesystem")
+                .subcommand(filesystem("list"))
+                .subcommand(filesystem("add").arg(arg("source")).arg(arg("new-source")))
+                .subcommand(filesystem("remove").arg(arg("source")).arg(arg("slot")))
+                .subcommand(
+                    filesystem("rotate")
+                        .about("Seal under a new master key, rewrapped for the slots of a source")
+                        .arg(arg("source")),
+                )
+                .subcommand(filesystem("rekey")),
+        )
+        .subcommand(
+            group("snapshot")
+                .about("Manage snapshots")
+                .subcommand(filesystem("create").arg(arg("name")))
+                .subcommand(filesystem("list"))
+                .subcommand(filesystem("delete").arg(arg("name"))),
+        )
+        .subcommand(
+            group("tenant")
+                .about("Manage filesystems sharing the provider, each with its own root")
+                .subcommand(
+                    command("create")
+                        .arg(arg("name"))
+                        .arg(option("key").value_name("source"))
+                        .arg(option("quota-bytes").value_name("bytes"))
+                        .arg(option("quota-inodes").value_name("count")),
+                )
+                .subcommand(command("list"))
+                .subcommand(command("delete").arg(arg("name"))),
+        )
+        .subcommand(
+            group("trash")
+                .about("Manage deleted entries")
+                .subcommand(filesystem("list"))
+                .subcommand(filesystem("restore").arg(arg("name")))
+                .subcommand(filesystem("purge").arg(Arg::with_name("seconds").default_value("0"))),
+        )
+        .subcommand(
+            group("quota")
+                .about("Manage directory quotas")
+                .subcommand(filesystem("get").arg(arg("path")))
+                .subcommand(
+                    filesystem("set")
+                        .arg(arg("path"))
+                        .arg(arg("bytes"))
+                        .arg(arg("inodes")),
+                ),
+        )
+        .subcommand(
+            group("versions")
+                .about("Manage previous versions of files")
+                .subcommand(filesystem("keep").arg(arg("path")).arg(arg("count")))
+                .subcommand(filesystem("list").arg(arg("path")))
+                .subcommand(filesystem("restore").arg(arg("path")).arg(arg("name"))),
+        )
+}
+
+fn run(matches: &ArgMatches) -> Result {
+    let (name, args) = matches.subcommand();
+    let args = args.expect("subcommand required");
+    let value = |name| args.value_of(name).expect("required argument");
+    #[cfg(feature = "seeded")]
+    if let Ok(seed) = env::var(SEED_VAR) {
+        rng::seed(Some(seed.parse()?));
+    }
+    match name {
+        "format" => format(args),
+        "fsck" => check(
+            value("provider"),
+            &superblock_id(args),
+            args.is_present("repair"),
+        ),
+        "mount" => mount(args, mount_options(args)?),
+        #[cfg(unix)]
+        "serve-9p" => serve_9p(args, mount_options(args)?),
+        #[cfg(unix)]
+        "serve-s3" => serve_s3(args, mount_options(args)?),
+        #[cfg(unix)]
+        "serve-sftp" => serve_sftp(args, mount_options(args)?),
+        #[cfg(unix)]
+        "sftp-server" => Ok(sftp::relay(Path::new(value("socket")))?),
+        #[cfg(unix)]
+        "umount" => umount(value("mountpoint")),
+        #[cfg(unix)]
+        "control" => {
+            let words: Vec<String> = args.values_of("command").unwrap().map(From::from).collect();
+            println!("{}", control::request(Path::new(value("socket")), &words)?);
+            Ok(())
+        }
+        #[cfg(windows)]
+        "serve-9p" | "serve-s3" | "serve-sftp" | "sftp-server" | "umount" | "control" => {
+            Err(format!("{} is not supported on Windows", name).into())
+        }
+        "stats" => offline(value("provider"), &superblock_id(args), stats),
+        "gc" => offline(value("provider"), &superblock_id(args), |fs| {
+            let stats = fs.gc()?;
+            println!(
+                "{} chunks referenced, {} deleted",
+                stats.referenced, stats.deleted
+            );
+            Ok(())
+        }),
+        "scrub" => offline(value("provider"), &superblock_id(args), |fs| {
+            let stats = fs.scrub()?;
+            for id in stats.corrupt.iter() {
+                println!("corrupt chunk {}", id.hex());
+            }
+            println!("{}", stats);
+            Ok(())
+        }),
+        "leaks" => offline(value("provider"), &superblock_id(args), |fs| {
+            report_leaks(fs, args.is_present("delete"))
+        }),
+        "migrate" => migrate(args),
+        "inspect" => offline(value("provider"), &superblock_id(args), |fs| {
+            let blocks = match args.value_of("dump") {
+                Some(range) => Some(block_range(range)?),
+                None => None,
+            };
+            for report in fs.inspect(&value("target").parse()?, blocks)? {
+                println!("{}", report);
+            }
+            Ok(())
+        }),
+        "export" => offline(value("provider"), &superblock_id(args), |fs| {
+            let path = split(value("path"));
+            let stats = match (args.is_present("tar"), value("dest")) {
+                (true, "-") => fs.export(&path, &mut TarSink::new(std::io::stdout().lock()))?,
+                (true, dest) => {
+                    let out = BufWriter::new(File::create(dest)?);
+                    fs.export(&path, &mut TarSink::new(out))?
+                }
+                (false, dest) => fs.export(&path, &mut DirSink::new(dest.into())?)?,
+            };
+            eprintln!(
+                "{} directories, {} files, {} bytes exported",
+                stats.dirs, stats.files, stats.bytes
+            );
+            Ok(())
+        }),
+        "import" => offline(value("provider"), &superblock_id(args), |fs| {
+            let stats = fs.import(Path::new(value("source")), &split(value("path")))?;
+            println!(
+                "{} directories, {} files, {} tiny files, {} bytes imported, {} skipped",
+                stats.dirs, stats.files, stats.tiny_files, stats.bytes, stats.skipped
+            );
+            Ok(())
+        }),
+        "clone" => offline(value("provider"), &superblock_id(args), |fs| {
+            Ok(fs.clone_dir(&split(value("from")), &split(value("to")))?)
+        }),
+        "sign" => match args.subcommand() {
+            ("keygen", Some(args)) => keygen(args.value_of("key-file").unwrap()),
+            _ => unreachable!(),
+        },
+        "key" => key(args),
+        "snapshot" => snapshot(args),
+        "tenant" => tenant(args),
+        "trash" => trash(args),
+        "quota" => quota(args),
+        "versions" => versions(args),
+        _ => unreachable!(),
+    }
+}
+
+fn format(args: &ArgMatches) -> Result {
+    let uri: ProviderUri = args.value_of("provider").unwrap().parse()?;
+    let mut options = FormatOptions {
+        force: args.is_present("force"),
+        convergent: args.is_present("convergent"),
+        content_addressed: args.is_present("content-addressed"),
+        compressed: args.is_present("compressed"),
+        ..FormatOptions::default()
+    };
+    if let Some(source) = args.value_of("key") {
+        new_key(&mut options, source)?;
+    }
+    if let Some(file) = args.value_of("signing-key") {
+        options.signing_key = Some(signing_key(file)?);
+    }
+    let superblock = EossFs::format(uri.open()?.as_ref(), &options)?;
+    println!("formatted {}, uuid {}", uri, hex::encode(superblock.uuid));
+    Ok(())
+}
+
+/// Encrypt the filesystem formatted with `options` with a new master key,
+/// unlocked by the secret from `source`.
+fn new_key(options: &mut FormatOptions, source: &str) -> Result {
+    let key = MasterKey::new_random();
+    let secret = source.parse::<KeySource>()?.load()?;
+    options.key_slots = vec![KeySlot::new(&key, &secret, KdfParams::default())?];
+    options.key = Some(key);
+    Ok(())
+}
+
+fn migrate(args: &ArgMatches) -> Result {
+    let uri = args.value_of("from").unwrap();
+    let mut options = MigrateOptions {
+        checkpoint: args.value_of("checkpoint").map(From::from),
+        verify: !args.is_present("no-verify"),
+        ..MigrateOptions::default()
+    };
+    if let Some(threads) = args.value_of("threads") {
+        options.threads = threads.parse()?;
+    }
+    let from = provider(uri)?;
+    let listed = from.list_chunks()?;
+    let ids = match args.is_present("live") {
+        true => {
+            let mut live = HashSet::new();
+            offline(uri, &superblock_id(args), |fs| {
+                live = fs.live_chunks()?;
+                Ok(())
+            })?;
+            let mut ids: Vec<Id> = live.into_iter().collect();
+            // metadata never written is not stored
+            if let Some(listed) = listed {
+                let listed: HashSet<Id> = listed.into_iter().collect();
+                ids.retain(|id| listed.contains(id));
+            }
+            ids
+        }
+        false => listed.ok_or(migrate::MigrateError::Unlistable)?,
+    };
+    let stats = migrate::migrate(from, provider(args.value_of("to").unwrap())?, ids, &options)?;
+    println!(
+        "{} chunks copied, {} already copied, {} missing",
+        stats.copied, stats.resumed, stats.missing
+    );
+    Ok(())
+}
+
+/// Write a new signing key to `file`, readable by its owner only, and print
+/// the verifying key to publish.
+fn keygen(file: &str) -> Result {
+    let key = SigningKey::new_random();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(file)?.write_all(&key.to_bytes())?;
+    println!("{}", key.verifying_key());
+    Ok(())
+}
+
+fn signing_key(file: &str) -> Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&std::fs::read(file)?)?)
+}
+
+/// The signing key in the file named by the environment, if any.
+fn env_signing_key() -> Result<Option<SigningKey>> {
+    match env::var(SIGNING_KEY_VAR) {
+        Ok(file) => Ok(Some(signing_key(&file)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn check(uri: &str, superblock_id: &Id, repair: bool) -> Result {
+    let report = fsck::fsck(provider(uri)?.as_ref(), superblock_id, repair)?;
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    println!(
+        "{} directories, {} files, {} chunks, {} problems{}",
+        report.dirs,
+        report.files,
+        report.chunks,
+        report.problems.len(),
+        if report.repaired { " repaired" } else { "" },
+    );
+    if !report.repaired && !report.is_clean() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Mount options from the flags of `mount`.
+fn mount_options(args: &ArgMatches) -> Result<MountOptions> {
+    let mut options = MountOptions {
+        snapshot: args.value_of("snapshot").map(str::to_owned),
+        disk_cache: args.value_of("disk-cache").map(Into::into),
+        warmup: args.value_of("warmup").map(Into::into),
+        spool: args.value_of("spool").map(Into::into),
+        read_only: args.is_present("read-only"),
+        write_back: args.is_present("write-back"),
+        detect_conflicts: args.is_present("detect-conflicts"),
+        dry_run: args.is_present("dry-run"),
+        verify: args.is_present("verify"),
+        heatmap: args.is_present("heatmap"),
+        lazy_chunks: args.is_present("lazy-chunks"),
+        nfs_export: args.is_present("nfs-export"),
+        ..MountOptions::default()
+    };
+    if let Some(source) = args.value_of("key") {
+        let uri = args.value_of("provider").unwrap();
+        options.key = Some(unlock(uri, &superblock_id(args), source)?);
+    }
+    if let Some(file) = args.value_of("signing-key") {
+        options.signing_key = Some(signing_key(file)?);
+    }
+    if let Some(key) = args.value_of("verifying-key") {
+        options.verifying_key = Some(key.parse()?);
+    }
+    if let Some(sequence) = args.value_of("min-sequence") {
+        options.min_sequence = sequence.parse()?;
+    }
+    if let Some(secs) = args.value_of("max-signature-age") {
+        options.max_signature_age = Some(Duration::from_secs(secs.parse()?));
+    }
+    if let Some(bytes) = args.value_of("chunk-cache-bytes") {
+        options.chunk_cache_bytes = bytes.parse()?;
+    }
+    if let Some(reads) = args.value_of("parallel-reads") {
+        options.parallel_reads = reads.parse()?;
+    }
+    if let Some(chunks) = args.value_of("warmup-chunks") {
+        options.warmup_chunks = chunks.parse()?;
+    }
+    if let Some(secs) = args.value_of("gc-interval") {
+        options.gc_interval = Some(Duration::from_secs(secs.parse()?));
+    }
+    if let Some(secs) = args.value_of("scrub-interval") {
+        options.scrub_interval = Some(Duration::from_secs(secs.parse()?));
+    }
+    if let Some(secs) = args.value_of("refresh-interval") {
+        options.refresh_interval = Some(Duration::from_secs(secs.parse()?));
+    }
+    if let Some(secs) = args.value_of("lease-ttl") {
+        options.lease_ttl = match secs.parse()? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+    }
+    if let Some(sink) = args.value_of("publish") {
+        options.publish = Some(sink.parse()?);
+    }
+    for rule in args.values_of("compress").into_iter().flatten() {
+        options.compression_rules.push(rule.parse()?);
+    }
+    if let Some(secs) = args.value_of("trash-ttl") {
+        options.trash_ttl = Some(Duration::from_secs(secs.parse()?));
+    }
+    if let Some(discard) = args.value_of("discard") {
+        options.discard = match discard {
+            "eager" => Discard::Eager,
+            _ => Discard::Deferred,
+        };
+    }
+    if let Some(millis) = args.value_of("slow-op-ms") {
+        options.slow_op = Some(Duration::from_millis(millis.parse()?));
+    }
+    #[cfg(all(feature = "macos", target_os = "macos"))]
+    {
+        if let Some(name) = args.value_of("volume-name") {
+            options.mac.volume_name = name.to_owned();
+        }
+        if args.is_present("fuse-t") {
+            options.mac.backend = MacBackend::FuseT;
+        }
+        options.mac.apple_double = args.is_present("apple-double");
+    }
+    if let Some(file) = args.value_of("config") {
+        options.apply_config(&std::fs::read_to_string(file)?)?;
+    }
+    Ok(options)
+}
+
+/// Mount until SIGTERM or SIGINT, in the background with `--daemon`,
+/// reloading the config on SIGHUP.
+#[cfg(unix)]
+fn mount(args: &ArgMatches, mut options: MountOptions) -> Result {
+    let provider = provider(args.value_of("provider").unwrap())?;
+    if options.dry_run {
+        let mut fs = EossFs::open(provider, options, &superblock_id(args))?;
+        print!("{}", fs.health()?);
+        return Ok(());
+    }
+    let ready = if args.is_present("daemon") {
+        Some(daemon::daemonize()?)
+    } else {
+        None
+    };
+    let _pidfile = args.value_of("pidfile").map(Pidfile::create).transpose()?;
+    let mut signals = Signals::install()?;
+    let mut fs = EossFs::open(provider, options.clone(), &superblock_id(args))?;
+    let reloader = fs.reloader();
+    let mailbox = args.value_of("control").map(|_| fs.control());
+    let session = fs.mount(args.value_of("mountpoint").unwrap())?;
+    if let (Some(socket), Some(mailbox)) = (args.value_of("control"), mailbox) {
+        control::serve(Path::new(socket), mailbox)?;
+    }
+    if let Some(ready) = ready {
+        ready.notify()?;
+    }
+    while signals.wait()? == Signal::Reload {
+        if let Some(file) = args.value_of("config") {
+            if let Err(e) = reload(file, &mut options, &reloader) {
+                tracing::error!("failed to reload {}: {}", file, e);
+            }
+        }
+    }
+    // flushed by the filesystem as it is unmounted
+    session.join();
+    if let Some(socket) = args.value_of("control") {
+        let _ = std::fs::remove_file(socket);
+    }
+    Ok(())
+}
+
+/// Mount through WinFsp until Ctrl+C, `--daemon`, `--pidfile` and
+/// `--control` having no effect on Windows.
+#[cfg(all(windows, feature = "winfsp"))]
+fn mount(args: &ArgMatches, options: MountOptions) -> Result {
+    let provider = provider(args.value_of("provider").unwrap())?;
+    if options.dry_run {
+        let mut fs = EossFs::open(provider, options, &superblock_id(args))?;
+        print!("{}", fs.health()?);
+        return Ok(());
+    }
+    let vfs = Vfs::open_at(provider, options, &superblock_id(args))?;
+    let mountpoint = Path::new(args.value_of("mountpoint").unwrap());
+    let mount = WinFs::new(vfs).mount(mountpoint)?;
+    winfsp::wait_for_interrupt()?;
+    Ok(mount.unmount()?)
+}
+
+#[cfg(all(windows, not(feature = "winfsp")))]
+fn mount(_args: &ArgMatches, _options: MountOptions) -> Result {
+    Err("mounting on Windows requires the winfsp feature".into())
+}
+
+/// Serve over 9P until SIGTERM or SIGINT, for clients without FUSE such as
+/// virtual machines.
+#[cfg(unix)]
+fn serve_9p(args: &ArgMatches, options: MountOptions) -> Result {
+    let provider = provider(args.value_of("provider").unwrap())?;
+    let mut signals = Signals::install()?;
+    let fs = EossFs::open(provider, options, &superblock_id(args))?;
+    let fs = Arc::new(Mutex::new(fs));
+    let socket = Path::new(args.value_of("socket").unwrap());
+    ninep::serve(socket, fs.clone())?;
+    while signals.wait()? == Signal::Reload {}
+    let _ = std::fs::remove_file(socket);
+    fs.lock().close()?;
+    Ok(())
+}
+
+/// Serve SFTP sessions until SIGTERM or SIGINT, one filesystem shared by
+/// every sftp-server sshd runs.
+#[cfg(unix)]
+fn serve_sftp(args: &ArgMatches, options: MountOptions) -> Result {
+    let provider = provider(args.value_of("provider").unwrap())?;
+    let mut signals = Signals::install()?;
+    let vfs = Vfs::open_at(provider, options, &superblock_id(args))?;
+    let vfs = Arc::new(Mutex::new(vfs));
+    let socket = Path::new(args.value_of("socket").unwrap());
+    sftp::serve(socket, vfs.clone())?;
+    while signals.wait()? == Signal::Reload {}
+    let _ = std::fs::remove_file(socket);
+    vfs.lock().close()?;
+    Ok(())
+}
+
+/// Serve objects over HTTP until SIGTERM or SIGINT, for applications that
+/// read from S3.
+#[cfg(unix)]
+fn serve_s3(args: &ArgMatches, options: MountOptions) -> Result {
+    let provider = provider(args.value_of("provider").unwrap())?;
+    let mut signals = Signals::install()?;
+    let listener = TcpListener::bind(args.value_of("address").unwrap())?;
+    let options = MountOptions {
+        read_only: true,
+        ..options
+    };
+    let vfs = Vfs::open_at(provider, options, &superblock_id(args))?;
+    let vfs = Arc::new(Mutex::new(vfs));
+    s3::serve(
+        listener,
+        args.value_of("bucket").unwrap().to_owned(),
+        vfs.clone(),
+    );
+    while signals.wait()? == Signal::Reload {}
+    vfs.lock().close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn reload(file: &str, options: &mut MountOptions, reloader: &Reloader) -> Result {
+    options.apply_config(&std::fs::read_to_string(file)?)?;
+    Ok(reloader.reload(options)?)
+}
+
+#[cfg(unix)]
+fn umount(mountpoint: &str) -> Result {
+    let status = if cfg!(target_os = "macos") {
+        Command::new("umount").arg(mountpoint).status()?
+    } else {
+        Command::new("fusermount")
+            .args(&["-u", mountpoint])
+            .status()?
+    };
+    if !status.success() {
+        return Err(format!("failed to unmount {}", mountpoint).into());
+    }
+    Ok(())
+}
+
+fn stats(fs: &mut EossFs) -> Result {
+    let (usage, _) = fs.quota(&[])?;
+    println!("bytes\t{}", usage.bytes);
+    println!("inodes\t{}", usage.inodes);
+    println!("chunks\t{}", usage.chunks);
+    println!("snapshots\t{}", fs.snapshots().len());
+    Ok(())
+}
+
+fn report_leaks(fs: &mut EossFs, delete: bool) -> Result {
+    let report = fs.leaks(delete)?;
+    for id in report.orphans.iter() {
+        println!("orphan chunk {}", id.hex());
+    }
+    for id in report.metadata.iter() {
+        println!("unreachable metadata {}", id.hex());
+    }
+    for slot in report.slots.iter() {
+        println!(
+            "leaked slot {} blocks {}+{}",
+            slot.chunk.hex(),
+            slot.offset,
+            slot.blocks
+        );
+    }
+    if delete {
+        println!("reclaimed");
+    }
+    Ok(())
+}
+
+fn key(args: &ArgMatches) -> Result {
+    let (name, args) = args.subcommand();
+    let args = args.expect("subcommand required");
+    let value = |name| args.value_of(name).expect("required argument");
+    let uri = value("provider");
+    let id = superblock_id(args);
+    match name {
+        "list" => list_slots(uri, &id),
+        "add" => edit_slots(uri, &id, value("source"), |key, slots| {
+            let secret = value("new-source").parse::<KeySource>()?.load()?;
+            slots.push(KeySlot::new(key, &secret, KdfParams::default())?);
+            Ok(())
+        }),
+        "remove" => edit_slots(uri, &id, value("source"), |_, slots| {
+            let slot: usize = value("slot").parse()?;
+            if slot >= slots.len() || slots.len() == 1 {
+                return Err("no such slot, or the last one".into());
+            }
+            slots.remove(slot);
+            Ok(())
+        }),
+        "rotate" => {
+            let secret = value("source").parse::<KeySource>()?.load()?;
+            let superblock = Superblock::load(provider(uri)?.as_ref(), &id)?;
+            let options = MountOptions {
+                key: Some(keys::unlock(&superblock.key_slots, &secret)?),
+                ..offline_options(uri, &id)?
+            };
+            offline_with(uri, &id, options, |fs| {
+                println!("sealing under epoch {}", fs.rotate_key(&secret)?);
+                Ok(())
+            })
+        }
+        "rekey" => offline(uri, &id, |fs| {
+            while !fs.rekey()? {}
+            Ok(())
+        }),
+        _ => unreachable!(),
+    }
+}
+
+fn snapshot(args: &ArgMatches) -> Result {
+    let (name, args) = args.subcommand();
+    let args = args.expect("subcommand required");
+    let value = |name| args.value_of(name).expect("required argument");
+    offline(value("provider"), &superblock_id(args), |fs| match name {
+        "create" => {
+            fs.snapshot(value("name"))?;
+            Ok(())
+        }
+        "list" => {
+            for snapshot in fs.snapshots() {
+                let created = snapshot.created.duration_since(UNIX_EPOCH)?;
+                println!("{}\t{}", snapshot.name, created.as_secs());
+            }
+            Ok(())
+        }
+        "delete" => Ok(fs.delete_snapshot(value("name"))?),
+        _ => unreachable!(),
+    })
+}
+
+fn tenant(args: &ArgMatches) -> Result {
+    let (name, args) = args.subcommand();
+    let args = args.expect("subcommand required");
+    let value = |name| args.value_of(name).expect("required argument");
+    let provider = provider(value("provider"))?;
+    match name {
+        "create" => {
+            let mut options = FormatOptions {
+                signing_key: env_signing_key()?,
+                ..FormatOptions::default()
+            };
+            if let Some(source) = args.value_of("key") {
+                new_key(&mut options, source)?;
+            }
+            if let Some(bytes) = args.value_of("quota-bytes") {
+                options.quota.bytes = bytes.parse()?;
+            }
+            if let Some(inodes) = args.value_of("quota-inodes") {
+                options.quota.inodes = inodes.parse()?;
+            }
+            let superblock = tenant::create(provider.as_ref(), value("name"), &options)?;
+            println!(
+                "created {}, uuid {}",
+                value("name"),
+                hex::encode(superblock.uuid)
+            );
+        }
+        "list" => {
+            for tenant in tenant::list(provider.as_ref())? {
+                let created = tenant.created.duration_since(UNIX_EPOCH)?;
+                println!("{}\t{}", tenant.name, created.as_secs());
+            }
+        }
+        "delete" => {
+            let id = tenant::superblock_id(value("name"));
+            let options = MountOptions {
+                key: match env::var(KEY_VAR) {
+                    Ok(source) => Some(unlock(value("provider"), &id, &source)?),
+                    Err(_) => None,
+                },
+                signing_key: env_signing_key()?,
+                ..MountOptions::default()
+            };
+            let deleted = tenant::delete(provider, value("name"), options)?;
+            println!("{} chunks deleted", deleted);
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Id of the superblock mounted, of the tenant given with `--tenant`.
+fn superblock_id(args: &ArgMatches) -> Id {
+    match args.value_of("tenant") {
+        Some(name) => tenant::superblock_id(name),
+        None => Id::new(SUPERBLOCK_ID),
+    }
+}
+
+fn trash(args: &ArgMatches) -> Result {
+    let (name, args) = args.subcommand();
+    let args = args.expect("subcommand required");
+    let value = |name| args.value_of(name).expect("required argument");
+    offline(value("provider"), &superblock_id(args), |fs| match name {
+        "list" => {
+            for name in fs.trash()? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        "restore" => {
+            println!("restored to /{}", fs.restore(value("name"))?.join("/"));
+            Ok(())
+        }
+        "purge" => {
+            let purged = fs.purge_trash(Duration::from_secs(value("seconds").parse()?))?;
+            println!("{} entries purged", purged);
+            Ok(())
+        }
+        _ => unreachable!(),
+    })
+}
+
+fn quota(args: &ArgMatches) -> Result {
+    let (name, args) = args.subcommand();
+    let args = args.expect("subcommand required");
+    let value = |name| args.value_of(name).expect("required argument");
+    let path = split(value("path"));
+    offline(value("provider"), &superblock_id(args), |fs| match name {
+        "get" => {
+            let (usage, quota) = fs.quota(&path)?;
+            println!("bytes\t{}\t{}", usage.bytes, quota.bytes);
+            println!("inodes\t{}\t{}", usage.inodes, quota.inodes);
+            println!("chunks\t{}", usage.chunks);
+            Ok(())
+        }
+        "set" => {
+            let quota = Quota {
+                bytes: value("bytes").parse()?,
+                inodes: value("inodes").parse()?,
+            };
+            Ok(fs.set_quota(&path, quota)?)
+        }
+        _ => unreachable!(),
+    })
+}
+
+fn versions(args: &ArgMatches) -> Result {
+    let (name, args) = args.subcommand();
+    let args = args.expect("subcommand required");
+    let value = |name| args.value_of(name).expect("required argument");
+    let path = split(value("path"));
+    offline(value("provider"), &superblock_id(args), |fs| match name {
+        "keep" => Ok(fs.set_versions(&path, value("count").parse()?)?),
+        "list" => {
+            for name in fs.versions(&path)? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        "restore" => Ok(fs.restore_version(&path, value("name"))?),
+        _ => unreachable!(),
+    })
+}
+
+/// Open the provider at `uri`.
+fn provider(uri: &str) -> Result<Arc<dyn ChunkProvider>> {
+    Ok(uri.parse::<ProviderUri>()?.open()?)
+}
+
+/// Unlock the master key of the filesystem at `uri` with the secret from
+/// `source`.
+fn unlock(uri: &str, superblock_id: &Id, source: &str) -> Result<MasterKey> {
+    let superblock = Superblock::load(provider(uri)?.as_ref(), superblock_id)?;
+    let secret = source.parse::<KeySource>()?.load()?;
+    Ok(keys::unlock(&superblock.key_slots, &secret)?)
+}
+
+fn list_slots(uri: &str, superblock_id: &Id) -> Result {
+    let superblock = Superblock::load(provider(uri)?.as_ref(), superblock_id)?;
+    for (n, slot) in superblock.key_slots.iter().enumerate() {
+        println!("{}\t{:?}", n, slot.kind);
+    }
+    Ok(())
+}
+
+/// Run `f` on the key slots of the filesystem of `superblock_id` at `uri`,
+/// not mounted, unlocked with the secret from `source`. A signed superblock
+/// is signed again with the key from the environment.
+fn edit_slots(
+    uri: &str,
+    superblock_id: &Id,
+    source: &str,
+    f: impl FnOnce(&MasterKey, &mut Vec<KeySlot>) -> Result,
+) -> Result {
+    let key = unlock(uri, superblock_id, source)?;
+    let provider = provider(uri)?;
+    let mut superblock = Superblock::load(provider.as_ref(), superblock_id)?;
+    f(&key, &mut superblock.key_slots)?;
+    match superblock.signer {
+        Some(_) => {
+            let signing = env_signing_key()?.ok_or(SignError::NoSigningKey)?;
+            superblock.check_signer(Some(&signing.verifying_key()))?;
+            let signed = SignedProvider::with_signing_key(provider.as_ref(), signing);
+            superblock.store(&signed, superblock_id)?;
+        }
+        None => superblock.store(provider.as_ref(), superblock_id)?,
+    }
+    Ok(())
+}
+
+/// Parse a range of blocks, `<first>..<end>` or a single block.
+fn block_range(range: &str) -> Result<Range<usize>> {
+    match range.split_once("..") {
+        Some((start, end)) => Ok(start.parse()?..end.parse()?),
+        None => {
+            let block: usize = range.parse()?;
+            Ok(block..block + 1)
+        }
+    }
+}
+
+/// Split a path inside the filesystem into its components.
+fn split(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Run `f` on the filesystem of `superblock_id` at `uri` without mounting
+/// it.
+fn offline(uri: &str, superblock_id: &Id, f: impl FnOnce(&mut EossFs) -> Result) -> Result {
+    offline_with(uri, superblock_id, offline_options(uri, superblock_id)?, f)
+}
+
+/// Run `f` on the filesystem of `superblock_id` at `uri` opened with
+/// `options`.
+fn offline_with(
+    uri: &str,
+    superblock_id: &Id,
+    options: MountOptions,
+    f: impl FnOnce(&mut EossFs) -> Result,
+) -> Result {
+    let mut fs = EossFs::open(provider(uri)?, options, superblock_id)?;
+    let result = f(&mut fs);
+    fs.close()?;
+    result
+}
+
+/// Options of commands opening the filesystem of `superblock_id` at `uri`
+/// without mounting it, keys taken from the environment.
+fn offline_options(uri: &str, superblock_id: &Id) -> Result<MountOptions> {
+    Ok(MountOptions {
+        key: match env::var(KEY_VAR) {
+            Ok(source) => Some(unlock(uri, superblock_id, &source)?),
+            Err(_) => None,
+        },
+        signing_key: env_signing_key()?,
+        ..MountOptions::default()
+    })
 }
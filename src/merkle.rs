@@ -0,0 +1,228 @@
+use blake3::guts::{self, ChunkState};
+use once_cell::sync::Lazy;
+
+use crate::chunk::{Chunk, BLOCK_PER_CHUNK, BLOCK_SIZE, CHUNK_SIZE};
+use crate::dedup::DedupIndex;
+use crate::fs::FileMeta;
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+/// Extended attribute holding the Merkle root of a file, in hex.
+pub const MERKLE_XATTR: &str = "user.eoss.merkle";
+/// Leaf of a chunk written since last hashed, not verified.
+pub const UNHASHED: [u8; 32] = [0; 32];
+/// Leaf of a chunk of zeros, as a chunk discarded reads.
+pub static ZERO_CHUNK: Lazy<[u8; 32]> = Lazy::new(|| chunk_hash(&vec![0; CHUNK_SIZE]));
+/// Context deriving the hash of an inner node from its children.
+const NODE_CONTEXT: &str = "eoss-fuse 2021-05 merkle node";
+/// Context deriving the id of the outboard of a chunk from its id.
+const OUTBOARD_CONTEXT: &str = "eoss-fuse 2021-05 merkle outboard";
+
+/// Hash of the data of a whole chunk.
+pub fn chunk_hash(data: &[u8]) -> [u8; 32] {
+    *blake3::hash(data).as_bytes()
+}
+
+/// Root of the Merkle tree over `leaves`, an odd node is carried up as is.
+pub fn root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return chunk_hash(&[]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut node = left.to_vec();
+                    node.extend_from_slice(right);
+                    blake3::derive_key(NODE_CONTEXT, &node)
+                }
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Id of the outboard of chunk `id`, the chaining values of its blocks in
+/// the BLAKE3 tree of `chunk_hash`, so a block read alone can be verified.
+pub fn outboard_id(id: &Id) -> Id {
+    Id::new(blake3::derive_key(OUTBOARD_CONTEXT, &**id))
+}
+
+/// Chaining value of `block`, the nth of its chunk, the root of its
+/// subtree in the BLAKE3 tree of the chunk.
+pub fn block_cv(n: usize, block: &[u8]) -> [u8; 32] {
+    let per_block = BLOCK_SIZE / guts::CHUNK_LEN;
+    let leaves = block
+        .chunks(guts::CHUNK_LEN)
+        .enumerate()
+        .map(|(i, data)| {
+            ChunkState::new((n * per_block + i) as u64)
+                .update(data)
+                .finalize(false)
+        })
+        .collect();
+    *subtree(leaves, false).as_bytes()
+}
+
+/// Root of a subtree of the BLAKE3 tree over its `nodes`, a power of two.
+fn subtree(mut nodes: Vec<blake3::Hash>, is_root: bool) -> blake3::Hash {
+    while nodes.len() > 1 {
+        let top = is_root && nodes.len() == 2;
+        nodes = nodes
+            .chunks(2)
+            .map(|pair| guts::parent_cv(&pair[0], &pair[1], top))
+            .collect();
+    }
+    nodes[0]
+}
+
+/// Chaining values of the blocks of chunk `data`, its outboard.
+pub fn outboard(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(BLOCK_SIZE)
+        .enumerate()
+        .map(|(n, block)| block_cv(n, block))
+        .collect()
+}
+
+/// Hash of the chunk of `outboard`, as `chunk_hash` of its data.
+pub fn outboard_hash(outboard: &[[u8; 32]]) -> [u8; 32] {
+    let nodes = outboard.iter().map(|cv| blake3::Hash::from(*cv)).collect();
+    *subtree(nodes, true).as_bytes()
+}
+
+/// Outboard stored as `encoded`, `None` if not one of a whole chunk.
+pub fn decode_outboard(encoded: &[u8]) -> Option<Vec<[u8; 32]>> {
+    if encoded.len() != BLOCK_PER_CHUNK * 32 {
+        return None;
+    }
+    let mut outboard = vec![[0; 32]; BLOCK_PER_CHUNK];
+    for (cv, bytes) in outboard.iter_mut().zip(encoded.chunks_exact(32)) {
+        cv.copy_from_slice(bytes);
+    }
+    Some(outboard)
+}
+
+/// Check `data` of chunk `n` of `file` against the leaf last hashed.
+pub fn verify(file: &FileMeta, n: usize, data: &[u8]) -> Result<(), ChunkProviderError> {
+    match file.hashes.get(n) {
+        Some(hash) if *hash != UNHASHED && *hash != chunk_hash(data) => Err(
+            ChunkProviderError::IntegrityError(file.chunk_id(n).into_id()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Hash chunks of `file` written since last hashed, and store them at their
+/// hash if content addressed, unless stored already, as counted by `dedup`
+/// if given. Their staging chunks are left for the caller to delete once
+/// the leaves are persisted, see `staged`. The outboard of each is stored along, if the
+/// provider stores objects, for their blocks to be read alone.
+/// Returns whether any leaf changed.
+pub fn update(
+    file: &mut FileMeta,
+    provider: &dyn ChunkProvider,
+    mut dedup: Option<&mut DedupIndex>,
+) -> Result<bool, ChunkProviderError> {
+    let mut changed = false;
+    // extended by a truncate
+    if file.hashes.len() < file.chunk_count() {
+        file.hashes.resize(file.chunk_count(), UNHASHED);
+    }
+    let mut data = vec![0; CHUNK_SIZE];
+    for n in 0..file.hashes.len() {
+        if file.hashes[n] == UNHASHED {
+            provider
+                .get_chunk_by_id(file.chunk_id(n).id())?
+                .read_at(0, &mut data);
+            let cvs = outboard(&data);
+            file.hashes[n] = outboard_hash(&cvs);
+            changed = true;
+            // a hole is not stored
+            if file.hole(n) {
+                continue;
+            }
+            let id = file.chunk_id(n).into_id();
+            let first = match (file.content, dedup.as_mut()) {
+                (false, _) => true,
+                (true, Some(dedup)) => dedup.add(&file.hashes[n]),
+                (true, None) => !provider.contains_chunk(&id)?,
+            };
+            if !first {
+                continue;
+            }
+            if file.content {
+                provider.save_chunk(&Chunk::new_with_data(id.clone(), data.clone())?)?;
+            }
+            // blocks are read whole along with their chunk without it
+            let _ = provider.save_object(&outboard_id(&id), &cvs.concat());
+        }
+    }
+    Ok(changed)
+}
+
+/// Staging chunks of `file` moved to their hash by `update` since its
+/// leaves were `before`, even if it failed half way.
+pub fn staged(before: &[[u8; 32]], file: &FileMeta) -> Vec<Id> {
+    (0..file.hashes.len())
+        .filter(|n| before.get(*n).map_or(true, |hash| *hash == UNHASHED) && file.shared(*n))
+        .map(|n| file.staging_id(n).into_id())
+        .collect()
+}
+
+/// Hex of the Merkle root of `file`.
+pub fn root_hex(file: &FileMeta) -> String {
+    root(&file.hashes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_hash, outboard, outboard_hash, root_hex, update, UNHASHED};
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::fs::{Attrs, FileMeta};
+    use crate::id::Id;
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_tamper_detected() {
+        let provider = MemoryProvider::new();
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        let data: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        assert_eq!(
+            outboard_hash(&outboard(&data[..CHUNK_SIZE])),
+            chunk_hash(&data[..CHUNK_SIZE])
+        );
+        file.write(&provider, 0, &data).unwrap();
+        assert_eq!(file.hashes, vec![UNHASHED; 2]);
+        assert!(update(&mut file, &provider, None).unwrap());
+        assert!(!update(&mut file, &provider, None).unwrap());
+        let root = root_hex(&file);
+
+        let mut buf = vec![0; data.len()];
+        file.read(&provider, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // changed behind the filesystem's back
+        let chunk = Chunk::new(file.chunk_id(1).into_id());
+        chunk.write_at(0, &[8; 10]);
+        provider.save_chunk(&chunk).unwrap();
+        assert!(matches!(
+            file.read(&provider, CHUNK_SIZE as u64, &mut buf),
+            Err(ChunkProviderError::IntegrityError(_))
+        ));
+        assert_eq!(root_hex(&file), root);
+    }
+}
@@ -0,0 +1,470 @@
+use std::cmp::min;
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::chunk::CHUNK_SIZE;
+use crate::fs::{Attrs, DirMeta, FileMeta, Node, TinyFileMeta};
+use crate::id::{ChunkId, Id, Meta, ID_LENGTH};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+/// Magic bytes at the beginning of encoded metadata.
+pub const META_MAGIC: &[u8; 8] = b"EOSSMETA";
+/// Current version of the metadata encoding.
+pub const META_VERSION: u16 = 1;
+/// Length of the header: magic, version, payload length and payload checksum.
+pub const HEADER_LENGTH: usize = 8 + 2 + 8 + 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum MetaError {
+    #[error("bad magic")]
+    BadMagic,
+    #[error("unsupported version {0}")]
+    UnsupportedVersion(u16),
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+    #[error("unexpected end of metadata")]
+    Truncated,
+    #[error("invalid utf-8 name")]
+    InvalidName,
+    #[error("invalid tag {0}")]
+    InvalidTag(u8),
+    #[error(transparent)]
+    ProviderError(#[from] ChunkProviderError),
+}
+
+impl MetaError {
+    /// Errno reported by a mount for the error, see
+    /// `ChunkProviderError::errno`.
+    pub fn errno(&self) -> i32 {
+        match self {
+            MetaError::ProviderError(e) => e.errno(),
+            _ => libc::EIO,
+        }
+    }
+}
+
+/// Types with a binary encoding in MetaChunks.
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+/// Types decoded from MetaChunks.
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError>;
+}
+
+/// Reader over encoded metadata.
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], MetaError> {
+        if self.data.len() < n {
+            return Err(MetaError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], MetaError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+macro_rules! impl_int {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes())
+                }
+            }
+
+            impl Decode for $t {
+                fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+                    Ok(<$t>::from_le_bytes(reader.take_array()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64);
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u8).encode(buf)
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(u8::decode(reader)? != 0)
+    }
+}
+
+impl Encode for [u8; ID_LENGTH] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self)
+    }
+}
+
+impl Decode for [u8; ID_LENGTH] {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        reader.take_array()
+    }
+}
+
+impl Encode for Id {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_ref())
+    }
+}
+
+impl Decode for Id {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Id::new(reader.take_array()?))
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        buf.extend_from_slice(self.as_bytes())
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let len = u32::decode(reader)? as usize;
+        let bytes = reader.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| MetaError::InvalidName)
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        self.iter().for_each(|item| item.encode(buf))
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let len = u32::decode(reader)? as usize;
+        (0..len).map(|_| T::decode(reader)).collect()
+    }
+}
+
+/// Time is encoded as seconds and nanoseconds since UNIX epoch,
+/// time before the epoch is clamped to it.
+impl Encode for SystemTime {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let since = self.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since.as_secs().encode(buf);
+        since.subsec_nanos().encode(buf);
+    }
+}
+
+impl Decode for SystemTime {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let secs = u64::decode(reader)?;
+        let nanos = u32::decode(reader)?;
+        Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+    }
+}
+
+impl Encode for Attrs {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.size.encode(buf);
+        self.blocks.encode(buf);
+        self.perm.encode(buf);
+        self.uid.encode(buf);
+        self.gid.encode(buf);
+        self.atime.encode(buf);
+        self.mtime.encode(buf);
+        self.ctime.encode(buf);
+        self.flags.encode(buf);
+    }
+}
+
+impl Decode for Attrs {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            size: Decode::decode(reader)?,
+            blocks: Decode::decode(reader)?,
+            perm: Decode::decode(reader)?,
+            uid: Decode::decode(reader)?,
+            gid: Decode::decode(reader)?,
+            atime: Decode::decode(reader)?,
+            mtime: Decode::decode(reader)?,
+            ctime: Decode::decode(reader)?,
+            flags: Decode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for FileMeta {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.id.encode(buf);
+        self.attrs.encode(buf);
+        self.hashes.encode(buf);
+        self.content.encode(buf);
+    }
+}
+
+impl Decode for FileMeta {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            name: Decode::decode(reader)?,
+            id: Decode::decode(reader)?,
+            attrs: Decode::decode(reader)?,
+            hashes: Decode::decode(reader)?,
+            content: Decode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for TinyFileMeta {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.id.encode(buf);
+        self.chunk_id.encode(buf);
+        self.chunk_offset.encode(buf);
+        self.chunk_blocks.encode(buf);
+        self.attrs.encode(buf);
+    }
+}
+
+impl Decode for TinyFileMeta {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            name: Decode::decode(reader)?,
+            id: Decode::decode(reader)?,
+            chunk_id: Decode::decode(reader)?,
+            chunk_offset: Decode::decode(reader)?,
+            chunk_blocks: Decode::decode(reader)?,
+            attrs: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// A directory is encoded along with all its entries.
+impl Encode for DirMeta {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.id.encode(buf);
+        self.attrs.encode(buf);
+        self.usage.encode(buf);
+        self.quota.encode(buf);
+        self.versions.encode(buf);
+        (self.entries.len() as u32).encode(buf);
+        self.entries.values().for_each(|node| node.encode(buf));
+    }
+}
+
+impl Decode for DirMeta {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let mut dir = DirMeta::new(Decode::decode(reader)?, Attrs::new(0, 0, 0));
+        dir.id = Decode::decode(reader)?;
+        dir.attrs = Decode::decode(reader)?;
+        dir.usage = Decode::decode(reader)?;
+        dir.quota = Decode::decode(reader)?;
+        dir.versions = Decode::decode(reader)?;
+        for node in Vec::<Node>::decode(reader)? {
+            dir.insert(node);
+        }
+        Ok(dir)
+    }
+}
+
+impl Encode for Node {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Node::Dir(dir) => {
+                0u8.encode(buf);
+                dir.encode(buf)
+            }
+            Node::File(file) => {
+                1u8.encode(buf);
+                file.encode(buf)
+            }
+            Node::TinyFile(file) => {
+                2u8.encode(buf);
+                file.encode(buf)
+            }
+        }
+    }
+}
+
+impl Decode for Node {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        match u8::decode(reader)? {
+            0 => Ok(Node::Dir(Decode::decode(reader)?)),
+            1 => Ok(Node::File(Decode::decode(reader)?)),
+            2 => Ok(Node::TinyFile(Decode::decode(reader)?)),
+            tag => Err(MetaError::InvalidTag(tag)),
+        }
+    }
+}
+
+/// Encode `value` with a header holding its version and checksum.
+pub fn encode<T: Encode>(value: &T) -> Vec<u8> {
+    let mut payload = Vec::new();
+    value.encode(&mut payload);
+
+    let mut buf = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    buf.extend_from_slice(META_MAGIC);
+    META_VERSION.encode(&mut buf);
+    (payload.len() as u64).encode(&mut buf);
+    buf.extend_from_slice(blake3::hash(&payload).as_bytes());
+    buf.extend_from_slice(&payload);
+    buf
+}
+
+/// Length of the encoded metadata from its header.
+pub fn encoded_length(header: &[u8]) -> Result<usize, MetaError> {
+    let mut reader = Reader::new(header);
+    if reader.take(META_MAGIC.len())? != META_MAGIC {
+        return Err(MetaError::BadMagic);
+    }
+    let version = u16::decode(&mut reader)?;
+    if version != META_VERSION {
+        return Err(MetaError::UnsupportedVersion(version));
+    }
+    Ok(HEADER_LENGTH + u64::decode(&mut reader)? as usize)
+}
+
+/// Decode and verify metadata encoded by `encode`.
+pub fn decode<T: Decode>(data: &[u8]) -> Result<T, MetaError> {
+    let length = encoded_length(data)?;
+    let mut reader = Reader::new(data);
+    reader.take(HEADER_LENGTH - 32)?;
+    let checksum = reader.take(32)?;
+    let payload = reader.take(length - HEADER_LENGTH)?;
+    if blake3::hash(payload).as_bytes() != checksum {
+        return Err(MetaError::ChecksumMismatch);
+    }
+    T::decode(&mut Reader::new(payload))
+}
+
+/// Store `value` in the MetaChunks of `id`, the nth chunk is `chunk_id(id, n)`.
+pub fn store<T: Encode>(
+    provider: &dyn ChunkProvider,
+    id: &ChunkId<Meta>,
+    value: &T,
+) -> Result<(), MetaError> {
+    store_bytes(provider, id, &encode(value))
+}
+
+/// Store metadata encoded by `encode` in the MetaChunks of `id`.
+pub fn store_bytes(
+    provider: &dyn ChunkProvider,
+    id: &ChunkId<Meta>,
+    data: &[u8],
+) -> Result<(), MetaError> {
+    for (n, part) in data.chunks(CHUNK_SIZE).enumerate() {
+        let chunk = provider.get_chunk_by_id(&chunk_id(id, n))?;
+        chunk.write_at(0, part);
+        provider.save_chunk(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Load a value stored by `store` from the MetaChunks of `id`.
+pub fn load<T: Decode>(provider: &dyn ChunkProvider, id: &ChunkId<Meta>) -> Result<T, MetaError> {
+    decode(&load_bytes(provider, id)?)
+}
+
+/// Load the encoded metadata stored in the MetaChunks of `id`.
+pub fn load_bytes(provider: &dyn ChunkProvider, id: &ChunkId<Meta>) -> Result<Vec<u8>, MetaError> {
+    let first = provider.get_chunk_by_id(&chunk_id(id, 0))?;
+    let mut header = [0; HEADER_LENGTH];
+    first.read_at(0, &mut header);
+    let length = encoded_length(&header)?;
+
+    let mut data = vec![0; length];
+    let mut read = first.read_at(0, &mut data[..min(length, CHUNK_SIZE)]);
+    let mut n = 1;
+    while read < length {
+        let chunk = provider.get_chunk_by_id(&chunk_id(id, n))?;
+        let end = min(length, read + CHUNK_SIZE);
+        read += chunk.read_at(0, &mut data[read..end]);
+        n += 1;
+    }
+    Ok(data)
+}
+
+/// Ids of the MetaChunks holding `length` bytes of encoded metadata at `id`.
+pub fn chunk_ids(id: &ChunkId<Meta>, length: usize) -> impl Iterator<Item = Id> + '_ {
+    (0..(length + CHUNK_SIZE - 1) / CHUNK_SIZE).map(move |n| chunk_id(id, n))
+}
+
+/// Id of the nth MetaChunk of `id`.
+pub fn chunk_id(id: &ChunkId<Meta>, n: usize) -> Id {
+    ChunkId::<Meta>::derive_n(id.id(), n).into_id()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, load, store, MetaError};
+    use crate::fs::{Attrs, DirMeta, FileMeta, Node, TinyFileMeta};
+    use crate::id::{ChunkId, Id};
+    use crate::providers::memory::MemoryProvider;
+
+    fn tree() -> DirMeta {
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 1000, 1000));
+        let mut sub = DirMeta::new("sub".to_owned(), Attrs::new(0o700, 0, 0));
+        sub.insert(Node::File(FileMeta {
+            name: "large".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        }));
+        root.insert(Node::Dir(sub));
+        root.insert(Node::TinyFile(TinyFileMeta::new(
+            "tiny".to_owned(),
+            Attrs::new(0o600, 1, 1),
+        )));
+        root
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let root = tree();
+        let data = encode(&root);
+        assert_eq!(data, encode(&decode::<DirMeta>(&data).unwrap()));
+
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode::<DirMeta>(&corrupted),
+            Err(MetaError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_store_load() {
+        let provider = MemoryProvider::new();
+        let id = ChunkId::new_random();
+        let mut root = tree();
+        // span multiple chunks
+        for i in 0..40_000 {
+            root.insert(Node::TinyFile(TinyFileMeta::new(
+                i.to_string(),
+                Attrs::new(0o644, 0, 0),
+            )));
+        }
+        store(&provider, &id, &root).unwrap();
+        let loaded: DirMeta = load(&provider, &id).unwrap();
+        assert_eq!(encode(&root), encode(&loaded));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::dirindex;
+use crate::fs::{DirMeta, EntryMut};
+use crate::meta::MetaError;
+use crate::provider::ChunkProvider;
+
+/// MetaCache bounds the directories with entries loaded in memory.
+/// Directories are loaded on demand along the paths accessed, and the least
+/// recently used ones are written back and unloaded beyond `capacity`.
+/// The root directory is always loaded.
+pub struct MetaCache {
+    capacity: usize,
+    tick: u64,
+    /// Last use of each loaded directory, by path
+    ticks: HashMap<Vec<String>, u64>,
+    /// Loaded directories ordered by last use
+    order: BTreeMap<u64, Vec<String>>,
+}
+
+impl MetaCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Load the directories along `path` in the tree at `root`, including
+    /// the entry at `path` if it is a directory, then evict directories
+    /// beyond capacity.
+    pub fn load(
+        &mut self,
+        root: &mut DirMeta,
+        provider: &dyn ChunkProvider,
+        path: &[String],
+    ) -> Result<(), MetaError> {
+        let mut dir = &mut *root;
+        let mut depth = 0;
+        loop {
+            dirindex::load_entries(provider, dir)?;
+            if depth == path.len() {
+                break;
+            }
+            match dir.lookup_mut(&path[depth]) {
+                Some(EntryMut::Dir(sub)) => dir = sub,
+                _ => break,
+            }
+            depth += 1;
+        }
+        // ancestors are used later than their descendants, so never evicted first
+        for n in (1..=depth).rev() {
+            self.touch(&path[..n]);
+        }
+        self.evict(root, provider)
+    }
+
+    /// Mark the directory at `path` used.
+    fn touch(&mut self, path: &[String]) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(path.to_vec(), self.tick) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, path.to_vec());
+    }
+
+    /// Write back and unload the least recently used directories beyond capacity.
+    fn evict(&mut self, root: &mut DirMeta, provider: &dyn ChunkProvider) -> Result<(), MetaError> {
+        while self.ticks.len() > self.capacity {
+            let tick = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            let path = self.order.remove(&tick).unwrap();
+            self.ticks.remove(&path);
+            if let Some(EntryMut::Dir(dir)) = root.resolve_mut(&path) {
+                dirindex::store_dir(provider, dir)?;
+                dir.unload();
+            }
+            self.forget(&path);
+        }
+        Ok(())
+    }
+
+    /// Unload the directory at `path` without writing it back, so it is
+    /// loaded again from the provider, e.g. after modified by another client.
+    /// Changes not stored yet are lost.
+    pub fn invalidate(&mut self, root: &mut DirMeta, path: &[String]) {
+        if let Some(EntryMut::Dir(dir)) = root.resolve_mut(path) {
+            dir.unload();
+        }
+        if let Some(tick) = self.ticks.remove(path) {
+            self.order.remove(&tick);
+        }
+        self.forget(path);
+    }
+
+    /// Unload all directories but the root, they have to be stored before.
+    pub fn clear(&mut self, root: &mut DirMeta) {
+        for dir in root.dirs_mut() {
+            dir.unload();
+        }
+        self.ticks.clear();
+        self.order.clear();
+    }
+
+    /// Unload the directories loaded besides those tracked, e.g. by walking
+    /// the whole tree, keeping those tracked which may hold changes not
+    /// stored yet.
+    pub fn release(&self, root: &mut DirMeta) {
+        release(&self.ticks, root, &mut Vec::new());
+    }
+
+    /// Drop directories beneath `path`, unloaded along with it.
+    fn forget(&mut self, path: &[String]) {
+        let order = &mut self.order;
+        self.ticks.retain(|loaded, tick| {
+            let beneath = loaded.len() > path.len() && loaded.starts_with(path);
+            if beneath {
+                order.remove(tick);
+            }
+            !beneath
+        });
+    }
+
+    /// Number of directories loaded besides the root.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+}
+
+fn release(ticks: &HashMap<Vec<String>, u64>, dir: &mut DirMeta, path: &mut Vec<String>) {
+    for sub in dir.dirs_mut() {
+        path.push(sub.name.clone());
+        if ticks.contains_key(path.as_slice()) {
+            release(ticks, sub, path);
+        } else {
+            sub.unload();
+        }
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetaCache;
+    use crate::dirindex;
+    use crate::fs::{Attrs, DirMeta, Entry, Node, TinyFileMeta};
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_evict_and_reload() {
+        let provider = MemoryProvider::new();
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        for name in &["a", "b"] {
+            let mut dir = DirMeta::new(name.to_string(), Attrs::new(0o755, 0, 0));
+            let file = TinyFileMeta::new("file".to_owned(), Attrs::new(0o644, 0, 0));
+            dir.insert(Node::TinyFile(file));
+            root.insert(Node::Dir(dir));
+        }
+        dirindex::store_dir(&provider, &mut root).unwrap();
+        let mut cache = MetaCache::new(1);
+        cache.clear(&mut root);
+        assert!(root.resolve(&["a", "file"]).is_none());
+
+        let a = vec!["a".to_owned()];
+        let b = vec!["b".to_owned()];
+        cache.load(&mut root, &provider, &a).unwrap();
+        let file = TinyFileMeta::new("new".to_owned(), Attrs::new(0o644, 0, 0));
+        match root.resolve_mut(&a) {
+            Some(crate::fs::EntryMut::Dir(dir)) => dir.insert(Node::TinyFile(file)),
+            _ => panic!("a not loaded"),
+        };
+
+        // a is written back when evicted
+        cache.load(&mut root, &provider, &b).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(root.resolve(&["a", "new"]).is_none());
+        assert!(matches!(
+            root.resolve(&["b", "file"]),
+            Some(Entry::TinyFile(_))
+        ));
+        cache.load(&mut root, &provider, &a).unwrap();
+        assert!(matches!(
+            root.resolve(&["a", "new"]),
+            Some(Entry::TinyFile(_))
+        ));
+
+        // the directories loaded whole are unloaded, but those tracked
+        let file = TinyFileMeta::new("unstored".to_owned(), Attrs::new(0o644, 0, 0));
+        match root.resolve_mut(&a) {
+            Some(crate::fs::EntryMut::Dir(dir)) => dir.insert(Node::TinyFile(file)),
+            _ => panic!("a not loaded"),
+        };
+        dirindex::load_all(&provider, &mut root).unwrap();
+        assert!(root.resolve(&["b", "file"]).is_some());
+        cache.release(&mut root);
+        assert!(root.resolve(&["b", "file"]).is_none());
+        assert!(root.resolve(&["a", "unstored"]).is_some());
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use parking_lot::Mutex;
+
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum MigrateError {
+    #[error("the source provider cannot list its chunks")]
+    Unlistable,
+    #[error("{0} copied differs from the source")]
+    Mismatch(Id),
+    #[error("malformed checkpoint line {0}")]
+    Checkpoint(usize),
+    #[error(transparent)]
+    ChunkProviderError(#[from] ChunkProviderError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// How chunks are migrated.
+#[derive(Clone, Debug)]
+pub struct MigrateOptions {
+    /// Chunks copied at once.
+    pub threads: usize,
+    /// File recording the chunks copied, which are skipped when migrating
+    /// again with it.
+    pub checkpoint: Option<PathBuf>,
+    /// Read each chunk back from the destination and compare.
+    pub verify: bool,
+}
+
+impl Default for MigrateOptions {
+    fn default() -> Self {
+        Self {
+            threads: 8,
+            checkpoint: None,
+            verify: true,
+        }
+    }
+}
+
+/// What a migration has done.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MigrateStats {
+    /// Chunks copied
+    pub copied: usize,
+    /// Chunks skipped, copied by a previous run
+    pub resumed: usize,
+    /// Chunks gone from the source meanwhile
+    pub missing: usize,
+}
+
+/// Copy the chunks `ids` from `from` to `to` as stored, byte for byte, so
+/// sealed or signed chunks stay valid. Both are raw providers, e.g. opened
+/// from their URI.
+pub fn migrate(
+    from: Arc<dyn ChunkProvider>,
+    to: Arc<dyn ChunkProvider>,
+    ids: Vec<Id>,
+    options: &MigrateOptions,
+) -> Result<MigrateStats, MigrateError> {
+    let done = match &options.checkpoint {
+        Some(path) if path.exists() => read_checkpoint(&fs::read_to_string(path)?)?,
+        _ => HashSet::new(),
+    };
+    let total = ids.len();
+    let queue: Vec<Id> = ids.into_iter().filter(|id| !done.contains(id)).collect();
+    let resumed = total - queue.len();
+    let checkpoint = match &options.checkpoint {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(queue),
+        checkpoint,
+        stats: Mutex::new(MigrateStats {
+            resumed,
+            ..MigrateStats::default()
+        }),
+    });
+    let workers: Vec<_> = (0..options.threads.max(1))
+        .map(|_| {
+            let (from, to, shared) = (from.clone(), to.clone(), shared.clone());
+            let verify = options.verify;
+            thread::spawn(move || shared.work(from.as_ref(), to.as_ref(), verify))
+        })
+        .collect();
+    let mut result = Ok(());
+    for worker in workers {
+        let outcome = worker.join().expect("migration worker panicked");
+        if result.is_ok() {
+            result = outcome;
+        }
+    }
+    result?;
+    to.flush()?;
+    let stats = *shared.stats.lock();
+    Ok(stats)
+}
+
+/// State of a migration shared by its workers.
+struct Shared {
+    queue: Mutex<Vec<Id>>,
+    checkpoint: Option<Mutex<fs::File>>,
+    stats: Mutex<MigrateStats>,
+}
+
+impl Shared {
+    /// Copy chunks until the queue is empty, or stop all workers on error.
+    fn work(
+        &self,
+        from: &dyn ChunkProvider,
+        to: &dyn ChunkProvider,
+        verify: bool,
+    ) -> Result<(), MigrateError> {
+        loop {
+            let id = match self.queue.lock().pop() {
+                Some(id) => id,
+                None => return Ok(()),
+            };
+            if let Err(e) = self.copy(from, to, &id, verify) {
+                self.queue.lock().clear();
+                return Err(e);
+            }
+        }
+    }
+
+    fn copy(
+        &self,
+        from: &dyn ChunkProvider,
+        to: &dyn ChunkProvider,
+        id: &Id,
+        verify: bool,
+    ) -> Result<(), MigrateError> {
+        let data = match from.get_object(id)? {
+            Some(data) => data,
+            None => {
+                self.stats.lock().missing += 1;
+                return Ok(());
+            }
+        };
+        to.save_object(id, &data)?;
+        if verify && to.get_object(id)?.as_deref() != Some(&data[..]) {
+            return Err(MigrateError::Mismatch(id.clone()));
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            writeln!(checkpoint.lock(), "{}", id.hex())?;
+        }
+        self.stats.lock().copied += 1;
+        Ok(())
+    }
+}
+
+/// Ids recorded in a checkpoint, one in hex per line. A last line cut
+/// short by a crash is ignored.
+fn read_checkpoint(text: &str) -> Result<HashSet<Id>, MigrateError> {
+    let mut ids = HashSet::new();
+    let complete = text.ends_with('\n');
+    let lines: Vec<&str> = text.lines().collect();
+    for (n, line) in lines.iter().enumerate() {
+        match Id::from_hex(line) {
+            Ok(id) => {
+                ids.insert(id);
+            }
+            Err(_) if !complete && n + 1 == lines.len() => {}
+            Err(_) => return Err(MigrateError::Checkpoint(n + 1)),
+        }
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate, MigrateOptions, MigrateStats};
+    use crate::id::Id;
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_migrate_resumed() {
+        let from = Arc::new(MemoryProvider::new());
+        let ids: Vec<Id> = (0..20).map(|_| Id::new_random()).collect();
+        for (n, id) in ids.iter().enumerate() {
+            from.save_object(id, &[n as u8; 100]).unwrap();
+        }
+        let checkpoint =
+            std::env::temp_dir().join(format!("eoss-fuse-{}.migrate", std::process::id()));
+        let _ = fs::remove_file(&checkpoint);
+        let options = MigrateOptions {
+            threads: 4,
+            checkpoint: Some(checkpoint.clone()),
+            verify: true,
+        };
+        let to = Arc::new(MemoryProvider::new());
+        let stats = migrate(from.clone(), to.clone(), ids[..5].to_vec(), &options).unwrap();
+        assert_eq!(stats.copied, 5);
+
+        let stats = migrate(from.clone(), to.clone(), ids.clone(), &options).unwrap();
+        let expected = MigrateStats {
+            copied: 15,
+            resumed: 5,
+            missing: 0,
+        };
+        assert_eq!(stats, expected);
+        for id in ids.iter() {
+            assert_eq!(to.get_object(id).unwrap(), from.get_object(id).unwrap());
+        }
+        fs::remove_file(&checkpoint).unwrap();
+    }
+}
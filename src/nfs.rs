@@ -0,0 +1,60 @@
+use std::convert::TryInto;
+
+use crate::fs::DirMeta;
+use crate::id::{Id, ID_LENGTH};
+
+/// Label of the id an entry's file handle is derived from.
+const HANDLE_LABEL: &[u8] = b"nfs handle";
+/// Inodes derived from ids fall in [2^62, 2^63), far above those assigned
+/// in order and below the virtual ones.
+const HANDLE_BASE: u64 = 1 << 62;
+
+/// Inode number and generation of the entry with id `id`, the same on every
+/// mount so NFS clients of a re-export keep valid file handles. An entry
+/// copied out of a snapshot gets a new id, handles of it go stale.
+pub fn handle(id: &[u8; ID_LENGTH]) -> (u64, u64) {
+    let derived = Id::from(*id).derive_labeled(HANDLE_LABEL);
+    let word = |n: usize| u64::from_le_bytes(derived[n * 8..n * 8 + 8].try_into().unwrap());
+    (word(0) >> 2 | HANDLE_BASE, word(1))
+}
+
+/// Inodes from `handle` of every entry beneath `dir` with their paths, the
+/// tree must be fully loaded.
+pub fn index(dir: &DirMeta) -> Vec<(u64, Vec<String>)> {
+    let mut found: Vec<(u64, Vec<String>)> = dir
+        .dirs()
+        .map(|sub| (&sub.name, &sub.id))
+        .chain(dir.files().map(|file| (&file.name, &file.id)))
+        .chain(dir.tiny_files().map(|file| (&file.name, &file.id)))
+        .map(|(name, id)| (handle(id).0, vec![name.clone()]))
+        .collect();
+    for sub in dir.dirs() {
+        found.extend(index(sub).into_iter().map(|(ino, mut path)| {
+            path.insert(0, sub.name.clone());
+            (ino, path)
+        }));
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, index, HANDLE_BASE};
+    use crate::fs::{Attrs, DirMeta, Node, TinyFileMeta};
+
+    #[test]
+    fn test_index() {
+        let mut sub = DirMeta::new("sub".to_owned(), Attrs::new(0o755, 0, 0));
+        let file = TinyFileMeta::new("file".to_owned(), Attrs::new(0o644, 0, 0));
+        let (ino, generation) = handle(&file.id);
+        assert_eq!(handle(&file.id), (ino, generation));
+        assert!((HANDLE_BASE..HANDLE_BASE * 2).contains(&ino));
+        sub.insert(Node::TinyFile(file));
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        root.insert(Node::Dir(sub));
+
+        let index = index(&root);
+        assert_eq!(index.len(), 2);
+        assert!(index.contains(&(ino, vec!["sub".to_owned(), "file".to_owned()])));
+    }
+}
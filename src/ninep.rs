@@ -0,0 +1,702 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+use libc::{
+    c_int, EACCES, EBADF, EINVAL, EOPNOTSUPP, EPERM, EPROTO, EROFS, O_ACCMODE, O_RDONLY, O_TRUNC,
+    O_WRONLY,
+};
+use parking_lot::Mutex;
+
+use crate::fuse::{EossFs, SetAttrs};
+use crate::meta::{Decode, Encode, Reader};
+use crate::vfs::User;
+
+/// Dialect of 9P spoken, the one of Linux clients.
+pub const VERSION: &str = "9P2000.L";
+/// Largest message accepted, whatever the client asks for.
+const MAX_MSIZE: u32 = 1 << 20;
+/// Size, type and tag of every message.
+const HEADER_LENGTH: u32 = 4 + 1 + 2;
+/// Bytes of a read or write message besides its data.
+const IO_HEADER: u32 = HEADER_LENGTH + 4 + 8 + 4;
+/// File types of modes as Linux numbers them, on every host.
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+/// Fields of Rgetattr always filled, mode to blocks.
+const GETATTR_BASIC: u64 = 0x7ff;
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+const AT_REMOVEDIR: u32 = 0x200;
+/// Uid given by clients naming the user only.
+const NO_UID: u32 = !0;
+
+/// Serve the filesystem to 9P2000.L clients, such as virtio-9p of QEMU or
+/// WSL, on a socket at `path` accessible by its owner only. Clients are
+/// served concurrently, one request at a time, as the user connected or
+/// the one they attach as if root.
+pub fn serve(path: &Path, fs: Arc<Mutex<EossFs>>) -> io::Result<()> {
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let peer = match User::of_peer(&stream) {
+                    Ok(peer) => peer,
+                    Err(e) => return tracing::warn!("9p client: {}", e),
+                };
+                let mut session = Session::new(&fs, peer);
+                if let Err(e) = session.run(&stream) {
+                    tracing::warn!("9p client: {}", e);
+                }
+                session.clunk_all();
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Fids of a client, each holding an inode of the filesystem.
+struct Session<'a> {
+    fs: &'a Mutex<EossFs>,
+    fids: HashMap<u32, u64>,
+    /// Lookups of the inodes fids hold, forgotten once none holds them
+    lookups: HashMap<u64, u64>,
+    /// Access fids were opened for, as permission bits
+    opened: HashMap<u32, u16>,
+    msize: u32,
+    /// Process connected, attaching as another user only if root
+    peer: User,
+    /// Owner of files created, the user attached, checked against
+    /// permissions
+    user: User,
+}
+
+impl<'a> Session<'a> {
+    fn new(fs: &'a Mutex<EossFs>, peer: User) -> Self {
+        Self {
+            fs,
+            fids: HashMap::new(),
+            lookups: HashMap::new(),
+            opened: HashMap::new(),
+            msize: MAX_MSIZE,
+            peer,
+            user: peer,
+        }
+    }
+
+    /// Answer requests on `stream` until the client hangs up.
+    fn run(&mut self, mut stream: &UnixStream) -> io::Result<()> {
+        loop {
+            let mut size = [0; 4];
+            match stream.read_exact(&mut size) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+            let size = u32::from_le_bytes(size);
+            if !(HEADER_LENGTH..=self.msize).contains(&size) {
+                return Err(io::Error::new(ErrorKind::InvalidData, "bad message size"));
+            }
+            let mut message = vec![0; size as usize - 4];
+            stream.read_exact(&mut message)?;
+            let (kind, tag) = (message[0], [message[1], message[2]]);
+            let (kind, body) = match self.answer(kind, &mut Reader::new(&message[3..])) {
+                Ok(body) => (kind + 1, body),
+                Err(errno) => (RLERROR, (errno as u32).to_le_bytes().to_vec()),
+            };
+            let mut reply = Vec::with_capacity(HEADER_LENGTH as usize + body.len());
+            (HEADER_LENGTH + body.len() as u32).encode(&mut reply);
+            kind.encode(&mut reply);
+            reply.extend_from_slice(&tag);
+            reply.extend_from_slice(&body);
+            stream.write_all(&reply)?;
+        }
+    }
+
+    /// Body of the reply to request `kind`, or the errno it failed with.
+    fn answer(&mut self, kind: u8, request: &mut Reader) -> Result<Vec<u8>, c_int> {
+        let mut reply = Vec::new();
+        let fs = self.fs;
+        match kind {
+            TVERSION => {
+                let msize: u32 = decode(request)?;
+                let version = string(request)?;
+                self.clunk_all();
+                self.msize = msize.clamp(IO_HEADER + 1, MAX_MSIZE);
+                self.msize.encode(&mut reply);
+                match version.starts_with(VERSION) {
+                    true => put_string(&mut reply, VERSION),
+                    false => put_string(&mut reply, "unknown"),
+                }
+            }
+            TATTACH => {
+                let fid = decode(request)?;
+                let _afid: u32 = decode(request)?;
+                let _uname = string(request)?;
+                let _aname = string(request)?;
+                let uid = decode(request)?;
+                if uid != NO_UID {
+                    if self.peer.uid != 0 && uid != self.peer.uid {
+                        return Err(EPERM);
+                    }
+                    let gid = match uid == self.peer.uid {
+                        true => self.peer.gid,
+                        false => primary_gid(uid).unwrap_or(NO_UID),
+                    };
+                    self.user = User { uid, gid };
+                }
+                let attr = fs.lock().attr(FUSE_ROOT_ID)?;
+                self.hold(fid, FUSE_ROOT_ID);
+                put_qid(&mut reply, &attr);
+            }
+            TWALK => {
+                let fid = decode(request)?;
+                let newfid = decode(request)?;
+                let count: u16 = decode(request)?;
+                let names = (0..count)
+                    .map(|_| string(request))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut ino = self.fid(fid)?;
+                let mut fs = fs.lock();
+                let mut walked = Vec::new();
+                for name in &names {
+                    let searchable = fs.attr(ino).and_then(|attr| self.check(&attr, 1));
+                    match searchable.and_then(|_| fs.lookup_entry(ino, name)) {
+                        Ok((attr, _)) => {
+                            ino = attr.ino;
+                            walked.push(attr);
+                        }
+                        Err(errno) if walked.is_empty() => return Err(errno),
+                        Err(_) => break,
+                    }
+                }
+                // only the last inode walked to is held, by `newfid`
+                let last = walked
+                    .len()
+                    .checked_sub(1)
+                    .filter(|_| walked.len() == names.len());
+                for (n, attr) in walked.iter().enumerate() {
+                    if Some(n) != last {
+                        fs.forget_inode(attr.ino, 1);
+                    }
+                }
+                drop(fs);
+                if walked.len() == names.len() {
+                    if !walked.is_empty() {
+                        self.looked_up(ino);
+                    }
+                    self.hold(newfid, ino);
+                }
+                (walked.len() as u16).encode(&mut reply);
+                for attr in &walked {
+                    put_qid(&mut reply, attr);
+                }
+            }
+            TLOPEN => {
+                let fid = decode(request)?;
+                let ino = self.fid(fid)?;
+                let flags: u32 = decode(request)?;
+                let access = access(flags);
+                let mut fs = fs.lock();
+                if access & 2 != 0 && fs.read_only() {
+                    return Err(EROFS);
+                }
+                let attr = fs.attr(ino)?;
+                self.check(&attr, access)?;
+                let attr = match flags as c_int & O_TRUNC {
+                    0 => attr,
+                    _ => fs.set_attrs(ino, size(0))?,
+                };
+                drop(fs);
+                self.opened.insert(fid, access);
+                put_qid(&mut reply, &attr);
+                (self.msize - IO_HEADER).encode(&mut reply);
+            }
+            TLCREATE => {
+                let fid = decode(request)?;
+                let name = string(request)?;
+                let flags: u32 = decode(request)?;
+                let mode: u32 = decode(request)?;
+                let gid = self.gid(decode(request)?)?;
+                let parent = self.fid(fid)?;
+                let mut fs = fs.lock();
+                self.check(&fs.attr(parent)?, 3)?;
+                let perm = (mode & 0o7777) as u16;
+                let (attr, _) = fs.create_file(parent, &name, perm, self.user.uid, gid)?;
+                drop(fs);
+                // the fid now stands for the file created, opened
+                self.looked_up(attr.ino);
+                self.hold(fid, attr.ino);
+                self.opened.insert(fid, access(flags));
+                put_qid(&mut reply, &attr);
+                (self.msize - IO_HEADER).encode(&mut reply);
+            }
+            TGETATTR => {
+                let ino = self.fid(decode(request)?)?;
+                let _mask: u64 = decode(request)?;
+                let attr = fs.lock().attr(ino)?;
+                put_attr(&mut reply, &attr);
+            }
+            TSETATTR => {
+                let ino = self.fid(decode(request)?)?;
+                let valid: u32 = decode(request)?;
+                let mode: u32 = decode(request)?;
+                let uid: u32 = decode(request)?;
+                let gid: u32 = decode(request)?;
+                let size: u64 = decode(request)?;
+                let atime = time(request)?;
+                let mtime = time(request)?;
+                let now = SystemTime::now();
+                let when = |set, specific| match valid & set {
+                    0 => now,
+                    _ => specific,
+                };
+                let changes = SetAttrs {
+                    mode: Some(mode).filter(|_| valid & SETATTR_MODE != 0),
+                    uid: Some(uid).filter(|_| valid & SETATTR_UID != 0),
+                    gid: Some(gid).filter(|_| valid & SETATTR_GID != 0),
+                    size: Some(size).filter(|_| valid & SETATTR_SIZE != 0),
+                    atime: Some(when(SETATTR_ATIME_SET, atime))
+                        .filter(|_| valid & SETATTR_ATIME != 0),
+                    mtime: Some(when(SETATTR_MTIME_SET, mtime))
+                        .filter(|_| valid & SETATTR_MTIME != 0),
+                };
+                let mut fs = fs.lock();
+                let attr = fs.attr(ino)?;
+                let writable = self.user.may(attr.perm, attr.uid, attr.gid, 2);
+                if !self.user.may_change(attr.uid, attr.gid, &changes, writable) {
+                    return Err(EPERM);
+                }
+                fs.set_attrs(ino, changes)?;
+            }
+            TREADDIR => {
+                let ino = self.opened_fid(decode(request)?, 4)?;
+                let offset: u64 = decode(request)?;
+                let count: u32 = decode(request)?;
+                let entries = fs.lock().list_dir(ino)?;
+                let mut data = Vec::new();
+                for (n, (name, attr)) in entries.iter().enumerate().skip(offset as usize) {
+                    let mut entry = Vec::new();
+                    put_qid(&mut entry, attr);
+                    (n as u64 + 1).encode(&mut entry);
+                    dirent_type(attr).encode(&mut entry);
+                    put_string(&mut entry, name);
+                    if data.len() + entry.len() > count.min(self.msize - IO_HEADER) as usize {
+                        break;
+                    }
+                    data.extend(entry);
+                }
+                (data.len() as u32).encode(&mut reply);
+                reply.extend(data);
+            }
+            TREAD => {
+                let ino = self.opened_fid(decode(request)?, 4)?;
+                let offset = decode(request)?;
+                let count: u32 = decode(request)?;
+                let count = count.min(self.msize - IO_HEADER) as usize;
+                let (data, range) = fs.lock().read_data(ino, offset, count, None)?;
+                (range.len() as u32).encode(&mut reply);
+                reply.extend_from_slice(&data[range]);
+            }
+            TWRITE => {
+                let ino = self.opened_fid(decode(request)?, 2)?;
+                let offset = decode(request)?;
+                let count: u32 = decode(request)?;
+                let data = request.take(count as usize).map_err(|_| EPROTO)?;
+                let written = fs.lock().write_direct(ino, offset, data)?;
+                (written as u32).encode(&mut reply);
+            }
+            TCLUNK => {
+                let fid = decode(request)?;
+                self.fid(fid)?;
+                self.release(fid);
+            }
+            TFSYNC => {
+                let ino = self.fid(decode(request)?)?;
+                let _datasync: u32 = decode(request)?;
+                fs.lock().sync_inode(ino)?;
+            }
+            TUNLINKAT => {
+                let parent = self.fid(decode(request)?)?;
+                let name = string(request)?;
+                let flags: u32 = decode(request)?;
+                let mut fs = fs.lock();
+                self.check(&fs.attr(parent)?, 3)?;
+                fs.remove(parent, &name, flags & AT_REMOVEDIR != 0)?;
+            }
+            TMKDIR => {
+                let parent = self.fid(decode(request)?)?;
+                let name = string(request)?;
+                let mode: u32 = decode(request)?;
+                let gid = self.gid(decode(request)?)?;
+                let mut fs = fs.lock();
+                self.check(&fs.attr(parent)?, 3)?;
+                let perm = (mode & 0o7777) as u16;
+                let (attr, _) = fs.create_dir(parent, &name, perm, self.user.uid, gid)?;
+                // held by no fid
+                fs.forget_inode(attr.ino, 1);
+                put_qid(&mut reply, &attr);
+            }
+            TRENAMEAT => {
+                let parent = self.fid(decode(request)?)?;
+                let name = string(request)?;
+                let newparent = self.fid(decode(request)?)?;
+                let newname = string(request)?;
+                let mut fs = fs.lock();
+                self.check(&fs.attr(parent)?, 3)?;
+                self.check(&fs.attr(newparent)?, 3)?;
+                fs.move_entry(parent, &name, newparent, &newname, 0)?;
+            }
+            // requests are answered in order, none is left to cancel
+            TFLUSH => {
+                let _oldtag: u16 = decode(request)?;
+            }
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(reply)
+    }
+
+    fn fid(&self, fid: u32) -> Result<u64, c_int> {
+        self.fids.get(&fid).copied().ok_or(EBADF)
+    }
+
+    /// Inode of `fid`, which has to be opened for the access `mask` asks.
+    fn opened_fid(&self, fid: u32, mask: u16) -> Result<u64, c_int> {
+        let ino = self.fid(fid)?;
+        match self.opened.get(&fid) {
+            Some(access) if access & mask == mask => Ok(ino),
+            _ => Err(EBADF),
+        }
+    }
+
+    /// Fail unless the user attached may access `attr` as `mask` asks.
+    fn check(&self, attr: &FileAttr, mask: u16) -> Result<(), c_int> {
+        match self.user.may(attr.perm, attr.uid, attr.gid, mask) {
+            true => Ok(()),
+            false => Err(EACCES),
+        }
+    }
+
+    /// Group of entries created, one of the user attached unless root.
+    fn gid(&self, gid: u32) -> Result<u32, c_int> {
+        match gid {
+            NO_UID => Ok(self.user.gid),
+            gid if self.user.uid == 0 || gid == self.user.gid => Ok(gid),
+            _ => Err(EPERM),
+        }
+    }
+
+    /// Count a lookup of `ino`, to be forgotten once no fid holds it.
+    fn looked_up(&mut self, ino: u64) {
+        *self.lookups.entry(ino).or_default() += 1;
+    }
+
+    /// Let `fid` stand for `ino`, instead of what it stood for.
+    fn hold(&mut self, fid: u32, ino: u64) {
+        if self.fids.get(&fid) != Some(&ino) {
+            self.release(fid);
+            self.fids.insert(fid, ino);
+        }
+    }
+
+    /// Drop `fid`, and the lookups of its inode if no other fid stands for
+    /// it, those of other sessions left.
+    fn release(&mut self, fid: u32) {
+        self.opened.remove(&fid);
+        if let Some(ino) = self.fids.remove(&fid) {
+            if !self.fids.values().any(|held| *held == ino) {
+                if let Some(lookups) = self.lookups.remove(&ino) {
+                    self.fs.lock().forget_inode(ino, lookups);
+                }
+            }
+        }
+    }
+
+    fn clunk_all(&mut self) {
+        let fids: Vec<u32> = self.fids.keys().copied().collect();
+        for fid in fids {
+            self.release(fid);
+        }
+    }
+}
+
+/// Permission bits asked by opening with `flags`.
+fn access(flags: u32) -> u16 {
+    let truncate = match flags as c_int & O_TRUNC {
+        0 => 0,
+        _ => 2,
+    };
+    truncate
+        | match flags as c_int & O_ACCMODE {
+            O_RDONLY => 4,
+            O_WRONLY => 2,
+            _ => 6,
+        }
+}
+
+/// Primary group of the user `uid`, as the password database says.
+fn primary_gid(uid: u32) -> Option<u32> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0; 4096];
+    let mut found = ptr::null_mut();
+    let got =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    match got == 0 && !found.is_null() {
+        true => Some(passwd.pw_gid),
+        false => None,
+    }
+}
+
+fn decode<T: Decode>(reader: &mut Reader) -> Result<T, c_int> {
+    T::decode(reader).map_err(|_| EPROTO)
+}
+
+/// Decode a string, prefixed by its length on 2 bytes.
+fn string(reader: &mut Reader) -> Result<String, c_int> {
+    let len: u16 = decode(reader)?;
+    let bytes = reader.take(len as usize).map_err(|_| EPROTO)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| EINVAL)
+}
+
+fn time(reader: &mut Reader) -> Result<SystemTime, c_int> {
+    let secs = decode(reader)?;
+    let nanos: u64 = decode(reader)?;
+    Ok(UNIX_EPOCH + Duration::new(secs, nanos as u32))
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    (s.len() as u16).encode(buf);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_time(buf: &mut Vec<u8>, time: SystemTime) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since.as_secs().encode(buf);
+    (since.subsec_nanos() as u64).encode(buf);
+}
+
+/// Encode the qid of an entry: its type, version and inode.
+fn put_qid(buf: &mut Vec<u8>, attr: &FileAttr) {
+    let kind: u8 = match attr.kind {
+        FileType::Directory => 0x80,
+        _ => 0,
+    };
+    kind.encode(buf);
+    0u32.encode(buf);
+    attr.ino.encode(buf);
+}
+
+/// Encode the body of Rgetattr.
+fn put_attr(buf: &mut Vec<u8>, attr: &FileAttr) {
+    GETATTR_BASIC.encode(buf);
+    put_qid(buf, attr);
+    let format = match attr.kind {
+        FileType::Directory => S_IFDIR,
+        _ => S_IFREG,
+    };
+    (format | attr.perm as u32).encode(buf);
+    attr.uid.encode(buf);
+    attr.gid.encode(buf);
+    (attr.nlink as u64).encode(buf);
+    (attr.rdev as u64).encode(buf);
+    attr.size.encode(buf);
+    (attr.blksize as u64).encode(buf);
+    attr.blocks.encode(buf);
+    put_time(buf, attr.atime);
+    put_time(buf, attr.mtime);
+    put_time(buf, attr.ctime);
+    put_time(buf, attr.crtime);
+    // generation and data version, not requested by basic getattr
+    0u64.encode(buf);
+    0u64.encode(buf);
+}
+
+fn dirent_type(attr: &FileAttr) -> u8 {
+    match attr.kind {
+        FileType::Directory => libc::DT_DIR,
+        _ => libc::DT_REG,
+    }
+}
+
+fn size(size: u64) -> SetAttrs {
+    SetAttrs {
+        size: Some(size),
+        ..SetAttrs::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    use fuser::FUSE_ROOT_ID;
+    use libc::{EACCES, EBADF, EPERM, O_RDONLY, O_RDWR, O_WRONLY};
+    use parking_lot::Mutex;
+
+    use super::{
+        put_string, Session, RLERROR, TATTACH, TCLUNK, TLCREATE, TLOPEN, TMKDIR, TREAD, TVERSION,
+        TWALK, TWRITE,
+    };
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::meta::Encode;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+    use crate::vfs::User;
+
+    fn body(values: &[&dyn Encode], names: &[&str]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for value in values {
+            value.encode(&mut body);
+        }
+        for name in names {
+            put_string(&mut body, name);
+        }
+        body
+    }
+
+    /// Send request `kind` with `body`, returning the reply type and body.
+    fn call(stream: &mut UnixStream, kind: u8, body: Vec<u8>) -> (u8, Vec<u8>) {
+        let mut message = Vec::new();
+        (7 + body.len() as u32).encode(&mut message);
+        kind.encode(&mut message);
+        1u16.encode(&mut message);
+        message.extend(body);
+        stream.write_all(&message).unwrap();
+        let mut header = [0; 7];
+        stream.read_exact(&mut header).unwrap();
+        let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let mut body = vec![0; size as usize - 7];
+        stream.read_exact(&mut body).unwrap();
+        (header[4], body)
+    }
+
+    /// Serve a session of `peer` on `fs`, returning the client end.
+    fn connect(fs: &Arc<Mutex<EossFs>>, peer: User) -> (UnixStream, JoinHandle<()>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let fs = fs.clone();
+        let served = thread::spawn(move || Session::new(&fs, peer).run(&server).unwrap());
+        (client, served)
+    }
+
+    fn error(errno: i32) -> (u8, Vec<u8>) {
+        (RLERROR, (errno as u32).to_le_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_session() {
+        let provider = Arc::new(MemoryProvider::new());
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let fs = EossFs::open(provider, MountOptions::default(), &Id::new(SUPERBLOCK_ID));
+        let fs = Arc::new(Mutex::new(fs.unwrap()));
+        let (mut client, served) = connect(&fs, User { uid: 0, gid: 0 });
+
+        let version = call(&mut client, TVERSION, body(&[&8192u32], &["9P2000.L"]));
+        assert_eq!(version.0, TVERSION + 1);
+        let attach = body(&[&0u32, &!0u32], &["root", ""]);
+        let attach = [attach, 0u32.to_le_bytes().to_vec()].concat();
+        assert_eq!(call(&mut client, TATTACH, attach).0, TATTACH + 1);
+
+        // clone the root into fid 1, which then stands for a file created
+        let clone = body(&[&0u32, &1u32, &0u16], &[]);
+        assert_eq!(call(&mut client, TWALK, clone).0, TWALK + 1);
+        let create = [
+            body(&[&1u32], &["file"]),
+            body(&[&(O_RDWR as u32), &0o644u32, &1000u32], &[]),
+        ]
+        .concat();
+        assert_eq!(call(&mut client, TLCREATE, create).0, TLCREATE + 1);
+        let write = [body(&[&1u32, &0u64, &5u32], &[]), b"hello".to_vec()].concat();
+        let written = call(&mut client, TWRITE, write);
+        assert_eq!(written, (TWRITE + 1, 5u32.to_le_bytes().to_vec()));
+
+        let walk = body(&[&0u32, &2u32, &1u16], &["file"]);
+        assert_eq!(call(&mut client, TWALK, walk).0, TWALK + 1);
+        let read = body(&[&2u32, &1u64, &64u32], &[]);
+        assert_eq!(call(&mut client, TREAD, read.clone()), error(EBADF));
+        let open = body(&[&2u32, &(O_RDONLY as u32)], &[]);
+        assert_eq!(call(&mut client, TLOPEN, open).0, TLOPEN + 1);
+        let (kind, data) = call(&mut client, TREAD, read.clone());
+        assert_eq!((kind, &data[4..]), (TREAD + 1, &b"ello"[..]));
+        let missing = body(&[&0u32, &3u32, &1u16], &["missing"]);
+        assert_eq!(call(&mut client, TWALK, missing).0, RLERROR);
+        let mkdir = [body(&[&0u32], &["dir"]), body(&[&0o755u32, &!0u32], &[])].concat();
+        assert_eq!(call(&mut client, TMKDIR, mkdir).0, TMKDIR + 1);
+        assert!(fs.lock().lookup_entry(FUSE_ROOT_ID, "dir").is_ok());
+
+        // another user reads the file, and cannot write to it
+        let (mut other, other_served) = connect(
+            &fs,
+            User {
+                uid: 1001,
+                gid: 1001,
+            },
+        );
+        call(&mut other, TVERSION, body(&[&8192u32], &["9P2000.L"]));
+        let attach = body(&[&0u32, &!0u32], &["other", ""]);
+        let attach = [attach, (!0u32).to_le_bytes().to_vec()].concat();
+        assert_eq!(call(&mut other, TATTACH, attach).0, TATTACH + 1);
+        let walk = body(&[&0u32, &1u32, &1u16], &["file"]);
+        assert_eq!(call(&mut other, TWALK, walk).0, TWALK + 1);
+        let open = body(&[&1u32, &(O_WRONLY as u32)], &[]);
+        assert_eq!(call(&mut other, TLOPEN, open), error(EACCES));
+        let create = [
+            body(&[&0u32], &["mine"]),
+            body(&[&(O_RDWR as u32), &0o644u32, &1001u32], &[]),
+        ]
+        .concat();
+        assert_eq!(call(&mut other, TLCREATE, create), error(EACCES));
+        let attach = body(&[&2u32, &!0u32], &["root", ""]);
+        let attach = [attach, 0u32.to_le_bytes().to_vec()].concat();
+        assert_eq!(call(&mut other, TATTACH, attach), error(EPERM));
+        // its lookups forgotten, not those of the first client
+        assert_eq!(call(&mut other, TCLUNK, body(&[&1u32], &[])).0, TCLUNK + 1);
+        drop(other);
+        other_served.join().unwrap();
+        let (kind, data) = call(&mut client, TREAD, read);
+        assert_eq!((kind, &data[4..]), (TREAD + 1, &b"ello"[..]));
+
+        drop(client);
+        served.join().unwrap();
+    }
+}
@@ -0,0 +1,430 @@
+use std::num::ParseIntError;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+
+use crate::compression::{CompressionError, CompressionRule};
+use crate::crypt::MasterKey;
+use crate::keys::KeySlot;
+use crate::publish::Sink;
+use crate::quota::Quota;
+use crate::sign::{SigningKey, VerifyingKey};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("line {0}: expected <name> = <value>")]
+    Syntax(usize),
+    #[error("line {0}: no option {1} reloadable")]
+    UnknownOption(usize, String),
+    #[error("line {0}: {1}")]
+    InvalidValue(usize, ParseIntError),
+    #[error("line {0}: {1}")]
+    InvalidRule(usize, CompressionError),
+}
+
+/// How the kernel page cache is used for file data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheMode {
+    /// Reads are cached by the kernel, writes go through to the filesystem.
+    PageCache,
+    /// Bypass the kernel page cache for every open file.
+    DirectIo,
+    /// Let the kernel cache writes and flush them back lazily.
+    /// Falls back to `PageCache` if the kernel does not support it.
+    Writeback,
+}
+
+impl Default for CacheMode {
+    fn default() -> Self {
+        CacheMode::PageCache
+    }
+}
+
+/// Whose prefetches are served in turn while fetches are contended, so
+/// one large sequential read does not hold up the others.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fairness {
+    /// In the order requested.
+    None,
+    /// In turn between open file handles.
+    Handle,
+    /// In turn between users.
+    Uid,
+}
+
+impl Default for Fairness {
+    fn default() -> Self {
+        Fairness::Handle
+    }
+}
+
+/// When chunks no longer holding data, truncated away or in a hole
+/// punched, are released to the provider.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Discard {
+    /// Deleted at once, so the space used by the provider follows the size
+    /// of files closely.
+    Eager,
+    /// Written as zeros, and deleted by the next garbage collection if
+    /// beyond the end of file.
+    Deferred,
+}
+
+impl Default for Discard {
+    fn default() -> Self {
+        Discard::Deferred
+    }
+}
+
+/// Tokio runtime running the background work of a mounted filesystem:
+/// prefetching, writing back, committing the journal and polling changes.
+#[derive(Clone, Debug)]
+pub enum RuntimeOptions {
+    /// Spawn a runtime dedicated to the filesystem, with `worker_threads`
+    /// running timers and at most `blocking_threads` calling providers.
+    Dedicated {
+        worker_threads: usize,
+        blocking_threads: usize,
+    },
+    /// Run on the runtime of the caller, which has timers enabled.
+    Handle(Handle),
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        RuntimeOptions::Dedicated {
+            worker_threads: 1,
+            blocking_threads: 16,
+        }
+    }
+}
+
+/// FUSE implementation mounting on macOS.
+#[cfg(all(feature = "macos", target_os = "macos"))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MacBackend {
+    /// The macFUSE kernel extension.
+    MacFuse,
+    /// fuse-t, serving the mount to the kernel over NFS without an extension.
+    FuseT,
+}
+
+/// How the filesystem shows in Finder and handles its metadata on macOS.
+#[cfg(all(feature = "macos", target_os = "macos"))]
+#[derive(Clone, Debug)]
+pub struct MacOptions {
+    pub backend: MacBackend,
+    /// Name of the volume in Finder.
+    pub volume_name: String,
+    /// Allow AppleDouble `._` files, which Finder creates beside every file
+    /// to hold extended attributes the filesystem does not support.
+    pub apple_double: bool,
+}
+
+#[cfg(all(feature = "macos", target_os = "macos"))]
+impl Default for MacOptions {
+    fn default() -> Self {
+        Self {
+            backend: MacBackend::MacFuse,
+            volume_name: "eoss".to_owned(),
+            apple_double: false,
+        }
+    }
+}
+
+/// Options of a mounted filesystem.
+#[derive(Clone, Debug)]
+pub struct MountOptions {
+    pub cache_mode: CacheMode,
+    /// How long the kernel may cache attributes of an inode.
+    /// Zero for strict consistency, long for immutable datasets.
+    pub attr_ttl: Duration,
+    /// How long the kernel may cache the result of a lookup.
+    pub entry_ttl: Duration,
+    /// Move files shrunk to fit in a shared chunk back into one.
+    pub demote_tiny_files: bool,
+    /// Shared chunks with less than this fraction of blocks used are
+    /// rewritten on compaction.
+    pub compact_threshold: f64,
+    /// Interval of polling the provider for chunks modified by other clients,
+    /// `None` disables polling.
+    pub invalidate_interval: Option<Duration>,
+    /// Interval of polling for a tree published by the writer of a
+    /// filesystem mounted read-only, which then replaces the one mounted.
+    /// `None` disables polling.
+    pub refresh_interval: Option<Duration>,
+    /// Persist the directory tree once the journal holds this many records.
+    pub journal_records: u64,
+    /// Save journal records appended within this window in one chunk
+    /// write, committed at the latest on `fsync` or once the window
+    /// elapsed. `None` saves every record on append.
+    pub journal_batch: Option<Duration>,
+    /// Directories kept loaded in memory, least recently used ones beyond
+    /// are written back and unloaded.
+    pub meta_cache_dirs: usize,
+    /// Mount the snapshot of this name read-only instead of the live tree.
+    pub snapshot: Option<String>,
+    /// Collect unreferenced chunks at most this often while mounted,
+    /// `None` disables collecting online.
+    pub gc_interval: Option<Duration>,
+    /// Verify all chunks against the hashes of their files at most this
+    /// often while mounted, `None` disables scrubbing online.
+    pub scrub_interval: Option<Duration>,
+    /// When chunks truncated away or punched out are deleted.
+    pub discard: Discard,
+    /// Move unlinked files into the trash at the root, and keep them this
+    /// long before purging. `None` deletes them immediately.
+    pub trash_ttl: Option<Duration>,
+    /// Bytes of chunks cached in memory, zero disables the cache.
+    pub chunk_cache_bytes: usize,
+    /// Keep writes in the chunk cache until `fsync`, or written back in
+    /// background beyond `dirty_bytes` or `dirty_age`. Otherwise every
+    /// metadata change is flushed along with the data before it.
+    pub write_back: bool,
+    pub dirty_bytes: usize,
+    pub dirty_age: Duration,
+    /// Chunks prefetched at most ahead of sequential reads into the chunk
+    /// cache, zero disables readahead.
+    pub readahead_chunks: usize,
+    /// Chunks fetched at most at a time into the chunk cache, by reads and
+    /// prefetching.
+    pub fetch_concurrency: usize,
+    pub fetch_fairness: Fairness,
+    /// Read chunks block by block as touched rather than whole, so random
+    /// reads of large files over remote providers transfer only the blocks
+    /// read. Blocks are checked against the outboard stored along with the
+    /// hash of their chunk, chunks without one are read whole. Chunks
+    /// cached or prefetched are read from the chunk cache.
+    pub lazy_chunks: bool,
+    /// Bytes of small sequential writes merged per file handle before
+    /// written, zero disables merging.
+    pub write_buffer_bytes: usize,
+    /// Directory keeping chunks fetched across restarts, along with the
+    /// chunk cache in memory. `None` disables the disk cache.
+    pub disk_cache: Option<PathBuf>,
+    pub disk_cache_bytes: usize,
+    /// Manifest of chunks prefetched into the chunk cache once mounted.
+    /// With `warmup_chunks` set, recorded on unmount with as many chunks
+    /// used most recently so the next mount starts warm, else kept as
+    /// given. `None` disables warm-up.
+    pub warmup: Option<PathBuf>,
+    pub warmup_chunks: usize,
+    /// Directory queueing changes while the provider is unreachable, up
+    /// to `spool_bytes`, replayed every `resync_interval`. `None` fails
+    /// writes while unreachable.
+    pub spool: Option<PathBuf>,
+    pub spool_bytes: usize,
+    pub resync_interval: Duration,
+    /// Master key of an encrypted filesystem, required to mount one and
+    /// refused for one in the clear.
+    pub key: Option<MasterKey>,
+    /// Chunks resealed under a new key epoch every `rekey_interval`.
+    pub rekey_batch: usize,
+    pub rekey_interval: Duration,
+    /// Key signing chunks of a signed filesystem, required to modify one.
+    pub signing_key: Option<SigningKey>,
+    /// Key verifying chunks of a signed filesystem, mounted read-only
+    /// unless `signing_key` is given.
+    pub verifying_key: Option<VerifyingKey>,
+    /// Refuse mounting without `signing_key` a signed superblock older than
+    /// the one of this sequence, the latest a reader knows of.
+    pub min_sequence: u64,
+    /// Refuse mounting without `signing_key` a signed superblock stored by
+    /// its signer longer ago, so a provider replaying an old one is found
+    /// out, the signer storing it again more often. `None` accepts any.
+    pub max_signature_age: Option<Duration>,
+    pub runtime: RuntimeOptions,
+    /// Bytes held at most by chunks, caches and write buffers, beyond which
+    /// caches are shrunk and new chunks wait. `None` is unlimited.
+    pub memory_limit: Option<usize>,
+    /// FUSE requests taking longer are logged at warn, with their inode,
+    /// offset and size. `None` logs none.
+    pub slow_op: Option<Duration>,
+    /// Open read-only, refusing every change, without taking the lease nor
+    /// writing the dirty flag. The tree is the one last persisted.
+    pub read_only: bool,
+    /// Open read-only without writing anything, not even the dirty flag,
+    /// to check the filesystem can be mounted.
+    pub dry_run: bool,
+    /// Open read-only, reading every chunk from the provider past all
+    /// caches so it is checked against its hash, and metadata against its
+    /// signature, on each access. Reads failing a check fail with `EIO`, as
+    /// do reads of chunks not hashed yet. A signed filesystem is checked
+    /// against `verifying_key` only, required then.
+    pub verify: bool,
+    /// Count reads and writes of files and of their chunks, exported through
+    /// the control socket to build tiering policies and warm-up manifests.
+    pub heatmap: bool,
+    /// Derive inode numbers and generations from entry ids, and resolve
+    /// inodes the kernel no longer holds, so the mount can be re-exported
+    /// over NFS.
+    pub nfs_export: bool,
+    /// Reads of file data served in parallel on the runtime while other
+    /// requests are served, zero serves every request on the session thread.
+    pub parallel_reads: usize,
+    /// Fail saving chunks another writer modified since this mount read or
+    /// saved them, for providers shared by several writers. Costs asking
+    /// the provider for the generation of each chunk read.
+    pub detect_conflicts: bool,
+    /// Hold a lease on mounting read-write, renewed within this long, so
+    /// the filesystem cannot be mounted read-write elsewhere meanwhile.
+    /// `None` mounts without one.
+    pub lease_ttl: Option<Duration>,
+    /// Publish changes of the tree to a webhook or a NATS subject once
+    /// mounted, `None` publishes none.
+    pub publish: Option<Sink>,
+    /// Compression of files by extension, unless set on them or on a
+    /// directory above with the compression xattr. The first rule matching
+    /// applies.
+    pub compression_rules: Vec<CompressionRule>,
+    #[cfg(all(feature = "macos", target_os = "macos"))]
+    pub mac: MacOptions,
+}
+
+impl MountOptions {
+    /// Key verifying chunks, if the filesystem is signed.
+    pub fn signer(&self) -> Option<VerifyingKey> {
+        match &self.signing_key {
+            Some(signing) => Some(signing.verifying_key()),
+            None => self.verifying_key,
+        }
+    }
+
+    /// Set the options reloadable while mounted from `config`, lines of
+    /// `<name> = <value>`: `chunk_cache_bytes` and `memory_limit`, a number
+    /// of bytes or `none`, and `compress`, a rule `<extension>=<compression>`
+    /// per line, which replace the rules set before, none clearing them.
+    /// Blank lines and lines from `#` are ignored.
+    pub fn apply_config(&mut self, config: &str) -> Result<(), ConfigError> {
+        let mut rules = Vec::new();
+        for (n, line) in config.lines().enumerate().map(|(n, line)| (n + 1, line)) {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = line.split_once('=').ok_or(ConfigError::Syntax(n))?;
+            let bytes = |value: &str| value.parse().map_err(|e| ConfigError::InvalidValue(n, e));
+            match (name.trim(), value.trim()) {
+                ("chunk_cache_bytes", value) => self.chunk_cache_bytes = bytes(value)?,
+                ("memory_limit", "none") => self.memory_limit = None,
+                ("memory_limit", value) => self.memory_limit = Some(bytes(value)?),
+                ("compress", value) => {
+                    rules.push(value.parse().map_err(|e| ConfigError::InvalidRule(n, e))?)
+                }
+                (name, _) => return Err(ConfigError::UnknownOption(n, name.to_owned())),
+            }
+        }
+        self.compression_rules = rules;
+        Ok(())
+    }
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            cache_mode: CacheMode::default(),
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            demote_tiny_files: false,
+            compact_threshold: 0.5,
+            invalidate_interval: None,
+            refresh_interval: None,
+            journal_records: 1024,
+            journal_batch: Some(Duration::from_millis(50)),
+            meta_cache_dirs: 4096,
+            snapshot: None,
+            gc_interval: None,
+            scrub_interval: None,
+            discard: Discard::default(),
+            trash_ttl: None,
+            chunk_cache_bytes: 256 << 20,
+            write_back: false,
+            dirty_bytes: 64 << 20,
+            dirty_age: Duration::from_secs(30),
+            readahead_chunks: 8,
+            fetch_concurrency: 8,
+            fetch_fairness: Fairness::default(),
+            lazy_chunks: false,
+            write_buffer_bytes: 64 << 10,
+            disk_cache: None,
+            disk_cache_bytes: 1 << 30,
+            warmup: None,
+            warmup_chunks: 0,
+            spool: None,
+            spool_bytes: 1 << 30,
+            resync_interval: Duration::from_secs(5),
+            key: None,
+            rekey_batch: 16,
+            rekey_interval: Duration::from_secs(1),
+            signing_key: None,
+            verifying_key: None,
+            min_sequence: 0,
+            max_signature_age: None,
+            runtime: RuntimeOptions::default(),
+            memory_limit: None,
+            slow_op: None,
+            read_only: false,
+            dry_run: false,
+            heatmap: false,
+            verify: false,
+            nfs_export: false,
+            parallel_reads: 4,
+            detect_conflicts: false,
+            lease_ttl: Some(Duration::from_secs(30)),
+            publish: None,
+            compression_rules: Vec::new(),
+            #[cfg(all(feature = "macos", target_os = "macos"))]
+            mac: MacOptions::default(),
+        }
+    }
+}
+
+/// Options of formatting a new filesystem.
+#[derive(Clone, Debug)]
+pub struct FormatOptions {
+    /// Overwrite an existing filesystem.
+    pub force: bool,
+    /// Permission of the root directory.
+    pub perm: u16,
+    /// Owner of the root directory.
+    pub uid: u32,
+    pub gid: u32,
+    /// Encrypt the filesystem with a master key.
+    pub key: Option<MasterKey>,
+    /// Ways to unlock the master key, e.g. a passphrase.
+    pub key_slots: Vec<KeySlot>,
+    /// Seal equal chunks alike so they can be deduplicated, see
+    /// `Mode::Convergent`.
+    pub convergent: bool,
+    /// Sign chunks, so the filesystem can be published and verified by
+    /// readers holding only the verifying key.
+    pub signing_key: Option<SigningKey>,
+    /// Store chunks of files at the hash of their contents once flushed,
+    /// so equal chunks are stored once. Refused along with `key`, ids are
+    /// not sealed.
+    pub content_addressed: bool,
+    /// Compress chunks of files as their compression says, see
+    /// `COMPRESSION_XATTR`, reading chunks whole.
+    pub compressed: bool,
+    /// Quota of the root directory.
+    pub quota: Quota,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            force: false,
+            perm: 0o755,
+            uid: 0,
+            gid: 0,
+            key: None,
+            key_slots: Vec::new(),
+            convergent: false,
+            signing_key: None,
+            content_addressed: false,
+            compressed: false,
+            quota: Quota::default(),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::fs::{DirMeta, Entry};
+use crate::id::Id;
+
+/// Extended attribute pinning an entry in the chunk cache, chunks of a
+/// pinned file or of files beneath a pinned directory are prefetched at
+/// mount and never evicted.
+pub const PIN_XATTR: &str = "user.eoss.pinned";
+
+/// Ids of chunks of the file at `entry`, or of all files beneath if a
+/// directory, which has to be fully loaded.
+pub fn chunk_ids(entry: Entry) -> Vec<Id> {
+    let mut ids = Vec::new();
+    collect(entry, &mut ids);
+    ids
+}
+
+/// Ids of chunks of files pinned in the tree at `dir`, or beneath a pinned
+/// directory, counting `dir` itself pinned if `pinned`.
+pub fn pinned_chunks(dir: &DirMeta, pinned: bool) -> Vec<Id> {
+    if pinned || dir.attrs.pinned() {
+        return chunk_ids(Entry::Dir(dir));
+    }
+    let mut ids = Vec::new();
+    for node in dir.entries.values() {
+        match node.as_entry() {
+            Entry::Dir(sub) => ids.extend(pinned_chunks(sub, false)),
+            entry if entry.attrs().pinned() => collect(entry, &mut ids),
+            _ => {}
+        }
+    }
+    ids
+}
+
+fn collect(entry: Entry, ids: &mut Vec<Id>) {
+    match entry {
+        Entry::Dir(dir) => {
+            for node in dir.entries.values() {
+                collect(node.as_entry(), ids);
+            }
+        }
+        Entry::File(file) => {
+            ids.extend((0..file.chunk_count()).map(|n| file.chunk_id(n).into_id()))
+        }
+        // the whole shared chunk is kept
+        Entry::TinyFile(file) if file.chunk_blocks > 0 => ids.push(file.chunk_id.clone()),
+        Entry::TinyFile(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pinned_chunks;
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fs::{Attrs, DirMeta, FileMeta, Node, PINNED_FL};
+    use crate::id::{Id, ID_LENGTH};
+
+    #[test]
+    fn test_pinned_chunks() {
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut dir = DirMeta::new("bin".to_owned(), Attrs::new(0o755, 0, 0));
+        for (n, name) in ["a", "b"].iter().enumerate() {
+            let mut file = FileMeta {
+                name: name.to_string(),
+                id: Id::new([n as u8; ID_LENGTH]),
+                attrs: Attrs::new(0o755, 0, 0),
+                hashes: Vec::new(),
+                content: false,
+            };
+            file.attrs.set_size(2 * CHUNK_SIZE as u64);
+            dir.insert(Node::File(file));
+        }
+        root.insert(Node::Dir(dir.clone()));
+        assert!(pinned_chunks(&root, false).is_empty());
+        assert_eq!(pinned_chunks(&dir, true).len(), 4);
+
+        dir.attrs.flags |= PINNED_FL;
+        root.insert(Node::Dir(dir));
+        assert_eq!(pinned_chunks(&root, false).len(), 4);
+    }
+}
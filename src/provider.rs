@@ -1,14 +1,104 @@
-use crate::chunk::{Chunk, ChunkError};
-use crate::id::Id;
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::pin::Pin;
+
+#[cfg(unix)]
+use libc::{EDQUOT, ESTALE};
+
+/// The types taken and returned by providers, for those implementing one.
+pub use crate::chunk::{
+    Block, BlockError, Chunk, ChunkError, BLOCK_PER_CHUNK, BLOCK_SIZE, CHUNK_SIZE,
+};
+pub use crate::compression::Compression;
+pub use crate::crypt::CryptError;
+pub use crate::id::{Id, IdError};
+pub use crate::sign::SignError;
+pub use crate::tier::StorageClass;
+/// Providers are opened from their URIs by those embedding a `Vfs`.
+pub use crate::uri::{ProviderUri, UriError};
+#[cfg(windows)]
+use crate::win::{EDQUOT, ESTALE};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ChunkProviderError {
+    #[error("not found: {0}")]
+    NotFound(io::Error),
+    #[error("permission denied: {0}")]
+    PermissionDenied(io::Error),
+    #[error("throttled: {0}")]
+    Throttled(io::Error),
+    #[error("timed out: {0}")]
+    Timeout(io::Error),
+    /// The backend failed otherwise.
     #[error(transparent)]
-    IoError(#[from] std::io::Error),
+    IoError(io::Error),
     #[error(transparent)]
     ChunkError(#[from] ChunkError),
+    #[error(transparent)]
+    CryptError(#[from] CryptError),
+    #[error("{0} does not match its hash")]
+    IntegrityError(Id),
+    #[error(transparent)]
+    SignError(#[from] SignError),
+    #[error("prefix {0} matches {1} chunks")]
+    AmbiguousPrefix(String, usize),
+    #[error("{0} was modified by another writer")]
+    Conflict(Id),
+}
+
+impl From<io::Error> for ChunkProviderError {
+    /// Classify a failure of the backend by its kind.
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => ChunkProviderError::NotFound(e),
+            io::ErrorKind::PermissionDenied => ChunkProviderError::PermissionDenied(e),
+            io::ErrorKind::WouldBlock => ChunkProviderError::Throttled(e),
+            io::ErrorKind::TimedOut => ChunkProviderError::Timeout(e),
+            _ => ChunkProviderError::IoError(e),
+        }
+    }
 }
 
+impl ChunkProviderError {
+    /// Whether the call may succeed once retried later, as the backend was
+    /// throttling, slow or dropped the connection, rather than refused it.
+    pub fn is_retryable(&self) -> bool {
+        use io::ErrorKind::*;
+        match self {
+            ChunkProviderError::Throttled(_) | ChunkProviderError::Timeout(_) => true,
+            ChunkProviderError::IoError(e) => matches!(
+                e.kind(),
+                Interrupted | ConnectionRefused | ConnectionReset | ConnectionAborted | BrokenPipe
+            ),
+            _ => false,
+        }
+    }
+
+    /// Errno reported by a mount for the error, `EIO` unless a more precise
+    /// one tells users what went wrong.
+    pub fn errno(&self) -> i32 {
+        match self {
+            // a chunk missing beneath an entry found is lost data
+            ChunkProviderError::NotFound(_) => libc::EIO,
+            ChunkProviderError::PermissionDenied(_) => libc::EACCES,
+            // once the spool is full while the provider is unreachable
+            ChunkProviderError::Throttled(_) => libc::EAGAIN,
+            ChunkProviderError::Timeout(_) => libc::ETIMEDOUT,
+            ChunkProviderError::Conflict(_) => ESTALE,
+            ChunkProviderError::AmbiguousPrefix(..) => libc::EINVAL,
+            ChunkProviderError::IoError(e) => match e.raw_os_error() {
+                Some(errno @ (libc::ENOSPC | EDQUOT | libc::EROFS)) => errno,
+                _ => libc::EIO,
+            },
+            _ => libc::EIO,
+        }
+    }
+}
+
+/// Future of `ChunkProvider::save_chunk_streaming`.
+pub type SaveFuture<'a> = Pin<Box<dyn Future<Output = Result<(), ChunkProviderError>> + Send + 'a>>;
+
 pub trait ChunkProvider: Send + Sync {
     /// Request a chunk from the provider with chunk id
     fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError>;
@@ -19,8 +109,24 @@ pub trait ChunkProvider: Send + Sync {
             .map(|id| self.get_chunk_by_id(id))
             .collect()
     }
+    /// Request blocks `blocks` of a chunk, zero if it does not exist, so
+    /// a chunk read partly transfers only those. Takes them out of the whole
+    /// chunk unless the provider can read a range.
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        Ok(self.get_chunk_by_id(id)?.into_blocks(blocks))
+    }
     /// Save modifications of a chunk, create if not exists
     fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError>;
+    /// Save a chunk still being written, consuming it through
+    /// `Chunk::read` block by block as its writer releases them, so the
+    /// upload overlaps with the writes. Saves it once released unless the
+    /// provider can store it as streamed.
+    fn save_chunk_streaming<'a>(&'a self, chunk: &'a Chunk) -> SaveFuture<'a> {
+        Box::pin(async move {
+            chunk.released().await;
+            self.save_chunk(chunk)
+        })
+    }
     /// Save modifications of all chunks, create if not exists
     fn save_all_chunks(&self, chunks: &[&Chunk]) -> Result<(), ChunkProviderError> {
         for chunk in chunks {
@@ -28,8 +134,138 @@ pub trait ChunkProvider: Send + Sync {
         }
         Ok(())
     }
+    /// Check whether a chunk exists, without creating it
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError>;
+    /// List ids of all chunks stored.
+    /// Returns `None` if the provider cannot enumerate its chunks.
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        Ok(None)
+    }
+    /// Ids of the chunks stored whose hex starts with `prefix`, as
+    /// abbreviated by tools, at most one as more is ambiguous.
+    fn resolve_prefix(&self, prefix: &str) -> Result<Vec<Id>, ChunkProviderError> {
+        let prefix = prefix.to_ascii_lowercase();
+        if !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        }
+        // a whole id needs no listing
+        if let Ok(id) = Id::from_hex(&prefix) {
+            return Ok(match self.contains_chunk(&id)? {
+                true => vec![id],
+                false => Vec::new(),
+            });
+        }
+        let ids = self
+            .list_chunks()?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?;
+        let found: Vec<Id> = ids
+            .into_iter()
+            .filter(|id| id.hex().starts_with(&prefix))
+            .collect();
+        if found.len() > 1 {
+            return Err(ChunkProviderError::AmbiguousPrefix(prefix, found.len()));
+        }
+        Ok(found)
+    }
+    /// Request the stored bytes of a chunk, of any length, for providers
+    /// stacked on top storing chunks transformed, e.g. sealed.
+    /// Returns `None` if the chunk does not exist.
+    fn get_object(&self, _id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+    /// Store bytes of any length as a chunk, create if not exists
+    fn save_object(&self, _id: &Id, _data: &[u8]) -> Result<(), ChunkProviderError> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+    /// Delete a chunk, deleting a non-existent chunk is not an error
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError>;
+    /// Request the generation of a chunk, which changes whenever the chunk is modified.
+    /// Returns `None` if the chunk does not exist or the provider cannot track it.
+    fn generation(&self, _id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        Ok(None)
+    }
+    /// Save modifications of a chunk only if its generation is still
+    /// `generation`, `None` for a chunk not stored, failing with `Conflict`
+    /// otherwise. Returns the generation saved.
+    /// Compares then saves, unless the provider can do both at once.
+    fn save_chunk_if(
+        &self,
+        chunk: &Chunk,
+        generation: Option<u64>,
+    ) -> Result<Option<u64>, ChunkProviderError> {
+        if self.generation(chunk.id())? != generation {
+            return Err(ChunkProviderError::Conflict(chunk.id().clone()));
+        }
+        self.save_chunk(chunk)?;
+        self.generation(chunk.id())
+    }
+    /// Store bytes as a chunk only if its generation is still `generation`,
+    /// like `save_chunk_if`.
+    fn save_object_if(
+        &self,
+        id: &Id,
+        data: &[u8],
+        generation: Option<u64>,
+    ) -> Result<Option<u64>, ChunkProviderError> {
+        if self.generation(id)? != generation {
+            return Err(ChunkProviderError::Conflict(id.clone()));
+        }
+        self.save_object(id, data)?;
+        self.generation(id)
+    }
+    /// Drop copies of a chunk cached along the way, after it is modified by
+    /// another client.
+    fn invalidate(&self, _id: &Id) {}
+    /// Store chunk `id` in the tier of `class` from its next save on,
+    /// ignored by providers with a single tier.
+    fn hint_class(&self, _id: &Id, _class: StorageClass) {}
+    /// Compress chunk `id` as `compression` from its next save on, ignored
+    /// by providers storing chunks as they are.
+    fn hint_compression(&self, _id: &Id, _compression: Compression) {}
     /// Request the provider to flush all cached writes.
     fn flush(&self) -> Result<(), ChunkProviderError> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{ChunkProvider, ChunkProviderError, ESTALE};
+    use crate::id::Id;
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_resolve_prefix() {
+        let provider = MemoryProvider::new();
+        let (a, b) = (Id::new([0xab; 32]), Id::new([0xac; 32]));
+        provider.save_object(&a, b"a").unwrap();
+        provider.save_object(&b, b"b").unwrap();
+        assert_eq!(provider.resolve_prefix("ABab").unwrap(), vec![a.clone()]);
+        assert_eq!(provider.resolve_prefix(a.hex()).unwrap(), vec![a]);
+        assert!(provider.resolve_prefix("ff").unwrap().is_empty());
+        assert!(matches!(
+            provider.resolve_prefix("a"),
+            Err(ChunkProviderError::AmbiguousPrefix(_, 2))
+        ));
+        assert!(provider.resolve_prefix("xyz").is_err());
+    }
+
+    #[test]
+    fn test_errno() {
+        let errors: Vec<(ChunkProviderError, i32, bool)> = vec![
+            (io::Error::from(io::ErrorKind::NotFound).into(), libc::EIO, false),
+            (io::Error::from_raw_os_error(libc::EACCES).into(), libc::EACCES, false),
+            (io::Error::new(io::ErrorKind::WouldBlock, "full").into(), libc::EAGAIN, true),
+            (io::Error::from(io::ErrorKind::TimedOut).into(), libc::ETIMEDOUT, true),
+            (io::Error::from(io::ErrorKind::ConnectionReset).into(), libc::EIO, true),
+            (io::Error::from_raw_os_error(libc::ENOSPC).into(), libc::ENOSPC, false),
+            (ChunkProviderError::IntegrityError(Id::new_random()), libc::EIO, false),
+            (ChunkProviderError::Conflict(Id::new_random()), ESTALE, false),
+        ];
+        for (error, errno, retryable) in errors {
+            assert_eq!((error.errno(), error.is_retryable()), (errno, retryable), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::chunk::{self, Block, Chunk, BLOCK_SIZE, CHUNK_SIZE};
+use crate::chunkcache::ChunkCache;
+use crate::compression::Compression;
+use crate::diskcache::DiskCache;
+use crate::fetcher::{Fetcher, Priority};
+use crate::governor::Reclaim;
+use crate::id::Id;
+use crate::merkle::{self, UNHASHED};
+use crate::provider::{ChunkProvider, ChunkProviderError, SaveFuture};
+use crate::tier::StorageClass;
+
+/// CachedProvider keeps recently used chunks of another provider in a
+/// `ChunkCache`, so reads of the same chunk are not fetched again and
+/// writes are deferred until evicted or flushed.
+/// Chunks fetched are also kept in a `DiskCache` if set, surviving restarts.
+/// Chunks missed are fetched through a `Fetcher`, reads before prefetches.
+/// Chunks fetched or read from the disk cache are verified against the
+/// hashes expected of them, if any.
+pub struct CachedProvider {
+    inner: Arc<dyn ChunkProvider>,
+    fetcher: Fetcher,
+    cache: Mutex<ChunkCache>,
+    disk: Option<Mutex<DiskCache>>,
+    /// Hashes of chunks to verify once loaded
+    expected: Mutex<HashMap<Id, [u8; 32]>>,
+}
+
+impl CachedProvider {
+    /// Cache chunks of `inner` within `budget` bytes, fetching at most
+    /// `concurrency` chunks at a time.
+    pub fn new(inner: Arc<dyn ChunkProvider>, budget: usize, concurrency: usize) -> Self {
+        Self {
+            fetcher: Fetcher::new(inner.clone(), concurrency),
+            inner,
+            cache: Mutex::new(ChunkCache::new(budget)),
+            disk: None,
+            expected: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep chunks fetched in `disk` as well.
+    pub fn with_disk_cache(mut self, disk: DiskCache) -> Self {
+        self.disk = Some(Mutex::new(disk));
+        self
+    }
+
+    /// Drop chunk `id` from the disk cache, the disk cache is best effort.
+    fn forget(&self, id: &Id) {
+        if let Some(disk) = &self.disk {
+            let _ = disk.lock().remove(id);
+        }
+    }
+}
+
+impl CachedProvider {
+    /// Keep chunks `ids` in the cache once fetched, or stop keeping them if
+    /// not `pinned`. Returns false, pinning none, if pinned chunks would
+    /// take more than half of the cache.
+    pub fn pin(&self, ids: &[Id], pinned: bool) -> bool {
+        let mut cache = self.cache.lock();
+        if pinned && !cache.pinnable(ids) {
+            return false;
+        }
+        for id in ids {
+            cache.pin(id.clone(), pinned);
+        }
+        true
+    }
+
+    /// Fetch chunk `id` into the cache ahead of being read on behalf of
+    /// `owner`, if not cached.
+    pub fn prefetch(&self, id: &Id, owner: u64) -> Result<(), ChunkProviderError> {
+        if self.cache.lock().contains(id) {
+            return Ok(());
+        }
+        self.load(id, Priority::Background(owner)).map(|_| ())
+    }
+
+    /// Verify chunk `id` against `hash` when next loaded, unless cached
+    /// already or `hash` is unknown.
+    pub fn expect(&self, id: &Id, hash: [u8; 32]) {
+        if hash == UNHASHED || self.cache.lock().contains(id) {
+            return;
+        }
+        self.expected.lock().insert(id.clone(), hash);
+    }
+
+    /// Copy of chunk `id` held by the caches, never fetched.
+    pub fn cached_copy(&self, id: &Id) -> Option<Vec<u8>> {
+        if let Some(data) = self.cache.lock().get(id) {
+            return Some(data.to_vec());
+        }
+        let disk = self.disk.as_ref()?;
+        let copy = disk.lock().get(self.inner.as_ref(), id);
+        copy.ok().flatten()
+    }
+
+    /// Data of chunk `id` cached in memory, never fetched.
+    pub fn resident(&self, id: &Id) -> Option<Arc<Vec<u8>>> {
+        self.cache.lock().get(id)
+    }
+
+    /// Keep `data` of chunk `id` as stored, unless cached already, so it is
+    /// not fetched again.
+    pub fn seed(&self, id: &Id, data: Vec<u8>) -> Result<(), ChunkProviderError> {
+        let mut cache = self.cache.lock();
+        if cache.contains(id) {
+            return Ok(());
+        }
+        cache.insert(self.inner.as_ref(), id.clone(), data, false)
+    }
+
+    /// The provider cached.
+    pub fn inner(&self) -> &Arc<dyn ChunkProvider> {
+        &self.inner
+    }
+
+    /// Bytes of chunks cached in memory.
+    pub fn cached_bytes(&self) -> usize {
+        self.cache.lock().size()
+    }
+
+    /// Bytes of dirty chunks cached in memory.
+    pub fn dirty_bytes(&self) -> usize {
+        self.cache.lock().dirty_size()
+    }
+
+    /// Ids of at most `n` chunks cached in memory, most recently used first.
+    pub fn recent(&self, n: usize) -> Vec<Id> {
+        self.cache.lock().recent(n)
+    }
+
+    /// Bytes of dirty chunks cached in memory among `ids`.
+    pub fn dirty_bytes_of(&self, ids: &[Id]) -> usize {
+        self.cache.lock().dirty_size_of(ids)
+    }
+
+    /// How long the chunk dirty for the longest has been waiting to be
+    /// written back.
+    pub fn dirty_age(&self) -> Option<Duration> {
+        self.cache.lock().oldest_dirty().map(|at| at.elapsed())
+    }
+
+    /// Drop the clean chunks cached in memory, not pinned.
+    pub fn drop_clean(&self) {
+        self.cache.lock().drop_clean();
+    }
+
+    /// Cache chunks within `budget` bytes from now on.
+    pub fn set_budget(&self, budget: usize) -> Result<(), ChunkProviderError> {
+        self.cache.lock().set_budget(self.inner.as_ref(), budget)
+    }
+
+    /// Data of chunk `id`, shared with the cache rather than copied.
+    pub fn chunk_data(&self, id: &Id) -> Result<Arc<Vec<u8>>, ChunkProviderError> {
+        self.load(id, Priority::current())
+    }
+
+    /// Write back dirty chunks while more than `max_bytes` are dirty, or
+    /// dirty for longer than `max_age`, in the order they were modified.
+    /// Returns the number of chunks written.
+    pub fn write_back_due(
+        &self,
+        max_bytes: usize,
+        max_age: Duration,
+    ) -> Result<usize, ChunkProviderError> {
+        let written = self
+            .cache
+            .lock()
+            .write_back_due(self.inner.as_ref(), max_bytes, max_age)?;
+        if written > 0 {
+            self.inner.flush()?;
+        }
+        Ok(written)
+    }
+
+    /// Write back chunks that became dirty at `point` or before, along with
+    /// all modified before, and flush the provider cached so they are
+    /// durable. Returns the number of chunks written.
+    pub fn write_back_before(&self, point: Instant) -> Result<usize, ChunkProviderError> {
+        let written = self
+            .cache
+            .lock()
+            .write_back_before(self.inner.as_ref(), point)?;
+        self.inner.flush()?;
+        Ok(written)
+    }
+}
+
+impl CachedProvider {
+    /// Data of chunk `id`, from the caches or fetched at `priority`.
+    fn load(&self, id: &Id, priority: Priority) -> Result<Arc<Vec<u8>>, ChunkProviderError> {
+        if let Some(data) = self.cache.lock().get(id) {
+            return Ok(data);
+        }
+        let _span = tracing::debug_span!("cache.miss", id = %id.hex()).entered();
+        // not holding the cache while fetching
+        let on_disk = match &self.disk {
+            Some(disk) => disk.lock().get(self.inner.as_ref(), id).ok().flatten(),
+            None => None,
+        };
+        let fetched = on_disk.is_none();
+        // taken before the data, so a change in between is found stale
+        let generation = match &self.disk {
+            Some(_) if fetched => self.inner.generation(id)?,
+            _ => None,
+        };
+        let data = match on_disk {
+            Some(data) => data,
+            None => self.fetcher.fetch(id, priority)?,
+        };
+        let expected = self.expected.lock().remove(id);
+        if expected.map_or(false, |hash| hash != merkle::chunk_hash(&data)) {
+            self.forget(id);
+            return Err(ChunkProviderError::IntegrityError(id.clone()));
+        }
+        let mut cache = self.cache.lock();
+        if let Some(data) = cache.get(id) {
+            // saved meanwhile
+            return Ok(data);
+        }
+        if let Some(disk) = self.disk.as_ref().filter(|_| fetched) {
+            let _ = disk.lock().insert(id.clone(), &data, generation);
+        }
+        let data = Arc::new(data);
+        cache.insert(self.inner.as_ref(), id.clone(), data.clone(), false)?;
+        Ok(data)
+    }
+}
+
+impl Reclaim for CachedProvider {
+    fn reclaim(&self, bytes: usize) {
+        // the cache is left to its holder, stalled for a while at most
+        if let Some(mut cache) = self.cache.try_lock() {
+            let _ = cache.shrink(self.inner.as_ref(), bytes);
+        }
+    }
+}
+
+impl ChunkProvider for CachedProvider {
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        let data = self.load(id, Priority::current())?;
+        Ok(Chunk::new_with_data(id.clone(), data.to_vec())?)
+    }
+
+    /// Blocks of the chunk cached, else read from the provider without
+    /// caching the chunk.
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        let cached = self.cache.lock().get(id);
+        match cached {
+            Some(data) => Ok(chunk::split(
+                &data[blocks.start * BLOCK_SIZE..blocks.end * BLOCK_SIZE],
+            )?),
+            None => self.inner.get_blocks(id, blocks),
+        }
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        let mut data = vec![0; CHUNK_SIZE];
+        chunk.read_at(0, &mut data);
+        let mut cache = self.cache.lock();
+        self.forget(chunk.id());
+        cache.insert(self.inner.as_ref(), chunk.id().clone(), data, true)
+    }
+
+    /// Streamed through to the inner provider rather than deferred, any copy
+    /// cached dropped first so it is never written back over it.
+    fn save_chunk_streaming<'a>(&'a self, chunk: &'a Chunk) -> SaveFuture<'a> {
+        self.cache.lock().remove(chunk.id());
+        self.forget(chunk.id());
+        self.inner.save_chunk_streaming(chunk)
+    }
+
+    /// Objects are not cached.
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        self.inner.get_object(id)
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        self.cache.lock().remove(id);
+        self.forget(id);
+        self.inner.save_object(id, data)
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        // clean chunks cached may be read as zeros without existing
+        Ok(self.cache.lock().is_dirty(id) || self.inner.contains_chunk(id)?)
+    }
+
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        let mut ids = match self.inner.list_chunks()? {
+            Some(ids) => ids,
+            None => return Ok(None),
+        };
+        let stored: HashSet<Id> = ids.iter().cloned().collect();
+        let dirty = self.cache.lock().dirty();
+        ids.extend(dirty.into_iter().filter(|id| !stored.contains(id)));
+        Ok(Some(ids))
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        self.cache.lock().remove(id);
+        self.forget(id);
+        self.inner.delete_chunk(id)
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        self.inner.generation(id)
+    }
+
+    fn hint_class(&self, id: &Id, class: StorageClass) {
+        self.inner.hint_class(id, class)
+    }
+
+    fn hint_compression(&self, id: &Id, compression: Compression) {
+        self.inner.hint_compression(id, compression)
+    }
+
+    fn invalidate(&self, id: &Id) {
+        let mut cache = self.cache.lock();
+        // our own changes are kept
+        if !cache.is_dirty(id) {
+            cache.remove(id);
+            self.forget(id);
+        }
+        drop(cache);
+        self.inner.invalidate(id)
+    }
+
+    fn flush(&self) -> Result<(), ChunkProviderError> {
+        self.cache.lock().flush(self.inner.as_ref())?;
+        if let Some(disk) = &self.disk {
+            let _ = disk.lock().save_index();
+        }
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::ops::{Deref, Range};
+
+use parking_lot::Mutex;
+
+use crate::chunk::{Block, Chunk, CHUNK_SIZE};
+use crate::compression::{self, Compression};
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::tier::StorageClass;
+
+/// CompressedProvider compresses the chunks hinted to be before they reach
+/// the inner provider, which stores them as objects shorter than a chunk,
+/// so told apart from the chunks stored as they are.
+/// Chunks are read whole, as those compressed are not known before.
+pub struct CompressedProvider<P> {
+    inner: P,
+    /// Compression hinted for each chunk, from its next save on
+    hints: Mutex<HashMap<Id, Compression>>,
+}
+
+impl<P> CompressedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            hints: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<P> ChunkProvider for CompressedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        match self.inner.get_object(id)? {
+            Some(data) if data.len() == CHUNK_SIZE => Ok(Chunk::new_with_data(id.clone(), data)?),
+            Some(stored) => {
+                let data = compression::decompress(&stored)?;
+                Ok(Chunk::new_with_data(id.clone(), data)?)
+            }
+            None => Ok(Chunk::new(id.clone())),
+        }
+    }
+
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        Ok(self.get_chunk_by_id(id)?.into_blocks(blocks))
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        let compression = match self.hints.lock().get(chunk.id()) {
+            Some(compression) => *compression,
+            None => return self.inner.save_chunk(chunk),
+        };
+        let mut data = vec![0; CHUNK_SIZE];
+        chunk.read_at(0, &mut data);
+        match compression::compress(compression, &data) {
+            Some(stored) => self.inner.save_object(chunk.id(), &stored),
+            None => self.inner.save_chunk(chunk),
+        }
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        self.inner.get_object(id)
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        self.inner.save_object(id, data)
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        self.inner.contains_chunk(id)
+    }
+
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        self.inner.list_chunks()
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        self.hints.lock().remove(id);
+        self.inner.delete_chunk(id)
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        self.inner.generation(id)
+    }
+
+    fn hint_class(&self, id: &Id, class: StorageClass) {
+        self.inner.hint_class(id, class)
+    }
+
+    fn hint_compression(&self, id: &Id, compression: Compression) {
+        let mut hints = self.hints.lock();
+        match compression {
+            Compression::None => hints.remove(id),
+            compression => hints.insert(id.clone(), compression),
+        };
+    }
+
+    fn invalidate(&self, id: &Id) {
+        self.inner.invalidate(id)
+    }
+
+    fn flush(&self) -> Result<(), ChunkProviderError> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::CompressedProvider;
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::compression::Compression;
+    use crate::crypt::{KeyRing, MasterKey};
+    use crate::id::Id;
+    use crate::provider::ChunkProvider;
+    use crate::providers::encrypted::EncryptedProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+
+    #[test]
+    fn test_stored_compressed() {
+        let memory = Arc::new(MemoryProvider::new());
+        let keys = Arc::new(KeyRing::new(MasterKey::new_random()));
+        let encrypted = EncryptedProvider::new(memory.clone(), keys, Id::new(SUPERBLOCK_ID));
+        let plain = CompressedProvider::new(memory.clone());
+        let sealed = CompressedProvider::new(Arc::new(encrypted));
+        for provider in [&plain as &dyn ChunkProvider, &sealed] {
+            let mut stored = Vec::new();
+            for compression in [None, Some(Compression::Lz4), Some(Compression::Zstd(3))] {
+                let chunk = Chunk::new(Id::new_random());
+                chunk.write_at(0, &b"compressible ".repeat(1000));
+                if let Some(compression) = compression {
+                    provider.hint_compression(chunk.id(), compression);
+                }
+                provider.save_chunk(&chunk).unwrap();
+                let mut data = vec![0; CHUNK_SIZE];
+                provider
+                    .get_chunk_by_id(chunk.id())
+                    .unwrap()
+                    .read_at(0, &mut data);
+                assert_eq!(&data[..13], b"compressible ");
+                stored.push(memory.get_object(chunk.id()).unwrap().unwrap().len());
+            }
+            assert!(stored[0] >= CHUNK_SIZE);
+            assert!(stored[1..].iter().all(|stored| *stored < CHUNK_SIZE / 4));
+        }
+    }
+}
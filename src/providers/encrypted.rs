@@ -0,0 +1,265 @@
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::chunk::{Block, Chunk, CHUNK_SIZE};
+use crate::compression::Compression;
+use crate::crypt::{self, KeyRing};
+use crate::id::{ChunkId, Id};
+use crate::meta;
+use crate::provider::{ChunkProvider, ChunkProviderError, SaveFuture};
+use crate::tier::StorageClass;
+
+/// EncryptedProvider seals chunks with keys derived from a master key
+/// before they reach the inner provider, which stores them as objects.
+/// Metadata chunks are sealed like any other, only the superblock is kept
+/// in the clear so the key can be checked before anything is opened.
+pub struct EncryptedProvider<P> {
+    inner: P,
+    keys: Arc<KeyRing>,
+    /// The chunk holding the superblock
+    clear: Id,
+    /// Held while a chunk is written, by the first byte of its id, so a
+    /// chunk resealed is never written back over a newer one
+    writing: Vec<Mutex<()>>,
+}
+
+impl<P> EncryptedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    /// Seal chunks stored in `inner`, except the one holding the superblock
+    /// stored at `superblock_id`.
+    pub fn new(inner: P, keys: Arc<KeyRing>, superblock_id: Id) -> Self {
+        Self {
+            inner,
+            keys,
+            clear: meta::chunk_id(&ChunkId::root(superblock_id), 0),
+            writing: (0..16).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    pub fn keys(&self) -> &Arc<KeyRing> {
+        &self.keys
+    }
+
+    /// Seal chunk `id` again under the current epoch if sealed under an
+    /// older one. Chunks not opening under the keys, in the clear beneath
+    /// or of another tenant, are left as they are. Returns whether
+    /// resealed.
+    pub fn reseal(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        if *id == self.clear {
+            return Ok(false);
+        }
+        let _writing = self.lock(id);
+        let sealed = match self.inner.get_object(id)? {
+            Some(sealed) if crypt::epoch(&sealed) != Some(self.keys.current()) => sealed,
+            _ => return Ok(false),
+        };
+        let data = match crypt::open(&self.keys, id, &sealed) {
+            Ok(data) => data,
+            Err(_) => return Ok(false),
+        };
+        self.inner
+            .save_object(id, &crypt::seal(&self.keys, id, &data))?;
+        Ok(true)
+    }
+
+    fn lock(&self, id: &Id) -> parking_lot::MutexGuard<()> {
+        self.writing[id[0] as usize % self.writing.len()].lock()
+    }
+}
+
+impl<P> ChunkProvider for EncryptedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        if *id == self.clear {
+            return self.inner.get_chunk_by_id(id);
+        }
+        match self.inner.get_object(id)? {
+            Some(sealed) => {
+                let data = crypt::open(&self.keys, id, &sealed)?;
+                Ok(Chunk::new_with_data(id.clone(), data)?)
+            }
+            None => Ok(Chunk::new(id.clone())),
+        }
+    }
+
+    /// Chunks are sealed whole, so opened whole for any of their blocks,
+    /// but the superblock.
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        if *id == self.clear {
+            return self.inner.get_blocks(id, blocks);
+        }
+        Ok(self.get_chunk_by_id(id)?.into_blocks(blocks))
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        if *chunk.id() == self.clear {
+            return self.inner.save_chunk(chunk);
+        }
+        let mut data = vec![0; CHUNK_SIZE];
+        chunk.read_at(0, &mut data);
+        let sealed = crypt::seal(&self.keys, chunk.id(), &data);
+        let _writing = self.lock(chunk.id());
+        self.inner.save_object(chunk.id(), &sealed)
+    }
+
+    /// The superblock is streamed through, other chunks are sealed whole so
+    /// saved once released.
+    fn save_chunk_streaming<'a>(&'a self, chunk: &'a Chunk) -> SaveFuture<'a> {
+        if *chunk.id() == self.clear {
+            return self.inner.save_chunk_streaming(chunk);
+        }
+        Box::pin(async move {
+            chunk.released().await;
+            self.save_chunk(chunk)
+        })
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        if *id == self.clear {
+            return self.inner.get_object(id);
+        }
+        match self.inner.get_object(id)? {
+            Some(sealed) => Ok(Some(crypt::open(&self.keys, id, &sealed)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        if *id == self.clear {
+            return self.inner.save_object(id, data);
+        }
+        let sealed = crypt::seal(&self.keys, id, data);
+        let _writing = self.lock(id);
+        self.inner.save_object(id, &sealed)
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        self.inner.contains_chunk(id)
+    }
+
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        self.inner.list_chunks()
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        self.inner.delete_chunk(id)
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        self.inner.generation(id)
+    }
+
+    fn hint_class(&self, id: &Id, class: StorageClass) {
+        self.inner.hint_class(id, class)
+    }
+
+    fn hint_compression(&self, _id: &Id, _compression: Compression) {
+        // sealed chunks do not compress
+    }
+
+    fn invalidate(&self, id: &Id) {
+        self.inner.invalidate(id)
+    }
+
+    fn flush(&self) -> Result<(), ChunkProviderError> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EncryptedProvider;
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::crypt::{self, KeyRing, MasterKey};
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::inode::FUSE_ROOT_ID;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::{SuperblockError, SUPERBLOCK_ID};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sealed_at_rest() {
+        let memory = Arc::new(MemoryProvider::new());
+        let inner: Arc<dyn ChunkProvider> = memory.clone();
+        let keys = Arc::new(KeyRing::new(MasterKey::new_random()));
+        let provider = EncryptedProvider::new(inner.clone(), keys.clone(), Id::new(SUPERBLOCK_ID));
+        let id = Id::new_random();
+        let chunk = Chunk::new(id.clone());
+        chunk.write_at(0, b"secret");
+        provider.save_chunk(&chunk).unwrap();
+
+        let mut data = vec![0; CHUNK_SIZE];
+        provider.get_chunk_by_id(&id).unwrap().read_at(0, &mut data);
+        assert_eq!(&data[..6], b"secret");
+        let sealed = memory.get_object(&id).unwrap().unwrap();
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+
+        let other = KeyRing::new(MasterKey::new_random());
+        let other = EncryptedProvider::new(inner, Arc::new(other), Id::new(SUPERBLOCK_ID));
+        assert!(matches!(
+            other.get_chunk_by_id(&id),
+            Err(ChunkProviderError::CryptError(_))
+        ));
+
+        // rotated, resealed once, chunks not opening left as they are
+        keys.rotate(MasterKey::new_random());
+        assert!(provider.reseal(&id).unwrap());
+        assert!(!provider.reseal(&id).unwrap());
+        let sealed = memory.get_object(&id).unwrap().unwrap();
+        assert_eq!(crypt::epoch(&sealed), Some(1));
+        let clear = Chunk::new(Id::new_random());
+        memory.save_chunk(&clear).unwrap();
+        assert!(!provider.reseal(clear.id()).unwrap());
+    }
+
+    #[test]
+    fn test_reopen_encrypted() {
+        let memory = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        let key = MasterKey::new_random();
+        let format = FormatOptions {
+            key: Some(key.clone()),
+            ..FormatOptions::default()
+        };
+        EossFs::format(memory.as_ref(), &format).unwrap();
+        let options = MountOptions {
+            key: Some(key),
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(memory.clone(), options.clone(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, b"secret").unwrap();
+        fs.close().unwrap();
+        drop(fs);
+
+        let mut fs = EossFs::open(memory.clone(), options, &id).unwrap();
+        let (attr, _) = fs.lookup_entry(FUSE_ROOT_ID, "file").unwrap();
+        let (data, range) = fs.read_shared(attr.ino, 0, 6, None).unwrap().unwrap();
+        assert_eq!(&data[range], b"secret");
+        drop(fs);
+        let stored = memory.list_chunks().unwrap().unwrap();
+        for id in stored {
+            let object = memory.get_object(&id).unwrap().unwrap();
+            assert!(!object.windows(6).any(|window| window == b"secret"));
+        }
+        let other = MountOptions {
+            key: Some(MasterKey::new_random()),
+            ..MountOptions::default()
+        };
+        assert!(matches!(
+            EossFs::open(memory, other, &id),
+            Err(SuperblockError::WrongKey)
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::ops::{Deref, Range};
+
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::chunk::{Block, Chunk};
+use crate::compression::Compression;
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError, SaveFuture};
+use crate::tier::StorageClass;
+
+/// GuardedProvider saves a chunk only if the inner provider still holds
+/// the generation it was last read or saved at, so of two writers sharing
+/// a backend the second to modify a chunk fails with `Conflict` rather
+/// than overwrite the change of the first.
+/// Chunks never read nor saved through it are saved as they are.
+pub struct GuardedProvider<P> {
+    inner: P,
+    /// Generation each chunk was last seen at
+    seen: Mutex<HashMap<Id, Option<u64>>>,
+    /// Held while a chunk is read or saved, by the first byte of its id,
+    /// so its generation is seen along with its content
+    locks: Vec<Mutex<()>>,
+}
+
+impl<P> GuardedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            seen: Mutex::new(HashMap::new()),
+            locks: (0..16).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    fn lock(&self, id: &Id) -> MutexGuard<'_, ()> {
+        self.locks[id[0] as usize % self.locks.len()].lock()
+    }
+
+    /// Run `read` recording the generation of chunk `id` before it, so a
+    /// change in between fails the next save rather than go unnoticed.
+    fn read<T>(
+        &self,
+        id: &Id,
+        read: impl FnOnce() -> Result<T, ChunkProviderError>,
+    ) -> Result<T, ChunkProviderError> {
+        let _chunk = self.lock(id);
+        let generation = self.inner.generation(id)?;
+        let value = read()?;
+        self.seen.lock().insert(id.clone(), generation);
+        Ok(value)
+    }
+
+    /// Save chunk `id` with `save_if` if seen before, `save` otherwise.
+    fn save(
+        &self,
+        id: &Id,
+        save_if: impl FnOnce(Option<u64>) -> Result<Option<u64>, ChunkProviderError>,
+        save: impl FnOnce() -> Result<(), ChunkProviderError>,
+    ) -> Result<(), ChunkProviderError> {
+        let _chunk = self.lock(id);
+        let seen = self.seen.lock().get(id).cloned();
+        let generation = match seen {
+            Some(generation) => save_if(generation)?,
+            None => {
+                save()?;
+                self.inner.generation(id)?
+            }
+        };
+        self.seen.lock().insert(id.clone(), generation);
+        Ok(())
+    }
+}
+
+impl<P> ChunkProvider for GuardedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        self.read(id, || self.inner.get_chunk_by_id(id))
+    }
+
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        self.read(id, || self.inner.get_blocks(id, blocks))
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        self.save(
+            chunk.id(),
+            |generation| self.inner.save_chunk_if(chunk, generation),
+            || self.inner.save_chunk(chunk),
+        )
+    }
+
+    /// Streamed through if never seen, saved conditionally once released
+    /// otherwise.
+    fn save_chunk_streaming<'a>(&'a self, chunk: &'a Chunk) -> SaveFuture<'a> {
+        Box::pin(async move {
+            if self.seen.lock().contains_key(chunk.id()) {
+                chunk.released().await;
+                return self.save_chunk(chunk);
+            }
+            self.inner.save_chunk_streaming(chunk).await?;
+            let _chunk = self.lock(chunk.id());
+            let generation = self.inner.generation(chunk.id())?;
+            self.seen.lock().insert(chunk.id().clone(), generation);
+            Ok(())
+        })
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        self.inner.contains_chunk(id)
+    }
+
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        self.inner.list_chunks()
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        self.read(id, || self.inner.get_object(id))
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        self.save(
+            id,
+            |generation| self.inner.save_object_if(id, data, generation),
+            || self.inner.save_object(id, data),
+        )
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        let _chunk = self.lock(id);
+        self.inner.delete_chunk(id)?;
+        self.seen.lock().remove(id);
+        Ok(())
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        self.inner.generation(id)
+    }
+
+    fn hint_class(&self, id: &Id, class: StorageClass) {
+        self.inner.hint_class(id, class)
+    }
+
+    fn hint_compression(&self, id: &Id, compression: Compression) {
+        self.inner.hint_compression(id, compression)
+    }
+
+    fn invalidate(&self, id: &Id) {
+        self.inner.invalidate(id)
+    }
+
+    fn flush(&self) -> Result<(), ChunkProviderError> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::inode::FUSE_ROOT_ID;
+
+    use super::GuardedProvider;
+    use crate::chunk::Chunk;
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::meta::MetaError;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::local::LocalProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::{SuperblockError, SUPERBLOCK_ID};
+
+    #[test]
+    fn test_conflict() {
+        let backend = Arc::new(MemoryProvider::new());
+        let (first, second) = (
+            GuardedProvider::new(backend.clone()),
+            GuardedProvider::new(backend.clone()),
+        );
+        let id = Id::new([1; 32]);
+        first.save_object(&id, b"a").unwrap();
+        second.get_object(&id).unwrap();
+        first.save_object(&id, b"b").unwrap();
+        assert!(matches!(
+            second.save_object(&id, b"c"),
+            Err(ChunkProviderError::Conflict(_))
+        ));
+        // saved over once the change is seen
+        assert_eq!(second.get_object(&id).unwrap().unwrap(), b"b");
+        second.save_object(&id, b"c").unwrap();
+
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(backend.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            detect_conflicts: true,
+            ..MountOptions::default()
+        };
+        let mut first = EossFs::open(backend.clone(), options.clone(), &id).unwrap();
+        let mut second = EossFs::open(backend.clone(), options, &id).unwrap();
+        second.close().unwrap();
+        assert!(matches!(
+            first.close(),
+            Err(SuperblockError::MetaError(MetaError::ProviderError(
+                ChunkProviderError::Conflict(_)
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_conflict_local() {
+        let base = env::temp_dir().join(format!("eoss-guarded-{}", Id::new_random().hex()));
+        let backend = Arc::new(LocalProvider::new(&base).unwrap());
+        let (first, second) = (
+            GuardedProvider::new(backend.clone()),
+            GuardedProvider::new(backend.clone()),
+        );
+        // read before ever written, then saved
+        let id = Id::new_random();
+        let chunk = first.get_chunk_by_id(&id).unwrap();
+        assert!(!backend.contains_chunk(&id).unwrap());
+        chunk.write_at(0, b"a");
+        first.save_chunk(&chunk).unwrap();
+        second.get_chunk_by_id(&id).unwrap();
+        first.save_chunk(&chunk).unwrap();
+        assert!(matches!(
+            second.save_chunk(&Chunk::new(id.clone())),
+            Err(ChunkProviderError::Conflict(_))
+        ));
+        // created by another meanwhile
+        let id = Id::new_random();
+        second.get_chunk_by_id(&id).unwrap();
+        first.save_object(&id, b"b").unwrap();
+        assert!(matches!(
+            second.save_object(&id, b"c"),
+            Err(ChunkProviderError::Conflict(_))
+        ));
+
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(backend.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            detect_conflicts: true,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(backend.clone(), options.clone(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, b"data").unwrap();
+        fs.close().unwrap();
+        drop(fs);
+        let mut fs = EossFs::open(backend, options, &id).unwrap();
+        fs.lookup_entry(FUSE_ROOT_ID, "file").unwrap();
+        fs.close().unwrap();
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -1,13 +1,31 @@
-use std::io;
+use std::io::{self, Write};
 use std::fs;
+use std::ops::Range;
+#[cfg(unix)]
+use std::os::unix::fs::{FileExt, MetadataExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::fs::{FileExt, MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
+#[cfg(windows)]
+use std::thread;
+#[cfg(windows)]
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
 
-use crate::provider::{ChunkProvider, ChunkProviderError};
-use crate::chunk::{Chunk, CHUNK_SIZE};
+use crate::provider::{ChunkProvider, ChunkProviderError, SaveFuture};
+use crate::chunk::{self, Block, Chunk, BLOCK_SIZE, CHUNK_SIZE};
 use crate::id::Id;
+use crate::stream;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::Uring;
 
 pub struct LocalProvider {
     base: PathBuf,
+    /// Chunk files are read and written through io_uring if supported
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<Uring>,
 }
 
 impl LocalProvider {
@@ -19,10 +37,97 @@ impl LocalProvider {
             return Err(io::Error::from(io::ErrorKind::AlreadyExists))
         }
         Ok(LocalProvider {
-            base: path.as_ref().to_owned()
+            base: path.as_ref().to_owned(),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring: Uring::new().ok(),
         })
     }
 
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            if let Some(uring) = &self.uring {
+                return uring.read(&fs::File::open(path)?);
+            }
+        }
+        fs::read(path)
+    }
+
+    fn write_file(&self, file: &mut fs::File, chunk: &Chunk) -> io::Result<()> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            if let Some(uring) = &self.uring {
+                let mut data = vec![0; CHUNK_SIZE];
+                chunk.read_at(0, &mut data);
+                return uring.write(file, &data);
+            }
+        }
+        let mut reader = chunk.read();
+        io::copy(&mut reader, file)?;
+        Ok(())
+    }
+
+    /// Create the file of a chunk, truncated if exists.
+    fn create_file(&self, id: &Id) -> io::Result<fs::File> {
+        let path = self.get_path(id);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path)
+    }
+
+    /// Store `data` as chunk `id` only if its generation is still
+    /// `generation`, `None` for a chunk not stored. The data is written to a
+    /// file of its own first, then linked in place if no chunk is expected,
+    /// or renamed over the chunk while its file is locked, so no other
+    /// process saving the chunk alike gets in between.
+    fn store_if(
+        &self,
+        id: &Id,
+        data: &[u8],
+        generation: Option<u64>,
+    ) -> Result<Option<u64>, ChunkProviderError> {
+        let path = self.get_path(id);
+        fs::create_dir_all(path.parent().unwrap())?;
+        // not taken for a chunk by `list_chunks`
+        let temp = path.with_extension(format!("{}.tmp", Id::new_random().hex()));
+        fs::write(&temp, data)?;
+        let swapped = self.swap(id, &path, &temp, generation);
+        let _ = fs::remove_file(&temp);
+        swapped?;
+        self.generation(id)
+    }
+
+    fn swap(
+        &self,
+        id: &Id,
+        path: &Path,
+        temp: &Path,
+        generation: Option<u64>,
+    ) -> Result<(), ChunkProviderError> {
+        let conflict = || ChunkProviderError::Conflict(id.clone());
+        let expected = match generation {
+            None => {
+                return match fs::hard_link(temp, path) {
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(conflict()),
+                    linked => Ok(linked?),
+                }
+            }
+            Some(expected) => expected,
+        };
+        let file = match lock_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(conflict()),
+            file => file?,
+        };
+        if generation_of(&file.metadata()?) != expected {
+            return Err(conflict());
+        }
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+
     fn get_path(&self, id: &Id) -> PathBuf {
         let file_name = id.hex();
         let mut path = self.base.clone();
@@ -36,26 +141,183 @@ impl LocalProvider {
 
 impl ChunkProvider for LocalProvider {
     fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
-        let path = self.get_path(id);
-        if path.exists() {
-            let data = fs::read(path)?;
-            Ok(Chunk::new_with_data(id.clone(), data)?)
-        } else {
-            let file = fs::File::create(path)?;
-            file.set_len(CHUNK_SIZE as u64)?;
-            Ok(Chunk::new(id.clone()))
+        // a chunk not stored reads as zero, without creating it
+        match self.read_file(&self.get_path(id)) {
+            Ok(data) => Ok(Chunk::new_with_data(id.clone(), data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Chunk::new(id.clone())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Read the range of the file of the chunk, without creating it.
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        let mut data = vec![0; blocks.len() * BLOCK_SIZE];
+        match fs::File::open(self.get_path(id)) {
+            Ok(file) => {
+                let mut read = 0;
+                while read < data.len() {
+                    let offset = (blocks.start * BLOCK_SIZE + read) as u64;
+                    #[cfg(unix)]
+                    let n = file.read_at(&mut data[read..], offset)?;
+                    #[cfg(windows)]
+                    let n = file.seek_read(&mut data[read..], offset)?;
+                    match n {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
         }
+        Ok(chunk::split(&data)?)
     }
 
     fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
-        let path = self.get_path(chunk.id());
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(path)?;
-        let mut reader = chunk.read();
-        io::copy(&mut reader, &mut file)?;
+        let mut file = self.create_file(chunk.id())?;
+        self.write_file(&mut file, chunk)?;
         Ok(())
     }
+
+    /// Write the file of the chunk block by block as released.
+    fn save_chunk_streaming<'a>(&'a self, chunk: &'a Chunk) -> SaveFuture<'a> {
+        Box::pin(async move {
+            let mut file = self.create_file(chunk.id())?;
+            let mut reader = chunk.read();
+            let mut buf = vec![0; BLOCK_SIZE];
+            loop {
+                match stream::read(&mut reader, &mut buf).await? {
+                    0 => break,
+                    n => file.write_all(&buf[..n])?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        Ok(self.get_path(id).exists())
+    }
+
+    /// Walk the three levels of prefix directories.
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        let mut dirs = vec![(self.base.clone(), 0)];
+        let mut ids = Vec::new();
+        while let Some((dir, depth)) = dirs.pop() {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if depth < 3 {
+                    if entry.file_type()?.is_dir() {
+                        dirs.push((entry.path(), depth + 1));
+                    }
+                    continue;
+                }
+                if let Some(name) = entry.file_name().to_str() {
+                    if let Ok(id) = Id::from_hex(name) {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+        Ok(Some(ids))
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        match fs::read(self.get_path(id)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        let path = self.get_path(id);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        match fs::remove_file(self.get_path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Use the modified time and inode of the chunk file as generation.
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        match fs::metadata(self.get_path(id)) {
+            Ok(metadata) => Ok(Some(generation_of(&metadata))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_chunk_if(
+        &self,
+        chunk: &Chunk,
+        generation: Option<u64>,
+    ) -> Result<Option<u64>, ChunkProviderError> {
+        let mut data = vec![0; CHUNK_SIZE];
+        chunk.read_at(0, &mut data);
+        self.store_if(chunk.id(), &data, generation)
+    }
+
+    fn save_object_if(
+        &self,
+        id: &Id,
+        data: &[u8],
+        generation: Option<u64>,
+    ) -> Result<Option<u64>, ChunkProviderError> {
+        self.store_if(id, data, generation)
+    }
+}
+
+/// Generation of a chunk file of `metadata`. Its modified time alone may
+/// not change between saves within the resolution of the clock, but a
+/// conditional save writes a new file, hence the inode mixed in, its
+/// creation time on Windows.
+fn generation_of(metadata: &fs::Metadata) -> u64 {
+    let nanos = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    #[cfg(unix)]
+    let identity = metadata.ino();
+    #[cfg(windows)]
+    let identity = metadata.creation_time();
+    nanos ^ identity.rotate_left(32)
+}
+
+/// Open the file at `path` locked against other swaps until closed.
+#[cfg(unix)]
+fn lock_file(path: &Path) -> io::Result<fs::File> {
+    loop {
+        let file = fs::File::open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // replaced while waiting for the lock
+        if fs::metadata(path)?.ino() == file.metadata()?.ino() {
+            return Ok(file);
+        }
+    }
+}
+
+/// Open the file at `path` locked against other swaps until closed, shared
+/// with nothing but the rename replacing it.
+#[cfg(windows)]
+fn lock_file(path: &Path) -> io::Result<fs::File> {
+    const FILE_SHARE_DELETE: u32 = 0x4;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    loop {
+        match fs::OpenOptions::new().read(true).share_mode(FILE_SHARE_DELETE).open(path) {
+            Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => {
+                thread::sleep(Duration::from_millis(1))
+            }
+            file => return file,
+        }
+    }
 }
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::chunk::{self, Block, Chunk, ChunkError, BLOCK_SIZE, CHUNK_SIZE};
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+/// MemoryProvider keeps all chunks in memory, mostly for testing.
+#[derive(Default)]
+pub struct MemoryProvider {
+    /// Stored bytes of chunks, with the generation they were saved at
+    chunks: Mutex<HashMap<Id, (Vec<u8>, u64)>>,
+    modified: AtomicU64,
+}
+
+impl MemoryProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn store(&self, id: &Id, data: Vec<u8>, expected: Option<Option<u64>>) -> StoreResult {
+        let mut chunks = self.chunks.lock();
+        if let Some(expected) = expected {
+            if chunks.get(id).map(|(_, generation)| *generation) != expected {
+                return Err(ChunkProviderError::Conflict(id.clone()));
+            }
+        }
+        let generation = self.modified.fetch_add(1, Ordering::Relaxed) + 1;
+        chunks.insert(id.clone(), (data, generation));
+        Ok(Some(generation))
+    }
+}
+
+type StoreResult = Result<Option<u64>, ChunkProviderError>;
+
+fn chunk_data(chunk: &Chunk) -> Vec<u8> {
+    let mut data = vec![0; CHUNK_SIZE];
+    chunk.read_at(0, &mut data);
+    data
+}
+
+impl ChunkProvider for MemoryProvider {
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        match self.chunks.lock().get(id) {
+            Some((data, _)) => Ok(Chunk::new_with_data(id.clone(), data.clone())?),
+            None => Ok(Chunk::new(id.clone())),
+        }
+    }
+
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        match self.chunks.lock().get(id) {
+            Some((data, _)) => {
+                let range = blocks.start * BLOCK_SIZE..blocks.end * BLOCK_SIZE;
+                let data = data
+                    .get(range)
+                    .ok_or(ChunkError::InvalidLength(data.len()))?;
+                Ok(chunk::split(data)?)
+            }
+            None => Ok(vec![Block::default(); blocks.len()]),
+        }
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        self.store(chunk.id(), chunk_data(chunk), None).map(drop)
+    }
+
+    fn save_chunk_if(&self, chunk: &Chunk, generation: Option<u64>) -> StoreResult {
+        self.store(chunk.id(), chunk_data(chunk), Some(generation))
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        Ok(self.chunks.lock().contains_key(id))
+    }
+
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        Ok(Some(self.chunks.lock().keys().cloned().collect()))
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        Ok(self.chunks.lock().get(id).map(|(data, _)| data.clone()))
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        self.store(id, data.to_vec(), None).map(drop)
+    }
+
+    fn save_object_if(&self, id: &Id, data: &[u8], generation: Option<u64>) -> StoreResult {
+        self.store(id, data.to_vec(), Some(generation))
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        self.chunks.lock().remove(id);
+        Ok(())
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        Ok(self
+            .chunks
+            .lock()
+            .get(id)
+            .map(|(_, generation)| *generation))
+    }
+}
@@ -1 +1,10 @@
-mod local;
\ No newline at end of file
+pub mod cached;
+pub mod compressed;
+pub mod encrypted;
+pub mod guarded;
+pub mod local;
+pub mod memory;
+pub mod signed;
+pub mod spool;
+pub mod tiered;
+pub mod traced;
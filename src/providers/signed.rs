@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+use std::ops::{Deref, Range};
+
+use crate::chunk::{Block, Chunk, CHUNK_SIZE};
+use crate::compression::Compression;
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError, SaveFuture};
+use crate::sign::{self, SignError, SigningKey, VerifyingKey};
+use crate::tier::StorageClass;
+
+/// SignedProvider checks every chunk read from the inner provider against
+/// a signature stored along with it, so a filesystem published can be read
+/// by anyone holding the verifying key, but only modified by the holder of
+/// the signing key.
+/// A chunk removed along with its signature reads as never written, which
+/// metadata refuses to decode and the hashes of files find.
+pub struct SignedProvider<P> {
+    inner: P,
+    verifying: VerifyingKey,
+    signing: Option<SigningKey>,
+}
+
+impl<P> SignedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    /// Check chunks of `inner` with `verifying`, read-only.
+    pub fn new(inner: P, verifying: VerifyingKey) -> Self {
+        Self {
+            inner,
+            verifying,
+            signing: None,
+        }
+    }
+
+    /// Sign chunks written with `signing`, and check them with its
+    /// verifying key.
+    pub fn with_signing_key(inner: P, signing: SigningKey) -> Self {
+        Self {
+            inner,
+            verifying: signing.verifying_key(),
+            signing: Some(signing),
+        }
+    }
+
+    /// Check `data` stored at `id` against its signature.
+    fn check(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        match self.inner.get_object(&sign::signature_id(id))? {
+            Some(signature) => Ok(self.verifying.verify(id, data, &signature)?),
+            // never written
+            None if data.iter().all(|b| *b == 0) => Ok(()),
+            None => Err(SignError::Unsigned(id.clone()).into()),
+        }
+    }
+
+    fn sign(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        let signing = self.signing.as_ref().ok_or(SignError::NoSigningKey)?;
+        self.inner
+            .save_object(&sign::signature_id(id), &signing.sign(id, data))
+    }
+}
+
+impl<P> ChunkProvider for SignedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        let chunk = self.inner.get_chunk_by_id(id)?;
+        let mut data = vec![0; CHUNK_SIZE];
+        chunk.read_at(0, &mut data);
+        self.check(id, &data)?;
+        Ok(chunk)
+    }
+
+    /// Signatures cover whole chunks, so checked whole for any of their
+    /// blocks.
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        Ok(self.get_chunk_by_id(id)?.into_blocks(blocks))
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        let mut data = vec![0; CHUNK_SIZE];
+        chunk.read_at(0, &mut data);
+        // signed first, a chunk is never left unsigned
+        self.sign(chunk.id(), &data)?;
+        self.inner.save_chunk(chunk)
+    }
+
+    /// Signed once released, then streamed through, so a chunk is still
+    /// never left unsigned.
+    fn save_chunk_streaming<'a>(&'a self, chunk: &'a Chunk) -> SaveFuture<'a> {
+        Box::pin(async move {
+            chunk.released().await;
+            let mut data = vec![0; CHUNK_SIZE];
+            chunk.read_at(0, &mut data);
+            self.sign(chunk.id(), &data)?;
+            self.inner.save_chunk_streaming(chunk).await
+        })
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        match self.inner.get_object(id)? {
+            Some(data) => {
+                self.check(id, &data)?;
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        self.sign(id, data)?;
+        self.inner.save_object(id, data)
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        self.inner.contains_chunk(id)
+    }
+
+    /// Signatures are left out, they go along with their chunks.
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        let mut ids = match self.inner.list_chunks()? {
+            Some(ids) => ids,
+            None => return Ok(None),
+        };
+        let signatures: HashSet<Id> = ids.iter().map(sign::signature_id).collect();
+        ids.retain(|id| !signatures.contains(id));
+        Ok(Some(ids))
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        if self.signing.is_none() {
+            return Err(SignError::NoSigningKey.into());
+        }
+        self.inner.delete_chunk(id)?;
+        self.inner.delete_chunk(&sign::signature_id(id))
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        self.inner.generation(id)
+    }
+
+    fn hint_class(&self, id: &Id, class: StorageClass) {
+        self.inner.hint_class(id, class)
+    }
+
+    fn hint_compression(&self, id: &Id, compression: Compression) {
+        self.inner.hint_compression(id, compression)
+    }
+
+    fn invalidate(&self, id: &Id) {
+        self.inner.invalidate(id)
+    }
+
+    fn flush(&self) -> Result<(), ChunkProviderError> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SignedProvider;
+    use crate::chunk::Chunk;
+    use crate::id::Id;
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::memory::MemoryProvider;
+    use crate::sign::{SignError, SigningKey};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_tampered_refused() {
+        let memory = Arc::new(MemoryProvider::new());
+        let signing = SigningKey::new_random();
+        let verifying = signing.verifying_key();
+        let publisher = SignedProvider::with_signing_key(memory.clone(), signing);
+        let id = Id::new_random();
+        let chunk = Chunk::new(id.clone());
+        chunk.write_at(0, b"published");
+        publisher.save_chunk(&chunk).unwrap();
+
+        let reader = SignedProvider::new(memory.clone(), verifying);
+        reader.get_chunk_by_id(&id).unwrap();
+        assert!(matches!(
+            reader.save_chunk(&chunk),
+            Err(ChunkProviderError::SignError(SignError::NoSigningKey))
+        ));
+        // never written
+        reader.get_chunk_by_id(&Id::new_random()).unwrap();
+
+        chunk.write_at(0, b"tampered");
+        memory.save_chunk(&chunk).unwrap();
+        assert!(matches!(
+            reader.get_chunk_by_id(&id),
+            Err(ChunkProviderError::SignError(SignError::BadSignature(_)))
+        ));
+        let other = SignedProvider::new(memory, SigningKey::new_random().verifying_key());
+        assert!(other.get_chunk_by_id(&id).is_err());
+    }
+}
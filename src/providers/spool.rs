@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::chunk::{self, Block, Chunk, BLOCK_SIZE, CHUNK_SIZE};
+use crate::compression::Compression;
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError, SaveFuture};
+use crate::tier::StorageClass;
+
+/// Suffix of a file spooling a deletion instead of chunk data.
+const DELETED: &str = ".deleted";
+
+/// Whether `e` means the provider cannot be reached, rather than failed.
+pub fn unreachable(e: &ChunkProviderError) -> bool {
+    use io::ErrorKind::*;
+    match e {
+        ChunkProviderError::IoError(e) => matches!(
+            e.kind(),
+            ConnectionRefused
+                | ConnectionReset
+                | ConnectionAborted
+                | NotConnected
+                | AddrNotAvailable
+                | BrokenPipe
+        ),
+        ChunkProviderError::Timeout(_) => true,
+        _ => false,
+    }
+}
+
+/// A change of a chunk spooled.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Change {
+    Saved,
+    Deleted,
+}
+
+struct State {
+    next: u64,
+    /// Changes in the order made
+    changes: BTreeMap<u64, (Id, Change)>,
+    /// The change spooled of each chunk
+    latest: HashMap<Id, u64>,
+    /// Number of chunks saved in the spool
+    saved: usize,
+}
+
+/// SpoolProvider keeps another provider writable while it is unreachable.
+/// A change failing to reach it, and all changes after, are queued durably
+/// in a local directory within a byte limit, then replayed in order by
+/// `resync` once it is back. Chunks spooled are read from the spool.
+/// A chunk changed again while spooled is only replayed once, after all
+/// changes before the last one, so the provider never holds a chunk
+/// without the chunks modified before it.
+pub struct SpoolProvider {
+    inner: Arc<dyn ChunkProvider>,
+    dir: PathBuf,
+    limit: usize,
+    state: Mutex<State>,
+}
+
+impl SpoolProvider {
+    /// Spool changes of `inner` in `dir` up to `limit` bytes, changes left
+    /// there by a previous mount are kept to be replayed.
+    pub fn open<P: AsRef<Path>>(
+        inner: Arc<dyn ChunkProvider>,
+        dir: P,
+        limit: usize,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+        let mut state = State {
+            next: 0,
+            changes: BTreeMap::new(),
+            latest: HashMap::new(),
+            saved: 0,
+        };
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            match name.to_str().and_then(parse_name) {
+                Some((seq, id, change)) => {
+                    state.changes.insert(seq, (id.clone(), change));
+                    state.latest.insert(id, seq);
+                    state.next = state.next.max(seq + 1);
+                    if change == Change::Saved {
+                        state.saved += 1;
+                    }
+                }
+                // half written
+                None => fs::remove_file(entry.path())?,
+            }
+        }
+        Ok(Self {
+            inner,
+            dir,
+            limit,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Number of changes waiting for the provider.
+    pub fn pending(&self) -> usize {
+        self.state.lock().changes.len()
+    }
+
+    /// Replay changes spooled to the provider in order, until one fails.
+    /// Returns the number of changes replayed.
+    pub fn resync(&self) -> Result<usize, ChunkProviderError> {
+        let mut state = self.state.lock();
+        let mut replayed = 0;
+        while let Some((&seq, (id, change))) = state.changes.iter().next() {
+            let (id, change) = (id.clone(), *change);
+            let path = self.path(seq, &id, change);
+            match change {
+                Change::Saved => {
+                    let chunk = Chunk::new_with_data(id.clone(), fs::read(&path)?)?;
+                    self.inner.save_chunk(&chunk)?;
+                    state.saved -= 1;
+                }
+                Change::Deleted => self.inner.delete_chunk(&id)?,
+            }
+            fs::remove_file(path)?;
+            state.changes.remove(&seq);
+            state.latest.remove(&id);
+            replayed += 1;
+        }
+        if replayed > 0 {
+            self.inner.flush()?;
+        }
+        Ok(replayed)
+    }
+
+    fn path(&self, seq: u64, id: &Id, change: Change) -> PathBuf {
+        let suffix = match change {
+            Change::Saved => "",
+            Change::Deleted => DELETED,
+        };
+        self.dir
+            .join(format!("{:016x}-{}{}", seq, id.hex(), suffix))
+    }
+
+    /// Queue `change` of chunk `id`, with the data of `chunk` if saved.
+    fn spool(
+        &self,
+        state: &mut State,
+        id: &Id,
+        chunk: Option<&Chunk>,
+    ) -> Result<(), ChunkProviderError> {
+        let change = match chunk {
+            Some(_) => Change::Saved,
+            None => Change::Deleted,
+        };
+        let replaced = state.latest.get(id).map(|seq| (*seq, state.changes[seq].1));
+        let saved = match replaced {
+            Some((_, Change::Saved)) => state.saved,
+            _ => state.saved + 1,
+        };
+        if change == Change::Saved && saved * CHUNK_SIZE > self.limit {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "spool is full").into());
+        }
+        let seq = state.next;
+        let path = self.path(seq, id, change);
+        let temp = path.with_extension("tmp");
+        let mut file = fs::File::create(&temp)?;
+        if let Some(chunk) = chunk {
+            let mut data = vec![0; CHUNK_SIZE];
+            chunk.read_at(0, &mut data);
+            file.write_all(&data)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        fs::File::open(&self.dir)?.sync_all()?;
+        if let Some((old, old_change)) = replaced {
+            fs::remove_file(self.path(old, id, old_change))?;
+            state.changes.remove(&old);
+            if old_change == Change::Saved {
+                state.saved -= 1;
+            }
+        }
+        state.next += 1;
+        state.changes.insert(seq, (id.clone(), change));
+        state.latest.insert(id.clone(), seq);
+        if change == Change::Saved {
+            state.saved += 1;
+        }
+        Ok(())
+    }
+
+    /// The change of chunk `id` spooled, if any.
+    fn spooled(&self, state: &State, id: &Id) -> Option<(u64, Change)> {
+        state.latest.get(id).map(|seq| (*seq, state.changes[seq].1))
+    }
+}
+
+/// Parse the name of a file of a change spooled.
+fn parse_name(name: &str) -> Option<(u64, Id, Change)> {
+    let (name, change) = match name.strip_suffix(DELETED) {
+        Some(name) => (name, Change::Deleted),
+        None => (name, Change::Saved),
+    };
+    let (seq, hex) = name.split_at(name.find('-')?);
+    let seq = u64::from_str_radix(seq, 16).ok()?;
+    let id = Id::from_hex(&hex[1..]).ok()?;
+    Some((seq, id, change))
+}
+
+impl ChunkProvider for SpoolProvider {
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        let state = self.state.lock();
+        match self.spooled(&state, id) {
+            Some((seq, Change::Saved)) => {
+                let data = fs::read(self.path(seq, id, Change::Saved))?;
+                Ok(Chunk::new_with_data(id.clone(), data)?)
+            }
+            Some((_, Change::Deleted)) => Ok(Chunk::new(id.clone())),
+            None => {
+                drop(state);
+                self.inner.get_chunk_by_id(id)
+            }
+        }
+    }
+
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        let state = self.state.lock();
+        match self.spooled(&state, id) {
+            Some((seq, Change::Saved)) => {
+                let mut file = fs::File::open(self.path(seq, id, Change::Saved))?;
+                file.seek(SeekFrom::Start((blocks.start * BLOCK_SIZE) as u64))?;
+                let mut data = vec![0; blocks.len() * BLOCK_SIZE];
+                file.read_exact(&mut data)?;
+                Ok(chunk::split(&data)?)
+            }
+            Some((_, Change::Deleted)) => Ok(Chunk::new(id.clone()).into_blocks(blocks)),
+            None => {
+                drop(state);
+                self.inner.get_blocks(id, blocks)
+            }
+        }
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        let mut state = self.state.lock();
+        // not overtaking changes spooled before
+        if state.changes.is_empty() {
+            match self.inner.save_chunk(chunk) {
+                Err(e) if unreachable(&e) => {}
+                result => return result,
+            }
+        }
+        self.spool(&mut state, chunk.id(), Some(chunk))
+    }
+
+    /// Streamed through if nothing is spooled, spooled once released if the
+    /// inner provider cannot be reached.
+    fn save_chunk_streaming<'a>(&'a self, chunk: &'a Chunk) -> SaveFuture<'a> {
+        Box::pin(async move {
+            let empty = self.state.lock().changes.is_empty();
+            if empty {
+                match self.inner.save_chunk_streaming(chunk).await {
+                    Err(e) if unreachable(&e) => {}
+                    result => return result,
+                }
+            }
+            chunk.released().await;
+            self.spool(&mut self.state.lock(), chunk.id(), Some(chunk))
+        })
+    }
+
+    /// Objects are not spooled, they fail while unreachable.
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        self.inner.get_object(id)
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        self.inner.save_object(id, data)
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        let state = self.state.lock();
+        match self.spooled(&state, id) {
+            Some((_, change)) => Ok(change == Change::Saved),
+            None => {
+                drop(state);
+                self.inner.contains_chunk(id)
+            }
+        }
+    }
+
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        let mut ids = match self.inner.list_chunks()? {
+            Some(ids) => ids,
+            None => return Ok(None),
+        };
+        let state = self.state.lock();
+        ids.retain(|id| !state.latest.contains_key(id));
+        ids.extend(
+            state
+                .changes
+                .values()
+                .filter(|(_, change)| *change == Change::Saved)
+                .map(|(id, _)| id.clone()),
+        );
+        Ok(Some(ids))
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        let mut state = self.state.lock();
+        if state.changes.is_empty() {
+            match self.inner.delete_chunk(id) {
+                Err(e) if unreachable(&e) => {}
+                result => return result,
+            }
+        }
+        self.spool(&mut state, id, None)
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        if self.spooled(&self.state.lock(), id).is_some() {
+            return Ok(None);
+        }
+        self.inner.generation(id)
+    }
+
+    fn hint_class(&self, id: &Id, class: StorageClass) {
+        self.inner.hint_class(id, class)
+    }
+
+    fn hint_compression(&self, id: &Id, compression: Compression) {
+        self.inner.hint_compression(id, compression)
+    }
+
+    fn invalidate(&self, id: &Id) {
+        self.inner.invalidate(id)
+    }
+
+    /// Changes still spooled are already durable.
+    fn flush(&self) -> Result<(), ChunkProviderError> {
+        match self.resync() {
+            Err(e) if unreachable(&e) => return Ok(()),
+            Err(e) => return Err(e),
+            Ok(_) if self.pending() > 0 => return Ok(()),
+            Ok(_) => {}
+        }
+        match self.inner.flush() {
+            Err(e) if unreachable(&e) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpoolProvider;
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::id::Id;
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::memory::MemoryProvider;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Fails to connect while down.
+    struct Remote {
+        inner: MemoryProvider,
+        down: AtomicBool,
+    }
+
+    impl Remote {
+        fn check(&self) -> Result<(), ChunkProviderError> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+            }
+            Ok(())
+        }
+    }
+
+    impl ChunkProvider for Remote {
+        fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+            self.check()?;
+            self.inner.get_chunk_by_id(id)
+        }
+
+        fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+            self.check()?;
+            self.inner.save_chunk(chunk)
+        }
+
+        fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+            self.check()?;
+            self.inner.contains_chunk(id)
+        }
+
+        fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+            self.check()?;
+            self.inner.delete_chunk(id)
+        }
+    }
+
+    #[test]
+    fn test_spool_and_resync() {
+        let dir = env::temp_dir().join(format!("eoss-spool-{}", Id::new_random().hex()));
+        let remote = Arc::new(Remote {
+            inner: MemoryProvider::new(),
+            down: AtomicBool::new(true),
+        });
+        let spool = SpoolProvider::open(remote.clone(), &dir, 2 * CHUNK_SIZE).unwrap();
+        let (a, b, c) = (Id::new_random(), Id::new_random(), Id::new_random());
+        for id in [&a, &a, &b].iter() {
+            let chunk = Chunk::new((*id).clone());
+            chunk.write_at(0, &[1]);
+            spool.save_chunk(&chunk).unwrap();
+        }
+        // saved again in place, until full
+        assert_eq!(spool.pending(), 2);
+        assert!(spool.save_chunk(&Chunk::new(c)).is_err());
+        let mut buf = [0];
+        spool.get_chunk_by_id(&a).unwrap().read_at(0, &mut buf);
+        assert_eq!(buf, [1]);
+        assert!(spool.resync().is_err());
+        drop(spool);
+
+        // replayed once reachable, after a restart
+        let spool = SpoolProvider::open(remote.clone(), &dir, 2 * CHUNK_SIZE).unwrap();
+        remote.down.store(false, Ordering::SeqCst);
+        assert_eq!(spool.resync().unwrap(), 2);
+        assert!(remote.inner.contains_chunk(&a).unwrap());
+        assert!(remote.inner.contains_chunk(&b).unwrap());
+        assert_eq!(spool.pending(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::chunk::{Block, Chunk};
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::tier::StorageClass;
+
+/// TieredProvider stores chunks in the tier of the storage class hinted for
+/// them, or else where they are stored already, or else in the default tier.
+/// A chunk is looked for in every tier until found once, and moved out of
+/// its former tier when saved to another.
+pub struct TieredProvider {
+    /// The default tier first, then the tier of each class
+    tiers: Vec<(Option<StorageClass>, Arc<dyn ChunkProvider>)>,
+    hints: Mutex<HashMap<Id, StorageClass>>,
+    /// Tier each chunk was last found in or saved to, by index
+    located: Mutex<HashMap<Id, usize>>,
+}
+
+impl TieredProvider {
+    /// Store chunks in `default` unless hinted a class of `tiers`.
+    pub fn new(
+        default: Arc<dyn ChunkProvider>,
+        tiers: Vec<(StorageClass, Arc<dyn ChunkProvider>)>,
+    ) -> Self {
+        let tiers = tiers.into_iter().map(|(class, tier)| (Some(class), tier));
+        Self {
+            tiers: std::iter::once((None, default)).chain(tiers).collect(),
+            hints: Mutex::new(HashMap::new()),
+            located: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Index of the tier holding chunk `id`, if stored.
+    fn locate(&self, id: &Id) -> Result<Option<usize>, ChunkProviderError> {
+        if let Some(n) = self.located.lock().get(id) {
+            return Ok(Some(*n));
+        }
+        for (n, (_, tier)) in self.tiers.iter().enumerate() {
+            if tier.contains_chunk(id)? {
+                self.located.lock().insert(id.clone(), n);
+                return Ok(Some(n));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Save chunk `id` with `save` to the tier it belongs to, deleting it
+    /// from the one it moved out of.
+    fn save(
+        &self,
+        id: &Id,
+        save: impl FnOnce(&dyn ChunkProvider) -> Result<(), ChunkProviderError>,
+    ) -> Result<(), ChunkProviderError> {
+        let located = self.locate(id)?;
+        let hinted = self.hints.lock().get(id).and_then(|class| {
+            self.tiers
+                .iter()
+                .position(|(tier, _)| *tier == Some(*class))
+        });
+        let target = hinted.or(located).unwrap_or(0);
+        save(self.tiers[target].1.as_ref())?;
+        self.located.lock().insert(id.clone(), target);
+        match located {
+            // left behind if it cannot be deleted, found in the new tier first
+            Some(n) if n != target => self.tiers[n].1.delete_chunk(id),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ChunkProvider for TieredProvider {
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        match self.locate(id)? {
+            Some(n) => self.tiers[n].1.get_chunk_by_id(id),
+            None => Ok(Chunk::new(id.clone())),
+        }
+    }
+
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        match self.locate(id)? {
+            Some(n) => self.tiers[n].1.get_blocks(id, blocks),
+            None => Ok(Chunk::new(id.clone()).into_blocks(blocks)),
+        }
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        self.save(chunk.id(), |tier| tier.save_chunk(chunk))
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        Ok(self.locate(id)?.is_some())
+    }
+
+    /// Chunks of every tier, `None` unless all of them can be listed.
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        let mut ids = Vec::new();
+        for (_, tier) in self.tiers.iter() {
+            match tier.list_chunks()? {
+                Some(listed) => ids.extend(listed),
+                None => return Ok(None),
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        Ok(Some(ids))
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        match self.locate(id)? {
+            Some(n) => self.tiers[n].1.get_object(id),
+            None => Ok(None),
+        }
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        self.save(id, |tier| tier.save_object(id, data))
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        self.hints.lock().remove(id);
+        if let Some(n) = self.locate(id)? {
+            self.tiers[n].1.delete_chunk(id)?;
+        }
+        self.located.lock().remove(id);
+        Ok(())
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        match self.locate(id)? {
+            Some(n) => self.tiers[n].1.generation(id),
+            None => Ok(None),
+        }
+    }
+
+    fn hint_class(&self, id: &Id, class: StorageClass) {
+        self.hints.lock().insert(id.clone(), class);
+    }
+
+    /// Another client may have moved the chunk as well.
+    fn invalidate(&self, id: &Id) {
+        self.located.lock().remove(id);
+        for (_, tier) in self.tiers.iter() {
+            tier.invalidate(id);
+        }
+    }
+
+    fn flush(&self) -> Result<(), ChunkProviderError> {
+        for (_, tier) in self.tiers.iter() {
+            tier.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::TieredProvider;
+    use crate::chunk::Chunk;
+    use crate::id::Id;
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::tier::StorageClass;
+
+    #[test]
+    fn test_moved_between_tiers() {
+        let (default, cold, hot) = (
+            Arc::new(MemoryProvider::new()),
+            Arc::new(MemoryProvider::new()),
+            Arc::new(MemoryProvider::new()),
+        );
+        let tiers: Vec<(StorageClass, Arc<dyn ChunkProvider>)> = vec![
+            (StorageClass::Cold, cold.clone()),
+            (StorageClass::Hot, hot.clone()),
+        ];
+        let provider = TieredProvider::new(default.clone(), tiers.clone());
+        let chunk = Chunk::new(Id::new_random());
+        chunk.write_at(0, b"tiered");
+        provider.hint_class(chunk.id(), StorageClass::Cold);
+        provider.save_chunk(&chunk).unwrap();
+        assert!(cold.contains_chunk(chunk.id()).unwrap());
+        assert!(!default.contains_chunk(chunk.id()).unwrap());
+
+        // found where stored, and kept there unless hinted otherwise
+        let provider = TieredProvider::new(default.clone(), tiers);
+        let mut data = [0; 6];
+        provider
+            .get_chunk_by_id(chunk.id())
+            .unwrap()
+            .read_at(0, &mut data);
+        assert_eq!(&data, b"tiered");
+        provider.save_chunk(&chunk).unwrap();
+        assert!(cold.contains_chunk(chunk.id()).unwrap());
+        provider.hint_class(chunk.id(), StorageClass::Hot);
+        provider.save_chunk(&chunk).unwrap();
+        assert!(hot.contains_chunk(chunk.id()).unwrap());
+        assert!(!cold.contains_chunk(chunk.id()).unwrap());
+        assert_eq!(
+            provider.list_chunks().unwrap().unwrap(),
+            vec![chunk.id().clone()]
+        );
+        provider.delete_chunk(chunk.id()).unwrap();
+        assert!(!hot.contains_chunk(chunk.id()).unwrap());
+    }
+}
@@ -0,0 +1,200 @@
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tracing::Instrument;
+
+use crate::chunk::{Block, Chunk};
+use crate::compression::Compression;
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError, SaveFuture};
+use crate::tier::StorageClass;
+
+/// TracedProvider runs each call to the inner provider in a span of its
+/// own, nested in the span of the FUSE request making it.
+pub struct TracedProvider<P> {
+    inner: P,
+    /// Calls failed
+    errors: Arc<AtomicU64>,
+}
+
+impl<P> TracedProvider<P> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            errors: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Count the calls failed in `errors`.
+    pub fn with_errors(mut self, errors: Arc<AtomicU64>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    fn count<T>(&self, result: Result<T, ChunkProviderError>) -> Result<T, ChunkProviderError> {
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<P> ChunkProvider for TracedProvider<P>
+where
+    P: Deref + Send + Sync,
+    P::Target: ChunkProvider,
+{
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.get", id = %id.hex()).entered();
+        self.count(self.inner.get_chunk_by_id(id))
+    }
+
+    fn get_chunk_by_ids(&self, ids: &[&Id]) -> Result<Vec<Chunk>, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.get_many", count = ids.len()).entered();
+        self.count(self.inner.get_chunk_by_ids(ids))
+    }
+
+    fn get_blocks(&self, id: &Id, blocks: Range<usize>) -> Result<Vec<Block>, ChunkProviderError> {
+        let _span = tracing::debug_span!(
+            "provider.get_blocks",
+            id = %id.hex(),
+            start = blocks.start,
+            end = blocks.end
+        )
+        .entered();
+        self.count(self.inner.get_blocks(id, blocks))
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.save", id = %chunk.id().hex()).entered();
+        self.count(self.inner.save_chunk(chunk))
+    }
+
+    fn save_chunk_streaming<'a>(&'a self, chunk: &'a Chunk) -> SaveFuture<'a> {
+        let span = tracing::debug_span!("provider.save_streaming", id = %chunk.id().hex());
+        let save = self.inner.save_chunk_streaming(chunk);
+        Box::pin(async move { self.count(save.await) }.instrument(span))
+    }
+
+    fn save_all_chunks(&self, chunks: &[&Chunk]) -> Result<(), ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.save_many", count = chunks.len()).entered();
+        self.count(self.inner.save_all_chunks(chunks))
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.contains", id = %id.hex()).entered();
+        self.count(self.inner.contains_chunk(id))
+    }
+
+    fn list_chunks(&self) -> Result<Option<Vec<Id>>, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.list").entered();
+        self.count(self.inner.list_chunks())
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Result<Vec<Id>, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.resolve", prefix).entered();
+        self.count(self.inner.resolve_prefix(prefix))
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.get_object", id = %id.hex()).entered();
+        self.count(self.inner.get_object(id))
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.save_object", id = %id.hex()).entered();
+        self.count(self.inner.save_object(id, data))
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.delete", id = %id.hex()).entered();
+        self.count(self.inner.delete_chunk(id))
+    }
+
+    fn generation(&self, id: &Id) -> Result<Option<u64>, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.generation", id = %id.hex()).entered();
+        self.count(self.inner.generation(id))
+    }
+
+    fn save_chunk_if(
+        &self,
+        chunk: &Chunk,
+        generation: Option<u64>,
+    ) -> Result<Option<u64>, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.save_if", id = %chunk.id().hex()).entered();
+        self.count(self.inner.save_chunk_if(chunk, generation))
+    }
+
+    fn save_object_if(
+        &self,
+        id: &Id,
+        data: &[u8],
+        generation: Option<u64>,
+    ) -> Result<Option<u64>, ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.save_object_if", id = %id.hex()).entered();
+        self.count(self.inner.save_object_if(id, data, generation))
+    }
+
+    fn hint_class(&self, id: &Id, class: StorageClass) {
+        self.inner.hint_class(id, class)
+    }
+
+    fn hint_compression(&self, id: &Id, compression: Compression) {
+        self.inner.hint_compression(id, compression)
+    }
+
+    fn invalidate(&self, id: &Id) {
+        self.inner.invalidate(id)
+    }
+
+    fn flush(&self) -> Result<(), ChunkProviderError> {
+        let _span = tracing::debug_span!("provider.flush").entered();
+        self.count(self.inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::atomic::Ordering;
+
+    use super::TracedProvider;
+    use crate::chunk::Chunk;
+    use crate::id::Id;
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::local::LocalProvider;
+    use crate::stream::block_on;
+
+    #[test]
+    fn test_forward() {
+        let base = env::temp_dir().join(format!("eoss-traced-{}", Id::new_random().hex()));
+        let provider = TracedProvider::new(Box::new(LocalProvider::new(&base).unwrap()));
+
+        // conditional saves reach the inner provider and count conflicts
+        let chunk = Chunk::new(Id::new_random());
+        let generation = provider.save_chunk_if(&chunk, None).unwrap();
+        assert_eq!(provider.generation(chunk.id()).unwrap(), generation);
+        assert!(matches!(
+            provider.save_chunk_if(&chunk, None),
+            Err(ChunkProviderError::Conflict(_))
+        ));
+        assert_eq!(provider.errors.load(Ordering::Relaxed), 1);
+        let id = Id::new_random();
+        provider.save_object_if(&id, b"object", None).unwrap();
+        assert_eq!(provider.get_object(&id).unwrap().unwrap(), b"object");
+
+        let prefix = &chunk.id().hex()[..8];
+        assert!(provider
+            .resolve_prefix(prefix)
+            .unwrap()
+            .contains(chunk.id()));
+
+        let chunk = Chunk::new(Id::new_random());
+        chunk.write_at(0, b"streamed");
+        block_on(provider.save_chunk_streaming(&chunk)).unwrap();
+        assert!(provider.contains_chunk(chunk.id()).unwrap());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
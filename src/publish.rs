@@ -0,0 +1,347 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::events::{Event, EventKind};
+
+/// Events queued at most for a sink, newer ones are dropped beyond.
+pub const QUEUE_EVENTS: usize = 4096;
+/// How long a sink may take to accept an event.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(thiserror::Error, Debug)]
+pub enum PublishError {
+    #[error("no publisher for {0}:// in this build")]
+    UnsupportedScheme(String),
+    #[error("invalid sink {0}")]
+    InvalidSink(String),
+    #[error("event rejected with status {0}")]
+    Rejected(u16),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// Where change events are published, as given on the command line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Sink {
+    /// Posted one by one as JSON over a connection kept alive,
+    /// `http://host[:port]/path`.
+    Webhook {
+        addr: String,
+        host: String,
+        path: String,
+    },
+    /// Published as JSON on a subject of a NATS server,
+    /// `nats://host[:port]/subject`.
+    Nats { addr: String, subject: String },
+}
+
+impl FromStr for Sink {
+    type Err = PublishError;
+
+    fn from_str(s: &str) -> Result<Self, PublishError> {
+        let invalid = || PublishError::InvalidSink(s.to_owned());
+        let (scheme, rest) = s.split_once("://").ok_or_else(invalid)?;
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let addr = |port| match host.contains(':') {
+            true => host.to_owned(),
+            false => format!("{}:{}", host, port),
+        };
+        match scheme {
+            "http" => Ok(Sink::Webhook {
+                addr: addr(80),
+                host: host.to_owned(),
+                path: format!("/{}", path),
+            }),
+            "nats" if !path.is_empty() && !path.contains(char::is_whitespace) => Ok(Sink::Nats {
+                addr: addr(4222),
+                subject: path.replace('/', "."),
+            }),
+            "nats" => Err(invalid()),
+            scheme => Err(PublishError::UnsupportedScheme(scheme.to_owned())),
+        }
+    }
+}
+
+/// `event` as a JSON object of its op, path, size, inode and id.
+pub fn to_json(event: &Event) -> String {
+    let op = match event.kind {
+        EventKind::Create => "create",
+        EventKind::Modify => "modify",
+        EventKind::Delete => "delete",
+    };
+    let id = match &event.id {
+        Some(id) => format!("\"{}\"", hex::encode(id)),
+        None => "null".to_owned(),
+    };
+    format!(
+        "{{\"op\":\"{}\",\"path\":\"{}\",\"size\":{},\"ino\":{},\"id\":{}}}",
+        op,
+        escape(&event.path),
+        event.size,
+        event.ino,
+        id,
+    )
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Publisher sends change events to a sink, keeping the connection to a
+/// webhook or a NATS server open in between.
+pub struct Publisher {
+    sink: Sink,
+    webhook: Option<BufReader<TcpStream>>,
+    nats: Option<TcpStream>,
+}
+
+impl Publisher {
+    pub fn new(sink: Sink) -> Self {
+        Self {
+            sink,
+            webhook: None,
+            nats: None,
+        }
+    }
+
+    pub fn publish(&mut self, event: &Event) -> Result<(), PublishError> {
+        let body = to_json(event);
+        match &self.sink {
+            Sink::Webhook { addr, host, path } => {
+                let request = format!(
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\n\r\n{}",
+                    path,
+                    host,
+                    body.len(),
+                    body
+                );
+                let (code, keep) = match self.webhook.as_mut().map(|s| post(s, &request)) {
+                    Some(Ok(answer)) => answer,
+                    // connected again once closed by the server meanwhile
+                    _ => {
+                        self.webhook = None;
+                        let mut stream = connect_webhook(addr)?;
+                        let answer = post(&mut stream, &request)?;
+                        self.webhook = Some(stream);
+                        answer
+                    }
+                };
+                if !keep {
+                    self.webhook = None;
+                }
+                match code {
+                    200..=299 => Ok(()),
+                    code => Err(PublishError::Rejected(code)),
+                }
+            }
+            Sink::Nats { addr, subject } => {
+                let message = format!("PUB {} {}\r\n{}\r\n", subject, body.len(), body);
+                if let Some(stream) = &mut self.nats {
+                    if pong(stream)
+                        .and_then(|_| stream.write_all(message.as_bytes()))
+                        .is_ok()
+                    {
+                        return Ok(());
+                    }
+                }
+                // connected again once dropped
+                self.nats = None;
+                let mut stream = connect_nats(addr)?;
+                stream.write_all(message.as_bytes())?;
+                self.nats = Some(stream);
+                Ok(())
+            }
+        }
+    }
+
+    /// Publish the events received in a thread of its own, until they stop
+    /// coming. Events failed to publish are logged and dropped.
+    pub fn spawn(mut self, events: Receiver<Event>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            for event in events {
+                if let Err(e) = self.publish(&event) {
+                    tracing::warn!("failed to publish {}: {}", event.path, e);
+                }
+            }
+        })
+    }
+}
+
+fn connect_webhook(addr: &str) -> io::Result<BufReader<TcpStream>> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(BufReader::new(stream))
+}
+
+/// Send `request` on `stream` and read the response whole. Returns its
+/// status, and whether the connection can be kept for the next one.
+fn post(stream: &mut BufReader<TcpStream>, request: &str) -> io::Result<(u16, bool)> {
+    stream.get_mut().write_all(request.as_bytes())?;
+    let mut status = String::new();
+    if stream.read_line(&mut status)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let code = status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    let mut keep = status.starts_with("HTTP/1.1");
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if stream.read_line(&mut header)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = header.trim_end().to_ascii_lowercase();
+        match header.split_once(':') {
+            Some(("content-length", n)) => length = n.trim().parse().ok(),
+            Some(("connection", value)) if value.trim() == "close" => keep = false,
+            // a body of chunks is left with the connection
+            Some(("transfer-encoding", _)) => keep = false,
+            _ if header.is_empty() => break,
+            _ => {}
+        }
+    }
+    match length {
+        Some(length) => {
+            io::copy(&mut stream.by_ref().take(length), &mut io::sink())?;
+        }
+        // the body lasts until the connection is closed
+        None if code != 204 && code != 304 => keep = false,
+        None => {}
+    }
+    Ok((code, keep))
+}
+
+fn connect_nats(addr: &str) -> Result<TcpStream, PublishError> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    // the server greets with INFO first
+    let mut info = String::new();
+    BufReader::new(&stream).read_line(&mut info)?;
+    if !info.starts_with("INFO") {
+        return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+    }
+    stream.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")?;
+    Ok(stream)
+}
+
+/// Answer the pings the server sent since, or it drops the connection.
+fn pong(stream: &mut TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(true)?;
+    let mut buf = [0; 512];
+    let mut pings = 0;
+    let read = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => pings += String::from_utf8_lossy(&buf[..n]).matches("PING").count(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    read?;
+    for _ in 0..pings {
+        stream.write_all(b"PONG\r\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::{PublishError, Publisher, Sink};
+    use crate::events::{Event, EventKind};
+
+    #[test]
+    fn test_publish() {
+        assert!(matches!(
+            "kafka://broker/topic".parse::<Sink>(),
+            Err(PublishError::UnsupportedScheme(_))
+        ));
+        assert!("nats://server".parse::<Sink>().is_err());
+        let event = Event::new(EventKind::Modify, 1, &["a \"b\"".to_owned()], None, 2);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            // both events on the one connection
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            for _ in 0..2 {
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    request.push_str(&line);
+                    if let Some(n) = line.strip_prefix("Content-Length: ") {
+                        length = n.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                tx.send(request + &String::from_utf8_lossy(&body)).unwrap();
+                (&stream)
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .unwrap();
+            }
+        });
+        let sink: Sink = format!("http://{}/hooks/eoss", addr).parse().unwrap();
+        let mut publisher = Publisher::new(sink);
+        publisher.publish(&event).unwrap();
+        publisher.publish(&event).unwrap();
+        server.join().unwrap();
+        let request = rx.recv().unwrap();
+        assert!(request.starts_with("POST /hooks/eoss HTTP/1.1\r\n"));
+        assert!(request.ends_with(
+            "{\"op\":\"modify\",\"path\":\"a \\\"b\\\"\",\"size\":0,\"ino\":2,\"id\":null}"
+        ));
+        assert_eq!(rx.recv().unwrap(), request);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let nats = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"INFO {}\r\n").unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let connect = lines.next().unwrap().unwrap();
+            let publish = lines.next().unwrap().unwrap();
+            (connect, publish, lines.next().unwrap().unwrap())
+        });
+        let sink: Sink = format!("nats://{}/fs/changes", addr).parse().unwrap();
+        Publisher::new(sink).publish(&event).unwrap();
+        let (connect, publish, payload) = nats.join().unwrap();
+        assert!(connect.starts_with("CONNECT"));
+        assert!(publish.starts_with("PUB fs.changes "));
+        assert!(payload.starts_with("{\"op\":\"modify\""));
+    }
+}
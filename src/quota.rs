@@ -0,0 +1,229 @@
+use crate::fs::{DirMeta, Entry, EntryMut, Node};
+use crate::meta::{Decode, Encode, MetaError, Reader};
+
+#[derive(thiserror::Error, Debug)]
+pub enum QuotaError {
+    #[error("{0} is not a directory")]
+    NotDir(String),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+}
+
+/// Space used beneath a directory.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Usage {
+    /// Sum of the sizes of files
+    pub bytes: u64,
+    /// Number of files and directories
+    pub inodes: u64,
+    /// Number of chunks of files, tiny files share theirs
+    pub chunks: u64,
+}
+
+/// Extended attributes of directories holding their usage, so `du`-style
+/// queries do not walk the tree.
+pub const USAGE_XATTRS: [&str; 3] = ["user.eoss.bytes", "user.eoss.inodes", "user.eoss.chunks"];
+
+impl Usage {
+    /// Value of the usage extended attribute `name`.
+    pub fn xattr(&self, name: &str) -> Option<u64> {
+        match name {
+            "user.eoss.bytes" => Some(self.bytes),
+            "user.eoss.inodes" => Some(self.inodes),
+            "user.eoss.chunks" => Some(self.chunks),
+            _ => None,
+        }
+    }
+}
+
+/// Limits of the usage of a directory, zero for no limit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quota {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+impl Quota {
+    /// Whether `usage` grown by `bytes` and `inodes` stays within this quota.
+    fn allows(&self, usage: &Usage, bytes: u64, inodes: u64) -> bool {
+        let within = |limit: u64, used: u64, added: u64| {
+            added == 0 || limit == 0 || used.saturating_add(added) <= limit
+        };
+        within(self.bytes, usage.bytes, bytes) && within(self.inodes, usage.inodes, inodes)
+    }
+}
+
+impl Encode for Usage {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.bytes.encode(buf);
+        self.inodes.encode(buf);
+        self.chunks.encode(buf);
+    }
+}
+
+impl Decode for Usage {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            bytes: Decode::decode(reader)?,
+            inodes: Decode::decode(reader)?,
+            chunks: Decode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for Quota {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.bytes.encode(buf);
+        self.inodes.encode(buf);
+    }
+}
+
+impl Decode for Quota {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            bytes: Decode::decode(reader)?,
+            inodes: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// Usage of an entry, counting itself.
+pub fn usage_of(entry: Entry) -> Usage {
+    match entry {
+        Entry::Dir(dir) => Usage {
+            inodes: dir.usage.inodes + 1,
+            ..dir.usage
+        },
+        Entry::File(file) => Usage {
+            bytes: file.attrs.size,
+            inodes: 1,
+            chunks: file.chunk_count() as u64,
+        },
+        Entry::TinyFile(file) => Usage {
+            bytes: file.attrs.size,
+            inodes: 1,
+            chunks: 0,
+        },
+    }
+}
+
+/// Directories containing the entry at `path` in the tree at `root`, which
+/// have to be loaded, from the root down.
+fn for_each_parent<S: AsRef<str>>(
+    root: &mut DirMeta,
+    path: &[S],
+    f: &mut impl FnMut(&mut DirMeta),
+) {
+    let mut dir = root;
+    let parents = path.len().saturating_sub(1);
+    for name in path[..parents].iter() {
+        f(dir);
+        dir = match dir.lookup_mut(name.as_ref()) {
+            Some(EntryMut::Dir(sub)) => sub,
+            _ => return,
+        };
+    }
+    f(dir);
+}
+
+/// Whether the entry at `path` may grow by `bytes` and `inodes` within the
+/// quotas of the directories containing it.
+pub fn check<S: AsRef<str>>(root: &mut DirMeta, path: &[S], bytes: u64, inodes: u64) -> bool {
+    let mut allowed = true;
+    for_each_parent(root, path, &mut |dir| {
+        allowed &= dir.quota.allows(&dir.usage, bytes, inodes);
+    });
+    allowed
+}
+
+/// Roll the change of usage of the entry at `path` from `old` to `new` up
+/// to the directories containing it.
+pub fn account<S: AsRef<str>>(root: &mut DirMeta, path: &[S], old: Usage, new: Usage) {
+    let apply = |used: u64, old: u64, new: u64| (used + new).saturating_sub(old);
+    for_each_parent(root, path, &mut |dir| {
+        dir.usage.bytes = apply(dir.usage.bytes, old.bytes, new.bytes);
+        dir.usage.inodes = apply(dir.usage.inodes, old.inodes, new.inodes);
+        dir.usage.chunks = apply(dir.usage.chunks, old.chunks, new.chunks);
+    });
+}
+
+/// Compute the usage of every directory in the tree at `dir`, which has to
+/// be fully loaded, e.g. after the journal is replayed.
+pub fn recount(dir: &mut DirMeta) -> Usage {
+    let mut usage = Usage::default();
+    for node in dir.entries.values_mut() {
+        if let Node::Dir(sub) = node {
+            recount(sub);
+        }
+        let of = usage_of(node.as_entry());
+        usage.bytes += of.bytes;
+        usage.inodes += of.inodes;
+        usage.chunks += of.chunks;
+    }
+    dir.usage = usage;
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{account, check, recount, usage_of, Quota, Usage};
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fs::{Attrs, DirMeta, Entry, FileMeta, Node, TinyFileMeta};
+    use crate::id::{Id, ID_LENGTH};
+
+    #[test]
+    fn test_quota() {
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut dir = DirMeta::new("dir".to_owned(), Attrs::new(0o755, 0, 0));
+        dir.quota = Quota {
+            bytes: 100,
+            inodes: 1,
+        };
+        root.insert(Node::Dir(dir));
+        let empty = Usage::default();
+        let one = Usage { inodes: 1, ..empty };
+        account(&mut root, &["dir"], empty, one);
+        let path = ["dir", "file"];
+        assert!(check(&mut root, &path, 0, 1));
+        let mut file = TinyFileMeta::new("file".to_owned(), Attrs::new(0o644, 0, 0));
+        file.attrs.set_size(60);
+        match root.resolve_mut(&["dir"]) {
+            Some(crate::fs::EntryMut::Dir(dir)) => dir.insert(Node::TinyFile(file)),
+            _ => unreachable!(),
+        };
+        let added = Usage { bytes: 60, ..one };
+        account(&mut root, &path, empty, added);
+
+        assert!(!check(&mut root, &["dir", "other"], 0, 1));
+        assert!(!check(&mut root, &path, 50, 0));
+        assert!(check(&mut root, &path, 40, 0));
+        // the root has no quota
+        assert!(check(&mut root, &["other"], 1000, 1));
+        let mut big = FileMeta {
+            name: "big".to_owned(),
+            id: Id::new([0; ID_LENGTH]),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        big.attrs.set_size(CHUNK_SIZE as u64 + 1);
+        let big = Node::File(big);
+        account(&mut root, &["big"], empty, usage_of(big.as_entry()));
+        root.insert(big);
+        assert_eq!((root.usage.inodes, root.usage.chunks), (3, 2));
+        assert_eq!(
+            root.usage.xattr("user.eoss.bytes"),
+            Some(CHUNK_SIZE as u64 + 61)
+        );
+
+        let usage = root.usage;
+        root.usage = Default::default();
+        assert_eq!(recount(&mut root), usage);
+        match root.resolve(&["dir"]) {
+            Some(Entry::Dir(dir)) => assert_eq!(dir.usage.inodes, 1),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::chunk::CHUNK_SIZE;
+
+/// Readahead detects sequential reads of a file handle and tells which
+/// chunks to prefetch. The window doubles up to `max` chunks on each read
+/// following the previous one, and halves on a read elsewhere.
+pub struct Readahead {
+    max: usize,
+    window: usize,
+    /// Offset following the last read
+    next: u64,
+    /// Chunks before this one are prefetched already
+    ahead: usize,
+}
+
+impl Readahead {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            window: 0,
+            next: 0,
+            ahead: 0,
+        }
+    }
+
+    /// Record a read of `len` bytes at `offset`.
+    /// Returns the indexes of chunks to prefetch, not prefetched yet.
+    pub fn read(&mut self, offset: u64, len: usize) -> Range<usize> {
+        let end = offset + len as u64;
+        if offset == self.next {
+            self.window = (self.window * 2).max(1).min(self.max);
+        } else {
+            self.window /= 2;
+            self.ahead = 0;
+        }
+        self.next = end;
+        if self.window == 0 {
+            return 0..0;
+        }
+        let last = (end.saturating_sub(1) / CHUNK_SIZE as u64) as usize;
+        let start = self.ahead.max(last + 1);
+        let stop = (last + 1 + self.window).max(start);
+        self.ahead = stop;
+        start..stop
+    }
+}
+
+/// ReadaheadTable assigns file handles to opened files, each detecting
+/// sequential reads on its own.
+pub struct ReadaheadTable {
+    max: usize,
+    handles: HashMap<u64, Readahead>,
+    next: u64,
+    /// Handles not released yet
+    open: usize,
+}
+
+impl ReadaheadTable {
+    /// Prefetch at most `max` chunks ahead, zero disables readahead.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            handles: HashMap::new(),
+            next: 1,
+            open: 0,
+        }
+    }
+
+    /// Allocate a file handle.
+    pub fn open(&mut self) -> u64 {
+        let fh = self.next;
+        self.next += 1;
+        self.open += 1;
+        if self.max > 0 {
+            self.handles.insert(fh, Readahead::new(self.max));
+        }
+        fh
+    }
+
+    /// Record a read of `len` bytes at `offset` through handle `fh`.
+    /// Returns the indexes of chunks to prefetch.
+    pub fn read(&mut self, fh: u64, offset: u64, len: usize) -> Range<usize> {
+        match self.handles.get_mut(&fh) {
+            Some(readahead) => readahead.read(offset, len),
+            None => 0..0,
+        }
+    }
+
+    /// Forget handle `fh`.
+    pub fn release(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+        self.open = self.open.saturating_sub(1);
+    }
+
+    /// Number of handles not released yet.
+    pub fn len(&self) -> usize {
+        self.open
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Readahead;
+    use crate::chunk::CHUNK_SIZE;
+
+    #[test]
+    fn test_adaptive_window() {
+        let mut readahead = Readahead::new(4);
+        let size = CHUNK_SIZE as u64;
+        assert_eq!(readahead.read(0, CHUNK_SIZE), 1..2);
+        assert_eq!(readahead.read(size, CHUNK_SIZE), 2..4);
+        // the window is at most 4 chunks, ahead of the chunk read
+        assert_eq!(readahead.read(2 * size, CHUNK_SIZE), 4..7);
+        assert_eq!(readahead.read(3 * size, CHUNK_SIZE), 7..8);
+        // random reads shrink the window until none are prefetched
+        assert_eq!(readahead.read(0, 100), 1..3);
+        assert_eq!(readahead.read(10 * size, 100), 11..12);
+        assert_eq!(readahead.read(0, 100), 0..0);
+        assert_eq!(readahead.read(100, 100), 1..2);
+    }
+}
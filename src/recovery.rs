@@ -0,0 +1,85 @@
+use crate::fs::DirMeta;
+use crate::journal::Record;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::quota;
+
+/// What a recovery has done.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RecoveryStats {
+    /// Journal records replayed
+    pub records: usize,
+    /// Files with data beyond their end cleared
+    pub files: usize,
+}
+
+/// Bring the tree at `root` back to a consistent state after an unclean
+/// shutdown: replay the journal `records` onto it, then clear data of
+/// writes in flight that never made it into metadata.
+pub fn recover(
+    root: &mut DirMeta,
+    provider: &dyn ChunkProvider,
+    records: Vec<Record>,
+) -> Result<RecoveryStats, ChunkProviderError> {
+    let mut stats = RecoveryStats {
+        records: records.len(),
+        ..RecoveryStats::default()
+    };
+    for record in records {
+        record.apply(root);
+    }
+    // usage is not journaled
+    quota::recount(root);
+    root.visit_files(&mut |file| {
+        if file.clear_tail(provider)? {
+            stats.files += 1;
+        }
+        Ok::<_, ChunkProviderError>(())
+    })?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::recover;
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fs::{Attrs, DirMeta, FileMeta, Node};
+    use crate::id::Id;
+    use crate::journal::Record;
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_recover() {
+        let provider = MemoryProvider::new();
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        file.write(&provider, 0, &[1; 100]).unwrap();
+        let logged = file.clone();
+        // in flight when crashed
+        file.write(&provider, 100, &vec![2; CHUNK_SIZE]).unwrap();
+
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let records = vec![Record::Put {
+            path: vec!["file".to_owned()],
+            node: Node::File(logged),
+        }];
+        let stats = recover(&mut root, &provider, records).unwrap();
+        assert_eq!((stats.records, stats.files), (1, 1));
+
+        let file = match root.lookup_mut("file") {
+            Some(crate::fs::EntryMut::File(file)) => file,
+            _ => panic!("file not replayed"),
+        };
+        assert_eq!(file.attrs.size, 100);
+        file.truncate(&provider, 2 * CHUNK_SIZE as u64, false)
+            .unwrap();
+        let mut buf = vec![0xff; 2 * CHUNK_SIZE];
+        file.read(&provider, 0, &mut buf).unwrap();
+        assert!(buf[..100].iter().all(|b| *b == 1));
+        assert!(buf[100..].iter().all(|b| *b == 0));
+    }
+}
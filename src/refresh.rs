@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::dirindex;
+use crate::events::Event;
+use crate::fs::DirMeta;
+use crate::id::{ChunkId, Id, Journal as JournalChunk, ID_LENGTH};
+use crate::invalidate::Target;
+use crate::journal::Journal;
+use crate::meta;
+use crate::provider::ChunkProvider;
+use crate::snapshot::Snapshots;
+use crate::superblock::{Superblock, SuperblockError};
+
+/// The tree a writer last published: its root directory, the epoch of
+/// the journal, which a writer starts anew each time it persists the tree,
+/// and the sequence of the superblock if signed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Publication {
+    pub root_id: [u8; ID_LENGTH],
+    pub epoch: u64,
+    pub sequence: u64,
+}
+
+/// Read the superblock stored at `superblock_id` and the publication it
+/// points at past what `provider` cached of them.
+pub fn publication(
+    provider: &dyn ChunkProvider,
+    superblock_id: &Id,
+) -> Result<(Superblock, Publication), SuperblockError> {
+    provider.invalidate(&meta::chunk_id(&ChunkId::root(superblock_id.clone()), 0));
+    let superblock = Superblock::load(provider, superblock_id)?;
+    let journal_id = superblock.journal_id();
+    provider.invalidate(&ChunkId::<JournalChunk>::derive_n(&journal_id, 0).into_id());
+    let (journal, _) = Journal::open(provider, journal_id)?;
+    let publication = Publication {
+        root_id: superblock.root_id,
+        epoch: journal.epoch(),
+        sequence: superblock.sequence,
+    };
+    Ok((superblock, publication))
+}
+
+/// A tree published, loaded whole to be swapped in for the one mounted.
+pub struct Loaded {
+    pub superblock: Superblock,
+    pub publication: Publication,
+    pub root: DirMeta,
+    pub snapshots: Snapshots,
+}
+
+/// Refresh tracks the publication mounted read-only, and loads the ones
+/// its writer publishes since in background, for requests to swap in.
+pub struct Refresh {
+    superblock_id: Id,
+    /// Age past which a signed superblock is refused
+    max_age: Option<Duration>,
+    mounted: Mutex<Publication>,
+    loaded: Mutex<Option<Loaded>>,
+    stale: AtomicBool,
+    /// Changes of the trees swapped in, and what the kernel cached of the
+    /// ones swapped out
+    invalidated: Mutex<(Vec<Event>, Vec<Target>)>,
+}
+
+impl Refresh {
+    pub fn new(superblock_id: Id, mounted: Publication, max_age: Option<Duration>) -> Self {
+        Self {
+            superblock_id,
+            max_age,
+            mounted: Mutex::new(mounted),
+            loaded: Mutex::new(None),
+            stale: AtomicBool::new(false),
+            invalidated: Default::default(),
+        }
+    }
+
+    /// Compare the publication with the one mounted, returns the superblock
+    /// of another one not loaded yet. A signed superblock older than the
+    /// one mounted, or stale, is refused.
+    pub fn poll(
+        &self,
+        provider: &dyn ChunkProvider,
+    ) -> Result<Option<(Superblock, Publication)>, SuperblockError> {
+        let (superblock, current) = publication(provider, &self.superblock_id)?;
+        superblock.check_fresh(self.mounted.lock().sequence, self.max_age)?;
+        let loaded = self.loaded.lock().as_ref().map(|loaded| loaded.publication);
+        if current == *self.mounted.lock() || loaded == Some(current) {
+            return Ok(None);
+        }
+        Ok(Some((superblock, current)))
+    }
+
+    /// Load the tree `publication` of `superblock` points at, the mount
+    /// being stale until it is taken.
+    pub fn load(
+        &self,
+        provider: &dyn ChunkProvider,
+        superblock: Superblock,
+        publication: Publication,
+    ) -> Result<(), SuperblockError> {
+        let root = dirindex::load_dir(provider, &superblock.root_id)?;
+        let snapshots = Snapshots::open(provider, superblock.snapshots_id())?;
+        *self.loaded.lock() = Some(Loaded {
+            superblock,
+            publication,
+            root,
+            snapshots,
+        });
+        self.stale.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// The tree loaded since the last one taken, recorded as mounted.
+    pub fn take(&self) -> Option<Loaded> {
+        if !self.stale.swap(false, Ordering::Acquire) {
+            return None;
+        }
+        let loaded = self.loaded.lock().take()?;
+        *self.mounted.lock() = loaded.publication;
+        Some(loaded)
+    }
+
+    /// Queue `events` for the kernel to be told of and `targets` for it to
+    /// drop, which it cannot be while a request is being handled.
+    pub fn invalidate(&self, events: Vec<Event>, targets: Vec<Target>) {
+        let mut invalidated = self.invalidated.lock();
+        invalidated.0.extend(events);
+        invalidated.1.extend(targets);
+    }
+
+    /// Events and targets queued for the kernel.
+    pub fn take_invalidated(&self) -> (Vec<Event>, Vec<Target>) {
+        std::mem::take(&mut *self.invalidated.lock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{publication, Refresh};
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+
+    #[test]
+    fn test_poll() {
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let (_, published) = publication(provider.as_ref(), &id).unwrap();
+        let refresh = Refresh::new(id.clone(), published, None);
+        assert!(refresh.poll(provider.as_ref()).unwrap().is_none());
+        assert!(refresh.take().is_none());
+
+        let mut writer = EossFs::open(provider.clone(), MountOptions::default(), &id).unwrap();
+        writer.close().unwrap();
+        let (superblock, published) = refresh.poll(provider.as_ref()).unwrap().unwrap();
+        assert!(refresh.take().is_none());
+        refresh
+            .load(provider.as_ref(), superblock, published)
+            .unwrap();
+        // loaded once
+        assert!(refresh.poll(provider.as_ref()).unwrap().is_none());
+        assert_eq!(refresh.take().unwrap().publication, published);
+        assert!(refresh.take().is_none());
+        assert!(refresh.poll(provider.as_ref()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,195 @@
+use std::ops::Deref;
+
+use crate::id::{ChunkId, Id, Meta, ID_LENGTH};
+use crate::meta::{self, Decode, Encode, MetaError, Reader};
+use crate::provider::ChunkProvider;
+use crate::providers::encrypted::EncryptedProvider;
+
+/// Context deriving the id of the progress from the superblock id.
+const PROGRESS_CONTEXT: &str = "eoss-fuse 2021-05 rekey progress";
+
+/// How far chunks are resealed under the current epoch, persisted so the
+/// job resumes after a restart.
+struct Progress {
+    epoch: u32,
+    /// Last chunk resealed, chunks are resealed in order of id
+    cursor: Option<[u8; ID_LENGTH]>,
+    done: bool,
+}
+
+impl Encode for Progress {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.epoch.encode(buf);
+        self.cursor.is_some().encode(buf);
+        self.cursor.unwrap_or_default().encode(buf);
+        self.done.encode(buf);
+    }
+}
+
+impl Decode for Progress {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let epoch = u32::decode(reader)?;
+        let started = bool::decode(reader)?;
+        let cursor = <[u8; ID_LENGTH]>::decode(reader)?;
+        Ok(Self {
+            epoch,
+            cursor: if started { Some(cursor) } else { None },
+            done: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// Id of the progress of the filesystem with superblock `superblock_id`.
+pub fn progress_id(superblock_id: &Id) -> ChunkId<Meta> {
+    ChunkId::root(Id::new(blake3::derive_key(
+        PROGRESS_CONTEXT,
+        &**superblock_id,
+    )))
+}
+
+/// A job resealing chunks sealed under an older epoch, which lists them
+/// once and checkpoints the last one resealed, so it resumes after a
+/// restart.
+pub struct Rekey {
+    progress_id: ChunkId<Meta>,
+    progress: Option<Progress>,
+    /// Chunks left to reseal in descending order of id, none if the
+    /// provider cannot enumerate them
+    pending: Option<Vec<Id>>,
+}
+
+impl Rekey {
+    /// Reseal chunks of the filesystem with superblock `superblock_id`.
+    pub fn new(superblock_id: &Id) -> Self {
+        Self {
+            progress_id: progress_id(superblock_id),
+            progress: None,
+            pending: None,
+        }
+    }
+
+    /// Whether all chunks are known to be sealed under `epoch`.
+    pub fn done(&self, epoch: u32) -> bool {
+        matches!(&self.progress, Some(progress) if progress.epoch == epoch && progress.done)
+    }
+
+    /// Reseal the next `batch` chunks sealed under an older epoch. Returns
+    /// true once all chunks are sealed under the current epoch, or if the
+    /// provider cannot enumerate them.
+    pub fn step<P>(
+        &mut self,
+        provider: &EncryptedProvider<P>,
+        batch: usize,
+    ) -> Result<bool, MetaError>
+    where
+        P: Deref + Send + Sync,
+        P::Target: ChunkProvider,
+    {
+        let epoch = provider.keys().current();
+        if !matches!(&self.progress, Some(progress) if progress.epoch == epoch) {
+            self.resume(provider, epoch)?;
+        }
+        let (progress, pending) = match (&mut self.progress, &mut self.pending) {
+            (Some(progress), Some(pending)) if !progress.done => (progress, pending),
+            _ => return Ok(true),
+        };
+        for _ in 0..batch {
+            let id = match pending.last() {
+                Some(id) => id,
+                None => break,
+            };
+            provider.reseal(id)?;
+            progress.cursor = Some(**id);
+            pending.pop();
+        }
+        progress.done = pending.is_empty();
+        meta::store(provider, &self.progress_id, progress)?;
+        Ok(progress.done)
+    }
+
+    /// Load the progress checkpointed for `epoch`, and list the chunks
+    /// past it.
+    fn resume<P>(&mut self, provider: &EncryptedProvider<P>, epoch: u32) -> Result<(), MetaError>
+    where
+        P: Deref + Send + Sync,
+        P::Target: ChunkProvider,
+    {
+        let progress = match meta::load::<Progress>(provider, &self.progress_id) {
+            Ok(progress) if progress.epoch == epoch => progress,
+            // rotated since, or never started
+            Ok(_) | Err(MetaError::BadMagic) => Progress {
+                epoch,
+                cursor: None,
+                done: false,
+            },
+            Err(e) => return Err(e),
+        };
+        self.pending = match progress.done {
+            true => Some(Vec::new()),
+            false => provider.list_chunks()?,
+        };
+        if let Some(pending) = &mut self.pending {
+            let own = meta::chunk_id(&self.progress_id, 0);
+            pending.retain(|id| *id != own && progress.cursor.map_or(true, |cursor| **id > cursor));
+            pending.sort_by(|a, b| b.cmp(a));
+        }
+        self.progress = Some(progress);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rekey;
+    use crate::chunk::Chunk;
+    use crate::crypt::{self, KeyRing, MasterKey};
+    use crate::id::Id;
+    use crate::provider::ChunkProvider;
+    use crate::providers::encrypted::EncryptedProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_resume() {
+        let memory = Arc::new(MemoryProvider::new());
+        let inner: Arc<dyn ChunkProvider> = memory.clone();
+        let key = MasterKey::new_random();
+        let keys = Arc::new(KeyRing::new(key));
+        let superblock_id = Id::new(SUPERBLOCK_ID);
+        let provider = EncryptedProvider::new(inner.clone(), keys.clone(), superblock_id.clone());
+        let ids: Vec<Id> = (0..5).map(|_| Id::new_random()).collect();
+        for id in ids.iter() {
+            provider.save_chunk(&Chunk::new(id.clone())).unwrap();
+        }
+        // a chunk in the clear beneath, left as it is
+        let clear = Chunk::new(Id::new_random());
+        clear.write_at(0, b"clear");
+        inner.save_chunk(&clear).unwrap();
+        let master = MasterKey::new_random();
+        let retired = keys.retire(&master);
+        keys.rotate(master.clone());
+        let mut job = Rekey::new(&superblock_id);
+        assert!(!job.step(&provider, 2).unwrap());
+
+        // restarted
+        let keys = Arc::new(KeyRing::open(master, 1, &retired).unwrap());
+        let provider = EncryptedProvider::new(inner, keys, superblock_id.clone());
+        let mut job = Rekey::new(&superblock_id);
+        while !job.step(&provider, 2).unwrap() {}
+        assert!(job.done(1));
+        for id in ids.iter() {
+            let sealed = memory.get_object(id).unwrap().unwrap();
+            assert_eq!(crypt::epoch(&sealed), Some(1));
+        }
+        assert_eq!(
+            &memory.get_object(clear.id()).unwrap().unwrap()[..5],
+            b"clear"
+        );
+        // opened under the new key alone
+        provider.keys().drop_retired();
+        for id in ids.iter() {
+            provider.get_chunk_by_id(id).unwrap();
+        }
+    }
+}
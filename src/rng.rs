@@ -0,0 +1,98 @@
+//! Ids are drawn unpredictably unless built for tests, or with the
+//! `seeded` feature, which may seed them to be reproducible.
+
+#[cfg(any(test, feature = "seeded"))]
+use std::cell::RefCell;
+#[cfg(any(test, feature = "seeded"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(any(test, feature = "seeded"))]
+use parking_lot::{const_mutex, Mutex};
+#[cfg(any(test, feature = "seeded"))]
+use rand::rngs::StdRng;
+#[cfg(any(test, feature = "seeded"))]
+use rand::SeedableRng;
+use rand::{thread_rng, RngCore};
+
+/// Generator shared by all threads once seeded.
+#[cfg(any(test, feature = "seeded"))]
+static SEEDED: Mutex<Option<StdRng>> = const_mutex(None);
+/// Whether `SEEDED` is set, checked without locking.
+#[cfg(any(test, feature = "seeded"))]
+static IS_SEEDED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(any(test, feature = "seeded"))]
+thread_local! {
+    /// Generator of this thread once seeded, ahead of the shared one.
+    static THREAD: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// Draw from a generator seeded by `seed` in every thread, reproducible as
+/// long as they draw in the same order, or from the OS again if `None`.
+#[cfg(any(test, feature = "seeded"))]
+pub fn seed(seed: Option<u64>) {
+    let mut seeded = SEEDED.lock();
+    *seeded = seed.map(StdRng::seed_from_u64);
+    IS_SEEDED.store(seeded.is_some(), Ordering::Release);
+}
+
+/// Draw from a generator seeded by `seed` in this thread only, regardless
+/// of other threads, or as they do again if `None`.
+#[cfg(any(test, feature = "seeded"))]
+pub fn seed_thread(seed: Option<u64>) {
+    THREAD.with(|rng| *rng.borrow_mut() = seed.map(StdRng::seed_from_u64));
+}
+
+/// Fill `buf` with random bytes, reproducible if seeded. Unseeded, they
+/// come from the generator of the calling thread, so threads share no
+/// pool to contend on or size. Keys, salts and nonces are always drawn
+/// from the OS instead.
+pub fn fill_bytes(buf: &mut [u8]) {
+    #[cfg(any(test, feature = "seeded"))]
+    if seeded_bytes(buf) {
+        return;
+    }
+    thread_rng().fill_bytes(buf)
+}
+
+/// Fill `buf` from a seeded generator if any. Returns whether filled.
+#[cfg(any(test, feature = "seeded"))]
+fn seeded_bytes(buf: &mut [u8]) -> bool {
+    let drawn = THREAD.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => {
+            rng.fill_bytes(buf);
+            true
+        }
+        None => false,
+    });
+    if drawn {
+        return true;
+    }
+    if IS_SEEDED.load(Ordering::Acquire) {
+        if let Some(rng) = SEEDED.lock().as_mut() {
+            rng.fill_bytes(buf);
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fill_bytes, seed_thread};
+
+    #[test]
+    fn test_seeded() {
+        let draw = || {
+            let mut buf = [0; 16];
+            fill_bytes(&mut buf);
+            buf
+        };
+        seed_thread(Some(7));
+        let first = (draw(), draw());
+        seed_thread(Some(7));
+        assert_eq!((draw(), draw()), first);
+        seed_thread(None);
+        assert_ne!(draw(), first.0);
+    }
+}
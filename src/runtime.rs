@@ -0,0 +1,108 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::task;
+
+use crate::options::RuntimeOptions;
+
+/// Background runs the background work of a mounted filesystem on a tokio
+/// runtime, periodic tasks as timers and calls into the synchronous
+/// providers on its blocking pool.
+pub struct Background {
+    handle: Handle,
+    /// Shut down along with the filesystem if dedicated
+    runtime: Option<Runtime>,
+}
+
+impl Background {
+    /// Spawn a dedicated runtime or use the one of the caller, as set by
+    /// `options`.
+    pub fn new(options: &RuntimeOptions) -> io::Result<Self> {
+        match options {
+            RuntimeOptions::Dedicated {
+                worker_threads,
+                blocking_threads,
+            } => {
+                let runtime = Builder::new_multi_thread()
+                    .worker_threads((*worker_threads).max(1))
+                    .max_blocking_threads((*blocking_threads).max(1))
+                    .thread_name("eoss-fuse")
+                    .enable_time()
+                    .build()?;
+                Ok(Self {
+                    handle: runtime.handle().clone(),
+                    runtime: Some(runtime),
+                })
+            }
+            RuntimeOptions::Handle(handle) => Ok(Self {
+                handle: handle.clone(),
+                runtime: None,
+            }),
+        }
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// Run `f` on the blocking pool.
+    pub fn spawn_blocking<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.spawn_blocking(f);
+    }
+
+    /// Run `f` on the blocking pool every `interval`, until it returns false.
+    pub fn every<F>(&self, interval: Duration, f: F)
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        self.handle.spawn(async move {
+            let mut f = f;
+            loop {
+                tokio::time::sleep(interval).await;
+                match task::spawn_blocking(move || (f(), f)).await {
+                    Ok((true, next)) => f = next,
+                    _ => break,
+                }
+            }
+        });
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        // tasks still running end with the filesystem they refer to
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Background;
+    use crate::options::RuntimeOptions;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_every() {
+        let background = Background::new(&RuntimeOptions::Dedicated {
+            worker_threads: 1,
+            blocking_threads: 1,
+        })
+        .unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let mut rounds = 0;
+        background.every(Duration::from_millis(1), move || {
+            rounds += 1;
+            sender.send(rounds).unwrap();
+            rounds < 3
+        });
+        let received: Vec<_> = receiver.iter().collect();
+        assert_eq!(received, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,496 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+use crate::chunk::CHUNK_SIZE;
+use crate::vfs::{FileKind, Metadata, Vfs};
+
+/// Keys listed at most by a request, as S3 does.
+const MAX_KEYS: usize = 1000;
+/// Longest request head accepted, request line and headers.
+const MAX_HEAD: usize = 16 * 1024;
+/// Start of continuation tokens, followed by the last key listed.
+const TOKEN_MARK: char = '~';
+
+/// Serve the filesystem read-only to S3 clients connecting to `listener`,
+/// as the single bucket `bucket` whose keys are paths of files. Requests
+/// are not authenticated, the listener should only be reachable by those
+/// allowed to read everything.
+pub fn serve(listener: TcpListener, bucket: String, vfs: Arc<Mutex<Vfs>>) {
+    let bucket = Arc::new(bucket);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (bucket, vfs) = (bucket.clone(), vfs.clone());
+            thread::spawn(move || {
+                if let Err(e) = answer(stream, &bucket, &vfs) {
+                    tracing::debug!("s3 client: {}", e);
+                }
+            });
+        }
+    });
+}
+
+/// A request, its path and query decoded.
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    range: Option<String>,
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Answer the one request read from `stream`, then hang up.
+fn answer(stream: TcpStream, bucket: &str, vfs: &Mutex<Vfs>) -> io::Result<()> {
+    let request = match read_request(&stream)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let mut stream = &stream;
+    let head = request.method == "HEAD";
+    if request.method != "GET" && !head {
+        return error(stream, 405, "MethodNotAllowed", "the gateway is read-only");
+    }
+    let path = request.path.trim_start_matches('/');
+    let (name, key) = match path.split_once('/') {
+        Some((name, key)) => (name, key),
+        None => (path, ""),
+    };
+    if name.is_empty() {
+        let body = format!(
+            "<ListAllMyBucketsResult><Buckets><Bucket><Name>{}</Name>\
+             </Bucket></Buckets></ListAllMyBucketsResult>",
+            escape(bucket)
+        );
+        return xml(stream, 200, &body, head);
+    }
+    if name != bucket {
+        return error(stream, 404, "NoSuchBucket", name);
+    }
+    if key.is_empty() {
+        return match request.param("list-type") {
+            Some("2") => list(stream, &request, vfs, head),
+            _ => error(
+                stream,
+                501,
+                "NotImplemented",
+                "only ListObjectsV2 is supported",
+            ),
+        };
+    }
+    let (file, metadata) = {
+        let mut vfs = vfs.lock();
+        match vfs.metadata(key) {
+            Ok(metadata) if metadata.kind == FileKind::File => (vfs.open_file(key)?, metadata),
+            _ => return error(stream, 404, "NoSuchKey", key),
+        }
+    };
+    let range = match request
+        .range
+        .as_deref()
+        .map(|range| parse_range(range, metadata.size))
+    {
+        Some(None) => {
+            let _ = vfs.lock().close_file(file);
+            return error(stream, 416, "InvalidRange", "bytes out of the object");
+        }
+        Some(Some(range)) => Some(range),
+        None => None,
+    };
+    let (start, end) = range.unwrap_or((0, metadata.size));
+    let mut response = match range {
+        Some(_) => format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+            start,
+            end.saturating_sub(1),
+            metadata.size
+        ),
+        None => "HTTP/1.1 200 OK\r\n".to_owned(),
+    };
+    response.push_str(&format!(
+        "Content-Length: {}\r\nContent-Type: application/octet-stream\r\n\
+         Last-Modified: {}\r\nETag: \"{}\"\r\nAccept-Ranges: bytes\r\n\
+         Connection: close\r\n\r\n",
+        end - start,
+        http_date(metadata.mtime),
+        etag(&metadata),
+    ));
+    let mut streamed = stream.write_all(response.as_bytes());
+    // a chunk at a time, without holding the filesystem in between
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut offset = start;
+    while streamed.is_ok() && !head && offset < end {
+        let len = (end - offset).min(CHUNK_SIZE as u64) as usize;
+        streamed = match vfs.lock().read(&file, offset, &mut buf[..len]) {
+            Ok(0) => Err(io::Error::new(ErrorKind::UnexpectedEof, "object shrank")),
+            Ok(n) => stream.write_all(&buf[..n]).map(|_| offset += n as u64),
+            Err(e) => Err(e),
+        };
+    }
+    let _ = vfs.lock().close_file(file);
+    streamed
+}
+
+/// Read the request line and headers, `None` if the client hung up first.
+fn read_request(stream: &TcpStream) -> io::Result<Option<Request>> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut words = line.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "bad request line")),
+    };
+    let mut range = None;
+    let mut read = line.len();
+    loop {
+        line.clear();
+        read += reader.read_line(&mut line)?;
+        if read > MAX_HEAD {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_owned());
+            }
+        }
+    }
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_query(key), decode_query(value))
+        })
+        .collect();
+    Ok(Some(Request {
+        method,
+        path: decode(path),
+        query,
+        range,
+    }))
+}
+
+/// Answer ListObjectsV2 with the keys under the `prefix` parameter.
+fn list(stream: &TcpStream, request: &Request, vfs: &Mutex<Vfs>, head: bool) -> io::Result<()> {
+    let prefix = request.param("prefix").unwrap_or("");
+    let delimiter = request
+        .param("delimiter")
+        .filter(|delimiter| *delimiter == "/");
+    // tokens are the key listing carries on after, marked to never be empty
+    let after = request
+        .param("continuation-token")
+        .map(|token| token.strip_prefix(TOKEN_MARK).unwrap_or(token))
+        .or_else(|| request.param("start-after"))
+        .unwrap_or("");
+    let max_keys = request
+        .param("max-keys")
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(MAX_KEYS)
+        .min(MAX_KEYS);
+    // only the directory holding the prefix is walked
+    let dir = prefix.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let mut keys = Vec::new();
+    walk(&mut vfs.lock(), dir, delimiter.is_some(), &mut keys);
+    keys.retain(|(key, _)| key.starts_with(prefix) && key.as_str() > after);
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    let truncated = keys.len() > max_keys;
+    keys.truncate(max_keys);
+
+    let mut body = format!(
+        "<ListBucketResult><Name>{}</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount>\
+         <MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+        escape(request.path.trim_matches('/')),
+        escape(prefix),
+        keys.len(),
+        max_keys,
+        truncated
+    );
+    if truncated {
+        let last = keys.last().map_or(after, |(last, _)| last.as_str());
+        body.push_str(&format!(
+            "<NextContinuationToken>{}{}</NextContinuationToken>",
+            TOKEN_MARK,
+            escape(last)
+        ));
+    }
+    for (key, metadata) in &keys {
+        match metadata {
+            Some(metadata) => body.push_str(&format!(
+                "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>\"{}\"</ETag>\
+                 <Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                escape(key),
+                iso_date(metadata.mtime),
+                etag(metadata),
+                metadata.size
+            )),
+            None => body.push_str(&format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                escape(key)
+            )),
+        }
+    }
+    body.push_str("</ListBucketResult>");
+    xml(stream, 200, &body, head)
+}
+
+/// Collect the keys of files beneath `dir`, or with `grouped` the files in
+/// it and its directories as common prefixes, without metadata.
+fn walk(vfs: &mut Vfs, dir: &str, grouped: bool, keys: &mut Vec<(String, Option<Metadata>)>) {
+    let entries = match vfs.read_dir(dir) {
+        Ok(entries) => entries.collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    for entry in entries {
+        let key = match dir {
+            "" => entry.name,
+            dir => format!("{}/{}", dir, entry.name),
+        };
+        match entry.metadata.kind {
+            FileKind::File => keys.push((key, Some(entry.metadata))),
+            FileKind::Dir if grouped => keys.push((key + "/", None)),
+            FileKind::Dir => walk(vfs, &key, grouped, keys),
+        }
+    }
+}
+
+/// Range of bytes `[start, end)` asked by a Range header, `None` if not
+/// satisfiable.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, (last + 1).min(size)),
+        (Ok(first), Err(_)) if last.is_empty() => (first, size),
+        (Err(_), Ok(suffix)) if first.is_empty() => (size.saturating_sub(suffix), size),
+        _ => return None,
+    };
+    Some((start, end)).filter(|_| start < end)
+}
+
+fn xml(mut stream: &TcpStream, status: u16, body: &str, head: bool) -> io::Result<()> {
+    let body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", body);
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/xml\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        reason(status),
+        body.len()
+    )?;
+    match head {
+        true => Ok(()),
+        false => stream.write_all(body.as_bytes()),
+    }
+}
+
+fn error(stream: &TcpStream, status: u16, code: &str, message: &str) -> io::Result<()> {
+    let body = format!(
+        "<Error><Code>{}</Code><Message>{}</Message></Error>",
+        code,
+        escape(message)
+    );
+    xml(stream, status, &body, false)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        _ => "Not Implemented",
+    }
+}
+
+/// Tag of the content of a file, changing whenever it is modified.
+fn etag(metadata: &Metadata) -> String {
+    let mtime = metadata
+        .mtime
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{:x}-{:x}", metadata.size, mtime.as_nanos())
+}
+
+/// Decode a component of a query, '+' standing for a space.
+fn decode_query(s: &str) -> String {
+    decode(&s.replace('+', " "))
+}
+
+/// Decode %-escapes of a URL component.
+fn decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (byte, hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+                continue;
+            }
+            (byte, _) => bytes.push(byte),
+        }
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Year, month and day of `days` since the epoch.
+fn civil(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// `time` as HTTP dates are, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// `time` as S3 lists it, e.g. "2009-10-12T17:50:30.000Z".
+fn iso_date(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (year, month, day) = civil(secs / 86400);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use parking_lot::Mutex;
+
+    use super::{http_date, parse_range, serve};
+    use crate::fuse::EossFs;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::providers::memory::MemoryProvider;
+    use crate::vfs::Vfs;
+
+    fn get(addr: &str, target: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: s3\r\n{}\r\n",
+            target, headers
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_gateway() {
+        let provider = Arc::new(MemoryProvider::new());
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut vfs = Vfs::open(provider.clone(), MountOptions::default()).unwrap();
+        let file = vfs.create("a b", 0o644).unwrap();
+        vfs.write(&file, 0, b"hello world").unwrap();
+        vfs.close_file(file).unwrap();
+        let file = vfs.create("a+b", 0o644).unwrap();
+        vfs.close_file(file).unwrap();
+        // persisted while still open
+        vfs.sync_all().unwrap();
+        let options = MountOptions {
+            read_only: true,
+            ..MountOptions::default()
+        };
+        let mut vfs = Vfs::open(provider, options).unwrap();
+        assert!(vfs.create("c", 0o644).is_err());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        serve(listener, "data".to_owned(), Arc::new(Mutex::new(vfs)));
+
+        let object = get(&addr, "/data/a%20b", "");
+        assert!(object.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(object.ends_with("\r\n\r\nhello world"));
+        let range = get(&addr, "/data/a%20b", "Range: bytes=6-\r\n");
+        assert!(range.contains("Content-Range: bytes 6-10/11\r\n"));
+        assert!(range.ends_with("\r\n\r\nworld"));
+        let listed = get(&addr, "/data?list-type=2&prefix=a", "");
+        assert!(listed.contains("<Key>a b</Key>"));
+        assert!(listed.contains("<Size>11</Size>"));
+        assert!(get(&addr, "/data/a+b", "").starts_with("HTTP/1.1 200 OK\r\n"));
+        let none = get(&addr, "/data?list-type=2&max-keys=0", "");
+        assert!(none.contains("<IsTruncated>true</IsTruncated>"));
+        assert!(none.contains("<NextContinuationToken>~</NextContinuationToken>"));
+        let rest = get(
+            &addr,
+            "/data?list-type=2&max-keys=1&continuation-token=~a%2Bb",
+            "",
+        );
+        assert!(rest.contains("<KeyCount>0</KeyCount>"));
+        assert!(get(&addr, "/data/missing", "").contains("<Code>NoSuchKey</Code>"));
+
+        assert_eq!(parse_range("bytes=-4", 11), Some((7, 11)));
+        assert_eq!(parse_range("bytes=20-", 11), None);
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::chunk::{Chunk, CHUNK_SIZE};
+use crate::fs::DirMeta;
+use crate::id::Id;
+use crate::merkle::{self, UNHASHED};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::providers::cached::CachedProvider;
+
+/// Extended attribute of the root reporting the last scrub.
+pub const SCRUB_XATTR: &str = "user.eoss.scrub";
+
+/// Result of a scrub.
+#[derive(Clone, Debug, Default)]
+pub struct ScrubStats {
+    /// Chunks verified against the hashes of their files
+    pub checked: usize,
+    /// Chunks not matching their hash, or not readable at all
+    pub corrupt: Vec<Id>,
+    /// Corrupt chunks rewritten from a copy matching
+    pub repaired: usize,
+}
+
+impl fmt::Display for ScrubStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} checked, {} corrupt, {} repaired",
+            self.checked,
+            self.corrupt.len(),
+            self.repaired
+        )
+    }
+}
+
+/// Verify the chunks of files in `trees`, fully loaded, as read from
+/// `origin`, the provider beneath any cache, against the hashes of their
+/// files. Chunks not hashed yet are skipped.
+/// With `repair`, a corrupt chunk is rewritten to `origin` from `copies`
+/// if they hold a copy matching its hash.
+pub fn scrub<'a>(
+    origin: &dyn ChunkProvider,
+    copies: Option<&CachedProvider>,
+    trees: impl IntoIterator<Item = &'a DirMeta>,
+    repair: bool,
+) -> Result<ScrubStats, ChunkProviderError> {
+    let mut stats = ScrubStats::default();
+    let mut seen = HashSet::new();
+    let mut data = vec![0; CHUNK_SIZE];
+    for tree in trees {
+        tree.visit_files(&mut |file| -> Result<(), ChunkProviderError> {
+            for (n, hash) in file.hashes.iter().enumerate() {
+                let id = file.chunk_id(n).into_id();
+                if *hash == UNHASHED || !seen.insert(id.clone()) {
+                    continue;
+                }
+                stats.checked += 1;
+                match origin.get_chunk_by_id(&id) {
+                    Ok(chunk) => {
+                        chunk.read_at(0, &mut data);
+                        if merkle::chunk_hash(&data) == *hash {
+                            continue;
+                        }
+                    }
+                    // not reachable, rather than corrupt
+                    Err(
+                        e @ (ChunkProviderError::IoError(_)
+                        | ChunkProviderError::NotFound(_)
+                        | ChunkProviderError::PermissionDenied(_)
+                        | ChunkProviderError::Throttled(_)
+                        | ChunkProviderError::Timeout(_)),
+                    ) => return Err(e),
+                    Err(_) => {}
+                }
+                let copy = copies
+                    .filter(|_| repair)
+                    .and_then(|copies| copies.cached_copy(&id))
+                    .filter(|copy| merkle::chunk_hash(copy) == *hash);
+                if let Some(copy) = copy {
+                    origin.save_chunk(&Chunk::new_with_data(id.clone(), copy)?)?;
+                    stats.repaired += 1;
+                }
+                stats.corrupt.push(id);
+            }
+            Ok(())
+        })?;
+    }
+    origin.flush()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scrub;
+    use crate::chunk::{Chunk, CHUNK_SIZE};
+    use crate::fs::{Attrs, DirMeta, FileMeta, Node};
+    use crate::id::Id;
+    use crate::merkle;
+    use crate::provider::ChunkProvider;
+    use crate::providers::cached::CachedProvider;
+    use crate::providers::memory::MemoryProvider;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_repair_from_cache() {
+        let memory = Arc::new(MemoryProvider::new());
+        let cached = CachedProvider::new(memory.clone(), 4 * CHUNK_SIZE, 1);
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        file.write(&cached, 0, &vec![7; 2 * CHUNK_SIZE]).unwrap();
+        merkle::update(&mut file, &cached, None).unwrap();
+        cached.flush().unwrap();
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let (first, second) = (file.chunk_id(0).into_id(), file.chunk_id(1).into_id());
+        root.insert(Node::File(file));
+
+        let stats = scrub(memory.as_ref(), Some(&cached), vec![&root], true).unwrap();
+        assert_eq!((stats.checked, stats.corrupt.len()), (2, 0));
+
+        // rotten behind the cache
+        for id in [&first, &second].iter() {
+            let chunk = Chunk::new((*id).clone());
+            chunk.write_at(0, b"rot");
+            memory.save_chunk(&chunk).unwrap();
+        }
+        cached.invalidate(&second);
+        let stats = scrub(memory.as_ref(), Some(&cached), vec![&root], true).unwrap();
+        assert_eq!((stats.corrupt.len(), stats.repaired), (2, 1));
+        let stats = scrub(memory.as_ref(), Some(&cached), vec![&root], true).unwrap();
+        assert_eq!(stats.corrupt, vec![second]);
+    }
+}
@@ -0,0 +1,771 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libc::{EACCES, EPERM, EROFS};
+use parking_lot::Mutex;
+
+use crate::vfs::{DirEntry, FileHandle, FileKind, Metadata, SetAttrs, User, Vfs};
+
+/// Version of the protocol spoken, the one of OpenSSH.
+pub const VERSION: u32 = 3;
+/// Largest packet accepted, well above the 32KiB clients send.
+const MAX_PACKET: u32 = 256 * 1024;
+/// Entries at most in a reply to READDIR.
+const READDIR_BATCH: usize = 100;
+
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_WRITE: u8 = 6;
+const FXP_LSTAT: u8 = 7;
+const FXP_FSTAT: u8 = 8;
+const FXP_SETSTAT: u8 = 9;
+const FXP_FSETSTAT: u8 = 10;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_REMOVE: u8 = 13;
+const FXP_MKDIR: u8 = 14;
+const FXP_RMDIR: u8 = 15;
+const FXP_REALPATH: u8 = 16;
+const FXP_STAT: u8 = 17;
+const FXP_RENAME: u8 = 18;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+const FXP_ATTRS: u8 = 105;
+
+const FX_OK: u32 = 0;
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+const FX_FAILURE: u32 = 4;
+const FX_BAD_MESSAGE: u32 = 5;
+const FX_OP_UNSUPPORTED: u32 = 8;
+
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+/// File types of permissions, as POSIX numbers them on every host.
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const FXF_READ: u32 = 0x1;
+const FXF_WRITE: u32 = 0x2;
+const FXF_APPEND: u32 = 0x4;
+const FXF_CREAT: u32 = 0x8;
+const FXF_TRUNC: u32 = 0x10;
+const FXF_EXCL: u32 = 0x20;
+
+/// Serve SFTP sessions on the unix socket at `path` in the background,
+/// each relayed by `relay` from sshd and acting as the user connected.
+pub fn serve(path: &Path, vfs: Arc<Mutex<Vfs>>) -> io::Result<()> {
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    // any user may connect, checked as who they are
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let vfs = vfs.clone();
+            thread::spawn(move || {
+                let served =
+                    User::of_peer(&stream).and_then(|user| session(&vfs, user, &stream, &stream));
+                if let Err(e) = served {
+                    tracing::warn!("sftp client: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Relay the requests of sshd's sftp subsystem, stdin and stdout, to the
+/// sessions served on the unix socket at `path`.
+pub fn relay(path: &Path) -> io::Result<()> {
+    let stream = UnixStream::connect(path)?;
+    let mut requests = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin().lock(), &mut requests);
+        let _ = requests.shutdown(Shutdown::Write);
+    });
+    // flushed as replies come, stdout buffering lines
+    let mut stdout = io::stdout();
+    let mut buf = vec![0; MAX_PACKET as usize];
+    loop {
+        match (&stream).read(&mut buf)? {
+            0 => return Ok(()),
+            n => {
+                stdout.write_all(&buf[..n])?;
+                stdout.flush()?;
+            }
+        }
+    }
+}
+
+/// Serve SFTP requests of `user` read from `input` until it ends, replying
+/// to `output`.
+pub fn session(
+    vfs: &Mutex<Vfs>,
+    user: User,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> io::Result<()> {
+    let mut server = Server {
+        vfs,
+        user,
+        handles: HashMap::new(),
+        next: 0,
+    };
+    let result = loop {
+        let mut len = [0; 4];
+        match input.read_exact(&mut len) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e),
+            Ok(()) => {}
+        }
+        let len = u32::from_be_bytes(len);
+        if len == 0 || len > MAX_PACKET {
+            break Err(io::Error::new(ErrorKind::InvalidData, "bad packet length"));
+        }
+        let mut packet = vec![0; len as usize];
+        if let Err(e) = input.read_exact(&mut packet) {
+            break Err(e);
+        }
+        let reply = server.answer(packet[0], &packet[1..]);
+        let written = output
+            .write_all(&(reply.len() as u32).to_be_bytes())
+            .and_then(|_| output.write_all(&reply))
+            .and_then(|_| output.flush());
+        if let Err(e) = written {
+            break Err(e);
+        }
+    };
+    server.close_all();
+    result
+}
+
+/// A file opened by the client, and what for.
+struct OpenFile {
+    file: FileHandle,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+enum Open {
+    File(OpenFile),
+    /// Entries of a directory not read yet
+    Dir(Vec<DirEntry>),
+}
+
+/// Handles opened by the client.
+struct Server<'a> {
+    vfs: &'a Mutex<Vfs>,
+    /// Who the client is, as permissions are checked
+    user: User,
+    handles: HashMap<Vec<u8>, Open>,
+    next: u32,
+}
+
+impl Server<'_> {
+    /// Reply to a packet of type `kind`, its type followed by its payload.
+    fn answer(&mut self, kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut request = Decoder { data: payload };
+        if kind == FXP_INIT {
+            let mut reply = vec![FXP_VERSION];
+            put_u32(&mut reply, VERSION);
+            return reply;
+        }
+        let id = match request.u32() {
+            Ok(id) => id,
+            Err(e) => return status(0, &e),
+        };
+        match self.handle(kind, id, &mut request) {
+            Ok(reply) => reply,
+            Err(e) => status(id, &e),
+        }
+    }
+
+    fn handle(&mut self, kind: u8, id: u32, request: &mut Decoder) -> io::Result<Vec<u8>> {
+        let vfs = self.vfs;
+        let vfs = &mut *vfs.lock();
+        let mut reply = Vec::new();
+        match kind {
+            FXP_OPEN => {
+                let path = request.string()?;
+                let flags = request.u32()?;
+                let attrs = request.attrs()?;
+                let perm = attrs.mode.map_or(0o644, |mode| (mode & 0o7777) as u16);
+                let read = flags & FXF_READ != 0;
+                let write = flags & FXF_WRITE != 0;
+                let exists = vfs.metadata(&path).is_ok();
+                let file = match flags & FXF_CREAT != 0 && (flags & FXF_EXCL != 0 || !exists) {
+                    true => {
+                        self.check_parent(vfs, &path)?;
+                        let file = vfs.create(&path, perm)?;
+                        vfs.set_file_attrs(&file, self.owner())?;
+                        file
+                    }
+                    false => {
+                        let mask = if read { 4 } else { 0 } | if write { 2 } else { 0 };
+                        self.check(vfs, &path, mask)?;
+                        vfs.open_file(&path)?
+                    }
+                };
+                if flags & FXF_TRUNC != 0 && write {
+                    vfs.set_len(&file, 0)?;
+                }
+                let append = flags & FXF_APPEND != 0;
+                let file = OpenFile {
+                    file,
+                    read,
+                    write,
+                    append,
+                };
+                reply = self.open(id, Open::File(file));
+            }
+            FXP_OPENDIR => {
+                let path = request.string()?;
+                self.check(vfs, &path, 4)?;
+                let entries = vfs.read_dir(&path)?.collect();
+                reply = self.open(id, Open::Dir(entries));
+            }
+            FXP_CLOSE => match self.handles.remove(&request.bytes()?) {
+                Some(Open::File(open)) => {
+                    vfs.close_file(open.file)?;
+                    return Ok(status_ok(id));
+                }
+                Some(Open::Dir(_)) => return Ok(status_ok(id)),
+                None => return Err(bad_handle()),
+            },
+            FXP_READ => {
+                let open = file_of(&self.handles, &request.bytes()?)?;
+                if !open.read {
+                    return Err(denied());
+                }
+                let offset = request.u64()?;
+                let len = request.u32()?.min(MAX_PACKET / 2);
+                let mut buf = vec![0; len as usize];
+                let n = vfs.read(&open.file, offset, &mut buf)?;
+                if n == 0 && len > 0 {
+                    return Ok(status_code(id, FX_EOF, "end of file"));
+                }
+                reply.push(FXP_DATA);
+                put_u32(&mut reply, id);
+                put_bytes(&mut reply, &buf[..n]);
+            }
+            FXP_WRITE => {
+                let open = file_of(&self.handles, &request.bytes()?)?;
+                let mut offset = request.u64()?;
+                let data = request.bytes()?;
+                if !open.write {
+                    return Err(denied());
+                }
+                if open.append {
+                    offset = vfs.file_metadata(&open.file)?.size;
+                }
+                vfs.write(&open.file, offset, &data)?;
+                return Ok(status_ok(id));
+            }
+            FXP_STAT | FXP_LSTAT => {
+                let metadata = self.check(vfs, &request.string()?, 0)?;
+                reply = attrs_reply(id, &metadata);
+            }
+            FXP_FSTAT => {
+                let open = file_of(&self.handles, &request.bytes()?)?;
+                reply = attrs_reply(id, &vfs.file_metadata(&open.file)?);
+            }
+            FXP_SETSTAT => {
+                let path = request.string()?;
+                let changes = request.attrs()?;
+                let metadata = self.check(vfs, &path, 0)?;
+                let writable = metadata.permits(self.user, 2);
+                self.check_changes(&metadata, &changes, writable)?;
+                vfs.set_attrs(&path, changes)?;
+                return Ok(status_ok(id));
+            }
+            FXP_FSETSTAT => {
+                let open = file_of(&self.handles, &request.bytes()?)?;
+                let changes = request.attrs()?;
+                let metadata = vfs.file_metadata(&open.file)?;
+                self.check_changes(&metadata, &changes, open.write)?;
+                vfs.set_file_attrs(&open.file, changes)?;
+                return Ok(status_ok(id));
+            }
+            FXP_READDIR => {
+                let entries = match self.handles.get_mut(&request.bytes()?) {
+                    Some(Open::Dir(entries)) => entries,
+                    _ => return Err(bad_handle()),
+                };
+                if entries.is_empty() {
+                    return Ok(status_code(id, FX_EOF, "no more entries"));
+                }
+                let batch = entries.split_off(entries.len().saturating_sub(READDIR_BATCH));
+                reply.push(FXP_NAME);
+                put_u32(&mut reply, id);
+                put_u32(&mut reply, batch.len() as u32);
+                for entry in batch {
+                    put_name(&mut reply, &entry.name, &entry.metadata);
+                }
+            }
+            FXP_REMOVE => {
+                let path = request.string()?;
+                self.check_parent(vfs, &path)?;
+                vfs.remove_file(&path)?;
+                return Ok(status_ok(id));
+            }
+            FXP_MKDIR => {
+                let path = request.string()?;
+                let attrs = request.attrs()?;
+                let perm = attrs.mode.map_or(0o755, |mode| (mode & 0o7777) as u16);
+                self.check_parent(vfs, &path)?;
+                vfs.create_dir(&path, perm)?;
+                vfs.set_attrs(&path, self.owner())?;
+                return Ok(status_ok(id));
+            }
+            FXP_RMDIR => {
+                let path = request.string()?;
+                self.check_parent(vfs, &path)?;
+                vfs.remove_dir(&path)?;
+                return Ok(status_ok(id));
+            }
+            FXP_RENAME => {
+                let from = request.string()?;
+                let to = request.string()?;
+                self.check_parent(vfs, &from)?;
+                self.check_parent(vfs, &to)?;
+                vfs.rename(&from, &to)?;
+                return Ok(status_ok(id));
+            }
+            FXP_REALPATH => {
+                let path = realpath(&request.string()?);
+                let metadata = self.check(vfs, &path, 0)?;
+                reply.push(FXP_NAME);
+                put_u32(&mut reply, id);
+                put_u32(&mut reply, 1);
+                put_name(&mut reply, &path, &metadata);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "unsupported request",
+                ))
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Metadata of the entry at `path`, failing unless the user may search
+    /// every directory leading to it and access it as `mask` asks.
+    fn check(&self, vfs: &mut Vfs, path: &str, mask: u16) -> io::Result<Metadata> {
+        let mut walked = String::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if !vfs.metadata(&walked)?.permits(self.user, 1) {
+                return Err(denied());
+            }
+            walked.push('/');
+            walked.push_str(name);
+        }
+        let metadata = vfs.metadata(&walked)?;
+        match metadata.permits(self.user, mask) {
+            true => Ok(metadata),
+            false => Err(denied()),
+        }
+    }
+
+    /// Fail unless the user may add and remove entries of the directory of
+    /// `path`.
+    fn check_parent(&self, vfs: &mut Vfs, path: &str) -> io::Result<()> {
+        let path = path.trim_end_matches('/');
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        self.check(vfs, parent, 3).map(drop)
+    }
+
+    /// Fail unless the user may make `changes` to the entry of `metadata`.
+    fn check_changes(
+        &self,
+        metadata: &Metadata,
+        changes: &SetAttrs,
+        writable: bool,
+    ) -> io::Result<()> {
+        match self
+            .user
+            .may_change(metadata.uid, metadata.gid, changes, writable)
+        {
+            true => Ok(()),
+            false => Err(denied()),
+        }
+    }
+
+    /// Changes making the user the owner of an entry they created.
+    fn owner(&self) -> SetAttrs {
+        SetAttrs {
+            uid: Some(self.user.uid),
+            gid: Some(self.user.gid),
+            ..SetAttrs::default()
+        }
+    }
+
+    /// Hold `open` under a new handle, replying with it.
+    fn open(&mut self, id: u32, open: Open) -> Vec<u8> {
+        let handle = self.next.to_be_bytes().to_vec();
+        self.next = self.next.wrapping_add(1);
+        let mut reply = vec![FXP_HANDLE];
+        put_u32(&mut reply, id);
+        put_bytes(&mut reply, &handle);
+        self.handles.insert(handle, open);
+        reply
+    }
+
+    fn close_all(&mut self) {
+        let mut vfs = self.vfs.lock();
+        for (_, open) in self.handles.drain() {
+            if let Open::File(open) = open {
+                let _ = vfs.close_file(open.file);
+            }
+        }
+    }
+}
+
+/// Reader of the fields of a packet, in network order.
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated packet"));
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()?;
+        Ok(self.take(len as usize)?.to_vec())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid utf-8 path"))
+    }
+
+    /// Decode attributes as the changes they ask for.
+    fn attrs(&mut self) -> io::Result<SetAttrs> {
+        let flags = self.u32()?;
+        let mut changes = SetAttrs::default();
+        if flags & ATTR_SIZE != 0 {
+            changes.size = Some(self.u64()?);
+        }
+        if flags & ATTR_UIDGID != 0 {
+            changes.uid = Some(self.u32()?);
+            changes.gid = Some(self.u32()?);
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            changes.mode = Some(self.u32()?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            let time = |secs: u32| UNIX_EPOCH + Duration::from_secs(secs as u64);
+            changes.atime = Some(time(self.u32()?));
+            changes.mtime = Some(time(self.u32()?));
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(changes)
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_attrs(buf: &mut Vec<u8>, metadata: &Metadata) {
+    put_u32(
+        buf,
+        ATTR_SIZE | ATTR_UIDGID | ATTR_PERMISSIONS | ATTR_ACMODTIME,
+    );
+    buf.extend_from_slice(&metadata.size.to_be_bytes());
+    put_u32(buf, metadata.uid);
+    put_u32(buf, metadata.gid);
+    put_u32(buf, mode(metadata));
+    put_u32(buf, unix_secs(metadata.atime));
+    put_u32(buf, unix_secs(metadata.mtime));
+}
+
+/// Encode an entry of a NAME reply, with the line `ls -l` would print.
+fn put_name(buf: &mut Vec<u8>, name: &str, metadata: &Metadata) {
+    let mut long = String::with_capacity(10);
+    long.push(match metadata.kind {
+        FileKind::Dir => 'd',
+        FileKind::File => '-',
+    });
+    for shift in [6, 3, 0].iter() {
+        let bits = metadata.perm >> shift;
+        long.push(if bits & 4 != 0 { 'r' } else { '-' });
+        long.push(if bits & 2 != 0 { 'w' } else { '-' });
+        long.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    let long = format!(
+        "{} 1 {} {} {} {}",
+        long, metadata.uid, metadata.gid, metadata.size, name
+    );
+    put_bytes(buf, name.as_bytes());
+    put_bytes(buf, long.as_bytes());
+    put_attrs(buf, metadata);
+}
+
+fn attrs_reply(id: u32, metadata: &Metadata) -> Vec<u8> {
+    let mut reply = vec![FXP_ATTRS];
+    put_u32(&mut reply, id);
+    put_attrs(&mut reply, metadata);
+    reply
+}
+
+fn status_ok(id: u32) -> Vec<u8> {
+    status_code(id, FX_OK, "ok")
+}
+
+fn status_code(id: u32, code: u32, message: &str) -> Vec<u8> {
+    let mut reply = vec![FXP_STATUS];
+    put_u32(&mut reply, id);
+    put_u32(&mut reply, code);
+    put_bytes(&mut reply, message.as_bytes());
+    put_bytes(&mut reply, b"en");
+    reply
+}
+
+/// Status reply of a request failed with `e`.
+fn status(id: u32, e: &io::Error) -> Vec<u8> {
+    let code = match (e.kind(), e.raw_os_error()) {
+        (ErrorKind::NotFound, _) => FX_NO_SUCH_FILE,
+        (_, Some(EACCES)) | (_, Some(EPERM)) | (_, Some(EROFS)) => FX_PERMISSION_DENIED,
+        (ErrorKind::InvalidData, _) => FX_BAD_MESSAGE,
+        (ErrorKind::Unsupported, _) => FX_OP_UNSUPPORTED,
+        _ => FX_FAILURE,
+    };
+    status_code(id, code, &e.to_string())
+}
+
+/// File opened under `handle`.
+fn file_of<'a>(handles: &'a HashMap<Vec<u8>, Open>, handle: &[u8]) -> io::Result<&'a OpenFile> {
+    match handles.get(handle) {
+        Some(Open::File(open)) => Ok(open),
+        _ => Err(bad_handle()),
+    }
+}
+
+fn bad_handle() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "no such handle")
+}
+
+fn denied() -> io::Error {
+    io::Error::from_raw_os_error(EACCES)
+}
+
+/// Mode bits of `metadata` with its file type, as stat(2) gives them.
+fn mode(metadata: &Metadata) -> u32 {
+    let format = match metadata.kind {
+        FileKind::Dir => S_IFDIR,
+        FileKind::File => S_IFREG,
+    };
+    format | metadata.perm as u32
+}
+
+fn unix_secs(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as u32)
+}
+
+/// Absolute form of `path`, without "." nor "..", the root being home.
+fn realpath(path: &str) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                names.pop();
+            }
+            name => names.push(name),
+        }
+    }
+    format!("/{}", names.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::{
+        put_bytes, put_u32, realpath, session, FXF_CREAT, FXF_READ, FXF_WRITE, FXP_CLOSE, FXP_DATA,
+        FXP_HANDLE, FXP_INIT, FXP_MKDIR, FXP_OPEN, FXP_READ, FXP_STATUS, FXP_VERSION, FXP_WRITE,
+        FX_NO_SUCH_FILE, FX_OK, FX_PERMISSION_DENIED,
+    };
+    use crate::fuse::EossFs;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::providers::memory::MemoryProvider;
+    use crate::vfs::{User, Vfs};
+
+    fn packet(input: &mut Vec<u8>, kind: u8, fields: &[&[u8]]) {
+        let payload: Vec<u8> = fields.concat();
+        put_u32(input, payload.len() as u32 + 1);
+        input.push(kind);
+        input.extend(payload);
+    }
+
+    fn string(s: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_bytes(&mut buf, s);
+        buf
+    }
+
+    /// Replies of a session of `user` sending `input`.
+    fn exchange(vfs: &Mutex<Vfs>, user: User, input: Vec<u8>) -> Vec<Vec<u8>> {
+        let mut output = Vec::new();
+        session(vfs, user, Cursor::new(input), &mut output).unwrap();
+        let mut replies = Vec::new();
+        let mut rest = &output[..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            replies.push(rest[4..4 + len].to_vec());
+            rest = &rest[4 + len..];
+        }
+        replies
+    }
+
+    #[test]
+    fn test_serve() {
+        let provider = Arc::new(MemoryProvider::new());
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let vfs = Mutex::new(Vfs::open(provider, MountOptions::default()).unwrap());
+        // handles are numbered from 0
+        let handle = string(&0u32.to_be_bytes());
+        let flags = (FXF_CREAT | FXF_READ | FXF_WRITE).to_be_bytes();
+        let mut input = Vec::new();
+        packet(&mut input, FXP_INIT, &[&3u32.to_be_bytes()]);
+        let open = [&1u32.to_be_bytes()[..], &string(b"/file"), &flags, &[0; 4]];
+        packet(&mut input, FXP_OPEN, &open);
+        let write = [&2u32.to_be_bytes()[..], &handle, &[0; 8], &string(b"hello")];
+        packet(&mut input, FXP_WRITE, &write);
+        let read = [
+            &3u32.to_be_bytes()[..],
+            &handle,
+            &1u64.to_be_bytes(),
+            &[0, 0, 0, 9],
+        ];
+        packet(&mut input, FXP_READ, &read);
+        packet(&mut input, FXP_CLOSE, &[&4u32.to_be_bytes(), &handle]);
+        let missing = [
+            &5u32.to_be_bytes()[..],
+            &string(b"missing"),
+            &[0; 4],
+            &[0; 4],
+        ];
+        packet(&mut input, FXP_OPEN, &missing);
+        let shared = [
+            &6u32.to_be_bytes()[..],
+            &string(b"/shared"),
+            &4u32.to_be_bytes(),
+            &0o777u32.to_be_bytes(),
+        ];
+        packet(&mut input, FXP_MKDIR, &shared);
+
+        let replies = exchange(&vfs, User { uid: 0, gid: 0 }, input);
+        let kinds: Vec<u8> = replies.iter().map(|reply| reply[0]).collect();
+        let expected = [
+            FXP_VERSION,
+            FXP_HANDLE,
+            FXP_STATUS,
+            FXP_DATA,
+            FXP_STATUS,
+            FXP_STATUS,
+            FXP_STATUS,
+        ];
+        assert_eq!(kinds, expected);
+        assert_eq!(replies[2][5..9], FX_OK.to_be_bytes());
+        assert_eq!(&replies[3][5..], &string(b"ello")[..]);
+        assert_eq!(replies[5][5..9], FX_NO_SUCH_FILE.to_be_bytes());
+        assert_eq!(replies[6][5..9], FX_OK.to_be_bytes());
+        assert_eq!(vfs.lock().metadata("/shared").unwrap().perm, 0o777);
+
+        // another user, only reading what root owns
+        let user = User {
+            uid: 1000,
+            gid: 1000,
+        };
+        let mut input = Vec::new();
+        let writing = FXF_WRITE.to_be_bytes();
+        let open = [
+            &1u32.to_be_bytes()[..],
+            &string(b"/file"),
+            &writing,
+            &[0; 4],
+        ];
+        packet(&mut input, FXP_OPEN, &open);
+        let reading = FXF_READ.to_be_bytes();
+        let open = [
+            &2u32.to_be_bytes()[..],
+            &string(b"/file"),
+            &reading,
+            &[0; 4],
+        ];
+        packet(&mut input, FXP_OPEN, &open);
+        let write = [&3u32.to_be_bytes()[..], &handle, &[0; 8], &string(b"hello")];
+        packet(&mut input, FXP_WRITE, &write);
+        let mkdir = [&4u32.to_be_bytes()[..], &string(b"/dir"), &[0; 4]];
+        packet(&mut input, FXP_MKDIR, &mkdir);
+        let open = [
+            &5u32.to_be_bytes()[..],
+            &string(b"/shared/mine"),
+            &flags,
+            &[0; 4],
+        ];
+        packet(&mut input, FXP_OPEN, &open);
+
+        let replies = exchange(&vfs, user, input);
+        let kinds: Vec<u8> = replies.iter().map(|reply| reply[0]).collect();
+        let expected = [FXP_STATUS, FXP_HANDLE, FXP_STATUS, FXP_STATUS, FXP_HANDLE];
+        assert_eq!(kinds, expected);
+        for denied in [&replies[0], &replies[2], &replies[3]].iter() {
+            assert_eq!(denied[5..9], FX_PERMISSION_DENIED.to_be_bytes());
+        }
+        let mine = vfs.lock().metadata("/shared/mine").unwrap();
+        assert_eq!((mine.uid, mine.gid), (1000, 1000));
+        assert_eq!(realpath("a/./b/../c/"), "/a/c");
+    }
+}
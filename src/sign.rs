@@ -0,0 +1,116 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand::{thread_rng, RngCore};
+
+use crate::id::Id;
+
+pub const SECRET_LENGTH: usize = 32;
+pub const PUBLIC_LENGTH: usize = 32;
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// Context deriving the id of the signature of a chunk from its id.
+const SIGNATURE_CONTEXT: &str = "eoss-fuse 2021-05 chunk signature";
+
+#[derive(thiserror::Error, Debug)]
+pub enum SignError {
+    #[error("invalid key")]
+    InvalidKey,
+    #[error("{0} is not signed")]
+    Unsigned(Id),
+    #[error("bad signature of {0}")]
+    BadSignature(Id),
+    #[error("the filesystem is published read-only, no signing key given")]
+    NoSigningKey,
+}
+
+/// SigningKey signs the chunks of a filesystem published, whose readers
+/// hold only its `VerifyingKey`.
+pub struct SigningKey(Keypair);
+
+impl SigningKey {
+    pub fn new_random() -> Self {
+        let mut secret = [0; SECRET_LENGTH];
+        thread_rng().fill_bytes(&mut secret);
+        Self::from_bytes(&secret).expect("key of the right length")
+    }
+
+    /// The key of `secret`, as written by `to_bytes`.
+    pub fn from_bytes(secret: &[u8]) -> Result<Self, SignError> {
+        let secret = SecretKey::from_bytes(secret).map_err(|_| SignError::InvalidKey)?;
+        let public = PublicKey::from(&secret);
+        Ok(Self(Keypair { secret, public }))
+    }
+
+    pub fn to_bytes(&self) -> [u8; SECRET_LENGTH] {
+        self.0.secret.to_bytes()
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.public.to_bytes())
+    }
+
+    /// Signature of `data` stored at `id`.
+    pub fn sign(&self, id: &Id, data: &[u8]) -> [u8; SIGNATURE_LENGTH] {
+        self.0.sign(&message(id, data)).to_bytes()
+    }
+}
+
+impl Clone for SigningKey {
+    fn clone(&self) -> Self {
+        Self::from_bytes(&self.to_bytes()).expect("key of the right length")
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SigningKey({})", self.verifying_key())
+    }
+}
+
+/// VerifyingKey checks chunks signed by a `SigningKey`, written in hex.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VerifyingKey(pub [u8; PUBLIC_LENGTH]);
+
+impl VerifyingKey {
+    /// Check `signature` of `data` stored at `id`.
+    pub fn verify(&self, id: &Id, data: &[u8], signature: &[u8]) -> Result<(), SignError> {
+        let public = PublicKey::from_bytes(&self.0).map_err(|_| SignError::InvalidKey)?;
+        let signature =
+            Signature::try_from(signature).map_err(|_| SignError::BadSignature(id.clone()))?;
+        public
+            .verify(&message(id, data), &signature)
+            .map_err(|_| SignError::BadSignature(id.clone()))
+    }
+}
+
+impl FromStr for VerifyingKey {
+    type Err = SignError;
+
+    fn from_str(s: &str) -> Result<Self, SignError> {
+        let mut key = [0; PUBLIC_LENGTH];
+        hex::decode_to_slice(s, &mut key).map_err(|_| SignError::InvalidKey)?;
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Display for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// Id of the signature of the chunk at `id`.
+pub fn signature_id(id: &Id) -> Id {
+    Id::new(blake3::derive_key(SIGNATURE_CONTEXT, &**id))
+}
+
+/// What is signed of `data` stored at `id`, so a signed chunk cannot be
+/// moved to another id.
+fn message(id: &Id, data: &[u8]) -> Vec<u8> {
+    let mut message = id.to_vec();
+    message.extend_from_slice(blake3::hash(data).as_bytes());
+    message
+}
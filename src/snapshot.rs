@@ -0,0 +1,427 @@
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use crate::allocator::TinyFileAllocator;
+use crate::chunk::{Chunk, CHUNK_SIZE};
+use crate::dedup;
+use crate::dirindex;
+use crate::fs::{DirMeta, EntryMut, Node, TinyFileError};
+use crate::id::{ChunkId, Data, Id, Meta, ID_LENGTH};
+use crate::meta::{self, Decode, Encode, MetaError, Reader};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SnapshotError {
+    #[error("invalid snapshot name")]
+    InvalidName,
+    #[error("snapshot {0} already exists")]
+    Exists(String),
+    #[error("snapshot {0} not found")]
+    NotFound(String),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+    #[error(transparent)]
+    ProviderError(#[from] ChunkProviderError),
+}
+
+/// A point-in-time copy of the directory tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: SystemTime,
+    /// Id of the MetaChunks holding the root directory of the copy
+    pub root_id: [u8; ID_LENGTH],
+}
+
+impl Encode for Snapshot {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.created.encode(buf);
+        self.root_id.encode(buf);
+    }
+}
+
+impl Decode for Snapshot {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            name: Decode::decode(reader)?,
+            created: Decode::decode(reader)?,
+            root_id: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// Data referenced by a set of directory trees.
+#[derive(Clone, Default)]
+struct References {
+    /// Ids of files in exclusive chunks
+    files: HashSet<Id>,
+    /// Blocks of tiny-file slots, by shared chunk and first block
+    slots: HashMap<(Id, u16), u16>,
+}
+
+impl References {
+    fn add(&mut self, dir: &DirMeta) {
+        self.files.extend(dir.files().map(|file| file.id.clone()));
+        for file in dir.tiny_files().filter(|file| file.chunk_blocks > 0) {
+            self.slots.insert(
+                (file.chunk_id.clone(), file.chunk_offset),
+                file.chunk_blocks,
+            );
+        }
+        for sub in dir.dirs() {
+            self.add(sub);
+        }
+    }
+
+    /// Add data referenced more than once in the tree at `dir`, given
+    /// what is already `seen`.
+    fn add_duplicates(&mut self, seen: &mut References, dir: &DirMeta) {
+        for file in dir.files() {
+            if !seen.files.insert(file.id.clone()) {
+                self.files.insert(file.id.clone());
+            }
+        }
+        for file in dir.tiny_files().filter(|file| file.chunk_blocks > 0) {
+            let slot = (file.chunk_id.clone(), file.chunk_offset);
+            if seen.slots.insert(slot.clone(), file.chunk_blocks).is_some() {
+                self.slots.insert(slot, file.chunk_blocks);
+            }
+        }
+        for sub in dir.dirs() {
+            self.add_duplicates(seen, sub);
+        }
+    }
+}
+
+/// Snapshots is the table of snapshots of a filesystem, stored at `id`.
+/// A snapshot copies the metadata of the tree only, files and tiny-file
+/// slots stay shared with the live tree until it modifies them, see `unshare`.
+/// The same goes for clones of directories within the live tree.
+pub struct Snapshots {
+    id: ChunkId<Meta>,
+    list: Vec<Snapshot>,
+    /// Data referenced by all snapshots
+    refs: References,
+    /// Data referenced by more than one entry of the live tree
+    clones: References,
+}
+
+impl Snapshots {
+    /// Write an empty table at `id`.
+    pub fn create(provider: &dyn ChunkProvider, id: &ChunkId<Meta>) -> Result<(), MetaError> {
+        meta::store(provider, id, &Vec::<Snapshot>::new())
+    }
+
+    /// Read the table stored at `id`, along with the trees of the snapshots.
+    pub fn open(provider: &dyn ChunkProvider, id: ChunkId<Meta>) -> Result<Self, MetaError> {
+        let list: Vec<Snapshot> = meta::load(provider, &id)?;
+        let refs = references(provider, &list)?;
+        Ok(Self {
+            id,
+            list,
+            refs,
+            clones: References::default(),
+        })
+    }
+
+    /// Track data shared by clones in the tree at `root`, which has to be
+    /// fully loaded.
+    pub fn track_clones(&mut self, root: &DirMeta) {
+        self.clones = References::default();
+        self.clones.add_duplicates(&mut References::default(), root);
+    }
+
+    /// Track data of `node` as shared with a copy of it just made in the
+    /// live tree.
+    pub fn track_clone(&mut self, node: &Node) {
+        match node {
+            Node::File(file) => {
+                self.clones.files.insert(file.id.clone());
+            }
+            Node::TinyFile(file) if file.chunk_blocks > 0 => {
+                let slot = (file.chunk_id.clone(), file.chunk_offset);
+                self.clones.slots.insert(slot, file.chunk_blocks);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn list(&self) -> &[Snapshot] {
+        &self.list
+    }
+
+    /// Mark slots of tiny files in snapshots used, so they are not handed
+    /// out to the live tree.
+    pub fn mark(&self, allocator: &mut TinyFileAllocator) {
+        for ((id, offset), blocks) in self.refs.slots.iter() {
+            allocator.mark(id, *offset, *blocks);
+        }
+    }
+
+    /// Whether the shared chunk `id` holds tiny files of snapshots.
+    pub fn references_chunk(&self, id: &Id) -> bool {
+        self.refs.slots.keys().any(|(chunk_id, _)| chunk_id == id)
+    }
+
+    /// Take a snapshot named `name` of the tree at `root`, which has to be
+    /// persisted and fully loaded.
+    pub fn take(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        name: &str,
+        root: &DirMeta,
+    ) -> Result<Snapshot, SnapshotError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(SnapshotError::InvalidName);
+        }
+        if self.list.iter().any(|snapshot| snapshot.name == name) {
+            return Err(SnapshotError::Exists(name.to_owned()));
+        }
+        let mut copy = root.clone();
+        renew_ids(&mut copy);
+        dirindex::store_dir(provider, &mut copy)?;
+        let snapshot = Snapshot {
+            name: name.to_owned(),
+            created: SystemTime::now(),
+            root_id: *copy.id,
+        };
+        self.list.push(snapshot.clone());
+        meta::store(provider, &self.id, &self.list)?;
+        provider.flush()?;
+        self.refs.add(&copy);
+        Ok(snapshot)
+    }
+
+    /// Delete the snapshot `name`, and the data referenced by nothing else,
+    /// neither other snapshots nor the tree at `root`, which has to be fully
+    /// loaded. Slots of tiny files are released to `allocator`.
+    /// Returns the hashes of chunks stored at their hash released.
+    pub fn delete(
+        &mut self,
+        provider: &dyn ChunkProvider,
+        name: &str,
+        root: &DirMeta,
+        allocator: &mut TinyFileAllocator,
+    ) -> Result<Vec<[u8; 32]>, SnapshotError> {
+        let pos = self
+            .list
+            .iter()
+            .position(|snapshot| snapshot.name == name)
+            .ok_or_else(|| SnapshotError::NotFound(name.to_owned()))?;
+        let snapshot = self.list.remove(pos);
+        // dropped from the table first, an interruption leaves orphan chunks only
+        meta::store(provider, &self.id, &self.list)?;
+        provider.flush()?;
+
+        self.refs = references(provider, &self.list)?;
+        let mut kept = self.refs.clone();
+        kept.add(root);
+        let tree = dirindex::load_dir(provider, &snapshot.root_id)?;
+        let mut released = Vec::new();
+        release(&tree, provider, allocator, &kept, &mut released)?;
+        for id in dirindex::chunk_ids(&tree) {
+            provider.delete_chunk(&id)?;
+        }
+        Ok(released)
+    }
+
+    /// Move the entry out of data shared with snapshots, before it is
+    /// modified in place.
+    pub fn unshare(
+        &self,
+        entry: EntryMut,
+        provider: &dyn ChunkProvider,
+        allocator: &mut TinyFileAllocator,
+    ) -> Result<(), TinyFileError> {
+        match entry {
+            EntryMut::File(file)
+                if self.refs.files.contains(&file.id) || self.clones.files.contains(&file.id) =>
+            {
+                let id = Id::new_random();
+                let mut buf = vec![0; CHUNK_SIZE];
+                for n in (0..file.chunk_count()).filter(|n| !file.shared(*n)) {
+                    provider
+                        .get_chunk_by_id(file.chunk_id(n).id())?
+                        .read_at(0, &mut buf);
+                    let chunk = Chunk::new(ChunkId::<Data>::derive_n(&id, n).into_id());
+                    chunk.write_at(0, &buf);
+                    provider.save_chunk(&chunk)?;
+                }
+                file.id = id;
+            }
+            EntryMut::TinyFile(file) if file.chunk_blocks > 0 => {
+                let (id, offset, blocks) =
+                    (file.chunk_id.clone(), file.chunk_offset, file.chunk_blocks);
+                let slot = (id.clone(), offset);
+                if self.refs.slots.contains_key(&slot) || self.clones.slots.contains_key(&slot) {
+                    file.relocate(provider, allocator, blocks)?;
+                    // the old slot still belongs to others
+                    allocator.mark(&id, offset, blocks);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Free data of the removed entry `node` not shared with snapshots or
+    /// clones. Returns the hashes of chunks stored at their hash released,
+    /// left to the dedup index.
+    pub fn release(
+        &self,
+        node: &Node,
+        provider: &dyn ChunkProvider,
+        allocator: &mut TinyFileAllocator,
+    ) -> Result<Vec<[u8; 32]>, ChunkProviderError> {
+        let mut released = Vec::new();
+        match node {
+            Node::Dir(dir) => {
+                for node in dir.entries.values() {
+                    released.extend(self.release(node, provider, allocator)?);
+                }
+            }
+            Node::File(file) => {
+                if !self.refs.files.contains(&file.id) && !self.clones.files.contains(&file.id) {
+                    for n in (0..file.chunk_count()).filter(|n| !file.shared(*n)) {
+                        provider.delete_chunk(file.chunk_id(n).id())?;
+                    }
+                    released.extend(dedup::held(file));
+                }
+            }
+            Node::TinyFile(file) if file.chunk_blocks > 0 => {
+                let slot = (file.chunk_id.clone(), file.chunk_offset);
+                if !self.refs.slots.contains_key(&slot) && !self.clones.slots.contains_key(&slot) {
+                    allocator.free(&slot.0, slot.1, file.chunk_blocks);
+                }
+            }
+            Node::TinyFile(_) => {}
+        }
+        Ok(released)
+    }
+
+    /// Ids of all chunks referenced by the table and the snapshots.
+    pub fn chunk_ids(&self, provider: &dyn ChunkProvider) -> Result<Vec<Id>, MetaError> {
+        let mut ids: Vec<Id> = meta::chunk_ids(&self.id, meta::encode(&self.list).len()).collect();
+        for snapshot in self.list.iter() {
+            let tree = dirindex::load_dir(provider, &snapshot.root_id)?;
+            ids.extend(dirindex::chunk_ids(&tree));
+            tree.visit_files(&mut |file| {
+                ids.extend(file.stored_ids());
+                Ok::<_, MetaError>(())
+            })?;
+        }
+        ids.extend(self.refs.slots.keys().map(|(id, _)| id.clone()));
+        Ok(ids)
+    }
+}
+
+fn references(provider: &dyn ChunkProvider, list: &[Snapshot]) -> Result<References, MetaError> {
+    let mut refs = References::default();
+    for snapshot in list.iter() {
+        refs.add(&dirindex::load_dir(provider, &snapshot.root_id)?);
+    }
+    Ok(refs)
+}
+
+/// Give the directories of a copied tree ids of their own.
+pub fn renew_ids(dir: &mut DirMeta) {
+    dir.id = Id::new_random();
+    dir.stored.clear();
+    for sub in dir.dirs_mut() {
+        renew_ids(sub);
+    }
+}
+
+/// Delete chunks of files and release slots of tiny files in the tree at
+/// `dir` not in `kept`, adding hashes of chunks stored at their hash to
+/// `released`.
+fn release(
+    dir: &DirMeta,
+    provider: &dyn ChunkProvider,
+    allocator: &mut TinyFileAllocator,
+    kept: &References,
+    released: &mut Vec<[u8; 32]>,
+) -> Result<(), ChunkProviderError> {
+    for file in dir.files().filter(|file| !kept.files.contains(&file.id)) {
+        for n in (0..file.chunk_count()).filter(|n| !file.shared(*n)) {
+            provider.delete_chunk(file.chunk_id(n).id())?;
+        }
+        released.extend(dedup::held(file));
+    }
+    for file in dir.tiny_files().filter(|file| file.chunk_blocks > 0) {
+        let slot = (file.chunk_id.clone(), file.chunk_offset);
+        if !kept.slots.contains_key(&slot) {
+            allocator.free(&slot.0, slot.1, file.chunk_blocks);
+        }
+    }
+    for sub in dir.dirs() {
+        release(sub, provider, allocator, kept, released)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Snapshots;
+    use crate::allocator::TinyFileAllocator;
+    use crate::dirindex;
+    use crate::fs::{Attrs, DirMeta, Entry, EntryMut, FileMeta, Node};
+    use crate::id::{ChunkId, Id};
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_snapshot_and_delete() {
+        let provider = MemoryProvider::new();
+        let mut allocator = TinyFileAllocator::new();
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut file = FileMeta {
+            name: "file".to_owned(),
+            id: Id::new_random(),
+            attrs: Attrs::new(0o644, 0, 0),
+            hashes: Vec::new(),
+            content: false,
+        };
+        file.write(&provider, 0, b"old").unwrap();
+        root.insert(Node::File(file));
+        dirindex::store_dir(&provider, &mut root).unwrap();
+
+        let id = ChunkId::new_random();
+        Snapshots::create(&provider, &id).unwrap();
+        let mut snapshots = Snapshots::open(&provider, id.clone()).unwrap();
+        snapshots.take(&provider, "s", &root).unwrap();
+        assert!(snapshots.take(&provider, "s", &root).is_err());
+
+        let old_chunk = match root.lookup("file") {
+            Some(Entry::File(file)) => file.chunk_id(0).into_id(),
+            _ => panic!("file not found"),
+        };
+        let entry = root.resolve_mut(&["file"]).unwrap();
+        snapshots.unshare(entry, &provider, &mut allocator).unwrap();
+        match root.lookup_mut("file") {
+            Some(EntryMut::File(file)) => file.write(&provider, 0, b"new").unwrap(),
+            _ => panic!("file not found"),
+        };
+
+        let mut buf = [0; 3];
+        let reopened = Snapshots::open(&provider, id).unwrap();
+        let tree = dirindex::load_dir(&provider, &reopened.list()[0].root_id).unwrap();
+        match tree.lookup("file") {
+            Some(Entry::File(file)) => file.read(&provider, 0, &mut buf).unwrap(),
+            _ => panic!("file not in snapshot"),
+        };
+        assert_eq!(&buf, b"old");
+
+        snapshots
+            .delete(&provider, "s", &root, &mut allocator)
+            .unwrap();
+        assert!(snapshots.list().is_empty());
+        assert!(!provider.contains_chunk(&old_chunk).unwrap());
+        match root.lookup("file") {
+            Some(Entry::File(file)) => file.read(&provider, 0, &mut buf).unwrap(),
+            _ => panic!("file not found"),
+        };
+        assert_eq!(&buf, b"new");
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::UNIX_EPOCH;
+
+#[cfg(unix)]
+use fuser::{FileAttr, FileType};
+
+use crate::chunk::BLOCK_SIZE;
+#[cfg(windows)]
+use crate::win::{FileAttr, FileType};
+
+/// Directory in the root of a mount holding the runtime stats, synthesized
+/// rather than stored.
+pub const STATS_DIR: &str = ".eoss";
+/// Name of the stats file in `STATS_DIR`.
+pub const STATS_FILE: &str = "stats.json";
+/// Inode of `STATS_DIR`, out of the range assigned to paths.
+pub const STATS_DIR_INO: u64 = u64::MAX - 1;
+/// Inode of the stats file.
+pub const STATS_INO: u64 = u64::MAX - 2;
+
+/// State of a mounted filesystem, read from the stats file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RuntimeStats {
+    /// Bytes of chunks in the chunk cache
+    pub cache_bytes: usize,
+    /// Bytes of dirty chunks in the chunk cache, not written back yet
+    pub dirty_bytes: usize,
+    /// Milliseconds the oldest dirty chunk has been waiting to be written back
+    pub oldest_dirty_ms: u64,
+    /// Calls to the provider failed since mounted
+    pub provider_errors: u64,
+    /// Files opened, not released yet
+    pub open_handles: usize,
+    /// Inodes held by the kernel
+    pub inodes: usize,
+    /// Journal records not checkpointed yet
+    pub journal_records: u64,
+}
+
+impl RuntimeStats {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"cache_bytes\":{},\"dirty_bytes\":{},\"oldest_dirty_ms\":{},\
+             \"provider_errors\":{},\"open_handles\":{},\"inodes\":{},\
+             \"journal_records\":{}}}\n",
+            self.cache_bytes,
+            self.dirty_bytes,
+            self.oldest_dirty_ms,
+            self.provider_errors,
+            self.open_handles,
+            self.inodes,
+            self.journal_records,
+        )
+    }
+}
+
+/// Attributes of the virtual entry `ino`, the stats file being `size` bytes.
+pub fn attr(ino: u64, size: u64) -> FileAttr {
+    let (kind, perm, nlink) = match ino {
+        STATS_DIR_INO => (FileType::Directory, 0o555, 2),
+        _ => (FileType::RegularFile, 0o444, 1),
+    };
+    FileAttr {
+        ino,
+        size,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind,
+        perm,
+        nlink,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: BLOCK_SIZE as u32,
+        flags: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RuntimeStats;
+
+    #[test]
+    fn test_stats_json() {
+        let stats = RuntimeStats {
+            cache_bytes: 4,
+            provider_errors: 2,
+            ..RuntimeStats::default()
+        };
+        assert_eq!(
+            stats.to_json(),
+            "{\"cache_bytes\":4,\"dirty_bytes\":0,\"oldest_dirty_ms\":0,\
+             \"provider_errors\":2,\"open_handles\":0,\"inodes\":0,\
+             \"journal_records\":0}\n"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use std::future::Future;
+use std::io::{self, Write};
+use std::mem;
+use std::pin::Pin;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle, Thread};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::chunk::{Chunk, BLOCK_SIZE, CHUNK_SIZE};
+use crate::id::Id;
+use crate::provider::{ChunkProvider, ChunkProviderError};
+
+/// Blocks of a `ChunkStream` queued to be saved at most, beyond which
+/// writes wait for the provider.
+const BACKLOG: usize = 64;
+
+/// Wakes the thread blocked on a future.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+fn current_waker() -> Waker {
+    Waker::from(Arc::new(Unpark(thread::current())))
+}
+
+/// Run `future` to completion on the current thread, parked while pending,
+/// for futures of providers driven outside of the runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = current_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Future of `read`.
+pub struct ReadOnce<'a, R> {
+    reader: &'a mut R,
+    buf: &'a mut [u8],
+}
+
+impl<R: AsyncRead + Unpin> Future for ReadOnce<'_, R> {
+    type Output = io::Result<usize>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let mut buf = ReadBuf::new(this.buf);
+        match Pin::new(&mut *this.reader).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Read from `reader` into `buf` once some data is available.
+/// Returns the number of bytes read, 0 at the end.
+pub fn read<'a, R: AsyncRead + Unpin>(reader: &'a mut R, buf: &'a mut [u8]) -> ReadOnce<'a, R> {
+    ReadOnce { reader, buf }
+}
+
+/// Fill `chunk` block by block by `fill`, given a block and returning the
+/// bytes filled in, until it fills less than a block, while `provider`
+/// saves it as streamed on a thread of its own, so each block is saved as
+/// soon as filled and filling never runs more than a chunk ahead of the
+/// save. Blocks never filled are left zero. Nothing is saved if nothing is
+/// filled, and the chunk partly saved is deleted if filling fails.
+/// Returns the number of bytes filled.
+pub fn save_filled<F, E>(
+    provider: &dyn ChunkProvider,
+    chunk: &Chunk,
+    mut fill: F,
+) -> Result<usize, E>
+where
+    F: FnMut(&mut [u8]) -> Result<usize, E>,
+    E: From<ChunkProviderError>,
+{
+    let mut block = vec![0; BLOCK_SIZE];
+    let first = fill(&mut block)?;
+    if first == 0 {
+        return Ok(0);
+    }
+    thread::scope(|scope| {
+        let mut writer = chunk.writer();
+        let save = scope.spawn(|| block_on(provider.save_chunk_streaming(chunk)));
+        let mut n = first;
+        let mut len = 0;
+        let filled = loop {
+            if let Err(e) = writer.write_all(&block[..n]) {
+                break Err(E::from(e.into()));
+            }
+            len += n;
+            if n < BLOCK_SIZE || len == CHUNK_SIZE {
+                break Ok(len);
+            }
+            n = match fill(&mut block) {
+                Ok(n) => n,
+                Err(e) => break Err(e),
+            };
+        };
+        drop(writer);
+        let saved = save.join().expect("saving thread panicked");
+        match filled {
+            Ok(len) => {
+                saved?;
+                Ok(len)
+            }
+            Err(e) => {
+                let _ = provider.delete_chunk(chunk.id());
+                Err(e)
+            }
+        }
+    })
+}
+
+/// ChunkStream saves a chunk written in order from its start as written,
+/// by `save_filled` on a thread of its own, so writes never hold the whole
+/// chunk and wait once `BACKLOG` blocks are queued ahead of the provider.
+pub struct ChunkStream {
+    /// Bytes written so far
+    len: usize,
+    /// Bytes written short of a block
+    pending: Vec<u8>,
+    blocks: Option<SyncSender<Vec<u8>>>,
+    saver: Option<JoinHandle<Result<usize, ChunkProviderError>>>,
+}
+
+impl ChunkStream {
+    /// Start saving chunk `id` to `provider` as written.
+    pub fn start(provider: Arc<dyn ChunkProvider>, id: Id) -> Self {
+        let (blocks, queued) = mpsc::sync_channel::<Vec<u8>>(BACKLOG);
+        let saver = thread::spawn(move || {
+            let chunk = Chunk::new(id);
+            save_filled(provider.as_ref(), &chunk, |block| match queued.recv() {
+                Ok(data) => {
+                    block[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                // finished
+                Err(_) => Ok(0),
+            })
+        });
+        Self {
+            len: 0,
+            pending: Vec::with_capacity(BLOCK_SIZE),
+            blocks: Some(blocks),
+            saver: Some(saver),
+        }
+    }
+
+    /// Bytes written so far, the offset written next.
+    pub fn written(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the chunk is written up to its end.
+    pub fn is_full(&self) -> bool {
+        self.len == CHUNK_SIZE
+    }
+
+    /// Append `data`, up to the end of the chunk.
+    /// Returns the number of bytes written.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, ChunkProviderError> {
+        let n = data.len().min(CHUNK_SIZE - self.len);
+        let mut rest = &data[..n];
+        while !rest.is_empty() {
+            let take = rest.len().min(BLOCK_SIZE - self.pending.len());
+            self.pending.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.pending.len() == BLOCK_SIZE {
+                let block = mem::replace(&mut self.pending, Vec::with_capacity(BLOCK_SIZE));
+                self.send(block)?;
+            }
+        }
+        self.len += n;
+        Ok(n)
+    }
+
+    fn send(&mut self, block: Vec<u8>) -> Result<(), ChunkProviderError> {
+        let sent = match &self.blocks {
+            Some(blocks) => blocks.send(block).is_ok(),
+            None => false,
+        };
+        match sent {
+            true => Ok(()),
+            // the saver gave up, telling why once joined
+            false => Err(match self.finish() {
+                Err(e) => e,
+                Ok(()) => io::Error::from(io::ErrorKind::BrokenPipe).into(),
+            }),
+        }
+    }
+
+    /// Save what is written so far, the rest of the chunk left zero, and
+    /// wait until saved.
+    pub fn finish(&mut self) -> Result<(), ChunkProviderError> {
+        if let Some(blocks) = self.blocks.take() {
+            if !self.pending.is_empty() {
+                let _ = blocks.send(mem::take(&mut self.pending));
+            }
+        }
+        match self.saver.take() {
+            Some(saver) => saver.join().expect("saving thread panicked").map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ChunkStream {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    use super::{block_on, read, save_filled};
+    use crate::chunk::{Chunk, BLOCK_SIZE, CHUNK_SIZE};
+    use crate::id::Id;
+    use crate::provider::{ChunkProvider, ChunkProviderError};
+    use crate::providers::local::LocalProvider;
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_stream() {
+        // a reader waits on blocks held by the writer, ends once all read
+        let chunk = Chunk::new(Id::new_random());
+        let mut writer = chunk.writer();
+        writer.write_all(&[7; BLOCK_SIZE + 1]).unwrap();
+        let mut reader = chunk.read();
+        let mut buf = vec![0; CHUNK_SIZE];
+        assert_eq!(block_on(read(&mut reader, &mut buf)).unwrap(), BLOCK_SIZE);
+        drop(writer);
+        let mut len = BLOCK_SIZE;
+        loop {
+            match block_on(read(&mut reader, &mut buf[len..])).unwrap() {
+                0 => break,
+                n => len += n,
+            }
+        }
+        assert_eq!(len, CHUNK_SIZE);
+        assert_eq!(buf[BLOCK_SIZE], 7);
+        assert_eq!(buf[BLOCK_SIZE + 1], 0);
+
+        let base = env::temp_dir().join(format!("eoss-stream-{}", Id::new_random().hex()));
+        let local = LocalProvider::new(&base).unwrap();
+        let memory = MemoryProvider::new();
+        let providers: [&dyn ChunkProvider; 2] = [&local, &memory];
+        for provider in providers.iter() {
+            let id = Id::new_random();
+            let chunk = Chunk::new(id.clone());
+            let mut data = (0..2 * BLOCK_SIZE + 10).map(|i| i as u8);
+            let filled = save_filled(*provider, &chunk, |block| {
+                let n = block.iter_mut().zip(&mut data).map(|(b, d)| *b = d).count();
+                Ok::<_, ChunkProviderError>(n)
+            });
+            assert_eq!(filled.unwrap(), 2 * BLOCK_SIZE + 10);
+            let mut stored = vec![0; CHUNK_SIZE];
+            provider
+                .get_chunk_by_id(&id)
+                .unwrap()
+                .read_at(0, &mut stored);
+            assert_eq!(stored[2 * BLOCK_SIZE + 9], (2 * BLOCK_SIZE + 9) as u8);
+            assert_eq!(stored[2 * BLOCK_SIZE + 10], 0);
+
+            // nothing filled, nothing saved
+            let id = Id::new_random();
+            let filled = save_filled(*provider, &Chunk::new(id.clone()), |_| {
+                Ok::<_, ChunkProviderError>(0)
+            });
+            assert_eq!(filled.unwrap(), 0);
+            assert!(!provider.contains_chunk(&id).unwrap());
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use rand::{thread_rng, RngCore};
+
+use crate::chunk::{BLOCK_PER_CHUNK, BLOCK_SIZE};
+use crate::crypt::{Epoch, MasterKey, Mode, KEY_LENGTH};
+use crate::diskcache::DiskCacheError;
+use crate::id::{ChunkId, Id, Meta, ID_LENGTH};
+use crate::keys::{KeyError, KeySlot};
+use crate::lease::LeaseError;
+use crate::meta::{self, Decode, Encode, MetaError, Reader};
+use crate::provider::ChunkProvider;
+use crate::sign::VerifyingKey;
+use crate::snapshot::SnapshotError;
+
+/// Well-known id of the superblock chunk.
+pub const SUPERBLOCK_ID: [u8; ID_LENGTH] = [0; ID_LENGTH];
+/// Version of the on-disk format.
+pub const FORMAT_VERSION: u16 = 1;
+/// Chunks other than the superblock are sealed with a master key.
+pub const FEATURE_ENCRYPTED: u64 = 1;
+/// Chunks are sealed under keys derived from their content, see
+/// `Mode::Convergent`.
+pub const FEATURE_CONVERGENT: u64 = 2;
+/// Chunks are signed, see `SignedProvider`.
+pub const FEATURE_SIGNED: u64 = 4;
+/// Chunks of new files are stored at the hash of their contents, see
+/// `FileMeta::content`.
+pub const FEATURE_CONTENT_ADDRESSED: u64 = 8;
+/// Chunks of files are compressed as their compression says, see
+/// `CompressedProvider`.
+pub const FEATURE_COMPRESSED: u64 = 16;
+/// Features understood by this implementation.
+pub const SUPPORTED_FEATURES: u64 = FEATURE_ENCRYPTED
+    | FEATURE_CONVERGENT
+    | FEATURE_SIGNED
+    | FEATURE_CONTENT_ADDRESSED
+    | FEATURE_COMPRESSED;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SuperblockError {
+    #[error("no filesystem found")]
+    NotFormatted,
+    #[error("a filesystem already exists")]
+    AlreadyFormatted,
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u16),
+    #[error("geometry mismatch: {0} blocks of {1} bytes per chunk")]
+    GeometryMismatch(u32, u32),
+    #[error("unsupported features {0:#x}")]
+    UnsupportedFeatures(u64),
+    #[error("the filesystem is encrypted, a key is required")]
+    KeyRequired,
+    #[error("wrong key")]
+    WrongKey,
+    #[error("the filesystem is not encrypted")]
+    NotEncrypted,
+    #[error("the filesystem is signed, a verifying key is required")]
+    VerifyingKeyRequired,
+    #[error("the filesystem is signed by another key")]
+    WrongSigner,
+    #[error("the filesystem is not signed")]
+    NotSigned,
+    #[error("the filesystem was not cleanly unmounted by its publisher")]
+    Unclean,
+    #[error("content addressed chunks would reveal hashes of encrypted contents")]
+    ContentAddressedEncrypted,
+    #[error("the superblock was rolled back from sequence {0} to {1}")]
+    RolledBack(u64, u64),
+    #[error("the superblock was signed {0:?} ago, longer than accepted")]
+    Stale(Duration),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+    #[error(transparent)]
+    SnapshotError(#[from] SnapshotError),
+    #[error(transparent)]
+    DiskCacheError(#[from] DiskCacheError),
+    #[error(transparent)]
+    LeaseError(#[from] LeaseError),
+    #[error(transparent)]
+    KeyError(#[from] KeyError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// Superblock is the entry point into the metadata of a filesystem,
+/// written at format time and validated at mount.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Superblock {
+    pub uuid: [u8; 16],
+    pub version: u16,
+    /// Id of the MetaChunks holding the root directory
+    pub root_id: [u8; ID_LENGTH],
+    /// Id of the metadata journal
+    pub journal_id: [u8; ID_LENGTH],
+    /// Id of the table of snapshots
+    pub snapshots_id: [u8; ID_LENGTH],
+    pub block_size: u32,
+    pub block_per_chunk: u32,
+    pub features: u64,
+    /// Set while mounted, a dirty superblock at mount means the journal
+    /// has to be replayed
+    pub dirty: bool,
+    /// Check value of the master key, with `FEATURE_ENCRYPTED`
+    pub key_check: Option<[u8; KEY_LENGTH]>,
+    /// Master key wrapped by each secret unlocking it, with
+    /// `FEATURE_ENCRYPTED`
+    pub key_slots: Vec<KeySlot>,
+    /// Epoch of the master key, incremented by each rotation, with
+    /// `FEATURE_ENCRYPTED`
+    pub key_epoch: u32,
+    /// Keys retired by rotations wrapped under the master key, until every
+    /// chunk is resealed, with `FEATURE_ENCRYPTED`
+    pub key_epochs: Vec<Epoch>,
+    /// Key verifying chunks, with `FEATURE_SIGNED`
+    pub signer: Option<VerifyingKey>,
+    /// Times stored by the signer, so an older superblock replayed is told
+    /// from the latest, with `FEATURE_SIGNED`
+    pub sequence: u64,
+    /// When last stored by the signer, with `FEATURE_SIGNED`
+    pub signed_at: SystemTime,
+}
+
+impl Superblock {
+    /// A superblock of a new filesystem with a random uuid.
+    pub fn new(root_id: &Id, journal_id: &Id, snapshots_id: &Id) -> Self {
+        let mut uuid = [0; 16];
+        thread_rng().fill_bytes(&mut uuid);
+        Self {
+            uuid,
+            version: FORMAT_VERSION,
+            root_id: **root_id,
+            journal_id: **journal_id,
+            snapshots_id: **snapshots_id,
+            block_size: BLOCK_SIZE as u32,
+            block_per_chunk: BLOCK_PER_CHUNK as u32,
+            features: 0,
+            dirty: false,
+            key_check: None,
+            key_slots: Vec::new(),
+            key_epoch: 0,
+            key_epochs: Vec::new(),
+            signer: None,
+            sequence: 0,
+            signed_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Mark the filesystem encrypted with `key`.
+    pub fn set_key(&mut self, key: &MasterKey) {
+        self.features |= FEATURE_ENCRYPTED;
+        self.key_check = Some(key.check_value());
+    }
+
+    /// Mark the filesystem signed by the holder of `signer`.
+    pub fn set_signer(&mut self, signer: VerifyingKey) {
+        self.features |= FEATURE_SIGNED;
+        self.signer = Some(signer);
+    }
+
+    /// Whether chunks of new files are content addressed, as they are when
+    /// sealed convergently for equal chunks to be stored once.
+    pub fn content_addressed(&self) -> bool {
+        self.features & (FEATURE_CONTENT_ADDRESSED | FEATURE_CONVERGENT) != 0
+    }
+
+    /// Whether chunks of files are compressed.
+    pub fn compressed(&self) -> bool {
+        self.features & FEATURE_COMPRESSED != 0
+    }
+
+    /// The mode chunks are sealed in.
+    pub fn seal_mode(&self) -> Mode {
+        if self.features & FEATURE_CONVERGENT != 0 {
+            Mode::Convergent
+        } else {
+            Mode::Random
+        }
+    }
+
+    /// Check `key` against the one the filesystem is encrypted with, if any.
+    pub fn check_key(&self, key: Option<&MasterKey>) -> Result<(), SuperblockError> {
+        match (self.key_check, key) {
+            (None, None) => Ok(()),
+            (None, Some(_)) => Err(SuperblockError::NotEncrypted),
+            (Some(_), None) => Err(SuperblockError::KeyRequired),
+            (Some(check), Some(key)) if check == key.check_value() => Ok(()),
+            (Some(_), Some(_)) => Err(SuperblockError::WrongKey),
+        }
+    }
+
+    /// Check `signer` against the key the filesystem is signed with, if any.
+    /// Only a hint read before chunks are verified, a forged superblock is
+    /// refused once read again through a `SignedProvider`.
+    pub fn check_signer(&self, signer: Option<&VerifyingKey>) -> Result<(), SuperblockError> {
+        match (self.signer, signer) {
+            (None, None) => Ok(()),
+            (None, Some(_)) => Err(SuperblockError::NotSigned),
+            (Some(_), None) => Err(SuperblockError::VerifyingKeyRequired),
+            (Some(stored), Some(signer)) if stored == *signer => Ok(()),
+            (Some(_), Some(_)) => Err(SuperblockError::WrongSigner),
+        }
+    }
+
+    /// Check a signed superblock is not older than the one of sequence
+    /// `seen`, nor signed longer than `max_age` ago, so one replayed by the
+    /// provider is refused.
+    pub fn check_fresh(&self, seen: u64, max_age: Option<Duration>) -> Result<(), SuperblockError> {
+        if self.signer.is_none() {
+            return Ok(());
+        }
+        if self.sequence < seen {
+            return Err(SuperblockError::RolledBack(seen, self.sequence));
+        }
+        let age = self.signed_at.elapsed().unwrap_or_default();
+        match max_age {
+            Some(max_age) if age > max_age => Err(SuperblockError::Stale(age)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that this implementation can mount the filesystem.
+    pub fn validate(&self) -> Result<(), SuperblockError> {
+        if self.version != FORMAT_VERSION {
+            return Err(SuperblockError::UnsupportedVersion(self.version));
+        }
+        if self.block_size != BLOCK_SIZE as u32 || self.block_per_chunk != BLOCK_PER_CHUNK as u32 {
+            return Err(SuperblockError::GeometryMismatch(
+                self.block_per_chunk,
+                self.block_size,
+            ));
+        }
+        if self.features & !SUPPORTED_FEATURES != 0 {
+            return Err(SuperblockError::UnsupportedFeatures(
+                self.features & !SUPPORTED_FEATURES,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read and validate the superblock stored at `id`.
+    pub fn load(provider: &dyn ChunkProvider, id: &Id) -> Result<Self, SuperblockError> {
+        let superblock: Self = match meta::load(provider, &ChunkId::root(id.clone())) {
+            Err(MetaError::BadMagic) => return Err(SuperblockError::NotFormatted),
+            result => result?,
+        };
+        superblock.validate()?;
+        Ok(superblock)
+    }
+
+    /// Write the superblock at `id`, as the next of its sequence if signed.
+    pub fn store(&mut self, provider: &dyn ChunkProvider, id: &Id) -> Result<(), SuperblockError> {
+        if self.signer.is_some() {
+            self.sequence += 1;
+            self.signed_at = SystemTime::now();
+        }
+        meta::store(provider, &ChunkId::root(id.clone()), self)?;
+        Ok(())
+    }
+
+    pub fn root_id(&self) -> Id {
+        Id::new(self.root_id)
+    }
+
+    pub fn journal_id(&self) -> Id {
+        Id::new(self.journal_id)
+    }
+
+    pub fn snapshots_id(&self) -> ChunkId<Meta> {
+        ChunkId::root(Id::new(self.snapshots_id))
+    }
+}
+
+impl Encode for Superblock {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.uuid);
+        self.version.encode(buf);
+        self.root_id.encode(buf);
+        self.journal_id.encode(buf);
+        self.snapshots_id.encode(buf);
+        self.block_size.encode(buf);
+        self.block_per_chunk.encode(buf);
+        self.features.encode(buf);
+        self.dirty.encode(buf);
+        if let Some(check) = &self.key_check {
+            check.encode(buf);
+            self.key_slots.encode(buf);
+            self.key_epoch.encode(buf);
+            self.key_epochs.encode(buf);
+        }
+        if let Some(signer) = &self.signer {
+            signer.0.encode(buf);
+            self.sequence.encode(buf);
+            self.signed_at.encode(buf);
+        }
+    }
+}
+
+impl Decode for Superblock {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(reader.take(16)?);
+        let mut superblock = Self {
+            uuid,
+            version: Decode::decode(reader)?,
+            root_id: Decode::decode(reader)?,
+            journal_id: Decode::decode(reader)?,
+            snapshots_id: Decode::decode(reader)?,
+            block_size: Decode::decode(reader)?,
+            block_per_chunk: Decode::decode(reader)?,
+            features: Decode::decode(reader)?,
+            dirty: Decode::decode(reader)?,
+            key_check: None,
+            key_slots: Vec::new(),
+            key_epoch: 0,
+            key_epochs: Vec::new(),
+            signer: None,
+            sequence: 0,
+            signed_at: SystemTime::UNIX_EPOCH,
+        };
+        if superblock.features & FEATURE_ENCRYPTED != 0 {
+            superblock.key_check = Some(Decode::decode(reader)?);
+            superblock.key_slots = Decode::decode(reader)?;
+            superblock.key_epoch = Decode::decode(reader)?;
+            superblock.key_epochs = Decode::decode(reader)?;
+        }
+        if superblock.features & FEATURE_SIGNED != 0 {
+            superblock.signer = Some(VerifyingKey(Decode::decode(reader)?));
+            superblock.sequence = Decode::decode(reader)?;
+            superblock.signed_at = Decode::decode(reader)?;
+        }
+        Ok(superblock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Superblock, SuperblockError, SUPERBLOCK_ID};
+    use crate::crypt::MasterKey;
+    use crate::id::Id;
+    use crate::providers::memory::MemoryProvider;
+    use crate::sign::SigningKey;
+    use std::time::Duration;
+
+    #[test]
+    fn test_store_and_validate() {
+        let provider = MemoryProvider::new();
+        let id = Id::new(SUPERBLOCK_ID);
+        assert!(matches!(
+            Superblock::load(&provider, &id),
+            Err(SuperblockError::NotFormatted)
+        ));
+
+        let mut superblock =
+            Superblock::new(&Id::new_random(), &Id::new_random(), &Id::new_random());
+        superblock.store(&provider, &id).unwrap();
+        assert_eq!(Superblock::load(&provider, &id).unwrap(), superblock);
+
+        let key = MasterKey::new_random();
+        superblock.set_key(&key);
+        superblock.store(&provider, &id).unwrap();
+        let loaded = Superblock::load(&provider, &id).unwrap();
+        assert_eq!(loaded, superblock);
+        assert!(loaded.check_key(Some(&key)).is_ok());
+        assert!(matches!(
+            loaded.check_key(Some(&MasterKey::new_random())),
+            Err(SuperblockError::WrongKey)
+        ));
+        assert!(matches!(
+            loaded.check_key(None),
+            Err(SuperblockError::KeyRequired)
+        ));
+
+        // signed, replayed once stored again
+        superblock.set_signer(SigningKey::new_random().verifying_key());
+        superblock.store(&provider, &id).unwrap();
+        let replayed = Superblock::load(&provider, &id).unwrap();
+        superblock.store(&provider, &id).unwrap();
+        let latest = Superblock::load(&provider, &id).unwrap();
+        latest.check_fresh(replayed.sequence, None).unwrap();
+        assert!(matches!(
+            replayed.check_fresh(latest.sequence, None),
+            Err(SuperblockError::RolledBack(2, 1))
+        ));
+        assert!(matches!(
+            latest.check_fresh(0, Some(Duration::ZERO)),
+            Err(SuperblockError::Stale(_))
+        ));
+        latest
+            .check_fresh(0, Some(Duration::from_secs(60)))
+            .unwrap();
+
+        superblock.features = 32;
+        superblock.store(&provider, &id).unwrap();
+        assert!(matches!(
+            Superblock::load(&provider, &id),
+            Err(SuperblockError::UnsupportedFeatures(32))
+        ));
+    }
+}
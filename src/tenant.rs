@@ -0,0 +1,235 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::fuse::EossFs;
+use crate::id::{ChunkId, Id, Meta};
+use crate::meta::{self, Decode, Encode, MetaError, Reader};
+use crate::options::{FormatOptions, MountOptions};
+use crate::provider::{ChunkProvider, ChunkProviderError};
+use crate::providers::signed::SignedProvider;
+use crate::sign::{SignError, SigningKey};
+use crate::superblock::{Superblock, SuperblockError, SUPERBLOCK_ID};
+
+#[derive(thiserror::Error, Debug)]
+pub enum TenantError {
+    #[error("invalid tenant name")]
+    InvalidName,
+    #[error("tenant {0} already exists")]
+    Exists(String),
+    #[error("tenant {0} not found")]
+    NotFound(String),
+    #[error("content addressed chunks could be shared with other tenants")]
+    ContentAddressed,
+    #[error(transparent)]
+    SuperblockError(#[from] SuperblockError),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+}
+
+/// A filesystem sharing the provider of others, with its own root,
+/// superblock and so keys, mounted by name.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tenant {
+    pub name: String,
+    pub created: SystemTime,
+}
+
+impl Encode for Tenant {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.created.encode(buf);
+    }
+}
+
+impl Decode for Tenant {
+    fn decode(reader: &mut Reader) -> Result<Self, MetaError> {
+        Ok(Self {
+            name: Decode::decode(reader)?,
+            created: Decode::decode(reader)?,
+        })
+    }
+}
+
+/// Id of the superblock of tenant `name`.
+pub fn superblock_id(name: &str) -> Id {
+    let label = format!("tenant/{}", name);
+    Id::new(Id::new(SUPERBLOCK_ID).derive_labeled(label.as_bytes()))
+}
+
+/// Whether `provider` hosts tenants, whose chunks cannot be told from
+/// garbage, or the filesystem of `superblock_id` is one.
+pub fn shares(provider: &dyn ChunkProvider, superblock_id: &Id) -> Result<bool, MetaError> {
+    Ok(*superblock_id != Id::new(SUPERBLOCK_ID) || !list(provider)?.is_empty())
+}
+
+/// Id of the table of tenants, stored in the clear next to the superblock.
+pub fn table_id() -> ChunkId<Meta> {
+    ChunkId::derive_labeled(&Id::new(SUPERBLOCK_ID), b"tenants")
+}
+
+/// Tenants hosted by `provider`, which has to be beneath any layer sealing
+/// or signing chunks.
+pub fn list(provider: &dyn ChunkProvider) -> Result<Vec<Tenant>, MetaError> {
+    match meta::load(provider, &table_id()) {
+        Err(MetaError::BadMagic) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Store `tenants` as the table, signed with `signing` if the filesystem
+/// hosting them is, so its signer alone edits them.
+fn store_table(
+    provider: &dyn ChunkProvider,
+    tenants: Vec<Tenant>,
+    signing: Option<&SigningKey>,
+) -> Result<(), TenantError> {
+    let signed = match Superblock::load(provider, &Id::new(SUPERBLOCK_ID)) {
+        Ok(host) if host.signer.is_some() => {
+            let signing = signing.ok_or_else(|| {
+                MetaError::from(ChunkProviderError::from(SignError::NoSigningKey))
+            })?;
+            host.check_signer(Some(&signing.verifying_key()))?;
+            Some(SignedProvider::with_signing_key(provider, signing.clone()))
+        }
+        Ok(_) | Err(SuperblockError::NotFormatted) => None,
+        Err(e) => return Err(e.into()),
+    };
+    match &signed {
+        Some(signed) => meta::store(signed, &table_id(), &tenants)?,
+        None => meta::store(provider, &table_id(), &tenants)?,
+    }
+    Ok(())
+}
+
+/// Format the filesystem of a new tenant `name` on `provider` with
+/// `options`, its key and the quota of its root among them.
+pub fn create(
+    provider: &dyn ChunkProvider,
+    name: &str,
+    options: &FormatOptions,
+) -> Result<Superblock, TenantError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(TenantError::InvalidName);
+    }
+    if options.content_addressed {
+        return Err(TenantError::ContentAddressed);
+    }
+    let mut tenants = list(provider)?;
+    if tenants.iter().any(|tenant| tenant.name == name) {
+        return Err(TenantError::Exists(name.to_owned()));
+    }
+    let options = FormatOptions {
+        force: false,
+        ..options.clone()
+    };
+    let superblock = EossFs::format_at(provider, &options, &superblock_id(name))?;
+    tenants.push(Tenant {
+        name: name.to_owned(),
+        created: SystemTime::now(),
+    });
+    store_table(provider, tenants, options.signing_key.as_ref())?;
+    Ok(superblock)
+}
+
+/// Delete tenant `name` and the chunks of its filesystem, opened with
+/// `options` holding its key. Returns the chunks deleted.
+pub fn delete(
+    provider: Arc<dyn ChunkProvider>,
+    name: &str,
+    options: MountOptions,
+) -> Result<usize, TenantError> {
+    let mut tenants = list(provider.as_ref())?;
+    let position = match tenants.iter().position(|tenant| tenant.name == name) {
+        Some(position) => position,
+        None => return Err(TenantError::NotFound(name.to_owned())),
+    };
+    let signing = options.signing_key.clone();
+    let mut fs = EossFs::open(provider.clone(), options, &superblock_id(name))?;
+    let live = fs.live_chunks()?;
+    // unmounted without being written again
+    drop(fs);
+    tenants.remove(position);
+    store_table(provider.as_ref(), tenants, signing.as_ref())?;
+    let mut deleted = 0;
+    // signatures are among them if signed
+    for id in live.iter() {
+        if provider.contains_chunk(id).map_err(MetaError::from)? {
+            provider.delete_chunk(id).map_err(MetaError::from)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{create, delete, list, superblock_id, TenantError};
+    use crate::crypt::MasterKey;
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::quota::Quota;
+    use crate::superblock::{SuperblockError, SUPERBLOCK_ID};
+
+    #[test]
+    fn test_tenants() {
+        let provider = Arc::new(MemoryProvider::new());
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let main = provider.list_chunks().unwrap().unwrap().len();
+        let key = MasterKey::new_random();
+        let options = FormatOptions {
+            key: Some(key.clone()),
+            quota: Quota {
+                bytes: 1 << 20,
+                inodes: 10,
+            },
+            ..FormatOptions::default()
+        };
+        create(provider.as_ref(), "a", &options).unwrap();
+        create(provider.as_ref(), "b", &FormatOptions::default()).unwrap();
+        assert!(matches!(
+            create(provider.as_ref(), "a", &options),
+            Err(TenantError::Exists(_))
+        ));
+        let names: Vec<_> = list(provider.as_ref()).unwrap();
+        assert_eq!(names.len(), 2);
+        assert_ne!(superblock_id("a"), Id::new(SUPERBLOCK_ID));
+
+        // each tenant is opened with its own key
+        assert!(matches!(
+            EossFs::open(
+                provider.clone(),
+                MountOptions::default(),
+                &superblock_id("a")
+            ),
+            Err(SuperblockError::KeyRequired)
+        ));
+        let keyed = MountOptions {
+            key: Some(key),
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), keyed.clone(), &superblock_id("a")).unwrap();
+        assert_eq!(fs.quota(&[]).unwrap().1.inodes, 10);
+        // chunks of other tenants are not garbage
+        assert_eq!(fs.gc().unwrap().deleted, 0);
+        fs.close().unwrap();
+
+        assert!(delete(provider.clone(), "a", keyed).unwrap() > 0);
+        assert!(matches!(
+            delete(provider.clone(), "a", MountOptions::default()),
+            Err(TenantError::NotFound(_))
+        ));
+        assert_eq!(list(provider.as_ref()).unwrap().len(), 1);
+        EossFs::open(
+            provider.clone(),
+            MountOptions::default(),
+            &superblock_id("b"),
+        )
+        .unwrap();
+        assert!(provider.list_chunks().unwrap().unwrap().len() > main);
+    }
+}
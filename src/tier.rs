@@ -0,0 +1,168 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::fs::{Attrs, DirMeta, STORAGE_CLASS_FL};
+
+/// Extended attribute setting the storage class of an entry, which files
+/// beneath a directory inherit unless set on their own.
+pub const STORAGE_CLASS_XATTR: &str = "user.eoss.storage_class";
+/// Offset of the storage class within the flags of an entry.
+const CLASS_SHIFT: u32 = STORAGE_CLASS_FL.trailing_zeros();
+
+#[derive(thiserror::Error, Debug)]
+pub enum TierError {
+    #[error("unknown storage class {0}")]
+    UnknownClass(String),
+}
+
+/// Tier of the provider chunks of a file are preferably written to, as
+/// hinted to providers storing chunks in several tiers or replica sets.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum StorageClass {
+    /// Read often, kept on the fastest tier.
+    Hot,
+    /// Read seldom.
+    Cold,
+    /// Kept for the record, slow or costly to read back.
+    Archive,
+}
+
+impl StorageClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageClass::Hot => "hot",
+            StorageClass::Cold => "cold",
+            StorageClass::Archive => "archive",
+        }
+    }
+}
+
+impl fmt::Display for StorageClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StorageClass {
+    type Err = TierError;
+
+    fn from_str(s: &str) -> Result<Self, TierError> {
+        match s {
+            "hot" => Ok(StorageClass::Hot),
+            "cold" => Ok(StorageClass::Cold),
+            "archive" => Ok(StorageClass::Archive),
+            _ => Err(TierError::UnknownClass(s.to_owned())),
+        }
+    }
+}
+
+impl Attrs {
+    /// Storage class set on the entry itself.
+    pub fn storage_class(&self) -> Option<StorageClass> {
+        match (self.flags & STORAGE_CLASS_FL) >> CLASS_SHIFT {
+            1 => Some(StorageClass::Hot),
+            2 => Some(StorageClass::Cold),
+            3 => Some(StorageClass::Archive),
+            _ => None,
+        }
+    }
+
+    pub fn set_storage_class(&mut self, class: Option<StorageClass>) {
+        let bits = match class {
+            None => 0,
+            Some(StorageClass::Hot) => 1,
+            Some(StorageClass::Cold) => 2,
+            Some(StorageClass::Archive) => 3,
+        };
+        self.flags = self.flags & !STORAGE_CLASS_FL | bits << CLASS_SHIFT;
+    }
+}
+
+/// Storage class of the entry at `path` of the tree at `root`, set on the
+/// entry or else inherited from the nearest directory above with one.
+pub fn resolve<S: AsRef<str>>(root: &DirMeta, path: &[S]) -> Option<StorageClass> {
+    (0..=path.len())
+        .rev()
+        .find_map(|n| root.resolve(&path[..n])?.attrs().storage_class())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::inode::FUSE_ROOT_ID;
+
+    use super::{resolve, StorageClass, TierError};
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fs::{Attrs, DirMeta, FileMeta, Node, PINNED_FL};
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::provider::ChunkProvider;
+    use crate::providers::memory::MemoryProvider;
+    use crate::providers::tiered::TieredProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+
+    #[test]
+    fn test_storage_class() {
+        assert_eq!("cold".parse::<StorageClass>().unwrap(), StorageClass::Cold);
+        assert!(matches!(
+            "warm".parse::<StorageClass>(),
+            Err(TierError::UnknownClass(_))
+        ));
+        let mut attrs = Attrs::new(0o755, 0, 0);
+        attrs.flags = PINNED_FL;
+        attrs.set_storage_class(Some(StorageClass::Archive));
+        assert_eq!(attrs.storage_class(), Some(StorageClass::Archive));
+        attrs.set_storage_class(None);
+        assert_eq!((attrs.flags, attrs.storage_class()), (PINNED_FL, None));
+
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let mut dir = DirMeta::new("logs".to_owned(), Attrs::new(0o755, 0, 0));
+        dir.attrs.set_storage_class(Some(StorageClass::Cold));
+        for (name, class) in [("old", None), ("new", Some(StorageClass::Hot))] {
+            let mut file = FileMeta {
+                name: name.to_owned(),
+                id: Id::new_random(),
+                attrs: Attrs::new(0o644, 0, 0),
+                hashes: Vec::new(),
+                content: false,
+            };
+            file.attrs.set_storage_class(class);
+            dir.insert(Node::File(file));
+        }
+        root.insert(Node::Dir(dir));
+        assert_eq!(resolve(&root, &["logs", "old"]), Some(StorageClass::Cold));
+        assert_eq!(resolve(&root, &["logs", "new"]), Some(StorageClass::Hot));
+        assert_eq!(resolve::<&str>(&root, &[]), None);
+
+        // stored in the tier of their class as chunks are written
+        let (default, archive) = (
+            Arc::new(MemoryProvider::new()),
+            Arc::new(MemoryProvider::new()),
+        );
+        let tiers: Vec<(StorageClass, Arc<dyn ChunkProvider>)> =
+            vec![(StorageClass::Archive, archive.clone())];
+        let provider = Arc::new(TieredProvider::new(default.clone(), tiers));
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            chunk_cache_bytes: 0,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), options, &id).unwrap();
+        fs.set_storage_class(0, FUSE_ROOT_ID, Some(StorageClass::Archive))
+            .unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, &vec![7; CHUNK_SIZE + 10])
+            .unwrap();
+        fs.close().unwrap();
+        // both chunks of the file, but no metadata
+        let archived = archive.list_chunks().unwrap().unwrap();
+        assert_eq!(archived.len(), 2);
+        assert!(default.list_chunks().unwrap().unwrap().len() > 2);
+        assert!(archived
+            .iter()
+            .all(|id| !default.contains_chunk(id).unwrap()));
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::{Duration, Instant};
+
+use tracing::span::{EnteredSpan, Span};
+
+/// Op times a FUSE request within its span, entered until dropped, and
+/// logs it at warn if slower than the threshold.
+pub struct Op {
+    span: Option<EnteredSpan>,
+    started: Instant,
+    slow: Option<Duration>,
+}
+
+impl Op {
+    pub fn new(span: Span, slow: Option<Duration>) -> Self {
+        Self {
+            span: Some(span.entered()),
+            started: Instant::now(),
+            slow,
+        }
+    }
+}
+
+impl Drop for Op {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        if self.slow.map_or(false, |slow| elapsed >= slow) {
+            // within the span, carrying its fields
+            tracing::warn!(elapsed_ms = elapsed.as_millis() as u64, "slow operation");
+        }
+        self.span.take();
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fs::{Attrs, DirMeta, Entry, EntryMut, Node};
+use crate::meta::MetaError;
+
+/// Name of the hidden directory at the root holding deleted entries.
+pub const TRASH_DIR: &str = ".trash";
+
+#[derive(thiserror::Error, Debug)]
+pub enum TrashError {
+    #[error("{0} not found in trash")]
+    NotFound(String),
+    #[error("{0} already exists")]
+    Exists(String),
+    #[error("{0} is not a directory")]
+    NotDir(String),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+}
+
+impl TrashError {
+    /// Errno reported by a mount for the error.
+    pub fn errno(&self) -> i32 {
+        match self {
+            TrashError::MetaError(e) => e.errno(),
+            _ => libc::EIO,
+        }
+    }
+}
+
+/// Name in the trash of the entry deleted from `path` at `deleted`,
+/// the original path is kept in the name to be restored.
+pub fn trash_name(path: &[String], deleted: SystemTime) -> String {
+    let secs = deleted
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = path.join("/").replace('%', "%25").replace('/', "%2F");
+    format!("{}.{}", secs, path)
+}
+
+/// Deletion time and original path of the entry `name` in the trash.
+pub fn parse_name(name: &str) -> Option<(SystemTime, Vec<String>)> {
+    let mut parts = name.splitn(2, '.');
+    let secs: u64 = parts.next()?.parse().ok()?;
+    let path = parts
+        .next()?
+        .split("%2F")
+        .map(|name| name.replace("%25", "%"))
+        .collect();
+    Some((UNIX_EPOCH + Duration::from_secs(secs), path))
+}
+
+/// Move `node` removed from `path` into the trash of the tree at `root`,
+/// creating the trash if needed.
+/// Returns its path in the trash, and an entry it replaces.
+pub fn put(
+    root: &mut DirMeta,
+    path: &[String],
+    mut node: Node,
+    now: SystemTime,
+) -> Result<(Vec<String>, Option<Node>), TrashError> {
+    if root.lookup(TRASH_DIR).is_none() {
+        // only the owner of the filesystem can restore
+        let attrs = Attrs::new(0o700, root.attrs.uid, root.attrs.gid);
+        root.insert(Node::Dir(DirMeta::new(TRASH_DIR.to_owned(), attrs)));
+    }
+    let trash = match root.lookup_mut(TRASH_DIR) {
+        Some(EntryMut::Dir(dir)) => dir,
+        _ => return Err(TrashError::NotDir(TRASH_DIR.to_owned())),
+    };
+    let name = trash_name(path, now);
+    node.set_name(name.clone());
+    let replaced = trash.insert(node);
+    Ok((vec![TRASH_DIR.to_owned(), name], replaced))
+}
+
+/// Names of entries in the trash of the tree at `root` deleted before `before`.
+pub fn expired(root: &DirMeta, before: SystemTime) -> Vec<String> {
+    let trash = match root.lookup(TRASH_DIR) {
+        Some(Entry::Dir(dir)) => dir,
+        _ => return Vec::new(),
+    };
+    trash
+        .entries
+        .keys()
+        .filter(|name| match parse_name(name) {
+            Some((deleted, _)) => deleted < before,
+            None => false,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Move the entry `name` out of the trash of the tree at `root`, which has to
+/// be fully loaded, back to its original path. Missing parent directories are
+/// created. Returns the original path.
+pub fn restore(root: &mut DirMeta, name: &str) -> Result<Vec<String>, TrashError> {
+    let not_found = || TrashError::NotFound(name.to_owned());
+    let (_, path) = parse_name(name).ok_or_else(not_found)?;
+    match root.resolve(&[TRASH_DIR, name]) {
+        Some(_) if root.resolve(&path).is_some() => {
+            return Err(TrashError::Exists(path.join("/")));
+        }
+        Some(_) => {}
+        None => return Err(not_found()),
+    }
+    let (last, parents) = path.split_last().ok_or_else(not_found)?;
+    let attrs = root.attrs.clone();
+    let mut dir = &mut *root;
+    for (i, parent) in parents.iter().enumerate() {
+        if dir.lookup(parent).is_none() {
+            let attrs = Attrs::new(attrs.perm, attrs.uid, attrs.gid);
+            dir.insert(Node::Dir(DirMeta::new(parent.clone(), attrs)));
+        }
+        dir = match dir.lookup_mut(parent) {
+            Some(EntryMut::Dir(sub)) => sub,
+            _ => return Err(TrashError::NotDir(path[..=i].join("/"))),
+        };
+    }
+    let mut node = match root.lookup_mut(TRASH_DIR) {
+        Some(EntryMut::Dir(trash)) => trash.remove(name).ok_or_else(not_found)?,
+        _ => return Err(not_found()),
+    };
+    node.set_name(last.clone());
+    match root.resolve_mut(parents) {
+        Some(EntryMut::Dir(dir)) => dir.insert(node),
+        _ => unreachable!(),
+    };
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expired, put, restore, TRASH_DIR};
+    use crate::fs::{Attrs, DirMeta, Entry, Node, TinyFileMeta};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_put_and_restore() {
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let path = vec!["a%b".to_owned(), "file".to_owned()];
+        let file = TinyFileMeta::new("file".to_owned(), Attrs::new(0o644, 0, 0));
+        let now = SystemTime::now();
+        let (trashed, _) = put(&mut root, &path, Node::TinyFile(file), now).unwrap();
+        assert_eq!(trashed[0], TRASH_DIR);
+        assert!(expired(&root, now - Duration::from_secs(1)).is_empty());
+        assert_eq!(expired(&root, now + Duration::from_secs(1)).len(), 1);
+
+        assert_eq!(restore(&mut root, &trashed[1]).unwrap(), path);
+        assert!(matches!(root.resolve(&path), Some(Entry::TinyFile(_))));
+        assert!(root.resolve(&trashed).is_none());
+    }
+}
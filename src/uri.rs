@@ -0,0 +1,122 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::provider::ChunkProvider;
+use crate::providers::local::LocalProvider;
+use crate::providers::tiered::TieredProvider;
+use crate::tier::{StorageClass, TierError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum UriError {
+    #[error("no provider for {0}:// in this build")]
+    UnsupportedScheme(String),
+    #[error("invalid tier {0}, expected <class>=<uri>")]
+    InvalidTier(String),
+    #[error(transparent)]
+    TierError(#[from] TierError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// Where the chunks of a filesystem are stored, as given on the command
+/// line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProviderUri {
+    /// A local directory, `local:///var/chunks` or a plain path.
+    Local(PathBuf),
+    /// A default provider and one per storage class, e.g.
+    /// `tiered:///var/chunks;cold=/mnt/disk;archive=/mnt/tape`.
+    Tiered(Box<ProviderUri>, Vec<(StorageClass, ProviderUri)>),
+}
+
+impl FromStr for ProviderUri {
+    type Err = UriError;
+
+    fn from_str(s: &str) -> Result<Self, UriError> {
+        match s.split_once("://") {
+            Some(("local", path)) => Ok(ProviderUri::Local(path.into())),
+            Some(("tiered", tiers)) => {
+                let mut tiers = tiers.split(';');
+                let default = tiers.next().unwrap_or_default().parse()?;
+                let tiers = tiers
+                    .map(|tier| match tier.split_once('=') {
+                        Some((class, uri)) => Ok((class.parse()?, uri.parse()?)),
+                        None => Err(UriError::InvalidTier(tier.to_owned())),
+                    })
+                    .collect::<Result<_, UriError>>()?;
+                Ok(ProviderUri::Tiered(Box::new(default), tiers))
+            }
+            Some((scheme, _)) => Err(UriError::UnsupportedScheme(scheme.to_owned())),
+            None => Ok(ProviderUri::Local(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for ProviderUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderUri::Local(path) => write!(f, "local://{}", path.display()),
+            ProviderUri::Tiered(default, tiers) => {
+                write!(f, "tiered://{}", default)?;
+                for (class, uri) in tiers {
+                    write!(f, ";{}={}", class, uri)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ProviderUri {
+    /// Open the provider.
+    pub fn open(&self) -> Result<Arc<dyn ChunkProvider>, UriError> {
+        match self {
+            ProviderUri::Local(path) => Ok(Arc::new(LocalProvider::new(path)?)),
+            ProviderUri::Tiered(default, tiers) => {
+                let tiers = tiers
+                    .iter()
+                    .map(|(class, uri)| Ok((*class, uri.open()?)))
+                    .collect::<Result<_, UriError>>()?;
+                Ok(Arc::new(TieredProvider::new(default.open()?, tiers)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProviderUri, UriError};
+    use crate::tier::StorageClass;
+
+    #[test]
+    fn test_parse() {
+        let local = ProviderUri::Local("/var/chunks".into());
+        assert_eq!("local:///var/chunks".parse::<ProviderUri>().unwrap(), local);
+        assert_eq!("/var/chunks".parse::<ProviderUri>().unwrap(), local);
+        assert_eq!(local.to_string(), "local:///var/chunks");
+        assert!(matches!(
+            "s3://bucket/prefix".parse::<ProviderUri>(),
+            Err(UriError::UnsupportedScheme(scheme)) if scheme == "s3"
+        ));
+
+        let tiered = "tiered:///var/chunks;cold=local:///mnt/disk";
+        let cold = ProviderUri::Local("/mnt/disk".into());
+        assert_eq!(
+            tiered.parse::<ProviderUri>().unwrap(),
+            ProviderUri::Tiered(Box::new(local), vec![(StorageClass::Cold, cold)])
+        );
+        assert_eq!(
+            tiered.parse::<ProviderUri>().unwrap().to_string(),
+            "tiered://local:///var/chunks;cold=local:///mnt/disk"
+        );
+        assert!("tiered:///var/chunks;/mnt/disk"
+            .parse::<ProviderUri>()
+            .is_err());
+        assert!("tiered:///var/chunks;warm=/mnt/disk"
+            .parse::<ProviderUri>()
+            .is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, types, IoUring};
+use parking_lot::Mutex;
+
+use crate::chunk::{BLOCK_SIZE, CHUNK_SIZE};
+
+/// A buffer of a chunk aligned to blocks, registered to the ring.
+struct Buffer {
+    ptr: *mut u8,
+}
+
+// only accessed under the lock of the ring
+unsafe impl Send for Buffer {}
+
+impl Buffer {
+    fn layout() -> Layout {
+        Layout::from_size_align(CHUNK_SIZE, BLOCK_SIZE).unwrap()
+    }
+
+    fn new() -> Self {
+        let ptr = unsafe { alloc::alloc_zeroed(Self::layout()) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(Self::layout());
+        }
+        Self { ptr }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, Self::layout()) }
+    }
+}
+
+struct Inner {
+    ring: IoUring,
+    buffer: Buffer,
+}
+
+/// Uring reads and writes whole chunk files through io_uring, with a buffer
+/// registered once instead of pages pinned on every call.
+pub struct Uring {
+    inner: Mutex<Inner>,
+}
+
+impl Uring {
+    /// Set up a ring, failing where the kernel does not support io_uring.
+    pub fn new() -> io::Result<Self> {
+        let ring = IoUring::new(4)?;
+        let buffer = Buffer::new();
+        let iovec = libc::iovec {
+            iov_base: buffer.ptr as *mut libc::c_void,
+            iov_len: CHUNK_SIZE,
+        };
+        // the buffer outlives the ring, dropped after it
+        unsafe { ring.submitter().register_buffers(&[iovec])? };
+        Ok(Self {
+            inner: Mutex::new(Inner { ring, buffer }),
+        })
+    }
+
+    /// Read `file` of at most a chunk from the start.
+    pub fn read(&self, file: &File) -> io::Result<Vec<u8>> {
+        let mut inner = self.inner.lock();
+        let mut read = 0;
+        while read < CHUNK_SIZE {
+            let ptr = unsafe { inner.buffer.ptr.add(read) };
+            let entry = opcode::ReadFixed::new(
+                types::Fd(file.as_raw_fd()),
+                ptr,
+                (CHUNK_SIZE - read) as u32,
+                0,
+            )
+            .offset(read as u64)
+            .build();
+            match inner.complete(&entry)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        let data = unsafe { std::slice::from_raw_parts(inner.buffer.ptr, read) };
+        Ok(data.to_vec())
+    }
+
+    /// Write `data` of at most a chunk to `file` from the start.
+    pub fn write(&self, file: &File, data: &[u8]) -> io::Result<()> {
+        assert!(data.len() <= CHUNK_SIZE);
+        let mut inner = self.inner.lock();
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), inner.buffer.ptr, data.len()) };
+        let mut written = 0;
+        while written < data.len() {
+            let ptr = unsafe { inner.buffer.ptr.add(written) };
+            let entry = opcode::WriteFixed::new(
+                types::Fd(file.as_raw_fd()),
+                ptr,
+                (data.len() - written) as u32,
+                0,
+            )
+            .offset(written as u64)
+            .build();
+            match inner.complete(&entry)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => written += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    /// Submit `entry` and wait for its result.
+    fn complete(&mut self, entry: &io_uring::squeue::Entry) -> io::Result<usize> {
+        unsafe {
+            self.ring
+                .submission()
+                .push(entry)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "submission queue is full"))?;
+        }
+        self.ring.submit_and_wait(1)?;
+        let cqe = self
+            .ring
+            .completion()
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no completion"))?;
+        match cqe.result() {
+            n if n < 0 => Err(io::Error::from_raw_os_error(-n)),
+            n => Ok(n as usize),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::SystemTime;
+
+use crate::fs::{Attrs, DirMeta, Entry, EntryMut, Node};
+use crate::meta::MetaError;
+use crate::trash::{parse_name, trash_name};
+
+/// Name of the hidden directory at the root holding previous versions of files.
+pub const VERSIONS_DIR: &str = ".versions";
+
+#[derive(thiserror::Error, Debug)]
+pub enum VersionError {
+    #[error("version {0} not found")]
+    NotFound(String),
+    #[error("{0} is not a directory")]
+    NotDir(String),
+    #[error(transparent)]
+    MetaError(#[from] MetaError),
+}
+
+impl VersionError {
+    /// Errno reported by a mount for the error.
+    pub fn errno(&self) -> i32 {
+        match self {
+            VersionError::MetaError(e) => e.errno(),
+            _ => libc::EIO,
+        }
+    }
+}
+
+/// Keep a copy of `node` at `path` as a version in the tree at `root`,
+/// creating the versions directory if needed. Versions are named like
+/// entries of the trash, by the time kept and the original path.
+/// Returns its path among versions, or `None` if a version of `path` is
+/// already kept at the same second.
+pub fn keep(
+    root: &mut DirMeta,
+    path: &[String],
+    node: &Node,
+    now: SystemTime,
+) -> Result<Option<Vec<String>>, VersionError> {
+    if root.lookup(VERSIONS_DIR).is_none() {
+        let attrs = Attrs::new(0o700, root.attrs.uid, root.attrs.gid);
+        root.insert(Node::Dir(DirMeta::new(VERSIONS_DIR.to_owned(), attrs)));
+    }
+    let versions = match root.lookup_mut(VERSIONS_DIR) {
+        Some(EntryMut::Dir(dir)) => dir,
+        _ => return Err(VersionError::NotDir(VERSIONS_DIR.to_owned())),
+    };
+    let name = trash_name(path, now);
+    if versions.lookup(&name).is_some() {
+        return Ok(None);
+    }
+    let mut node = node.clone();
+    node.set_name(name.clone());
+    versions.insert(node);
+    Ok(Some(vec![VERSIONS_DIR.to_owned(), name]))
+}
+
+/// Names of the versions of the file at `path` in the tree at `root`,
+/// oldest first.
+pub fn list(root: &DirMeta, path: &[String]) -> Vec<String> {
+    let versions = match root.lookup(VERSIONS_DIR) {
+        Some(Entry::Dir(dir)) => dir,
+        _ => return Vec::new(),
+    };
+    let mut found: Vec<_> = versions
+        .entries
+        .keys()
+        .filter_map(|name| match parse_name(name) {
+            Some((kept, of)) if of == path => Some((kept, name.clone())),
+            _ => None,
+        })
+        .collect();
+    found.sort();
+    found.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Remove the oldest versions of the file at `path` beyond `count`.
+/// Returns the names and entries removed.
+pub fn prune(root: &mut DirMeta, path: &[String], count: usize) -> Vec<(String, Node)> {
+    let names = list(root, path);
+    let excess = names.len().saturating_sub(count);
+    let versions = match root.lookup_mut(VERSIONS_DIR) {
+        Some(EntryMut::Dir(dir)) => dir,
+        _ => return Vec::new(),
+    };
+    names
+        .into_iter()
+        .take(excess)
+        .filter_map(|name| versions.remove(&name).map(|node| (name, node)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keep, list, prune};
+    use crate::fs::{Attrs, DirMeta, Node, TinyFileMeta};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_keep_and_prune() {
+        let mut root = DirMeta::new(String::new(), Attrs::new(0o755, 0, 0));
+        let path = vec!["doc".to_owned()];
+        let file = Node::TinyFile(TinyFileMeta::new("doc".to_owned(), Attrs::new(0o644, 0, 0)));
+        let now = SystemTime::now();
+        for secs in 0..3 {
+            let kept = keep(&mut root, &path, &file, now + Duration::from_secs(secs)).unwrap();
+            assert!(kept.is_some());
+        }
+        // one version a second
+        assert!(keep(&mut root, &path, &file, now).unwrap().is_none());
+        assert!(list(&root, &["other".to_owned()]).is_empty());
+
+        let names = list(&root, &path);
+        assert_eq!(names.len(), 3);
+        let pruned = prune(&mut root, &path, 2);
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].0, names[0]);
+        assert_eq!(list(&root, &path), names[1..]);
+    }
+}
@@ -0,0 +1,422 @@
+use std::io;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[cfg(unix)]
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+use libc::{c_int, EISDIR, ENOENT};
+
+use crate::fuse::EossFs;
+pub use crate::fuse::SetAttrs;
+pub use crate::id::Id;
+use crate::options::{FormatOptions, MountOptions};
+use crate::provider::ChunkProvider;
+pub use crate::superblock::SuperblockError;
+use crate::superblock::SUPERBLOCK_ID;
+#[cfg(windows)]
+use crate::win::{FileAttr, FileType, FUSE_ROOT_ID};
+
+/// Kind of an entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileKind {
+    Dir,
+    File,
+}
+
+/// Attributes of an entry.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub size: u64,
+    pub perm: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+}
+
+impl Metadata {
+    /// Whether `user` may access the entry as `mask` asks, bits 4 to read,
+    /// 2 to write and 1 to execute or search, root being allowed anything.
+    pub fn permits(&self, user: User, mask: u16) -> bool {
+        user.may(self.perm, self.uid, self.gid, mask)
+    }
+}
+
+/// A user acting on entries, checked against their permissions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl User {
+    /// User of the process at the other end of `stream`.
+    #[cfg(target_os = "linux")]
+    pub fn of_peer(stream: &UnixStream) -> io::Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let got = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        match got {
+            0 => Ok(Self {
+                uid: cred.uid,
+                gid: cred.gid,
+            }),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// User of the process at the other end of `stream`.
+    #[cfg(all(unix, not(target_os = "linux")))]
+    pub fn of_peer(stream: &UnixStream) -> io::Result<Self> {
+        let (mut uid, mut gid) = (0, 0);
+        match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+            0 => Ok(Self { uid, gid }),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Whether the user may access an entry with permissions `perm` owned
+    /// by `uid` and `gid` as `mask` asks, root being allowed anything.
+    pub fn may(&self, perm: u16, uid: u32, gid: u32, mask: u16) -> bool {
+        if self.uid == 0 {
+            return true;
+        }
+        let bits = if self.uid == uid {
+            perm >> 6
+        } else if self.gid == gid {
+            perm >> 3
+        } else {
+            perm
+        };
+        bits & mask == mask
+    }
+
+    /// Whether the user may make `changes` to an entry owned by `uid` and
+    /// `gid`: its owner changing its mode and times, to a group of theirs,
+    /// and anyone allowed to write, as `writable` says, resizing it. Root
+    /// may do anything.
+    pub fn may_change(&self, uid: u32, gid: u32, changes: &SetAttrs, writable: bool) -> bool {
+        if self.uid == 0 {
+            return true;
+        }
+        let owner = self.uid == uid;
+        let chown = changes.uid.map_or(false, |changed| changed != uid);
+        let chgrp = changes
+            .gid
+            .map_or(false, |changed| changed != gid && changed != self.gid);
+        let owned = changes.mode.is_some() || changes.atime.is_some() || changes.mtime.is_some();
+        if chown || chgrp || ((owned || changes.gid.is_some()) && !owner) {
+            return false;
+        }
+        changes.size.is_none() || writable
+    }
+}
+
+/// An entry of a directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// A file opened by `Vfs::open_file` or `Vfs::create`, to be closed by
+/// `Vfs::close_file`.
+#[derive(Debug)]
+pub struct FileHandle {
+    ino: u64,
+}
+
+/// A filesystem used in process by path, without mounting it nor any
+/// kernel support. Paths are relative to its root, their components
+/// separated by '/'.
+pub struct Vfs {
+    fs: EossFs,
+}
+
+impl Vfs {
+    /// Format a new, empty filesystem in `provider`.
+    pub fn format(
+        provider: &dyn ChunkProvider,
+        options: &FormatOptions,
+    ) -> Result<(), SuperblockError> {
+        EossFs::format(provider, options).map(drop)
+    }
+
+    /// Open the filesystem stored in `provider`.
+    pub fn open(
+        provider: Arc<dyn ChunkProvider>,
+        options: MountOptions,
+    ) -> Result<Self, SuperblockError> {
+        Self::open_at(provider, options, &Id::new(SUPERBLOCK_ID))
+    }
+
+    /// Open the filesystem whose superblock is stored at `superblock_id`,
+    /// a tenant's.
+    pub fn open_at(
+        provider: Arc<dyn ChunkProvider>,
+        options: MountOptions,
+        superblock_id: &Id,
+    ) -> Result<Self, SuperblockError> {
+        let fs = EossFs::open(provider, options, superblock_id)?;
+        Ok(Self { fs })
+    }
+
+    /// Persist everything and mark the filesystem cleanly closed.
+    pub fn close(&mut self) -> Result<(), SuperblockError> {
+        self.fs.close()
+    }
+
+    /// Persist everything, the filesystem staying open for those sharing it.
+    pub fn sync_all(&mut self) -> Result<(), SuperblockError> {
+        self.fs.sync_all()
+    }
+
+    /// Start the tasks a mounted filesystem runs in background, such as
+    /// flushing dirty chunks and renewing the lease, for a frontend of its
+    /// own.
+    pub fn start_background(&self) {
+        self.fs.start_background()
+    }
+
+    pub fn metadata(&mut self, path: &str) -> io::Result<Metadata> {
+        let ino = self.resolve(path)?;
+        let attr = self.fs.attr(ino);
+        self.fs.forget_inode(ino, 1);
+        Ok(metadata(&attr.map_err(error)?))
+    }
+
+    /// Change the attributes of the entry at `path` as `changes` says.
+    pub fn set_attrs(&mut self, path: &str, changes: SetAttrs) -> io::Result<Metadata> {
+        let ino = self.resolve(path)?;
+        let attr = self.fs.set_attrs(ino, changes);
+        self.fs.forget_inode(ino, 1);
+        Ok(metadata(&attr.map_err(error)?))
+    }
+
+    /// Entries of the directory at `path`, in no particular order.
+    pub fn read_dir(&mut self, path: &str) -> io::Result<impl Iterator<Item = DirEntry>> {
+        let ino = self.resolve(path)?;
+        let entries = self.fs.list_dir(ino);
+        self.fs.forget_inode(ino, 1);
+        let entries = entries.map_err(error)?.into_iter();
+        Ok(entries.map(|(name, attr)| DirEntry {
+            name,
+            metadata: metadata(&attr),
+        }))
+    }
+
+    /// Open the file at `path` for reading and writing.
+    pub fn open_file(&mut self, path: &str) -> io::Result<FileHandle> {
+        let ino = self.resolve(path)?;
+        match self.fs.attr(ino).map_err(error)?.kind {
+            FileType::Directory => {
+                self.fs.forget_inode(ino, 1);
+                Err(error(EISDIR))
+            }
+            _ => Ok(FileHandle { ino }),
+        }
+    }
+
+    /// Create the empty file at `path`, which must not exist, and open it.
+    pub fn create(&mut self, path: &str, perm: u16) -> io::Result<FileHandle> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let created = self.fs.create_file(parent, &name, perm, 0, 0);
+        self.fs.forget_inode(parent, 1);
+        let (attr, _) = created.map_err(error)?;
+        Ok(FileHandle { ino: attr.ino })
+    }
+
+    /// Create the empty directory at `path`, which must not exist.
+    pub fn create_dir(&mut self, path: &str, perm: u16) -> io::Result<Metadata> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let created = self.fs.create_dir(parent, &name, perm, 0, 0);
+        self.fs.forget_inode(parent, 1);
+        let (attr, _) = created.map_err(error)?;
+        self.fs.forget_inode(attr.ino, 1);
+        Ok(metadata(&attr))
+    }
+
+    /// Read into `buf` from `offset` of `file`, returning the bytes read,
+    /// fewer at its end.
+    pub fn read(&mut self, file: &FileHandle, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (data, range) = self
+            .fs
+            .read_data(file.ino, offset, buf.len(), None)
+            .map_err(error)?;
+        buf[..range.len()].copy_from_slice(&data[range.clone()]);
+        Ok(range.len())
+    }
+
+    /// Write `data` at `offset` of `file`.
+    pub fn write(&mut self, file: &FileHandle, offset: u64, data: &[u8]) -> io::Result<usize> {
+        self.fs.write_direct(file.ino, offset, data).map_err(error)
+    }
+
+    /// Truncate or extend `file` to `size` bytes.
+    pub fn set_len(&mut self, file: &FileHandle, size: u64) -> io::Result<()> {
+        let changes = SetAttrs {
+            size: Some(size),
+            ..SetAttrs::default()
+        };
+        self.set_file_attrs(file, changes).map(drop)
+    }
+
+    pub fn file_metadata(&mut self, file: &FileHandle) -> io::Result<Metadata> {
+        Ok(metadata(&self.fs.attr(file.ino).map_err(error)?))
+    }
+
+    /// Change the attributes of `file` as `changes` says.
+    pub fn set_file_attrs(&mut self, file: &FileHandle, changes: SetAttrs) -> io::Result<Metadata> {
+        Ok(metadata(
+            &self.fs.set_attrs(file.ino, changes).map_err(error)?,
+        ))
+    }
+
+    /// Persist what was written to `file`.
+    pub fn sync(&mut self, file: &FileHandle) -> io::Result<()> {
+        self.fs.sync_inode(file.ino).map_err(error)
+    }
+
+    /// Close `file`, persisting what was written to it.
+    pub fn close_file(&mut self, file: FileHandle) -> io::Result<()> {
+        let synced = self.fs.sync_inode(file.ino);
+        self.fs.forget_inode(file.ino, 1);
+        synced.map_err(error)
+    }
+
+    pub fn remove_file(&mut self, path: &str) -> io::Result<()> {
+        self.remove(path, false)
+    }
+
+    /// Remove the empty directory at `path`.
+    pub fn remove_dir(&mut self, path: &str) -> io::Result<()> {
+        self.remove(path, true)
+    }
+
+    /// Move the entry at `from` to `to`, replacing what is there.
+    pub fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let (parent, name) = self.resolve_parent(from)?;
+        let (newparent, newname) = match self.resolve_parent(to) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                self.fs.forget_inode(parent, 1);
+                return Err(e);
+            }
+        };
+        let moved = self.fs.move_entry(parent, &name, newparent, &newname, 0);
+        self.fs.forget_inode(parent, 1);
+        self.fs.forget_inode(newparent, 1);
+        moved.map_err(error)
+    }
+
+    fn remove(&mut self, path: &str, rmdir: bool) -> io::Result<()> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let removed = self.fs.remove(parent, &name, rmdir);
+        self.fs.forget_inode(parent, 1);
+        removed.map_err(error)
+    }
+
+    /// Inode of `path`, counted as a lookup.
+    fn resolve(&mut self, path: &str) -> io::Result<u64> {
+        let mut ino = FUSE_ROOT_ID;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let found = self.fs.lookup_entry(ino, name);
+            self.fs.forget_inode(ino, 1);
+            ino = found.map_err(error)?.0.ino;
+        }
+        Ok(ino)
+    }
+
+    /// Inode of the parent of `path`, counted as a lookup, and the name of
+    /// `path` in it.
+    fn resolve_parent(&mut self, path: &str) -> io::Result<(u64, String)> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(error(ENOENT));
+        }
+        Ok((self.resolve(parent)?, name.to_owned()))
+    }
+}
+
+fn error(errno: c_int) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn metadata(attr: &FileAttr) -> Metadata {
+    Metadata {
+        kind: match attr.kind {
+            FileType::Directory => FileKind::Dir,
+            _ => FileKind::File,
+        },
+        size: attr.size,
+        perm: attr.perm,
+        uid: attr.uid,
+        gid: attr.gid,
+        atime: attr.atime,
+        mtime: attr.mtime,
+        ctime: attr.ctime,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+
+    use super::{FileKind, User, Vfs};
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::providers::memory::MemoryProvider;
+
+    #[test]
+    fn test_vfs() {
+        let provider = Arc::new(MemoryProvider::new());
+        Vfs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let mut vfs = Vfs::open(provider.clone(), MountOptions::default()).unwrap();
+        let file = vfs.create("/file", 0o644).unwrap();
+        assert_eq!(vfs.write(&file, 0, b"hello").unwrap(), 5);
+        vfs.close_file(file).unwrap();
+        vfs.rename("file", "renamed").unwrap();
+        vfs.create_dir("/dir", 0o750).unwrap();
+        let dir = vfs.metadata("dir").unwrap();
+        assert_eq!(dir.kind, FileKind::Dir);
+        assert!(dir.permits(User { uid: 0, gid: 0 }, 7));
+        assert!(!dir.permits(User { uid: 1000, gid: 0 }, 2));
+        assert!(dir.permits(User { uid: 1000, gid: 0 }, 5));
+        let file = vfs.create("/dir/file", 0o644).unwrap();
+        vfs.close_file(file).unwrap();
+        vfs.close().unwrap();
+
+        let mut vfs = Vfs::open(provider, MountOptions::default()).unwrap();
+        let mut names: Vec<String> = vfs.read_dir("/").unwrap().map(|entry| entry.name).collect();
+        names.sort();
+        assert_eq!(names, ["dir", "renamed"]);
+        assert_eq!(vfs.metadata("/").unwrap().kind, FileKind::Dir);
+        let file = vfs.open_file("renamed").unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(vfs.read(&file, 1, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ello");
+        vfs.close_file(file).unwrap();
+        vfs.remove_file("renamed").unwrap();
+        let missing = vfs.metadata("renamed").unwrap_err();
+        assert_eq!(missing.kind(), ErrorKind::NotFound);
+    }
+}
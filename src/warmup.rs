@@ -0,0 +1,159 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::id::{Id, IdError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum WarmupError {
+    #[error("invalid chunk id on line {0}: {1}")]
+    InvalidId(usize, IdError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// Chunk ids of the warm-up manifest at `path`, in hex one per line in the
+/// order to prefetch them. Blank lines and lines starting with `#` are
+/// skipped, a manifest not recorded yet is empty.
+pub fn load(path: &Path) -> Result<Vec<Id>, WarmupError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut ids = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        ids.push(Id::from_hex(line).map_err(|e| WarmupError::InvalidId(n + 1, e))?);
+    }
+    Ok(ids)
+}
+
+/// Progress of a warm-up prefetching in background.
+pub struct WarmUp {
+    queued: usize,
+    /// Workers still prefetching
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl WarmUp {
+    /// A warm-up of `queued` chunks prefetched by `workers`, each calling
+    /// `done` once finished.
+    pub fn new(queued: usize, workers: usize) -> Self {
+        Self {
+            queued,
+            running: Arc::new((Mutex::new(workers), Condvar::new())),
+        }
+    }
+
+    /// Nothing to prefetch.
+    pub fn none() -> Self {
+        Self::new(0, 0)
+    }
+
+    /// Chunks queued to prefetch.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    /// Called by a worker once finished.
+    pub fn done(&self) -> impl FnOnce() + Send + 'static {
+        let running = self.running.clone();
+        move || {
+            let (count, finished) = &*running;
+            *count.lock() -= 1;
+            finished.notify_all();
+        }
+    }
+
+    /// Wait for the workers to finish.
+    pub fn wait(&self) {
+        let (count, finished) = &*self.running;
+        let mut count = count.lock();
+        while *count > 0 {
+            finished.wait(&mut count);
+        }
+    }
+}
+
+/// Record `ids` as the warm-up manifest at `path`, replaced at once.
+pub fn store(path: &Path, ids: &[Id]) -> io::Result<()> {
+    let text: String = ids.iter().map(|id| format!("{}\n", id.hex())).collect();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, text)?;
+    fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::inode::FUSE_ROOT_ID;
+
+    use super::{load, store, WarmupError};
+    use crate::chunk::CHUNK_SIZE;
+    use crate::fuse::EossFs;
+    use crate::id::Id;
+    use crate::options::{FormatOptions, MountOptions};
+    use crate::providers::memory::MemoryProvider;
+    use crate::superblock::SUPERBLOCK_ID;
+
+    #[test]
+    fn test_warmup() {
+        let path = env::temp_dir().join(format!("eoss-warmup-{}", Id::new_random().hex()));
+        assert!(load(&path).unwrap().is_empty());
+        let ids = vec![Id::new_random(), Id::new_random()];
+        store(&path, &ids).unwrap();
+        assert_eq!(load(&path).unwrap(), ids);
+        fs::write(&path, format!("# hot\n\n{}\nnot hex\n", ids[0].hex())).unwrap();
+        assert!(matches!(load(&path), Err(WarmupError::InvalidId(4, _))));
+
+        // recorded on unmount, prefetched once mounted again
+        let provider = Arc::new(MemoryProvider::new());
+        let id = Id::new(SUPERBLOCK_ID);
+        EossFs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+        let options = MountOptions {
+            warmup: Some(path.clone()),
+            warmup_chunks: 16,
+            ..MountOptions::default()
+        };
+        let mut fs = EossFs::open(provider.clone(), options.clone(), &id).unwrap();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "file", 0o644, 0, 0).unwrap();
+        fs.write_direct(attr.ino, 0, &vec![7; 2 * CHUNK_SIZE])
+            .unwrap();
+        fs.close().unwrap();
+        drop(fs);
+        let mut recorded = load(&path).unwrap();
+        assert!(recorded.len() >= 2);
+        // chunks deleted since left out
+        recorded.push(Id::new_random());
+        store(&path, &recorded).unwrap();
+
+        let fs = EossFs::open(provider.clone(), options.clone(), &id).unwrap();
+        let warm_up = fs.warm_up();
+        assert_eq!(warm_up.queued(), recorded.len());
+        warm_up.wait();
+        let stored = (recorded.len() - 1) * CHUNK_SIZE;
+        assert_eq!(fs.runtime_stats().cache_bytes, stored);
+        drop(fs);
+
+        // not recorded by default
+        fs::remove_file(&path).unwrap();
+        let options = MountOptions {
+            warmup_chunks: 0,
+            ..options
+        };
+        let mut fs = EossFs::open(provider, options, &id).unwrap();
+        fs.close().unwrap();
+        assert!(!path.exists());
+    }
+}
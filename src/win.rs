@@ -0,0 +1,67 @@
+use std::ffi::OsStr;
+use std::io;
+use std::time::SystemTime;
+
+use libc::c_int;
+
+/// Inode of the root directory, as FUSE numbers it.
+pub const FUSE_ROOT_ID: u64 = 1;
+
+/// Errnos and flags of Linux the filesystem core uses, missing from the
+/// C runtime of Windows, numbered as on Linux.
+pub const EDQUOT: c_int = 122;
+pub const ESTALE: c_int = 116;
+pub const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+pub const RENAME_NOREPLACE: u32 = 0x01;
+pub const RENAME_EXCHANGE: u32 = 0x02;
+
+/// Kind of an inode, as fuser defines it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FileType {
+    NamedPipe,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    RegularFile,
+    Symlink,
+    Socket,
+}
+
+/// Attributes of an inode, as fuser defines them.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FileAttr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub crtime: SystemTime,
+    pub kind: FileType,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+    pub flags: u32,
+}
+
+/// Notifier of the kernel, never set as Windows has no kernel cache to
+/// invalidate.
+pub enum Notifier {}
+
+impl Notifier {
+    pub fn inval_inode(&self, _ino: u64, _offset: i64, _len: i64) -> io::Result<()> {
+        match *self {}
+    }
+
+    pub fn inval_entry(&self, _parent: u64, _name: &OsStr) -> io::Result<()> {
+        match *self {}
+    }
+
+    pub fn delete(&self, _parent: u64, _child: u64, _name: &OsStr) -> io::Result<()> {
+        match *self {}
+    }
+}
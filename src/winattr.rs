@@ -0,0 +1,102 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::vfs::{FileKind, Metadata};
+
+pub const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
+pub const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+pub const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+pub const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+
+/// Intervals of 100ns from 1601, when FILETIMEs start, to the Unix epoch.
+const FILETIME_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// Windows attributes of the entry `name` with `metadata`: read-only
+/// without write permission for its owner, and hidden if a dotfile as on
+/// Unix.
+pub fn attributes(name: &str, metadata: &Metadata) -> u32 {
+    let mut attributes = match metadata.kind {
+        FileKind::Dir => FILE_ATTRIBUTE_DIRECTORY,
+        FileKind::File => FILE_ATTRIBUTE_ARCHIVE,
+    };
+    if metadata.perm & 0o200 == 0 {
+        attributes |= FILE_ATTRIBUTE_READONLY;
+    }
+    if name.starts_with('.') {
+        attributes |= FILE_ATTRIBUTE_HIDDEN;
+    }
+    attributes
+}
+
+/// Permission after setting Windows `attributes` on an entry with `perm`,
+/// the read-only attribute clearing write permissions and unsetting it
+/// granting the owner's. Other attributes are not kept.
+pub fn perm(attributes: u32, perm: u16) -> u16 {
+    match attributes & FILE_ATTRIBUTE_READONLY {
+        0 if perm & 0o200 == 0 => perm | 0o200,
+        0 => perm,
+        _ => perm & !0o222,
+    }
+}
+
+pub fn filetime(time: SystemTime) -> u64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    FILETIME_EPOCH + since.as_nanos() as u64 / 100
+}
+
+/// Time of `filetime`, `None` for zero which leaves a time unchanged.
+pub fn system_time(filetime: u64) -> Option<SystemTime> {
+    match filetime {
+        0 => None,
+        filetime => {
+            let since = filetime.saturating_sub(FILETIME_EPOCH);
+            Some(UNIX_EPOCH + Duration::from_nanos(since * 100))
+        }
+    }
+}
+
+/// Path in the filesystem of the Windows path `path`, such as `\dir\file`.
+pub fn path(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        attributes, filetime, path, perm, system_time, FILE_ATTRIBUTE_ARCHIVE,
+        FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_READONLY,
+    };
+    use crate::vfs::{FileKind, Metadata};
+
+    #[test]
+    fn test_attributes() {
+        let mut metadata = Metadata {
+            kind: FileKind::Dir,
+            size: 0,
+            perm: 0o755,
+            uid: 0,
+            gid: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+        };
+        assert_eq!(attributes("dir", &metadata), FILE_ATTRIBUTE_DIRECTORY);
+        metadata.kind = FileKind::File;
+        metadata.perm = 0o444;
+        assert_eq!(
+            attributes(".file", &metadata),
+            FILE_ATTRIBUTE_ARCHIVE | FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN
+        );
+
+        assert_eq!(perm(FILE_ATTRIBUTE_READONLY, 0o664), 0o444);
+        assert_eq!(perm(FILE_ATTRIBUTE_ARCHIVE, 0o444), 0o644);
+        assert_eq!(perm(0, 0o640), 0o640);
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert_eq!(filetime(UNIX_EPOCH), 116_444_736_000_000_000);
+        assert_eq!(system_time(filetime(time)), Some(time));
+        assert_eq!(system_time(0), None);
+        assert_eq!(path(r"\dir\file"), "/dir/file");
+    }
+}
@@ -0,0 +1,407 @@
+use std::ffi::c_void;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::{const_mutex, Condvar, Mutex};
+use windows::Win32::Foundation::{
+    BOOL, NTSTATUS, STATUS_ACCESS_DENIED, STATUS_DIRECTORY_NOT_EMPTY, STATUS_FILE_IS_A_DIRECTORY,
+    STATUS_INVALID_DEVICE_REQUEST, STATUS_IO_DEVICE_ERROR, STATUS_MEDIA_WRITE_PROTECTED,
+    STATUS_NOT_A_DIRECTORY, STATUS_OBJECT_NAME_COLLISION, STATUS_OBJECT_NAME_NOT_FOUND, TRUE,
+};
+use windows::Win32::System::Console::SetConsoleCtrlHandler;
+use winfsp::filesystem::{
+    DirBuffer, DirInfo, DirMarker, FileInfo, FileSecurity, FileSystemContext, OpenFileInfo,
+    VolumeInfo, WideNameInfo,
+};
+use winfsp::host::{FileSystemHost, VolumeParams};
+use winfsp::{FspError, U16CStr};
+
+use crate::vfs::{FileHandle, FileKind, Metadata, SetAttrs, SuperblockError, Vfs};
+use crate::winattr;
+
+/// Bytes of the sectors and allocation units reported to Windows.
+const SECTOR_SIZE: u16 = 512;
+const SECTORS_PER_UNIT: u16 = 8;
+/// Permission of files created from Windows, which has no mode to give.
+const CREATE_PERM: u16 = 0o644;
+/// Create option of an entry to be a directory, as Windows numbers it.
+const FILE_DIRECTORY_FILE: u32 = 0x1;
+/// Bytes reported as the size and free space of the volume, as providers
+/// have no set capacity.
+const VOLUME_SIZE: u64 = 1 << 50;
+
+/// An entry opened by Windows, a directory having no handle.
+pub struct Open {
+    path: Mutex<String>,
+    file: Option<FileHandle>,
+    delete: Mutex<bool>,
+    entries: DirBuffer,
+}
+
+/// The WinFsp frontend, serving the filesystem in `vfs` to Windows.
+pub struct WinFs {
+    vfs: Arc<Mutex<Vfs>>,
+}
+
+/// A filesystem mounted through WinFsp, until unmounted.
+pub struct WinMount {
+    host: FileSystemHost<'static>,
+    vfs: Arc<Mutex<Vfs>>,
+}
+
+impl WinFs {
+    pub fn new(vfs: Vfs) -> Self {
+        Self {
+            vfs: Arc::new(Mutex::new(vfs)),
+        }
+    }
+
+    /// Mount at `mountpoint`, a drive letter such as `X:` or a directory,
+    /// the tasks of the filesystem running in background meanwhile.
+    pub fn mount(self, mountpoint: &Path) -> io::Result<WinMount> {
+        winfsp::winfsp_init().map_err(other)?;
+        self.vfs.lock().start_background();
+        let vfs = self.vfs.clone();
+        let mut params = VolumeParams::new();
+        params
+            .filesystem_name("eoss")
+            .sector_size(SECTOR_SIZE)
+            .sectors_per_allocation_unit(SECTORS_PER_UNIT)
+            .case_sensitive_search(true)
+            .case_preserved_names(true)
+            .unicode_on_disk(true)
+            .file_info_timeout(1000);
+        let mut host = FileSystemHost::new(params, self).map_err(other)?;
+        host.mount(mountpoint.as_os_str()).map_err(other)?;
+        host.start().map_err(other)?;
+        Ok(WinMount { host, vfs })
+    }
+
+    fn open_path(&self, path: String, info: &mut FileInfo) -> io::Result<Open> {
+        let mut vfs = self.vfs.lock();
+        let metadata = vfs.metadata(&path)?;
+        let file = match metadata.kind {
+            FileKind::File => Some(vfs.open_file(&path)?),
+            FileKind::Dir => None,
+        };
+        fill(info, &path, &metadata);
+        Ok(Open {
+            path: Mutex::new(path),
+            file,
+            delete: Mutex::new(false),
+            entries: DirBuffer::new(),
+        })
+    }
+
+    /// Attributes of `open` into `info`.
+    fn refresh(&self, open: &Open, info: &mut FileInfo) -> io::Result<()> {
+        let mut vfs = self.vfs.lock();
+        let path = open.path.lock();
+        let metadata = match &open.file {
+            Some(file) => vfs.file_metadata(file)?,
+            None => vfs.metadata(&path)?,
+        };
+        fill(info, &path, &metadata);
+        Ok(())
+    }
+}
+
+impl FileSystemContext for WinFs {
+    type FileContext = Open;
+
+    fn get_security_by_name(
+        &self,
+        file_name: &U16CStr,
+        _security_descriptor: Option<&mut [c_void]>,
+        _resolve_reparse_points: impl FnOnce(&U16CStr) -> Option<FileSecurity>,
+    ) -> winfsp::Result<FileSecurity> {
+        let path = winattr::path(&file_name.to_string_lossy());
+        let metadata = self.vfs.lock().metadata(&path).map_err(status)?;
+        // without a security descriptor access is checked by permissions only
+        Ok(FileSecurity {
+            reparse: false,
+            sz_security_descriptor: 0,
+            attributes: winattr::attributes(name(&path), &metadata),
+        })
+    }
+
+    fn open(
+        &self,
+        file_name: &U16CStr,
+        _create_options: u32,
+        _granted_access: u32,
+        file_info: &mut OpenFileInfo,
+    ) -> winfsp::Result<Open> {
+        let path = winattr::path(&file_name.to_string_lossy());
+        self.open_path(path, file_info.as_mut()).map_err(status)
+    }
+
+    fn close(&self, open: Open) {
+        if let Some(file) = open.file {
+            let _ = self.vfs.lock().close_file(file);
+        }
+    }
+
+    fn create(
+        &self,
+        file_name: &U16CStr,
+        create_options: u32,
+        _granted_access: u32,
+        _file_attributes: u32,
+        _security_descriptor: Option<&[c_void]>,
+        _allocation_size: u64,
+        _extra_buffer: Option<&[u8]>,
+        _extra_buffer_is_reparse_point: bool,
+        file_info: &mut OpenFileInfo,
+    ) -> winfsp::Result<Open> {
+        // directories are not created through the filesystem core
+        if create_options & FILE_DIRECTORY_FILE != 0 {
+            return Err(STATUS_INVALID_DEVICE_REQUEST.into());
+        }
+        let path = winattr::path(&file_name.to_string_lossy());
+        let file = self.vfs.lock().create(&path, CREATE_PERM).map_err(status)?;
+        let open = Open {
+            path: Mutex::new(path),
+            file: Some(file),
+            delete: Mutex::new(false),
+            entries: DirBuffer::new(),
+        };
+        self.refresh(&open, file_info.as_mut()).map_err(status)?;
+        Ok(open)
+    }
+
+    fn cleanup(&self, open: &Open, _file_name: Option<&U16CStr>, _flags: u32) {
+        if !*open.delete.lock() {
+            return;
+        }
+        let path = open.path.lock();
+        let mut vfs = self.vfs.lock();
+        let _ = match open.file {
+            Some(_) => vfs.remove_file(&path),
+            None => vfs.remove_dir(&path),
+        };
+    }
+
+    fn flush(&self, open: Option<&Open>, file_info: &mut FileInfo) -> winfsp::Result<()> {
+        let open = match open {
+            Some(open) => open,
+            None => return Ok(()),
+        };
+        if let Some(file) = &open.file {
+            self.vfs.lock().sync(file).map_err(status)?;
+        }
+        self.refresh(open, file_info).map_err(status)
+    }
+
+    fn get_file_info(&self, open: &Open, file_info: &mut FileInfo) -> winfsp::Result<()> {
+        self.refresh(open, file_info).map_err(status)
+    }
+
+    fn get_volume_info(&self, out_volume_info: &mut VolumeInfo) -> winfsp::Result<()> {
+        out_volume_info.total_size = VOLUME_SIZE;
+        out_volume_info.free_size = VOLUME_SIZE;
+        out_volume_info.set_volume_label("eoss");
+        Ok(())
+    }
+
+    fn overwrite(
+        &self,
+        open: &Open,
+        _file_attributes: u32,
+        _replace_file_attributes: bool,
+        _allocation_size: u64,
+        _extra_buffer: Option<&[u8]>,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        if let Some(file) = &open.file {
+            self.vfs.lock().set_len(file, 0).map_err(status)?;
+        }
+        self.refresh(open, file_info).map_err(status)
+    }
+
+    fn read(&self, open: &Open, buffer: &mut [u8], offset: u64) -> winfsp::Result<u32> {
+        let file = open.file.as_ref().ok_or(STATUS_FILE_IS_A_DIRECTORY)?;
+        let read = self.vfs.lock().read(file, offset, buffer).map_err(status)?;
+        Ok(read as u32)
+    }
+
+    fn read_directory(
+        &self,
+        open: &Open,
+        _pattern: Option<&U16CStr>,
+        marker: DirMarker,
+        buffer: &mut [u8],
+    ) -> winfsp::Result<u32> {
+        if let Ok(lock) = open.entries.acquire(marker.is_none(), None) {
+            let path = open.path.lock().clone();
+            for entry in self.vfs.lock().read_dir(&path).map_err(status)? {
+                let mut info: DirInfo<255> = DirInfo::new();
+                fill(info.file_info_mut(), &entry.name, &entry.metadata);
+                info.set_name(entry.name.as_str())?;
+                lock.write(&mut info)?;
+            }
+        }
+        Ok(open.entries.read(marker, buffer))
+    }
+
+    fn rename(
+        &self,
+        open: &Open,
+        _file_name: &U16CStr,
+        new_file_name: &U16CStr,
+        replace_if_exists: bool,
+    ) -> winfsp::Result<()> {
+        let to = winattr::path(&new_file_name.to_string_lossy());
+        let mut path = open.path.lock();
+        let mut vfs = self.vfs.lock();
+        if !replace_if_exists && vfs.metadata(&to).is_ok() {
+            return Err(STATUS_OBJECT_NAME_COLLISION.into());
+        }
+        vfs.rename(&path, &to).map_err(status)?;
+        *path = to;
+        Ok(())
+    }
+
+    fn set_basic_info(
+        &self,
+        open: &Open,
+        file_attributes: u32,
+        _creation_time: u64,
+        last_access_time: u64,
+        last_write_time: u64,
+        _last_change_time: u64,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        {
+            let mut vfs = self.vfs.lock();
+            let path = open.path.lock();
+            let metadata = vfs.metadata(&path).map_err(status)?;
+            let changes = SetAttrs {
+                // INVALID_FILE_ATTRIBUTES leaves them unchanged
+                mode: Some(file_attributes)
+                    .filter(|attributes| *attributes != u32::MAX)
+                    .map(|attributes| winattr::perm(attributes, metadata.perm) as u32),
+                atime: winattr::system_time(last_access_time),
+                mtime: winattr::system_time(last_write_time),
+                ..SetAttrs::default()
+            };
+            vfs.set_attrs(&path, changes).map_err(status)?;
+        }
+        self.refresh(open, file_info).map_err(status)
+    }
+
+    fn set_delete(
+        &self,
+        open: &Open,
+        _file_name: &U16CStr,
+        delete_file: bool,
+    ) -> winfsp::Result<()> {
+        *open.delete.lock() = delete_file;
+        Ok(())
+    }
+
+    fn set_file_size(
+        &self,
+        open: &Open,
+        new_size: u64,
+        set_allocation_size: bool,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        let file = open.file.as_ref().ok_or(STATUS_FILE_IS_A_DIRECTORY)?;
+        // space is not allocated ahead
+        if !set_allocation_size {
+            self.vfs.lock().set_len(file, new_size).map_err(status)?;
+        }
+        self.refresh(open, file_info).map_err(status)
+    }
+
+    fn write(
+        &self,
+        open: &Open,
+        buffer: &[u8],
+        offset: u64,
+        write_to_eof: bool,
+        constrained_io: bool,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<u32> {
+        let file = open.file.as_ref().ok_or(STATUS_FILE_IS_A_DIRECTORY)?;
+        let written = {
+            let mut vfs = self.vfs.lock();
+            let size = vfs.file_metadata(file).map_err(status)?.size;
+            let offset = if write_to_eof { size } else { offset };
+            // paging I/O does not extend the file
+            let len = match constrained_io {
+                true if offset >= size => 0,
+                true => buffer.len().min((size - offset) as usize),
+                false => buffer.len(),
+            };
+            vfs.write(file, offset, &buffer[..len]).map_err(status)?
+        };
+        self.refresh(open, file_info).map_err(status)?;
+        Ok(written as u32)
+    }
+}
+
+impl WinMount {
+    /// Unmount, then persist everything and mark the filesystem cleanly
+    /// closed.
+    pub fn unmount(mut self) -> Result<(), SuperblockError> {
+        self.host.stop();
+        self.host.unmount();
+        self.vfs.lock().close()
+    }
+}
+
+static INTERRUPTED: Mutex<bool> = const_mutex(false);
+static INTERRUPT: Condvar = Condvar::new();
+
+/// Wait until the console is interrupted by Ctrl+C or Ctrl+Break.
+pub fn wait_for_interrupt() -> io::Result<()> {
+    unsafe extern "system" fn interrupted(_ctrl_type: u32) -> BOOL {
+        *INTERRUPTED.lock() = true;
+        INTERRUPT.notify_all();
+        TRUE
+    }
+    unsafe { SetConsoleCtrlHandler(Some(interrupted), TRUE) }.map_err(other)?;
+    let mut interrupted = INTERRUPTED.lock();
+    while !*interrupted {
+        INTERRUPT.wait(&mut interrupted);
+    }
+    Ok(())
+}
+
+/// Attributes of the entry at `path` with `metadata` into `info`.
+fn fill(info: &mut FileInfo, path: &str, metadata: &Metadata) {
+    let unit = (SECTOR_SIZE * SECTORS_PER_UNIT) as u64;
+    info.file_attributes = winattr::attributes(name(path), metadata);
+    info.file_size = metadata.size;
+    info.allocation_size = (metadata.size + unit - 1) / unit * unit;
+    info.creation_time = winattr::filetime(metadata.ctime);
+    info.last_access_time = winattr::filetime(metadata.atime);
+    info.last_write_time = winattr::filetime(metadata.mtime);
+    info.change_time = winattr::filetime(metadata.ctime);
+}
+
+fn name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Status of an error of the filesystem core, which carries errnos.
+fn status(e: io::Error) -> FspError {
+    let status: NTSTATUS = match e.raw_os_error() {
+        Some(libc::ENOENT) => STATUS_OBJECT_NAME_NOT_FOUND,
+        Some(libc::EEXIST) => STATUS_OBJECT_NAME_COLLISION,
+        Some(libc::EACCES) | Some(libc::EPERM) => STATUS_ACCESS_DENIED,
+        Some(libc::ENOTEMPTY) => STATUS_DIRECTORY_NOT_EMPTY,
+        Some(libc::EISDIR) => STATUS_FILE_IS_A_DIRECTORY,
+        Some(libc::ENOTDIR) => STATUS_NOT_A_DIRECTORY,
+        Some(libc::EROFS) => STATUS_MEDIA_WRITE_PROTECTED,
+        _ => STATUS_IO_DEVICE_ERROR,
+    };
+    status.into()
+}
+
+fn other(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(ErrorKind::Other, e.to_string())
+}
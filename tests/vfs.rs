@@ -0,0 +1,78 @@
+//! Embeds the filesystem through the public API alone, over a provider
+//! implemented outside the crate.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use eoss_fuse::options::{FormatOptions, MountOptions};
+use eoss_fuse::provider::{Chunk, ChunkProvider, ChunkProviderError, Id, CHUNK_SIZE};
+use eoss_fuse::vfs::{FileKind, SuperblockError, Vfs};
+
+/// Keeps the bytes of chunks and objects in memory.
+#[derive(Default)]
+struct Objects(Mutex<HashMap<Id, Vec<u8>>>);
+
+impl ChunkProvider for Objects {
+    fn get_chunk_by_id(&self, id: &Id) -> Result<Chunk, ChunkProviderError> {
+        match self.0.lock().unwrap().get(id) {
+            Some(data) => Ok(Chunk::new_with_data(id.clone(), data.clone())?),
+            None => Ok(Chunk::new(id.clone())),
+        }
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) -> Result<(), ChunkProviderError> {
+        let mut data = vec![0; CHUNK_SIZE];
+        chunk.read_at(0, &mut data);
+        self.0.lock().unwrap().insert(chunk.id().clone(), data);
+        Ok(())
+    }
+
+    fn contains_chunk(&self, id: &Id) -> Result<bool, ChunkProviderError> {
+        Ok(self.0.lock().unwrap().contains_key(id))
+    }
+
+    fn get_object(&self, id: &Id) -> Result<Option<Vec<u8>>, ChunkProviderError> {
+        Ok(self.0.lock().unwrap().get(id).cloned())
+    }
+
+    fn save_object(&self, id: &Id, data: &[u8]) -> Result<(), ChunkProviderError> {
+        self.0.lock().unwrap().insert(id.clone(), data.to_vec());
+        Ok(())
+    }
+
+    fn delete_chunk(&self, id: &Id) -> Result<(), ChunkProviderError> {
+        self.0.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_embedded() {
+    let provider = Arc::new(Objects::default());
+    let superblock_id = Id::new([0; 32]);
+    assert!(matches!(
+        Vfs::open_at(provider.clone(), MountOptions::default(), &superblock_id),
+        Err(SuperblockError::NotFormatted)
+    ));
+
+    Vfs::format(provider.as_ref(), &FormatOptions::default()).unwrap();
+    let mut vfs = Vfs::open_at(provider.clone(), MountOptions::default(), &superblock_id).unwrap();
+    vfs.create_dir("dir", 0o755).unwrap();
+    let file = vfs.create("dir/file", 0o644).unwrap();
+    assert_eq!(vfs.write(&file, 0, b"hello").unwrap(), 5);
+    vfs.close_file(file).unwrap();
+    vfs.close().unwrap();
+
+    let mut vfs = Vfs::open(provider, MountOptions::default()).unwrap();
+    vfs.start_background();
+    let entries: Vec<_> = vfs.read_dir("dir").unwrap().collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "file");
+    assert_eq!(entries[0].metadata.kind, FileKind::File);
+    let file = vfs.open_file("dir/file").unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(vfs.read(&file, 0, &mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    vfs.close_file(file).unwrap();
+    vfs.close().unwrap();
+}